@@ -0,0 +1,290 @@
+//! Random transforms of training windows.
+//!
+//! Small EEG datasets overfit quickly, so an [`Augmenter`] perturbs each
+//! `channels x samples` window of a `[windows, channels, samples]` array a
+//! little differently every time it is drawn, without changing its class:
+//!
+//! - `noise`: Gaussian noise at a share of each channel's deviation;
+//! - `shift`: a time shift of up to so many seconds, the edge sample held;
+//! - `dropout`: channels flattened to their mean with a probability;
+//! - `scale`: the amplitude scaled by up to a share either way;
+//! - `freq`: the spectrum shifted by up to so many Hz, through the analytic
+//!   signal;
+//! - `mix`: with a probability, a stretch of up to half the window cut from
+//!   another window of the same class.
+//!
+//! Steps run in the order given. Every draw comes from the seed and a round
+//! number, e.g. the training epoch, so a pipeline and its seed reproduce
+//! exactly and can be saved with whatever was trained on them.
+
+use ndarray::{s, Array1, Array3, ArrayViewMut1, Axis};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rustfft::num_complex::Complex64;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum AugmentError {
+    #[error("Augmentation '{0}' must look like noise=0.1, shift=0.1, dropout=0.1, scale=0.2, freq=1 or mix=0.5")]
+    Syntax(String),
+    #[error("Augmentation {0} is out of range, {1}")]
+    Range(Augmentation, &'static str),
+    #[error("Augmenting needs a positive sample rate, got {0}")]
+    SampleRate(f64),
+    #[error("{windows} windows but {labels} labels")]
+    Labels { windows: usize, labels: usize },
+}
+
+/// One transform and how strong it may get
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Augmentation {
+    /// Gaussian noise at this share of each channel's standard deviation
+    Noise { std: f64 },
+    /// Shift of up to this many seconds either way
+    Shift { seconds: f64 },
+    /// Each channel flattened to its mean with this probability
+    ChannelDropout { probability: f64 },
+    /// Amplitude times a factor up to this share from 1 either way
+    Scale { spread: f64 },
+    /// Spectrum moved by up to this many Hz either way
+    FrequencyShift { hz: f64 },
+    /// A stretch swapped in from a window of the same class with this
+    /// probability
+    Mix { probability: f64 },
+}
+
+impl Augmentation {
+    fn check(&self) -> Result<(), AugmentError> {
+        let (value, why) = match *self {
+            Self::Noise { std: v } | Self::Shift { seconds: v } | Self::FrequencyShift { hz: v } => {
+                (v.is_finite() && v >= 0.0, "expected at least 0")
+            }
+            Self::ChannelDropout { probability: v } | Self::Mix { probability: v } => {
+                ((0.0..=1.0).contains(&v), "expected a probability")
+            }
+            Self::Scale { spread: v } => ((0.0..1.0).contains(&v), "expected a share in [0, 1)"),
+        };
+        match value {
+            true => Ok(()),
+            false => Err(AugmentError::Range(*self, why)),
+        }
+    }
+}
+
+impl FromStr for Augmentation {
+    type Err = AugmentError;
+
+    /// `noise=0.1`, `shift=0.1`, `dropout=0.1`, `scale=0.2`, `freq=1` or
+    /// `mix=0.5`
+    fn from_str(s: &str) -> Result<Self, AugmentError> {
+        let syntax = || AugmentError::Syntax(s.to_string());
+        let (kind, value) = s.split_once('=').ok_or_else(syntax)?;
+        let value: f64 = value.trim().parse().map_err(|_| syntax())?;
+        let augmentation = match kind.trim().to_lowercase().as_str() {
+            "noise" => Self::Noise { std: value },
+            "shift" => Self::Shift { seconds: value },
+            "dropout" | "channel_dropout" => Self::ChannelDropout { probability: value },
+            "scale" => Self::Scale { spread: value },
+            "freq" | "frequency_shift" => Self::FrequencyShift { hz: value },
+            "mix" => Self::Mix { probability: value },
+            _ => return Err(syntax()),
+        };
+        augmentation.check()?;
+        Ok(augmentation)
+    }
+}
+
+impl fmt::Display for Augmentation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Noise { std } => write!(f, "noise={}", std),
+            Self::Shift { seconds } => write!(f, "shift={}", seconds),
+            Self::ChannelDropout { probability } => write!(f, "dropout={}", probability),
+            Self::Scale { spread } => write!(f, "scale={}", spread),
+            Self::FrequencyShift { hz } => write!(f, "freq={}", hz),
+            Self::Mix { probability } => write!(f, "mix={}", probability),
+        }
+    }
+}
+
+/// A seeded pipeline of augmentations for windows at one sample rate
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Augmenter {
+    pub steps: Vec<Augmentation>,
+    pub sample_rate: f64,
+    pub seed: u64,
+}
+
+impl Augmenter {
+    pub fn new(steps: Vec<Augmentation>, sample_rate: f64, seed: u64) -> Result<Self, AugmentError> {
+        if !(sample_rate.is_finite() && sample_rate > 0.0) {
+            return Err(AugmentError::SampleRate(sample_rate));
+        }
+        for step in &steps {
+            step.check()?;
+        }
+        Ok(Self { steps, sample_rate, seed })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Augment every window in place with the draws of `round`; `labels`
+    /// has the class of every window, for `mix`
+    pub fn apply(&self, windows: &mut Array3<f32>, labels: &[usize], round: u64) -> Result<(), AugmentError> {
+        let (count, _, samples) = windows.dim();
+        if labels.len() != count {
+            return Err(AugmentError::Labels { windows: count, labels: labels.len() });
+        }
+        if samples == 0 {
+            return Ok(());
+        }
+        let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(round.wrapping_mul(0x9E37_79B9_7F4A_7C15)));
+        let mut analytic: Option<Analytic> = None;
+        for step in &self.steps {
+            match *step {
+                Augmentation::Noise { std } => {
+                    for mut channel in windows.rows_mut() {
+                        let deviation = deviation(channel.view().iter().copied()) * std;
+                        channel.mapv_inplace(|x| x + (deviation * gaussian(&mut rng)) as f32);
+                    }
+                }
+                Augmentation::Shift { seconds } => {
+                    let most = (seconds * self.sample_rate).round() as i64;
+                    for mut window in windows.outer_iter_mut() {
+                        let offset = rng.gen_range(-most..=most);
+                        for mut channel in window.rows_mut() {
+                            shift(&mut channel, offset);
+                        }
+                    }
+                }
+                Augmentation::ChannelDropout { probability } => {
+                    for mut channel in windows.rows_mut() {
+                        if rng.gen_bool(probability) {
+                            let mean = channel.mean().unwrap_or(0.0);
+                            channel.fill(mean);
+                        }
+                    }
+                }
+                Augmentation::Scale { spread } => {
+                    for mut window in windows.outer_iter_mut() {
+                        let factor = 1.0 + rng.gen_range(-spread..=spread);
+                        window.mapv_inplace(|x| x * factor as f32);
+                    }
+                }
+                Augmentation::FrequencyShift { hz } => {
+                    let analytic = analytic.get_or_insert_with(|| Analytic::new(samples));
+                    for mut window in windows.outer_iter_mut() {
+                        let cycles = rng.gen_range(-hz..=hz) / self.sample_rate;
+                        for mut channel in window.rows_mut() {
+                            analytic.shift(&mut channel, cycles);
+                        }
+                    }
+                }
+                Augmentation::Mix { probability } => mix(windows, labels, probability, &mut rng),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Standard normal draw, by Box-Muller
+fn gaussian(rng: &mut StdRng) -> f64 {
+    let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+fn deviation(values: impl Iterator<Item = f32> + Clone) -> f64 {
+    let n = values.clone().count().max(1) as f64;
+    let mean = values.clone().map(f64::from).sum::<f64>() / n;
+    (values.map(|v| (v as f64 - mean).powi(2)).sum::<f64>() / n).sqrt()
+}
+
+/// Delay `channel` by `offset` samples (advance if negative), holding the
+/// edge sample over the gap
+fn shift(channel: &mut ArrayViewMut1<f32>, offset: i64) {
+    let original: Array1<f32> = channel.to_owned();
+    let last = original.len() as i64 - 1;
+    for (t, value) in channel.iter_mut().enumerate() {
+        *value = original[(t as i64 - offset).clamp(0, last) as usize];
+    }
+}
+
+/// With `probability`, replace a random stretch of each window by the same
+/// stretch of another window of its class
+fn mix(windows: &mut Array3<f32>, labels: &[usize], probability: f64, rng: &mut StdRng) {
+    let samples = windows.len_of(Axis(2));
+    let mut by_class: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (i, &label) in labels.iter().enumerate() {
+        by_class.entry(label).or_default().push(i);
+    }
+    // Stretches come from the windows as they were before mixing
+    let original = windows.clone();
+    for (i, &label) in labels.iter().enumerate() {
+        let members = &by_class[&label];
+        if members.len() < 2 || !rng.gen_bool(probability) {
+            continue;
+        }
+        let mut donor = members[rng.gen_range(0..members.len() - 1)];
+        if donor == i {
+            donor = members[members.len() - 1];
+        }
+        let length = rng.gen_range(1..=(samples / 2).max(1));
+        let start = rng.gen_range(0..=samples - length);
+        windows
+            .slice_mut(s![i, .., start..start + length])
+            .assign(&original.slice(s![donor, .., start..start + length]));
+    }
+}
+
+/// Frequency shifting through the analytic signal of one window length
+struct Analytic {
+    forward: Arc<dyn Fft<f64>>,
+    inverse: Arc<dyn Fft<f64>>,
+    buffer: Vec<Complex64>,
+}
+
+impl Analytic {
+    fn new(samples: usize) -> Self {
+        let mut planner = FftPlanner::new();
+        Self {
+            forward: planner.plan_fft_forward(samples),
+            inverse: planner.plan_fft_inverse(samples),
+            buffer: vec![Complex64::new(0.0, 0.0); samples],
+        }
+    }
+
+    /// Move every component of `channel` up by `cycles` per sample (down if
+    /// negative), keeping its mean
+    fn shift(&mut self, channel: &mut ArrayViewMut1<f32>, cycles: f64) {
+        let n = self.buffer.len();
+        let mean = channel.iter().map(|&v| v as f64).sum::<f64>() / n as f64;
+        for (slot, &value) in self.buffer.iter_mut().zip(channel.iter()) {
+            *slot = Complex64::new(value as f64 - mean, 0.0);
+        }
+        self.forward.process(&mut self.buffer);
+        // Keep DC and Nyquist, double the positive frequencies, drop the negative
+        for (k, bin) in self.buffer.iter_mut().enumerate().skip(1) {
+            if 2 * k < n {
+                *bin *= 2.0;
+            } else if 2 * k > n {
+                *bin = Complex64::new(0.0, 0.0);
+            }
+        }
+        self.inverse.process(&mut self.buffer);
+        for (t, (value, z)) in channel.iter_mut().zip(&self.buffer).enumerate() {
+            let rotated = z / n as f64 * Complex64::from_polar(1.0, 2.0 * PI * cycles * t as f64);
+            *value = (mean + rotated.re) as f32;
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! Band-power features of sliding windows.
+//!
+//! A [`BandPowerExtractor`] turns each window of multichannel EEG into one
+//! feature vector: the Welch power of every band of every channel,
+//! channel-major and log10 by default. Windows come from a whole recording
+//! with [`BandPowerExtractor::extract_all`] or frame by frame from a stream
+//! with [`BandPowerExtractor::push`], and give the same numbers either way,
+//! so a model trained on exported features sees the same inputs online.
+
+use crate::history::History;
+use crate::sample::Sample;
+use crate::spectrum::{band_power, Periodogram, SpectralParams, SpectrumError};
+use ndarray::{Array2, ArrayView2};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Named frequency band, parsed from `name=low-high` (Hz)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Band {
+    pub name: String,
+    pub low: f64,
+    pub high: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum FeatureError {
+    #[error(transparent)]
+    Spectrum(#[from] SpectrumError),
+    #[error("Band '{0}' must look like name=low-high")]
+    Syntax(String),
+    #[error("Band '{0}' has low >= high")]
+    Order(String),
+    #[error("Band '{name}' of {low}-{high} Hz must satisfy 0 <= low < high <= {nyquist} Hz")]
+    Band { name: String, low: f64, high: f64, nyquist: f64 },
+    #[error("No bands to extract")]
+    NoBands,
+    #[error("Window of {window} samples is shorter than the {segment} sample segment")]
+    Window { window: usize, segment: usize },
+    #[error("Windows must step at least one sample")]
+    Step,
+    #[error("Time-domain feature '{0}' must be one of activity, mobility, complexity, line_length, zero_crossings or rms")]
+    Feature(String),
+    #[error("No time-domain features to extract")]
+    NoFeatures,
+    #[error("Window of {window} samples is too short, time-domain features need at least {needed}")]
+    Short { window: usize, needed: usize },
+}
+
+impl Band {
+    pub fn new(name: impl Into<String>, low: f64, high: f64) -> Self {
+        Self { name: name.into(), low, high }
+    }
+}
+
+impl FromStr for Band {
+    type Err = FeatureError;
+
+    fn from_str(s: &str) -> Result<Self, FeatureError> {
+        let syntax = || FeatureError::Syntax(s.to_string());
+        let (name, range) = s.split_once('=').ok_or_else(syntax)?;
+        let (low, high) = range.split_once('-').ok_or_else(syntax)?;
+        let band = Self {
+            name: name.trim().to_string(),
+            low: low.trim().parse().map_err(|_| syntax())?,
+            high: high.trim().parse().map_err(|_| syntax())?,
+        };
+        if band.low >= band.high {
+            return Err(FeatureError::Order(s.to_string()));
+        }
+        Ok(band)
+    }
+}
+
+/// Mu and beta, the motor imagery defaults
+pub fn motor_imagery_bands() -> Vec<Band> {
+    vec![Band::new("mu", 8.0, 13.0), Band::new("beta", 13.0, 30.0)]
+}
+
+/// Band powers of windows of `channels` channels
+#[derive(Clone)]
+pub struct BandPowerExtractor {
+    bands: Vec<Band>,
+    params: SpectralParams,
+    channels: usize,
+    window: usize,
+    step: usize,
+    log: bool,
+    periodogram: Periodogram,
+    /// One channel's window, and its density
+    samples: Vec<f64>,
+    psd: Vec<f64>,
+    history: History,
+    features: Vec<f64>,
+}
+
+impl BandPowerExtractor {
+    /// Feature vectors of `window` samples, stepping a whole window, from
+    /// 1 s Hann segments overlapping by half (the whole window if it is
+    /// shorter)
+    pub fn new(bands: Vec<Band>, sample_rate: f64, channels: usize, window: usize) -> Result<Self, FeatureError> {
+        let segment = (sample_rate.round() as usize).min(window).max(2);
+        Self::with_params(bands, SpectralParams::new(sample_rate, segment), channels, window)
+    }
+
+    /// Feature vectors of `window` samples, Welch over segments of `params`
+    pub fn with_params(
+        bands: Vec<Band>,
+        params: SpectralParams,
+        channels: usize,
+        window: usize,
+    ) -> Result<Self, FeatureError> {
+        params.validate()?;
+        if bands.is_empty() {
+            return Err(FeatureError::NoBands);
+        }
+        let nyquist = params.sample_rate / 2.0;
+        if let Some(band) = bands.iter().find(|b| !(b.low >= 0.0 && b.low < b.high && b.high <= nyquist)) {
+            return Err(FeatureError::Band { name: band.name.clone(), low: band.low, high: band.high, nyquist });
+        }
+        if window < params.segment {
+            return Err(FeatureError::Window { window, segment: params.segment });
+        }
+        Ok(Self {
+            periodogram: Periodogram::new(&params),
+            samples: vec![0.0; window],
+            psd: vec![0.0; params.bins()],
+            history: History::new(channels, window, window),
+            features: vec![0.0; channels * bands.len()],
+            bands,
+            params,
+            channels,
+            window,
+            step: window,
+            log: true,
+        })
+    }
+
+    /// Samples from one window's start to the next
+    pub fn with_step(self, step: usize) -> Result<Self, FeatureError> {
+        if step == 0 {
+            return Err(FeatureError::Step);
+        }
+        let mut extractor = Self { step, ..self };
+        extractor.history.set_step(step);
+        Ok(extractor)
+    }
+
+    /// Powers as they are instead of their log10
+    pub fn with_log(self, log: bool) -> Self {
+        Self { log, ..self }
+    }
+
+    pub fn bands(&self) -> &[Band] {
+        &self.bands
+    }
+
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    pub fn step(&self) -> usize {
+        self.step
+    }
+
+    /// Values in a feature vector, bands times channels
+    pub fn dimension(&self) -> usize {
+        self.features.len()
+    }
+
+    /// `<channel>_<band>_logpow` (or `_pow`) of every feature
+    pub fn feature_names<S: AsRef<str>>(&self, channel_names: &[S]) -> Vec<String> {
+        let suffix = if self.log { "logpow" } else { "pow" };
+        channel_names
+            .iter()
+            .flat_map(|channel| self.bands.iter().map(move |band| format!("{}_{}_{}", channel.as_ref(), band.name, suffix)))
+            .collect()
+    }
+
+    /// Features of one window, `channels x samples`, into `out`
+    pub fn extract<T: Sample>(&mut self, window: ArrayView2<T>, out: &mut [f64]) -> Result<(), FeatureError> {
+        debug_assert_eq!(window.nrows(), self.channels);
+        if window.ncols() < self.params.segment {
+            return Err(FeatureError::Window { window: window.ncols(), segment: self.params.segment });
+        }
+        self.samples.resize(window.ncols(), 0.0);
+        for (channel, row) in window.rows().into_iter().enumerate() {
+            for (slot, value) in self.samples.iter_mut().zip(row) {
+                *slot = value.to_f64();
+            }
+            self.channel_features(channel);
+        }
+        out.copy_from_slice(&self.features);
+        Ok(())
+    }
+
+    /// Features of every whole window of `data`, one vector per row
+    pub fn extract_all<T: Sample>(&mut self, data: ArrayView2<T>) -> Result<Array2<f64>, FeatureError> {
+        let starts: Vec<usize> = match data.ncols().checked_sub(self.window) {
+            Some(last) => (0..=last).step_by(self.step).collect(),
+            None => Vec::new(),
+        };
+        let mut vectors = Array2::zeros((starts.len(), self.dimension()));
+        for (&start, mut out) in starts.iter().zip(vectors.rows_mut()) {
+            let window = data.slice(ndarray::s![.., start..start + self.window]);
+            self.extract(window, out.as_slice_mut().expect("rows of a new array are contiguous"))?;
+        }
+        Ok(vectors)
+    }
+
+    /// Add one sample of every channel; the features of the latest window
+    /// when a step completes
+    pub fn push<T: Sample>(&mut self, frame: &[T]) -> Option<&[f64]> {
+        if !self.history.push(frame) {
+            return None;
+        }
+        self.samples.resize(self.window, 0.0);
+        for channel in 0..self.channels {
+            self.history.channel(channel, &mut self.samples);
+            self.channel_features(channel);
+        }
+        Some(&self.features)
+    }
+
+    /// Forget the stream's history
+    pub fn reset(&mut self) {
+        self.history.reset();
+    }
+
+    /// Band powers of `samples` as the features of `channel`
+    fn channel_features(&mut self, channel: usize) {
+        let (segment, hop) = (self.params.segment, self.params.hop());
+        let segments = (self.samples.len() - segment) / hop + 1;
+        self.psd.fill(0.0);
+        for start in (0..segments).map(|k| k * hop) {
+            let samples = self.samples[start..start + segment].iter().copied();
+            self.periodogram.accumulate(samples, 1.0 / segments as f64, &mut self.psd);
+        }
+        let resolution = self.params.resolution();
+        let features = &mut self.features[channel * self.bands.len()..(channel + 1) * self.bands.len()];
+        for (feature, band) in features.iter_mut().zip(&self.bands) {
+            let power = band_power(&self.psd, resolution, band.low, band.high);
+            *feature = if self.log { power.max(f64::MIN_POSITIVE).log10() } else { power };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{gaussian, sine};
+
+    #[test]
+    fn stream_and_recording_give_the_same_features() {
+        let data = Array2::from_shape_vec((2, 1000), gaussian(1, 2000)).unwrap();
+        let mut offline = BandPowerExtractor::new(motor_imagery_bands(), 250.0, 2, 500).unwrap().with_step(125).unwrap();
+        let mut online = offline.clone();
+        let windows = offline.extract_all(data.view()).unwrap();
+        assert_eq!(windows.dim(), (5, 4));
+        let streamed: Vec<Vec<f64>> = data.columns().into_iter().filter_map(|frame| online.push(&frame.to_vec()).map(<[f64]>::to_vec)).collect();
+        assert_eq!(streamed.len(), 5);
+        for (row, features) in windows.rows().into_iter().zip(&streamed) {
+            assert!(row.iter().zip(features).all(|(a, b)| (a - b).abs() < 1e-12));
+        }
+    }
+
+    #[test]
+    fn power_lands_in_the_band_of_the_sine() {
+        let data = Array2::from_shape_vec((1, 500), sine(10.0, 250.0, 500)).unwrap();
+        let mut extractor = BandPowerExtractor::new(motor_imagery_bands(), 250.0, 1, 500).unwrap().with_log(false);
+        let mut powers = [0.0; 2];
+        extractor.extract(data.view(), &mut powers).unwrap();
+        assert!((powers[0] - 0.5).abs() < 0.01 && powers[1] < 1e-3, "{:?}", powers);
+        assert_eq!(extractor.feature_names(&["C3"]), ["C3_mu_pow", "C3_beta_pow"]);
+    }
+
+    #[test]
+    fn bands_parse_and_validate() {
+        assert_eq!("alpha = 8-12".parse(), Ok(Band::new("alpha", 8.0, 12.0)));
+        assert!(matches!("alpha".parse::<Band>(), Err(FeatureError::Syntax(_))));
+        assert!(matches!("alpha=12-8".parse::<Band>(), Err(FeatureError::Order(_))));
+        let high = vec![Band::new("gamma", 30.0, 200.0)];
+        assert!(matches!(BandPowerExtractor::new(high, 250.0, 1, 500), Err(FeatureError::Band { .. })));
+        assert!(matches!(BandPowerExtractor::new(Vec::new(), 250.0, 1, 500), Err(FeatureError::NoBands)));
+    }
+}
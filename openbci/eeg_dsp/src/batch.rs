@@ -0,0 +1,152 @@
+//! Labelled windows dealt out in mini-batches.
+//!
+//! [`Windows`] are `[windows, channels, samples]` with a class index per
+//! window, kept raw. A [`BatchLoader`] deals them out in batches, every
+//! epoch in a fresh order drawn from its seed, augmented afresh by its
+//! [`Augmenter`] with the epoch as round and standardized on the fly.
+//! Trainers draw their batches from one; scoring uses one unshuffled.
+
+use crate::augment::{AugmentError, Augmenter};
+use crate::standardize::Standardization;
+use ndarray::{Array3, Axis};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::borrow::Cow;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum BatchError {
+    #[error("{windows} windows but {labels} labels")]
+    Labels { windows: usize, labels: usize },
+    #[error("Windows have {windows} channels, the standardization {standardization}")]
+    Channels { windows: usize, standardization: usize },
+    #[error(transparent)]
+    Augment(#[from] AugmentError),
+}
+
+/// Windows with their class
+#[derive(Debug, Clone)]
+pub struct Windows {
+    /// `[windows, channels, samples]`
+    pub windows: Array3<f32>,
+    /// Class index of every window
+    pub labels: Vec<usize>,
+}
+
+impl Windows {
+    pub fn new(windows: Array3<f32>, labels: Vec<usize>) -> Result<Self, BatchError> {
+        if windows.len_of(Axis(0)) != labels.len() {
+            return Err(BatchError::Labels { windows: windows.len_of(Axis(0)), labels: labels.len() });
+        }
+        Ok(Self { windows, labels })
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Channels and samples of a window
+    pub fn shape(&self) -> (usize, usize) {
+        let (_, channels, samples) = self.windows.dim();
+        (channels, samples)
+    }
+}
+
+/// Mini-batches of some windows, epoch after epoch
+#[derive(Debug, Clone)]
+pub struct BatchLoader<'a> {
+    windows: &'a Windows,
+    batch_size: usize,
+    /// Seed of the order; in order when `None`
+    seed: Option<u64>,
+    standardization: Option<Standardization>,
+    augmenter: Option<Augmenter>,
+}
+
+impl<'a> BatchLoader<'a> {
+    /// Batches of `batch_size` windows in order, as they are
+    pub fn new(windows: &'a Windows, batch_size: usize) -> Self {
+        Self { windows, batch_size: batch_size.max(1), seed: None, standardization: None, augmenter: None }
+    }
+
+    /// Shuffle every epoch, by `seed` and the epoch
+    pub fn with_shuffle(self, seed: u64) -> Self {
+        Self { seed: Some(seed), ..self }
+    }
+
+    pub fn with_standardization(self, standardization: Standardization) -> Self {
+        Self { standardization: Some(standardization), ..self }
+    }
+
+    /// Augment every epoch, before standardizing
+    pub fn with_augmenter(self, augmenter: Augmenter) -> Self {
+        Self { augmenter: Some(augmenter), ..self }
+    }
+
+    pub fn windows(&self) -> &Windows {
+        self.windows
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// The batches of `epoch`
+    pub fn epoch(&self, epoch: u64) -> Result<Batches<'_>, BatchError> {
+        let (channels, _) = self.windows.shape();
+        if let Some(standardization) = &self.standardization {
+            if standardization.center.len() != channels {
+                return Err(BatchError::Channels { windows: channels, standardization: standardization.center.len() });
+            }
+        }
+        let mut order: Vec<usize> = (0..self.windows.len()).collect();
+        if let Some(seed) = self.seed {
+            order.shuffle(&mut StdRng::seed_from_u64(seed.wrapping_add(epoch)));
+        }
+        let windows = match &self.augmenter {
+            Some(augmenter) if !augmenter.is_empty() => {
+                let mut windows = self.windows.windows.clone();
+                augmenter.apply(&mut windows, &self.windows.labels, epoch)?;
+                Cow::Owned(windows)
+            }
+            _ => Cow::Borrowed(&self.windows.windows),
+        };
+        Ok(Batches {
+            windows,
+            labels: &self.windows.labels,
+            order,
+            batch_size: self.batch_size,
+            next: 0,
+            standardization: self.standardization.as_ref(),
+        })
+    }
+}
+
+/// The batches of one epoch: `[batch, channels, samples]` and the classes
+pub struct Batches<'a> {
+    windows: Cow<'a, Array3<f32>>,
+    labels: &'a [usize],
+    order: Vec<usize>,
+    batch_size: usize,
+    next: usize,
+    standardization: Option<&'a Standardization>,
+}
+
+impl Iterator for Batches<'_> {
+    type Item = (Array3<f32>, Vec<usize>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let indices = self.order.get(self.next..)?.chunks(self.batch_size).next()?;
+        self.next += indices.len();
+        let mut batch = self.windows.select(Axis(0), indices);
+        if let Some(standardization) = self.standardization {
+            standardization.apply_all(batch.view_mut());
+        }
+        Some((batch, indices.iter().map(|&i| self.labels[i]).collect()))
+    }
+}
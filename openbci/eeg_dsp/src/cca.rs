@@ -0,0 +1,171 @@
+//! Canonical correlation analysis for SSVEP detection.
+//!
+//! A steady-state visual evoked potential follows the flicker the subject
+//! attends, at its frequency and its harmonics. [`SsvepReferences`] holds
+//! a bank of sines and cosines of each candidate frequency and its
+//! harmonics over one window; the largest canonical correlation between a
+//! `channels x samples` window and a bank says how closely the EEG follows
+//! that flicker, without any training (Lin et al., 2006). Both sides are
+//! centred and whitened through the Cholesky factors of their covariances,
+//! so the correlations are the singular values of the whitened
+//! cross-covariance. The banks are whitened once, when they are built.
+
+use crate::linalg::{cholesky, invert_lower, symmetric_eigen};
+use crate::sample::Sample;
+use ndarray::{Array2, ArrayView2};
+use std::f64::consts::TAU;
+use thiserror::Error;
+
+/// Ridge added to covariance diagonals, relative to their mean variance,
+/// so rank-deficient windows still whiten
+const RIDGE: f64 = 1e-9;
+
+#[derive(Debug, Error)]
+pub enum CcaError {
+    #[error("No target frequencies")]
+    NoFrequencies,
+    #[error("At least one harmonic is needed")]
+    Harmonics,
+    #[error("{frequency} Hz with {harmonics} harmonics reaches the Nyquist frequency of {sample_rate} Hz")]
+    Nyquist { frequency: f64, harmonics: usize, sample_rate: f64 },
+    #[error("Windows of {samples} samples are too short for {harmonics} harmonics")]
+    Samples { samples: usize, harmonics: usize },
+}
+
+/// Sine and cosine references of target frequencies over one window
+#[derive(Debug, Clone)]
+pub struct SsvepReferences {
+    frequencies: Vec<f64>,
+    harmonics: usize,
+    samples: usize,
+    /// Whitened `2 harmonics x samples` bank of each frequency
+    banks: Vec<Array2<f64>>,
+}
+
+impl SsvepReferences {
+    /// Banks of `harmonics` harmonics of each frequency, for windows of
+    /// `samples` samples at `sample_rate`
+    pub fn new(frequencies: &[f64], harmonics: usize, sample_rate: f64, samples: usize) -> Result<Self, CcaError> {
+        if frequencies.is_empty() {
+            return Err(CcaError::NoFrequencies);
+        }
+        if harmonics == 0 {
+            return Err(CcaError::Harmonics);
+        }
+        if samples <= 2 * harmonics {
+            return Err(CcaError::Samples { samples, harmonics });
+        }
+        let mut banks = Vec::with_capacity(frequencies.len());
+        for &frequency in frequencies {
+            if !(frequency > 0.0 && frequency * harmonics as f64 * 2.0 < sample_rate) {
+                return Err(CcaError::Nyquist { frequency, harmonics, sample_rate });
+            }
+            let bank = Array2::from_shape_fn((2 * harmonics, samples), |(row, t)| {
+                let phase = TAU * frequency * (row / 2 + 1) as f64 * t as f64 / sample_rate;
+                if row % 2 == 0 { phase.sin() } else { phase.cos() }
+            });
+            banks.push(whiten(bank).ok_or(CcaError::Samples { samples, harmonics })?);
+        }
+        Ok(Self { frequencies: frequencies.to_vec(), harmonics, samples, banks })
+    }
+
+    pub fn frequencies(&self) -> &[f64] {
+        &self.frequencies
+    }
+
+    pub fn harmonics(&self) -> usize {
+        self.harmonics
+    }
+
+    /// Samples of a window
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// Largest canonical correlation of a `channels x samples` window with
+    /// each frequency's bank, in frequency order; all zero for a flat
+    /// window
+    pub fn correlations<T: Sample>(&self, window: ArrayView2<T>) -> Vec<f64> {
+        assert_eq!(window.ncols(), self.samples, "window length differs from the references'");
+        match whiten(window.mapv(|x| x.to_f64())) {
+            Some(eeg) => self.banks.iter().map(|bank| largest_correlation(&eeg, bank)).collect(),
+            None => vec![0.0; self.banks.len()],
+        }
+    }
+}
+
+/// Largest canonical correlation between the rows of `x` and those of `y`,
+/// both `variables x samples`
+pub fn canonical_correlation(x: ArrayView2<f64>, y: ArrayView2<f64>) -> f64 {
+    match (whiten(x.to_owned()), whiten(y.to_owned())) {
+        (Some(x), Some(y)) => largest_correlation(&x, &y),
+        _ => 0.0,
+    }
+}
+
+/// Rows centred and decorrelated to unit variance, `None` when they are
+/// all flat
+fn whiten(mut rows: Array2<f64>) -> Option<Array2<f64>> {
+    let samples = rows.ncols() as f64;
+    for mut row in rows.rows_mut() {
+        let mean = row.sum() / samples;
+        row -= mean;
+    }
+    let mut covariance = rows.dot(&rows.t()) / samples;
+    let ridge = RIDGE * covariance.diag().sum() / covariance.nrows() as f64;
+    if ridge.is_nan() || ridge <= 0.0 {
+        return None;
+    }
+    covariance.diag_mut().mapv_inplace(|v| v + ridge);
+    Some(invert_lower(&cholesky(&covariance)?).dot(&rows))
+}
+
+/// Largest singular value of the cross-covariance of whitened rows
+fn largest_correlation(x: &Array2<f64>, y: &Array2<f64>) -> f64 {
+    let cross = x.dot(&y.t()) / x.ncols() as f64;
+    let product = if cross.nrows() <= cross.ncols() { cross.dot(&cross.t()) } else { cross.t().dot(&cross) };
+    let (values, _) = symmetric_eigen(&product);
+    values[0].max(0.0).sqrt().min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{gaussian, sine};
+
+    #[test]
+    fn picks_the_stimulus_frequency() {
+        let (fs, samples) = (250.0, 500);
+        let references = SsvepReferences::new(&[8.0, 10.0, 12.0, 15.0], 2, fs, samples).unwrap();
+        // Three channels of the 10 Hz response at different phases in noise
+        let noise = gaussian(1, 3 * samples);
+        let window = Array2::from_shape_fn((3, samples), |(c, t)| {
+            let phase = c as f64 * 0.7;
+            (TAU * 10.0 * t as f64 / fs + phase).sin() + noise[c * samples + t]
+        });
+        let correlations = references.correlations(window.view());
+        let best = (0..4).max_by(|&a, &b| correlations[a].total_cmp(&correlations[b])).unwrap();
+        assert_eq!(references.frequencies()[best], 10.0);
+        assert!(correlations.iter().all(|r| (0.0..=1.0).contains(r)));
+        assert_eq!(references.correlations(Array2::<f64>::zeros((3, samples)).view()), [0.0; 4]);
+    }
+
+    #[test]
+    fn correlation_is_one_for_a_linear_mix() {
+        let x = Array2::from_shape_vec((2, 300), gaussian(1, 600)).unwrap();
+        let y = ndarray::arr2(&[[0.5, -2.0]]).dot(&x);
+        assert!((canonical_correlation(x.view(), y.view()) - 1.0).abs() < 1e-6);
+        let unrelated = Array2::from_shape_vec((1, 300), gaussian(2, 300)).unwrap();
+        assert!(canonical_correlation(x.view(), unrelated.view()) < 0.3);
+        let flat = Array2::from_shape_vec((1, 300), sine(0.0, 250.0, 300)).unwrap();
+        assert_eq!(canonical_correlation(x.view(), flat.view()), 0.0);
+    }
+
+    #[test]
+    fn rejects_references_past_nyquist() {
+        assert!(matches!(SsvepReferences::new(&[], 2, 250.0, 500), Err(CcaError::NoFrequencies)));
+        assert!(matches!(SsvepReferences::new(&[10.0], 0, 250.0, 500), Err(CcaError::Harmonics)));
+        assert!(matches!(SsvepReferences::new(&[50.0], 3, 250.0, 500), Err(CcaError::Nyquist { .. })));
+        assert!(matches!(SsvepReferences::new(&[10.0], 2, 250.0, 4), Err(CcaError::Samples { .. })));
+    }
+}
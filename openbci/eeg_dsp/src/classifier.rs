@@ -0,0 +1,321 @@
+//! Linear classifiers of feature vectors.
+//!
+//! [`Lda`] and [`LogisticRegression`] are trained on rows of features,
+//! e.g. CSP log-variances, band powers or tangent-space vectors, with a
+//! class label per row. Both give class probabilities in label order, which
+//! the online pipeline can smooth or threshold. A trained classifier is
+//! saved as a [`Model`], a JSON file that records which kind it is, so the
+//! online side loads either without knowing in advance.
+
+use crate::covariance::{shrunk_covariance, Shrinkage};
+use crate::linalg::spd_inverse;
+use ndarray::{Array1, Array2, ArrayView1, ArrayView2, Axis};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ClassifierError {
+    #[error("{samples} feature vectors but {labels} labels")]
+    Labels { samples: usize, labels: usize },
+    #[error("Classifiers need at least two classes, got {0:?}")]
+    Classes(Vec<u8>),
+    #[error("Within-class covariance is singular, try shrinkage")]
+    Singular,
+    #[error("Shrinkage must be between 0 and 1, got {0}")]
+    Shrinkage(f64),
+    #[error("Failed to access model file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to read model file: {0}")]
+    Format(#[from] serde_json::Error),
+}
+
+/// A trained classifier of fixed-length feature vectors
+pub trait Classifier {
+    /// Class labels in ascending order
+    fn labels(&self) -> &[u8];
+
+    /// Length of the feature vectors it takes
+    fn dimension(&self) -> usize;
+
+    /// Class probabilities in label order
+    fn probabilities(&self, features: ArrayView1<f64>) -> Array1<f64>;
+
+    /// Most probable label
+    fn predict(&self, features: ArrayView1<f64>) -> u8 {
+        let probabilities = self.probabilities(features);
+        let best = (0..probabilities.len()).max_by(|&a, &b| probabilities[a].total_cmp(&probabilities[b]));
+        best.map_or(0, |k| self.labels()[k])
+    }
+}
+
+/// Linear discriminant analysis with a shrunk shared covariance
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lda {
+    labels: Vec<u8>,
+    /// One row per class
+    weights: Array2<f64>,
+    biases: Array1<f64>,
+}
+
+/// Gradient descent settings of [`LogisticRegression`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LogisticParams {
+    pub learning_rate: f64,
+    pub epochs: usize,
+    /// L2 penalty on the weights
+    pub l2: f64,
+}
+
+/// Multinomial logistic regression on standardised features
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogisticRegression {
+    labels: Vec<u8>,
+    /// Feature means and standard deviations of the training set
+    means: Array1<f64>,
+    scales: Array1<f64>,
+    /// One row per class
+    weights: Array2<f64>,
+    biases: Array1<f64>,
+    params: LogisticParams,
+}
+
+/// A trained classifier as saved to disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Model {
+    Lda(Lda),
+    Logistic(LogisticRegression),
+}
+
+impl Default for LogisticParams {
+    fn default() -> Self {
+        Self { learning_rate: 0.5, epochs: 500, l2: 1e-3 }
+    }
+}
+
+/// Distinct labels in ascending order and each row's index among them
+fn classes(features: &ArrayView2<f64>, labels: &[u8]) -> Result<(Vec<u8>, Vec<usize>), ClassifierError> {
+    if features.nrows() != labels.len() {
+        return Err(ClassifierError::Labels { samples: features.nrows(), labels: labels.len() });
+    }
+    let mut distinct = labels.to_vec();
+    distinct.sort_unstable();
+    distinct.dedup();
+    if distinct.len() < 2 {
+        return Err(ClassifierError::Classes(distinct));
+    }
+    let indices = labels.iter().map(|l| distinct.binary_search(l).unwrap_or_default()).collect();
+    Ok((distinct, indices))
+}
+
+/// Probabilities of linear scores, shifted against overflow
+fn softmax(scores: Array1<f64>) -> Array1<f64> {
+    let top = scores.fold(f64::NEG_INFINITY, |m, &s| m.max(s));
+    let exp = scores.mapv(|s| (s - top).exp());
+    let total = exp.sum();
+    exp / total
+}
+
+impl Lda {
+    /// Fit to `samples x features` rows and their labels
+    pub fn fit(features: ArrayView2<f64>, labels: &[u8], shrinkage: Shrinkage) -> Result<Self, ClassifierError> {
+        if let Shrinkage::Fixed(amount) = shrinkage {
+            if !(0.0..=1.0).contains(&amount) {
+                return Err(ClassifierError::Shrinkage(amount));
+            }
+        }
+        let (labels, indices) = classes(&features, labels)?;
+        let (samples, dimension) = features.dim();
+
+        let mut means = Array2::<f64>::zeros((labels.len(), dimension));
+        let mut counts = vec![0usize; labels.len()];
+        for (row, &k) in features.rows().into_iter().zip(&indices) {
+            let mut mean = means.row_mut(k);
+            mean += &row;
+            counts[k] += 1;
+        }
+        for (mut mean, &count) in means.rows_mut().into_iter().zip(&counts) {
+            mean /= count as f64;
+        }
+
+        // Pooled within-class scatter, from rows centred on their class mean
+        let mut centred = features.t().to_owned();
+        for (mut column, &k) in centred.columns_mut().into_iter().zip(&indices) {
+            column -= &means.row(k);
+        }
+        let precision = spd_inverse(&shrunk_covariance(&centred, shrinkage)).ok_or(ClassifierError::Singular)?;
+
+        let weights = means.dot(&precision);
+        let biases = Array1::from_iter(weights.rows().into_iter().zip(means.rows()).zip(&counts).map(
+            |((w, mean), &count)| -0.5 * w.dot(&mean) + (count as f64 / samples as f64).ln(),
+        ));
+        Ok(Self { labels, weights, biases })
+    }
+}
+
+impl Classifier for Lda {
+    fn labels(&self) -> &[u8] {
+        &self.labels
+    }
+
+    fn dimension(&self) -> usize {
+        self.weights.ncols()
+    }
+
+    fn probabilities(&self, features: ArrayView1<f64>) -> Array1<f64> {
+        softmax(self.weights.dot(&features) + &self.biases)
+    }
+}
+
+impl LogisticRegression {
+    /// Fit to `samples x features` rows and their labels by full-batch
+    /// gradient descent on the cross-entropy
+    pub fn fit(features: ArrayView2<f64>, labels: &[u8], params: LogisticParams) -> Result<Self, ClassifierError> {
+        let (labels, indices) = classes(&features, labels)?;
+        let (samples, dimension) = features.dim();
+
+        let means = features.mean_axis(Axis(0)).unwrap_or_else(|| Array1::zeros(dimension));
+        let scales = features.std_axis(Axis(0), 0.0).mapv(|s| if s > 0.0 { s } else { 1.0 });
+        let standard = (&features - &means) / &scales;
+        let mut targets = Array2::<f64>::zeros((samples, labels.len()));
+        for (mut row, &k) in targets.rows_mut().into_iter().zip(&indices) {
+            row[k] = 1.0;
+        }
+
+        let mut weights = Array2::<f64>::zeros((labels.len(), dimension));
+        let mut biases = Array1::<f64>::zeros(labels.len());
+        for _ in 0..params.epochs {
+            let mut errors = standard.dot(&weights.t()) + &biases;
+            for mut row in errors.rows_mut() {
+                let probabilities = softmax(row.to_owned());
+                row.assign(&probabilities);
+            }
+            errors -= &targets;
+            let gradient = errors.t().dot(&standard) / samples as f64 + &weights * params.l2;
+            weights.scaled_add(-params.learning_rate, &gradient);
+            biases.scaled_add(-params.learning_rate, &(errors.sum_axis(Axis(0)) / samples as f64));
+        }
+        Ok(Self { labels, means, scales, weights, biases, params })
+    }
+
+    pub fn params(&self) -> LogisticParams {
+        self.params
+    }
+}
+
+impl Classifier for LogisticRegression {
+    fn labels(&self) -> &[u8] {
+        &self.labels
+    }
+
+    fn dimension(&self) -> usize {
+        self.weights.ncols()
+    }
+
+    fn probabilities(&self, features: ArrayView1<f64>) -> Array1<f64> {
+        let standard = (&features - &self.means) / &self.scales;
+        softmax(self.weights.dot(&standard) + &self.biases)
+    }
+}
+
+impl Model {
+    pub fn save(&self, path: &Path) -> Result<(), ClassifierError> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ClassifierError> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn classifier(&self) -> &dyn Classifier {
+        match self {
+            Self::Lda(lda) => lda,
+            Self::Logistic(logistic) => logistic,
+        }
+    }
+}
+
+impl Classifier for Model {
+    fn labels(&self) -> &[u8] {
+        self.classifier().labels()
+    }
+
+    fn dimension(&self) -> usize {
+        self.classifier().dimension()
+    }
+
+    fn probabilities(&self, features: ArrayView1<f64>) -> Array1<f64> {
+        self.classifier().probabilities(features)
+    }
+}
+
+impl From<Lda> for Model {
+    fn from(lda: Lda) -> Self {
+        Self::Lda(lda)
+    }
+}
+
+impl From<LogisticRegression> for Model {
+    fn from(logistic: LogisticRegression) -> Self {
+        Self::Logistic(logistic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::gaussian;
+
+    /// Two Gaussian blobs in two dimensions, centred at 0 and 3
+    fn blobs() -> (Array2<f64>, Vec<u8>) {
+        let noise = gaussian(1, 400);
+        let features = Array2::from_shape_fn((200, 2), |(i, d)| noise[2 * i + d] + if i < 100 { 0.0 } else { 3.0 });
+        let labels = (0..200).map(|i| if i < 100 { 1 } else { 4 }).collect();
+        (features, labels)
+    }
+
+    fn accuracy(classifier: &dyn Classifier, features: &Array2<f64>, labels: &[u8]) -> f64 {
+        let correct = features.rows().into_iter().zip(labels).filter(|(row, &label)| classifier.predict(row.view()) == label).count();
+        correct as f64 / labels.len() as f64
+    }
+
+    #[test]
+    fn lda_and_logistic_regression_separate_blobs() {
+        let (features, labels) = blobs();
+        let lda = Lda::fit(features.view(), &labels, Shrinkage::LedoitWolf).unwrap();
+        let logistic = LogisticRegression::fit(features.view(), &labels, LogisticParams::default()).unwrap();
+        for classifier in [&lda as &dyn Classifier, &logistic] {
+            assert_eq!(classifier.labels(), [1, 4]);
+            assert_eq!(classifier.dimension(), 2);
+            assert!(accuracy(classifier, &features, &labels) > 0.95);
+            let probabilities = classifier.probabilities(ndarray::aview1(&[1.5, 1.5]));
+            assert!((probabilities.sum() - 1.0).abs() < 1e-12);
+            // The midpoint between the blobs is a toss-up
+            assert!((probabilities[0] - 0.5).abs() < 0.15, "{}", probabilities);
+        }
+    }
+
+    #[test]
+    fn model_round_trips_through_json() {
+        let (features, labels) = blobs();
+        let model = Model::Lda(Lda::fit(features.view(), &labels, Shrinkage::None).unwrap());
+        let restored: Model = serde_json::from_str(&serde_json::to_string(&model).unwrap()).unwrap();
+        let row = features.row(7);
+        // serde_json may round the last bit of a weight
+        let difference = &restored.classifier().probabilities(row) - &model.classifier().probabilities(row);
+        assert!(difference.iter().all(|d| d.abs() < 1e-12), "{}", difference);
+    }
+
+    #[test]
+    fn rejects_bad_training_sets() {
+        let (features, labels) = blobs();
+        assert!(matches!(Lda::fit(features.view(), &labels[..10], Shrinkage::None), Err(ClassifierError::Labels { .. })));
+        assert!(matches!(Lda::fit(features.view(), &[2; 200], Shrinkage::None), Err(ClassifierError::Classes(_))));
+        assert!(matches!(Lda::fit(features.view(), &labels, Shrinkage::Fixed(2.0)), Err(ClassifierError::Shrinkage(_))));
+        let constant = Array2::zeros((200, 2));
+        assert!(matches!(Lda::fit(constant.view(), &labels, Shrinkage::None), Err(ClassifierError::Singular)));
+    }
+}
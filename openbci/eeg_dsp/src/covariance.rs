@@ -0,0 +1,68 @@
+//! Spatial covariance of multichannel trials.
+//!
+//! Each trial is summarised by its channel covariance, normalised to unit
+//! trace so trials of different amplitude weigh the same. Short trials or
+//! many channels leave the sample covariance badly conditioned or singular;
+//! shrinking it toward a scaled identity keeps it positive definite, as
+//! CSP and the Riemannian methods need.
+
+use crate::sample::Sample;
+use ndarray::{Array2, ArrayView2};
+use serde::{Deserialize, Serialize};
+
+/// How a trial's sample covariance is regularised
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Shrinkage {
+    /// The sample covariance as it is
+    None,
+    /// Blend of the sample covariance and a scaled identity, 0 to 1
+    Fixed(f64),
+    /// Blend chosen per trial by the Ledoit-Wolf estimate
+    #[default]
+    LedoitWolf,
+}
+
+/// Covariance of one `channels x samples` trial, normalised to unit trace
+pub fn covariance<T: Sample>(trial: ArrayView2<T>, shrinkage: Shrinkage) -> Array2<f64> {
+    let mut centred = trial.mapv(|x| x.to_f64());
+    for mut row in centred.rows_mut() {
+        let mean = row.mean().unwrap_or(0.0);
+        row -= mean;
+    }
+    let cov = shrunk_covariance(&centred, shrinkage);
+    let trace = cov.diag().sum().max(f64::MIN_POSITIVE);
+    cov / trace
+}
+
+/// Shrunk covariance of `variables x observations` data already centred
+pub(crate) fn shrunk_covariance(centred: &Array2<f64>, shrinkage: Shrinkage) -> Array2<f64> {
+    let variables = centred.nrows();
+    let count = centred.ncols().max(1) as f64;
+    let mut cov = centred.dot(&centred.t()) / count;
+
+    let mu = cov.diag().sum() / variables.max(1) as f64;
+    let amount = match shrinkage {
+        Shrinkage::None => 0.0,
+        Shrinkage::Fixed(amount) => amount,
+        Shrinkage::LedoitWolf => {
+            // Spread of the sample covariance around mu I against the
+            // variance of the per-sample outer products around it
+            let norm2 = cov.iter().map(|x| x * x).sum::<f64>();
+            let dispersion = norm2 - 2.0 * mu * cov.diag().sum() + mu * mu * variables as f64;
+            let fourth = centred.columns().into_iter().map(|x| x.dot(&x).powi(2)).sum::<f64>() / count;
+            let noise = ((fourth - norm2) / count).min(dispersion);
+            if dispersion > 0.0 { noise / dispersion } else { 0.0 }
+        }
+    };
+    cov *= 1.0 - amount;
+    for i in 0..variables {
+        cov[[i, i]] += amount * mu;
+    }
+    cov
+}
+
+/// Covariances of `channels x samples` trials, one per trial
+pub fn covariances<T: Sample>(trials: &[ArrayView2<T>], shrinkage: Shrinkage) -> Vec<Array2<f64>> {
+    trials.iter().map(|trial| covariance(trial.view(), shrinkage)).collect()
+}
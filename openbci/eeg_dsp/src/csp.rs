@@ -0,0 +1,184 @@
+//! Two-class Common Spatial Patterns.
+//!
+//! CSP finds spatial filters whose output variance is as large as possible
+//! for one class while as small as possible for the other, by solving the
+//! generalized eigenproblem `C_a w = λ (C_a + C_b) w` of the mean class
+//! covariances. Each filter's eigenvalue is the share of its variance that
+//! comes from class A, so the filters at both ends of the spectrum are kept.
+//! The class covariances come from [`covariance`], optionally shrunk. A
+//! fitted [`Csp`] saves to and loads from JSON, so filters learned offline
+//! can be applied by the online classifier.
+
+use crate::covariance::{covariance, Shrinkage};
+use crate::linalg::{cholesky, invert_lower, symmetric_eigen};
+use crate::sample::Sample;
+use ndarray::{Array1, Array2, ArrayView2, Axis};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CspError {
+    #[error("CSP needs trials from both classes")]
+    Classes,
+    #[error("Trials must all have {expected} channels, got {got}")]
+    Channels { expected: usize, got: usize },
+    #[error("CSP with {channels} channels supports at most {max} filter pairs, asked for {pairs}")]
+    Pairs { pairs: usize, channels: usize, max: usize },
+    #[error("Shrinkage must be between 0 and 1, got {0}")]
+    Shrinkage(f64),
+    #[error("Class covariances are singular, try shrinkage")]
+    Singular,
+    #[error("Failed to access CSP file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to read CSP file: {0}")]
+    Format(#[from] serde_json::Error),
+}
+
+/// Fitted spatial filters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Csp {
+    /// One row per component, one column per channel
+    filters: Array2<f64>,
+    /// Share of each component's variance from class A, descending
+    eigenvalues: Array1<f64>,
+    shrinkage: Shrinkage,
+}
+
+impl Csp {
+    /// Fit `pairs` filter pairs from `channels x samples` trials of two
+    /// classes
+    pub fn fit<'a, T: Sample>(
+        class_a: &[ArrayView2<'a, T>],
+        class_b: &[ArrayView2<'a, T>],
+        pairs: usize,
+        shrinkage: Shrinkage,
+    ) -> Result<Self, CspError> {
+        if class_a.is_empty() || class_b.is_empty() {
+            return Err(CspError::Classes);
+        }
+        if let Shrinkage::Fixed(amount) = shrinkage {
+            if !(0.0..=1.0).contains(&amount) {
+                return Err(CspError::Shrinkage(amount));
+            }
+        }
+        let channels = class_a[0].nrows();
+        if let Some(trial) = class_a.iter().chain(class_b).find(|t| t.nrows() != channels) {
+            return Err(CspError::Channels { expected: channels, got: trial.nrows() });
+        }
+        if pairs == 0 || pairs * 2 > channels {
+            return Err(CspError::Pairs { pairs, channels, max: channels / 2 });
+        }
+
+        let mean_covariance = |trials: &[ArrayView2<T>]| {
+            trials.iter().fold(Array2::<f64>::zeros((channels, channels)), |sum, trial| {
+                sum + covariance(trial.view(), shrinkage)
+            }) / trials.len() as f64
+        };
+        let cov_a = mean_covariance(class_a);
+        let composite = &cov_a + &mean_covariance(class_b);
+
+        // With composite = L Lᵀ the problem becomes the ordinary symmetric
+        // one of L⁻¹ C_a L⁻ᵀ, whose eigenvectors v give filters L⁻ᵀ v
+        let whitening = invert_lower(&cholesky(&composite).ok_or(CspError::Singular)?);
+        let whitened = whitening.dot(&cov_a).dot(&whitening.t());
+        let (values, vectors) = symmetric_eigen(&whitened);
+        let all = vectors.t().dot(&whitening);
+
+        let picks: Vec<usize> = (0..pairs).chain(channels - pairs..channels).collect();
+        Ok(Self {
+            filters: all.select(Axis(0), &picks),
+            eigenvalues: values.select(Axis(0), &picks),
+            shrinkage,
+        })
+    }
+
+    /// One row per component, one column per channel
+    pub fn filters(&self) -> &Array2<f64> {
+        &self.filters
+    }
+
+    pub fn eigenvalues(&self) -> &Array1<f64> {
+        &self.eigenvalues
+    }
+
+    pub fn shrinkage(&self) -> Shrinkage {
+        self.shrinkage
+    }
+
+    pub fn channels(&self) -> usize {
+        self.filters.ncols()
+    }
+
+    pub fn components(&self) -> usize {
+        self.filters.nrows()
+    }
+
+    /// Component signals of a `channels x samples` trial
+    pub fn project<T: Sample>(&self, trial: ArrayView2<T>) -> Array2<f64> {
+        self.filters.dot(&trial.mapv(|x| x.to_f64()))
+    }
+
+    /// Log of each component's share of the total variance, the usual
+    /// classifier input
+    pub fn features<T: Sample>(&self, trial: ArrayView2<T>) -> Array1<f64> {
+        let variances = self.project(trial).map_axis(Axis(1), |c| c.var(0.0));
+        let total = variances.sum().max(f64::MIN_POSITIVE);
+        variances.mapv(|v| (v / total).max(f64::MIN_POSITIVE).ln())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), CspError> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, CspError> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::gaussian;
+
+    /// Trials of four mixed sources where `loud` has five times the
+    /// amplitude of the others
+    fn trials(loud: usize, seed: u64) -> Vec<Array2<f64>> {
+        let mixing = ndarray::arr2(&[[1.0, 0.5, 0.2, 0.0], [0.3, 1.0, 0.0, 0.4], [0.0, 0.6, 1.0, 0.2], [0.5, 0.0, 0.3, 1.0]]);
+        (0..20)
+            .map(|trial| {
+                let mut sources = Array2::from_shape_vec((4, 200), gaussian(seed * 100 + trial, 800)).unwrap();
+                sources.row_mut(loud).mapv_inplace(|v| 5.0 * v);
+                mixing.dot(&sources)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn separates_sources_of_different_variance() {
+        let (a, b) = (trials(0, 1), trials(1, 2));
+        let (views_a, views_b): (Vec<_>, Vec<_>) = (a.iter().map(|t| t.view()).collect(), b.iter().map(|t| t.view()).collect());
+        let csp = Csp::fit(&views_a, &views_b, 1, Shrinkage::None).unwrap();
+        assert_eq!((csp.components(), csp.channels()), (2, 4));
+        let eigenvalues = csp.eigenvalues();
+        assert!(eigenvalues[0] > 0.9 && eigenvalues[1] < 0.1, "{}", eigenvalues);
+
+        let mean = |trials: &[Array2<f64>]| {
+            trials.iter().fold(Array1::<f64>::zeros(2), |sum, t| sum + csp.features(t.view())) / trials.len() as f64
+        };
+        let (features_a, features_b) = (mean(&trials(0, 3)), mean(&trials(1, 4)));
+        // The first filter passes class A's loud source, the last class B's
+        assert!(features_a[0] > features_b[0] + 1.0 && features_b[1] > features_a[1] + 1.0, "{} vs {}", features_a, features_b);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let a = trials(0, 1);
+        let views: Vec<_> = a.iter().map(|t| t.view()).collect();
+        assert!(matches!(Csp::fit(&views, &[], 1, Shrinkage::None), Err(CspError::Classes)));
+        assert!(matches!(Csp::fit(&views, &views, 3, Shrinkage::None), Err(CspError::Pairs { max: 2, .. })));
+        assert!(matches!(Csp::fit(&views, &views, 1, Shrinkage::Fixed(1.5)), Err(CspError::Shrinkage(_))));
+    }
+}
@@ -0,0 +1,67 @@
+//! Sliding windows over a multichannel stream, shared by the feature
+//! extractors.
+
+/// Last `window` samples of every channel, with a window due every `step`
+/// samples once it is full
+#[derive(Debug, Clone)]
+pub(crate) struct History {
+    channels: usize,
+    window: usize,
+    step: usize,
+    /// Channel by channel, oldest at `head`
+    data: Vec<f64>,
+    head: usize,
+    filled: usize,
+    /// Samples since the last window, `None` before the first
+    since_step: Option<usize>,
+}
+
+impl History {
+    pub(crate) fn new(channels: usize, window: usize, step: usize) -> Self {
+        Self {
+            channels,
+            window,
+            step,
+            data: vec![0.0; channels * window],
+            head: 0,
+            filled: 0,
+            since_step: None,
+        }
+    }
+
+    pub(crate) fn set_step(&mut self, step: usize) {
+        self.step = step;
+    }
+
+    /// Add one sample of every channel; whether a window is due
+    pub(crate) fn push<T: crate::sample::Sample>(&mut self, frame: &[T]) -> bool {
+        debug_assert_eq!(frame.len(), self.channels);
+        for (channel, value) in frame.iter().enumerate() {
+            self.data[channel * self.window + self.head] = value.to_f64();
+        }
+        self.head = (self.head + 1) % self.window;
+        self.filled = (self.filled + 1).min(self.window);
+        if let Some(since) = &mut self.since_step {
+            *since += 1;
+        }
+        if self.filled < self.window || self.since_step.is_some_and(|since| since < self.step) {
+            return false;
+        }
+        self.since_step = Some(0);
+        true
+    }
+
+    /// Copy the window of `channel`, oldest first, into `out`
+    pub(crate) fn channel(&self, channel: usize, out: &mut [f64]) {
+        let history = &self.data[channel * self.window..(channel + 1) * self.window];
+        let ordered = history[self.head..].iter().chain(&history[..self.head]);
+        for (slot, &value) in out.iter_mut().zip(ordered) {
+            *slot = value;
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.data.fill(0.0);
+        (self.head, self.filled, self.since_step) = (0, 0, None);
+    }
+}
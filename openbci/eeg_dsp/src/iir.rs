@@ -0,0 +1,523 @@
+//! Streaming IIR filters as cascades of second-order sections.
+//!
+//! A [`FilterSpec`] names the response (low-pass, high-pass, band-pass or
+//! band-stop), the prototype (Butterworth, or Chebyshev type I with its
+//! pass band ripple) and the order. [`FilterSpec::design`] turns it into
+//! biquads for a sample rate: the analog prototype's poles are scaled or
+//! split to the response, pre-warped and mapped with the bilinear
+//! transform, then paired into sections with the gain spread evenly over
+//! them. A [`FilterBank`] runs a cascade over every channel with its own
+//! state, in transposed direct form II, without allocating once built, or
+//! forward and backward over a whole recording for zero phase.
+
+use crate::complex::Complex;
+use crate::sample::Sample;
+use std::f64::consts::PI;
+use thiserror::Error;
+
+/// Highest prototype order a spec may ask for
+pub const MAX_ORDER: usize = 16;
+
+/// Poles closer than this to the real axis count as real
+const REAL_TOLERANCE: f64 = 1e-10;
+
+/// Frequency response, edges in Hz
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Response {
+    LowPass(f64),
+    HighPass(f64),
+    BandPass { low: f64, high: f64 },
+    BandStop { low: f64, high: f64 },
+}
+
+/// Analog prototype the filter is designed from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Prototype {
+    /// Maximally flat pass band
+    Butterworth,
+    /// Steeper edge for `ripple_db` of pass band ripple; the edges are
+    /// where the gain leaves the ripple band
+    Chebyshev { ripple_db: f64 },
+}
+
+/// Filter to design. Band-pass and band-stop filters have twice the
+/// prototype's order, one section per prototype order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterSpec {
+    pub prototype: Prototype,
+    pub response: Response,
+    pub order: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum DesignError {
+    #[error("Filter order must be 1 to {MAX_ORDER}, got {0}")]
+    Order(usize),
+    #[error("Cutoff {cutoff} Hz must be between 0 and {nyquist} Hz (half the sample rate)")]
+    Cutoff { cutoff: f64, nyquist: f64 },
+    #[error("Band {low}-{high} Hz needs its low edge below the high edge")]
+    Band { low: f64, high: f64 },
+    #[error("Chebyshev ripple must be positive, got {0} dB")]
+    Ripple(f64),
+}
+
+/// One second-order section, normalized to `a0 = 1`:
+/// `H(z) = (b0 + b1 z^-1 + b2 z^-2) / (1 + a1 z^-1 + a2 z^-2)`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Biquad<T = f64> {
+    pub b0: T,
+    pub b1: T,
+    pub b2: T,
+    pub a1: T,
+    pub a2: T,
+}
+
+impl FilterSpec {
+    pub fn butterworth(order: usize, response: Response) -> Self {
+        Self { prototype: Prototype::Butterworth, response, order }
+    }
+
+    pub fn chebyshev(order: usize, ripple_db: f64, response: Response) -> Self {
+        Self { prototype: Prototype::Chebyshev { ripple_db }, response, order }
+    }
+
+    /// Sections of the filter at `sample_rate` Hz
+    pub fn design(&self, sample_rate: f64) -> Result<Vec<Biquad>, DesignError> {
+        self.validate(sample_rate)?;
+        let (prototype, target_gain) = self.prototype_poles();
+        let fs2 = 2.0 * sample_rate;
+        // Pre-warped analog edge, so the digital edge lands on the spec
+        let warp = |hz: f64| fs2 * (PI * hz / sample_rate).tan();
+
+        let mut poles = Vec::with_capacity(2 * self.order);
+        let mut zeros = Vec::with_capacity(2 * self.order);
+        let reference = match self.response {
+            Response::LowPass(cutoff) => {
+                let wc = warp(cutoff);
+                poles.extend(prototype.iter().map(|&p| p * wc));
+                Complex::real(1.0)
+            }
+            Response::HighPass(cutoff) => {
+                let wc = warp(cutoff);
+                poles.extend(prototype.iter().map(|&p| Complex::real(wc) / p));
+                zeros.extend(std::iter::repeat_n(Complex::real(0.0), self.order));
+                Complex::real(-1.0)
+            }
+            Response::BandPass { low, high } => {
+                let (w1, w2) = (warp(low), warp(high));
+                let (w0, bandwidth) = ((w1 * w2).sqrt(), w2 - w1);
+                for &p in &prototype {
+                    let half = p * (bandwidth / 2.0);
+                    let offset = (half * half - Complex::real(w0 * w0)).sqrt();
+                    poles.extend([half + offset, half - offset]);
+                }
+                zeros.extend(std::iter::repeat_n(Complex::real(0.0), self.order));
+                // The center frequency, back through the warp
+                Complex::from_angle(2.0 * (w0 / fs2).atan())
+            }
+            Response::BandStop { low, high } => {
+                let (w1, w2) = (warp(low), warp(high));
+                let (w0, bandwidth) = ((w1 * w2).sqrt(), w2 - w1);
+                for &p in &prototype {
+                    let half = Complex::real(bandwidth / 2.0) / p;
+                    let offset = (half * half - Complex::real(w0 * w0)).sqrt();
+                    poles.extend([half + offset, half - offset]);
+                    zeros.extend([Complex::new(0.0, w0), Complex::new(0.0, -w0)]);
+                }
+                Complex::real(1.0)
+            }
+        };
+
+        // Bilinear transform; zeros at infinity land on Nyquist
+        let bilinear = |s: Complex| (Complex::real(fs2) + s) / (Complex::real(fs2) - s);
+        let poles: Vec<Complex> = poles.into_iter().map(bilinear).collect();
+        let mut zeros: Vec<Complex> = zeros.into_iter().map(bilinear).collect();
+        zeros.resize(poles.len(), Complex::real(-1.0));
+
+        let pole_groups = pair(poles);
+        let zero_groups = pair(zeros);
+        let mut sections: Vec<Biquad> = pole_groups
+            .iter()
+            .zip(&zero_groups)
+            .map(|(poles, zeros)| {
+                let [b0, b1, b2] = polynomial(zeros);
+                let [_, a1, a2] = polynomial(poles);
+                Biquad { b0, b1, b2, a1, a2 }
+            })
+            .collect();
+
+        let gain: f64 = sections.iter().map(|s| s.evaluate(reference).norm()).product();
+        let scale = (target_gain / gain).powf(1.0 / sections.len() as f64);
+        for section in &mut sections {
+            section.b0 *= scale;
+            section.b1 *= scale;
+            section.b2 *= scale;
+        }
+        Ok(sections)
+    }
+
+    fn validate(&self, sample_rate: f64) -> Result<(), DesignError> {
+        if !(1..=MAX_ORDER).contains(&self.order) {
+            return Err(DesignError::Order(self.order));
+        }
+        if let Prototype::Chebyshev { ripple_db } = self.prototype {
+            if ripple_db.is_nan() || ripple_db <= 0.0 {
+                return Err(DesignError::Ripple(ripple_db));
+            }
+        }
+        let nyquist = sample_rate / 2.0;
+        let check = |cutoff: f64| {
+            if cutoff > 0.0 && cutoff < nyquist {
+                Ok(())
+            } else {
+                Err(DesignError::Cutoff { cutoff, nyquist })
+            }
+        };
+        match self.response {
+            Response::LowPass(cutoff) | Response::HighPass(cutoff) => check(cutoff),
+            Response::BandPass { low, high } | Response::BandStop { low, high } => {
+                check(low)?;
+                check(high)?;
+                if low < high {
+                    Ok(())
+                } else {
+                    Err(DesignError::Band { low, high })
+                }
+            }
+        }
+    }
+
+    /// Poles of the normalized analog low-pass, and the gain the pass band
+    /// reference frequency should have
+    fn prototype_poles(&self) -> (Vec<Complex>, f64) {
+        let n = self.order as f64;
+        let angles = (0..self.order).map(|k| PI * (2 * k + 1) as f64 / (2.0 * n));
+        match self.prototype {
+            Prototype::Butterworth => (angles.map(|theta| Complex::new(-theta.sin(), theta.cos())).collect(), 1.0),
+            Prototype::Chebyshev { ripple_db } => {
+                let epsilon = (10f64.powf(ripple_db / 10.0) - 1.0).sqrt();
+                let mu = (1.0 / epsilon).asinh() / n;
+                let poles = angles.map(|theta| Complex::new(-mu.sinh() * theta.sin(), mu.cosh() * theta.cos())).collect();
+                // Even orders start the pass band at the bottom of the ripple
+                let gain = if self.order.is_multiple_of(2) { 1.0 / (1.0 + epsilon * epsilon).sqrt() } else { 1.0 };
+                (poles, gain)
+            }
+        }
+    }
+}
+
+/// Roots grouped into conjugate pairs and pairs of real roots, with a real
+/// root left alone for odd counts. Real roots pair from opposite ends so a
+/// band-pass section gets one zero at DC and one at Nyquist.
+fn pair(roots: Vec<Complex>) -> Vec<Vec<Complex>> {
+    let (mut real, complex): (Vec<Complex>, Vec<Complex>) = roots.into_iter().partition(|r| r.im.abs() < REAL_TOLERANCE);
+    let mut groups: Vec<Vec<Complex>> = complex.into_iter().filter(|r| r.im > 0.0).map(|r| vec![r, r.conj()]).collect();
+    real.sort_by(|a, b| a.re.total_cmp(&b.re));
+    while real.len() >= 2 {
+        let (low, high) = (real.remove(0), real.pop().unwrap());
+        groups.push(vec![Complex::real(low.re), Complex::real(high.re)]);
+    }
+    groups.extend(real.into_iter().map(|r| vec![Complex::real(r.re)]));
+    groups
+}
+
+/// Coefficients of `prod (1 - r z^-1)` over one or two roots
+fn polynomial(roots: &[Complex]) -> [f64; 3] {
+    match roots {
+        [r] => [1.0, -r.re, 0.0],
+        [r1, r2] => [1.0, -(r1.re + r2.re), (*r1 * *r2).re],
+        _ => unreachable!("sections have one or two roots"),
+    }
+}
+
+impl Biquad {
+    /// Notch at `frequency` Hz with quality factor `q` (bandwidth
+    /// `frequency / q`)
+    pub fn notch(frequency: f64, sample_rate: f64, q: f64) -> Self {
+        let w0 = 2.0 * PI * frequency / sample_rate;
+        let (cos, alpha) = (w0.cos(), w0.sin() / (2.0 * q));
+        let a0 = 1.0 + alpha;
+        Self {
+            b0: 1.0 / a0,
+            b1: -2.0 * cos / a0,
+            b2: 1.0 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+        }
+    }
+
+    /// `H(z)` at a point of the z plane
+    fn evaluate(&self, z: Complex) -> Complex {
+        let inverse = Complex::real(1.0) / z;
+        let inverse2 = inverse * inverse;
+        let numerator = Complex::real(self.b0) + inverse * self.b1 + inverse2 * self.b2;
+        let denominator = Complex::real(1.0) + inverse * self.a1 + inverse2 * self.a2;
+        numerator / denominator
+    }
+
+    /// Gain at `frequency` Hz
+    pub fn magnitude(&self, frequency: f64, sample_rate: f64) -> f64 {
+        self.evaluate(Complex::from_angle(2.0 * PI * frequency / sample_rate)).norm()
+    }
+
+    /// Gain for a constant input
+    pub fn dc_gain(&self) -> f64 {
+        (self.b0 + self.b1 + self.b2) / (1.0 + self.a1 + self.a2)
+    }
+
+    /// The section in another sample type
+    pub fn cast<T: Sample>(&self) -> Biquad<T> {
+        Biquad {
+            b0: T::from_f64(self.b0),
+            b1: T::from_f64(self.b1),
+            b2: T::from_f64(self.b2),
+            a1: T::from_f64(self.a1),
+            a2: T::from_f64(self.a2),
+        }
+    }
+}
+
+/// A cascade of sections over every channel, each with its own state
+#[derive(Debug, Clone)]
+pub struct FilterBank<T: Sample = f32> {
+    /// The design, for steady-state starts and responses
+    design: Vec<Biquad>,
+    sections: Vec<Biquad<T>>,
+    /// `[s1, s2]` of every section, channel by channel
+    state: Vec<[T; 2]>,
+    channels: usize,
+}
+
+impl<T: Sample> FilterBank<T> {
+    pub fn new(sections: &[Biquad], channels: usize) -> Self {
+        Self {
+            design: sections.to_vec(),
+            sections: sections.iter().map(Biquad::cast).collect(),
+            state: vec![[T::default(); 2]; sections.len() * channels],
+            channels,
+        }
+    }
+
+    /// The specs one after the other, designed at `sample_rate` Hz
+    pub fn from_specs(specs: &[FilterSpec], sample_rate: f64, channels: usize) -> Result<Self, DesignError> {
+        let mut sections = Vec::new();
+        for spec in specs {
+            sections.extend(spec.design(sample_rate)?);
+        }
+        Ok(Self::new(&sections, channels))
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn sections(&self) -> &[Biquad] {
+        &self.design
+    }
+
+    /// Gain of the whole cascade at `frequency` Hz
+    pub fn magnitude(&self, frequency: f64, sample_rate: f64) -> f64 {
+        self.design.iter().map(|s| s.magnitude(frequency, sample_rate)).product()
+    }
+
+    /// Forget the past input of every channel
+    pub fn reset(&mut self) {
+        self.state.fill([T::default(); 2]);
+    }
+
+    /// Set every channel's state as if `frame` had always been its input,
+    /// so a DC offset does not ring through the start of a recording
+    pub fn settle(&mut self, frame: &[T]) {
+        debug_assert_eq!(frame.len(), self.channels);
+        for (channel, &value) in frame.iter().enumerate() {
+            self.settle_channel(channel, value);
+        }
+    }
+
+    /// Set one channel's state as if `value` had always been its input
+    pub fn settle_channel(&mut self, channel: usize, value: T) {
+        let per_channel = self.design.len();
+        let mut x = value.to_f64();
+        let state = &mut self.state[channel * per_channel..(channel + 1) * per_channel];
+        for (section, state) in self.design.iter().zip(state) {
+            let y = section.dc_gain() * x;
+            let s2 = section.b2 * x - section.a2 * y;
+            let s1 = section.b1 * x - section.a1 * y + s2;
+            *state = [T::from_f64(s1), T::from_f64(s2)];
+            x = y;
+        }
+    }
+
+    /// Filter one sample of every channel in place
+    pub fn process_frame(&mut self, frame: &mut [T]) {
+        debug_assert_eq!(frame.len(), self.channels);
+        let per_channel = self.sections.len();
+        for (value, state) in frame.iter_mut().zip(self.state.chunks_exact_mut(per_channel.max(1))) {
+            *value = run(&self.sections, state, *value);
+        }
+    }
+
+    /// Filter frames of one value per channel, one after the other
+    pub fn process_interleaved(&mut self, data: &mut [T]) {
+        for frame in data.chunks_exact_mut(self.channels) {
+            self.process_frame(frame);
+        }
+    }
+
+    /// Filter consecutive samples of one channel in place
+    pub fn process_channel(&mut self, channel: usize, block: &mut [T]) {
+        let per_channel = self.sections.len();
+        let state = &mut self.state[channel * per_channel..(channel + 1) * per_channel];
+        for value in block {
+            *value = run(&self.sections, state, *value);
+        }
+    }
+
+    /// Filter a whole recording of one channel forward and then backward,
+    /// each pass settled on its first sample, so the output has no phase
+    /// shift and twice the attenuation
+    pub fn filtfilt(&mut self, channel: usize, block: &mut [T]) {
+        let Some(&first) = block.first() else { return };
+        self.settle_channel(channel, first);
+        self.process_channel(channel, block);
+        block.reverse();
+        self.settle_channel(channel, block[0]);
+        self.process_channel(channel, block);
+        block.reverse();
+    }
+}
+
+/// One input through the cascade
+#[inline]
+fn run<T: Sample>(sections: &[Biquad<T>], state: &mut [[T; 2]], mut x: T) -> T {
+    for (s, [s1, s2]) in sections.iter().zip(state) {
+        let y = s.b0 * x + *s1;
+        *s1 = s.b1 * x - s.a1 * y + *s2;
+        *s2 = s.b2 * x - s.a2 * y;
+        x = y;
+    }
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sine;
+    use std::f64::consts::FRAC_1_SQRT_2;
+
+    const FS: f64 = 250.0;
+
+    fn gain(spec: FilterSpec, frequency: f64) -> f64 {
+        FilterBank::<f64>::from_specs(&[spec], FS, 1).unwrap().magnitude(frequency, FS)
+    }
+
+    #[test]
+    fn butterworth_is_3db_down_at_its_cutoff() {
+        for order in 1..=6 {
+            let low = FilterSpec::butterworth(order, Response::LowPass(30.0));
+            assert!((gain(low, 30.0) - FRAC_1_SQRT_2).abs() < 1e-9, "order {} low-pass", order);
+            assert!((gain(low, 0.0) - 1.0).abs() < 1e-9);
+            let high = FilterSpec::butterworth(order, Response::HighPass(1.0));
+            assert!((gain(high, 1.0) - FRAC_1_SQRT_2).abs() < 1e-9, "order {} high-pass", order);
+            assert!(gain(high, 0.0) < 1e-9);
+        }
+        let band = FilterSpec::butterworth(4, Response::BandPass { low: 8.0, high: 30.0 });
+        assert!((gain(band, 8.0) - FRAC_1_SQRT_2).abs() < 1e-9);
+        assert!((gain(band, 30.0) - FRAC_1_SQRT_2).abs() < 1e-9);
+        assert!((gain(band, (8.0f64 * 30.0).sqrt()) - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn chebyshev_leaves_the_ripple_band_at_its_cutoff() {
+        let ripple_db = 1.0;
+        let edge = 10f64.powf(-ripple_db / 20.0);
+        for order in 1..=6 {
+            let spec = FilterSpec::chebyshev(order, ripple_db, Response::LowPass(30.0));
+            assert!((gain(spec, 30.0) - edge).abs() < 1e-9, "order {}", order);
+            for k in 0..30 {
+                let g = gain(spec, k as f64);
+                assert!(g <= 1.0 + 1e-9 && g >= edge - 1e-9, "order {} at {} Hz: {}", order, k, g);
+            }
+        }
+    }
+
+    #[test]
+    fn notch_removes_its_frequency_only() {
+        let notch = Biquad::notch(50.0, FS, 30.0);
+        assert!(notch.magnitude(50.0, FS) < 1e-9);
+        assert!((notch.dc_gain() - 1.0).abs() < 1e-12);
+        assert!((notch.magnitude(10.0, FS) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn designs_are_stable() {
+        let specs = [
+            FilterSpec::butterworth(8, Response::LowPass(40.0)),
+            FilterSpec::chebyshev(5, 0.5, Response::HighPass(0.5)),
+            FilterSpec::butterworth(4, Response::BandPass { low: 8.0, high: 13.0 }),
+            FilterSpec::chebyshev(3, 1.0, Response::BandStop { low: 48.0, high: 52.0 }),
+        ];
+        for spec in specs {
+            for s in spec.design(FS).unwrap() {
+                // Both poles inside the unit circle (the stability triangle)
+                assert!(s.a2.abs() < 1.0 && s.a1.abs() < 1.0 + s.a2, "{:?}: {:?}", spec, s);
+            }
+        }
+    }
+
+    #[test]
+    fn roots_pair_into_conjugates_and_opposite_reals() {
+        let roots = vec![
+            Complex::new(0.5, 0.25),
+            Complex::real(-0.9),
+            Complex::new(0.5, -0.25),
+            Complex::real(0.1),
+            Complex::real(0.9),
+        ];
+        let groups = pair(roots);
+        assert_eq!(
+            groups,
+            vec![
+                vec![Complex::new(0.5, 0.25), Complex::new(0.5, -0.25)],
+                vec![Complex::real(-0.9), Complex::real(0.9)],
+                vec![Complex::real(0.1)],
+            ]
+        );
+        let band = FilterSpec::butterworth(3, Response::BandPass { low: 8.0, high: 30.0 }).design(FS).unwrap();
+        assert_eq!(band.len(), 3);
+        // Each band-pass section has one zero at DC and one at Nyquist
+        assert!(band.iter().all(|s| s.dc_gain().abs() < 1e-9 && s.magnitude(FS / 2.0, FS) < 1e-9));
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        assert_eq!(FilterSpec::butterworth(0, Response::LowPass(30.0)).design(FS), Err(DesignError::Order(0)));
+        assert!(matches!(FilterSpec::butterworth(2, Response::LowPass(200.0)).design(FS), Err(DesignError::Cutoff { .. })));
+        assert!(matches!(
+            FilterSpec::butterworth(2, Response::BandPass { low: 30.0, high: 8.0 }).design(FS),
+            Err(DesignError::Band { .. })
+        ));
+        assert_eq!(FilterSpec::chebyshev(2, 0.0, Response::LowPass(30.0)).design(FS), Err(DesignError::Ripple(0.0)));
+    }
+
+    #[test]
+    fn filtfilt_keeps_phase() {
+        let mut signal = sine(10.0, FS, 1000);
+        let original = signal.clone();
+        let mut bank = FilterBank::<f64>::from_specs(&[FilterSpec::butterworth(4, Response::LowPass(40.0))], FS, 1).unwrap();
+        bank.filtfilt(0, &mut signal);
+        let gain = bank.magnitude(10.0, FS).powi(2);
+        for t in 200..800 {
+            assert!((signal[t] - gain * original[t]).abs() < 1e-3, "sample {}", t);
+        }
+    }
+
+    #[test]
+    fn settled_high_pass_starts_without_a_transient() {
+        let mut bank = FilterBank::<f32>::from_specs(&[FilterSpec::butterworth(2, Response::HighPass(1.0))], FS, 2).unwrap();
+        let mut frame = [1000.0, -500.0];
+        bank.settle(&frame);
+        bank.process_frame(&mut frame);
+        assert!(frame.iter().all(|v| v.abs() < 1e-2), "{:?}", frame);
+    }
+}
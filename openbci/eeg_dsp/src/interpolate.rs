@@ -0,0 +1,167 @@
+//! Electrode positions and bad-channel interpolation.
+//!
+//! [`position`] places a 10-10 label on the unit sphere of an idealized
+//! head: Cz on top, the 10% ring (Fpz, T7, Oz, T8) on the equator, x to the
+//! right, y to the nose. Rows run from Fp to O at 22.5° steps of the
+//! midline and columns out to the ring at quarter steps, which puts every
+//! position within a few degrees of the usual spherical fits; that is
+//! plenty for picking and weighting neighbours. A
+//! [`ChannelInterpolation`] replaces each bad channel with the
+//! inverse-square-distance weighted mean of its nearest good ones.
+
+use crate::sample::Sample;
+use ndarray::ArrayViewMut2;
+use thiserror::Error;
+
+/// Good channels a bad one is interpolated from, at most
+pub const NEIGHBORS: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum InterpolationError {
+    #[error("Channel '{0}' has no known 10-10 position to interpolate it at")]
+    Position(String),
+    #[error("No good channel with a known position to interpolate '{0}' from")]
+    NoNeighbors(String),
+    #[error("Channel {index} is out of range for {channels} channels")]
+    Index { index: usize, channels: usize },
+}
+
+/// Midline angle of a row from Cz, towards the nose
+fn row_angle(row: &str) -> Option<f64> {
+    Some(match row {
+        "fp" => 90.0,
+        "af" => 67.5,
+        "f" => 45.0,
+        "fc" | "ft" => 22.5,
+        "c" | "t" => 0.0,
+        "cp" | "tp" => -22.5,
+        "p" => -45.0,
+        "po" => -67.5,
+        "o" => -90.0,
+        _ => return None,
+    })
+}
+
+/// Unit vector of a 10-10 label (ignoring case; the old T3, T4, T5, T6 and
+/// the A1, A2, M1, M2 ear and mastoid sites too). A label annotated after
+/// an underscore, e.g. `C3_left_motor`, is placed by its first part.
+pub fn position(label: &str) -> Option<[f64; 3]> {
+    let label = label.trim().to_lowercase();
+    let label = label.split('_').next().unwrap_or_default();
+    let label = match label {
+        "t3" => "t7",
+        "t4" => "t8",
+        "t5" => "p7",
+        "t6" => "p8",
+        other => other,
+    };
+    // Ear lobes level with Cz, mastoids just behind, both 30° below the ring
+    let (down, behind) = (30f64.to_radians(), 20f64.to_radians());
+    match label {
+        "a1" => return Some([-down.cos(), 0.0, -down.sin()]),
+        "a2" => return Some([down.cos(), 0.0, -down.sin()]),
+        "m1" => return Some([-down.cos() * behind.cos(), -down.cos() * behind.sin(), -down.sin()]),
+        "m2" => return Some([down.cos() * behind.cos(), -down.cos() * behind.sin(), -down.sin()]),
+        _ => {}
+    }
+
+    let split = label.find(|c: char| c.is_ascii_digit() || c == 'z')?;
+    let (row, column) = label.split_at(split);
+    let a = row_angle(row)?.to_radians();
+    let (side, fraction) = match column {
+        "z" => (0.0, 0.0),
+        _ => {
+            let number: u32 = column.parse().ok().filter(|&n| n > 0)?;
+            let side = if number % 2 == 1 { -1.0 } else { 1.0 };
+            // Fp1, Fp2, O1 and O2 sit on the ring itself
+            let fraction = if matches!(row, "fp" | "o") { 1.0 } else { number.div_ceil(2) as f64 / 4.0 };
+            (side, fraction)
+        }
+    };
+    let midline = [0.0, a.sin(), a.cos()];
+    if fraction == 0.0 {
+        return Some(midline);
+    }
+    // Where the row meets the ring: Fp1 18° from the nose, T7 at 90°, O1 162°
+    let azimuth = (90.0 - 0.8 * a.to_degrees()).to_radians();
+    let ring = [side * azimuth.sin(), azimuth.cos(), 0.0];
+    let omega = dot(&midline, &ring).clamp(-1.0, 1.0).acos();
+    let (from, to) = (((1.0 - fraction) * omega).sin(), (fraction * omega).sin());
+    let point: Vec<f64> = midline.iter().zip(&ring).map(|(m, r)| (from * m + to * r) / omega.sin()).collect();
+    Some([point[0], point[1], point[2]])
+}
+
+fn dot(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Bad channels of a recording and the weights that rebuild them
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelInterpolation {
+    channels: usize,
+    /// Bad channel and its neighbours with their weights
+    targets: Vec<(usize, Vec<(usize, f64)>)>,
+}
+
+impl ChannelInterpolation {
+    /// Interpolate channels `bad` of a recording labelled `labels` from up
+    /// to `neighbors` of the nearest other channels with known positions
+    pub fn new<S: AsRef<str>>(labels: &[S], bad: &[usize], neighbors: usize) -> Result<Self, InterpolationError> {
+        let channels = labels.len();
+        if let Some(&index) = bad.iter().find(|&&index| index >= channels) {
+            return Err(InterpolationError::Index { index, channels });
+        }
+        let positions: Vec<Option<[f64; 3]>> = labels.iter().map(|l| position(l.as_ref())).collect();
+        let mut targets = Vec::with_capacity(bad.len());
+        for &index in bad {
+            let label = labels[index].as_ref();
+            let target = positions[index].ok_or_else(|| InterpolationError::Position(label.to_string()))?;
+            let mut nearest: Vec<(usize, f64)> = positions
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !bad.contains(i))
+                .filter_map(|(i, p)| p.map(|p| (i, dot(&target, &p).clamp(-1.0, 1.0).acos())))
+                .collect();
+            if nearest.is_empty() {
+                return Err(InterpolationError::NoNeighbors(label.to_string()));
+            }
+            nearest.sort_by(|a, b| a.1.total_cmp(&b.1));
+            nearest.truncate(neighbors.max(1));
+            let weights: Vec<(usize, f64)> =
+                nearest.iter().map(|&(i, distance)| (i, 1.0 / distance.max(1e-6).powi(2))).collect();
+            let total: f64 = weights.iter().map(|(_, w)| w).sum();
+            targets.push((index, weights.into_iter().map(|(i, w)| (i, w / total)).collect()));
+        }
+        Ok(Self { channels, targets })
+    }
+
+    /// Channels that are rebuilt
+    pub fn bad(&self) -> Vec<usize> {
+        self.targets.iter().map(|(index, _)| *index).collect()
+    }
+
+    /// Neighbours and weights of bad channel `index`
+    pub fn weights(&self, index: usize) -> Option<&[(usize, f64)]> {
+        self.targets.iter().find(|(i, _)| *i == index).map(|(_, w)| w.as_slice())
+    }
+
+    /// Rebuild the bad channels of one frame in place
+    pub fn apply_frame<T: Sample>(&self, frame: &mut [T]) {
+        debug_assert_eq!(frame.len(), self.channels);
+        for (index, weights) in &self.targets {
+            let value = weights.iter().map(|&(i, w)| w * frame[i].to_f64()).sum();
+            frame[*index] = T::from_f64(value);
+        }
+    }
+
+    /// Rebuild the bad channels of `data`, `channels x samples`, in place
+    pub fn apply<T: Sample>(&self, mut data: ArrayViewMut2<T>) {
+        debug_assert_eq!(data.nrows(), self.channels);
+        for mut column in data.columns_mut() {
+            for (index, weights) in &self.targets {
+                let value = weights.iter().map(|&(i, w)| w * column[i].to_f64()).sum();
+                column[*index] = T::from_f64(value);
+            }
+        }
+    }
+}
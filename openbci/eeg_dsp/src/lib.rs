@@ -0,0 +1,67 @@
+//! Signal processing for OpenBCI EEG, shared by offline export and online
+//! classification.
+//!
+//! Filters are designed once from a cutoff spec at runtime and then run
+//! sample by sample or block by block without allocating, with their own
+//! state for every channel, in `f32` or `f64`. Spectra come from Welch's
+//! method or short-time spectrograms over whole recordings, or from a stream
+//! updated every hop for live displays, and band-power feature vectors from
+//! either for classifiers, alongside Hjorth parameters, other time-domain
+//! features and wavelet level energies. Common Spatial Patterns learn
+//! spatial filters from labelled trials and save them for online use; trial
+//! covariances can also be classified on the Riemannian manifold or
+//! flattened in its tangent space, and SSVEP targets told apart by their
+//! canonical correlation with sinusoid references. Channels can be
+//! re-referenced to their common average, the mastoids or bipolar pairs
+//! first, resampled to a common rate and cleaned of blinks by regression on
+//! EOG channels; bad channels are found by correlation and interpolated
+//! from their neighbours, and outlying epochs rejected. LDA and logistic
+//! regression classify the resulting feature vectors and save as JSON
+//! models. Labelled windows are dealt out to trainers in shuffled
+//! mini-batches, standardized per channel and augmented by a seeded
+//! pipeline of random transforms on the fly.
+
+pub mod augment;
+pub mod bandpower;
+pub mod batch;
+pub mod cca;
+pub mod classifier;
+mod complex;
+pub mod covariance;
+pub mod csp;
+mod history;
+pub mod iir;
+pub mod interpolate;
+pub mod linalg;
+pub mod reference;
+pub mod regression;
+pub mod rejection;
+pub mod resample;
+pub mod riemann;
+pub mod sample;
+pub mod spectrum;
+pub mod standardize;
+#[cfg(test)]
+mod testing;
+pub mod timedomain;
+pub mod wavelet;
+
+pub use augment::{Augmentation, AugmentError, Augmenter};
+pub use bandpower::{motor_imagery_bands, Band, BandPowerExtractor, FeatureError};
+pub use batch::{BatchError, BatchLoader, Batches, Windows};
+pub use cca::{canonical_correlation, CcaError, SsvepReferences};
+pub use classifier::{Classifier, ClassifierError, Lda, LogisticParams, LogisticRegression, Model};
+pub use covariance::{covariance, covariances, Shrinkage};
+pub use csp::{Csp, CspError};
+pub use iir::{Biquad, DesignError, FilterBank, FilterSpec, Prototype, Response};
+pub use interpolate::{position, ChannelInterpolation, InterpolationError};
+pub use reference::{Reference, ReferenceError, Rereference};
+pub use regression::{EogCalibration, EogCleaner, EogRegression, RegressionError, StreamingRegression};
+pub use rejection::{bad_by_correlation, reject_epochs, EpochStatistics, Rejection};
+pub use resample::{resample, ResampleError, Resampler};
+pub use riemann::{Mdm, RiemannError, TangentSpace};
+pub use sample::Sample;
+pub use spectrum::{segment_spectra, spectrogram, welch, Complex64, Psd, SpectralParams, Spectrogram, SpectrumError, SpectrumStream, Window};
+pub use standardize::Standardization;
+pub use timedomain::{TimeDomainExtractor, TimeFeature, TimeFeatures};
+pub use wavelet::{denoise, wavedec, waverec, Decomposition, Thresholding, Wavelet, WaveletError};
@@ -0,0 +1,101 @@
+//! Small dense linear algebra on symmetric matrices of channel size.
+
+use ndarray::{Array1, Array2};
+
+/// Cyclic Jacobi eigendecomposition of a symmetric matrix: eigenvalues in
+/// descending order and their eigenvectors as columns
+pub fn symmetric_eigen(m: &Array2<f64>) -> (Array1<f64>, Array2<f64>) {
+    let n = m.nrows();
+    let mut a = m.clone();
+    let mut v = Array2::<f64>::eye(n);
+    let scale: f64 = a.iter().map(|x| x * x).sum::<f64>().max(f64::MIN_POSITIVE);
+
+    for _ in 0..100 {
+        let off: f64 = a.indexed_iter().filter(|((i, j), _)| i != j).map(|(_, x)| x * x).sum();
+        if off <= 1e-30 * scale {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                let apq = a[[p, q]];
+                if apq == 0.0 {
+                    continue;
+                }
+                let theta = (a[[q, q]] - a[[p, p]]) / (2.0 * apq);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let t = if theta == 0.0 { 1.0 } else { t };
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for k in 0..n {
+                    let (akp, akq) = (a[[k, p]], a[[k, q]]);
+                    a[[k, p]] = c * akp - s * akq;
+                    a[[k, q]] = s * akp + c * akq;
+                }
+                for k in 0..n {
+                    let (apk, aqk) = (a[[p, k]], a[[q, k]]);
+                    a[[p, k]] = c * apk - s * aqk;
+                    a[[q, k]] = s * apk + c * aqk;
+                }
+                for k in 0..n {
+                    let (vkp, vkq) = (v[[k, p]], v[[k, q]]);
+                    v[[k, p]] = c * vkp - s * vkq;
+                    v[[k, q]] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&i, &j| a[[j, j]].total_cmp(&a[[i, i]]));
+    let values = order.iter().map(|&k| a[[k, k]]).collect();
+    let vectors = Array2::from_shape_fn((n, n), |(i, k)| v[[i, order[k]]]);
+    (values, vectors)
+}
+
+/// Lower triangular `L` with `L Lᵀ = m`, `None` unless `m` is positive definite
+pub(crate) fn cholesky(m: &Array2<f64>) -> Option<Array2<f64>> {
+    let n = m.nrows();
+    let mut l = Array2::<f64>::zeros((n, n));
+    for i in 0..n {
+        for j in 0..=i {
+            let sum: f64 = (0..j).map(|k| l[[i, k]] * l[[j, k]]).sum();
+            if i == j {
+                let d = m[[i, i]] - sum;
+                if d.is_nan() || d <= 0.0 {
+                    return None;
+                }
+                l[[i, i]] = d.sqrt();
+            } else {
+                l[[i, j]] = (m[[i, j]] - sum) / l[[j, j]];
+            }
+        }
+    }
+    Some(l)
+}
+
+/// Inverse of a lower triangular matrix with a nonzero diagonal
+pub(crate) fn invert_lower(l: &Array2<f64>) -> Array2<f64> {
+    let n = l.nrows();
+    let mut inverse = Array2::<f64>::zeros((n, n));
+    for col in 0..n {
+        inverse[[col, col]] = 1.0 / l[[col, col]];
+        for i in col + 1..n {
+            let sum: f64 = (col..i).map(|k| l[[i, k]] * inverse[[k, col]]).sum();
+            inverse[[i, col]] = -sum / l[[i, i]];
+        }
+    }
+    inverse
+}
+
+/// `V f(Λ) Vᵀ` of a symmetric matrix, e.g. its square root or logarithm
+pub(crate) fn symmetric_map(m: &Array2<f64>, f: impl Fn(f64) -> f64) -> Array2<f64> {
+    let (values, vectors) = symmetric_eigen(m);
+    let scaled = &vectors * &values.mapv(f);
+    scaled.dot(&vectors.t())
+}
+
+/// Inverse of a symmetric positive definite matrix, `None` if it is not
+pub(crate) fn spd_inverse(m: &Array2<f64>) -> Option<Array2<f64>> {
+    let inverse_lower = invert_lower(&cholesky(m)?);
+    Some(inverse_lower.t().dot(&inverse_lower))
+}
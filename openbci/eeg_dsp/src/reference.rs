@@ -0,0 +1,202 @@
+//! Re-referencing: each output channel as a weighted sum of the recorded
+//! ones.
+//!
+//! A [`Reference`] names the scheme: the common average of all channels,
+//! the average of the two mastoids (or of any named channels) or bipolar
+//! derivations such as `C3-Cz`. [`Rereference`] resolves it against the
+//! channel labels of a recording once and then applies it to whole arrays
+//! at export time or frame by frame online, with identical results.
+//! Channels used as the reference are dropped from the output, as they
+//! would be all zero.
+
+use crate::sample::Sample;
+use ndarray::{Array2, ArrayView2};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Mastoid labels, either naming convention
+const MASTOIDS: [[&str; 2]; 2] = [["M1", "M2"], ["A1", "A2"]];
+
+/// Re-referencing scheme, the `rereference` of a montage file, e.g.
+/// `{ kind = "bipolar", pairs = ["C3-Cz", "C4-Cz"] }`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Reference {
+    /// Mean of all channels subtracted from each
+    #[serde(alias = "car")]
+    CommonAverage,
+    /// Mean of M1 and M2 (or A1 and A2) subtracted from the others
+    LinkedMastoids,
+    /// Mean of the named channels subtracted from the others
+    Average { channels: Vec<String> },
+    /// One output per `A-B` pair, channel A minus channel B
+    Bipolar { pairs: Vec<String> },
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ReferenceError {
+    #[error("Reference '{0}' must be car, linked_mastoids, average:A,B or bipolar:A-B,C-D")]
+    Syntax(String),
+    #[error("Bipolar pair '{0}' must look like C3-Cz")]
+    Pair(String),
+    #[error("No channel is labelled {0}")]
+    Missing(String),
+    #[error("Several channels are labelled {0}")]
+    Ambiguous(String),
+    #[error("Linked mastoids need M1 and M2 or A1 and A2")]
+    Mastoids,
+    #[error("Re-referencing needs at least one output and {0} reference channels")]
+    TooFew(usize),
+}
+
+/// A [`Reference`] resolved against the channels of a recording
+#[derive(Debug, Clone)]
+pub struct Rereference {
+    /// One row per output channel, one column per input channel
+    weights: Array2<f64>,
+    labels: Vec<String>,
+}
+
+impl FromStr for Reference {
+    type Err = ReferenceError;
+
+    /// `car`, `linked_mastoids`, `average:M1,M2` or `bipolar:C3-Cz,C4-Cz`
+    fn from_str(s: &str) -> Result<Self, ReferenceError> {
+        let (kind, list) = s.split_once(':').unwrap_or((s, ""));
+        let items = || list.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect();
+        match kind.trim().to_lowercase().as_str() {
+            "car" | "common_average" if list.is_empty() => Ok(Self::CommonAverage),
+            "linked_mastoids" | "mastoids" if list.is_empty() => Ok(Self::LinkedMastoids),
+            "average" => Ok(Self::Average { channels: items() }),
+            "bipolar" => Ok(Self::Bipolar { pairs: items() }),
+            _ => Err(ReferenceError::Syntax(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::CommonAverage => write!(f, "car"),
+            Self::LinkedMastoids => write!(f, "linked_mastoids"),
+            Self::Average { channels } => write!(f, "average:{}", channels.join(",")),
+            Self::Bipolar { pairs } => write!(f, "bipolar:{}", pairs.join(",")),
+        }
+    }
+}
+
+/// Index of the one channel labelled `label`, ignoring case
+pub(crate) fn find<S: AsRef<str>>(channels: &[S], label: &str) -> Result<usize, ReferenceError> {
+    let mut matches = channels.iter().enumerate().filter(|(_, c)| c.as_ref().trim().eq_ignore_ascii_case(label.trim()));
+    match (matches.next(), matches.next()) {
+        (Some((index, _)), None) => Ok(index),
+        (Some(_), Some(_)) => Err(ReferenceError::Ambiguous(label.to_string())),
+        (None, _) => Err(ReferenceError::Missing(label.to_string())),
+    }
+}
+
+impl Rereference {
+    /// Resolve `reference` against the labels of the recorded channels
+    pub fn new<S: AsRef<str>>(reference: &Reference, channels: &[S]) -> Result<Self, ReferenceError> {
+        let inputs = channels.len();
+        let unit = |index: usize| {
+            let mut row = vec![0.0; inputs];
+            row[index] = 1.0;
+            row
+        };
+        // The others minus the mean of `references`
+        let average = |references: Vec<usize>| {
+            let kept: Vec<usize> = (0..inputs).filter(|i| !references.contains(i)).collect();
+            if references.is_empty() || kept.is_empty() {
+                return Err(ReferenceError::TooFew(references.len().max(1)));
+            }
+            let share = 1.0 / references.len() as f64;
+            let rows = kept
+                .iter()
+                .map(|&i| {
+                    let mut row = unit(i);
+                    for &r in &references {
+                        row[r] -= share;
+                    }
+                    row
+                })
+                .collect();
+            Ok((rows, kept.iter().map(|&i| channels[i].as_ref().trim().to_string()).collect()))
+        };
+
+        let (rows, labels): (Vec<Vec<f64>>, Vec<String>) = match reference {
+            Reference::CommonAverage => {
+                if inputs < 2 {
+                    return Err(ReferenceError::TooFew(2));
+                }
+                let rows = (0..inputs).map(|i| unit(i).iter().map(|w| w - 1.0 / inputs as f64).collect()).collect();
+                (rows, channels.iter().map(|c| c.as_ref().trim().to_string()).collect())
+            }
+            Reference::LinkedMastoids => {
+                let pair = MASTOIDS
+                    .iter()
+                    .find_map(|pair| pair.iter().map(|label| find(channels, label)).collect::<Result<Vec<_>, _>>().ok())
+                    .ok_or(ReferenceError::Mastoids)?;
+                average(pair)?
+            }
+            Reference::Average { channels: names } => {
+                average(names.iter().map(|name| find(channels, name)).collect::<Result<_, _>>()?)?
+            }
+            Reference::Bipolar { pairs } => {
+                if pairs.is_empty() {
+                    return Err(ReferenceError::TooFew(1));
+                }
+                let mut rows = Vec::new();
+                let mut labels = Vec::new();
+                for pair in pairs {
+                    let (active, reference) = pair.split_once('-').ok_or_else(|| ReferenceError::Pair(pair.clone()))?;
+                    let (a, b) = (find(channels, active)?, find(channels, reference)?);
+                    let mut row = unit(a);
+                    row[b] -= 1.0;
+                    rows.push(row);
+                    labels.push(format!("{}-{}", channels[a].as_ref().trim(), channels[b].as_ref().trim()));
+                }
+                (rows, labels)
+            }
+        };
+
+        let weights = Array2::from_shape_fn((rows.len(), inputs), |(o, i)| rows[o][i]);
+        Ok(Self { weights, labels })
+    }
+
+    /// Recorded channels it takes
+    pub fn inputs(&self) -> usize {
+        self.weights.ncols()
+    }
+
+    /// Channels it gives
+    pub fn outputs(&self) -> usize {
+        self.weights.nrows()
+    }
+
+    /// Output channel labels: the kept input labels, or `A-B` for bipolar
+    /// derivations
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    /// One row per output channel, one column per input channel
+    pub fn weights(&self) -> &Array2<f64> {
+        &self.weights
+    }
+
+    /// Re-reference one frame of `inputs()` samples into `outputs()`
+    pub fn apply_frame<T: Sample>(&self, frame: &[T], out: &mut [T]) {
+        debug_assert_eq!(frame.len(), self.inputs());
+        for (value, weights) in out.iter_mut().zip(self.weights.rows()) {
+            *value = T::from_f64(weights.iter().zip(frame).map(|(w, x)| w * x.to_f64()).sum());
+        }
+    }
+
+    /// Re-reference a `channels x samples` array
+    pub fn apply<T: Sample>(&self, data: ArrayView2<T>) -> Array2<T> {
+        self.weights.dot(&data.mapv(|x| x.to_f64())).mapv(T::from_f64)
+    }
+}
@@ -0,0 +1,344 @@
+//! Ocular artifact removal by regression on EOG channels.
+//!
+//! Blinks and eye movements reach every electrode as a scaled copy of what
+//! the EOG channels see, strongest at the frontal ones. An
+//! [`EogCalibration`] learns those scales by least squares over a period
+//! with blinks in it; an [`EogCleaner`] then subtracts the scaled EOG from
+//! every other channel, frame by frame. Both high-pass the EOG at
+//! [`HIGHPASS_HZ`] so electrode offsets and drift are not regressed out;
+//! each channel keeps its own DC. Without dedicated EOG electrodes, Fp1 and
+//! Fp2 serve as proxies. [`StreamingRegression`] calibrates on the start of
+//! a stream and cleans the rest.
+
+use crate::iir::{FilterBank, FilterSpec, Response};
+use crate::linalg::{symmetric_eigen, symmetric_map};
+use crate::reference::{find, ReferenceError};
+use crate::sample::Sample;
+use ndarray::{Array1, Array2, ArrayView2};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use thiserror::Error;
+
+/// Edge of the first order high-pass applied to the EOG, low enough to
+/// keep the shape of blinks
+pub const HIGHPASS_HZ: f64 = 0.1;
+
+#[derive(Debug, Error)]
+pub enum RegressionError {
+    #[error("EOG regression needs at least one EOG channel")]
+    NoEog,
+    #[error("EOG regression needs channels besides the EOG")]
+    NoEeg,
+    #[error(transparent)]
+    Label(#[from] ReferenceError),
+    #[error("Sample rate {0} Hz is too low for the EOG high-pass")]
+    Rate(f64),
+    #[error("EOG calibration needs more than {needed} samples, got {got}")]
+    TooShort { needed: usize, got: usize },
+    #[error("EOG channels are flat or identical during calibration")]
+    Singular,
+    #[error("Failed to access EOG regression file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to read EOG regression file: {0}")]
+    Format(#[from] serde_json::Error),
+}
+
+/// High-pass over `channels` channels
+fn highpass(sample_rate: f64, channels: usize) -> Result<FilterBank<f64>, RegressionError> {
+    let spec = FilterSpec::butterworth(1, Response::HighPass(HIGHPASS_HZ));
+    FilterBank::from_specs(&[spec], sample_rate, channels).map_err(|_| RegressionError::Rate(sample_rate))
+}
+
+/// Propagation of the EOG into every channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EogRegression {
+    /// Labels of the channels it was fitted on, in order
+    labels: Vec<String>,
+    /// Indices of the EOG channels among them
+    eog: Vec<usize>,
+    sample_rate: f64,
+    /// One row per channel, one column per EOG channel; rows of the EOG
+    /// channels are zero, they are left as recorded
+    coefficients: Array2<f64>,
+    /// Frames the calibration had
+    samples: usize,
+}
+
+impl EogRegression {
+    /// Fit on a `channels x samples` calibration recording
+    pub fn fit<T: Sample, S: AsRef<str>>(
+        data: ArrayView2<T>,
+        labels: &[S],
+        eog: &[S],
+        sample_rate: f64,
+    ) -> Result<Self, RegressionError> {
+        let mut calibration = EogCalibration::new(labels, eog, sample_rate)?;
+        let mut frame = vec![T::default(); labels.len()];
+        for column in data.columns() {
+            frame.iter_mut().zip(column).for_each(|(value, x)| *value = *x);
+            calibration.push(&frame);
+        }
+        calibration.finish()
+    }
+
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    /// Labels of the EOG channels
+    pub fn eog_labels(&self) -> Vec<&str> {
+        self.eog.iter().map(|&i| self.labels[i].as_str()).collect()
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    pub fn coefficients(&self) -> &Array2<f64> {
+        &self.coefficients
+    }
+
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// Clean a `channels x samples` array, as an [`EogCleaner`] would
+    pub fn apply<T: Sample>(&self, data: ArrayView2<T>) -> Array2<T> {
+        let mut cleaner = EogCleaner::new(self.clone());
+        let mut out = Array2::from_elem(data.dim(), T::default());
+        let mut frame = vec![T::default(); data.nrows()];
+        let mut cleaned = frame.clone();
+        for (input, mut output) in data.columns().into_iter().zip(out.columns_mut()) {
+            frame.iter_mut().zip(input).for_each(|(value, x)| *value = *x);
+            cleaner.push(&frame, &mut cleaned);
+            output.iter_mut().zip(&cleaned).for_each(|(value, x)| *value = *x);
+        }
+        out
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), RegressionError> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, RegressionError> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+/// Least squares statistics of a calibration period, gathered frame by
+/// frame
+#[derive(Debug, Clone)]
+pub struct EogCalibration {
+    labels: Vec<String>,
+    eog: Vec<usize>,
+    sample_rate: f64,
+    filter: FilterBank<f64>,
+    frame: Vec<f64>,
+    /// Sums of the high-passed channels and of their products with the EOG
+    sum: Array1<f64>,
+    cross: Array2<f64>,
+    samples: usize,
+}
+
+impl EogCalibration {
+    /// Start calibrating channels labelled `labels`, `eog` naming the EOG
+    /// channels among them
+    pub fn new<S: AsRef<str>>(labels: &[S], eog: &[S], sample_rate: f64) -> Result<Self, RegressionError> {
+        if eog.is_empty() {
+            return Err(RegressionError::NoEog);
+        }
+        let mut indices = Vec::with_capacity(eog.len());
+        for label in eog {
+            let index = find(labels, label.as_ref())?;
+            if !indices.contains(&index) {
+                indices.push(index);
+            }
+        }
+        if indices.len() == labels.len() {
+            return Err(RegressionError::NoEeg);
+        }
+        let (channels, eog_channels) = (labels.len(), indices.len());
+        Ok(Self {
+            labels: labels.iter().map(|l| l.as_ref().trim().to_string()).collect(),
+            eog: indices,
+            sample_rate,
+            filter: highpass(sample_rate, channels)?,
+            frame: vec![0.0; channels],
+            sum: Array1::zeros(channels),
+            cross: Array2::zeros((channels, eog_channels)),
+            samples: 0,
+        })
+    }
+
+    /// Frames gathered so far
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// Drop what was gathered and start over
+    pub fn reset(&mut self) {
+        self.filter.reset();
+        self.sum.fill(0.0);
+        self.cross.fill(0.0);
+        self.samples = 0;
+    }
+
+    /// Add one frame of every channel
+    pub fn push<T: Sample>(&mut self, frame: &[T]) {
+        debug_assert_eq!(frame.len(), self.labels.len());
+        self.frame.iter_mut().zip(frame).for_each(|(value, x)| *value = x.to_f64());
+        if self.samples == 0 {
+            self.filter.settle(&self.frame);
+        }
+        self.filter.process_frame(&mut self.frame);
+        for (channel, &x) in self.frame.iter().enumerate() {
+            self.sum[channel] += x;
+            for (k, &e) in self.eog.iter().enumerate() {
+                self.cross[[channel, k]] += x * self.frame[e];
+            }
+        }
+        self.samples += 1;
+    }
+
+    /// Solve for the coefficients: the channels' covariance with the EOG
+    /// times the inverse EOG covariance
+    pub fn finish(&self) -> Result<EogRegression, RegressionError> {
+        let needed = 2 * self.eog.len();
+        if self.samples <= needed {
+            return Err(RegressionError::TooShort { needed, got: self.samples });
+        }
+        let n = self.samples as f64;
+        let mean = &self.sum / n;
+        let covariance = Array2::from_shape_fn(self.cross.dim(), |(c, k)| {
+            (self.cross[[c, k]] - n * mean[c] * mean[self.eog[k]]) / (n - 1.0)
+        });
+        let eog_covariance = Array2::from_shape_fn((self.eog.len(), self.eog.len()), |(i, j)| covariance[[self.eog[i], j]]);
+        let (values, _) = symmetric_eigen(&eog_covariance);
+        if values.iter().any(|&v| v.is_nan() || v <= values[0] * 1e-9) {
+            return Err(RegressionError::Singular);
+        }
+        let inverse = symmetric_map(&eog_covariance, |v| 1.0 / v);
+        let mut coefficients = covariance.dot(&inverse);
+        for &e in &self.eog {
+            coefficients.row_mut(e).fill(0.0);
+        }
+        Ok(EogRegression {
+            labels: self.labels.clone(),
+            eog: self.eog.clone(),
+            sample_rate: self.sample_rate,
+            coefficients,
+            samples: self.samples,
+        })
+    }
+}
+
+/// Subtracts the fitted EOG propagation from a stream
+#[derive(Debug, Clone)]
+pub struct EogCleaner {
+    model: EogRegression,
+    filter: FilterBank<f64>,
+    eog: Vec<f64>,
+    started: bool,
+}
+
+impl EogCleaner {
+    pub fn new(model: EogRegression) -> Self {
+        let filter = highpass(model.sample_rate, model.eog.len()).expect("fitted at a valid rate");
+        Self {
+            filter,
+            eog: vec![0.0; model.eog.len()],
+            started: false,
+            model,
+        }
+    }
+
+    pub fn model(&self) -> &EogRegression {
+        &self.model
+    }
+
+    /// Clean one frame of every channel into `out`
+    pub fn push<T: Sample>(&mut self, frame: &[T], out: &mut [T]) {
+        debug_assert_eq!(frame.len(), self.model.labels.len());
+        self.eog.iter_mut().zip(&self.model.eog).for_each(|(value, &e)| *value = frame[e].to_f64());
+        if !self.started {
+            self.filter.settle(&self.eog);
+            self.started = true;
+        }
+        self.filter.process_frame(&mut self.eog);
+        for ((value, &x), weights) in out.iter_mut().zip(frame).zip(self.model.coefficients.rows()) {
+            let artifact: f64 = weights.iter().zip(&self.eog).map(|(w, e)| w * e).sum();
+            *value = T::from_f64(x.to_f64() - artifact);
+        }
+    }
+
+    /// Forget the stream's history
+    pub fn reset(&mut self) {
+        self.filter.reset();
+        self.started = false;
+    }
+}
+
+#[derive(Debug, Clone)]
+enum State {
+    Calibrating { calibration: EogCalibration, length: usize },
+    Cleaning(EogCleaner),
+}
+
+/// Calibrates on the first frames of a stream, passing them through, then
+/// cleans the rest
+#[derive(Debug, Clone)]
+pub struct StreamingRegression {
+    state: State,
+}
+
+impl StreamingRegression {
+    /// Calibrate on the first `seconds` of the stream
+    pub fn new<S: AsRef<str>>(labels: &[S], eog: &[S], sample_rate: f64, seconds: f64) -> Result<Self, RegressionError> {
+        let calibration = EogCalibration::new(labels, eog, sample_rate)?;
+        let length = (seconds * sample_rate).round() as usize;
+        Ok(Self { state: State::Calibrating { calibration, length } })
+    }
+
+    /// Clean with a regression fitted earlier
+    pub fn calibrated(model: EogRegression) -> Self {
+        Self { state: State::Cleaning(EogCleaner::new(model)) }
+    }
+
+    /// The regression once calibration is over
+    pub fn model(&self) -> Option<&EogRegression> {
+        match &self.state {
+            State::Calibrating { .. } => None,
+            State::Cleaning(cleaner) => Some(cleaner.model()),
+        }
+    }
+
+    /// Pass one frame into `out`, cleaned once calibration is over. Returns
+    /// whether it was cleaned; a failed calibration starts over.
+    pub fn push<T: Sample>(&mut self, frame: &[T], out: &mut [T]) -> Result<bool, RegressionError> {
+        match &mut self.state {
+            State::Cleaning(cleaner) => {
+                cleaner.push(frame, out);
+                Ok(true)
+            }
+            State::Calibrating { calibration, length } => {
+                out.copy_from_slice(frame);
+                calibration.push(frame);
+                if calibration.samples() < *length {
+                    return Ok(false);
+                }
+                match calibration.finish() {
+                    Ok(model) => {
+                        self.state = State::Cleaning(EogCleaner::new(model));
+                        Ok(false)
+                    }
+                    Err(e) => {
+                        calibration.reset();
+                        Err(e)
+                    }
+                }
+            }
+        }
+    }
+}
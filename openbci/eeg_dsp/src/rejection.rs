@@ -0,0 +1,168 @@
+//! Epoch rejection statistics and bad-channel detection.
+//!
+//! [`EpochStatistics`] sums an epoch up in two numbers: the mean variance
+//! of its channels, which movement and electrode pops inflate, and the
+//! largest excess kurtosis of any channel, which brief spikes such as
+//! blinks raise even when they barely move the variance. [`reject_epochs`]
+//! scores each against the other epochs of the same set with a robust
+//! z-score (median and MAD, so the outliers do not hide themselves) and
+//! rejects those past a threshold, as FASTER does. [`bad_by_correlation`]
+//! flags channels that follow none of the others, as the PREP pipeline
+//! does: EEG spreads over the scalp, so a channel whose best correlation
+//! with any other stays low is most likely off the head or broken.
+
+use crate::sample::Sample;
+use ndarray::ArrayView2;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Fewest epochs a median and MAD say anything about
+pub const MIN_EPOCHS: usize = 5;
+
+/// MAD of a normal distribution, in standard deviations
+const MAD_SCALE: f64 = 1.4826;
+
+/// Variance and kurtosis of one epoch
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct EpochStatistics {
+    /// Mean over channels of each channel's variance
+    pub variance: f64,
+    /// Largest excess kurtosis of any channel, 0 for a normal signal
+    pub kurtosis: f64,
+}
+
+impl EpochStatistics {
+    /// Statistics of `epoch`, `channels x samples`
+    pub fn of<T: Sample>(epoch: ArrayView2<T>) -> Self {
+        let n = epoch.ncols();
+        if epoch.nrows() == 0 || n == 0 {
+            return Self::default();
+        }
+        let (mut variance, mut kurtosis) = (0.0, f64::NEG_INFINITY);
+        for row in epoch.rows() {
+            let mean = row.iter().map(|x| x.to_f64()).sum::<f64>() / n as f64;
+            let (mut m2, mut m4) = (0.0, 0.0);
+            for x in row.iter().map(|x| x.to_f64() - mean) {
+                let square = x * x;
+                m2 += square;
+                m4 += square * square;
+            }
+            let (m2, m4) = (m2 / n as f64, m4 / n as f64);
+            variance += m2;
+            kurtosis = kurtosis.max(if m2 > 0.0 { m4 / (m2 * m2) - 3.0 } else { 0.0 });
+        }
+        Self { variance: variance / epoch.nrows() as f64, kurtosis }
+    }
+}
+
+/// Why an epoch was rejected, with its robust z-score
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "statistic", content = "z", rename_all = "snake_case")]
+pub enum Rejection {
+    Variance(f64),
+    Kurtosis(f64),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Variance(z) => write!(f, "variance z = {:.1}", z),
+            Self::Kurtosis(z) => write!(f, "kurtosis z = {:.1}", z),
+        }
+    }
+}
+
+/// Robust z-scores of `values`: distance from the median in MADs scaled to
+/// standard deviations. All zero when the values do not spread.
+fn robust_z(values: &[f64]) -> Vec<f64> {
+    let median = |values: &mut Vec<f64>| {
+        values.sort_by(f64::total_cmp);
+        let mid = values.len() / 2;
+        if values.len().is_multiple_of(2) {
+            (values[mid - 1] + values[mid]) / 2.0
+        } else {
+            values[mid]
+        }
+    };
+    let center = median(&mut values.to_vec());
+    let spread = MAD_SCALE * median(&mut values.iter().map(|v| (v - center).abs()).collect());
+    if spread <= 0.0 {
+        return vec![0.0; values.len()];
+    }
+    values.iter().map(|v| (v - center) / spread).collect()
+}
+
+/// Verdict on every epoch of one set: rejected when the variance or
+/// kurtosis lies more than `threshold` robust standard deviations from the
+/// set's median, either way (a flat epoch is as suspect as a noisy one).
+/// Nothing is rejected from fewer than [`MIN_EPOCHS`] epochs.
+pub fn reject_epochs(statistics: &[EpochStatistics], threshold: f64) -> Vec<Option<Rejection>> {
+    if statistics.len() < MIN_EPOCHS {
+        return vec![None; statistics.len()];
+    }
+    let variance = robust_z(&statistics.iter().map(|s| s.variance).collect::<Vec<_>>());
+    let kurtosis = robust_z(&statistics.iter().map(|s| s.kurtosis).collect::<Vec<_>>());
+    variance
+        .into_iter()
+        .zip(kurtosis)
+        .map(|(variance, kurtosis)| {
+            if variance.abs() > threshold {
+                Some(Rejection::Variance(variance))
+            } else if kurtosis.abs() > threshold {
+                Some(Rejection::Kurtosis(kurtosis))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Channels of `data`, `channels x samples`, whose largest absolute
+/// correlation with any other channel is below `min_correlation` in more
+/// than `max_bad_fraction` of its windows of `window` samples (the whole
+/// of `data` if it is shorter). A flat channel correlates with nothing.
+pub fn bad_by_correlation<T: Sample>(
+    data: ArrayView2<T>,
+    window: usize,
+    min_correlation: f64,
+    max_bad_fraction: f64,
+) -> Vec<usize> {
+    let (channels, len) = data.dim();
+    if channels < 2 || len == 0 {
+        return Vec::new();
+    }
+    let window = window.clamp(1, len);
+    let windows = len / window;
+    let mut bad_windows = vec![0usize; channels];
+    let mut centered = vec![vec![0.0; window]; channels];
+    let mut norms = vec![0.0; channels];
+    for start in (0..windows).map(|w| w * window) {
+        for (channel, (samples, norm)) in centered.iter_mut().zip(&mut norms).enumerate() {
+            let row = data.row(channel);
+            let row = row.slice(ndarray::s![start..start + window]);
+            let mean = row.iter().map(|x| x.to_f64()).sum::<f64>() / window as f64;
+            for (slot, x) in samples.iter_mut().zip(row) {
+                *slot = x.to_f64() - mean;
+            }
+            *norm = samples.iter().map(|x| x * x).sum::<f64>().sqrt();
+        }
+        for (channel, bad) in bad_windows.iter_mut().enumerate() {
+            let best = (0..channels)
+                .filter(|&other| other != channel && norms[channel] > 0.0 && norms[other] > 0.0)
+                .map(|other| {
+                    let dot: f64 = centered[channel].iter().zip(&centered[other]).map(|(a, b)| a * b).sum();
+                    (dot / (norms[channel] * norms[other])).abs()
+                })
+                .fold(0.0, f64::max);
+            if best < min_correlation {
+                *bad += 1;
+            }
+        }
+    }
+    bad_windows
+        .iter()
+        .enumerate()
+        .filter(|(_, &bad)| bad as f64 > max_bad_fraction * windows as f64)
+        .map(|(channel, _)| channel)
+        .collect()
+}
@@ -0,0 +1,239 @@
+//! Rational resampling with a polyphase anti-alias filter.
+//!
+//! Changing the rate from `from` to `to` Hz conceptually inserts `up - 1`
+//! zeros between samples, low-passes at the lower of the two Nyquist
+//! frequencies and keeps every `down`-th sample, where `up / down` is
+//! `to / from` in lowest terms (1000 to 250 Hz is 1/4, 160 to 250 Hz is
+//! 25/16). The filter is a Kaiser-windowed sinc as `scipy.signal.resample_poly`
+//! designs it, and only the taps that meet a nonzero input are evaluated.
+//! [`resample`] works on whole recordings and compensates the filter delay;
+//! [`Resampler`] runs causally frame by frame, its output lagging by
+//! [`Resampler::delay`].
+
+use crate::sample::Sample;
+use ndarray::{Array2, ArrayView2};
+use std::f64::consts::PI;
+use thiserror::Error;
+
+/// Taps on each side of the centre per unit of `max(up, down)`
+const HALF_LENGTH: usize = 10;
+/// Kaiser window shape, about 50 dB of stop band attenuation
+const KAISER_BETA: f64 = 5.0;
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ResampleError {
+    #[error("Sample rates must be positive, got {from} Hz to {to} Hz")]
+    Rate { from: u32, to: u32 },
+}
+
+/// Up and down factors of `from` to `to` in lowest terms
+pub fn ratio(from: u32, to: u32) -> Result<(usize, usize), ResampleError> {
+    if from == 0 || to == 0 {
+        return Err(ResampleError::Rate { from, to });
+    }
+    let (mut a, mut b) = (from, to);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    Ok(((to / a) as usize, (from / a) as usize))
+}
+
+/// Samples `samples` input samples resample to
+pub fn resampled_len(samples: usize, up: usize, down: usize) -> usize {
+    (samples * up).div_ceil(down)
+}
+
+/// Modified Bessel function of the first kind, order 0
+fn bessel_i0(x: f64) -> f64 {
+    let mut term = 1.0;
+    let mut sum = 1.0;
+    for k in 1..50 {
+        term *= (x / (2.0 * k as f64)).powi(2);
+        sum += term;
+        if term < sum * 1e-16 {
+            break;
+        }
+    }
+    sum
+}
+
+/// Low-pass taps at the upsampled rate with a DC gain of `up`
+fn design(up: usize, down: usize) -> Vec<f64> {
+    let half = HALF_LENGTH * up.max(down);
+    let cutoff = 1.0 / up.max(down) as f64;
+    let len = 2 * half + 1;
+    let mut taps: Vec<f64> = (0..len)
+        .map(|j| {
+            let x = cutoff * (j as f64 - half as f64);
+            let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+            let r = 2.0 * j as f64 / (len - 1) as f64 - 1.0;
+            cutoff * sinc * bessel_i0(KAISER_BETA * (1.0 - r * r).max(0.0).sqrt()) / bessel_i0(KAISER_BETA)
+        })
+        .collect();
+    let gain = up as f64 / taps.iter().sum::<f64>();
+    for tap in &mut taps {
+        *tap *= gain;
+    }
+    taps
+}
+
+/// Taps of each phase: `phases[p][k]` is tap `p + k up`
+fn polyphase(taps: &[f64], up: usize) -> Vec<Vec<f64>> {
+    (0..up).map(|phase| taps.iter().skip(phase).step_by(up).copied().collect()).collect()
+}
+
+/// Resample a `channels x samples` array from `from` to `to` Hz, aligned
+/// so output sample `m` falls at input time `m from / to`
+pub fn resample<T: Sample>(data: ArrayView2<T>, from: u32, to: u32) -> Result<Array2<T>, ResampleError> {
+    let (up, down) = ratio(from, to)?;
+    let (channels, samples) = data.dim();
+    if up == 1 && down == 1 {
+        return Ok(data.to_owned());
+    }
+    let taps = design(up, down);
+    let phases = polyphase(&taps, up);
+    let half = taps.len() / 2;
+
+    let mut out = Array2::from_elem((channels, resampled_len(samples, up, down)), T::from_f64(0.0));
+    for (input, mut output) in data.rows().into_iter().zip(out.rows_mut()) {
+        let input: Vec<f64> = input.iter().map(|x| x.to_f64()).collect();
+        for (m, value) in output.iter_mut().enumerate() {
+            let position = m * down + half;
+            let (phase, newest) = (position % up, position / up);
+            let sum: f64 = phases[phase]
+                .iter()
+                .enumerate()
+                .take_while(|&(k, _)| k <= newest)
+                .filter(|&(k, _)| newest - k < samples)
+                .map(|(k, tap)| tap * input[newest - k])
+                .sum();
+            *value = T::from_f64(sum);
+        }
+    }
+    Ok(out)
+}
+
+/// Causal resampler of a multichannel stream
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    up: usize,
+    down: usize,
+    from: u32,
+    phases: Vec<Vec<f64>>,
+    channels: usize,
+    /// Last `phases[0].len()` samples of every channel, newest at `head`
+    history: Vec<f64>,
+    head: usize,
+    /// Input frames so far, and the index of the next output frame
+    received: u64,
+    next: u64,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32, channels: usize) -> Result<Self, ResampleError> {
+        let (up, down) = ratio(from, to)?;
+        let phases = polyphase(&design(up, down), up);
+        let depth = phases[0].len();
+        Ok(Self {
+            up,
+            down,
+            from,
+            phases,
+            channels,
+            history: vec![0.0; channels * depth],
+            head: 0,
+            received: 0,
+            next: 0,
+        })
+    }
+
+    /// Up and down factors in lowest terms
+    pub fn ratio(&self) -> (usize, usize) {
+        (self.up, self.down)
+    }
+
+    /// Seconds the output lags the input by, half the filter length
+    pub fn delay(&self) -> f64 {
+        let half = (self.phases.iter().map(Vec::len).sum::<usize>() / 2) as f64;
+        half / (self.up as f64 * self.from as f64)
+    }
+
+    /// Add one input frame and append the output frames it completes to
+    /// `out`, interleaved; returns how many were added
+    pub fn push<T: Sample>(&mut self, frame: &[T], out: &mut Vec<T>) -> usize {
+        debug_assert_eq!(frame.len(), self.channels);
+        let depth = self.phases[0].len();
+        self.head = (self.head + 1) % depth;
+        for (channel, value) in frame.iter().enumerate() {
+            self.history[channel * depth + self.head] = value.to_f64();
+        }
+        let newest = self.received;
+        self.received += 1;
+
+        let mut added = 0;
+        while self.next * self.down as u64 / self.up as u64 == newest {
+            let phase = (self.next * self.down as u64 % self.up as u64) as usize;
+            for channel in 0..self.channels {
+                let history = &self.history[channel * depth..(channel + 1) * depth];
+                let sum: f64 = self.phases[phase]
+                    .iter()
+                    .enumerate()
+                    .map(|(k, tap)| tap * history[(self.head + depth - k) % depth])
+                    .sum();
+                out.push(T::from_f64(sum));
+            }
+            self.next += 1;
+            added += 1;
+        }
+        added
+    }
+
+    /// Forget the stream's history
+    pub fn reset(&mut self) {
+        self.history.fill(0.0);
+        (self.head, self.received, self.next) = (0, 0, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sine;
+
+    #[test]
+    fn ratios_are_in_lowest_terms() {
+        assert_eq!(ratio(1000, 250), Ok((1, 4)));
+        assert_eq!(ratio(160, 250), Ok((25, 16)));
+        assert_eq!(ratio(250, 250), Ok((1, 1)));
+        assert_eq!(ratio(0, 250), Err(ResampleError::Rate { from: 0, to: 250 }));
+        assert_eq!(resampled_len(1001, 1, 4), 251);
+    }
+
+    #[test]
+    fn resampling_up_and_back_round_trips() {
+        let signal = sine(7.0, 250.0, 1000);
+        let data = Array2::from_shape_vec((1, signal.len()), signal.clone()).unwrap();
+        for rate in [500, 1000, 400] {
+            let up = resample(data.view(), 250, rate).unwrap();
+            assert_eq!(up.ncols(), resampled_len(1000, rate as usize / 50, 5));
+            let back = resample(up.view(), rate, 250).unwrap();
+            assert_eq!(back.ncols(), 1000);
+            // Away from the edges, where the filter runs off the recording
+            for t in 100..900 {
+                assert!((back[[0, t]] - signal[t]).abs() < 1e-2, "{} Hz, sample {}", rate, t);
+            }
+        }
+    }
+
+    #[test]
+    fn stream_emits_the_output_rate() {
+        let mut resampler = Resampler::new(250, 1000, 2).unwrap();
+        let mut out = Vec::new();
+        let added: usize = (0..250).map(|_| resampler.push(&[1.0f32, -1.0], &mut out)).sum();
+        assert_eq!(added, 1000);
+        assert_eq!(out.len(), 2000);
+        // A constant settles to itself once the filter has filled
+        assert!((out[1998] - 1.0).abs() < 1e-3 && (out[1999] + 1.0).abs() < 1e-3);
+        assert!(resampler.delay() > 0.0);
+    }
+}
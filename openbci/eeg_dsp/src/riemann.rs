@@ -0,0 +1,315 @@
+//! Riemannian geometry on spatial covariance matrices.
+//!
+//! Trial covariances are symmetric positive definite (SPD) matrices, and
+//! distances and means between them are taken under the affine-invariant
+//! metric rather than entry by entry. [`TangentSpace`] flattens the
+//! matrices at a reference point into feature vectors for any vector
+//! classifier, and [`Mdm`] classifies directly on the manifold by the
+//! nearest class mean. Both save to and load from JSON.
+
+use crate::linalg::{symmetric_eigen, symmetric_map};
+use ndarray::{Array1, Array2};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::SQRT_2;
+use std::fs;
+use std::path::Path;
+use thiserror::Error;
+
+/// Mean iterations stop once the mean tangent step is this small
+const MEAN_TOLERANCE: f64 = 1e-8;
+const MEAN_MAX_ITERATIONS: usize = 50;
+
+#[derive(Debug, Error)]
+pub enum RiemannError {
+    #[error("Riemannian mean of no matrices")]
+    Empty,
+    #[error("{covariances} covariances but {labels} labels")]
+    Labels { covariances: usize, labels: usize },
+    #[error("MDM needs at least two classes, got {0:?}")]
+    Classes(Vec<u8>),
+    #[error("Covariances must all be {expected}x{expected}, got {got:?}")]
+    Shape { expected: usize, got: (usize, usize) },
+    #[error("Failed to access model file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to read model file: {0}")]
+    Format(#[from] serde_json::Error),
+}
+
+pub fn sqrtm(m: &Array2<f64>) -> Array2<f64> {
+    symmetric_map(m, |v| v.max(0.0).sqrt())
+}
+
+pub fn invsqrtm(m: &Array2<f64>) -> Array2<f64> {
+    symmetric_map(m, |v| 1.0 / v.max(f64::MIN_POSITIVE).sqrt())
+}
+
+pub fn logm(m: &Array2<f64>) -> Array2<f64> {
+    symmetric_map(m, |v| v.max(f64::MIN_POSITIVE).ln())
+}
+
+pub fn expm(m: &Array2<f64>) -> Array2<f64> {
+    symmetric_map(m, f64::exp)
+}
+
+/// `a b a` for symmetric `a`, symmetrised against rounding
+fn congruence(a: &Array2<f64>, b: &Array2<f64>) -> Array2<f64> {
+    let m = a.dot(b).dot(a);
+    (&m + &m.t()) / 2.0
+}
+
+/// Distance from the matrix whose inverse square root is `whitening`
+fn whitened_distance(whitening: &Array2<f64>, b: &Array2<f64>) -> f64 {
+    let (values, _) = symmetric_eigen(&congruence(whitening, b));
+    values.iter().map(|v| v.max(f64::MIN_POSITIVE).ln().powi(2)).sum::<f64>().sqrt()
+}
+
+/// Affine-invariant distance `‖log(A^-1/2 B A^-1/2)‖_F`
+pub fn distance(a: &Array2<f64>, b: &Array2<f64>) -> f64 {
+    whitened_distance(&invsqrtm(a), b)
+}
+
+/// Riemannian (Karcher) mean, by fixed-point iteration from the
+/// arithmetic mean
+pub fn mean(covs: &[Array2<f64>]) -> Result<Array2<f64>, RiemannError> {
+    let first = covs.first().ok_or(RiemannError::Empty)?;
+    let n = first.nrows();
+    if let Some(cov) = covs.iter().find(|c| c.dim() != (n, n)) {
+        return Err(RiemannError::Shape { expected: n, got: cov.dim() });
+    }
+    let count = covs.len() as f64;
+    let mut m = covs.iter().fold(Array2::zeros((n, n)), |sum, c| sum + c) / count;
+
+    for _ in 0..MEAN_MAX_ITERATIONS {
+        let (root, inv_root) = (sqrtm(&m), invsqrtm(&m));
+        let step = covs
+            .iter()
+            .fold(Array2::zeros((n, n)), |sum, c| sum + logm(&congruence(&inv_root, c)))
+            / count;
+        m = congruence(&root, &expm(&step));
+        if step.iter().map(|v| v * v).sum::<f64>().sqrt() < MEAN_TOLERANCE {
+            break;
+        }
+    }
+    Ok(m)
+}
+
+/// Projection onto the tangent space at a reference point
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TangentSpace {
+    reference: Array2<f64>,
+    /// `reference^-1/2`
+    whitening: Array2<f64>,
+}
+
+impl TangentSpace {
+    /// At the Riemannian mean of `covs`
+    pub fn fit(covs: &[Array2<f64>]) -> Result<Self, RiemannError> {
+        Ok(Self::at(mean(covs)?))
+    }
+
+    pub fn at(reference: Array2<f64>) -> Self {
+        Self { whitening: invsqrtm(&reference), reference }
+    }
+
+    pub fn reference(&self) -> &Array2<f64> {
+        &self.reference
+    }
+
+    /// Length of the feature vector, `n (n + 1) / 2` for `n` channels
+    pub fn dimension(&self) -> usize {
+        let n = self.reference.nrows();
+        n * (n + 1) / 2
+    }
+
+    /// Upper triangle of `log(P^-1/2 C P^-1/2)` row by row, off-diagonal
+    /// entries scaled by √2 so the Euclidean norm is the Riemannian distance
+    pub fn transform(&self, cov: &Array2<f64>) -> Array1<f64> {
+        let log = logm(&congruence(&self.whitening, cov));
+        let n = log.nrows();
+        let mut features = Vec::with_capacity(self.dimension());
+        for i in 0..n {
+            features.push(log[[i, i]]);
+            features.extend((i + 1..n).map(|j| SQRT_2 * log[[i, j]]));
+        }
+        Array1::from(features)
+    }
+
+    /// Feature vectors of `covs`, one per row
+    pub fn transform_all(&self, covs: &[Array2<f64>]) -> Array2<f64> {
+        let mut features = Array2::zeros((covs.len(), self.dimension()));
+        for (mut row, cov) in features.rows_mut().into_iter().zip(covs) {
+            row.assign(&self.transform(cov));
+        }
+        features
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), RiemannError> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, RiemannError> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+/// Minimum distance to mean classifier
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mdm {
+    classes: Vec<ClassMean>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ClassMean {
+    label: u8,
+    mean: Array2<f64>,
+    /// `mean^-1/2`
+    whitening: Array2<f64>,
+}
+
+impl Mdm {
+    pub fn fit(covs: &[Array2<f64>], labels: &[u8]) -> Result<Self, RiemannError> {
+        if covs.len() != labels.len() {
+            return Err(RiemannError::Labels { covariances: covs.len(), labels: labels.len() });
+        }
+        let mut distinct = labels.to_vec();
+        distinct.sort_unstable();
+        distinct.dedup();
+        if distinct.len() < 2 {
+            return Err(RiemannError::Classes(distinct));
+        }
+
+        let classes = distinct
+            .into_iter()
+            .map(|label| {
+                let members: Vec<Array2<f64>> =
+                    covs.iter().zip(labels).filter(|(_, &l)| l == label).map(|(c, _)| c.clone()).collect();
+                let mean = mean(&members)?;
+                Ok(ClassMean { label, whitening: invsqrtm(&mean), mean })
+            })
+            .collect::<Result<_, RiemannError>>()?;
+        Ok(Self { classes })
+    }
+
+    /// Class labels in ascending order
+    pub fn labels(&self) -> Vec<u8> {
+        self.classes.iter().map(|class| class.label).collect()
+    }
+
+    pub fn class_mean(&self, label: u8) -> Option<&Array2<f64>> {
+        self.classes.iter().find(|class| class.label == label).map(|class| &class.mean)
+    }
+
+    /// Distance from `cov` to each class mean, in label order
+    pub fn distances(&self, cov: &Array2<f64>) -> Vec<(u8, f64)> {
+        self.classes.iter().map(|class| (class.label, whitened_distance(&class.whitening, cov))).collect()
+    }
+
+    /// Softmax of the negative squared distances, in label order
+    pub fn probabilities(&self, cov: &Array2<f64>) -> Vec<(u8, f64)> {
+        let distances = self.distances(cov);
+        let nearest = distances.iter().map(|(_, d)| d * d).fold(f64::INFINITY, f64::min);
+        let weights: Vec<f64> = distances.iter().map(|(_, d)| (nearest - d * d).exp()).collect();
+        let total: f64 = weights.iter().sum();
+        distances.iter().zip(weights).map(|((label, _), w)| (*label, w / total)).collect()
+    }
+
+    pub fn predict(&self, cov: &Array2<f64>) -> u8 {
+        self.distances(cov).into_iter().min_by(|a, b| a.1.total_cmp(&b.1)).map_or(0, |(label, _)| label)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), RiemannError> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, RiemannError> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::covariance::{covariance, Shrinkage};
+    use crate::testing::gaussian;
+    use ndarray::arr2;
+
+    fn close(a: &Array2<f64>, b: &Array2<f64>, tolerance: f64) -> bool {
+        a.iter().zip(b).all(|(x, y)| (x - y).abs() < tolerance)
+    }
+
+    fn random_spd(seed: u64) -> Array2<f64> {
+        let samples = Array2::from_shape_vec((3, 100), gaussian(seed, 300)).unwrap();
+        covariance(samples.view(), Shrinkage::None)
+    }
+
+    #[test]
+    fn matrix_functions_invert_each_other() {
+        let m = random_spd(1);
+        let root = sqrtm(&m);
+        assert!(close(&root.dot(&root), &m, 1e-10));
+        assert!(close(&invsqrtm(&m).dot(&root), &Array2::eye(3), 1e-10));
+        assert!(close(&expm(&logm(&m)), &m, 1e-10));
+    }
+
+    #[test]
+    fn distance_is_affine_invariant() {
+        let (a, b) = (random_spd(2), random_spd(3));
+        let w = arr2(&[[2.0, 0.3, 0.0], [0.1, 1.0, 0.5], [0.0, -0.4, 3.0]]);
+        let moved = |m: &Array2<f64>| w.dot(m).dot(&w.t());
+        assert!((distance(&a, &b) - distance(&moved(&a), &moved(&b))).abs() < 1e-8);
+        assert!((distance(&a, &b) - distance(&b, &a)).abs() < 1e-10);
+        assert!(distance(&a, &a) < 1e-10);
+        // Between diagonal matrices the distance is that of the log eigenvalues
+        let expected = (2.0 * 4f64.ln().powi(2)).sqrt();
+        assert!((distance(&arr2(&[[1.0, 0.0], [0.0, 4.0]]), &arr2(&[[4.0, 0.0], [0.0, 1.0]])) - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn mean_is_geometric() {
+        let (a, b) = (arr2(&[[1.0, 0.0], [0.0, 4.0]]), arr2(&[[4.0, 0.0], [0.0, 1.0]]));
+        let m = mean(&[a.clone(), b.clone()]).unwrap();
+        assert!(close(&m, &arr2(&[[2.0, 0.0], [0.0, 2.0]]), 1e-8));
+
+        let covs: Vec<_> = (10..16).map(random_spd).collect();
+        let m = mean(&covs).unwrap();
+        // The mean zeroes the average tangent vector at itself
+        let tangent = TangentSpace::at(m.clone());
+        let average = tangent.transform_all(&covs).mean_axis(ndarray::Axis(0)).unwrap();
+        assert!(average.iter().all(|v| v.abs() < 1e-6), "{}", average);
+        assert!(tangent.transform(&m).iter().all(|v| v.abs() < 1e-10));
+        assert_eq!(tangent.dimension(), 6);
+        assert!(matches!(mean(&[]), Err(RiemannError::Empty)));
+    }
+
+    #[test]
+    fn tangent_vectors_keep_distance_to_the_reference() {
+        let (reference, c) = (random_spd(4), random_spd(5));
+        let vector = TangentSpace::at(reference.clone()).transform(&c);
+        assert!((vector.dot(&vector).sqrt() - distance(&reference, &c)).abs() < 1e-8);
+    }
+
+    #[test]
+    fn mdm_picks_the_nearest_class_mean() {
+        let class = |scale: [f64; 3], seed: u64| -> Vec<Array2<f64>> {
+            (0..10)
+                .map(|k| {
+                    let noise = gaussian(seed + k, 300);
+                    let samples = Array2::from_shape_fn((3, 100), |(c, t)| scale[c] * noise[c * 100 + t]);
+                    covariance(samples.view(), Shrinkage::LedoitWolf)
+                })
+                .collect()
+        };
+        let (a, b) = (class([3.0, 1.0, 1.0], 100), class([1.0, 1.0, 3.0], 200));
+        let covs: Vec<_> = a.iter().chain(&b).cloned().collect();
+        let labels: Vec<u8> = [0; 10].into_iter().chain([1; 10]).collect();
+        let mdm = Mdm::fit(&covs, &labels).unwrap();
+        assert_eq!(mdm.labels(), [0, 1]);
+        assert_eq!(mdm.predict(&class([3.0, 1.0, 1.0], 300)[0]), 0);
+        assert_eq!(mdm.predict(&class([1.0, 1.0, 3.0], 400)[0]), 1);
+        let total: f64 = mdm.probabilities(&covs[0]).iter().map(|(_, p)| p).sum();
+        assert!((total - 1.0).abs() < 1e-12);
+        assert!(matches!(Mdm::fit(&covs, &[0; 20]), Err(RiemannError::Classes(_))));
+    }
+}
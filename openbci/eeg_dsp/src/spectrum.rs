@@ -0,0 +1,499 @@
+//! Power spectra: Welch's averaged periodogram and short-time spectrograms.
+//!
+//! Segments of [`SpectralParams::segment`] samples, overlapping by
+//! [`SpectralParams::overlap`], are detrended to their mean, windowed and
+//! transformed. Powers are one-sided densities in units² / Hz, so
+//! integrating over a band gives the band's power whatever the window or
+//! segment length. [`welch`] and [`spectrogram`] take whole recordings as
+//! `channels x samples` arrays; [`SpectrumStream`] takes one frame at a
+//! time and keeps the spectrum of the latest segment and the average of the
+//! last few, for live displays. [`segment_spectra`] keeps every segment's
+//! complex spectrum, for phase and cross-spectral measures.
+
+use crate::sample::Sample;
+use ndarray::{s, Array1, Array2, Array3, ArrayView1, ArrayView2};
+pub use rustfft::num_complex::Complex64;
+use rustfft::{Fft, FftPlanner};
+use std::f64::consts::PI;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Taper applied to every segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Window {
+    Rectangular,
+    #[default]
+    Hann,
+    Hamming,
+    Blackman,
+}
+
+impl Window {
+    /// Periodic coefficients of a `len` sample window, as spectral
+    /// analysis uses them
+    pub fn coefficients(self, len: usize) -> Vec<f64> {
+        let n = len as f64;
+        (0..len)
+            .map(|i| {
+                let phase = 2.0 * PI * i as f64 / n;
+                match self {
+                    Self::Rectangular => 1.0,
+                    Self::Hann => 0.5 - 0.5 * phase.cos(),
+                    Self::Hamming => 0.54 - 0.46 * phase.cos(),
+                    Self::Blackman => 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// How spectra are estimated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpectralParams {
+    pub sample_rate: f64,
+    pub window: Window,
+    /// Samples per segment; the frequency resolution is
+    /// `sample_rate / segment`
+    pub segment: usize,
+    /// Samples consecutive segments share
+    pub overlap: usize,
+    /// Remove each segment's mean before windowing
+    pub detrend: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum SpectrumError {
+    #[error("Sample rate must be positive, got {0} Hz")]
+    SampleRate(f64),
+    #[error("Segments need at least 2 samples, got {0}")]
+    Segment(usize),
+    #[error("Overlap of {overlap} samples must be shorter than the {segment} sample segment")]
+    Overlap { overlap: usize, segment: usize },
+    #[error("{samples} samples are fewer than one {segment} sample segment")]
+    TooShort { samples: usize, segment: usize },
+    #[error("Averaging needs at least one segment")]
+    Averages,
+}
+
+impl SpectralParams {
+    /// Hann segments of `segment` samples overlapping by half, detrended
+    pub fn new(sample_rate: f64, segment: usize) -> Self {
+        Self {
+            sample_rate,
+            window: Window::Hann,
+            segment,
+            overlap: segment / 2,
+            detrend: true,
+        }
+    }
+
+    pub fn with_window(self, window: Window) -> Self {
+        Self { window, ..self }
+    }
+
+    pub fn with_overlap(self, overlap: usize) -> Self {
+        Self { overlap, ..self }
+    }
+
+    pub fn with_detrend(self, detrend: bool) -> Self {
+        Self { detrend, ..self }
+    }
+
+    pub fn validate(&self) -> Result<(), SpectrumError> {
+        if self.sample_rate.is_nan() || self.sample_rate <= 0.0 {
+            return Err(SpectrumError::SampleRate(self.sample_rate));
+        }
+        if self.segment < 2 {
+            return Err(SpectrumError::Segment(self.segment));
+        }
+        if self.overlap >= self.segment {
+            return Err(SpectrumError::Overlap { overlap: self.overlap, segment: self.segment });
+        }
+        Ok(())
+    }
+
+    /// Samples from one segment's start to the next
+    pub fn hop(&self) -> usize {
+        self.segment - self.overlap
+    }
+
+    /// Frequency bins from 0 Hz to Nyquist
+    pub fn bins(&self) -> usize {
+        self.segment / 2 + 1
+    }
+
+    /// Hz between bins
+    pub fn resolution(&self) -> f64 {
+        self.sample_rate / self.segment as f64
+    }
+
+    /// Center frequency of every bin
+    pub fn frequencies(&self) -> Array1<f64> {
+        Array1::from_shape_fn(self.bins(), |bin| bin as f64 * self.resolution())
+    }
+
+    /// Start of every whole segment in `samples`
+    fn starts(&self, samples: usize) -> Result<Vec<usize>, SpectrumError> {
+        self.validate()?;
+        if samples < self.segment {
+            return Err(SpectrumError::TooShort { samples, segment: self.segment });
+        }
+        Ok((0..=samples - self.segment).step_by(self.hop()).collect())
+    }
+}
+
+/// Power spectral density of every channel
+#[derive(Debug, Clone)]
+pub struct Psd {
+    pub frequencies: Array1<f64>,
+    /// `channels x bins`
+    pub power: Array2<f64>,
+}
+
+impl Psd {
+    /// Power of `channel` from `low` up to `high` Hz
+    pub fn band_power(&self, channel: usize, low: f64, high: f64) -> f64 {
+        let resolution = self.frequencies.get(1).copied().unwrap_or_default();
+        self.power
+            .row(channel)
+            .iter()
+            .zip(&self.frequencies)
+            .filter(|(_, frequency)| (low..high).contains(*frequency))
+            .map(|(power, _)| power * resolution)
+            .sum()
+    }
+}
+
+/// Spectra of consecutive segments of every channel
+#[derive(Debug, Clone)]
+pub struct Spectrogram {
+    pub frequencies: Array1<f64>,
+    /// Center of every segment, in seconds from the first sample
+    pub times: Array1<f64>,
+    /// `channels x segments x bins`
+    pub power: Array3<f64>,
+}
+
+/// Power in the bins from `low` up to `high` Hz of a density with bins
+/// `resolution` Hz apart, so adjacent bands do not share a bin
+pub fn band_power(psd: &[f64], resolution: f64, low: f64, high: f64) -> f64 {
+    psd.iter()
+        .enumerate()
+        .filter(|&(bin, _)| (low..high).contains(&(bin as f64 * resolution)))
+        .map(|(_, power)| power * resolution)
+        .sum()
+}
+
+/// Welch's PSD of `data`, one channel per row: the average of the
+/// periodograms of every whole segment
+pub fn welch<T: Sample>(data: ArrayView2<T>, params: &SpectralParams) -> Result<Psd, SpectrumError> {
+    let starts = params.starts(data.ncols())?;
+    let mut periodogram = Periodogram::new(params);
+    let mut power = Array2::zeros((data.nrows(), params.bins()));
+    let weight = 1.0 / starts.len() as f64;
+    for (row, mut out) in data.rows().into_iter().zip(power.rows_mut()) {
+        let out = out.as_slice_mut().expect("rows of a new array are contiguous");
+        for &start in &starts {
+            let segment = row.slice(s![start..start + params.segment]);
+            periodogram.accumulate(segment.iter().map(|v| v.to_f64()), weight, out);
+        }
+    }
+    Ok(Psd { frequencies: params.frequencies(), power })
+}
+
+/// Short-time spectra of `data`, one channel per row
+pub fn spectrogram<T: Sample>(data: ArrayView2<T>, params: &SpectralParams) -> Result<Spectrogram, SpectrumError> {
+    let starts = params.starts(data.ncols())?;
+    let mut periodogram = Periodogram::new(params);
+    let mut power = Array3::zeros((data.nrows(), starts.len(), params.bins()));
+    for (row, mut frames) in data.rows().into_iter().zip(power.outer_iter_mut()) {
+        for (&start, mut out) in starts.iter().zip(frames.rows_mut()) {
+            let out = out.as_slice_mut().expect("rows of a new array are contiguous");
+            let segment = row.slice(s![start..start + params.segment]);
+            periodogram.accumulate(segment.iter().map(|v| v.to_f64()), 1.0, out);
+        }
+    }
+    let center = params.segment as f64 / 2.0;
+    let times = starts.iter().map(|&start| (start as f64 + center) / params.sample_rate).collect();
+    Ok(Spectrogram { frequencies: params.frequencies(), times, power })
+}
+
+/// Windowed and detrended Fourier transform of every whole segment of one
+/// channel, `segments x bins` from 0 Hz to Nyquist, unscaled
+pub fn segment_spectra<T: Sample>(signal: ArrayView1<T>, params: &SpectralParams) -> Result<Array2<Complex64>, SpectrumError> {
+    let starts = params.starts(signal.len())?;
+    let mut periodogram = Periodogram::new(params);
+    let mut spectra = Array2::zeros((starts.len(), params.bins()));
+    for (&start, mut out) in starts.iter().zip(spectra.rows_mut()) {
+        let segment = signal.slice(s![start..start + params.segment]);
+        let spectrum = periodogram.transform(segment.iter().map(|v| v.to_f64()));
+        out.assign(&ArrayView1::from(&spectrum[..out.len()]));
+    }
+    Ok(spectra)
+}
+
+/// Spectra of a live stream, updated every hop: the latest segment's, and
+/// the average of the last `averages` segments
+#[derive(Clone)]
+pub struct SpectrumStream {
+    params: SpectralParams,
+    periodogram: Periodogram,
+    channels: usize,
+    /// Last `segment` samples of every channel, oldest at `head`
+    history: Vec<f64>,
+    head: usize,
+    /// Samples seen, up to a segment
+    filled: usize,
+    /// Samples since the last spectrum
+    since_hop: usize,
+    /// `channels x bins` of the latest segment
+    latest: Vec<f64>,
+    /// `averages x channels x bins` of the last segments
+    recent: Vec<f64>,
+    /// Their sum, `channels x bins`
+    sum: Vec<f64>,
+    averages: usize,
+    /// Spectra in `recent`, and the slot the next one goes to
+    count: usize,
+    slot: usize,
+}
+
+impl SpectrumStream {
+    pub fn new(params: SpectralParams, channels: usize, averages: usize) -> Result<Self, SpectrumError> {
+        params.validate()?;
+        if averages == 0 {
+            return Err(SpectrumError::Averages);
+        }
+        let bins = params.bins();
+        Ok(Self {
+            periodogram: Periodogram::new(&params),
+            params,
+            channels,
+            history: vec![0.0; channels * params.segment],
+            head: 0,
+            filled: 0,
+            since_hop: 0,
+            latest: vec![0.0; channels * bins],
+            recent: vec![0.0; averages * channels * bins],
+            sum: vec![0.0; channels * bins],
+            averages,
+            count: 0,
+            slot: 0,
+        })
+    }
+
+    pub fn params(&self) -> &SpectralParams {
+        &self.params
+    }
+
+    /// Add one sample of every channel; true when it completed a hop and
+    /// the spectra were updated
+    pub fn push<T: Sample>(&mut self, frame: &[T]) -> bool {
+        debug_assert_eq!(frame.len(), self.channels);
+        let segment = self.params.segment;
+        for (channel, value) in frame.iter().enumerate() {
+            self.history[channel * segment + self.head] = value.to_f64();
+        }
+        self.head = (self.head + 1) % segment;
+        self.filled = (self.filled + 1).min(segment);
+        self.since_hop += 1;
+        if self.filled < segment || (self.since_hop < self.params.hop() && self.count > 0) {
+            return false;
+        }
+        self.since_hop = 0;
+        self.update();
+        true
+    }
+
+    /// Spectra of the segment now in the history
+    fn update(&mut self) {
+        let (segment, bins) = (self.params.segment, self.params.bins());
+        self.latest.fill(0.0);
+        for channel in 0..self.channels {
+            let history = &self.history[channel * segment..(channel + 1) * segment];
+            let samples = history[self.head..].iter().chain(&history[..self.head]).copied();
+            self.periodogram.accumulate(samples, 1.0, &mut self.latest[channel * bins..(channel + 1) * bins]);
+        }
+
+        let size = self.channels * bins;
+        let slot = &mut self.recent[self.slot * size..(self.slot + 1) * size];
+        for ((sum, old), new) in self.sum.iter_mut().zip(slot.iter_mut()).zip(&self.latest) {
+            *sum += new - *old;
+            *old = *new;
+        }
+        self.slot = (self.slot + 1) % self.averages;
+        self.count = (self.count + 1).min(self.averages);
+    }
+
+    /// Spectra averaged so far, at most `averages`
+    pub fn segments(&self) -> usize {
+        self.count
+    }
+
+    /// Density of `channel` in the latest segment
+    pub fn latest(&self, channel: usize) -> &[f64] {
+        let bins = self.params.bins();
+        &self.latest[channel * bins..(channel + 1) * bins]
+    }
+
+    /// Welch density of `channel` over the last segments, in `out`
+    pub fn average(&self, channel: usize, out: &mut [f64]) {
+        let bins = self.params.bins();
+        let scale = 1.0 / self.count.max(1) as f64;
+        for (out, sum) in out.iter_mut().zip(&self.sum[channel * bins..(channel + 1) * bins]) {
+            *out = (sum * scale).max(0.0);
+        }
+    }
+
+    /// Power of `channel` from `low` up to `high` Hz, averaged over the
+    /// last segments
+    pub fn band_power(&self, channel: usize, low: f64, high: f64) -> f64 {
+        let bins = self.params.bins();
+        let scale = 1.0 / self.count.max(1) as f64;
+        band_power(&self.sum[channel * bins..(channel + 1) * bins], self.params.resolution(), low, high) * scale
+    }
+
+    /// Forget the history and the spectra
+    pub fn reset(&mut self) {
+        self.history.fill(0.0);
+        self.latest.fill(0.0);
+        self.recent.fill(0.0);
+        self.sum.fill(0.0);
+        (self.head, self.filled, self.since_hop, self.count, self.slot) = (0, 0, 0, 0, 0);
+    }
+}
+
+/// FFT plan, window and buffers for one segment length
+#[derive(Clone)]
+pub(crate) struct Periodogram {
+    fft: Arc<dyn Fft<f64>>,
+    window: Vec<f64>,
+    buffer: Vec<Complex64>,
+    scratch: Vec<Complex64>,
+    /// Density scaling, `1 / (rate * sum w^2)`
+    scale: f64,
+    detrend: bool,
+}
+
+impl Periodogram {
+    pub(crate) fn new(params: &SpectralParams) -> Self {
+        let fft = FftPlanner::new().plan_fft_forward(params.segment);
+        let window = params.window.coefficients(params.segment);
+        let energy: f64 = window.iter().map(|w| w * w).sum();
+        Self {
+            scratch: vec![Complex64::default(); fft.get_inplace_scratch_len()],
+            buffer: vec![Complex64::default(); params.segment],
+            scale: 1.0 / (params.sample_rate * energy),
+            window,
+            fft,
+            detrend: params.detrend,
+        }
+    }
+
+    /// Add `weight` times the one-sided density of a segment to `out`
+    pub(crate) fn accumulate(&mut self, samples: impl Iterator<Item = f64>, weight: f64, out: &mut [f64]) {
+        self.transform(samples);
+        let n = self.buffer.len();
+        for (bin, out) in out.iter_mut().enumerate() {
+            // Negative frequencies fold onto positive ones, except at DC
+            // and an even length's Nyquist bin
+            let fold = if bin == 0 || 2 * bin == n { 1.0 } else { 2.0 };
+            *out += weight * fold * self.scale * self.buffer[bin].norm_sqr();
+        }
+    }
+
+    /// Spectrum of a segment, detrended and windowed
+    fn transform(&mut self, samples: impl Iterator<Item = f64>) -> &[Complex64] {
+        for (slot, value) in self.buffer.iter_mut().zip(samples) {
+            *slot = Complex64::new(value, 0.0);
+        }
+        let mean = if self.detrend {
+            self.buffer.iter().map(|c| c.re).sum::<f64>() / self.buffer.len() as f64
+        } else {
+            0.0
+        };
+        for (slot, w) in self.buffer.iter_mut().zip(&self.window) {
+            *slot = Complex64::new((slot.re - mean) * w, 0.0);
+        }
+        self.fft.process_with_scratch(&mut self.buffer, &mut self.scratch);
+        &self.buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{gaussian, sine};
+    use ndarray::Array2;
+
+    const FS: f64 = 250.0;
+
+    fn rows(channels: &[Vec<f64>]) -> Array2<f64> {
+        Array2::from_shape_fn((channels.len(), channels[0].len()), |(c, t)| channels[c][t])
+    }
+
+    #[test]
+    fn welch_peaks_at_a_sine() {
+        let noise = gaussian(1, 2500);
+        let signal: Vec<f64> = sine(12.0, FS, 2500).iter().zip(&noise).map(|(s, n)| 3.0 * s + 0.1 * n).collect();
+        let psd = welch(rows(&[signal]).view(), &SpectralParams::new(FS, 250)).unwrap();
+        let peak = (0..psd.frequencies.len()).max_by(|&a, &b| psd.power[[0, a]].total_cmp(&psd.power[[0, b]])).unwrap();
+        assert_eq!(psd.frequencies[peak], 12.0);
+        // A sine of amplitude A has power A² / 2, here spread over the Hann main lobe
+        let power = psd.band_power(0, 9.0, 16.0);
+        assert!((power - 4.5).abs() < 0.05, "{}", power);
+    }
+
+    #[test]
+    fn density_integrates_to_the_mean_square() {
+        let signal = gaussian(2, 256);
+        let mean_square = signal.iter().map(|v| v * v).sum::<f64>() / signal.len() as f64;
+        let params = SpectralParams::new(FS, 256).with_window(Window::Rectangular).with_overlap(0).with_detrend(false);
+        let psd = welch(rows(&[signal]).view(), &params).unwrap();
+        let total = psd.power.sum() * params.resolution();
+        assert!((total - mean_square).abs() < 1e-9 * mean_square, "{} vs {}", total, mean_square);
+    }
+
+    #[test]
+    fn stream_average_matches_welch() {
+        let data = rows(&[gaussian(3, 1000), gaussian(4, 1000)]);
+        let params = SpectralParams::new(FS, 250);
+        let psd = welch(data.view(), &params).unwrap();
+        let mut stream = SpectrumStream::new(params, 2, 7).unwrap();
+        for frame in data.columns() {
+            stream.push(&frame.to_vec());
+        }
+        assert_eq!(stream.segments(), 7);
+        let mut average = vec![0.0; params.bins()];
+        for channel in 0..2 {
+            stream.average(channel, &mut average);
+            for (a, w) in average.iter().zip(psd.power.row(channel)) {
+                assert!((a - w).abs() < 1e-9 * w.max(1.0));
+            }
+        }
+    }
+
+    #[test]
+    fn segment_spectra_match_the_periodogram() {
+        let signal = gaussian(5, 500);
+        let params = SpectralParams::new(FS, 100);
+        let spectra = segment_spectra(ndarray::aview1(&signal), &params).unwrap();
+        let psd = spectrogram(rows(&[signal]).view(), &params).unwrap();
+        assert_eq!(spectra.nrows(), psd.times.len());
+        // Interior bins of the density are twice |X|² over the window energy
+        let energy: f64 = params.window.coefficients(params.segment).iter().map(|w| w * w).sum();
+        for (segment, row) in spectra.rows().into_iter().enumerate() {
+            let density = 2.0 * row[10].norm_sqr() / (energy * FS);
+            assert!((density - psd.power[[0, segment, 10]]).abs() < 1e-9 * density);
+        }
+    }
+
+    #[test]
+    fn short_or_invalid_input_is_rejected() {
+        let data = rows(&[vec![0.0; 100]]);
+        assert_eq!(welch(data.view(), &SpectralParams::new(FS, 250)).unwrap_err(), SpectrumError::TooShort { samples: 100, segment: 250 });
+        assert_eq!(SpectralParams::new(FS, 1).validate(), Err(SpectrumError::Segment(1)));
+        assert_eq!(SpectralParams::new(0.0, 100).validate(), Err(SpectrumError::SampleRate(0.0)));
+        assert!(SpectrumStream::new(SpectralParams::new(FS, 100), 1, 0).is_err());
+    }
+}
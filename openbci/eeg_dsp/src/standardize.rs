@@ -0,0 +1,49 @@
+//! Per-channel standardization of windows.
+//!
+//! Raw EEG is in nanovolts with channel offsets in the thousands, so every
+//! channel is centred and scaled by the mean and standard deviation of the
+//! training windows before it reaches a network; the same statistics are
+//! saved with the model and applied to every window online.
+
+use ndarray::{ArrayView3, ArrayViewMut2, ArrayViewMut3, Axis};
+use serde::{Deserialize, Serialize};
+
+/// Per-channel centre and scale
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Standardization {
+    pub center: Vec<f32>,
+    pub scale: Vec<f32>,
+}
+
+impl Standardization {
+    /// Mean and standard deviation of every channel over all
+    /// `[windows, channels, samples]`
+    pub fn fit(windows: ArrayView3<f32>) -> Self {
+        let (_, channels, _) = windows.dim();
+        let mut center = Vec::with_capacity(channels);
+        let mut scale = Vec::with_capacity(channels);
+        for channel in windows.axis_iter(Axis(1)) {
+            let n = channel.len().max(1) as f64;
+            let mean = channel.iter().map(|&v| v as f64).sum::<f64>() / n;
+            let var = channel.iter().map(|&v| (v as f64 - mean).powi(2)).sum::<f64>() / n;
+            center.push(mean as f32);
+            // A flat channel is left at zero rather than blown up
+            scale.push(if var > 0.0 { var.sqrt() as f32 } else { 1.0 });
+        }
+        Self { center, scale }
+    }
+
+    /// Standardize one `channels x samples` window in place
+    pub fn apply(&self, mut window: ArrayViewMut2<f32>) {
+        for ((mut row, center), scale) in window.axis_iter_mut(Axis(0)).zip(&self.center).zip(&self.scale) {
+            row.mapv_inplace(|v| (v - center) / scale);
+        }
+    }
+
+    /// Standardize `[windows, channels, samples]` in place
+    pub fn apply_all(&self, mut windows: ArrayViewMut3<f32>) {
+        for window in windows.axis_iter_mut(Axis(0)) {
+            self.apply(window);
+        }
+    }
+}
@@ -0,0 +1,28 @@
+//! Deterministic signals for the unit tests.
+
+use std::f64::consts::PI;
+
+/// `len` standard normal samples, the same for the same `seed`
+pub fn gaussian(seed: u64, len: usize) -> Vec<f64> {
+    // SplitMix64, so the tests need no random number crate
+    let mut state = seed;
+    let mut uniform = || {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        ((z ^ (z >> 31)) >> 11) as f64 / (1u64 << 53) as f64
+    };
+    (0..len)
+        .map(|_| {
+            // Box-Muller; 1 - u keeps the logarithm finite
+            let (u, v) = (uniform(), uniform());
+            (-2.0 * (1.0 - u).ln()).sqrt() * (2.0 * PI * v).cos()
+        })
+        .collect()
+}
+
+/// `len` samples of a unit sine at `frequency` Hz
+pub fn sine(frequency: f64, sample_rate: f64, len: usize) -> Vec<f64> {
+    (0..len).map(|t| (2.0 * PI * frequency * t as f64 / sample_rate).sin()).collect()
+}
@@ -0,0 +1,288 @@
+//! Hjorth parameters and other time-domain features of sliding windows.
+//!
+//! [`TimeFeatures::of`] computes all of them for one channel's window in
+//! two passes, cheap enough to score signal quality on every channel of a
+//! live stream. Activity is the variance, mobility the standard deviation
+//! of the first difference relative to that of the signal (in radians per
+//! sample, a rough mean frequency) and complexity the mobility of the first
+//! difference relative to the signal's, 1 for a pure sine. Line length is
+//! the mean absolute change between samples, zero crossings are counted
+//! after removing the mean, and RMS is taken after removing it too, as the
+//! DC offset of a raw channel would otherwise swamp it. A
+//! [`TimeDomainExtractor`] picks some of them for every channel, windowing
+//! recordings or streams like [`BandPowerExtractor`](crate::BandPowerExtractor).
+
+use crate::bandpower::FeatureError;
+use crate::history::History;
+use crate::sample::Sample;
+use ndarray::{Array2, ArrayView2};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Shortest window with a second difference
+pub const MIN_WINDOW: usize = 3;
+
+/// One time-domain feature of a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeFeature {
+    Activity,
+    Mobility,
+    Complexity,
+    LineLength,
+    ZeroCrossings,
+    Rms,
+}
+
+impl TimeFeature {
+    pub const ALL: [TimeFeature; 6] = [
+        Self::Activity,
+        Self::Mobility,
+        Self::Complexity,
+        Self::LineLength,
+        Self::ZeroCrossings,
+        Self::Rms,
+    ];
+
+    /// The three Hjorth parameters
+    pub const HJORTH: [TimeFeature; 3] = [Self::Activity, Self::Mobility, Self::Complexity];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Activity => "activity",
+            Self::Mobility => "mobility",
+            Self::Complexity => "complexity",
+            Self::LineLength => "line_length",
+            Self::ZeroCrossings => "zero_crossings",
+            Self::Rms => "rms",
+        }
+    }
+}
+
+impl FromStr for TimeFeature {
+    type Err = FeatureError;
+
+    fn from_str(s: &str) -> Result<Self, FeatureError> {
+        let name = s.trim().to_lowercase().replace('-', "_");
+        Self::ALL
+            .into_iter()
+            .find(|f| f.name() == name)
+            .ok_or_else(|| FeatureError::Feature(s.to_string()))
+    }
+}
+
+impl fmt::Display for TimeFeature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Every time-domain feature of one window of one channel
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct TimeFeatures {
+    pub activity: f64,
+    pub mobility: f64,
+    pub complexity: f64,
+    pub line_length: f64,
+    pub zero_crossings: f64,
+    pub rms: f64,
+}
+
+impl TimeFeatures {
+    /// Features of `samples`; all zero for fewer than [`MIN_WINDOW`]
+    pub fn of<T: Sample>(samples: &[T]) -> Self {
+        let n = samples.len();
+        if n < MIN_WINDOW {
+            return Self::default();
+        }
+        let mean = samples.iter().map(|x| x.to_f64()).sum::<f64>() / n as f64;
+
+        let (mut squares, mut crossings, mut sign) = (0.0, 0usize, 0.0);
+        let (mut d1_sum, mut d1_squares, mut d1_abs) = (0.0, 0.0, 0.0);
+        let (mut d2_sum, mut d2_squares) = (0.0, 0.0);
+        let (mut last, mut last_d1): (Option<f64>, Option<f64>) = (None, None);
+        for x in samples.iter().map(|x| x.to_f64() - mean) {
+            squares += x * x;
+            // Samples exactly at the mean do not cross on their own
+            if x != 0.0 {
+                if sign != 0.0 && x.signum() != sign {
+                    crossings += 1;
+                }
+                sign = x.signum();
+            }
+            if let Some(last) = last {
+                let d1 = x - last;
+                (d1_sum, d1_squares, d1_abs) = (d1_sum + d1, d1_squares + d1 * d1, d1_abs + d1.abs());
+                if let Some(last_d1) = last_d1 {
+                    let d2 = d1 - last_d1;
+                    (d2_sum, d2_squares) = (d2_sum + d2, d2_squares + d2 * d2);
+                }
+                last_d1 = Some(d1);
+            }
+            last = Some(x);
+        }
+
+        let variance = |sum: f64, squares: f64, count: usize| {
+            let mean = sum / count as f64;
+            (squares / count as f64 - mean * mean).max(0.0)
+        };
+        let activity = squares / n as f64;
+        let d1_variance = variance(d1_sum, d1_squares, n - 1);
+        let d2_variance = variance(d2_sum, d2_squares, n - 2);
+        let ratio = |numerator: f64, denominator: f64| {
+            if denominator > 0.0 {
+                (numerator / denominator).sqrt()
+            } else {
+                0.0
+            }
+        };
+        let mobility = ratio(d1_variance, activity);
+        let d1_mobility = ratio(d2_variance, d1_variance);
+        Self {
+            activity,
+            mobility,
+            complexity: if mobility > 0.0 { d1_mobility / mobility } else { 0.0 },
+            line_length: d1_abs / (n - 1) as f64,
+            zero_crossings: crossings as f64,
+            rms: activity.sqrt(),
+        }
+    }
+
+    pub fn get(&self, feature: TimeFeature) -> f64 {
+        match feature {
+            TimeFeature::Activity => self.activity,
+            TimeFeature::Mobility => self.mobility,
+            TimeFeature::Complexity => self.complexity,
+            TimeFeature::LineLength => self.line_length,
+            TimeFeature::ZeroCrossings => self.zero_crossings,
+            TimeFeature::Rms => self.rms,
+        }
+    }
+}
+
+/// Time-domain features of windows of `channels` channels
+#[derive(Debug, Clone)]
+pub struct TimeDomainExtractor {
+    features: Vec<TimeFeature>,
+    channels: usize,
+    window: usize,
+    step: usize,
+    samples: Vec<f64>,
+    history: History,
+    values: Vec<f64>,
+}
+
+impl TimeDomainExtractor {
+    /// Feature vectors of `window` samples, stepping a whole window
+    pub fn new(features: Vec<TimeFeature>, channels: usize, window: usize) -> Result<Self, FeatureError> {
+        if features.is_empty() {
+            return Err(FeatureError::NoFeatures);
+        }
+        if window < MIN_WINDOW {
+            return Err(FeatureError::Short { window, needed: MIN_WINDOW });
+        }
+        Ok(Self {
+            samples: vec![0.0; window],
+            history: History::new(channels, window, window),
+            values: vec![0.0; channels * features.len()],
+            features,
+            channels,
+            window,
+            step: window,
+        })
+    }
+
+    /// Samples from one window's start to the next
+    pub fn with_step(self, step: usize) -> Result<Self, FeatureError> {
+        if step == 0 {
+            return Err(FeatureError::Step);
+        }
+        let mut extractor = Self { step, ..self };
+        extractor.history.set_step(step);
+        Ok(extractor)
+    }
+
+    pub fn features(&self) -> &[TimeFeature] {
+        &self.features
+    }
+
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    pub fn step(&self) -> usize {
+        self.step
+    }
+
+    /// Values in a feature vector, features times channels
+    pub fn dimension(&self) -> usize {
+        self.values.len()
+    }
+
+    /// `<channel>_<feature>` of every feature, channel-major
+    pub fn feature_names<S: AsRef<str>>(&self, channel_names: &[S]) -> Vec<String> {
+        channel_names
+            .iter()
+            .flat_map(|channel| self.features.iter().map(move |f| format!("{}_{}", channel.as_ref(), f)))
+            .collect()
+    }
+
+    /// Features of one window, `channels x samples`, into `out`
+    pub fn extract<T: Sample>(&mut self, window: ArrayView2<T>, out: &mut [f64]) -> Result<(), FeatureError> {
+        debug_assert_eq!(window.nrows(), self.channels);
+        if window.ncols() < MIN_WINDOW {
+            return Err(FeatureError::Short { window: window.ncols(), needed: MIN_WINDOW });
+        }
+        self.samples.resize(window.ncols(), 0.0);
+        for (channel, row) in window.rows().into_iter().enumerate() {
+            for (slot, value) in self.samples.iter_mut().zip(row) {
+                *slot = value.to_f64();
+            }
+            self.channel_features(channel);
+        }
+        out.copy_from_slice(&self.values);
+        Ok(())
+    }
+
+    /// Features of every whole window of `data`, one vector per row
+    pub fn extract_all<T: Sample>(&mut self, data: ArrayView2<T>) -> Result<Array2<f64>, FeatureError> {
+        let starts: Vec<usize> = match data.ncols().checked_sub(self.window) {
+            Some(last) => (0..=last).step_by(self.step).collect(),
+            None => Vec::new(),
+        };
+        let mut vectors = Array2::zeros((starts.len(), self.dimension()));
+        for (&start, mut out) in starts.iter().zip(vectors.rows_mut()) {
+            let window = data.slice(ndarray::s![.., start..start + self.window]);
+            self.extract(window, out.as_slice_mut().expect("rows of a new array are contiguous"))?;
+        }
+        Ok(vectors)
+    }
+
+    /// Add one sample of every channel; the features of the latest window
+    /// when a step completes
+    pub fn push<T: Sample>(&mut self, frame: &[T]) -> Option<&[f64]> {
+        if !self.history.push(frame) {
+            return None;
+        }
+        self.samples.resize(self.window, 0.0);
+        for channel in 0..self.channels {
+            self.history.channel(channel, &mut self.samples);
+            self.channel_features(channel);
+        }
+        Some(&self.values)
+    }
+
+    /// Forget the stream's history
+    pub fn reset(&mut self) {
+        self.history.reset();
+    }
+
+    fn channel_features(&mut self, channel: usize) {
+        let all = TimeFeatures::of(&self.samples);
+        let count = self.features.len();
+        for (value, &feature) in self.values[channel * count..(channel + 1) * count].iter_mut().zip(&self.features) {
+            *value = all.get(feature);
+        }
+    }
+}
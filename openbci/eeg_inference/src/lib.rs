@@ -0,0 +1,16 @@
+//! ONNX inference for EEG classifiers, on the CPU with tract.
+//!
+//! Models trained in PyTorch (EEGNet, the tiny transformer) are exported to
+//! ONNX together with a small JSON [`ModelSpec`] naming the channels,
+//! window length, classes and input layout they were trained with. An
+//! [`OnnxModel`] checks the model's input shape against that spec when it
+//! is loaded, so a window or channel mismatch fails then rather than on
+//! the first prediction, optimizes the graph once for any batch size and
+//! turns windows of `channels x samples` into class probabilities one at
+//! a time online or in batches offline.
+
+pub mod model;
+pub mod spec;
+
+pub use model::{InferenceError, OnnxModel};
+pub use spec::{spec_path, Layout, ModelSpec};
@@ -0,0 +1,233 @@
+//! Running an exported ONNX model on windows of EEG.
+
+use crate::spec::{spec_path, Layout, ModelSpec};
+use ndarray::{Array2, ArrayView2, ArrayView3, Axis};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tract_onnx::prelude::*;
+use tract_onnx::tract_core::internal::DimLike;
+use tract_onnx::tract_hir::infer::Factoid;
+
+#[derive(Debug, Error)]
+pub enum InferenceError {
+    #[error("Failed to access {0:?}: {1}")]
+    Io(PathBuf, #[source] std::io::Error),
+    #[error("Invalid model spec {0:?}: {1}")]
+    Spec(PathBuf, #[source] serde_json::Error),
+    #[error("Spec needs at least one channel and one sample, and pad_to no shorter than the window")]
+    EmptySpec,
+    #[error("Layout '{0}' must be image, channels_first or time_first")]
+    Layout(String),
+    #[error("Failed to load ONNX model {0:?}: {1}")]
+    Load(PathBuf, String),
+    #[error("Cannot tell the layout of model input {0:?}, set `layout` in the spec")]
+    UnknownLayout(Vec<String>),
+    #[error("Model input {found:?} does not take {layout} windows of {channels} channels x {samples} samples")]
+    Input { found: Vec<String>, layout: Layout, channels: usize, samples: usize },
+    #[error("Model output {0:?} is not [batch, classes]")]
+    Output(Vec<String>),
+    #[error("Spec names {spec} classes, the model outputs {model}")]
+    Classes { spec: usize, model: usize },
+    #[error("Window of {channels} x {samples} does not match the model's {expected_channels} x {expected_samples}")]
+    Window { channels: usize, samples: usize, expected_channels: usize, expected_samples: usize },
+    #[error("Inference failed: {0}")]
+    Run(String),
+}
+
+/// Whether the known `dims` of an input take `layout` windows
+fn fits(dims: &[Option<usize>], layout: Layout, channels: usize, samples: usize) -> bool {
+    dims.len() == layout.rank()
+        && dims.iter().zip(layout.shape(0, channels, samples)).skip(1).all(|(d, e)| d.is_none_or(|d| d == e))
+}
+
+/// Layout whose shape fits the known `dims` of an input, channels first
+/// when both 3-D layouts do; otherwise the one of their rank, so the
+/// mismatch is reported against it
+fn infer_layout(dims: &[Option<usize>], channels: usize, samples: usize) -> Option<Layout> {
+    let layouts = [Layout::Image, Layout::ChannelsFirst, Layout::TimeFirst];
+    layouts
+        .into_iter()
+        .find(|&layout| fits(dims, layout, channels, samples))
+        .or_else(|| layouts.into_iter().find(|layout| layout.rank() == dims.len()))
+}
+
+/// A loaded, optimized model and the spec it runs with
+#[derive(Debug)]
+pub struct OnnxModel {
+    spec: ModelSpec,
+    layout: Layout,
+    classes: usize,
+    /// Windows per run when the model was exported with a fixed batch
+    batch: Option<usize>,
+    plan: TypedRunnableModel<TypedModel>,
+}
+
+impl OnnxModel {
+    /// Load `path` with the spec exported next to it (`<model>.json`)
+    pub fn open(path: &Path) -> Result<Self, InferenceError> {
+        let spec = ModelSpec::load(&spec_path(path))?;
+        Self::load(path, spec)
+    }
+
+    /// Load `path`, check its input against `spec` and optimize it for
+    /// any batch size (or the one it was exported with)
+    pub fn load(path: &Path, spec: ModelSpec) -> Result<Self, InferenceError> {
+        let model = tract_onnx::onnx().model_for_path(path);
+        Self::build(path, model, spec)
+    }
+
+    /// Load the ONNX model in `bytes` like [`load`](Self::load); `source`
+    /// names it in errors
+    pub fn from_bytes(source: &Path, mut bytes: &[u8], spec: ModelSpec) -> Result<Self, InferenceError> {
+        let model = tract_onnx::onnx().model_for_read(&mut bytes);
+        Self::build(source, model, spec)
+    }
+
+    fn build(path: &Path, model: TractResult<InferenceModel>, spec: ModelSpec) -> Result<Self, InferenceError> {
+        let load = |e: TractError| InferenceError::Load(path.to_path_buf(), format!("{:#}", e));
+        let samples = spec.input_samples();
+        if spec.channels == 0 || spec.window == 0 || spec.pad_to.is_some_and(|pad| pad < spec.window) {
+            return Err(InferenceError::EmptySpec);
+        }
+        let model = model.map_err(load)?;
+
+        let shape = &model.input_fact(0).map_err(load)?.shape;
+        let names: Vec<String> = shape.dims().map(|d| d.to_string()).collect();
+        let dims: Vec<Option<usize>> = shape.dims().map(|d| d.concretize().and_then(|d| d.to_usize().ok())).collect();
+        let layout = match spec.layout {
+            Some(layout) => layout,
+            None if shape.is_open() => return Err(InferenceError::UnknownLayout(names)),
+            None => infer_layout(&dims, spec.channels, samples).ok_or_else(|| InferenceError::UnknownLayout(names.clone()))?,
+        };
+        if !shape.is_open() && !fits(&dims, layout, spec.channels, samples) {
+            return Err(InferenceError::Input { found: names, layout, channels: spec.channels, samples });
+        }
+
+        // A symbolic batch first; some graphs only optimize for a fixed one
+        let fixed = dims.first().copied().flatten().filter(|_| !shape.is_open());
+        let (plan, batch) = match fixed {
+            Some(batch) => (Self::plan(model, layout, &spec, Some(batch)).map_err(load)?, Some(batch)),
+            None => match Self::plan(model.clone(), layout, &spec, None) {
+                Ok(plan) => (plan, None),
+                Err(_) => (Self::plan(model, layout, &spec, Some(1)).map_err(load)?, Some(1)),
+            },
+        };
+
+        let output = &plan.model().output_fact(0).map_err(load)?.shape;
+        let classes = (output.rank() == 2)
+            .then(|| output[1].to_usize().ok())
+            .flatten()
+            .ok_or_else(|| InferenceError::Output(output.iter().map(|d| d.to_string()).collect()))?;
+        if !spec.classes.is_empty() && spec.classes.len() != classes {
+            return Err(InferenceError::Classes { spec: spec.classes.len(), model: classes });
+        }
+        Ok(Self { spec, layout, classes, batch, plan })
+    }
+
+    fn plan(
+        mut model: InferenceModel,
+        layout: Layout,
+        spec: &ModelSpec,
+        batch: Option<usize>,
+    ) -> TractResult<TypedRunnableModel<TypedModel>> {
+        // The batch symbol the model was exported with, which its output
+        // shares
+        let symbol = model.input_fact(0)?.shape.dim(0).and_then(|d| d.concretize()).filter(|d| d.to_usize().is_err());
+        let batch = match batch {
+            Some(batch) => batch.to_dim(),
+            None => symbol.unwrap_or_else(|| model.symbol_table.sym("N").to_dim()),
+        };
+        let mut shape: TVec<TDim> =
+            layout.shape(1, spec.channels, spec.input_samples()).into_iter().map(|d| d.to_dim()).collect();
+        shape[0] = batch;
+        model.set_input_fact(0, InferenceFact::dt_shape(f32::datum_type(), shape))?;
+        model.set_output_fact(0, InferenceFact::default())?;
+        model.into_optimized()?.into_runnable()
+    }
+
+    pub fn spec(&self) -> &ModelSpec {
+        &self.spec
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Outputs per window
+    pub fn classes(&self) -> usize {
+        self.classes
+    }
+
+    /// Class names from the spec, `class_<i>` where it has none
+    pub fn class_name(&self, index: usize) -> String {
+        self.spec.classes.get(index).cloned().unwrap_or_else(|| format!("class_{}", index))
+    }
+
+    /// Class probabilities of one window, `channels x samples`
+    pub fn predict(&self, window: ArrayView2<f32>) -> Result<Vec<f32>, InferenceError> {
+        let batch = window.insert_axis(Axis(0));
+        Ok(self.predict_batch(batch)?.row(0).to_vec())
+    }
+
+    /// Class probabilities of `windows x channels x samples`, one row per
+    /// window, in as few runs as the model's batch size allows
+    pub fn predict_batch(&self, windows: ArrayView3<f32>) -> Result<Array2<f32>, InferenceError> {
+        let (count, channels, samples) = windows.dim();
+        if channels != self.spec.channels || samples != self.spec.window {
+            return Err(InferenceError::Window {
+                channels,
+                samples,
+                expected_channels: self.spec.channels,
+                expected_samples: self.spec.window,
+            });
+        }
+        let mut probabilities = Array2::zeros((count, self.classes));
+        let chunk = self.batch.unwrap_or(count).max(1);
+        for (start, mut rows) in (0..count).step_by(chunk).zip(probabilities.axis_chunks_iter_mut(Axis(0), chunk)) {
+            let windows = windows.slice(ndarray::s![start..(start + chunk).min(count), .., ..]);
+            let input = self.input(windows, self.batch.unwrap_or(windows.len_of(Axis(0))))?;
+            let outputs = self.plan.run(tvec!(input.into())).map_err(|e| InferenceError::Run(format!("{:#}", e)))?;
+            let output = outputs[0].cast_to::<f32>().map_err(|e| InferenceError::Run(format!("{:#}", e)))?;
+            let values = output.as_slice::<f32>().map_err(|e| InferenceError::Run(format!("{:#}", e)))?;
+            for (mut row, scores) in rows.rows_mut().into_iter().zip(values.chunks(self.classes)) {
+                row.assign(&ndarray::ArrayView1::from(scores));
+                if !self.spec.probabilities {
+                    softmax(row.as_slice_mut().expect("rows of a new array are contiguous"));
+                }
+            }
+        }
+        Ok(probabilities)
+    }
+
+    /// Input tensor of `windows`, scaled, padded and laid out, with zero
+    /// windows up to `batch`
+    fn input(&self, windows: ArrayView3<f32>, batch: usize) -> Result<Tensor, InferenceError> {
+        let (channels, samples) = (self.spec.channels, self.spec.input_samples());
+        let mut data = vec![0.0f32; batch * channels * samples];
+        for (w, window) in windows.outer_iter().enumerate() {
+            let base = w * channels * samples;
+            for ((c, t), &value) in window.indexed_iter() {
+                let offset = match self.layout {
+                    Layout::Image | Layout::ChannelsFirst => c * samples + t,
+                    Layout::TimeFirst => t * channels + c,
+                };
+                data[base + offset] = value * self.spec.scale;
+            }
+        }
+        Tensor::from_shape(&self.layout.shape(batch, channels, samples), &data)
+            .map_err(|e| InferenceError::Run(format!("{:#}", e)))
+    }
+}
+
+/// Logits to probabilities in place
+fn softmax(values: &mut [f32]) {
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut total = 0.0;
+    for value in values.iter_mut() {
+        *value = (*value - max).exp();
+        total += *value;
+    }
+    for value in values.iter_mut() {
+        *value /= total;
+    }
+}
@@ -0,0 +1,135 @@
+//! What a model expects: the JSON file exported next to it.
+
+use crate::model::InferenceError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// How a batch of windows is laid out in the model's input tensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    /// `[batch, 1, channels, samples]`, a one-plane image, as EEGNet takes
+    Image,
+    /// `[batch, channels, samples]`, as the tiny transformer takes
+    ChannelsFirst,
+    /// `[batch, samples, channels]`, one token per sample
+    TimeFirst,
+}
+
+impl Layout {
+    /// Input shape of `batch` windows
+    pub fn shape(&self, batch: usize, channels: usize, samples: usize) -> Vec<usize> {
+        match self {
+            Self::Image => vec![batch, 1, channels, samples],
+            Self::ChannelsFirst => vec![batch, channels, samples],
+            Self::TimeFirst => vec![batch, samples, channels],
+        }
+    }
+
+    pub fn rank(&self) -> usize {
+        match self {
+            Self::Image => 4,
+            Self::ChannelsFirst | Self::TimeFirst => 3,
+        }
+    }
+}
+
+impl FromStr for Layout {
+    type Err = InferenceError;
+
+    fn from_str(s: &str) -> Result<Self, InferenceError> {
+        match s.trim().to_lowercase().replace('-', "_").as_str() {
+            "image" | "eegnet" => Ok(Self::Image),
+            "channels_first" => Ok(Self::ChannelsFirst),
+            "time_first" => Ok(Self::TimeFirst),
+            _ => Err(InferenceError::Layout(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Image => "image",
+            Self::ChannelsFirst => "channels_first",
+            Self::TimeFirst => "time_first",
+        })
+    }
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+/// Input and output conventions of an exported model, e.g.
+/// `{"channels": 3, "window": 500, "sample_rate": 250, "classes": ["left_hand", "right_hand"]}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelSpec {
+    /// EEG channels per window, in training order
+    pub channels: usize,
+    /// Samples per window
+    pub window: usize,
+    /// Rate the model was trained at, Hz
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<f64>,
+    /// Channel names in training order, for matching a stream's columns
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub channel_names: Vec<String>,
+    /// Class names in output order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub classes: Vec<String>,
+    /// Taken from the model's input shape when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layout: Option<Layout>,
+    /// Samples the model takes when windows are zero-padded at the end,
+    /// e.g. 512 for a transformer trained on padded 500-sample epochs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pad_to: Option<usize>,
+    /// Factor applied to every sample first, e.g. 0.001 to feed nanovolts
+    /// to a model trained on microvolts
+    #[serde(default = "one")]
+    pub scale: f32,
+    /// The model ends in a softmax; otherwise its logits (or log
+    /// probabilities) are put through one
+    #[serde(default)]
+    pub probabilities: bool,
+}
+
+impl ModelSpec {
+    pub fn new(channels: usize, window: usize) -> Self {
+        Self {
+            channels,
+            window,
+            sample_rate: None,
+            channel_names: Vec::new(),
+            classes: Vec::new(),
+            layout: None,
+            pad_to: None,
+            scale: 1.0,
+            probabilities: false,
+        }
+    }
+
+    /// Samples of the model's input, the window or what it is padded to
+    pub fn input_samples(&self) -> usize {
+        self.pad_to.unwrap_or(self.window).max(self.window)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, InferenceError> {
+        let text = fs::read_to_string(path).map_err(|e| InferenceError::Io(path.to_path_buf(), e))?;
+        serde_json::from_str(&text).map_err(|e| InferenceError::Spec(path.to_path_buf(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), InferenceError> {
+        let text = serde_json::to_string_pretty(self).map_err(|e| InferenceError::Spec(path.to_path_buf(), e))?;
+        fs::write(path, text).map_err(|e| InferenceError::Io(path.to_path_buf(), e))
+    }
+}
+
+/// Where the spec of `model` lives: `eegnet.onnx` -> `eegnet.json`
+pub fn spec_path(model: &Path) -> PathBuf {
+    model.with_extension("json")
+}
@@ -0,0 +1,227 @@
+//! Confusion matrices and the scores read off them.
+//!
+//! A [`ConfusionMatrix`] counts windows (or trials) by their true and
+//! predicted class. Accuracy, balanced accuracy (the mean recall, which a
+//! classifier cannot inflate by favouring the larger class), Cohen's kappa
+//! and per-class precision, recall and F1 all come from those counts, so
+//! matrices of several folds or sessions are merged before scoring.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum MetricsError {
+    #[error("Class {index} is out of range for {classes} classes")]
+    Class { index: usize, classes: usize },
+    #[error("Expected {expected} values, got {got}")]
+    Length { expected: usize, got: usize },
+    #[error("Confusion matrices of classes {0:?} and {1:?} cannot be merged")]
+    Classes(Vec<String>, Vec<String>),
+    #[error("Seconds per selection must be positive, got {0}")]
+    Selection(f64),
+}
+
+/// Counts of true against predicted classes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfusionMatrix {
+    classes: Vec<String>,
+    /// `counts[true][predicted]` in `classes` order
+    counts: Vec<Vec<usize>>,
+}
+
+impl ConfusionMatrix {
+    pub fn new(classes: Vec<String>) -> Self {
+        let counts = vec![vec![0; classes.len()]; classes.len()];
+        Self { classes, counts }
+    }
+
+    /// A matrix from counts already tallied, `counts[true][predicted]`
+    pub fn from_counts(classes: Vec<String>, counts: Vec<Vec<usize>>) -> Result<Self, MetricsError> {
+        if counts.len() != classes.len() {
+            return Err(MetricsError::Length { expected: classes.len(), got: counts.len() });
+        }
+        if let Some(row) = counts.iter().find(|row| row.len() != classes.len()) {
+            return Err(MetricsError::Length { expected: classes.len(), got: row.len() });
+        }
+        Ok(Self { classes, counts })
+    }
+
+    /// Count one prediction
+    pub fn add(&mut self, truth: usize, predicted: usize) -> Result<(), MetricsError> {
+        let classes = self.classes.len();
+        if let Some(&index) = [truth, predicted].iter().find(|&&i| i >= classes) {
+            return Err(MetricsError::Class { index, classes });
+        }
+        self.counts[truth][predicted] += 1;
+        Ok(())
+    }
+
+    /// Add the counts of another matrix of the same classes
+    pub fn merge(&mut self, other: &ConfusionMatrix) -> Result<(), MetricsError> {
+        if other.classes != self.classes {
+            return Err(MetricsError::Classes(self.classes.clone(), other.classes.clone()));
+        }
+        for (row, other) in self.counts.iter_mut().zip(&other.counts) {
+            for (count, other) in row.iter_mut().zip(other) {
+                *count += other;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn classes(&self) -> &[String] {
+        &self.classes
+    }
+
+    pub fn counts(&self) -> &[Vec<usize>] {
+        &self.counts
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.iter().flatten().sum()
+    }
+
+    pub fn correct(&self) -> usize {
+        (0..self.classes.len()).map(|k| self.counts[k][k]).sum()
+    }
+
+    /// Predictions whose true class is `k`
+    pub fn support(&self, k: usize) -> usize {
+        self.counts[k].iter().sum()
+    }
+
+    /// Predictions of class `k`
+    pub fn predicted(&self, k: usize) -> usize {
+        self.counts.iter().map(|row| row[k]).sum()
+    }
+
+    /// Share of predictions that are right, 0 when there are none
+    pub fn accuracy(&self) -> f64 {
+        self.correct() as f64 / self.total().max(1) as f64
+    }
+
+    /// Share of class `k` predicted as `k`; `None` without any of it
+    pub fn recall(&self, k: usize) -> Option<f64> {
+        let support = self.support(k);
+        (support > 0).then(|| self.counts[k][k] as f64 / support as f64)
+    }
+
+    /// Share of predictions of `k` that are right; `None` when `k` was never
+    /// predicted
+    pub fn precision(&self, k: usize) -> Option<f64> {
+        let predicted = self.predicted(k);
+        (predicted > 0).then(|| self.counts[k][k] as f64 / predicted as f64)
+    }
+
+    /// Harmonic mean of precision and recall of class `k`, 0 when it was
+    /// neither present nor predicted
+    pub fn f1(&self, k: usize) -> f64 {
+        let both = self.support(k) + self.predicted(k);
+        if both == 0 {
+            0.0
+        } else {
+            2.0 * self.counts[k][k] as f64 / both as f64
+        }
+    }
+
+    /// Mean recall over the classes that occur
+    pub fn balanced_accuracy(&self) -> f64 {
+        let recalls: Vec<f64> = (0..self.classes.len()).filter_map(|k| self.recall(k)).collect();
+        recalls.iter().sum::<f64>() / recalls.len().max(1) as f64
+    }
+
+    /// Cohen's kappa: agreement beyond what the class frequencies give by
+    /// chance; 0 when chance already explains every prediction
+    pub fn kappa(&self) -> f64 {
+        let total = self.total();
+        if total == 0 {
+            return 0.0;
+        }
+        let n = total as f64;
+        let observed = self.correct() as f64 / n;
+        let expected = (0..self.classes.len())
+            .map(|k| self.support(k) as f64 * self.predicted(k) as f64)
+            .sum::<f64>()
+            / (n * n);
+        if expected >= 1.0 {
+            0.0
+        } else {
+            (observed - expected) / (1.0 - expected)
+        }
+    }
+}
+
+/// Rows are true classes, columns predictions
+impl fmt::Display for ConfusionMatrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self
+            .classes
+            .iter()
+            .map(String::len)
+            .chain(self.counts.iter().flatten().map(|c| c.to_string().len()))
+            .max()
+            .unwrap_or(0)
+            .max(5);
+        write!(f, "{:<width$}", "true")?;
+        for class in &self.classes {
+            write!(f, "  {:>width$}", class)?;
+        }
+        for (class, row) in self.classes.iter().zip(&self.counts) {
+            write!(f, "\n{:<width$}", class)?;
+            for count in row {
+                write!(f, "  {:>width$}", count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classes(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn scores_read_off_the_counts() {
+        // 40 left (30 right), 10 right (5 right)
+        let matrix = ConfusionMatrix::from_counts(classes(&["left", "right"]), vec![vec![30, 10], vec![5, 5]]).unwrap();
+        assert_eq!((matrix.total(), matrix.correct()), (50, 35));
+        assert!((matrix.accuracy() - 0.7).abs() < 1e-12);
+        assert_eq!(matrix.recall(0), Some(0.75));
+        assert_eq!(matrix.precision(1), Some(5.0 / 15.0));
+        assert!((matrix.f1(1) - 0.4).abs() < 1e-12);
+        assert!((matrix.balanced_accuracy() - 0.625).abs() < 1e-12);
+        // Chance agreement (40 * 35 + 10 * 15) / 50² = 0.62
+        assert!((matrix.kappa() - (0.7 - 0.62) / 0.38).abs() < 1e-12);
+    }
+
+    #[test]
+    fn kappa_is_one_when_perfect_and_zero_at_chance() {
+        let perfect = ConfusionMatrix::from_counts(classes(&["a", "b", "c"]), vec![vec![5, 0, 0], vec![0, 3, 0], vec![0, 0, 2]]).unwrap();
+        assert!((perfect.kappa() - 1.0).abs() < 1e-12);
+        let chance = ConfusionMatrix::from_counts(classes(&["a", "b"]), vec![vec![5, 5], vec![5, 5]]).unwrap();
+        assert!(chance.kappa().abs() < 1e-12);
+        // Always predicting one class agrees only by chance
+        let constant = ConfusionMatrix::from_counts(classes(&["a", "b"]), vec![vec![8, 0], vec![2, 0]]).unwrap();
+        assert_eq!(constant.kappa(), 0.0);
+        assert_eq!(ConfusionMatrix::new(classes(&["a", "b"])).kappa(), 0.0);
+    }
+
+    #[test]
+    fn adding_and_merging_check_the_classes() {
+        let mut matrix = ConfusionMatrix::new(classes(&["a", "b"]));
+        matrix.add(0, 1).unwrap();
+        assert_eq!(matrix.add(2, 0), Err(MetricsError::Class { index: 2, classes: 2 }));
+        let mut other = ConfusionMatrix::new(classes(&["a", "b"]));
+        other.add(1, 1).unwrap();
+        matrix.merge(&other).unwrap();
+        assert_eq!(matrix.counts(), [vec![0, 1], vec![0, 1]]);
+        assert_eq!(matrix.recall(0), Some(0.0));
+        assert!(matrix.merge(&ConfusionMatrix::new(classes(&["x", "y"]))).is_err());
+        assert!(ConfusionMatrix::from_counts(classes(&["a", "b"]), vec![vec![1, 2]]).is_err());
+    }
+}
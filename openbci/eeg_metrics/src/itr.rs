@@ -0,0 +1,69 @@
+//! Information transfer rate after Wolpaw et al. (2002).
+//!
+//! Each selection among `N` classes made with accuracy `P` carries
+//!
+//! `B = log2 N + P log2 P + (1 - P) log2((1 - P) / (N - 1))`
+//!
+//! bits, assuming every class is equally likely and errors spread evenly
+//! over the other classes. At or below chance (`P <= 1/N`) this is taken as
+//! zero, as a speller that does worse than guessing transfers nothing.
+//! Bits per minute follow from the time one selection takes: the window
+//! length offline, the hop or trial length online.
+
+use crate::confusion::MetricsError;
+
+/// Bits per selection among `classes` at `accuracy`
+pub fn bits_per_selection(classes: usize, accuracy: f64) -> f64 {
+    if classes < 2 {
+        return 0.0;
+    }
+    let n = classes as f64;
+    let p = accuracy.clamp(0.0, 1.0);
+    if p <= 1.0 / n {
+        return 0.0;
+    }
+    let mut bits = n.log2() + p * p.log2();
+    if p < 1.0 {
+        bits += (1.0 - p) * ((1.0 - p) / (n - 1.0)).log2();
+    }
+    bits
+}
+
+/// Bits per minute when every selection takes `seconds`
+pub fn bits_per_minute(classes: usize, accuracy: f64, seconds: f64) -> Result<f64, MetricsError> {
+    if seconds <= 0.0 || !seconds.is_finite() {
+        return Err(MetricsError::Selection(seconds));
+    }
+    Ok(bits_per_selection(classes, accuracy) * 60.0 / seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn perfect_selection_carries_log2_n_bits() {
+        for classes in [2, 4, 26] {
+            assert!((bits_per_selection(classes, 1.0) - (classes as f64).log2()).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn chance_and_below_carry_nothing() {
+        assert_eq!(bits_per_selection(2, 0.5), 0.0);
+        assert_eq!(bits_per_selection(4, 0.2), 0.0);
+        assert_eq!(bits_per_selection(1, 1.0), 0.0);
+        // Just above chance carries a little, and more accuracy carries more
+        let (low, high) = (bits_per_selection(4, 0.3), bits_per_selection(4, 0.8));
+        assert!(low > 0.0 && low < high && high < 2.0);
+    }
+
+    #[test]
+    fn matches_wolpaw_at_80_percent_of_two() {
+        // 1 + 0.8 log2 0.8 + 0.2 log2 0.2
+        assert!((bits_per_selection(2, 0.8) - 0.278_071_905_112_638).abs() < 1e-12);
+        assert!((bits_per_minute(2, 1.0, 4.0).unwrap() - 15.0).abs() < 1e-12);
+        assert_eq!(bits_per_minute(2, 1.0, 0.0), Err(MetricsError::Selection(0.0)));
+        assert!(bits_per_minute(2, 1.0, f64::NAN).is_err());
+    }
+}
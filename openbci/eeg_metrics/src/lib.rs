@@ -0,0 +1,21 @@
+//! Classification metrics for EEG decoders, shared by offline benchmarks
+//! and the summary of an online session.
+//!
+//! Predictions are counted into a confusion matrix, from which accuracy,
+//! balanced accuracy, Cohen's kappa and per-class precision, recall and F1
+//! follow; class probabilities give ROC curves and their area, one-vs-rest
+//! for more than two classes; and the accuracy with the time a selection
+//! takes gives Wolpaw's information transfer rate in bits per minute. An
+//! [`Evaluation`] collects windows and reports all of them at once, as JSON
+//! or text. Scores that are undefined for the data, such as the AUC of a
+//! session that only ever saw one class, are `None` rather than made up.
+
+pub mod confusion;
+pub mod itr;
+pub mod report;
+pub mod roc;
+
+pub use confusion::{ConfusionMatrix, MetricsError};
+pub use itr::{bits_per_minute, bits_per_selection};
+pub use report::{ClassReport, Evaluation, Report};
+pub use roc::{auc, macro_auc, one_vs_rest_auc, roc_curve, RocPoint};
@@ -0,0 +1,193 @@
+//! Every score of a run of predictions, in one report.
+//!
+//! An [`Evaluation`] collects each window's true class and class
+//! probabilities, as the benchmark folds or an online session produce them,
+//! and turns them into a [`Report`]: the confusion matrix with the scores
+//! read off it, the AUC from the probabilities and, given how long one
+//! selection takes, the information transfer rate. Reports serialize to
+//! JSON and print as a short summary.
+
+use crate::confusion::{ConfusionMatrix, MetricsError};
+use crate::{itr, roc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Predictions collected for scoring
+#[derive(Debug, Clone)]
+pub struct Evaluation {
+    confusion: ConfusionMatrix,
+    truth: Vec<usize>,
+    probabilities: Vec<Vec<f64>>,
+}
+
+impl Evaluation {
+    pub fn new(classes: Vec<String>) -> Self {
+        Self { confusion: ConfusionMatrix::new(classes), truth: Vec::new(), probabilities: Vec::new() }
+    }
+
+    /// Count one window of class `truth`, predicted as its most probable
+    /// class, which is returned
+    pub fn add<T: Copy + Into<f64>>(&mut self, truth: usize, probabilities: &[T]) -> Result<usize, MetricsError> {
+        let classes = self.confusion.classes().len();
+        if probabilities.len() != classes {
+            return Err(MetricsError::Length { expected: classes, got: probabilities.len() });
+        }
+        let probabilities: Vec<f64> = probabilities.iter().map(|&p| p.into()).collect();
+        let predicted = probabilities.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1)).map_or(0, |(i, _)| i);
+        self.confusion.add(truth, predicted)?;
+        self.truth.push(truth);
+        self.probabilities.push(probabilities);
+        Ok(predicted)
+    }
+
+    /// Add the windows of another evaluation of the same classes
+    pub fn merge(&mut self, other: &Evaluation) -> Result<(), MetricsError> {
+        self.confusion.merge(&other.confusion)?;
+        self.truth.extend_from_slice(&other.truth);
+        self.probabilities.extend_from_slice(&other.probabilities);
+        Ok(())
+    }
+
+    pub fn confusion(&self) -> &ConfusionMatrix {
+        &self.confusion
+    }
+
+    pub fn len(&self) -> usize {
+        self.truth.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.truth.is_empty()
+    }
+
+    /// Score the windows; the ITR needs the seconds one selection takes
+    pub fn report(&self, seconds_per_selection: Option<f64>) -> Result<Report, MetricsError> {
+        let confusion = &self.confusion;
+        let classes = confusion.classes().len();
+        let auc = roc::one_vs_rest_auc(&self.probabilities, &self.truth, classes)?;
+        let accuracy = confusion.accuracy();
+        let itr_bits_per_minute = match seconds_per_selection {
+            Some(seconds) => Some(itr::bits_per_minute(classes, accuracy, seconds)?),
+            None => None,
+        };
+        Ok(Report {
+            predictions: confusion.total(),
+            accuracy,
+            balanced_accuracy: confusion.balanced_accuracy(),
+            kappa: confusion.kappa(),
+            auc: roc::macro_auc(&auc),
+            chance_level: 1.0 / classes.max(1) as f64,
+            bits_per_selection: itr::bits_per_selection(classes, accuracy),
+            seconds_per_selection,
+            itr_bits_per_minute,
+            per_class: confusion
+                .classes()
+                .iter()
+                .enumerate()
+                .map(|(k, class)| ClassReport {
+                    class: class.clone(),
+                    support: confusion.support(k),
+                    precision: confusion.precision(k),
+                    recall: confusion.recall(k),
+                    f1: confusion.f1(k),
+                    auc: auc[k],
+                })
+                .collect(),
+            confusion: confusion.clone(),
+        })
+    }
+}
+
+/// Scores of one class
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClassReport {
+    pub class: String,
+    /// Windows of the class
+    pub support: usize,
+    pub precision: Option<f64>,
+    pub recall: Option<f64>,
+    pub f1: f64,
+    /// One-vs-rest AUC
+    pub auc: Option<f64>,
+}
+
+/// Scores of an evaluation; undefined ones are `None` (`null` in JSON)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub predictions: usize,
+    pub accuracy: f64,
+    pub balanced_accuracy: f64,
+    pub kappa: f64,
+    /// Mean one-vs-rest AUC over the classes that occur
+    pub auc: Option<f64>,
+    pub chance_level: f64,
+    pub bits_per_selection: f64,
+    pub seconds_per_selection: Option<f64>,
+    pub itr_bits_per_minute: Option<f64>,
+    pub per_class: Vec<ClassReport>,
+    pub confusion: ConfusionMatrix,
+}
+
+fn optional(value: Option<f64>, precision: usize) -> String {
+    value.map_or("n/a".to_string(), |v| format!("{:.precision$}", v))
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} predictions: accuracy {:.1}% (chance {:.1}%), balanced {:.1}%, kappa {:.3}, AUC {}",
+            self.predictions,
+            100.0 * self.accuracy,
+            100.0 * self.chance_level,
+            100.0 * self.balanced_accuracy,
+            self.kappa,
+            optional(self.auc, 3)
+        )?;
+        match (self.seconds_per_selection, self.itr_bits_per_minute) {
+            (Some(seconds), Some(itr)) => writeln!(
+                f,
+                "ITR {:.2} bits/min ({:.3} bits per {} s selection)",
+                itr, self.bits_per_selection, seconds
+            )?,
+            _ => writeln!(f, "ITR {:.3} bits per selection", self.bits_per_selection)?,
+        }
+        for class in &self.per_class {
+            writeln!(
+                f,
+                "{}: {} windows, precision {}, recall {}, F1 {:.2}",
+                class.class,
+                class.support,
+                optional(class.precision, 2),
+                optional(class.recall, 2),
+                class.f1
+            )?;
+        }
+        write!(f, "{}", self.confusion)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_combines_every_score() {
+        let mut evaluation = Evaluation::new(vec!["left".to_string(), "right".to_string()]);
+        for (truth, probabilities) in [(0, [0.9, 0.1]), (0, [0.6, 0.4]), (1, [0.3, 0.7]), (1, [0.55, 0.45])] {
+            evaluation.add(truth, &probabilities).unwrap();
+        }
+        assert_eq!(evaluation.add(0, &[1.0f32]), Err(MetricsError::Length { expected: 2, got: 1 }));
+        let report = evaluation.report(Some(4.0)).unwrap();
+        assert_eq!(report.predictions, 4);
+        assert!((report.accuracy - 0.75).abs() < 1e-12);
+        assert_eq!(report.chance_level, 0.5);
+        // Right scores 0.7 and 0.45 against left's 0.4 and 0.1
+        assert_eq!(report.auc, Some(1.0));
+        assert_eq!(report.per_class[1].recall, Some(0.5));
+        let bits = itr::bits_per_selection(2, 0.75);
+        assert!((report.itr_bits_per_minute.unwrap() - bits * 15.0).abs() < 1e-12);
+        assert!(evaluation.report(Some(-1.0)).is_err());
+        assert!(report.to_string().contains("4 predictions"));
+    }
+}
@@ -0,0 +1,159 @@
+//! Receiver operating characteristic of class scores.
+//!
+//! The area under the ROC curve is the chance that a random window of the
+//! class scores higher than a random window of the others, computed from
+//! the rank sum (Mann-Whitney U) with tied scores counted half, so it does
+//! not depend on any decision threshold. With more than two classes each
+//! class is scored one-vs-rest and the areas averaged. Neither is defined
+//! without windows on both sides, which is `None`.
+
+use crate::confusion::MetricsError;
+use serde::{Deserialize, Serialize};
+
+/// One threshold of an ROC curve
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RocPoint {
+    /// Windows scoring at least this count as positive
+    pub threshold: f64,
+    pub false_positive_rate: f64,
+    pub true_positive_rate: f64,
+}
+
+fn check(scores: &[f64], positive: &[bool]) -> Result<(usize, usize), MetricsError> {
+    if scores.len() != positive.len() {
+        return Err(MetricsError::Length { expected: scores.len(), got: positive.len() });
+    }
+    let positives = positive.iter().filter(|&&p| p).count();
+    Ok((positives, positive.len() - positives))
+}
+
+/// Area under the ROC curve of `scores`, higher meaning more likely
+/// `positive`
+pub fn auc(scores: &[f64], positive: &[bool]) -> Result<Option<f64>, MetricsError> {
+    let (positives, negatives) = check(scores, positive)?;
+    if positives == 0 || negatives == 0 {
+        return Ok(None);
+    }
+    let mut order: Vec<usize> = (0..scores.len()).collect();
+    order.sort_by(|&a, &b| scores[a].total_cmp(&scores[b]));
+    // Ranks from 1, ties sharing their mean rank
+    let mut rank_sum = 0.0;
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && scores[order[end]] == scores[order[start]] {
+            end += 1;
+        }
+        let rank = (start + end + 1) as f64 / 2.0;
+        rank_sum += rank * order[start..end].iter().filter(|&&i| positive[i]).count() as f64;
+        start = end;
+    }
+    let p = positives as f64;
+    let u = rank_sum - p * (p + 1.0) / 2.0;
+    Ok(Some(u / (p * negatives as f64)))
+}
+
+/// The ROC curve from the highest threshold down, starting at (0, 0) and
+/// ending at (1, 1)
+pub fn roc_curve(scores: &[f64], positive: &[bool]) -> Result<Option<Vec<RocPoint>>, MetricsError> {
+    let (positives, negatives) = check(scores, positive)?;
+    if positives == 0 || negatives == 0 {
+        return Ok(None);
+    }
+    let mut order: Vec<usize> = (0..scores.len()).collect();
+    order.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+    let mut curve = vec![RocPoint { threshold: f64::INFINITY, false_positive_rate: 0.0, true_positive_rate: 0.0 }];
+    let (mut tp, mut fp) = (0, 0);
+    for (n, &i) in order.iter().enumerate() {
+        if positive[i] {
+            tp += 1;
+        } else {
+            fp += 1;
+        }
+        // One point per distinct score, after all windows tied at it
+        if order.get(n + 1).is_some_and(|&next| scores[next] == scores[i]) {
+            continue;
+        }
+        curve.push(RocPoint {
+            threshold: scores[i],
+            false_positive_rate: fp as f64 / negatives as f64,
+            true_positive_rate: tp as f64 / positives as f64,
+        });
+    }
+    Ok(Some(curve))
+}
+
+/// One-vs-rest AUC of every class from each window's class probabilities
+pub fn one_vs_rest_auc(probabilities: &[Vec<f64>], truth: &[usize], classes: usize) -> Result<Vec<Option<f64>>, MetricsError> {
+    if probabilities.len() != truth.len() {
+        return Err(MetricsError::Length { expected: truth.len(), got: probabilities.len() });
+    }
+    if let Some(row) = probabilities.iter().find(|row| row.len() != classes) {
+        return Err(MetricsError::Length { expected: classes, got: row.len() });
+    }
+    if let Some(&index) = truth.iter().find(|&&t| t >= classes) {
+        return Err(MetricsError::Class { index, classes });
+    }
+    (0..classes)
+        .map(|k| {
+            let scores: Vec<f64> = probabilities.iter().map(|row| row[k]).collect();
+            let positive: Vec<bool> = truth.iter().map(|&t| t == k).collect();
+            auc(&scores, &positive)
+        })
+        .collect()
+}
+
+/// Mean of the defined one-vs-rest areas; with two classes both are the
+/// same, so this is the binary AUC
+pub fn macro_auc(per_class: &[Option<f64>]) -> Option<f64> {
+    let defined: Vec<f64> = per_class.iter().flatten().copied().collect();
+    (!defined.is_empty()).then(|| defined.iter().sum::<f64>() / defined.len() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auc_of_perfect_reversed_and_tied_scores() {
+        let positive = [false, false, true, true];
+        assert_eq!(auc(&[0.1, 0.2, 0.8, 0.9], &positive), Ok(Some(1.0)));
+        assert_eq!(auc(&[0.9, 0.8, 0.2, 0.1], &positive), Ok(Some(0.0)));
+        assert_eq!(auc(&[0.5; 4], &positive), Ok(Some(0.5)));
+        // Three of the four positive-negative pairs are ordered right
+        assert_eq!(auc(&[0.1, 0.6, 0.5, 0.9], &positive), Ok(Some(0.75)));
+    }
+
+    #[test]
+    fn undefined_without_both_sides() {
+        assert_eq!(auc(&[0.1, 0.2], &[true, true]), Ok(None));
+        assert_eq!(roc_curve(&[0.1, 0.2], &[false, false]), Ok(None));
+        assert_eq!(auc(&[0.1], &[true, false]), Err(MetricsError::Length { expected: 1, got: 2 }));
+        assert_eq!(macro_auc(&[None, None]), None);
+    }
+
+    #[test]
+    fn curve_runs_from_origin_to_corner() {
+        let scores = [0.1, 0.4, 0.35, 0.8];
+        let positive = [false, false, true, true];
+        let curve = roc_curve(&scores, &positive).unwrap().unwrap();
+        let (first, last) = (curve[0], curve[curve.len() - 1]);
+        assert_eq!((first.false_positive_rate, first.true_positive_rate), (0.0, 0.0));
+        assert_eq!((last.false_positive_rate, last.true_positive_rate), (1.0, 1.0));
+        // Trapezoids under the curve give the rank-sum AUC
+        let area: f64 = curve
+            .windows(2)
+            .map(|w| (w[1].false_positive_rate - w[0].false_positive_rate) * (w[1].true_positive_rate + w[0].true_positive_rate) / 2.0)
+            .sum();
+        assert!((area - auc(&scores, &positive).unwrap().unwrap()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn one_vs_rest_scores_every_class() {
+        let probabilities = vec![vec![0.8, 0.1, 0.1], vec![0.2, 0.7, 0.1], vec![0.1, 0.2, 0.7], vec![0.6, 0.3, 0.1]];
+        let per_class = one_vs_rest_auc(&probabilities, &[0, 1, 2, 0], 3).unwrap();
+        assert_eq!(per_class, [Some(1.0), Some(1.0), Some(1.0)]);
+        assert_eq!(macro_auc(&[Some(1.0), None, Some(0.5)]), Some(0.75));
+        assert_eq!(one_vs_rest_auc(&probabilities, &[0, 1, 3, 0], 3), Err(MetricsError::Class { index: 3, classes: 3 }));
+    }
+}
@@ -0,0 +1,31 @@
+//! Batches as burn tensors.
+//!
+//! Windows and their mini-batches come from [`eeg_dsp::batch`]: a
+//! [`BatchLoader`] shuffles, augments and standardizes them, and every
+//! `[batch, channels, samples]` batch it yields is moved onto the device
+//! here with its class indices.
+
+use burn::tensor::backend::Backend;
+use burn::tensor::{Int, Tensor, TensorData};
+use eeg_dsp::BatchLoader;
+use ndarray::Array3;
+
+/// A batch and its labels as tensors
+pub fn tensors<B: Backend>(batch: Array3<f32>, labels: &[usize], device: &B::Device) -> (Tensor<B, 3>, Tensor<B, 1, Int>) {
+    let shape = batch.dim();
+    let values: Vec<f32> = batch.into_iter().collect();
+    let labels: Vec<i64> = labels.iter().map(|&l| l as i64).collect();
+    (
+        Tensor::from_data(TensorData::new(values, [shape.0, shape.1, shape.2]), device),
+        Tensor::from_data(TensorData::new(labels, [shape.0]), device),
+    )
+}
+
+/// The tensors of every batch of `epoch`
+pub fn tensor_batches<'a, B: Backend>(
+    loader: &'a BatchLoader,
+    epoch: u64,
+    device: &'a B::Device,
+) -> Result<impl Iterator<Item = (Tensor<B, 3>, Tensor<B, 1, Int>)> + 'a, eeg_dsp::BatchError> {
+    Ok(loader.epoch(epoch)?.map(move |(batch, labels)| tensors::<B>(batch, &labels, device)))
+}
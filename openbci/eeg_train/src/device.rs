@@ -0,0 +1,102 @@
+//! Where trained networks classify.
+//!
+//! Training always runs on the CPU. With the `wgpu` feature a
+//! [`TrainedModel`](crate::TrainedModel) can classify on a GPU through
+//! burn's wgpu backend (Vulkan, Metal, DirectX 12 or OpenGL), which pays
+//! off for larger networks benchmarked offline. [`probe`] says whether a
+//! device works before weights are moved there, so callers can fall back
+//! to the CPU.
+
+use crate::train::TrainError;
+use std::fmt;
+
+/// Where a network runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Device {
+    #[default]
+    Cpu,
+    /// The default wgpu adapter, or the discrete GPU of this index
+    Gpu(Option<usize>),
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cpu => f.write_str("CPU"),
+            Self::Gpu(None) => f.write_str("GPU"),
+            Self::Gpu(Some(index)) => write!(f, "GPU {}", index),
+        }
+    }
+}
+
+/// The GPU through wgpu
+#[cfg(feature = "wgpu")]
+pub type GpuBackend = burn::backend::Wgpu<f32>;
+
+#[cfg(feature = "wgpu")]
+pub(crate) fn wgpu_device(index: Option<usize>) -> burn::backend::wgpu::WgpuDevice {
+    use burn::backend::wgpu::WgpuDevice;
+    index.map_or(WgpuDevice::DefaultDevice, WgpuDevice::DiscreteGpu)
+}
+
+/// Whether `device` can run networks: a GPU is asked to add two numbers
+pub fn probe(device: Device) -> Result<(), TrainError> {
+    match device {
+        Device::Cpu => Ok(()),
+        #[cfg(feature = "wgpu")]
+        Device::Gpu(index) => {
+            use burn::tensor::Tensor;
+            // wgpu panics when no adapter fits; the message becomes the error
+            let sum = quiet::catch_unwind(|| {
+                let tensor = Tensor::<GpuBackend, 1>::from_floats([1.0, 2.0], &wgpu_device(index));
+                tensor.sum().into_scalar()
+            });
+            match sum {
+                Ok(3.0) => Ok(()),
+                Ok(sum) => Err(TrainError::Device(format!("{} added 1 and 2 to {}", device, sum))),
+                Err(panic) => {
+                    let reason = panic
+                        .downcast_ref::<String>()
+                        .cloned()
+                        .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                        .unwrap_or_else(|| "wgpu failed".to_string());
+                    Err(TrainError::Device(reason))
+                }
+            }
+        }
+        #[cfg(not(feature = "wgpu"))]
+        Device::Gpu(_) => Err(TrainError::Device("GPU support not compiled in, enable the wgpu feature".to_string())),
+    }
+}
+
+/// Catching a probe's panic without printing it. The process-wide panic
+/// hook is wrapped once, and only stays quiet on a thread that is probing,
+/// so panics elsewhere (and hooks installed before the first probe) are
+/// reported as usual.
+#[cfg(feature = "wgpu")]
+mod quiet {
+    use std::cell::Cell;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Once;
+
+    thread_local! {
+        static PROBING: Cell<bool> = const { Cell::new(false) };
+    }
+
+    static WRAP_HOOK: Once = Once::new();
+
+    pub(super) fn catch_unwind<T>(f: impl FnOnce() -> T) -> std::thread::Result<T> {
+        WRAP_HOOK.call_once(|| {
+            let previous = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                if !PROBING.with(Cell::get) {
+                    previous(info);
+                }
+            }));
+        });
+        PROBING.with(|p| p.set(true));
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        PROBING.with(|p| p.set(false));
+        result
+    }
+}
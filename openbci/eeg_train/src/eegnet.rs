@@ -0,0 +1,150 @@
+//! EEGNet (Lawhern et al., 2018).
+//!
+//! A compact convolutional network for windows of `channels x samples`:
+//!
+//! 1. a temporal convolution of `f1` filters, each a learned band-pass
+//!    `kernel_length` samples long (half a second by default);
+//! 2. a depthwise convolution across all channels, `depth` spatial filters
+//!    per temporal filter, like CSP, then ELU, average pooling by 4 and
+//!    dropout;
+//! 3. a separable convolution (depthwise in time, then pointwise mixing to
+//!    `f2` maps), ELU, average pooling by 8 and dropout;
+//! 4. a linear layer from the flattened maps to the class logits.
+//!
+//! Every convolution is batch normalized. Kernel lengths are odd so the
+//! temporal convolutions keep the window length.
+
+use crate::network::Network;
+use burn::module::Module;
+use burn::nn::conv::{Conv2d, Conv2dConfig};
+use burn::nn::pool::{AvgPool2d, AvgPool2dConfig};
+use burn::nn::{BatchNorm, BatchNormConfig, Dropout, DropoutConfig, Linear, LinearConfig, PaddingConfig2d};
+use burn::tensor::backend::Backend;
+use burn::tensor::Tensor;
+use serde::{Deserialize, Serialize};
+
+/// Shape and hyperparameters of an EEGNet
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EegNetConfig {
+    pub channels: usize,
+    pub samples: usize,
+    pub classes: usize,
+    /// Temporal filters
+    pub f1: usize,
+    /// Spatial filters per temporal filter
+    pub depth: usize,
+    /// Pointwise filters of the separable convolution
+    pub f2: usize,
+    /// Length of the temporal filters in samples, made odd
+    pub kernel_length: usize,
+    /// Length of the separable convolution in samples, made odd
+    pub separable_length: usize,
+    pub dropout: f64,
+}
+
+impl EegNetConfig {
+    /// EEGNet-8,2 for windows at `sample_rate`, with half-second temporal
+    /// filters
+    pub fn new(channels: usize, samples: usize, classes: usize, sample_rate: f64) -> Self {
+        Self {
+            channels,
+            samples,
+            classes,
+            f1: 8,
+            depth: 2,
+            f2: 16,
+            kernel_length: (sample_rate / 2.0).round() as usize,
+            separable_length: 16,
+            dropout: 0.25,
+        }
+    }
+
+    /// Maps left after both poolings
+    fn pooled(&self) -> usize {
+        self.samples / 4 / 8
+    }
+
+    /// Why the network cannot be built, if it cannot
+    pub fn check(&self) -> Result<(), String> {
+        if self.channels == 0 || self.classes < 2 {
+            return Err(format!("EEGNet needs channels and at least two classes, got {} and {}", self.channels, self.classes));
+        }
+        if self.f1 == 0 || self.depth == 0 || self.f2 == 0 || self.kernel_length == 0 || self.separable_length == 0 {
+            return Err("EEGNet filter counts and kernel lengths must be positive".to_string());
+        }
+        if self.pooled() == 0 {
+            return Err(format!("EEGNet windows need at least 32 samples, got {}", self.samples));
+        }
+        if !(0.0..1.0).contains(&self.dropout) {
+            return Err(format!("Dropout must be in [0, 1), got {}", self.dropout));
+        }
+        Ok(())
+    }
+
+    pub fn init<B: Backend>(&self, device: &B::Device) -> EegNet<B> {
+        let odd = |length: usize| length | 1;
+        let (kernel, separable) = (odd(self.kernel_length), odd(self.separable_length));
+        let spatial = self.f1 * self.depth;
+        EegNet {
+            temporal: Conv2dConfig::new([1, self.f1], [1, kernel])
+                .with_padding(PaddingConfig2d::Explicit(0, kernel / 2))
+                .with_bias(false)
+                .init(device),
+            temporal_norm: BatchNormConfig::new(self.f1).init(device),
+            spatial: Conv2dConfig::new([self.f1, spatial], [self.channels, 1])
+                .with_groups(self.f1)
+                .with_bias(false)
+                .init(device),
+            spatial_norm: BatchNormConfig::new(spatial).init(device),
+            pool_spatial: AvgPool2dConfig::new([1, 4]).with_strides([1, 4]).init(),
+            depthwise: Conv2dConfig::new([spatial, spatial], [1, separable])
+                .with_padding(PaddingConfig2d::Explicit(0, separable / 2))
+                .with_groups(spatial)
+                .with_bias(false)
+                .init(device),
+            pointwise: Conv2dConfig::new([spatial, self.f2], [1, 1]).with_bias(false).init(device),
+            separable_norm: BatchNormConfig::new(self.f2).init(device),
+            pool_separable: AvgPool2dConfig::new([1, 8]).with_strides([1, 8]).init(),
+            dropout: DropoutConfig::new(self.dropout).init(),
+            classifier: LinearConfig::new(self.f2 * self.pooled(), self.classes).init(device),
+        }
+    }
+}
+
+#[derive(Module, Debug)]
+pub struct EegNet<B: Backend> {
+    pub(crate) temporal: Conv2d<B>,
+    pub(crate) temporal_norm: BatchNorm<B>,
+    pub(crate) spatial: Conv2d<B>,
+    pub(crate) spatial_norm: BatchNorm<B>,
+    pub(crate) pool_spatial: AvgPool2d,
+    pub(crate) depthwise: Conv2d<B>,
+    pub(crate) pointwise: Conv2d<B>,
+    pub(crate) separable_norm: BatchNorm<B>,
+    pub(crate) pool_separable: AvgPool2d,
+    pub(crate) dropout: Dropout,
+    pub(crate) classifier: Linear<B>,
+}
+
+/// Exponential linear unit with alpha 1
+pub(crate) fn elu<B: Backend, const D: usize>(x: Tensor<B, D>) -> Tensor<B, D> {
+    x.clone().clamp_min(0.0) + x.clamp_max(0.0).exp().sub_scalar(1.0)
+}
+
+impl<B: Backend> Network<B> for EegNet<B> {
+    fn features(&self, windows: Tensor<B, 3>) -> Tensor<B, 2> {
+        let [batch, channels, samples] = windows.dims();
+        let x = windows.reshape([batch, 1, channels, samples]);
+        let x = self.temporal_norm.forward(self.temporal.forward(x));
+        let x = elu(self.spatial_norm.forward(self.spatial.forward(x)));
+        let x = self.dropout.forward(self.pool_spatial.forward(x));
+        let x = self.pointwise.forward(self.depthwise.forward(x));
+        let x = elu(self.separable_norm.forward(x));
+        let x = self.dropout.forward(self.pool_separable.forward(x));
+        x.flatten(1, 3)
+    }
+
+    fn readout(&self, features: Tensor<B, 2>) -> Tensor<B, 2> {
+        self.classifier.forward(features)
+    }
+}
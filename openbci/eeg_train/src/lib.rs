@@ -0,0 +1,37 @@
+//! Training EEG classifiers in Rust, on the CPU with burn.
+//!
+//! Small subject-specific networks can be fitted next to the recorder
+//! without a Python environment. EEGNet is built from its temporal,
+//! depthwise spatial and separable convolution blocks, and the tiny
+//! transformer from patch embeddings, positional encoding and a few
+//! self-attention layers; any [`Network`] takes `[batch, channels,
+//! samples]` windows and is trained by the same loop: Adam on cross-entropy
+//! over seeded mini-batches of a [`BatchLoader`], early stopping on the
+//! validation loss and checkpoints after every epoch to resume from,
+//! optionally on windows augmented by an [`eeg_dsp::Augmenter`] every
+//! epoch. Inputs are standardized per channel on the fly with statistics of
+//! the training windows, and a [`TrainedModel`] saves its weights with a
+//! JSON [`NetSpec`] of the channels, window, sample rate, classes and
+//! training history, then classifies one raw window at a time like an ONNX
+//! export, on the CPU or (with the `wgpu` feature) a GPU. A trained EEGNet
+//! quantizes to int8 for microcontrollers, as a blob or a C header with a
+//! reference inference.
+
+pub mod data;
+pub mod device;
+pub mod eegnet;
+pub mod model;
+pub mod network;
+pub mod quantize;
+pub mod train;
+pub mod transformer;
+
+pub use data::{tensor_batches, tensors};
+pub use device::Device;
+pub use eeg_dsp::{BatchLoader, Standardization, Windows};
+pub use eegnet::{EegNet, EegNetConfig};
+pub use model::{spec_path, NetSpec, TrainedModel};
+pub use network::{Architecture, CpuBackend, Net, Network, TrainingBackend};
+pub use quantize::{QuantizeError, QuantizedEegNet};
+pub use train::{evaluate, train, EpochStats, History, TrainError, TrainingConfig};
+pub use transformer::{TinyTransformer, TinyTransformerConfig};
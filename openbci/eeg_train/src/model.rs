@@ -0,0 +1,164 @@
+//! A trained network with everything needed to run it.
+//!
+//! The weights go to a `.mpk` file and a JSON [`NetSpec`] next to it, as an
+//! ONNX export sits next to its spec: the architecture, the classes in
+//! output order, the montage labels of the channels and the sample rate and
+//! window the network was trained on, the standardization of its inputs
+//! and how training went. A loaded network classifies on the CPU, or on the
+//! GPU it is moved to with [`TrainedModel::on_device`], and gives the
+//! features its last layer classifies, for readouts fitted elsewhere.
+
+#[cfg(feature = "wgpu")]
+use crate::device::{self, GpuBackend};
+use crate::device::Device;
+use crate::network::{Architecture, CpuBackend, Net};
+use crate::train::{History, TrainError, TrainingConfig};
+use burn::tensor::activation::softmax;
+use burn::tensor::backend::Backend;
+use burn::tensor::{Tensor, TensorData};
+use eeg_dsp::Standardization;
+use ndarray::ArrayView2;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Everything about a trained network but its weights
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetSpec {
+    pub architecture: Architecture,
+    /// Class names in output order
+    pub classes: Vec<String>,
+    /// Montage labels of the channels in input order
+    pub channel_names: Vec<String>,
+    pub sample_rate: f64,
+    /// Samples of a window
+    pub window: usize,
+    pub standardization: Standardization,
+    pub training: TrainingConfig,
+    pub history: History,
+}
+
+/// The spec next to the weights at `path`
+pub fn spec_path(path: &Path) -> PathBuf {
+    path.with_extension("json")
+}
+
+/// A network ready to classify windows, from any thread
+pub struct TrainedModel {
+    spec: NetSpec,
+    /// Burn parameters are not `Sync`
+    net: Mutex<Net<CpuBackend>>,
+    /// A copy on the GPU that classifies instead, with its adapter
+    #[cfg(feature = "wgpu")]
+    gpu: Option<(Mutex<Net<GpuBackend>>, Option<usize>)>,
+}
+
+impl TrainedModel {
+    pub fn new(spec: NetSpec, net: Net<CpuBackend>) -> Self {
+        Self {
+            spec,
+            net: Mutex::new(net),
+            #[cfg(feature = "wgpu")]
+            gpu: None,
+        }
+    }
+
+    /// Classify on `device` from now on; check it with
+    /// [`device::probe`](crate::device::probe) first, as a GPU without a
+    /// working adapter fails here
+    pub fn on_device(self, device: Device) -> Result<Self, TrainError> {
+        match device {
+            Device::Cpu => Ok(Self::new(self.spec, self.net.into_inner().unwrap_or_else(|e| e.into_inner()))),
+            #[cfg(feature = "wgpu")]
+            Device::Gpu(index) => {
+                device::probe(device)?;
+                let weights = self.net().to_bytes()?;
+                let net = self.spec.architecture.load_bytes::<GpuBackend>(weights, &device::wgpu_device(index))?;
+                Ok(Self { gpu: Some((Mutex::new(net), index)), ..self })
+            }
+            #[cfg(not(feature = "wgpu"))]
+            Device::Gpu(_) => crate::device::probe(device).map(|_| self),
+        }
+    }
+
+    /// Where windows are classified
+    pub fn device(&self) -> Device {
+        #[cfg(feature = "wgpu")]
+        if let Some((_, index)) = &self.gpu {
+            return Device::Gpu(*index);
+        }
+        Device::Cpu
+    }
+
+    pub(crate) fn net(&self) -> Net<CpuBackend> {
+        // A panic mid-forward leaves the weights as they were
+        self.net.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Load the weights at `path` (`.mpk`) and the spec next to them
+    pub fn open(path: &Path) -> Result<Self, TrainError> {
+        let spec: NetSpec = serde_json::from_str(&fs::read_to_string(spec_path(path))?)?;
+        let net = spec.architecture.load::<CpuBackend>(path, &Default::default())?;
+        Ok(Self::new(spec, net))
+    }
+
+    /// The network of `spec` with the weights of a `.mpk` file read into
+    /// `weights`
+    pub fn from_bytes(spec: NetSpec, weights: Vec<u8>) -> Result<Self, TrainError> {
+        let net = spec.architecture.load_bytes::<CpuBackend>(weights, &Default::default())?;
+        Ok(Self::new(spec, net))
+    }
+
+    /// Write the weights to `path` (as `.mpk`) and the spec next to them
+    pub fn save(&self, path: &Path) -> Result<(), TrainError> {
+        self.net().save(path)?;
+        fs::write(spec_path(path), serde_json::to_string_pretty(&self.spec)?)?;
+        Ok(())
+    }
+
+    pub fn spec(&self) -> &NetSpec {
+        &self.spec
+    }
+
+    pub fn num_params(&self) -> usize {
+        self.net().num_params()
+    }
+
+    /// Class probabilities of one raw `channels x window` window
+    pub fn predict(&self, window: ArrayView2<f32>) -> Result<Vec<f32>, TrainError> {
+        Ok(self.predict_features(window)?.0)
+    }
+
+    /// Class probabilities of one raw `channels x window` window, and the
+    /// features they come from
+    pub fn predict_features(&self, window: ArrayView2<f32>) -> Result<(Vec<f32>, Vec<f32>), TrainError> {
+        let (channels, samples, _) = self.spec.architecture.shape();
+        if window.dim() != (channels, samples) {
+            return Err(TrainError::Data(format!(
+                "Window is {:?} (channels, samples), the network takes ({}, {})",
+                window.dim(),
+                channels,
+                samples
+            )));
+        }
+        let mut window = window.to_owned();
+        self.spec.standardization.apply(window.view_mut());
+        let data = TensorData::new(window.iter().copied().collect::<Vec<f32>>(), [1, channels, samples]);
+        #[cfg(feature = "wgpu")]
+        if let Some((net, index)) = &self.gpu {
+            let net = net.lock().unwrap_or_else(|e| e.into_inner());
+            return forward(&net, data, &device::wgpu_device(*index));
+        }
+        forward(&self.net(), data, &Default::default())
+    }
+}
+
+/// Softmax of the logits `net` gives one `[1, channels, samples]` window,
+/// and its features
+fn forward<B: Backend>(net: &Net<B>, data: TensorData, device: &B::Device) -> Result<(Vec<f32>, Vec<f32>), TrainError> {
+    let features = net.features(Tensor::<B, 3>::from_data(data, device));
+    let probabilities = softmax(net.readout(features.clone()), 1);
+    let values = |tensor: Tensor<B, 2>| tensor.into_data().to_vec::<f32>().map_err(|e| TrainError::Data(format!("{:?}", e)));
+    Ok((values(probabilities)?, values(features)?))
+}
@@ -0,0 +1,167 @@
+//! The networks that can be trained, behind one interface.
+//!
+//! Every network takes a batch of `[batch, channels, samples]` windows and
+//! returns class logits, so the training loop, checkpoints and inference
+//! do not care which one they run. Each ends in a linear layer, whose
+//! inputs are the network's features. [`Architecture`] is the serialized
+//! choice with its hyperparameters, and [`Net`] the network it builds.
+
+use crate::eegnet::{EegNet, EegNetConfig};
+use crate::transformer::{TinyTransformer, TinyTransformerConfig};
+use burn::backend::{Autodiff, NdArray};
+use burn::module::Module;
+use burn::record::{FullPrecisionSettings, NamedMpkBytesRecorder, NamedMpkFileRecorder, Record, Recorder, RecorderError};
+use burn::tensor::backend::Backend;
+use burn::tensor::Tensor;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Where networks run: the CPU, through ndarray
+pub type CpuBackend = NdArray<f32>;
+/// The CPU with gradients, for training
+pub type TrainingBackend = Autodiff<CpuBackend>;
+
+/// Weights are saved as named MessagePack at full precision
+pub(crate) fn recorder() -> NamedMpkFileRecorder<FullPrecisionSettings> {
+    NamedMpkFileRecorder::new()
+}
+
+/// Save `record` to `path` as `.mpk`, replacing the file there
+pub(crate) fn save_record<B: Backend, R: Record<B>>(record: R, path: &Path) -> Result<(), RecorderError> {
+    let path = path.with_extension("mpk");
+    // The recorder warns on every file it replaces; checkpoints replace theirs each epoch
+    if path.exists() {
+        std::fs::remove_file(&path).map_err(|e| RecorderError::Unknown(e.to_string()))?;
+    }
+    Recorder::<B>::record(&recorder(), record, path)
+}
+
+/// A network from windows to class logits
+pub trait Network<B: Backend>: Module<B> {
+    /// Features `[batch, features]` of `[batch, channels, samples]`
+    /// windows, the inputs of the last layer
+    fn features(&self, windows: Tensor<B, 3>) -> Tensor<B, 2>;
+
+    /// Logits `[batch, classes]` of features
+    fn readout(&self, features: Tensor<B, 2>) -> Tensor<B, 2>;
+
+    /// Logits `[batch, classes]` of `[batch, channels, samples]` windows
+    fn forward(&self, windows: Tensor<B, 3>) -> Tensor<B, 2> {
+        self.readout(self.features(windows))
+    }
+}
+
+/// Which network, with its hyperparameters
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Architecture {
+    #[serde(rename = "eegnet")]
+    EegNet(EegNetConfig),
+    Transformer(TinyTransformerConfig),
+}
+
+impl Architecture {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::EegNet(_) => "EEGNet",
+            Self::Transformer(_) => "Tiny transformer",
+        }
+    }
+
+    /// Channels and samples of a window, and classes
+    pub fn shape(&self) -> (usize, usize, usize) {
+        match self {
+            Self::EegNet(config) => (config.channels, config.samples, config.classes),
+            Self::Transformer(config) => (config.channels, config.samples, config.classes),
+        }
+    }
+
+    /// Why the network cannot be built, if it cannot
+    pub fn check(&self) -> Result<(), String> {
+        match self {
+            Self::EegNet(config) => config.check(),
+            Self::Transformer(config) => config.check(),
+        }
+    }
+
+    /// A freshly initialized network
+    pub fn init<B: Backend>(&self, device: &B::Device) -> Net<B> {
+        match self {
+            Self::EegNet(config) => Net::EegNet(config.init(device)),
+            Self::Transformer(config) => Net::Transformer(config.init(device)),
+        }
+    }
+
+    /// The network with the weights saved at `path`
+    pub fn load<B: Backend>(&self, path: &Path, device: &B::Device) -> Result<Net<B>, RecorderError> {
+        Ok(match self.init::<B>(device) {
+            Net::EegNet(net) => Net::EegNet(net.load_file(path, &recorder(), device)?),
+            Net::Transformer(net) => Net::Transformer(net.load_file(path, &recorder(), device)?),
+        })
+    }
+
+    /// The network with the weights of a `.mpk` file read into `bytes`
+    pub fn load_bytes<B: Backend>(&self, bytes: Vec<u8>, device: &B::Device) -> Result<Net<B>, RecorderError> {
+        let recorder = NamedMpkBytesRecorder::<FullPrecisionSettings>::default();
+        Ok(match self.init::<B>(device) {
+            Net::EegNet(net) => Net::EegNet(net.load_record(recorder.load(bytes, device)?)),
+            Net::Transformer(net) => Net::Transformer(net.load_record(recorder.load(bytes, device)?)),
+        })
+    }
+}
+
+/// A built network of any architecture; built once, so never boxed
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum Net<B: Backend> {
+    EegNet(EegNet<B>),
+    Transformer(TinyTransformer<B>),
+}
+
+impl<B: Backend> Net<B> {
+    pub fn forward(&self, windows: Tensor<B, 3>) -> Tensor<B, 2> {
+        match self {
+            Self::EegNet(net) => net.forward(windows),
+            Self::Transformer(net) => net.forward(windows),
+        }
+    }
+
+    pub fn features(&self, windows: Tensor<B, 3>) -> Tensor<B, 2> {
+        match self {
+            Self::EegNet(net) => net.features(windows),
+            Self::Transformer(net) => net.features(windows),
+        }
+    }
+
+    pub fn readout(&self, features: Tensor<B, 2>) -> Tensor<B, 2> {
+        match self {
+            Self::EegNet(net) => net.readout(features),
+            Self::Transformer(net) => net.readout(features),
+        }
+    }
+
+    /// Trainable parameters
+    pub fn num_params(&self) -> usize {
+        match self {
+            Self::EegNet(net) => net.num_params(),
+            Self::Transformer(net) => net.num_params(),
+        }
+    }
+
+    /// The weights as the bytes of a `.mpk` file
+    pub fn to_bytes(self) -> Result<Vec<u8>, RecorderError> {
+        let recorder = NamedMpkBytesRecorder::<FullPrecisionSettings>::default();
+        match self {
+            Self::EegNet(net) => Recorder::<B>::record(&recorder, net.into_record(), ()),
+            Self::Transformer(net) => Recorder::<B>::record(&recorder, net.into_record(), ()),
+        }
+    }
+
+    /// Save the weights to `path`, replacing its extension with `.mpk`
+    pub fn save(self, path: &Path) -> Result<(), RecorderError> {
+        match self {
+            Self::EegNet(net) => save_record::<B, _>(net.into_record(), path),
+            Self::Transformer(net) => save_record::<B, _>(net.into_record(), path),
+        }
+    }
+}
@@ -0,0 +1,680 @@
+//! EEGNet in 8-bit integers, for microcontrollers.
+//!
+//! A trained EEGNet fits on an ESP32 once its weights are int8. The batch
+//! norms are folded into the convolutions next to them, every row of a
+//! layer's weights (one output) gets its own scale, and the activations
+//! after each block get one scale each, from the largest value the float
+//! network reaches on calibration windows. Products are summed in 32 bits
+//! and rescaled in float, which the ESP32's FPU does cheaply.
+//!
+//! [`QuantizedEegNet::logits`] is the reference of what a device computes;
+//! calibration runs the folded float weights through the same code. The
+//! network is written as a little-endian blob to read back with
+//! [`QuantizedEegNet::from_bytes`], e.g. from `include_bytes!`, or as a C
+//! header carrying the same reference in C.
+
+use crate::eegnet::{EegNet, EegNetConfig};
+use crate::model::TrainedModel;
+use crate::network::{Architecture, CpuBackend, Net};
+use burn::nn::BatchNorm;
+use burn::tensor::Tensor;
+use eeg_dsp::{Standardization, Windows};
+use ndarray::ArrayView2;
+use std::fmt::Write;
+use std::ops::{Add, Mul};
+use thiserror::Error;
+
+const MAGIC: &[u8; 4] = b"EEGQ";
+const VERSION: u32 = 1;
+
+#[derive(Debug, Error)]
+pub enum QuantizeError {
+    #[error("Only EEGNet can be quantized, not the {0}")]
+    Architecture(&'static str),
+    #[error("Calibrating needs at least one window")]
+    NoWindows,
+    #[error("Window is {found:?} (channels, samples), the network takes {expected:?}")]
+    Shape { found: (usize, usize), expected: (usize, usize) },
+    #[error("Failed to read the weights: {0}")]
+    Tensor(String),
+    #[error("Not a quantized EEGNet: {0}")]
+    Blob(String),
+}
+
+/// Sizes of an EEGNet, with its kernel lengths as built (odd)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EegNetShape {
+    pub channels: usize,
+    pub samples: usize,
+    pub classes: usize,
+    pub f1: usize,
+    pub depth: usize,
+    pub f2: usize,
+    pub kernel_length: usize,
+    pub separable_length: usize,
+}
+
+impl EegNetShape {
+    pub fn new(config: &EegNetConfig) -> Self {
+        Self {
+            channels: config.channels,
+            samples: config.samples,
+            classes: config.classes,
+            f1: config.f1,
+            depth: config.depth,
+            f2: config.f2,
+            kernel_length: config.kernel_length | 1,
+            separable_length: config.separable_length | 1,
+        }
+    }
+
+    /// Maps after the spatial filters
+    pub fn spatial(&self) -> usize {
+        self.f1 * self.depth
+    }
+
+    /// Samples after the first pooling
+    pub fn quarter(&self) -> usize {
+        self.samples / 4
+    }
+
+    /// Samples after the second pooling
+    pub fn pooled(&self) -> usize {
+        self.quarter() / 8
+    }
+
+    /// Rows and row length of the temporal, spatial, depthwise, pointwise
+    /// and classifier weights
+    fn layers(&self) -> [(usize, usize); 5] {
+        [
+            (self.f1, self.kernel_length),
+            (self.spatial(), self.channels),
+            (self.spatial(), self.separable_length),
+            (self.f2, self.spatial()),
+            (self.classes, self.f2 * self.pooled()),
+        ]
+    }
+}
+
+/// Weights of one layer, a row per output, with a scale and bias per row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer<T> {
+    pub weights: Vec<T>,
+    /// Real value of one unit of a row's weights
+    pub scales: Vec<f32>,
+    pub bias: Vec<f32>,
+}
+
+impl<T> Layer<T> {
+    fn row(&self, i: usize) -> &[T] {
+        let width = self.weights.len() / self.scales.len();
+        &self.weights[i * width..(i + 1) * width]
+    }
+}
+
+impl Layer<f32> {
+    fn float(weights: Vec<f32>, bias: Vec<f32>) -> Self {
+        Self { weights, scales: vec![1.0; bias.len()], bias }
+    }
+
+    /// Every row in int8 at the scale of its largest weight
+    fn quantize(&self) -> Layer<i8> {
+        let mut quantized = Layer { weights: Vec::with_capacity(self.weights.len()), scales: Vec::new(), bias: self.bias.clone() };
+        for i in 0..self.scales.len() {
+            let row = self.row(i);
+            let scale = unit(row.iter().fold(0.0f32, |max, w| max.max(w.abs()))) * self.scales[i];
+            quantized.weights.extend(row.iter().map(|&w| i8::quantize(w, scale)));
+            quantized.scales.push(scale);
+        }
+        quantized
+    }
+}
+
+/// The weights of every layer, batch norms folded in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layers<T> {
+    pub temporal: Layer<T>,
+    pub spatial: Layer<T>,
+    pub depthwise: Layer<T>,
+    pub pointwise: Layer<T>,
+    pub classifier: Layer<T>,
+}
+
+/// An EEGNet with int8 weights and activations
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizedEegNet {
+    pub shape: EegNetShape,
+    pub sample_rate: f64,
+    /// Class names in output order
+    pub classes: Vec<String>,
+    /// Montage labels of the channels in input order
+    pub channel_names: Vec<String>,
+    pub standardization: Standardization,
+    /// Real value of one unit of the input and of the activations after the
+    /// temporal filters, the first pooling, the depthwise filters and the
+    /// second pooling
+    pub activations: [f32; 5],
+    pub layers: Layers<i8>,
+}
+
+impl QuantizedEegNet {
+    /// Quantize the EEGNet of `model`, scaling activations to the raw
+    /// `calibration` windows, typically the training windows
+    pub fn new(model: &TrainedModel, calibration: &Windows) -> Result<Self, QuantizeError> {
+        let spec = model.spec();
+        let (Architecture::EegNet(config), Net::EegNet(net)) = (&spec.architecture, model.net()) else {
+            return Err(QuantizeError::Architecture(spec.architecture.name()));
+        };
+        let shape = EegNetShape::new(config);
+        if calibration.is_empty() {
+            return Err(QuantizeError::NoWindows);
+        }
+        if calibration.shape() != (shape.channels, shape.samples) {
+            return Err(QuantizeError::Shape { found: calibration.shape(), expected: (shape.channels, shape.samples) });
+        }
+        let float = fold(&net, &shape)?;
+        let mut largest = [0.0f32; 5];
+        for window in calibration.windows.outer_iter() {
+            let mut window = window.to_owned();
+            spec.standardization.apply(window.view_mut());
+            let values: Vec<f32> = window.iter().copied().collect();
+            run(&shape, &float, &[1.0; 5], &values, &mut |stage, real| largest[stage] = largest[stage].max(real.abs()));
+        }
+        Ok(Self {
+            shape,
+            sample_rate: spec.sample_rate,
+            classes: spec.classes.clone(),
+            channel_names: spec.channel_names.clone(),
+            standardization: spec.standardization.clone(),
+            activations: largest.map(unit),
+            layers: Layers {
+                temporal: float.temporal.quantize(),
+                spatial: float.spatial.quantize(),
+                depthwise: float.depthwise.quantize(),
+                pointwise: float.pointwise.quantize(),
+                classifier: float.classifier.quantize(),
+            },
+        })
+    }
+
+    /// Class logits of one raw `channels x samples` window, in integer
+    /// arithmetic as on the device
+    pub fn logits(&self, window: ArrayView2<f32>) -> Result<Vec<f32>, QuantizeError> {
+        let expected = (self.shape.channels, self.shape.samples);
+        if window.dim() != expected {
+            return Err(QuantizeError::Shape { found: window.dim(), expected });
+        }
+        let mut window = window.to_owned();
+        self.standardization.apply(window.view_mut());
+        let values: Vec<f32> = window.iter().copied().collect();
+        Ok(run(&self.shape, &self.layers, &self.activations, &values, &mut |_, _| {}))
+    }
+
+    /// Class probabilities of one raw window
+    pub fn predict(&self, window: ArrayView2<f32>) -> Result<Vec<f32>, QuantizeError> {
+        let logits = self.logits(window)?;
+        let max = logits.iter().fold(f32::NEG_INFINITY, |max, &l| max.max(l));
+        let exp: Vec<f32> = logits.iter().map(|l| (l - max).exp()).collect();
+        let total: f32 = exp.iter().sum();
+        Ok(exp.into_iter().map(|e| e / total).collect())
+    }
+
+    /// Bytes of the int8 weights
+    pub fn weight_bytes(&self) -> usize {
+        self.layer_list().iter().map(|layer| layer.weights.len()).sum()
+    }
+
+    fn layer_list(&self) -> [&Layer<i8>; 5] {
+        let l = &self.layers;
+        [&l.temporal, &l.spatial, &l.depthwise, &l.pointwise, &l.classifier]
+    }
+
+    /// The network as a blob: `EEGQ`, the format version and the sizes as
+    /// u32, the sample rate as f64, the standardization and activation
+    /// scales as f32, then per layer the row scales and biases as f32 and
+    /// the weights as i8, and last the class and channel names as
+    /// length-prefixed UTF-8; all little-endian
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        let s = &self.shape;
+        for value in [VERSION as usize, s.channels, s.samples, s.classes, s.f1, s.depth, s.f2, s.kernel_length, s.separable_length] {
+            bytes.extend((value as u32).to_le_bytes());
+        }
+        bytes.extend(self.sample_rate.to_le_bytes());
+        let floats = self.standardization.center.iter().chain(&self.standardization.scale).chain(&self.activations);
+        bytes.extend(floats.flat_map(|v| v.to_le_bytes()));
+        for layer in self.layer_list() {
+            bytes.extend(layer.scales.iter().chain(&layer.bias).flat_map(|v| v.to_le_bytes()));
+            bytes.extend(layer.weights.iter().map(|&w| w as u8));
+        }
+        for name in self.classes.iter().chain(&self.channel_names) {
+            bytes.extend((name.len() as u32).to_le_bytes());
+            bytes.extend(name.as_bytes());
+        }
+        bytes
+    }
+
+    /// Read a blob of [`to_bytes`](Self::to_bytes)
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, QuantizeError> {
+        let mut blob = Blob(bytes);
+        if blob.take(4)? != MAGIC {
+            return Err(QuantizeError::Blob("missing EEGQ header".to_string()));
+        }
+        let version = blob.u32()?;
+        if version != VERSION {
+            return Err(QuantizeError::Blob(format!("version {}, expected {}", version, VERSION)));
+        }
+        let mut sizes = [0usize; 8];
+        for size in &mut sizes {
+            *size = blob.u32()? as usize;
+        }
+        let [channels, samples, classes, f1, depth, f2, kernel_length, separable_length] = sizes;
+        let shape = EegNetShape { channels, samples, classes, f1, depth, f2, kernel_length, separable_length };
+        if sizes.contains(&0) || shape.pooled() == 0 {
+            return Err(QuantizeError::Blob(format!("invalid sizes {:?}", sizes)));
+        }
+        let sample_rate = f64::from_le_bytes(blob.take(8)?.try_into().expect("8 bytes"));
+        let standardization = Standardization { center: blob.f32s(channels)?, scale: blob.f32s(channels)? };
+        let activations: [f32; 5] = blob.f32s(5)?.try_into().expect("5 scales");
+        let mut layers = Vec::with_capacity(5);
+        for (rows, width) in shape.layers() {
+            let scales = blob.f32s(rows)?;
+            let bias = blob.f32s(rows)?;
+            let count = rows.checked_mul(width).ok_or_else(|| QuantizeError::Blob("layer too large".to_string()))?;
+            let weights = blob.take(count)?.iter().map(|&b| b as i8).collect();
+            layers.push(Layer { weights, scales, bias });
+        }
+        let [temporal, spatial, depthwise, pointwise, classifier]: [Layer<i8>; 5] = layers.try_into().expect("5 layers");
+        let names = (0..classes + channels).map(|_| blob.string()).collect::<Result<Vec<_>, _>>()?;
+        if !blob.0.is_empty() {
+            return Err(QuantizeError::Blob(format!("{} trailing bytes", blob.0.len())));
+        }
+        Ok(Self {
+            shape,
+            sample_rate,
+            classes: names[..classes].to_vec(),
+            channel_names: names[classes..].to_vec(),
+            standardization,
+            activations,
+            layers: Layers { temporal, spatial, depthwise, pointwise, classifier },
+        })
+    }
+
+    /// The network as a C header: the sizes as macros, the weights as const
+    /// arrays and `<prefix>_logits`, the reference inference in C
+    pub fn c_header(&self, prefix: &str) -> String {
+        let lower: String = prefix.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect();
+        let upper = lower.to_ascii_uppercase();
+        let s = &self.shape;
+        let mut h = String::new();
+        let quoted = |names: &[String]| names.iter().map(|n| format!("{:?}", n)).collect::<Vec<_>>().join(", ");
+        writeln!(h, "/* EEGNet in int8: {} classes ({}),", s.classes, self.classes.join(", ")).unwrap();
+        writeln!(h, " * {} channels ({}) of {} samples at {} Hz.", s.channels, self.channel_names.join(", "), s.samples, self.sample_rate).unwrap();
+        writeln!(h, " * {}_logits() is the reference inference; it keeps its activations in", lower).unwrap();
+        writeln!(h, " * static buffers, so it is not reentrant. */").unwrap();
+        writeln!(h, "#ifndef {}_H\n#define {}_H\n\n#include <math.h>\n#include <stdint.h>\n", upper, upper).unwrap();
+        for (name, value) in [
+            ("CHANNELS", s.channels),
+            ("SAMPLES", s.samples),
+            ("CLASSES", s.classes),
+            ("F1", s.f1),
+            ("DEPTH", s.depth),
+            ("SPATIAL", s.spatial()),
+            ("F2", s.f2),
+            ("KERNEL", s.kernel_length),
+            ("SEPARABLE", s.separable_length),
+            ("QUARTER", s.quarter()),
+            ("POOLED", s.pooled()),
+        ] {
+            writeln!(h, "#define {}_{} {}", upper, name, value).unwrap();
+        }
+        writeln!(h, "#define {}_SAMPLE_RATE {}\n", upper, c_float(self.sample_rate as f32)).unwrap();
+        writeln!(h, "static const char *const {}_CLASS_NAMES[{}] = {{{}}};", upper, s.classes, quoted(&self.classes)).unwrap();
+        writeln!(h, "static const char *const {}_CHANNEL_NAMES[{}] = {{{}}};", upper, s.channels, quoted(&self.channel_names)).unwrap();
+        c_array(&mut h, "float", &format!("{}_CENTER", upper), &self.standardization.center, |v| c_float(*v));
+        c_array(&mut h, "float", &format!("{}_SCALE", upper), &self.standardization.scale, |v| c_float(*v));
+        c_array(&mut h, "float", &format!("{}_ACTIVATIONS", upper), &self.activations, |v| c_float(*v));
+        for (name, layer) in ["TEMPORAL", "SPATIAL", "DEPTHWISE", "POINTWISE", "CLASSIFIER"].iter().zip(self.layer_list()) {
+            c_array(&mut h, "int8_t", &format!("{}_{}_WEIGHTS", upper, name), &layer.weights, |v| v.to_string());
+            c_array(&mut h, "float", &format!("{}_{}_SCALES", upper, name), &layer.scales, |v| c_float(*v));
+            c_array(&mut h, "float", &format!("{}_{}_BIAS", upper, name), &layer.bias, |v| c_float(*v));
+        }
+        h.push_str(&C_REFERENCE.replace("PREFIX_", &format!("{}_", upper)).replace("prefix_", &format!("{}_", lower)));
+        writeln!(h, "\n#endif /* {}_H */", upper).unwrap();
+        h
+    }
+}
+
+/// The scale that puts `largest` at 127
+fn unit(largest: f32) -> f32 {
+    if largest > 0.0 {
+        largest / 127.0
+    } else {
+        1.0
+    }
+}
+
+/// Values of the int8 run or the float one it is calibrated with
+trait Value: Copy {
+    type Sum: Copy + Default + Add<Output = Self::Sum> + Mul<Output = Self::Sum>;
+    fn widen(self) -> Self::Sum;
+    fn real(sum: Self::Sum) -> f32;
+    /// `real` in units of `scale`
+    fn quantize(real: f32, scale: f32) -> Self;
+}
+
+impl Value for i8 {
+    type Sum = i32;
+
+    fn widen(self) -> i32 {
+        self as i32
+    }
+
+    fn real(sum: i32) -> f32 {
+        sum as f32
+    }
+
+    fn quantize(real: f32, scale: f32) -> i8 {
+        (real / scale).round().clamp(-127.0, 127.0) as i8
+    }
+}
+
+impl Value for f32 {
+    type Sum = f32;
+
+    fn widen(self) -> f32 {
+        self
+    }
+
+    fn real(sum: f32) -> f32 {
+        sum
+    }
+
+    fn quantize(real: f32, scale: f32) -> f32 {
+        real / scale
+    }
+}
+
+/// Logits of a standardized window (channel after channel), activations
+/// kept in units of `activations`; `observe` sees every activation with its
+/// stage before it is quantized
+fn run<T: Value>(
+    shape: &EegNetShape,
+    layers: &Layers<T>,
+    activations: &[f32; 5],
+    window: &[f32],
+    observe: &mut impl FnMut(usize, f32),
+) -> Vec<f32> {
+    let (samples, quarter) = (shape.samples, shape.quarter());
+    let mut quantize = |stage: usize, real: f32| {
+        observe(stage, real);
+        T::quantize(real, activations[stage])
+    };
+    let input: Vec<T> = window.iter().map(|&x| quantize(0, x)).collect();
+
+    // Temporal filters on every channel, [f1][channels][samples]
+    let mut temporal = Vec::with_capacity(shape.f1 * input.len());
+    for f in 0..shape.f1 {
+        let layer = &layers.temporal;
+        let unit = activations[0] * layer.scales[f];
+        for channel in input.chunks(samples) {
+            for t in 0..samples {
+                temporal.push(quantize(1, unit * T::real(correlate(channel, layer.row(f), t)) + layer.bias[f]));
+            }
+        }
+    }
+    // Spatial filters of each temporal filter's maps, ELU and pooling by 4
+    let mut spatial = Vec::with_capacity(shape.spatial() * quarter);
+    for o in 0..shape.spatial() {
+        let layer = &layers.spatial;
+        let unit = activations[1] * layer.scales[o];
+        let maps = &temporal[o / shape.depth * shape.channels * samples..];
+        for j in 0..quarter {
+            let pooled: f32 = (4 * j..4 * j + 4)
+                .map(|t| elu(unit * T::real(dot(layer.row(o), maps[t..].iter().step_by(samples))) + layer.bias[o]))
+                .sum();
+            spatial.push(quantize(2, pooled / 4.0));
+        }
+    }
+    // Depthwise filters in time
+    let mut depthwise = Vec::with_capacity(spatial.len());
+    for (o, map) in spatial.chunks(quarter).enumerate() {
+        let layer = &layers.depthwise;
+        let unit = activations[2] * layer.scales[o];
+        for t in 0..quarter {
+            depthwise.push(quantize(3, unit * T::real(correlate(map, layer.row(o), t)) + layer.bias[o]));
+        }
+    }
+    // Pointwise mixing, ELU and pooling by 8
+    let mut features = Vec::with_capacity(shape.f2 * shape.pooled());
+    for g in 0..shape.f2 {
+        let layer = &layers.pointwise;
+        let unit = activations[3] * layer.scales[g];
+        for j in 0..shape.pooled() {
+            let pooled: f32 = (8 * j..8 * j + 8)
+                .map(|t| elu(unit * T::real(dot(layer.row(g), depthwise[t..].iter().step_by(quarter))) + layer.bias[g]))
+                .sum();
+            features.push(quantize(4, pooled / 8.0));
+        }
+    }
+    let layer = &layers.classifier;
+    (0..shape.classes)
+        .map(|n| activations[4] * layer.scales[n] * T::real(dot(layer.row(n), features.iter())) + layer.bias[n])
+        .collect()
+}
+
+/// `kernel` against `signal` centred on `at`, zero outside the signal
+fn correlate<T: Value>(signal: &[T], kernel: &[T], at: usize) -> T::Sum {
+    let half = kernel.len() / 2;
+    kernel.iter().enumerate().fold(T::Sum::default(), |sum, (k, &w)| {
+        match (at + k).checked_sub(half).and_then(|i| signal.get(i)) {
+            Some(&x) => sum + w.widen() * x.widen(),
+            None => sum,
+        }
+    })
+}
+
+fn dot<'a, T: Value + 'a>(weights: &[T], values: impl Iterator<Item = &'a T>) -> T::Sum {
+    weights.iter().zip(values).fold(T::Sum::default(), |sum, (&w, &x)| sum + w.widen() * x.widen())
+}
+
+fn elu(x: f32) -> f32 {
+    if x > 0.0 {
+        x
+    } else {
+        x.exp() - 1.0
+    }
+}
+
+fn values<const D: usize>(tensor: Tensor<CpuBackend, D>) -> Result<Vec<f32>, QuantizeError> {
+    tensor.into_data().to_vec::<f32>().map_err(|e| QuantizeError::Tensor(format!("{:?}", e)))
+}
+
+/// Scale and shift of a batch norm at inference
+fn affine(norm: &BatchNorm<CpuBackend>) -> Result<(Vec<f32>, Vec<f32>), QuantizeError> {
+    let (gamma, beta) = (values(norm.gamma.val())?, values(norm.beta.val())?);
+    let (mean, var) = (values(norm.running_mean.value())?, values(norm.running_var.value())?);
+    let scale: Vec<f32> = gamma.iter().zip(&var).map(|(g, v)| g / (v + norm.epsilon as f32).sqrt()).collect();
+    let shift = beta.iter().zip(&mean).zip(&scale).map(|((b, m), s)| b - s * m).collect();
+    Ok((scale, shift))
+}
+
+/// The float weights of `net` with its batch norms folded in
+fn fold(net: &EegNet<CpuBackend>, shape: &EegNetShape) -> Result<Layers<f32>, QuantizeError> {
+    let (temporal_scale, temporal_shift) = affine(&net.temporal_norm)?;
+    let temporal: Vec<f32> = values(net.temporal.weight.val())?
+        .chunks(shape.kernel_length)
+        .zip(&temporal_scale)
+        .flat_map(|(row, a)| row.iter().map(move |w| w * a))
+        .collect();
+
+    let (spatial_scale, spatial_shift) = affine(&net.spatial_norm)?;
+    let (mut spatial, mut spatial_bias) = (Vec::new(), Vec::new());
+    for (o, row) in values(net.spatial.weight.val())?.chunks(shape.channels).enumerate() {
+        spatial.extend(row.iter().map(|w| w * spatial_scale[o]));
+        // The temporal norm's shift passes through the spatial filter
+        let shift = temporal_shift[o / shape.depth] * row.iter().sum::<f32>();
+        spatial_bias.push(spatial_scale[o] * shift + spatial_shift[o]);
+    }
+
+    let (pointwise_scale, pointwise_shift) = affine(&net.separable_norm)?;
+    let pointwise: Vec<f32> = values(net.pointwise.weight.val())?
+        .chunks(shape.spatial())
+        .zip(&pointwise_scale)
+        .flat_map(|(row, a)| row.iter().map(move |w| w * a))
+        .collect();
+
+    // Burn keeps linear weights as [inputs, outputs]
+    let linear = values(net.classifier.weight.val())?;
+    let classifier = (0..shape.classes).flat_map(|n| linear.iter().skip(n).step_by(shape.classes).copied()).collect();
+    let classifier_bias = match &net.classifier.bias {
+        Some(bias) => values(bias.val())?,
+        None => vec![0.0; shape.classes],
+    };
+
+    Ok(Layers {
+        temporal: Layer::float(temporal, vec![0.0; shape.f1]),
+        spatial: Layer::float(spatial, spatial_bias),
+        depthwise: Layer::float(values(net.depthwise.weight.val())?, vec![0.0; shape.spatial()]),
+        pointwise: Layer::float(pointwise, pointwise_shift),
+        classifier: Layer::float(classifier, classifier_bias),
+    })
+}
+
+/// Reads a blob front to back
+struct Blob<'a>(&'a [u8]);
+
+impl<'a> Blob<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], QuantizeError> {
+        if self.0.len() < count {
+            return Err(QuantizeError::Blob("truncated".to_string()));
+        }
+        let (head, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, QuantizeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().expect("4 bytes")))
+    }
+
+    fn f32s(&mut self, count: usize) -> Result<Vec<f32>, QuantizeError> {
+        let bytes = self.take(count.checked_mul(4).ok_or_else(|| QuantizeError::Blob("layer too large".to_string()))?)?;
+        Ok(bytes.chunks(4).map(|b| f32::from_le_bytes(b.try_into().expect("4 bytes"))).collect())
+    }
+
+    fn string(&mut self) -> Result<String, QuantizeError> {
+        let length = self.u32()? as usize;
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|e| QuantizeError::Blob(e.to_string()))
+    }
+}
+
+/// A float literal C reads back exactly
+fn c_float(v: f32) -> String {
+    format!("{:?}f", v)
+}
+
+fn c_array<T>(h: &mut String, kind: &str, name: &str, values: &[T], literal: impl Fn(&T) -> String) {
+    writeln!(h, "static const {} {}[{}] = {{", kind, name, values.len()).unwrap();
+    for line in values.chunks(16) {
+        writeln!(h, "    {},", line.iter().map(&literal).collect::<Vec<_>>().join(", ")).unwrap();
+    }
+    h.push_str("};\n");
+}
+
+/// [`run`] in C, for the int8 weights of the header
+const C_REFERENCE: &str = r#"
+static inline int8_t prefix_quantize(float real, float scale) {
+    float q = roundf(real / scale);
+    return (int8_t)(q > 127.0f ? 127.0f : q < -127.0f ? -127.0f : q);
+}
+
+static inline float prefix_elu(float x) {
+    return x > 0.0f ? x : expf(x) - 1.0f;
+}
+
+/* kernel against signal centred on at, zero outside the signal */
+static inline int32_t prefix_correlate(const int8_t *signal, int length, const int8_t *kernel, int kernel_length, int at) {
+    int32_t sum = 0;
+    for (int k = 0; k < kernel_length; k++) {
+        int i = at + k - kernel_length / 2;
+        if (i >= 0 && i < length) {
+            sum += (int32_t)kernel[k] * (int32_t)signal[i];
+        }
+    }
+    return sum;
+}
+
+/* Class logits of one raw window, window[channel * PREFIX_SAMPLES + sample] */
+static inline void prefix_logits(const float *window, float *logits) {
+    static int8_t input[PREFIX_CHANNELS * PREFIX_SAMPLES];
+    static int8_t temporal[PREFIX_F1 * PREFIX_CHANNELS * PREFIX_SAMPLES];
+    static int8_t spatial[PREFIX_SPATIAL * PREFIX_QUARTER];
+    static int8_t depthwise[PREFIX_SPATIAL * PREFIX_QUARTER];
+    static int8_t features[PREFIX_F2 * PREFIX_POOLED];
+    const float *act = PREFIX_ACTIVATIONS;
+
+    for (int c = 0; c < PREFIX_CHANNELS; c++) {
+        for (int t = 0; t < PREFIX_SAMPLES; t++) {
+            float x = (window[c * PREFIX_SAMPLES + t] - PREFIX_CENTER[c]) / PREFIX_SCALE[c];
+            input[c * PREFIX_SAMPLES + t] = prefix_quantize(x, act[0]);
+        }
+    }
+    for (int f = 0; f < PREFIX_F1; f++) {
+        float unit = act[0] * PREFIX_TEMPORAL_SCALES[f];
+        for (int c = 0; c < PREFIX_CHANNELS; c++) {
+            for (int t = 0; t < PREFIX_SAMPLES; t++) {
+                int32_t sum = prefix_correlate(&input[c * PREFIX_SAMPLES], PREFIX_SAMPLES,
+                                               &PREFIX_TEMPORAL_WEIGHTS[f * PREFIX_KERNEL], PREFIX_KERNEL, t);
+                float real = unit * (float)sum + PREFIX_TEMPORAL_BIAS[f];
+                temporal[(f * PREFIX_CHANNELS + c) * PREFIX_SAMPLES + t] = prefix_quantize(real, act[1]);
+            }
+        }
+    }
+    for (int o = 0; o < PREFIX_SPATIAL; o++) {
+        float unit = act[1] * PREFIX_SPATIAL_SCALES[o];
+        const int8_t *maps = &temporal[o / PREFIX_DEPTH * PREFIX_CHANNELS * PREFIX_SAMPLES];
+        for (int j = 0; j < PREFIX_QUARTER; j++) {
+            float pooled = 0.0f;
+            for (int t = 4 * j; t < 4 * j + 4; t++) {
+                int32_t sum = 0;
+                for (int c = 0; c < PREFIX_CHANNELS; c++) {
+                    sum += (int32_t)PREFIX_SPATIAL_WEIGHTS[o * PREFIX_CHANNELS + c] * (int32_t)maps[c * PREFIX_SAMPLES + t];
+                }
+                pooled += prefix_elu(unit * (float)sum + PREFIX_SPATIAL_BIAS[o]);
+            }
+            spatial[o * PREFIX_QUARTER + j] = prefix_quantize(pooled / 4.0f, act[2]);
+        }
+    }
+    for (int o = 0; o < PREFIX_SPATIAL; o++) {
+        float unit = act[2] * PREFIX_DEPTHWISE_SCALES[o];
+        for (int t = 0; t < PREFIX_QUARTER; t++) {
+            int32_t sum = prefix_correlate(&spatial[o * PREFIX_QUARTER], PREFIX_QUARTER,
+                                           &PREFIX_DEPTHWISE_WEIGHTS[o * PREFIX_SEPARABLE], PREFIX_SEPARABLE, t);
+            float real = unit * (float)sum + PREFIX_DEPTHWISE_BIAS[o];
+            depthwise[o * PREFIX_QUARTER + t] = prefix_quantize(real, act[3]);
+        }
+    }
+    for (int g = 0; g < PREFIX_F2; g++) {
+        float unit = act[3] * PREFIX_POINTWISE_SCALES[g];
+        for (int j = 0; j < PREFIX_POOLED; j++) {
+            float pooled = 0.0f;
+            for (int t = 8 * j; t < 8 * j + 8; t++) {
+                int32_t sum = 0;
+                for (int o = 0; o < PREFIX_SPATIAL; o++) {
+                    sum += (int32_t)PREFIX_POINTWISE_WEIGHTS[g * PREFIX_SPATIAL + o] * (int32_t)depthwise[o * PREFIX_QUARTER + t];
+                }
+                pooled += prefix_elu(unit * (float)sum + PREFIX_POINTWISE_BIAS[g]);
+            }
+            features[g * PREFIX_POOLED + j] = prefix_quantize(pooled / 8.0f, act[4]);
+        }
+    }
+    for (int n = 0; n < PREFIX_CLASSES; n++) {
+        int32_t sum = 0;
+        for (int i = 0; i < PREFIX_F2 * PREFIX_POOLED; i++) {
+            sum += (int32_t)PREFIX_CLASSIFIER_WEIGHTS[n * PREFIX_F2 * PREFIX_POOLED + i] * (int32_t)features[i];
+        }
+        logits[n] = act[4] * PREFIX_CLASSIFIER_SCALES[n] * (float)sum + PREFIX_CLASSIFIER_BIAS[n];
+    }
+}
+"#;
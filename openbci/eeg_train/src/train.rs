@@ -0,0 +1,285 @@
+//! The training loop.
+//!
+//! Mini-batches of the raw windows come from a [`BatchLoader`], in a fresh
+//! seeded order every epoch, augmented afresh when the config has an
+//! [`Augmenter`] and standardized on the way, and the network is fitted
+//! with Adam on cross-entropy. After each epoch the validation windows are
+//! scored; the epoch with the lowest validation loss (training loss without
+//! validation windows) is kept, and training stops once `patience` epochs
+//! in a row have not improved on it.
+//!
+//! With a checkpoint directory, the latest weights, the optimizer state and
+//! the history so far are written there after every epoch, and the best
+//! weights whenever they improve, so an interrupted run resumes where it
+//! stopped.
+
+use crate::data::tensor_batches;
+use crate::network::{recorder, save_record, Architecture, CpuBackend, Net, Network, TrainingBackend};
+use eeg_dsp::{Augmenter, BatchError, BatchLoader, Standardization, Windows};
+use burn::module::{AutodiffModule, Module};
+use burn::nn::loss::CrossEntropyLossConfig;
+use burn::optim::decay::WeightDecayConfig;
+use burn::optim::{AdamConfig, GradientsParams, Optimizer};
+use burn::record::{Recorder, RecorderError};
+use burn::tensor::backend::Backend;
+use burn::tensor::ElementConversion;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TrainError {
+    #[error("{0}")]
+    Config(String),
+    #[error("{0}")]
+    Data(String),
+    #[error("Failed to save or load weights: {0}")]
+    Record(#[from] RecorderError),
+    #[error("Failed to access checkpoint: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to read checkpoint: {0}")]
+    Format(#[from] serde_json::Error),
+    #[error("Failed to load a batch: {0}")]
+    Batch(#[from] BatchError),
+    #[error("Device unavailable: {0}")]
+    Device(String),
+}
+
+/// How a network is fitted
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrainingConfig {
+    pub epochs: usize,
+    pub batch_size: usize,
+    pub learning_rate: f64,
+    /// L2 penalty on the weights, 0 for none
+    pub weight_decay: f64,
+    /// Epochs without improvement before stopping
+    pub patience: usize,
+    pub seed: u64,
+    /// Applied to the training windows every epoch
+    #[serde(default)]
+    pub augmentation: Option<Augmenter>,
+}
+
+impl Default for TrainingConfig {
+    fn default() -> Self {
+        Self {
+            epochs: 300,
+            batch_size: 32,
+            learning_rate: 1e-3,
+            weight_decay: 0.0,
+            patience: 30,
+            seed: 0,
+            augmentation: None,
+        }
+    }
+}
+
+/// Losses and accuracies after one epoch
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EpochStats {
+    /// From 1
+    pub epoch: usize,
+    pub train_loss: f64,
+    pub train_accuracy: f64,
+    pub valid_loss: Option<f64>,
+    pub valid_accuracy: Option<f64>,
+    pub seconds: f64,
+}
+
+impl EpochStats {
+    /// What early stopping watches
+    pub fn monitored_loss(&self) -> f64 {
+        self.valid_loss.unwrap_or(self.train_loss)
+    }
+}
+
+/// Every epoch so far and the one kept
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct History {
+    pub epochs: Vec<EpochStats>,
+    /// Epoch whose weights are kept, from 1; 0 before the first
+    pub best_epoch: usize,
+    /// Stopped for lack of improvement before the last epoch
+    pub stopped_early: bool,
+}
+
+impl History {
+    pub fn best(&self) -> Option<&EpochStats> {
+        self.best_epoch.checked_sub(1).and_then(|i| self.epochs.get(i))
+    }
+
+    /// Training is over: out of epochs or patience
+    fn done(&self, config: &TrainingConfig) -> bool {
+        self.stopped_early || self.epochs.len() >= config.epochs
+    }
+}
+
+const LAST: &str = "last";
+const BEST: &str = "best";
+const OPTIMIZER: &str = "optimizer";
+const HISTORY: &str = "history.json";
+
+/// Train a new network of `architecture` on raw windows standardized by
+/// `standardization`, or resume the run checkpointed in `checkpoints`;
+/// returns the best network and the history
+pub fn train(
+    architecture: &Architecture,
+    config: &TrainingConfig,
+    standardization: &Standardization,
+    train: &Windows,
+    valid: &Windows,
+    checkpoints: Option<&Path>,
+    on_epoch: impl FnMut(&EpochStats),
+) -> Result<(Net<CpuBackend>, History), TrainError> {
+    architecture.check().map_err(TrainError::Config)?;
+    if config.epochs == 0 || config.batch_size == 0 || config.learning_rate <= 0.0 {
+        return Err(TrainError::Config("Epochs, batch size and learning rate must be positive".to_string()));
+    }
+    let (channels, samples, classes) = architecture.shape();
+    for (name, examples) in [("training", train), ("validation", valid)] {
+        if !examples.is_empty() && examples.shape() != (channels, samples) {
+            return Err(TrainError::Data(format!(
+                "{} windows are {:?} (channels, samples), the network takes ({}, {})",
+                name,
+                examples.shape(),
+                channels,
+                samples
+            )));
+        }
+        if let Some(&label) = examples.labels.iter().find(|&&l| l >= classes) {
+            return Err(TrainError::Data(format!("{} label {} is out of range for {} classes", name, label, classes)));
+        }
+    }
+    if train.is_empty() {
+        return Err(TrainError::Data("No training windows".to_string()));
+    }
+
+    let mut train = BatchLoader::new(train, config.batch_size)
+        .with_shuffle(config.seed)
+        .with_standardization(standardization.clone());
+    if let Some(augmenter) = &config.augmentation {
+        train = train.with_augmenter(augmenter.clone());
+    }
+    let valid = BatchLoader::new(valid, config.batch_size).with_standardization(standardization.clone());
+
+    let device = Default::default();
+    TrainingBackend::seed(&device, config.seed);
+    Ok(match architecture.init::<TrainingBackend>(&device) {
+        Net::EegNet(net) => {
+            let (net, history) = fit(net, config, &train, &valid, checkpoints, on_epoch)?;
+            (Net::EegNet(net), history)
+        }
+        Net::Transformer(net) => {
+            let (net, history) = fit(net, config, &train, &valid, checkpoints, on_epoch)?;
+            (Net::Transformer(net), history)
+        }
+    })
+}
+
+fn fit<N>(
+    mut net: N,
+    config: &TrainingConfig,
+    train: &BatchLoader,
+    valid: &BatchLoader,
+    checkpoints: Option<&Path>,
+    mut on_epoch: impl FnMut(&EpochStats),
+) -> Result<(N::InnerModule, History), TrainError>
+where
+    N: AutodiffModule<TrainingBackend> + Network<TrainingBackend>,
+    N::InnerModule: Network<CpuBackend>,
+{
+    let device = Default::default();
+    let mut optimizer = AdamConfig::new()
+        .with_weight_decay((config.weight_decay > 0.0).then(|| WeightDecayConfig::new(config.weight_decay as f32)))
+        .init::<TrainingBackend, N>();
+    let mut history = History::default();
+    let mut best = net.valid();
+
+    if let Some(dir) = checkpoints {
+        if dir.join(HISTORY).exists() {
+            history = serde_json::from_str(&fs::read_to_string(dir.join(HISTORY))?)?;
+            net = net.load_file(dir.join(LAST), &recorder(), &device)?;
+            optimizer = optimizer.load_record(recorder().load(dir.join(OPTIMIZER).with_extension("mpk"), &device)?);
+            best = best.load_file(dir.join(BEST), &recorder(), &device)?;
+        } else {
+            fs::create_dir_all(dir)?;
+        }
+    }
+
+    let loss_fn = CrossEntropyLossConfig::new().init(&device);
+    let count = train.windows().len();
+    while !history.done(config) {
+        let start = Instant::now();
+        let epoch = history.epochs.len() + 1;
+        let (mut loss_sum, mut correct) = (0.0, 0);
+        for (windows, labels) in tensor_batches::<TrainingBackend>(train, epoch as u64, &device)? {
+            let size = labels.dims()[0];
+            let logits = net.forward(windows);
+            correct += hits(&logits, &labels);
+            let loss = loss_fn.forward(logits, labels);
+            loss_sum += loss.clone().into_scalar().elem::<f64>() * size as f64;
+            let grads = GradientsParams::from_grads(loss.backward(), &net);
+            net = optimizer.step(config.learning_rate, net, grads);
+        }
+        let (valid_loss, valid_accuracy) = match valid.windows().is_empty() {
+            true => (None, None),
+            false => {
+                let (loss, accuracy) = evaluate(&net.valid(), valid)?;
+                (Some(loss), Some(accuracy))
+            }
+        };
+        let stats = EpochStats {
+            epoch,
+            train_loss: loss_sum / count as f64,
+            train_accuracy: correct as f64 / count as f64,
+            valid_loss,
+            valid_accuracy,
+            seconds: start.elapsed().as_secs_f64(),
+        };
+
+        let improved = history.best().is_none_or(|best| stats.monitored_loss() < best.monitored_loss());
+        if improved {
+            history.best_epoch = epoch;
+            best = net.valid();
+        }
+        on_epoch(&stats);
+        history.epochs.push(stats);
+        if epoch - history.best_epoch >= config.patience.max(1) && epoch < config.epochs {
+            history.stopped_early = true;
+        }
+
+        if let Some(dir) = checkpoints {
+            if improved {
+                save_record::<CpuBackend, _>(best.clone().into_record(), &dir.join(BEST))?;
+            }
+            save_record::<TrainingBackend, _>(net.clone().into_record(), &dir.join(LAST))?;
+            save_record::<TrainingBackend, _>(optimizer.to_record(), &dir.join(OPTIMIZER))?;
+            fs::write(dir.join(HISTORY), serde_json::to_string_pretty(&history)?)?;
+        }
+    }
+    Ok((best, history))
+}
+
+/// Windows of the batch whose most likely class is their label
+fn hits<B: Backend>(logits: &burn::tensor::Tensor<B, 2>, labels: &burn::tensor::Tensor<B, 1, burn::tensor::Int>) -> usize {
+    let predicted = logits.clone().argmax(1).flatten::<1>(0, 1);
+    predicted.equal(labels.clone()).int().sum().into_scalar().elem::<i64>() as usize
+}
+
+/// Mean loss and accuracy of a network over the windows of `loader`
+pub fn evaluate<N: Network<CpuBackend>>(net: &N, loader: &BatchLoader) -> Result<(f64, f64), TrainError> {
+    let device = Default::default();
+    let loss_fn = CrossEntropyLossConfig::new().init(&device);
+    let (mut loss_sum, mut correct) = (0.0, 0);
+    for (windows, labels) in tensor_batches::<CpuBackend>(loader, 0, &device)? {
+        let size = labels.dims()[0];
+        let logits = net.forward(windows);
+        correct += hits(&logits, &labels);
+        loss_sum += loss_fn.forward(logits, labels).into_scalar().elem::<f64>() * size as f64;
+    }
+    let n = loader.windows().len().max(1) as f64;
+    Ok((loss_sum / n, correct as f64 / n))
+}
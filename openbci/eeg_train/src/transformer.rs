@@ -0,0 +1,132 @@
+//! A tiny transformer encoder for EEG windows.
+//!
+//! 1. Patch embedding: the window is cut into non-overlapping patches of
+//!    `patch_length` samples across all channels, and each patch is
+//!    projected to a `d_model` token by one strided convolution;
+//! 2. sinusoidal positional encoding of the patch order, then dropout;
+//! 3. `layers` pre-norm encoder layers of `heads`-head self-attention and a
+//!    `d_ff` feed-forward block;
+//! 4. a head: layer norm of the tokens averaged over patches and a linear
+//!    layer to the class logits.
+//!
+//! Samples after the last whole patch are left out.
+
+use crate::network::Network;
+use burn::module::Module;
+use burn::nn::conv::{Conv1d, Conv1dConfig};
+use burn::nn::transformer::{TransformerEncoder, TransformerEncoderConfig, TransformerEncoderInput};
+use burn::nn::{Dropout, DropoutConfig, LayerNorm, LayerNormConfig, Linear, LinearConfig, PositionalEncoding, PositionalEncodingConfig};
+use burn::tensor::backend::Backend;
+use burn::tensor::Tensor;
+use serde::{Deserialize, Serialize};
+
+/// Shape and hyperparameters of a tiny transformer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TinyTransformerConfig {
+    pub channels: usize,
+    pub samples: usize,
+    pub classes: usize,
+    /// Samples of a patch, one token each
+    pub patch_length: usize,
+    /// Token size
+    pub d_model: usize,
+    /// Attention heads, dividing `d_model`
+    pub heads: usize,
+    /// Encoder layers
+    pub layers: usize,
+    /// Hidden size of the feed-forward blocks
+    pub d_ff: usize,
+    pub dropout: f64,
+}
+
+impl TinyTransformerConfig {
+    /// Two layers of four heads over 32-wide tokens of 100 ms patches, for
+    /// windows at `sample_rate`
+    pub fn new(channels: usize, samples: usize, classes: usize, sample_rate: f64) -> Self {
+        Self {
+            channels,
+            samples,
+            classes,
+            patch_length: ((sample_rate / 10.0).round() as usize).max(1),
+            d_model: 32,
+            heads: 4,
+            layers: 2,
+            d_ff: 64,
+            dropout: 0.1,
+        }
+    }
+
+    /// Tokens of a window
+    fn patches(&self) -> usize {
+        self.samples / self.patch_length.max(1)
+    }
+
+    /// Why the network cannot be built, if it cannot
+    pub fn check(&self) -> Result<(), String> {
+        if self.channels == 0 || self.classes < 2 {
+            return Err(format!(
+                "The transformer needs channels and at least two classes, got {} and {}",
+                self.channels, self.classes
+            ));
+        }
+        if self.patch_length == 0 || self.d_model == 0 || self.heads == 0 || self.layers == 0 || self.d_ff == 0 {
+            return Err("Transformer patch length, sizes, heads and layers must be positive".to_string());
+        }
+        if !self.d_model.is_multiple_of(self.heads) {
+            return Err(format!("{} heads do not divide a token of {}", self.heads, self.d_model));
+        }
+        if self.patches() == 0 {
+            return Err(format!("Windows of {} samples are shorter than a {}-sample patch", self.samples, self.patch_length));
+        }
+        if !(0.0..1.0).contains(&self.dropout) {
+            return Err(format!("Dropout must be in [0, 1), got {}", self.dropout));
+        }
+        Ok(())
+    }
+
+    pub fn init<B: Backend>(&self, device: &B::Device) -> TinyTransformer<B> {
+        TinyTransformer {
+            embedding: Conv1dConfig::new(self.channels, self.d_model, self.patch_length)
+                .with_stride(self.patch_length)
+                .init(device),
+            position: PositionalEncodingConfig::new(self.d_model).with_max_sequence_size(self.patches()).init(device),
+            dropout: DropoutConfig::new(self.dropout).init(),
+            encoder: TransformerEncoderConfig::new(self.d_model, self.d_ff, self.heads, self.layers)
+                .with_dropout(self.dropout)
+                .with_norm_first(true)
+                .init(device),
+            norm: LayerNormConfig::new(self.d_model).init(device),
+            classifier: LinearConfig::new(self.d_model, self.classes).init(device),
+            patch_length: self.patch_length,
+        }
+    }
+}
+
+#[derive(Module, Debug)]
+pub struct TinyTransformer<B: Backend> {
+    embedding: Conv1d<B>,
+    position: PositionalEncoding<B>,
+    dropout: Dropout,
+    encoder: TransformerEncoder<B>,
+    norm: LayerNorm<B>,
+    classifier: Linear<B>,
+    patch_length: usize,
+}
+
+impl<B: Backend> Network<B> for TinyTransformer<B> {
+    fn features(&self, windows: Tensor<B, 3>) -> Tensor<B, 2> {
+        let [batch, _, samples] = windows.dims();
+        let whole = samples / self.patch_length * self.patch_length;
+        // [batch, d_model, patches] to one token per patch
+        let tokens = self.embedding.forward(windows.narrow(2, 0, whole)).swap_dims(1, 2);
+        let tokens = self.dropout.forward(self.position.forward(tokens));
+        let tokens = self.encoder.forward(TransformerEncoderInput::new(tokens));
+        let [_, _, d_model] = tokens.dims();
+        let pooled = tokens.mean_dim(1).reshape([batch, d_model]);
+        self.norm.forward(pooled)
+    }
+
+    fn readout(&self, features: Tensor<B, 2>) -> Tensor<B, 2> {
+        self.classifier.forward(features)
+    }
+}
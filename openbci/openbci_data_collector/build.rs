@@ -0,0 +1,23 @@
+//! Embeds the git commit the collector is built from, for the trial
+//! metadata. Builds outside a git checkout simply go without it.
+
+use std::process::Command;
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    let text = String::from_utf8(output.stdout).ok()?;
+    (output.status.success() && !text.trim().is_empty()).then(|| text.trim().to_string())
+}
+
+fn main() {
+    if let Some(commit) = git(&["describe", "--always", "--dirty", "--abbrev=12"]) {
+        println!("cargo:rustc-env=OPENBCI_GIT_COMMIT={}", commit);
+    }
+    // A commit moves HEAD's branch and rewrites the index
+    if let Some(dir) = git(&["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", dir);
+        println!("cargo:rerun-if-changed={}/index", dir);
+    } else {
+        println!("cargo:rerun-if-changed=build.rs");
+    }
+}
@@ -17,6 +17,15 @@ echo "========================================="
 echo ""
 read -p "Press Enter to start data collection..."
 
+# Confirm which electrode is on which channel once per session
+if [ ! -f "motor_imagery_data/$SUBJECT/$SESSION/montage.json" ]; then
+    cargo run --release -- check \
+        --montage-wizard \
+        --subject-id "$SUBJECT" \
+        --session-id "$SESSION" \
+        --channels 2
+fi
+
 # Classes for motor imagery
 CLASSES=("left_hand" "right_hand" "rest")
 
@@ -30,21 +30,18 @@ echo "Class:    $CLASS"
 echo "Trial:    $TRIAL"
 echo "========================================="
 echo ""
-echo "Starting in 3 seconds..."
-sleep 1
-echo "2..."
-sleep 1
-echo "1..."
-sleep 1
-echo "GO! Start imagining the movement..."
+echo "Watch the fixation cross, then imagine the movement the arrow points to"
+echo "until RELAX appears."
 echo ""
 
-cargo run --release -- \
+cargo run --release -- record \
     --class "$CLASS" \
     --trial "$TRIAL" \
     --subject-id "$SUBJECT" \
     --session-id "$SESSION" \
-    --duration 5 \
+    --duration 7 \
+    --cues \
+    --cue-delay 2 \
     --channels 2
 
 echo ""
@@ -48,14 +48,14 @@ for subject_dir in "$DATA_DIR"/*; do
                         3) class_name="rest" ;;
                     esac
 
-                    count=$(find "$session_dir" -name "*_class_${class_id}_*.csv" | wc -l)
+                    count=$(find "$session_dir" -name "*_class_${class_id}_*.csv*" | wc -l)
                     if [ $count -gt 0 ]; then
                         echo "      Class $class_id ($class_name): $count trials"
                     fi
                 done
 
                 # Total trials
-                total=$(find "$session_dir" -name "*_class_*.csv" | wc -l)
+                total=$(find "$session_dir" -name "*_class_*.csv*" | wc -l)
                 echo "      Total trials: $total"
                 echo ""
             fi
@@ -67,7 +67,7 @@ done
 echo "========================================="
 echo "Overall Dataset Statistics"
 echo "========================================="
-total_trials=$(find "$DATA_DIR" -name "*_class_*.csv" | wc -l)
+total_trials=$(find "$DATA_DIR" -name "*_class_*.csv*" | wc -l)
 total_metadata=$(find "$DATA_DIR" -name "*_metadata.json" | wc -l)
 echo "Total trials: $total_trials"
 echo "Total metadata files: $total_metadata"
@@ -84,7 +84,7 @@ for class_id in 0 1 2 3; do
         3) class_name="rest" ;;
     esac
 
-    count=$(find "$DATA_DIR" -name "*_class_${class_id}_*.csv" | wc -l)
+    count=$(find "$DATA_DIR" -name "*_class_${class_id}_*.csv*" | wc -l)
     echo "  Class $class_id ($class_name): $count trials"
 done
 
@@ -14,15 +14,30 @@ import torch
 from torch.utils.data import Dataset, DataLoader
 
 
+def session_passed_qc(session_dir: Path) -> bool:
+    """Check session_manifest.json; sessions without one predate QC and pass"""
+    manifest_file = Path(session_dir) / "session_manifest.json"
+    if not manifest_file.exists():
+        return True
+    with open(manifest_file, 'r') as f:
+        manifest = json.load(f)
+    passed = manifest.get('qc', {}).get('passed', False)
+    if not passed:
+        print(f"Skipping {session_dir}: failed QC {manifest.get('qc', {}).get('failures', [])}")
+    return passed
+
+
 class MotorImageryDataset(Dataset):
     """PyTorch Dataset for motor imagery EEG data"""
 
-    def __init__(self, data_dir: str, subject_id: str = None, session_id: str = None):
+    def __init__(self, data_dir: str, subject_id: str = None, session_id: str = None,
+                 include_failed_qc: bool = False):
         """
         Args:
             data_dir: Root directory containing motor_imagery_data
             subject_id: Specific subject (e.g., 'S01') or None for all
             session_id: Specific session (e.g., 'session_01') or None for all
+            include_failed_qc: Also load sessions flagged as failing QC
         """
         self.data = []
         self.labels = []
@@ -42,16 +57,19 @@ class MotorImageryDataset(Dataset):
 
         pattern = str(Path(pattern) / "*_class_*.csv")
 
-        # Load all matching CSV files
-        csv_files = glob.glob(pattern)
-        print(f"Found {len(csv_files)} trial files")
+        # Load all matching CSV files, including --compress ones (.csv.gz,
+        # .csv.zst; pandas needs the zstandard package for the latter)
+        csv_files = glob.glob(pattern + '*')
+        if not include_failed_qc:
+            csv_files = [f for f in csv_files if session_passed_qc(Path(f).parent)]
+        print(f"Found {len(csv_files)} CSV trial files")
 
         for csv_file in sorted(csv_files):
             df = pd.read_csv(csv_file)
 
-            # Extract EEG channels (exclude timestamp, sample_id, class_id)
+            # Extract EEG channels (exclude timestamp, sample_id, class_id, marker)
             channel_cols = [col for col in df.columns
-                          if col not in ['timestamp', 'sample_id', 'class_id']]
+                          if col not in ['timestamp', 'sample_id', 'class_id', 'marker']]
             eeg_data = df[channel_cols].values
 
             # Get label
@@ -74,6 +92,50 @@ class MotorImageryDataset(Dataset):
             self.labels.append(int(label))
             self.metadata.append(metadata)
 
+        # Trials recorded with --format npz need no parsing at all
+        npz_files = glob.glob(pattern[:-len('.csv')] + '.npz')
+        if not include_failed_qc:
+            npz_files = [f for f in npz_files if session_passed_qc(Path(f).parent)]
+        for npz_file in sorted(npz_files):
+            with np.load(npz_file) as trial:
+                self.data.append(torch.from_numpy(trial['X']))
+                self.labels.append(int(trial['y']))
+                self.metadata.append(json.loads(str(trial['meta'])))
+
+        # HDF5 trials hold the same arrays as NPZ; h5py is only needed for them
+        h5_files = glob.glob(pattern[:-len('.csv')] + '.h5')
+        if not include_failed_qc:
+            h5_files = [f for f in h5_files if session_passed_qc(Path(f).parent)]
+        if h5_files:
+            import h5py
+        for h5_file in sorted(h5_files):
+            with h5py.File(h5_file, 'r') as trial:
+                self.data.append(torch.from_numpy(trial['X'][:]))
+                self.labels.append(int(trial['y'][()]))
+                self.metadata.append(json.loads(trial['meta'][()]))
+
+        # BrainVision trials are float32 µV, multiplexed; scale back to nV
+        vhdr_files = glob.glob(pattern[:-len('.csv')] + '.vhdr')
+        if not include_failed_qc:
+            vhdr_files = [f for f in vhdr_files if session_passed_qc(Path(f).parent)]
+        for vhdr_file in sorted(vhdr_files):
+            with open(vhdr_file, 'r') as f:
+                n_channels = int(next(line for line in f
+                                      if line.startswith('NumberOfChannels=')).split('=')[1])
+            eeg = np.fromfile(vhdr_file[:-len('.vhdr')] + '.eeg', dtype='<f4')
+            eeg_data = eeg.reshape(-1, n_channels) * 1000.0
+            label = int(vhdr_file.rsplit('_class_', 1)[1].split('_')[0])
+
+            meta_files = glob.glob(vhdr_file.rsplit('_', 1)[0] + '_metadata.json')
+            metadata = {}
+            if meta_files:
+                with open(meta_files[0], 'r') as f:
+                    metadata = json.load(f)
+
+            self.data.append(torch.FloatTensor(eeg_data))
+            self.labels.append(label)
+            self.metadata.append(metadata)
+
     def __len__(self):
         return len(self.data)
 
@@ -102,7 +164,8 @@ def load_data_numpy(data_dir: str, subject_id: str = None, session_id: str = Non
     return X, y
 
 
-def load_data_pandas(data_dir: str, subject_id: str = None) -> pd.DataFrame:
+def load_data_pandas(data_dir: str, subject_id: str = None,
+                     include_failed_qc: bool = False) -> pd.DataFrame:
     """
     Load all data as a single pandas DataFrame
 
@@ -117,7 +180,9 @@ def load_data_pandas(data_dir: str, subject_id: str = None) -> pd.DataFrame:
 
     pattern = str(Path(pattern) / "*_class_*.csv")
 
-    csv_files = glob.glob(pattern)
+    csv_files = glob.glob(pattern + '*')
+    if not include_failed_qc:
+        csv_files = [f for f in csv_files if session_passed_qc(Path(f).parent)]
     print(f"Loading {len(csv_files)} files...")
 
     dfs = []
@@ -154,6 +219,9 @@ def print_dataset_info(data_dir: str):
         meta = dataset.metadata[0]
         print("\nRecording parameters:")
         print(f"  Sample rate: {meta.get('sample_rate', 'N/A')} Hz")
+        measured = meta.get('measured_sample_rate')
+        if measured:
+            print(f"  Measured rate: {measured['measured_hz']:.2f} Hz ({measured['deviation_percent']:+.2f}%)")
         print(f"  Duration: {meta.get('duration_seconds', 'N/A')} seconds")
         print(f"  Channels: {meta.get('electrode_config', {}).get('channels', 'N/A')}")
 
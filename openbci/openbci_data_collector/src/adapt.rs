@@ -0,0 +1,375 @@
+//! Keeping a model in step with a long session.
+//!
+//! EEG drifts over an hour of driving the robot: electrodes dry out, the
+//! subject tires and a model fitted at the start loses its edge. An
+//! [`Adapter`] pools the session's windows that carry a label, from a block
+//! the subject was cued through (`--label-blocks`) or from a prediction the
+//! model was confident of (`--adapt-confidence`), and every `--adapt-every`
+//! seconds refits part of the model to the latest of them: the LDA of a
+//! CSP+LDA model from `openbci calibrate` (its CSP filters as well with
+//! `--adapt-csp`), or an LDA readout on the frozen features of a network
+//! from `openbci_train` in place of its last layer. ONNX models do not show
+//! their features and SSVEP decoders fit nothing, so neither adapts. A
+//! [`Refit`] is fitted beside the stream, and windows are classified with
+//! the latest [`Readout`] through [`classify`].
+
+use crate::calibration::CalibrationModel;
+use crate::classifier::Classifier;
+use crate::riemann::Shrinkage;
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+#[cfg(feature = "train")]
+use eeg_dsp::Classifier as _;
+use eeg_dsp::{Csp, Lda};
+use log::{info, warn};
+use ndarray::{Array1, Array2, ArrayView2};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Options of adapting a model online
+#[derive(clap::Args, Debug, Clone)]
+pub struct AdaptArgs {
+    /// Refit the model every this many seconds of the session, from its
+    /// labelled and confident windows; off unless given
+    #[arg(long, value_name = "SECONDS")]
+    pub adapt_every: Option<f64>,
+
+    /// Learn from windows whose best probability reaches this, as that
+    /// class [default: only from --label-blocks]
+    #[arg(long, value_name = "P")]
+    pub adapt_confidence: Option<f32>,
+
+    /// CSV of blocks the subject was cued through, with `start` and `end`
+    /// in seconds from the first sample and the `class`
+    #[arg(long, value_name = "FILE")]
+    pub label_blocks: Option<PathBuf>,
+
+    /// Most recent windows a refit learns from
+    #[arg(long, default_value = "480")]
+    pub adapt_memory: usize,
+
+    /// Windows of every class a refit needs
+    #[arg(long, default_value = "20")]
+    pub adapt_min: usize,
+
+    /// Refit a CSP+LDA model's CSP filters too, not only its LDA
+    #[arg(long)]
+    pub adapt_csp: bool,
+
+    /// Covariance shrinkage of the refits: lw (Ledoit-Wolf) or a fixed
+    /// intensity in [0, 1]
+    #[arg(long, default_value = "lw")]
+    pub adapt_shrinkage: Shrinkage,
+
+    /// Save the last refitted CSP+LDA model to this file
+    #[arg(long, value_name = "FILE")]
+    pub adapted: Option<PathBuf>,
+}
+
+/// One row of a label blocks file
+#[derive(Debug, Deserialize)]
+struct LabelBlock {
+    start: f64,
+    end: f64,
+    class: String,
+}
+
+/// What a refit learns from a window
+#[derive(Debug, Clone)]
+pub enum Input {
+    /// A CSP+LDA model's band-passed window
+    Filtered(Array2<f32>),
+    /// A network's features
+    Features(Array1<f64>),
+}
+
+/// What a refit replaces; only the latest is kept, so the variants' sizes
+/// do not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum Readout {
+    /// The whole CSP+LDA model
+    Calibrated(CalibrationModel),
+    /// An LDA in place of a network's last layer
+    Linear(Lda),
+}
+
+/// Class probabilities of one raw `channels x window` window from `model`,
+/// or from its refitted `readout`, and what a refit would learn from it
+pub fn classify(model: &Classifier, readout: Option<&Readout>, window: ArrayView2<f32>) -> Result<(Vec<f32>, Option<Input>)> {
+    match model {
+        Classifier::Calibrated(base) => {
+            let model = match readout {
+                Some(Readout::Calibrated(model)) => model,
+                _ => base,
+            };
+            let filtered = base.filter(window)?;
+            Ok((model.classify(filtered.view()), Some(Input::Filtered(filtered))))
+        }
+        #[cfg(feature = "train")]
+        Classifier::Trained(net) => {
+            let (probabilities, features) = net.predict_features(window)?;
+            let features = Array1::from_iter(features.into_iter().map(f64::from));
+            let probabilities = match readout {
+                Some(Readout::Linear(lda)) => lda.probabilities(features.view()).iter().map(|&p| p as f32).collect(),
+                _ => probabilities,
+            };
+            Ok((probabilities, Some(Input::Features(features))))
+        }
+        model => Ok((model.predict(window)?, None)),
+    }
+}
+
+/// Pools labelled windows of a session and refits the model on schedule
+pub struct Adapter {
+    args: AdaptArgs,
+    every: f64,
+    classes: Vec<String>,
+    /// Labelled blocks as seconds of the session and a class index
+    blocks: Vec<(f64, f64, usize)>,
+    pool: VecDeque<(Input, usize)>,
+    /// Second of the session the next refit is due at
+    due: f64,
+    refits: usize,
+    labelled: usize,
+    confident: usize,
+    latest: Option<Arc<Readout>>,
+}
+
+impl Adapter {
+    /// An adapter for `model`, or none without `--adapt-every`
+    pub fn new(args: &AdaptArgs, model: &Classifier) -> Result<Option<Self>> {
+        let Some(every) = args.adapt_every else {
+            if args.adapt_confidence.is_some() || args.label_blocks.is_some() || args.adapt_csp || args.adapted.is_some() {
+                bail!("--adapt-confidence, --label-blocks, --adapt-csp and --adapted need --adapt-every");
+            }
+            return Ok(None);
+        };
+        if every <= 0.0 {
+            bail!("--adapt-every must be positive, got {}", every);
+        }
+        match model {
+            Classifier::Calibrated(_) => {}
+            #[cfg(feature = "train")]
+            Classifier::Trained(_) if !args.adapt_csp && args.adapted.is_none() => {}
+            #[cfg(feature = "train")]
+            Classifier::Trained(_) => bail!("--adapt-csp and --adapted are for CSP+LDA models, a network only adapts its readout"),
+            _ => bail!("Only calibration models and networks from openbci_train can adapt; ONNX models hide their features"),
+        }
+        if args.adapt_confidence.is_none() && args.label_blocks.is_none() {
+            bail!("--adapt-every needs windows to learn from: --label-blocks, --adapt-confidence or both");
+        }
+        if args.adapt_confidence.is_some_and(|p| !(0.0..=1.0).contains(&p)) {
+            bail!("--adapt-confidence must be between 0 and 1");
+        }
+        let classes = model.classes();
+        if args.adapt_min < 2 || args.adapt_memory < args.adapt_min * classes.len() {
+            bail!(
+                "--adapt-min must be at least 2 and --adapt-memory hold that many windows of each of the {} classes",
+                classes.len()
+            );
+        }
+        let blocks = match &args.label_blocks {
+            Some(path) => load_blocks(path, &classes)?,
+            None => Vec::new(),
+        };
+        Ok(Some(Self {
+            args: args.clone(),
+            every,
+            classes,
+            blocks,
+            pool: VecDeque::with_capacity(args.adapt_memory),
+            due: every,
+            refits: 0,
+            labelled: 0,
+            confident: 0,
+            latest: None,
+        }))
+    }
+
+    /// Learn from the window `start` to `end` seconds into the session when
+    /// a block labels all of it, or else when its `probabilities` are
+    /// confident; a window reaching into a block is left out
+    pub fn add(&mut self, input: Input, start: f64, end: f64, probabilities: &[f32]) {
+        let label = match self.blocks.iter().find(|&&(from, to, _)| start < to && end > from) {
+            Some(&(from, to, class)) if start >= from && end <= to => {
+                self.labelled += 1;
+                class
+            }
+            Some(_) => return,
+            None => {
+                let best = probabilities.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1));
+                match (self.args.adapt_confidence, best) {
+                    (Some(confidence), Some((class, &p))) if p >= confidence => {
+                        self.confident += 1;
+                        class
+                    }
+                    _ => return,
+                }
+            }
+        };
+        if self.pool.len() == self.args.adapt_memory {
+            self.pool.pop_front();
+        }
+        self.pool.push_back((input, label));
+    }
+
+    /// The windows to refit on once the session reaches `now` seconds past
+    /// the last refit, if every class has enough of them
+    pub fn due(&mut self, now: f64) -> Option<Refit> {
+        if now < self.due {
+            return None;
+        }
+        self.due = now + self.every;
+        let mut counts = vec![0usize; self.classes.len()];
+        for (_, label) in &self.pool {
+            counts[*label] += 1;
+        }
+        let summary: Vec<String> = self.classes.iter().zip(&counts).map(|(class, count)| format!("{} {}", class, count)).collect();
+        if counts.iter().any(|&count| count < self.args.adapt_min) {
+            info!("Not refitting yet, {} windows of each class needed: {}", self.args.adapt_min, summary.join(", "));
+            return None;
+        }
+        Some(Refit {
+            number: self.refits + 1,
+            pool: self.pool.iter().cloned().collect(),
+            counts,
+            summary: summary.join(", "),
+            csp: self.args.adapt_csp,
+            shrinkage: self.args.adapt_shrinkage.into(),
+        })
+    }
+
+    /// A refit is done and classifies from now on
+    pub fn refitted(&mut self, readout: Arc<Readout>) {
+        self.refits += 1;
+        self.latest = Some(readout);
+    }
+
+    /// Log what the session learned from, and save the last refitted
+    /// CSP+LDA model with `--adapted`
+    pub fn finish(&self) -> Result<()> {
+        info!(
+            "Adaptation: {} refits from {} labelled and {} confident windows",
+            self.refits, self.labelled, self.confident
+        );
+        if let Some(path) = &self.args.adapted {
+            match self.latest.as_deref() {
+                Some(Readout::Calibrated(model)) => {
+                    model.save(path)?;
+                    info!("Saved the adapted model to {:?}", path);
+                }
+                _ => warn!("The model was never refitted, {:?} is not written", path),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Windows a refit learns from; [`Refit::run`] fits them away from the
+/// stream, as a network's readout can take a while
+pub struct Refit {
+    number: usize,
+    pool: Vec<(Input, usize)>,
+    counts: Vec<usize>,
+    summary: String,
+    /// Refit CSP filters too
+    csp: bool,
+    shrinkage: eeg_dsp::Shrinkage,
+}
+
+impl Refit {
+    /// The readout that replaces `model`'s
+    pub fn run(self, model: &Classifier) -> Result<Readout> {
+        let start = Instant::now();
+        let labels: Vec<usize> = self.pool.iter().map(|(_, label)| *label).collect();
+        let (readout, what) = match model {
+            Classifier::Calibrated(base) => {
+                let windows: Vec<ArrayView2<f32>> = self
+                    .pool
+                    .iter()
+                    .filter_map(|(input, _)| match input {
+                        Input::Filtered(window) => Some(window.view()),
+                        Input::Features(_) => None,
+                    })
+                    .collect();
+                let csp = if self.csp {
+                    let class = |k: usize| windows.iter().zip(&labels).filter(|(_, &l)| l == k).map(|(w, _)| *w).collect::<Vec<_>>();
+                    Csp::fit(&class(0), &class(1), base.csp.components() / 2, self.shrinkage)?
+                } else {
+                    base.csp.clone()
+                };
+                let features = stack(windows.iter().map(|window| csp.features(*window)).collect(), csp.components());
+                let ids: Vec<u8> = labels.iter().map(|&k| base.class_ids[k]).collect();
+                let lda = Lda::fit(features.view(), &ids, self.shrinkage)?;
+                let model = CalibrationModel {
+                    created: Utc::now(),
+                    windows_per_class: self.counts,
+                    csp,
+                    classifier: lda.into(),
+                    cross_validation: None,
+                    ..base.clone()
+                };
+                (Readout::Calibrated(model), if self.csp { "CSP+LDA" } else { "LDA" })
+            }
+            #[cfg(feature = "train")]
+            Classifier::Trained(_) => {
+                let rows: Vec<Array1<f64>> = self
+                    .pool
+                    .into_iter()
+                    .filter_map(|(input, _)| match input {
+                        Input::Features(features) => Some(features),
+                        Input::Filtered(_) => None,
+                    })
+                    .collect();
+                let dimension = rows.first().map_or(0, Array1::len);
+                let ids: Vec<u8> = labels.iter().map(|&k| k as u8).collect();
+                (Readout::Linear(Lda::fit(stack(rows, dimension).view(), &ids, self.shrinkage)?), "readout")
+            }
+            _ => bail!("Only calibration models and networks from openbci_train can adapt"),
+        };
+        info!(
+            "Refit {}: {} on the last {} windows ({}) in {:.1} ms",
+            self.number,
+            what,
+            labels.len(),
+            self.summary,
+            start.elapsed().as_secs_f64() * 1000.0
+        );
+        Ok(readout)
+    }
+}
+
+/// Rows of features as a `windows x dimension` matrix
+fn stack(rows: Vec<Array1<f64>>, dimension: usize) -> Array2<f64> {
+    let mut features = Array2::zeros((rows.len(), dimension));
+    for (row, mut out) in rows.iter().zip(features.rows_mut()) {
+        out.assign(row);
+    }
+    features
+}
+
+/// Blocks of a label blocks file as seconds and class indices
+fn load_blocks(path: &Path, classes: &[String]) -> Result<Vec<(f64, f64, usize)>> {
+    let mut reader = csv::Reader::from_path(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let blocks: Vec<LabelBlock> = reader
+        .deserialize()
+        .collect::<std::result::Result<_, _>>()
+        .with_context(|| format!("Invalid label blocks {:?}", path))?;
+    blocks
+        .into_iter()
+        .map(|block| {
+            let class = classes
+                .iter()
+                .position(|c| *c == block.class)
+                .with_context(|| format!("{:?}: '{}' is not one of the model's classes {:?}", path, block.class, classes))?;
+            if block.end <= block.start {
+                bail!("{:?}: the block from {} s ends at {} s", path, block.start, block.end);
+            }
+            Ok((block.start, block.end, class))
+        })
+        .collect()
+}
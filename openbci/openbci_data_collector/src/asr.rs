@@ -0,0 +1,492 @@
+//! Artifact subspace reconstruction (ASR) for online cleaning.
+//!
+//! Calibration learns the spatial covariance of a clean resting baseline
+//! and, per principal component, how large its RMS gets on clean data.
+//! Online, every short block is decomposed into principal components over
+//! a sliding window; components whose variance exceeds the calibrated
+//! threshold are treated as artifact and reconstructed from the remaining
+//! ones instead of rejecting the whole window. Successive reconstruction
+//! matrices are blended with a raised cosine to avoid discontinuities.
+//!
+//! Data is high-passed causally before calibration and cleaning, so the
+//! cleaned stream has no DC offset.
+
+use crate::augment::MixedSource;
+use crate::eog::EogProcessor;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use eeg_dsp::linalg::symmetric_eigen;
+use eeg_dsp::riemann::sqrtm;
+use eeg_dsp::{FilterBank, FilterSpec, Response};
+use log::info;
+use ndarray::{Array1, Array2};
+use openbci_wifi_client::{Sample, StreamEvent};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+
+/// File name of the calibration in a session directory
+pub const ASR_FILE: &str = "asr_calibration.json";
+
+/// Default burst criterion, in robust standard deviations of clean RMS
+pub const DEFAULT_CUTOFF: f64 = 20.0;
+/// Shortest baseline that gives usable statistics
+pub const MIN_CALIBRATION_SECONDS: f64 = 15.0;
+
+const HIGHPASS_HZ: f64 = 0.5;
+/// RMS and covariance window, in seconds
+const WINDOW_SECONDS: f64 = 0.5;
+/// Blocks per second the reconstruction is updated at
+const BLOCKS_PER_SECOND: u32 = 16;
+/// At most this share of components may be reconstructed at once
+const MAX_DIMS: f64 = 0.66;
+
+/// Causal second-order Butterworth high-pass of every channel. A
+/// high-pass settles at zero output for a constant input, so settling on
+/// the first sample starts it without a transient.
+fn highpass(sample_rate: f64, channels: usize) -> FilterBank<f64> {
+    FilterBank::from_specs(&[FilterSpec::butterworth(2, Response::HighPass(HIGHPASS_HZ))], sample_rate, channels)
+        .expect("the ASR high-pass is below Nyquist at every board rate")
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        0.5 * (values[mid - 1] + values[mid])
+    } else {
+        values[mid]
+    }
+}
+
+/// Window start offsets at 50% overlap
+fn windows(len: usize, window: usize) -> impl Iterator<Item = usize> {
+    let step = (window / 2).max(1);
+    (0..).map(move |i| i * step).take_while(move |start| start + window <= len)
+}
+
+/// Row-major matrix as persisted in the calibration file
+pub type Matrix = Vec<Vec<f64>>;
+
+fn to_rows(m: &Array2<f64>) -> Matrix {
+    m.rows().into_iter().map(|row| row.to_vec()).collect()
+}
+
+fn from_rows(m: &Matrix) -> Array2<f64> {
+    Array2::from_shape_fn((m.len(), m.first().map_or(0, Vec::len)), |(i, j)| m[i][j])
+}
+
+/// Learned clean-data statistics, persisted per session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AsrCalibration {
+    pub sample_rate: u32,
+    pub channel_names: Vec<String>,
+    pub calibration_seconds: f64,
+    pub calibrated_at: DateTime<Utc>,
+    /// Square root of the robust baseline covariance
+    pub mixing: Matrix,
+    /// Principal directions of `mixing`, one per column
+    pub components: Matrix,
+    /// Median windowed RMS of each component on the baseline
+    pub rms_median: Vec<f64>,
+    /// Robust standard deviation (1.4826 MAD) of that RMS
+    pub rms_spread: Vec<f64>,
+}
+
+impl AsrCalibration {
+    /// Calibrate on a clean, channel-major baseline in nanovolts
+    pub fn fit(baseline: &[Vec<f32>], sample_rate: u32, channel_names: &[String]) -> Result<Self> {
+        let n = baseline.len();
+        let len = baseline.iter().map(|c| c.len()).min().unwrap_or(0);
+        let seconds = len as f64 / sample_rate.max(1) as f64;
+        if n == 0 || channel_names.len() != n {
+            bail!("ASR calibration needs one name per channel");
+        }
+        if seconds < MIN_CALIBRATION_SECONDS {
+            bail!(
+                "ASR calibration needs at least {} s of clean data, got {:.1} s",
+                MIN_CALIBRATION_SECONDS,
+                seconds
+            );
+        }
+
+        let filtered: Vec<Vec<f64>> = baseline
+            .iter()
+            .map(|channel| {
+                let mut filter = highpass(sample_rate as f64, 1);
+                let mut filtered: Vec<f64> = channel[..len].iter().map(|&x| x as f64).collect();
+                if let Some(&first) = filtered.first() {
+                    filter.settle_channel(0, first);
+                }
+                filter.process_channel(0, &mut filtered);
+                filtered
+            })
+            .collect();
+        let window = (WINDOW_SECONDS * sample_rate as f64) as usize;
+
+        // Element-wise median of window covariances is robust to the odd
+        // artifact left in the baseline
+        let starts: Vec<usize> = windows(len, window).collect();
+        let mut cov = Array2::zeros((n, n));
+        for i in 0..n {
+            for j in i..n {
+                let mut values: Vec<f64> = starts
+                    .iter()
+                    .map(|&s| {
+                        filtered[i][s..s + window]
+                            .iter()
+                            .zip(&filtered[j][s..s + window])
+                            .map(|(a, b)| a * b)
+                            .sum::<f64>()
+                            / window as f64
+                    })
+                    .collect();
+                let m = median(&mut values);
+                cov[[i, j]] = m;
+                cov[[j, i]] = m;
+            }
+        }
+        let mixing = sqrtm(&cov);
+        let (_, components) = symmetric_eigen(&mixing);
+
+        // Robust mean and spread of each component's windowed RMS
+        let mut rms_median = Vec::with_capacity(n);
+        let mut rms_spread = Vec::with_capacity(n);
+        for direction in components.columns() {
+            let component: Vec<f64> = (0..len)
+                .map(|t| direction.iter().zip(&filtered).map(|(v, channel)| v * channel[t]).sum())
+                .collect();
+            let mut rms: Vec<f64> = windows(len, window)
+                .map(|s| (component[s..s + window].iter().map(|v| v * v).sum::<f64>() / window as f64).sqrt())
+                .collect();
+            let mu = median(&mut rms);
+            let mut deviations: Vec<f64> = rms.iter().map(|r| (r - mu).abs()).collect();
+            rms_median.push(mu);
+            rms_spread.push(1.4826 * median(&mut deviations));
+        }
+
+        Ok(Self {
+            sample_rate,
+            channel_names: channel_names.to_vec(),
+            calibration_seconds: seconds,
+            calibrated_at: Utc::now(),
+            mixing: to_rows(&mixing),
+            components: to_rows(&components),
+            rms_median,
+            rms_spread,
+        })
+    }
+
+    /// `diag(threshold) * V^T`: each component's RMS threshold at `cutoff`
+    /// robust standard deviations, along its principal direction
+    pub fn thresholds(&self, cutoff: f64) -> Array2<f64> {
+        let n = self.components.len();
+        Array2::from_shape_fn((self.rms_median.len(), n), |(k, c)| {
+            (self.rms_median[k] + cutoff * self.rms_spread[k]) * self.components[c][k]
+        })
+    }
+
+    /// Read the session's calibration, if one was made
+    pub fn load(session_dir: &Path) -> Result<Option<Self>> {
+        let path = session_dir.join(ASR_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let text = fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        let calibration = serde_json::from_str(&text).with_context(|| format!("Failed to parse {:?}", path))?;
+        Ok(Some(calibration))
+    }
+
+    pub fn save(&self, session_dir: &Path) -> Result<()> {
+        fs::create_dir_all(session_dir)?;
+        let path = session_dir.join(ASR_FILE);
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        info!("Saved ASR calibration to {:?}", path);
+        Ok(())
+    }
+}
+
+/// Moore-Penrose pseudo-inverse via the eigendecomposition of `A^T A`
+fn pinv(a: &Array2<f64>) -> Array2<f64> {
+    let (values, vectors) = symmetric_eigen(&a.t().dot(a));
+    let largest = values.iter().copied().fold(0.0, f64::max);
+    let n = values.len();
+    let inverse = Array2::from_shape_fn((n, n), |(i, j)| {
+        (0..n)
+            .filter(|&k| values[k] > largest * 1e-10)
+            .map(|k| vectors[[i, k]] * vectors[[j, k]] / values[k])
+            .sum()
+    });
+    inverse.dot(&a.t())
+}
+
+/// `weight * next x + (1 - weight) * previous x`, identity for `None`
+fn blend(x: &[f32], previous: Option<&Array2<f64>>, next: Option<&Array2<f64>>, weight: f64) -> Vec<f32> {
+    let x: Array1<f64> = x.iter().map(|&v| v as f64).collect();
+    let apply = |r: Option<&Array2<f64>>| -> Array1<f64> {
+        match r {
+            Some(r) => r.dot(&x),
+            None => x.clone(),
+        }
+    };
+    apply(next)
+        .iter()
+        .zip(apply(previous))
+        .map(|(n, o)| (weight * n + (1.0 - weight) * o) as f32)
+        .collect()
+}
+
+/// Cleaning counters, for the trial metadata
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AsrStats {
+    pub blocks: u64,
+    /// Blocks in which at least one component was reconstructed
+    pub reconstructed_blocks: u64,
+    pub removed_components: u64,
+}
+
+/// Online ASR over a stream of events. The covariance window is centred on
+/// the block being cleaned, so output lags the input by half a window plus
+/// one block (about 0.3 s); markers stay in order with their samples.
+pub struct AsrProcessor {
+    calibration: AsrCalibration,
+    cutoff: f64,
+    /// The calibration's mixing matrix and [`AsrCalibration::thresholds`]
+    mixing: Array2<f64>,
+    thresholds: Array2<f64>,
+    filter: FilterBank<f64>,
+    /// Whether the first sample has settled the high-pass
+    settled: bool,
+    window: usize,
+    step: usize,
+    lookahead: usize,
+    /// Recent high-passed samples the covariance is taken over
+    history: VecDeque<Vec<f64>>,
+    /// Events not yet emitted; samples are already high-passed
+    pending: VecDeque<StreamEvent>,
+    pending_samples: usize,
+    /// Reconstruction of the previous block, `None` for identity
+    last: Option<Array2<f64>>,
+    stats: AsrStats,
+}
+
+impl AsrProcessor {
+    pub fn new(calibration: AsrCalibration, cutoff: f64) -> Self {
+        let fs = calibration.sample_rate;
+        let n = calibration.channel_names.len();
+        let window = ((WINDOW_SECONDS * fs as f64) as usize).max(1);
+        Self {
+            cutoff,
+            mixing: from_rows(&calibration.mixing),
+            thresholds: calibration.thresholds(cutoff),
+            filter: highpass(fs as f64, n),
+            settled: false,
+            window,
+            step: (fs / BLOCKS_PER_SECOND).max(1) as usize,
+            lookahead: window / 2,
+            history: VecDeque::new(),
+            pending: VecDeque::new(),
+            pending_samples: 0,
+            last: None,
+            stats: AsrStats::default(),
+            calibration,
+        }
+    }
+
+    pub fn calibration(&self) -> &AsrCalibration {
+        &self.calibration
+    }
+
+    pub fn cutoff(&self) -> f64 {
+        self.cutoff
+    }
+
+    pub fn stats(&self) -> &AsrStats {
+        &self.stats
+    }
+
+    /// Feed one event; returns the events of a completed block
+    pub fn push(&mut self, event: StreamEvent) -> Vec<StreamEvent> {
+        let StreamEvent::Sample(sample) = event else {
+            self.pending.push_back(event);
+            return Vec::new();
+        };
+        let mut filtered: Vec<f64> = sample.data.iter().map(|&x| x as f64).collect();
+        if !self.settled {
+            self.filter.settle(&filtered);
+            self.settled = true;
+        }
+        self.filter.process_frame(&mut filtered);
+        if self.history.len() == self.window {
+            self.history.pop_front();
+        }
+        self.history.push_back(filtered.clone());
+        self.pending.push_back(StreamEvent::Sample(Sample {
+            data: filtered.iter().map(|&x| x as f32).collect(),
+            timestamp: sample.timestamp,
+        }));
+        self.pending_samples += 1;
+
+        if self.pending_samples < self.lookahead + self.step {
+            return Vec::new();
+        }
+        let next = self.reconstruction();
+        self.emit(next, self.step)
+    }
+
+    /// Release whatever is pending, cleaned with the last reconstruction
+    pub fn flush(&mut self) -> Vec<StreamEvent> {
+        let last = self.last.clone();
+        let mut events = self.emit(last, self.pending_samples);
+        // Trailing markers have no sample after them
+        events.extend(self.pending.drain(..));
+        events
+    }
+
+    /// Take the oldest `count` samples (and the markers before them) and
+    /// apply `next`, cross-faded from the previous reconstruction
+    fn emit(&mut self, next: Option<Array2<f64>>, count: usize) -> Vec<StreamEvent> {
+        let previous = self.last.take();
+        let total = count.max(1);
+        let mut index = 0;
+        let mut events = Vec::new();
+        while index < count {
+            let Some(mut event) = self.pending.pop_front() else { break };
+            if let StreamEvent::Sample(sample) = &mut event {
+                index += 1;
+                if previous.is_some() || next.is_some() {
+                    let weight = 0.5 * (1.0 - (std::f64::consts::PI * index as f64 / total as f64).cos());
+                    sample.data = blend(&sample.data, previous.as_ref(), next.as_ref(), weight);
+                }
+            }
+            events.push(event);
+        }
+        self.pending_samples -= index;
+        self.last = next;
+        events
+    }
+
+    /// Reconstruction matrix for the current window, `None` when every
+    /// component is within its threshold
+    fn reconstruction(&mut self) -> Option<Array2<f64>> {
+        let n = self.calibration.channel_names.len();
+        let len = self.history.len().max(1) as f64;
+        let mut cov = Array2::zeros((n, n));
+        for x in &self.history {
+            for ((i, j), value) in cov.indexed_iter_mut() {
+                *value += x[i] * x[j] / len;
+            }
+        }
+
+        let (values, vectors) = symmetric_eigen(&cov);
+        let mut order: Vec<usize> = (0..n).collect();
+        order.sort_by(|&a, &b| values[a].total_cmp(&values[b]));
+
+        // Smallest components are always kept so at most MAX_DIMS go
+        let always_keep = n - (MAX_DIMS * n as f64).floor() as usize;
+        let thresholds = &self.thresholds;
+        let keep: Vec<bool> = order
+            .iter()
+            .enumerate()
+            .map(|(rank, &k)| {
+                let limit: f64 = thresholds.dot(&vectors.column(k)).iter().map(|v| v * v).sum();
+                rank < always_keep || values[k] < limit
+            })
+            .collect();
+
+        self.stats.blocks += 1;
+        let removed = keep.iter().filter(|k| !**k).count();
+        if removed == 0 {
+            return None;
+        }
+        self.stats.reconstructed_blocks += 1;
+        self.stats.removed_components += removed as u64;
+
+        // R = M pinv(keep .* V^T M) V^T, with V's columns in `order`
+        let v_t = Array2::from_shape_fn((n, n), |(r, c)| vectors[[c, order[r]]]);
+        let mut projected = v_t.dot(&self.mixing);
+        for (mut row, &kept) in projected.rows_mut().into_iter().zip(&keep) {
+            if !kept {
+                row.fill(0.0);
+            }
+        }
+        Some(self.mixing.dot(&pinv(&projected)).dot(&v_t))
+    }
+}
+
+/// Stream source with optional EOG regression and ASR cleaning after
+/// artifact injection. Without processors it passes events through
+/// unchanged.
+pub struct CleanedSource {
+    inner: MixedSource,
+    eog: Option<EogProcessor>,
+    asr: Option<AsrProcessor>,
+    queue: VecDeque<StreamEvent>,
+    finished: bool,
+}
+
+impl CleanedSource {
+    pub fn new(inner: MixedSource, eog: Option<EogProcessor>, asr: Option<AsrProcessor>) -> Self {
+        Self {
+            inner,
+            eog,
+            asr,
+            queue: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    pub fn inner(&self) -> &MixedSource {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut MixedSource {
+        &mut self.inner
+    }
+
+    pub fn asr(&self) -> Option<&AsrProcessor> {
+        self.asr.as_ref()
+    }
+
+    /// Stop reading the live stream; `recv` drains the cleaning stage and
+    /// then returns `None`
+    pub fn finish(&mut self) {
+        if !self.finished {
+            if let Some(asr) = &mut self.asr {
+                self.queue.extend(asr.flush());
+            }
+            self.finished = true;
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Next cleaned sample or marker
+    pub async fn recv(&mut self) -> Option<StreamEvent> {
+        loop {
+            if let Some(event) = self.queue.pop_front() {
+                return Some(event);
+            }
+            if self.finished {
+                return None;
+            }
+            let Some(mut event) = self.inner.recv().await else {
+                self.finish();
+                continue;
+            };
+            if let (Some(eog), StreamEvent::Sample(sample)) = (&mut self.eog, &mut event) {
+                eog.process(sample);
+            }
+            match &mut self.asr {
+                Some(asr) => self.queue.extend(asr.push(event)),
+                None => return Some(event),
+            }
+        }
+    }
+}
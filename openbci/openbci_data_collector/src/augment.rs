@@ -0,0 +1,313 @@
+//! Live stream mixed with replayed artifact segments.
+//!
+//! For testing the online classifier and artifact detector under controlled
+//! contamination: blink or EMG segments are added onto the live samples at
+//! scheduled times, and every injection is bracketed by `artifact_start:*`
+//! and `artifact_end:*` markers so the ground truth lands in the recording.
+
+use crate::recording::Recording;
+use anyhow::{bail, Context, Result};
+use log::info;
+use openbci_wifi_client::{Marker, Sample, StreamEvent, StreamHandle};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Blink peak amplitude on frontal channels, in nanovolts
+const BLINK_PEAK_NV: f32 = 150_000.0;
+const BLINK_SECONDS: f32 = 0.3;
+/// EMG burst RMS, in nanovolts
+const EMG_RMS_NV: f32 = 30_000.0;
+const EMG_SECONDS: f32 = 0.5;
+
+/// Marker label prefixes bracketing every injected segment
+pub const ARTIFACT_START: &str = "artifact_start:";
+pub const ARTIFACT_END: &str = "artifact_end:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArtifactKind {
+    Blink,
+    Emg,
+}
+
+impl ArtifactKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Blink => "blink",
+            Self::Emg => "emg",
+        }
+    }
+}
+
+impl fmt::Display for ArtifactKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ArtifactKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "blink" => Ok(Self::Blink),
+            "emg" => Ok(Self::Emg),
+            _ => bail!("Unknown artifact '{}', expected blink or emg", s),
+        }
+    }
+}
+
+/// Recorded artifact to replay, parsed from `kind=path/to/trial_metadata.json`
+#[derive(Debug, Clone)]
+pub struct ArtifactRecording {
+    pub kind: ArtifactKind,
+    pub path: PathBuf,
+}
+
+impl FromStr for ArtifactRecording {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, path) = s
+            .split_once('=')
+            .with_context(|| format!("Artifact recording '{}' must look like kind=path", s))?;
+        Ok(Self {
+            kind: kind.parse()?,
+            path: PathBuf::from(path.trim()),
+        })
+    }
+}
+
+/// Channel-major artifact waveform added sample by sample
+#[derive(Debug, Clone)]
+pub struct ArtifactSegment {
+    pub kind: ArtifactKind,
+    pub data: Vec<Vec<f32>>,
+}
+
+impl ArtifactSegment {
+    pub fn len(&self) -> usize {
+        self.data.first().map_or(0, |c| c.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Replay a recorded trial with its per-channel mean removed, so only
+    /// the artifact is added to the live signal
+    pub fn from_recording(kind: ArtifactKind, metadata_path: &Path) -> Result<Self> {
+        let recording = Recording::load(metadata_path)?;
+        let data = recording
+            .channel_data()
+            .into_iter()
+            .map(|channel| {
+                let mean = channel.iter().sum::<f32>() / channel.len().max(1) as f32;
+                channel.into_iter().map(|x| x - mean).collect()
+            })
+            .collect();
+        Ok(Self { kind, data })
+    }
+
+    /// Synthetic template weighted by electrode position: blinks are
+    /// strongest frontally, EMG at temporal sites
+    pub fn synthetic(kind: ArtifactKind, labels: &[String], sample_rate: u32, rng: &mut StdRng) -> Self {
+        let fs = sample_rate as f32;
+        let data = labels
+            .iter()
+            .map(|label| {
+                let weight = site_weight(kind, label);
+                match kind {
+                    ArtifactKind::Blink => {
+                        let n = (BLINK_SECONDS * fs) as usize;
+                        (0..n)
+                            .map(|i| {
+                                let phase = std::f32::consts::PI * i as f32 / n as f32;
+                                weight * BLINK_PEAK_NV * phase.sin().powi(2)
+                            })
+                            .collect()
+                    }
+                    ArtifactKind::Emg => {
+                        let n = (EMG_SECONDS * fs) as usize;
+                        // Uniform noise scaled to the target RMS, ramped in and out
+                        let amplitude = weight * EMG_RMS_NV * 3f32.sqrt();
+                        (0..n)
+                            .map(|i| {
+                                let envelope = (std::f32::consts::PI * i as f32 / n as f32).sin();
+                                envelope * amplitude * rng.gen_range(-1.0..1.0)
+                            })
+                            .collect()
+                    }
+                }
+            })
+            .collect();
+        Self { kind, data }
+    }
+}
+
+/// Relative artifact amplitude at a 10-20 site (labels may carry an
+/// annotation suffix such as `C3_left_motor`)
+fn site_weight(kind: ArtifactKind, label: &str) -> f32 {
+    let site = label.split('_').next().unwrap_or(label).to_ascii_uppercase();
+    match kind {
+        ArtifactKind::Blink if site.starts_with("FP") => 1.0,
+        ArtifactKind::Blink if site.starts_with("AF") || site.starts_with('F') => 0.6,
+        ArtifactKind::Blink if site.starts_with('C') => 0.2,
+        ArtifactKind::Blink => 0.1,
+        ArtifactKind::Emg if site.starts_with('T') || site.starts_with("FT") => 1.0,
+        ArtifactKind::Emg if site.starts_with("FP") || site.starts_with('F') => 0.7,
+        ArtifactKind::Emg => 0.4,
+    }
+}
+
+/// When to inject: roughly every `interval` seconds with +/-25% jitter,
+/// first injection after `interval`
+#[derive(Debug, Clone)]
+pub struct InjectionSchedule {
+    pub interval: f64,
+    pub sample_rate: u32,
+}
+
+impl InjectionSchedule {
+    fn next_gap(&self, rng: &mut StdRng) -> u64 {
+        let jitter = rng.gen_range(0.75..1.25);
+        ((self.interval * jitter) * self.sample_rate as f64).max(1.0) as u64
+    }
+}
+
+/// Injection in progress
+struct Active {
+    segment: usize,
+    offset: usize,
+}
+
+/// Adds artifact segments onto live samples on a schedule
+pub struct ArtifactInjector {
+    segments: Vec<ArtifactSegment>,
+    schedule: InjectionSchedule,
+    rng: StdRng,
+    samples_seen: u64,
+    next_at: u64,
+    active: Option<Active>,
+    injected: usize,
+}
+
+impl ArtifactInjector {
+    pub fn new(segments: Vec<ArtifactSegment>, schedule: InjectionSchedule, seed: Option<u64>) -> Result<Self> {
+        let segments: Vec<ArtifactSegment> = segments.into_iter().filter(|s| !s.is_empty()).collect();
+        if segments.is_empty() {
+            bail!("No artifact segments to inject");
+        }
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let next_at = schedule.next_gap(&mut rng);
+        Ok(Self {
+            segments,
+            schedule,
+            rng,
+            samples_seen: 0,
+            next_at,
+            active: None,
+            injected: 0,
+        })
+    }
+
+    /// Number of injections started so far
+    pub fn injected(&self) -> usize {
+        self.injected
+    }
+
+    /// Contaminate one live sample. Returns the marker events that belong
+    /// right before it (start) or right after it (end).
+    fn apply(&mut self, sample: &mut Sample) -> (Option<Marker>, Option<Marker>) {
+        let mut start = None;
+        if self.active.is_none() && self.samples_seen >= self.next_at {
+            let segment = self.rng.gen_range(0..self.segments.len());
+            let kind = self.segments[segment].kind;
+            info!("Injecting {} artifact at sample {}", kind, self.samples_seen);
+            start = Some(Marker::now(format!("{}{}", ARTIFACT_START, kind)));
+            self.active = Some(Active { segment, offset: 0 });
+            self.injected += 1;
+        }
+        self.samples_seen += 1;
+
+        let Some(active) = &mut self.active else {
+            return (start, None);
+        };
+        let segment = &self.segments[active.segment];
+        for (value, channel) in sample.data.iter_mut().zip(&segment.data) {
+            *value += channel[active.offset];
+        }
+        active.offset += 1;
+
+        let mut end = None;
+        if active.offset >= segment.len() {
+            end = Some(Marker::now(format!("{}{}", ARTIFACT_END, segment.kind)));
+            self.active = None;
+            self.next_at = self.samples_seen + self.schedule.next_gap(&mut self.rng);
+        }
+        (start, end)
+    }
+}
+
+/// Stream source that fans in the live board stream and replayed artifacts.
+/// Without an injector it passes the live stream through unchanged.
+pub struct MixedSource {
+    live: StreamHandle,
+    injector: Option<ArtifactInjector>,
+    queue: VecDeque<StreamEvent>,
+}
+
+impl MixedSource {
+    pub fn new(live: StreamHandle, injector: Option<ArtifactInjector>) -> Self {
+        Self {
+            live,
+            injector,
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn live(&self) -> &StreamHandle {
+        &self.live
+    }
+
+    pub fn live_mut(&mut self) -> &mut StreamHandle {
+        &mut self.live
+    }
+
+    pub fn injector(&self) -> Option<&ArtifactInjector> {
+        self.injector.as_ref()
+    }
+
+    /// Next sample or marker; `None` once the live stream ends
+    pub async fn recv(&mut self) -> Option<StreamEvent> {
+        if let Some(event) = self.queue.pop_front() {
+            return Some(event);
+        }
+        let event = self.live.recv().await?;
+        let Some(injector) = &mut self.injector else {
+            return Some(event);
+        };
+        match event {
+            StreamEvent::Sample(mut sample) => {
+                let (start, end) = injector.apply(&mut sample);
+                self.queue.push_back(StreamEvent::Sample(sample));
+                if let Some(end) = end {
+                    self.queue.push_back(StreamEvent::Marker(end));
+                }
+                match start {
+                    Some(start) => Some(StreamEvent::Marker(start)),
+                    None => self.queue.pop_front(),
+                }
+            }
+            marker => Some(marker),
+        }
+    }
+}
@@ -0,0 +1,328 @@
+//! Token-based access control for the WebSocket server (`--ws-tokens`).
+//!
+//! Every client is checked against a role scope when it connects, and
+//! again for every prediction it sends, and each attempt is recorded in an
+//! append-only audit log together with the principal that made it, so a
+//! lab network deployment never accepts anonymous commands.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use thiserror::Error;
+
+/// Default audit log, in the output directory
+pub const AUDIT_FILE: &str = "ws_audit.jsonl";
+
+/// Role scopes, ordered from least to most privileged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// May watch the live stream
+    Observer,
+    /// May also send predictions to the other clients
+    Operator,
+    /// Everything
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::Observer => "observer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What a client asks to do, and the minimum role each one requires
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endpoint {
+    /// Connect and receive the live stream
+    Stream,
+    /// Send a `prediction` message to be relayed
+    Prediction,
+}
+
+impl Endpoint {
+    /// Minimum role allowed to call this endpoint
+    pub fn required_role(&self) -> Role {
+        match self {
+            Endpoint::Stream => Role::Observer,
+            Endpoint::Prediction => Role::Operator,
+        }
+    }
+}
+
+/// Authenticated caller of the control API
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Principal {
+    pub name: String,
+    pub role: Role,
+}
+
+/// Authorization failures, each of which is audited before being returned
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AuthError {
+    #[error("missing bearer token")]
+    MissingToken,
+    #[error("invalid bearer token")]
+    InvalidToken,
+    #[error("{principal} ({role}) may not call {endpoint:?}, requires {required}")]
+    Forbidden {
+        principal: String,
+        role: Role,
+        endpoint: Endpoint,
+        required: Role,
+    },
+}
+
+/// One entry of the tokens file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenEntry {
+    pub principal: String,
+    pub role: Role,
+    /// Hex-encoded SHA-256 of the bearer token; plain tokens are never stored
+    pub sha256: String,
+}
+
+/// Tokens file loaded at startup
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TokenStore {
+    pub tokens: Vec<TokenEntry>,
+}
+
+impl TokenStore {
+    /// Load a tokens file (JSON: `{"tokens": [{"principal", "role", "sha256"}]}`)
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read tokens file {:?}", path))?;
+        let store: TokenStore = serde_json::from_str(&text)
+            .with_context(|| format!("Failed to parse tokens file {:?}", path))?;
+        info!("Loaded {} WebSocket tokens from {:?}", store.tokens.len(), path);
+        Ok(store)
+    }
+
+    /// Look up the principal owning a bearer token
+    pub fn lookup(&self, token: &str) -> Option<Principal> {
+        let digest = hash_token(token);
+        self.tokens
+            .iter()
+            .find(|entry| constant_time_eq(entry.sha256.as_bytes(), digest.as_bytes()))
+            .map(|entry| Principal {
+                name: entry.principal.clone(),
+                role: entry.role,
+            })
+    }
+}
+
+/// Hex-encoded SHA-256 of a token, as stored in the tokens file
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Extract the token from an `Authorization: Bearer <token>` header value
+pub fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// A single audited remote command
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    time: DateTime<Utc>,
+    principal: Option<&'a str>,
+    role: Option<Role>,
+    endpoint: Endpoint,
+    command: &'a str,
+    allowed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+/// Append-only JSON-lines audit log
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log {:?}", path))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    fn record(&self, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())
+            .with_context(|| format!("Failed to write audit log {:?}", self.path))?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Authentication, per-endpoint authorization and auditing in one place
+pub struct AccessControl {
+    tokens: TokenStore,
+    audit: AuditLog,
+}
+
+impl AccessControl {
+    pub fn new(tokens: TokenStore, audit: AuditLog) -> Self {
+        Self { tokens, audit }
+    }
+
+    /// Authorize `command` against `endpoint` for the caller identified by the
+    /// raw `Authorization` header. Every attempt is audited, allowed or not.
+    pub fn authorize(
+        &self,
+        authorization: Option<&str>,
+        endpoint: Endpoint,
+        command: &str,
+    ) -> std::result::Result<Principal, AuthError> {
+        let outcome = match authorization.and_then(bearer_token) {
+            None => Err(AuthError::MissingToken),
+            Some(token) => match self.tokens.lookup(token) {
+                None => Err(AuthError::InvalidToken),
+                Some(principal) if principal.role < endpoint.required_role() => {
+                    Err(AuthError::Forbidden {
+                        principal: principal.name,
+                        role: principal.role,
+                        endpoint,
+                        required: endpoint.required_role(),
+                    })
+                }
+                Some(principal) => Ok(principal),
+            },
+        };
+
+        let (principal, role) = match &outcome {
+            Ok(p) => (Some(p.name.as_str()), Some(p.role)),
+            Err(AuthError::Forbidden { principal, role, .. }) => (Some(principal.as_str()), Some(*role)),
+            Err(_) => (None, None),
+        };
+
+        let record = AuditRecord {
+            time: Utc::now(),
+            principal,
+            role,
+            endpoint,
+            command,
+            allowed: outcome.is_ok(),
+            reason: outcome.as_ref().err().map(|e| e.to_string()),
+        };
+        if let Err(e) = self.audit.record(&record) {
+            warn!("Failed to audit {:?} command: {}", endpoint, e);
+        }
+
+        match &outcome {
+            Ok(p) => info!("{} ({}) -> {:?}: {}", p.name, p.role, endpoint, command),
+            Err(e) => warn!("Rejected {:?} command: {}", endpoint, e),
+        }
+
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(name: &str) -> (AccessControl, PathBuf) {
+        let path = std::env::temp_dir().join(format!("openbci_auth_{}_{}.jsonl", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let tokens = TokenStore {
+            tokens: [("dashboard", Role::Observer, "watch"), ("decoder", Role::Operator, "decode"), ("lab", Role::Admin, "all")]
+                .into_iter()
+                .map(|(principal, role, token)| TokenEntry { principal: principal.to_string(), role, sha256: hash_token(token) })
+                .collect(),
+        };
+        (AccessControl::new(tokens, AuditLog::open(&path).unwrap()), path)
+    }
+
+    #[test]
+    fn roles_reach_their_scopes() {
+        let (access, path) = access("scopes");
+        for token in ["watch", "decode", "all"] {
+            assert!(access.authorize(Some(&format!("Bearer {}", token)), Endpoint::Stream, "connect").is_ok());
+        }
+        assert_eq!(
+            access.authorize(Some("Bearer watch"), Endpoint::Prediction, "{}"),
+            Err(AuthError::Forbidden {
+                principal: "dashboard".to_string(),
+                role: Role::Observer,
+                endpoint: Endpoint::Prediction,
+                required: Role::Operator,
+            })
+        );
+        let decoder = access.authorize(Some("bearer  decode "), Endpoint::Prediction, "{}").unwrap();
+        assert_eq!(decoder, Principal { name: "decoder".to_string(), role: Role::Operator });
+        assert!(access.authorize(Some("Bearer all"), Endpoint::Prediction, "{}").is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_missing_and_unknown_tokens() {
+        let (access, path) = access("tokens");
+        assert_eq!(access.authorize(None, Endpoint::Stream, "connect"), Err(AuthError::MissingToken));
+        assert_eq!(access.authorize(Some("Basic d2F0Y2g="), Endpoint::Stream, "connect"), Err(AuthError::MissingToken));
+        assert_eq!(access.authorize(Some("Bearer "), Endpoint::Stream, "connect"), Err(AuthError::MissingToken));
+        assert_eq!(access.authorize(Some("Bearer guess"), Endpoint::Stream, "connect"), Err(AuthError::InvalidToken));
+        // The stored hash is not a token
+        assert_eq!(
+            access.authorize(Some(&format!("Bearer {}", hash_token("watch"))), Endpoint::Stream, "connect"),
+            Err(AuthError::InvalidToken)
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn audits_every_attempt() {
+        let (access, path) = access("audit");
+        let _ = access.authorize(Some("Bearer decode"), Endpoint::Prediction, "{\"type\":\"prediction\"}");
+        let _ = access.authorize(Some("Bearer watch"), Endpoint::Prediction, "{\"type\":\"prediction\"}");
+        let _ = access.authorize(Some("Bearer guess"), Endpoint::Stream, "connect");
+        let records: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!((&records[0]["principal"], &records[0]["allowed"]), (&"decoder".into(), &true.into()));
+        assert_eq!((&records[1]["principal"], &records[1]["allowed"]), (&"dashboard".into(), &false.into()));
+        assert_eq!(records[1]["endpoint"], "prediction");
+        assert!(records[2]["principal"].is_null());
+        assert_eq!(records[2]["reason"], "invalid bearer token");
+        std::fs::remove_file(path).unwrap();
+    }
+}
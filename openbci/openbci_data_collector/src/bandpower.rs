@@ -0,0 +1,185 @@
+//! Live mu and beta power over the motor cortex, for `--band-power-every`.
+//!
+//! Welch PSD over the last two seconds of C3 and C4 (1 s Hann segments,
+//! half overlapping), averaged over 8-13 Hz and 13-30 Hz. In cued trials
+//! the window at the cue onset, the end of fixation, becomes the
+//! reference, and later estimates also report the change against it as
+//! ERD/ERS: motor imagery of one hand should show mu and beta power
+//! dropping (negative %) over the opposite hemisphere.
+
+use anyhow::{bail, Result};
+use eeg_dsp::{motor_imagery_bands, welch, SpectralParams};
+use ndarray::{aview1, Axis};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Channels the monitor follows, when the montage has them
+pub const MOTOR_CHANNELS: [&str; 2] = ["C3", "C4"];
+/// Window every estimate is made over
+pub const WINDOW_SECONDS: f64 = 2.0;
+/// Least fixation that still gives a reference, one Welch segment
+const MIN_REFERENCE_SECONDS: f64 = 1.0;
+/// Samples are in nanovolts, powers are reported in µV²/Hz
+const NV2_PER_UV2: f64 = 1e6;
+
+/// Mu and beta power of one channel, in µV²/Hz
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelPower {
+    pub label: String,
+    pub mu: f64,
+    pub beta: f64,
+    /// Change against the reference in %, once there is one
+    pub mu_change: Option<f64>,
+    pub beta_change: Option<f64>,
+}
+
+/// One estimate of every followed channel
+#[derive(Debug, Clone)]
+pub struct BandPowers(pub Vec<ChannelPower>);
+
+impl fmt::Display for BandPowers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, channel) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" | ")?;
+            }
+            write!(f, "{} mu {:.2} beta {:.2} µV²/Hz", channel.label, channel.mu, channel.beta)?;
+            if let (Some(mu), Some(beta)) = (channel.mu_change, channel.beta_change) {
+                write!(f, " ({:+.0}% / {:+.0}%)", mu, beta)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sliding-window mu/beta power of C3 and C4
+pub struct BandPowerMonitor {
+    sample_rate: f64,
+    /// Index into the sample and label of every followed channel
+    channels: Vec<(usize, String)>,
+    window: usize,
+    every: usize,
+    buffers: Vec<VecDeque<f32>>,
+    since_last: usize,
+    /// `[mu, beta]` per followed channel at the end of fixation
+    reference: Option<Vec<[f64; 2]>>,
+}
+
+impl BandPowerMonitor {
+    /// Follow whichever of C3 and C4 are among `labels` (10-20 labels in
+    /// channel order), estimating every `every_seconds`
+    pub fn new(labels: &[String], sample_rate: u32, every_seconds: f64) -> Result<Self> {
+        let channels: Vec<(usize, String)> = labels
+            .iter()
+            .enumerate()
+            .filter(|(_, label)| MOTOR_CHANNELS.contains(&label.as_str()))
+            .map(|(i, label)| (i, label.clone()))
+            .collect();
+        if channels.is_empty() {
+            bail!("Band power needs C3 or C4 in the montage, channels are {:?}", labels);
+        }
+        if every_seconds <= 0.0 {
+            bail!("Band power interval must be positive, got {}", every_seconds);
+        }
+        let fs = sample_rate as f64;
+        if fs / 2.0 <= 30.0 {
+            bail!("Band power needs a sample rate above 60 Hz for the beta band, got {} Hz", sample_rate);
+        }
+        let window = (WINDOW_SECONDS * fs) as usize;
+        Ok(Self {
+            sample_rate: fs,
+            window,
+            every: ((every_seconds * fs) as usize).max(1),
+            buffers: vec![VecDeque::with_capacity(window); channels.len()],
+            channels,
+            since_last: 0,
+            reference: None,
+        })
+    }
+
+    /// Add one sample; returns the powers once the window is full and an
+    /// update is due
+    pub fn push(&mut self, sample: &[f32]) -> Option<BandPowers> {
+        for (buffer, (index, _)) in self.buffers.iter_mut().zip(&self.channels) {
+            if buffer.len() == self.window {
+                buffer.pop_front();
+            }
+            buffer.push_back(sample.get(*index).copied().unwrap_or(0.0));
+        }
+        self.since_last += 1;
+        if self.since_last < self.every || self.buffers.first().is_none_or(|b| b.len() < self.window) {
+            return None;
+        }
+        self.since_last = 0;
+
+        let powers = self.estimate();
+        Some(BandPowers(
+            self.channels
+                .iter()
+                .zip(&powers)
+                .enumerate()
+                .map(|(i, ((_, label), &[mu, beta]))| {
+                    let reference = self.reference.as_ref().map(|r| r[i]);
+                    ChannelPower {
+                        label: label.clone(),
+                        mu,
+                        beta,
+                        mu_change: reference.map(|[r, _]| change(mu, r)),
+                        beta_change: reference.map(|[_, r]| change(beta, r)),
+                    }
+                })
+                .collect(),
+        ))
+    }
+
+    /// The cue appeared: the window so far becomes the reference. Returns
+    /// `false` when fixation was too short for one.
+    pub fn set_reference(&mut self) -> bool {
+        let held = self.buffers.first().map_or(0, |b| b.len());
+        if (held as f64) < MIN_REFERENCE_SECONDS * self.sample_rate {
+            return false;
+        }
+        self.reference = Some(self.estimate());
+        true
+    }
+
+    /// `[mu, beta]` of every followed channel over the samples held
+    fn estimate(&self) -> Vec<[f64; 2]> {
+        self.buffers
+            .iter()
+            .map(|buffer| mu_beta(&buffer.iter().copied().collect::<Vec<f32>>(), self.sample_rate))
+            .collect()
+    }
+}
+
+/// Mean Welch PSD of `signal` (nanovolts) over the mu and beta bands, in
+/// µV²/Hz
+pub fn mu_beta(signal: &[f32], sample_rate: f64) -> [f64; 2] {
+    // One-second segments give 1 Hz resolution
+    let params = SpectralParams::new(sample_rate, (sample_rate.round() as usize).min(signal.len()));
+    let Ok(psd) = welch(aview1(signal).insert_axis(Axis(0)), &params) else {
+        return [0.0; 2];
+    };
+    let mut powers = [0.0; 2];
+    for (power, band) in powers.iter_mut().zip(&motor_imagery_bands()) {
+        let bins: Vec<f64> = psd
+            .frequencies
+            .iter()
+            .zip(psd.power.row(0))
+            .filter(|(&f, _)| f >= band.low && f < band.high)
+            .map(|(_, &p)| p)
+            .collect();
+        *power = bins.iter().sum::<f64>() / bins.len().max(1) as f64 / NV2_PER_UV2;
+    }
+    powers
+}
+
+/// Relative change in %, negative for desynchronization
+pub fn change(power: f64, reference: f64) -> f64 {
+    if reference > 0.0 {
+        (power / reference - 1.0) * 100.0
+    } else {
+        0.0
+    }
+}
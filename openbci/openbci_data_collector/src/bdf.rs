@@ -0,0 +1,527 @@
+//! BDF+ (24-bit EDF) and EDF+ files.
+//!
+//! The ADS1299 delivers 24-bit samples; BDF stores them as 24-bit integers
+//! so nothing is lost to 16-bit EDF or to float formatting in the CSV.
+//! EDF+ is written for tools that only read EDF, over a narrower range.
+//! Data records are one second long and markers go into an annotation
+//! signal as EDF+ time-stamped annotation lists.
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Annotation signal size per record, in samples of the file's width
+const ANNOTATION_SAMPLES: usize = 128;
+/// Byte offset of the "number of data records" header field
+const NUM_RECORDS_OFFSET: u64 = 236;
+
+/// Physical range of EDF files in microvolts: 0.1 µV steps, enough for
+/// high-passed EEG but not for the Cyton's raw DC offsets
+pub const EDF_RANGE_UV: f64 = 3276.7;
+
+/// Input range of the ADS1299 at `gain`, in microvolts (4.5 V reference)
+pub fn ads1299_range_uv(gain: u8) -> f64 {
+    4_500_000.0 / gain.max(1) as f64
+}
+
+/// Sample width of the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flavor {
+    /// 24-bit BDF+
+    Bdf,
+    /// 16-bit EDF+
+    Edf,
+}
+
+impl Flavor {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Bdf => "BDF",
+            Self::Edf => "EDF",
+        }
+    }
+
+    fn sample_bytes(self) -> usize {
+        match self {
+            Self::Bdf => 3,
+            Self::Edf => 2,
+        }
+    }
+
+    fn digital_range(self) -> (i32, i32) {
+        match self {
+            Self::Bdf => (-8_388_608, 8_388_607),
+            Self::Edf => (-32_768, 32_767),
+        }
+    }
+
+    fn annotations_label(self) -> &'static str {
+        match self {
+            Self::Bdf => "BDF Annotations",
+            Self::Edf => "EDF Annotations",
+        }
+    }
+
+    /// The 8-byte version field
+    fn version(self) -> Vec<u8> {
+        match self {
+            Self::Bdf => [&[0xFF][..], b"BIOSEMI"].concat(),
+            Self::Edf => field("0", 8),
+        }
+    }
+
+    fn reserved(self) -> &'static str {
+        match self {
+            Self::Bdf => "BDF+C",
+            Self::Edf => "EDF+C",
+        }
+    }
+}
+
+/// Left-aligned, space-padded ASCII header field of exactly `width` bytes
+fn field(value: &str, width: usize) -> Vec<u8> {
+    let mut bytes: Vec<u8> = value
+        .chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() { c as u8 } else { b'_' })
+        .take(width)
+        .collect();
+    bytes.resize(width, b' ');
+    bytes
+}
+
+/// Number formatted to fit an 8-byte header field
+fn number_field(value: f64) -> Vec<u8> {
+    let mut text = format!("{}", value);
+    if text.len() > 8 {
+        let integer_digits = format!("{}", value.trunc() as i64).len();
+        let decimals = 8usize.saturating_sub(integer_digits + 1);
+        text = format!("{:.*}", decimals, value);
+    }
+    field(&text, 8)
+}
+
+/// Writes a BDF+ or EDF+ file record by record
+pub struct BdfWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    flavor: Flavor,
+    num_channels: usize,
+    samples_per_record: usize,
+    /// Nanovolts per digital step
+    scale_nv: f64,
+    /// Channel-major samples of the record being filled
+    record: Vec<Vec<i32>>,
+    /// Annotations not yet written, `(onset seconds, label)`
+    annotations: Vec<(f64, String)>,
+    records_written: u64,
+    samples_written: u64,
+}
+
+impl BdfWriter {
+    /// Create `path` with one signal per label. `range_uv` is the
+    /// symmetric physical range mapped onto the digital range.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create(
+        flavor: Flavor,
+        path: &Path,
+        patient: &str,
+        recording: &str,
+        start: DateTime<Utc>,
+        labels: &[String],
+        sample_rate: u32,
+        range_uv: f64,
+    ) -> Result<Self> {
+        if sample_rate == 0 || labels.is_empty() {
+            bail!("{} needs a non-zero sample rate and at least one channel", flavor.name());
+        }
+        let file = File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+        let mut file = BufWriter::new(file);
+        let samples_per_record = sample_rate as usize;
+        let ns = labels.len() + 1;
+
+        let mut header = Vec::with_capacity(256 * (ns + 1));
+        header.extend(flavor.version());
+        header.extend(field(patient, 80));
+        header.extend(field(recording, 80));
+        header.extend(field(&start.format("%d.%m.%y").to_string(), 8));
+        header.extend(field(&start.format("%H.%M.%S").to_string(), 8));
+        header.extend(field(&(256 * (ns + 1)).to_string(), 8));
+        header.extend(field(flavor.reserved(), 44));
+        header.extend(field("-1", 8));
+        header.extend(field("1", 8));
+        header.extend(field(&ns.to_string(), 4));
+
+        let signals: Vec<&str> = labels.iter().map(String::as_str).chain([flavor.annotations_label()]).collect();
+        let is_eeg = |i: usize| i < labels.len();
+        for label in &signals {
+            header.extend(field(label, 16));
+        }
+        for i in 0..ns {
+            header.extend(field(if is_eeg(i) { "AgAgCl electrode" } else { "" }, 80));
+        }
+        for i in 0..ns {
+            header.extend(field(if is_eeg(i) { "uV" } else { "" }, 8));
+        }
+        for i in 0..ns {
+            header.extend(if is_eeg(i) { number_field(-range_uv) } else { number_field(-1.0) });
+        }
+        for i in 0..ns {
+            header.extend(if is_eeg(i) { number_field(range_uv) } else { number_field(1.0) });
+        }
+        let (digital_min, digital_max) = flavor.digital_range();
+        for _ in 0..ns {
+            header.extend(number_field(digital_min as f64));
+        }
+        for _ in 0..ns {
+            header.extend(number_field(digital_max as f64));
+        }
+        for _ in 0..ns {
+            header.extend(field("", 80));
+        }
+        for i in 0..ns {
+            let samples = if is_eeg(i) { samples_per_record } else { ANNOTATION_SAMPLES };
+            header.extend(field(&samples.to_string(), 8));
+        }
+        for _ in 0..ns {
+            header.extend(field("", 32));
+        }
+        file.write_all(&header)?;
+
+        // Readers derive the scale from the header fields, so use the
+        // rounded range that was actually written
+        let written_range: f64 = String::from_utf8_lossy(&number_field(range_uv)).trim().parse()?;
+        let scale_nv = 2.0 * written_range * 1000.0 / (digital_max as f64 - digital_min as f64);
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            flavor,
+            num_channels: labels.len(),
+            samples_per_record,
+            scale_nv,
+            record: vec![Vec::with_capacity(samples_per_record); labels.len()],
+            annotations: Vec::new(),
+            records_written: 0,
+            samples_written: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn samples_written(&self) -> u64 {
+        self.samples_written
+    }
+
+    /// Append one sample, in nanovolts; values outside the range clip
+    pub fn write_sample(&mut self, sample: &[f32]) -> Result<()> {
+        if sample.len() != self.num_channels {
+            bail!("Sample has {} channels, file has {}", sample.len(), self.num_channels);
+        }
+        let (digital_min, digital_max) = self.flavor.digital_range();
+        for (channel, &nv) in self.record.iter_mut().zip(sample) {
+            let digital = (nv as f64 / self.scale_nv).round();
+            let digital = if digital.is_nan() { 0.0 } else { digital };
+            channel.push(digital.clamp(digital_min as f64, digital_max as f64) as i32);
+        }
+        self.samples_written += 1;
+        if self.record[0].len() == self.samples_per_record {
+            self.flush_record()?;
+        }
+        Ok(())
+    }
+
+    /// Annotate the sample that will be written next
+    pub fn annotate(&mut self, label: &str) {
+        let onset = self.samples_written as f64 / self.samples_per_record as f64;
+        self.annotations.push((onset, label.to_string()));
+    }
+
+    fn flush_record(&mut self) -> Result<()> {
+        let width = self.flavor.sample_bytes();
+        let mut bytes = Vec::with_capacity(width * (self.num_channels * self.samples_per_record + ANNOTATION_SAMPLES));
+        for channel in &mut self.record {
+            // The last record of a recording is padded with zeros
+            channel.resize(self.samples_per_record, 0);
+            for value in channel.drain(..) {
+                bytes.extend_from_slice(&value.to_le_bytes()[..width]);
+            }
+        }
+
+        // Time-keeping TAL first, then as many pending annotations as fit;
+        // the rest carry over, their onsets are absolute
+        let capacity = width * ANNOTATION_SAMPLES;
+        let mut tal = format!("+{}\x14\x14\0", self.records_written).into_bytes();
+        let mut written = 0;
+        for (onset, label) in &self.annotations {
+            let prefix = format!("+{:.4}\x14", onset);
+            let mut label = label.replace(['\x14', '\0'], " ");
+            let room = capacity.saturating_sub(tal.len() + prefix.len() + 2);
+            if label.len() > room {
+                if written > 0 {
+                    break;
+                }
+                // Alone in a record and still too long: it would never
+                // fit, so cut it on a character boundary
+                let end = (0..=room).rev().find(|&i| label.is_char_boundary(i)).unwrap_or(0);
+                warn!("Annotation {:?} is longer than {} bytes, truncated", label, room);
+                label.truncate(end);
+            }
+            let entry = format!("{}{}\x14\0", prefix, label);
+            tal.extend(entry.into_bytes());
+            written += 1;
+        }
+        if written < self.annotations.len() {
+            debug!("{} annotations deferred to the next data record", self.annotations.len() - written);
+        }
+        self.annotations.drain(..written);
+        tal.resize(capacity, 0);
+        bytes.extend(tal);
+
+        self.file.write_all(&bytes)?;
+        self.records_written += 1;
+        Ok(())
+    }
+
+    /// Write the last, padded record plus any leftover annotations and
+    /// fill in the record count
+    pub fn finish(mut self) -> Result<()> {
+        while !self.record[0].is_empty() || !self.annotations.is_empty() {
+            self.flush_record()?;
+        }
+        self.file.seek(SeekFrom::Start(NUM_RECORDS_OFFSET))?;
+        self.file.write_all(&field(&self.records_written.to_string(), 8))?;
+        self.file.flush()?;
+        info!("Finalized {} file: {:?} ({} records)", self.flavor.name(), self.path, self.records_written);
+        Ok(())
+    }
+}
+
+/// Contents of a BDF+ or EDF+ file
+#[derive(Debug)]
+pub struct BdfData {
+    pub labels: Vec<String>,
+    pub sample_rate: f64,
+    /// Row-major samples in nanovolts, `samples[i][channel]`
+    pub samples: Vec<Vec<f32>>,
+    /// `(onset seconds, label)` from the annotation signal
+    pub annotations: Vec<(f64, String)>,
+}
+
+struct SignalHeader {
+    label: String,
+    samples_per_record: usize,
+    /// Nanovolts per digital step and the offset at digital zero
+    gain: f64,
+    offset: f64,
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim().to_string()
+}
+
+fn number<T: std::str::FromStr>(bytes: &[u8], what: &str) -> Result<T> {
+    text(bytes)
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid EDF header field {}: '{}'", what, text(bytes)))
+}
+
+impl BdfData {
+    pub fn read(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let flavor = match bytes.get(..8) {
+            Some(version) if bytes.len() >= 256 && version == Flavor::Bdf.version() => Flavor::Bdf,
+            Some(version) if bytes.len() >= 256 && version == Flavor::Edf.version() => Flavor::Edf,
+            _ => bail!("{:?} is not a BDF or EDF file", path),
+        };
+        let width = flavor.sample_bytes();
+        let annotations_label = flavor.annotations_label();
+        let header_bytes: usize = number(&bytes[184..192], "header size")?;
+        let num_records: i64 = number(&bytes[236..244], "record count")?;
+        let record_seconds: f64 = number(&bytes[244..252], "record duration")?;
+        let ns: usize = number(&bytes[252..256], "signal count")?;
+        if bytes.len() < header_bytes || header_bytes != 256 * (ns + 1) {
+            bail!("{:?} has a truncated header", path);
+        }
+
+        // Per-signal fields are stored field by field across all signals
+        let mut offset = 256;
+        let mut column = |width: usize| -> Vec<&[u8]> {
+            let fields = (0..ns).map(|i| &bytes[offset + i * width..offset + (i + 1) * width]).collect();
+            offset += ns * width;
+            fields
+        };
+        let labels = column(16);
+        let _transducer = column(80);
+        let units = column(8);
+        let phys_min = column(8);
+        let phys_max = column(8);
+        let dig_min = column(8);
+        let dig_max = column(8);
+        let _prefilter = column(80);
+        let samples_per_record = column(8);
+
+        let mut signals = Vec::with_capacity(ns);
+        for i in 0..ns {
+            let (pmin, pmax): (f64, f64) = (number(phys_min[i], "physical min")?, number(phys_max[i], "physical max")?);
+            let (dmin, dmax): (f64, f64) = (number(dig_min[i], "digital min")?, number(dig_max[i], "digital max")?);
+            let to_nv = match text(units[i]).as_str() {
+                "nV" => 1.0,
+                "mV" => 1_000_000.0,
+                "V" => 1_000_000_000.0,
+                _ => 1000.0,
+            };
+            let gain = (pmax - pmin) / (dmax - dmin).max(1.0);
+            signals.push(SignalHeader {
+                label: text(labels[i]),
+                samples_per_record: number(samples_per_record[i], "samples per record")?,
+                gain: gain * to_nv,
+                offset: (pmin - dmin * gain) * to_nv,
+            });
+        }
+
+        let record_bytes: usize = signals.iter().map(|s| width * s.samples_per_record).sum();
+        let available = (bytes.len() - header_bytes) / record_bytes.max(1);
+        let num_records = if num_records < 0 { available } else { (num_records as usize).min(available) };
+
+        let eeg: Vec<usize> = (0..ns).filter(|&i| signals[i].label != annotations_label).collect();
+        let per_record = eeg.first().map_or(0, |&i| signals[i].samples_per_record);
+        if eeg.iter().any(|&i| signals[i].samples_per_record != per_record) {
+            bail!("{:?} mixes sample rates, which is not supported", path);
+        }
+
+        let mut samples = Vec::with_capacity(num_records * per_record);
+        let mut annotations = Vec::new();
+        for record in 0..num_records {
+            let mut position = header_bytes + record * record_bytes;
+            let mut channels: Vec<Vec<f32>> = Vec::with_capacity(eeg.len());
+            for signal in &signals {
+                let data = &bytes[position..position + width * signal.samples_per_record];
+                position += data.len();
+                if signal.label == annotations_label {
+                    annotations.extend(parse_tals(data));
+                    continue;
+                }
+                channels.push(
+                    data.chunks_exact(width)
+                        .map(|b| {
+                            let digital = match flavor {
+                                Flavor::Bdf => {
+                                    i32::from_le_bytes([b[0], b[1], b[2], if b[2] & 0x80 != 0 { 0xFF } else { 0 }])
+                                }
+                                Flavor::Edf => i16::from_le_bytes([b[0], b[1]]) as i32,
+                            };
+                            (signal.offset + digital as f64 * signal.gain) as f32
+                        })
+                        .collect(),
+                );
+            }
+            for i in 0..per_record {
+                samples.push(channels.iter().map(|c| c[i]).collect());
+            }
+        }
+
+        Ok(Self {
+            labels: eeg.iter().map(|&i| signals[i].label.clone()).collect(),
+            sample_rate: per_record as f64 / record_seconds,
+            samples,
+            annotations,
+        })
+    }
+}
+
+/// Annotations in one record's TALs, skipping the time-keeping entries
+fn parse_tals(data: &[u8]) -> Vec<(f64, String)> {
+    let mut found = Vec::new();
+    for tal in data.split(|&b| b == 0).filter(|t| !t.is_empty()) {
+        let mut parts = tal.split(|&b| b == 0x14);
+        let Some(onset) = parts.next() else { continue };
+        // Onset may carry a duration after 0x15
+        let onset = onset.split(|&b| b == 0x15).next().unwrap_or(onset);
+        let Ok(onset) = text(onset).parse::<f64>() else { continue };
+        for label in parts.map(text).filter(|l| !l.is_empty()) {
+            found.push((onset, label));
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("openbci_bdf_{}_{}", std::process::id(), name))
+    }
+
+    fn writer(flavor: Flavor, path: &Path, channels: usize, range_uv: f64) -> BdfWriter {
+        let labels: Vec<String> = (1..=channels).map(|i| format!("EEG{}", i)).collect();
+        BdfWriter::create(flavor, path, "X", "test", Utc::now(), &labels, 4, range_uv).unwrap()
+    }
+
+    #[test]
+    fn round_trips_samples_and_annotations() {
+        for flavor in [Flavor::Bdf, Flavor::Edf] {
+            let path = temp_path(&format!("round_trip.{}", flavor.name()));
+            let mut file = writer(flavor, &path, 2, 1000.0);
+            for i in 0..10 {
+                if i == 2 || i == 9 {
+                    file.annotate(&format!("cue {}", i));
+                }
+                file.write_sample(&[i as f32 * 10_000.0, -(i as f32) * 5_000.0]).unwrap();
+            }
+            file.finish().unwrap();
+
+            let data = BdfData::read(&path).unwrap();
+            assert_eq!(data.labels, ["EEG1", "EEG2"]);
+            assert_eq!(data.sample_rate, 4.0);
+            // Three one-second records, the last one padded
+            assert_eq!(data.samples.len(), 12);
+            let step = if flavor == Flavor::Bdf { 1.0 } else { 100.0 };
+            for (i, row) in data.samples.iter().take(10).enumerate() {
+                assert!((row[0] - i as f32 * 10_000.0).abs() <= step, "{:?}", row);
+                assert!((row[1] + i as f32 * 5_000.0).abs() <= step, "{:?}", row);
+            }
+            assert_eq!(data.annotations, [(0.5, "cue 2".to_string()), (2.25, "cue 9".to_string())]);
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn truncates_labels_longer_than_a_record() {
+        let path = temp_path("long_label.edf");
+        let mut file = writer(Flavor::Edf, &path, 1, 1000.0);
+        file.annotate(&"x".repeat(1000));
+        file.annotate("after");
+        file.write_sample(&[0.0]).unwrap();
+        file.finish().unwrap();
+
+        let data = BdfData::read(&path).unwrap();
+        assert_eq!(data.annotations.len(), 2);
+        let (onset, label) = &data.annotations[0];
+        assert_eq!(*onset, 0.0);
+        assert!(label.len() < 256 && label.chars().all(|c| c == 'x'), "{}", label);
+        assert_eq!(data.annotations[1], (0.0, "after".to_string()));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn writes_24_bit_little_endian_samples() {
+        let path = temp_path("encoding.bdf");
+        // One digital step per nanovolt
+        let mut file = writer(Flavor::Bdf, &path, 1, 8388.608);
+        for nv in [0x12_3456 as f32, -1.0, 1e9, -1e9] {
+            file.write_sample(&[nv]).unwrap();
+        }
+        file.finish().unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        let data = &bytes[256 * 3..256 * 3 + 12];
+        assert_eq!(data, [0x56, 0x34, 0x12, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F, 0x00, 0x00, 0x80]);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,402 @@
+//! Cross-validated comparison of classifiers on a built dataset. Run as
+//! `openbci benchmark`.
+//!
+//! The trials of a dataset manifest are loaded from the recording tree and
+//! filtered with the online band-pass, notch and common average reference
+//! the live pipeline would use (see [`crate::loader`]), and split into
+//! folds: stratified k-fold over trials, or one fold
+//! per session with that session held out. Every model then classifies one
+//! window per cue of each fold's test trials, exactly as
+//! `openbci_online_bci` would, one window at a time:
+//!
+//! - exported or Rust-trained networks (EEGNet, the tiny transformer) and
+//!   calibration models are fixed files; `{fold}` in a path picks the file trained for
+//!   that fold, e.g. `eegnet_{fold}.onnx`, otherwise the same model is
+//!   scored on every fold
+//! - `--csp-lda` refits CSP+LDA (see [`crate::calibration`]) on every
+//!   fold's training trials
+//!
+//! Each model gets per-fold accuracy and, over all folds' windows together,
+//! balanced accuracy, Cohen's kappa, AUC, per-class F1 and the information
+//! transfer rate of one selection per window (see [`eeg_metrics`]), and the
+//! time one window takes. This is the EEGNet vs tiny
+//! transformer comparison, with CSP+LDA as the classical reference.
+
+use crate::calibration::{self, FitArgs};
+use crate::classifier::Classifier;
+use crate::compute::{ComputeArgs, ComputeConfig, GpuSelection};
+use crate::latency::Latency;
+use crate::loader::{DatasetArgs, Trials};
+use crate::recording::Recording;
+use anyhow::{bail, Result};
+use clap::Args;
+use eeg_metrics::{Evaluation, Report};
+use log::{info, warn};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+
+/// Placeholder in a model path for the fold's name
+pub const FOLD_PLACEHOLDER: &str = "{fold}";
+
+/// Options of `openbci benchmark`
+#[derive(Args, Debug, Clone)]
+pub struct BenchmarkArgs {
+    /// Models to compare: ONNX files with their spec next to them (build
+    /// with --features inference), networks from openbci_train (.mpk, with
+    /// --features train) or calibration models; {fold} in a path is
+    /// replaced by the fold's name
+    #[arg(short, long = "model", value_name = "FILE")]
+    pub models: Vec<String>,
+
+    /// Also refit CSP+LDA on every fold's training trials
+    #[arg(long)]
+    pub csp_lda: bool,
+
+    #[command(flatten)]
+    pub data: DatasetArgs,
+
+    /// Folds: kfold (--folds, stratified by class) or session (leave one
+    /// session out)
+    #[arg(long, default_value = "kfold")]
+    pub cv: Folding,
+
+    #[command(flatten)]
+    pub fit: FitArgs,
+
+    /// Write the results as JSON to this file
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub compute: ComputeArgs,
+}
+
+/// How trials are dealt into folds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Folding {
+    /// Stratified k-fold over trials
+    KFold,
+    /// One fold per session, holding it out
+    Session,
+}
+
+impl FromStr for Folding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "kfold" | "k-fold" | "trial" => Ok(Self::KFold),
+            "session" | "loso" | "leave-one-session-out" => Ok(Self::Session),
+            _ => bail!("Unknown cross-validation '{}', expected kfold or session", s),
+        }
+    }
+}
+
+impl fmt::Display for Folding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::KFold => "kfold",
+            Self::Session => "session",
+        })
+    }
+}
+
+/// Scores of one model over every fold
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelResults {
+    pub model: String,
+    /// What the model is, e.g. its input layout or CSP band
+    pub description: String,
+    /// A model was trained (or fitted) for every fold
+    pub per_fold: bool,
+    pub classes: Vec<String>,
+    pub windows: usize,
+    /// Test trials of classes the model does not know, left out
+    pub skipped_trials: usize,
+    pub fold_accuracy: Vec<f64>,
+    pub mean_accuracy: f64,
+    pub std_accuracy: f64,
+    /// Scores of all folds' windows together, one selection per window
+    pub scores: Report,
+    /// Time one window took
+    pub latency_ms: Latency,
+}
+
+/// Everything `benchmark` found, as written with `--output`
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchmarkResults {
+    pub manifest: PathBuf,
+    pub cv: Folding,
+    pub folds: Vec<String>,
+    pub num_trials: usize,
+    pub window_start: f64,
+    pub models: Vec<ModelResults>,
+    pub compute: ComputeConfig,
+}
+
+/// Test trials of one fold; the others train
+struct Fold {
+    name: String,
+    test: Vec<usize>,
+}
+
+/// A model under test
+enum Candidate {
+    /// Path, with `{fold}` for one file per fold
+    File(String),
+    CspLda,
+}
+
+impl Candidate {
+    fn name(&self) -> String {
+        match self {
+            Self::File(path) => path.clone(),
+            Self::CspLda => "csp_lda".to_string(),
+        }
+    }
+
+    /// The model of `fold`, fitted to `train` when it is refitted; trained
+    /// networks run on `gpu` when they can
+    fn for_fold(&self, fold: &Fold, train: &[&Recording], args: &FitArgs, gpu: GpuSelection) -> Result<Classifier> {
+        match self {
+            Self::File(path) => Classifier::open(Path::new(&path.replace(FOLD_PLACEHOLDER, &fold.name)))?.on_gpu(gpu),
+            Self::CspLda => {
+                // The folds are the benchmark's, not cross-validated again inside
+                let args = FitArgs { folds: 1, ..args.clone() };
+                Ok(Classifier::Calibrated(calibration::fit(train, &args, None)?))
+            }
+        }
+    }
+}
+
+/// Run the benchmark and print the comparison; returns the results
+pub fn run(args: &BenchmarkArgs) -> Result<BenchmarkResults> {
+    let compute = args.compute.apply()?;
+    let mut candidates: Vec<Candidate> = args.models.iter().cloned().map(Candidate::File).collect();
+    if args.csp_lda {
+        candidates.push(Candidate::CspLda);
+    }
+    if candidates.is_empty() {
+        bail!("Nothing to benchmark, give --model files and/or --csp-lda");
+    }
+
+    let trials = Trials::load(&args.data)?;
+    let folds = folds(&trials.recordings, args)?;
+    info!(
+        "Benchmarking {} models on {} trials, {} {} folds",
+        candidates.len(),
+        trials.len(),
+        folds.len(),
+        args.cv
+    );
+
+    let mut models = Vec::with_capacity(candidates.len());
+    for candidate in &candidates {
+        let per_fold = match candidate {
+            Candidate::File(path) => path.contains(FOLD_PLACEHOLDER),
+            Candidate::CspLda => true,
+        };
+        if !per_fold && folds.len() > 1 {
+            warn!(
+                "{} is scored on every fold as it is; that is only fair if it was not trained on these trials",
+                candidate.name()
+            );
+        }
+        models.push(evaluate(candidate, per_fold, &trials, &folds, args)?);
+    }
+
+    let results = BenchmarkResults {
+        manifest: args.data.manifest.clone(),
+        cv: args.cv,
+        folds: folds.iter().map(|f| f.name.clone()).collect(),
+        num_trials: trials.len(),
+        window_start: args.fit.window_start,
+        models,
+        compute,
+    };
+    println!();
+    println!("{}", table(&results));
+    if let Some(path) = &args.output {
+        fs::write(path, serde_json::to_string_pretty(&results)?)?;
+        info!("Saved results to {:?}", path);
+    }
+    Ok(results)
+}
+
+/// Deal the trials into folds
+fn folds(trials: &[Recording], args: &BenchmarkArgs) -> Result<Vec<Fold>> {
+    match args.cv {
+        Folding::KFold => {
+            let folds = args.fit.folds;
+            if folds < 2 {
+                bail!("--folds must be at least 2");
+            }
+            let mut by_class: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+            for (i, rec) in trials.iter().enumerate() {
+                by_class.entry(rec.metadata.class_label.as_str()).or_default().push(i);
+            }
+            if let Some((class, members)) = by_class.iter().find(|(_, m)| m.len() < folds) {
+                bail!("Class {} has {} trials, fewer than {} folds", class, members.len(), folds);
+            }
+            let mut rng = StdRng::seed_from_u64(args.fit.seed);
+            let mut test = vec![Vec::new(); folds];
+            for members in by_class.values_mut() {
+                members.shuffle(&mut rng);
+                for (position, &trial) in members.iter().enumerate() {
+                    test[position % folds].push(trial);
+                }
+            }
+            Ok(test.into_iter().enumerate().map(|(i, test)| Fold { name: (i + 1).to_string(), test }).collect())
+        }
+        Folding::Session => {
+            let mut by_session: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+            for (i, rec) in trials.iter().enumerate() {
+                let name = format!("{}_{}", rec.metadata.subject_id, rec.metadata.session_id);
+                by_session.entry(name).or_default().push(i);
+            }
+            if by_session.len() < 2 {
+                bail!("Leave-one-session-out needs at least two sessions, found {:?}", by_session.keys());
+            }
+            Ok(by_session.into_iter().map(|(name, test)| Fold { name, test }).collect())
+        }
+    }
+}
+
+/// Score one model on every fold
+fn evaluate(
+    candidate: &Candidate,
+    per_fold: bool,
+    trials: &Trials,
+    folds: &[Fold],
+    args: &BenchmarkArgs,
+) -> Result<ModelResults> {
+    let name = candidate.name();
+    let mut classes: Vec<String> = Vec::new();
+    let mut description = String::new();
+    let mut pooled: Option<Evaluation> = None;
+    let mut selection = None;
+    let mut fold_accuracy = Vec::with_capacity(folds.len());
+    let mut times = Vec::new();
+    let mut skipped = 0;
+
+    for fold in folds {
+        let train: Vec<&Recording> =
+            trials.recordings.iter().enumerate().filter(|(i, _)| !fold.test.contains(i)).map(|(_, rec)| rec).collect();
+        let model = candidate.for_fold(fold, &train, &args.fit, args.compute.gpu)?;
+        let model_classes = model.classes();
+        if classes.is_empty() {
+            classes = model_classes.clone();
+            description = model.describe();
+        } else if model_classes != classes {
+            bail!("{}: the model of fold {} has classes {:?}, not {:?}", name, fold.name, model_classes, classes);
+        }
+
+        let mut scored = Evaluation::new(classes.clone());
+        for &i in &fold.test {
+            let rec = &trials.recordings[i];
+            let Some(truth) = classes.iter().position(|c| *c == rec.metadata.class_label) else {
+                skipped += 1;
+                continue;
+            };
+            if let Some(rate) = model.sample_rate() {
+                if (rate - rec.metadata.sample_rate as f64).abs() > 1e-6 {
+                    bail!("{} was trained at {} Hz, {:?} is at {} Hz", name, rate, rec.metadata_path, rec.metadata.sample_rate);
+                }
+            }
+            selection.get_or_insert(model.window() as f64 / rec.metadata.sample_rate as f64);
+            let windows = trials.windows(&[i], args.fit.window_start, model.window(), |labels| model.select_channels(labels))?;
+            for window in windows.windows.outer_iter() {
+                let start = Instant::now();
+                let probabilities = model.predict(window.view())?;
+                times.push(start.elapsed().as_secs_f64() * 1000.0);
+                scored.add(truth, &probabilities)?;
+            }
+        }
+        let confusion = scored.confusion();
+        let accuracy = confusion.accuracy();
+        info!(
+            "{}, fold {}: {}/{} windows correct ({:.1}%)",
+            name,
+            fold.name,
+            confusion.correct(),
+            confusion.total(),
+            100.0 * accuracy
+        );
+        fold_accuracy.push(accuracy);
+        match &mut pooled {
+            Some(pooled) => pooled.merge(&scored)?,
+            None => pooled = Some(scored),
+        }
+    }
+
+    let mean_accuracy = fold_accuracy.iter().sum::<f64>() / fold_accuracy.len().max(1) as f64;
+    let std_accuracy = (fold_accuracy.iter().map(|a| (a - mean_accuracy).powi(2)).sum::<f64>()
+        / fold_accuracy.len().max(1) as f64)
+        .sqrt();
+    let scores = pooled.unwrap_or_else(|| Evaluation::new(classes.clone())).report(selection)?;
+    if skipped > 0 {
+        warn!("{}: left out {} test trials of classes the model does not know", name, skipped);
+    }
+    Ok(ModelResults {
+        model: name,
+        description,
+        per_fold,
+        classes,
+        windows: scores.predictions,
+        skipped_trials: skipped,
+        fold_accuracy,
+        mean_accuracy,
+        std_accuracy,
+        scores,
+        latency_ms: Latency::of(times),
+    })
+}
+
+/// The comparison as a text table
+pub fn table(results: &BenchmarkResults) -> String {
+    let mut text = String::new();
+    let width = results.models.iter().map(|m| m.model.len()).max().unwrap_or(0).max(5);
+    // Writing to a String cannot fail
+    let _ = writeln!(
+        text,
+        "{:<width$}  {:>7}  {:>15}  {:>8}  {:>6}  {:>5}  {:>8}  {:>11}  F1",
+        "Model", "Windows", "Accuracy", "Balanced", "Kappa", "AUC", "bits/min", "ms (p95)"
+    );
+    for model in &results.models {
+        let scores = &model.scores;
+        let f1 = scores.per_class.iter().map(|c| format!("{} {:.2}", c.class, c.f1)).collect::<Vec<_>>().join(", ");
+        let _ = writeln!(
+            text,
+            "{:<width$}  {:>7}  {:>6.1}% +/- {:>4.1}  {:>7.1}%  {:>6.3}  {:>5}  {:>8}  {:>4.2} ({:>4.2})  {}",
+            model.model,
+            model.windows,
+            100.0 * model.mean_accuracy,
+            100.0 * model.std_accuracy,
+            100.0 * scores.balanced_accuracy,
+            scores.kappa,
+            scores.auc.map_or("n/a".to_string(), |auc| format!("{:.3}", auc)),
+            scores.itr_bits_per_minute.map_or("n/a".to_string(), |itr| format!("{:.2}", itr)),
+            model.latency_ms.mean,
+            model.latency_ms.p95,
+            f1
+        );
+    }
+    let _ = write!(
+        text,
+        "{} folds ({}) over {} trials, windows from {} s after each cue, one selection per window",
+        results.folds.len(),
+        results.cv,
+        results.num_trials,
+        results.window_start
+    );
+    text
+}
@@ -0,0 +1,329 @@
+//! BIDS-EEG layout and sidecar files for `--bids`.
+//!
+//! Each trial becomes one run of the paradigm's task, `motorimagery` unless
+//! `--paradigm` names another:
+//! `sub-<subject>/ses-<session>/eeg/sub-<subject>_ses-<session>_task-motorimagery_run-<n>_eeg.<ext>`
+//! with `_channels.tsv`, `_events.tsv` and `_eeg.json` next to it. The
+//! collector's own files (trial metadata, host receive times, session
+//! manifest, montage, ASR calibration) stay in the `eeg` directory and are
+//! listed in `.bidsignore`.
+
+use crate::asr::ASR_FILE;
+use crate::events::{self, tsv};
+use crate::metadata::TrialMetadata;
+use crate::montage::MONTAGE_FILE;
+use crate::paradigm::Paradigm;
+use crate::qc::SESSION_MANIFEST;
+use crate::timesync::HOST_TIMES_SUFFIX;
+use crate::recording::Recording;
+use anyhow::{bail, Context, Result};
+use log::info;
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// BIDS version the sidecars follow
+pub const BIDS_VERSION: &str = "1.9.0";
+
+/// Collector files the validator should not look at
+const IGNORED: &[&str] = &["*_metadata.json", "*_host_times.tsv", SESSION_MANIFEST, MONTAGE_FILE, ASR_FILE];
+
+/// BIDS labels are alphanumeric: `session_01` becomes `session01`
+pub fn label(id: &str) -> String {
+    id.chars().filter(char::is_ascii_alphanumeric).collect()
+}
+
+/// `eeg` directory of a subject's session under the dataset root
+pub fn session_dir(root: &Path, subject_id: &str, session_id: &str) -> PathBuf {
+    root.join(format!("sub-{}", label(subject_id)))
+        .join(format!("ses-{}", label(session_id)))
+        .join("eeg")
+}
+
+/// File names of one run
+#[derive(Debug, Clone)]
+pub struct BidsRun {
+    dir: PathBuf,
+    /// `sub-XX_ses-XX_task-motorimagery_run-XX`
+    stem: String,
+    pub run: u32,
+    /// Mains frequency at the recording site, for `_eeg.json`
+    pub power_line_frequency: f64,
+    /// Task of the run, for `_eeg.json` and the dataset files
+    pub paradigm: Paradigm,
+}
+
+impl BidsRun {
+    /// The next unused run of the session. Trial numbers restart for every
+    /// class, so runs are numbered in recording order instead.
+    pub fn next(
+        root: &Path,
+        subject_id: &str,
+        session_id: &str,
+        power_line_frequency: f64,
+        paradigm: &Paradigm,
+    ) -> Result<Self> {
+        let (subject, session) = (label(subject_id), label(session_id));
+        if subject.is_empty() || session.is_empty() {
+            bail!("Subject '{}' and session '{}' need alphanumeric characters for BIDS", subject_id, session_id);
+        }
+        let dir = session_dir(root, subject_id, session_id);
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {:?}", dir))?;
+
+        let prefix = format!("sub-{}_ses-{}_task-{}_run-", subject, session, paradigm.task);
+        let mut last = 0;
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let run = name
+                .to_str()
+                .and_then(|n| n.strip_prefix(&prefix))
+                .and_then(|rest| rest.split('_').next())
+                .and_then(|digits| digits.parse::<u32>().ok());
+            last = last.max(run.unwrap_or(0));
+        }
+
+        let run = last + 1;
+        Ok(Self {
+            dir,
+            stem: format!("{}{:02}", prefix, run),
+            run,
+            power_line_frequency,
+            paradigm: paradigm.clone(),
+        })
+    }
+
+    /// `_eeg` data file with the given extension
+    pub fn data_path(&self, extension: &str) -> PathBuf {
+        self.dir.join(format!("{}_eeg.{}", self.stem, extension))
+    }
+
+    /// Trial metadata of the run (ignored by the validator)
+    pub fn metadata_path(&self) -> PathBuf {
+        self.dir.join(format!("{}_metadata.json", self.stem))
+    }
+
+    /// Host receive times of the run (ignored by the validator)
+    pub fn host_times_path(&self) -> PathBuf {
+        self.sidecar(HOST_TIMES_SUFFIX)
+    }
+
+    /// Delete the sidecars of a discarded run
+    pub fn remove_sidecars(&self) -> Result<()> {
+        for suffix in ["channels.tsv", "events.tsv", "eeg.json"] {
+            let path = self.sidecar(suffix);
+            if path.exists() {
+                fs::remove_file(&path).with_context(|| format!("Failed to remove {:?}", path))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sidecar(&self, suffix: &str) -> PathBuf {
+        self.dir.join(format!("{}_{}", self.stem, suffix))
+    }
+
+    /// Write `_channels.tsv`, `_events.tsv` and `_eeg.json` for the run as
+    /// read back from its data file
+    pub fn write_sidecars(&self, recording: &Recording) -> Result<()> {
+        let units = "µV";
+        let reference = eeg_reference(&recording.metadata);
+        let montage = recording.metadata.montage.as_ref();
+        let mut channels = String::from("name\ttype\tunits\treference\tstatus\tdescription\n");
+        for (i, name) in recording.channel_names.iter().enumerate() {
+            // Names from the montage file; the BIDS name is the position
+            let description = montage
+                .and_then(|m| m.channels.get(i))
+                .and_then(|a| a.name.as_deref())
+                .unwrap_or("n/a");
+            writeln!(channels, "{}\tEEG\t{}\t{}\tgood\t{}", tsv(name), units, tsv(&reference), tsv(description))?;
+        }
+        write(&self.sidecar("channels.tsv"), &channels)?;
+
+        let rate = recording.metadata.sample_rate.max(1) as f64;
+        let events = events::events_tsv(&events::events_of(recording), recording.metadata.sample_rate);
+        write(&self.sidecar("events.tsv"), &events)?;
+
+        let metadata = &recording.metadata;
+        let mut filters = serde_json::Map::new();
+        if let Some(eog) = &metadata.eog_regression {
+            filters.insert(
+                "EogRegression".to_string(),
+                serde_json::json!({
+                    "EogChannels": eog.eog_channels,
+                    "HighPass": "0.1 Hz",
+                }),
+            );
+        }
+        if let Some(asr) = &metadata.asr {
+            filters.insert(
+                "ArtifactSubspaceReconstruction".to_string(),
+                serde_json::json!({
+                    "HighPass": "0.5 Hz",
+                    "Cutoff": asr.cutoff,
+                }),
+            );
+        }
+        if let Some(filter) = &metadata.online_filter {
+            let mut online = serde_json::Map::new();
+            if let (Some(low), Some(high), Some(order)) = (filter.highpass_hz, filter.lowpass_hz, filter.order) {
+                online.insert("Bandpass".to_string(), format!("{}-{} Hz", low, high).into());
+                online.insert("Type".to_string(), format!("causal Butterworth, order {}", order).into());
+            }
+            if let Some(hz) = filter.notch_hz {
+                online.insert("Notch".to_string(), format!("{} Hz", hz).into());
+            }
+            if filter.common_average_reference {
+                online.insert("Reference".to_string(), "common average".into());
+            }
+            filters.insert("OnlineFilter".to_string(), online.into());
+        }
+        let software_filters = if filters.is_empty() {
+            serde_json::json!("n/a")
+        } else {
+            filters.into()
+        };
+        let sidecar = EegSidecar {
+            task_name: &self.paradigm.task,
+            task_description: &self.paradigm.description,
+            manufacturer: "OpenBCI",
+            sampling_frequency: rate,
+            eeg_reference: eeg_reference(metadata),
+            eeg_ground: metadata.electrode_config.ground.clone(),
+            eeg_placement_scheme: "10-20",
+            power_line_frequency: self.power_line_frequency,
+            software_filters,
+            software_versions: metadata.environment.as_ref().map(|env| {
+                let commit = env.git_commit.as_ref().map(|c| format!(" ({})", c)).unwrap_or_default();
+                format!("openbci_data_collector {}{}", env.collector_version, commit)
+            }),
+            eeg_channel_count: recording.num_channels(),
+            eog_channel_count: 0,
+            ecg_channel_count: 0,
+            emg_channel_count: 0,
+            misc_channel_count: 0,
+            trigger_channel_count: 0,
+            recording_duration: recording.samples.len() as f64 / rate,
+            recording_type: "continuous",
+        };
+        write(&self.sidecar("eeg.json"), &serde_json::to_string_pretty(&sidecar)?)?;
+
+        info!("Wrote BIDS sidecars for run {} to {:?}", self.run, self.dir);
+        Ok(())
+    }
+}
+
+/// Create the dataset-level files that are missing and list the subject in
+/// `participants.tsv`
+pub fn init_dataset(root: &Path, subject_id: &str, paradigm: &Paradigm) -> Result<()> {
+    let description = root.join("dataset_description.json");
+    if !description.exists() {
+        let json = serde_json::json!({
+            "Name": format!("OpenBCI {}", paradigm.title().to_lowercase()),
+            "BIDSVersion": BIDS_VERSION,
+            "DatasetType": "raw",
+            "GeneratedBy": [{
+                "Name": "openbci_data_collector",
+                "Version": env!("CARGO_PKG_VERSION"),
+            }],
+        });
+        write(&description, &serde_json::to_string_pretty(&json)?)?;
+    }
+
+    let readme = root.join("README");
+    if !readme.exists() {
+        write(
+            &readme,
+            &format!(
+                "{} EEG recorded with an OpenBCI board and openbci_data_collector.\n\
+                 Each run is one cued trial; its class is the first row of the run's events.tsv.\n",
+                paradigm.title()
+            ),
+        )?;
+    }
+
+    let events = root.join(format!("task-{}_events.json", paradigm.task));
+    if !events.exists() {
+        let json = serde_json::json!({
+            "trial_type": {
+                "Description": format!("{} class for the whole run, or the type of a marker \
+                                inserted during it: fixation, cue, stimulus, key, gap or clock_jump; \
+                                artifact_<kind> spans a synthetic artifact", paradigm.title())
+            },
+            "value": {
+                "Description": format!("Class ID used for training on the run-long event ({}); for \
+                                markers the cued class, the flashed stimulus, the key, the missing \
+                                samples or the clock jump in seconds", paradigm.class_ids())
+            },
+            "sample": { "Description": "Sample index of the onset, starting at 0" }
+        });
+        write(&events, &serde_json::to_string_pretty(&json)?)?;
+    }
+
+    let ignore = root.join(".bidsignore");
+    if !ignore.exists() {
+        write(&ignore, &(IGNORED.join("\n") + "\n"))?;
+    }
+
+    let participants = root.join("participants.tsv");
+    let mut table = match fs::read_to_string(&participants) {
+        Ok(text) => text,
+        Err(_) => String::from("participant_id\n"),
+    };
+    let participant = format!("sub-{}", label(subject_id));
+    if !table.lines().skip(1).any(|line| line.split('\t').next() == Some(participant.as_str())) {
+        if !table.ends_with('\n') {
+            table.push('\n');
+        }
+        table.push_str(&participant);
+        table.push('\n');
+        write(&participants, &table)?;
+    }
+    Ok(())
+}
+
+/// `_eeg.json` fields for a run
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct EegSidecar<'a> {
+    task_name: &'a str,
+    task_description: &'a str,
+    manufacturer: &'static str,
+    sampling_frequency: f64,
+    #[serde(rename = "EEGReference")]
+    eeg_reference: String,
+    #[serde(rename = "EEGGround")]
+    eeg_ground: String,
+    #[serde(rename = "EEGPlacementScheme")]
+    eeg_placement_scheme: &'static str,
+    power_line_frequency: f64,
+    software_filters: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    software_versions: Option<String>,
+    #[serde(rename = "EEGChannelCount")]
+    eeg_channel_count: usize,
+    #[serde(rename = "EOGChannelCount")]
+    eog_channel_count: usize,
+    #[serde(rename = "ECGChannelCount")]
+    ecg_channel_count: usize,
+    #[serde(rename = "EMGChannelCount")]
+    emg_channel_count: usize,
+    misc_channel_count: usize,
+    trigger_channel_count: usize,
+    recording_duration: f64,
+    recording_type: &'static str,
+}
+
+fn write(path: &Path, contents: &str) -> Result<()> {
+    fs::write(path, contents).with_context(|| format!("Failed to write {:?}", path))
+}
+
+/// Reference of the recorded signal: the common average if the collector
+/// re-referenced it, the electrode otherwise
+fn eeg_reference(metadata: &TrialMetadata) -> String {
+    if metadata.online_filter.as_ref().is_some_and(|f| f.common_average_reference) {
+        "average".to_string()
+    } else {
+        metadata.electrode_config.reference.clone()
+    }
+}
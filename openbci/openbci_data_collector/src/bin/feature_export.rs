@@ -0,0 +1,447 @@
+//! Feature-only export for sharing datasets without raw EEG.
+//!
+//! Writes per-trial log band powers, CSP log-variances and optional
+//! time-domain, wavelet energy, channel-pair connectivity and tangent-space
+//! covariance features, perturbed with the Laplace mechanism, plus a manifest stating
+//! that the package contains no raw time series.
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use eeg_dsp::{Band, BandPowerExtractor, Csp, Reference, TangentSpace, TimeFeature, TimeFeatures, Wavelet};
+use log::{info, warn};
+use openbci_data_collector::compute::{ComputeArgs, ComputeConfig};
+use openbci_data_collector::connectivity::{self, ConnectivityMetric};
+use ndarray::{Array2, ArrayView2};
+use openbci_data_collector::privacy::LaplaceMechanism;
+use openbci_data_collector::recording::{self, Recording};
+use openbci_data_collector::riemann::{self, Shrinkage};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+
+/// Command line arguments
+#[derive(Parser, Debug)]
+#[command(name = "OpenBCI Feature Export")]
+#[command(about = "Export derived features only (no raw EEG) for external collaborations", long_about = None)]
+struct Args {
+    /// Root of the recorded dataset
+    #[arg(short, long, default_value = "motor_imagery_data")]
+    data_dir: PathBuf,
+
+    /// Output package directory
+    #[arg(short, long, default_value = "feature_package")]
+    output_dir: PathBuf,
+
+    /// Frequency bands as name=low-high, comma separated
+    #[arg(long, value_delimiter = ',', default_value = "mu=8-13,beta=13-30")]
+    bands: Vec<Band>,
+
+    /// CSP filter pairs to export (0 disables CSP)
+    #[arg(long, default_value = "2")]
+    csp_pairs: usize,
+
+    /// Class IDs CSP is fitted on (defaults to the two lowest present)
+    #[arg(long, value_delimiter = ',')]
+    csp_classes: Vec<u8>,
+
+    /// Time-domain features per channel over the whole trial: activity,
+    /// mobility, complexity (Hjorth), line_length, zero_crossings, rms
+    #[arg(long, value_delimiter = ',')]
+    time_features: Vec<TimeFeature>,
+
+    /// Log energy of every wavelet detail level per channel, with this
+    /// wavelet: db4 or sym4
+    #[arg(long)]
+    wavelet_energy: Option<Wavelet>,
+
+    /// Detail levels of --wavelet-energy
+    #[arg(long, default_value = "5")]
+    wavelet_level: usize,
+
+    /// Channel-pair connectivity per band: plv, coherence (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    connectivity: Vec<ConnectivityMetric>,
+
+    /// Export Riemannian tangent-space features of each trial's covariance
+    #[arg(long)]
+    tangent_space: bool,
+
+    /// Band the covariance is estimated in, as name=low-high
+    #[arg(long, default_value = "mi=8-30")]
+    riemann_band: Band,
+
+    /// Covariance shrinkage for CSP and the tangent space: lw (Ledoit-Wolf)
+    /// or a fixed intensity in [0, 1]
+    #[arg(long, default_value = "lw")]
+    shrinkage: Shrinkage,
+
+    /// Privacy budget per trial; enables Laplace noise when set
+    #[arg(long)]
+    epsilon: Option<f64>,
+
+    /// Lower clipping bound for features when adding noise
+    #[arg(long, allow_hyphen_values = true)]
+    clip_low: Option<f64>,
+
+    /// Upper clipping bound for features when adding noise
+    #[arg(long, allow_hyphen_values = true)]
+    clip_high: Option<f64>,
+
+    /// Seed for the noise generator (random if omitted)
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Re-reference every trial first: car, linked_mastoids, average:A,B or
+    /// bipolar:A-B,C-D (default: the `rereference` of the trial's montage)
+    #[arg(long)]
+    rereference: Option<Reference>,
+
+    /// Also export sessions that failed QC
+    #[arg(long)]
+    include_failed_qc: bool,
+
+    #[command(flatten)]
+    compute: ComputeArgs,
+}
+
+/// Contents of the package `manifest.json`
+#[derive(Debug, Serialize)]
+struct PackageManifest {
+    package_type: &'static str,
+    contains_raw_timeseries: bool,
+    created: DateTime<Utc>,
+    num_trials: usize,
+    subjects: BTreeSet<String>,
+    features: Vec<String>,
+    bands: Vec<Band>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rereference: Option<Reference>,
+    csp: Option<CspInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    time_features: Vec<TimeFeature>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wavelet_energy: Option<WaveletInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    connectivity: Vec<ConnectivityMetric>,
+    tangent_space: Option<TangentSpaceInfo>,
+    privacy: Option<PrivacyInfo>,
+    compute: ComputeConfig,
+}
+
+#[derive(Debug, Serialize)]
+struct CspInfo {
+    classes: [u8; 2],
+    filter_pairs: usize,
+    shrinkage: Shrinkage,
+}
+
+#[derive(Debug, Serialize)]
+struct WaveletInfo {
+    wavelet: Wavelet,
+    levels: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct TangentSpaceInfo {
+    band: Band,
+    shrinkage: Shrinkage,
+    /// Riemannian mean of all exported trials, the tangent point
+    reference: Vec<Vec<f64>>,
+}
+
+#[derive(Debug, Serialize)]
+struct PrivacyInfo {
+    mechanism: &'static str,
+    #[serde(flatten)]
+    params: LaplaceMechanism,
+    noise_scale: f64,
+}
+
+/// Channel-major trial as a `channels x samples` array
+fn trial_array(data: &[Vec<f32>]) -> Array2<f32> {
+    let len = data.iter().map(Vec::len).min().unwrap_or(0);
+    Array2::from_shape_fn((data.len(), len), |(channel, t)| data[channel][t])
+}
+
+/// Log band powers of a whole trial, one window spanning it
+fn band_powers(trial: ArrayView2<f32>, sample_rate: f64, bands: &[Band]) -> Result<Vec<f64>> {
+    let mut extractor = BandPowerExtractor::new(bands.to_vec(), sample_rate, trial.nrows(), trial.ncols())?;
+    let mut values = vec![0.0; extractor.dimension()];
+    extractor.extract(trial, &mut values)?;
+    Ok(values)
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_default_env()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let args = Args::parse();
+    let compute = args.compute.apply()?;
+
+    let mechanism = match (args.epsilon, args.clip_low, args.clip_high) {
+        (None, _, _) => None,
+        (Some(epsilon), Some(clip_low), Some(clip_high)) if epsilon > 0.0 && clip_low < clip_high => {
+            Some(LaplaceMechanism { epsilon, clip_low, clip_high })
+        }
+        (Some(_), Some(_), Some(_)) => bail!("--epsilon must be positive and --clip-low below --clip-high"),
+        (Some(_), _, _) => bail!("--epsilon requires --clip-low and --clip-high to bound sensitivity"),
+    };
+    if args.wavelet_energy.is_some() && args.wavelet_level == 0 {
+        bail!("--wavelet-level must be at least 1");
+    }
+
+    let trials = recording::find_trials(&args.data_dir, args.include_failed_qc)?;
+    let recordings: Vec<Recording> = trials
+        .par_iter()
+        .filter_map(|path| match Recording::load(path) {
+            Ok(rec) if rec.metadata.artifact_injection.is_some() => {
+                warn!("Skipping {:?}: contains injected test artifacts", path);
+                None
+            }
+            Ok(rec) if !rec.samples.is_empty() => Some(rec),
+            Ok(_) => {
+                warn!("Skipping empty trial {:?}", path);
+                None
+            }
+            Err(e) => {
+                warn!("Skipping {:?}: {}", path, e);
+                None
+            }
+        })
+        .collect();
+    if recordings.is_empty() {
+        bail!("No usable trials found under {:?}", args.data_dir);
+    }
+
+    let (layouts, channel_data): (Vec<Vec<String>>, Vec<Vec<Vec<f32>>>) = recordings
+        .par_iter()
+        .map(|r| r.rereferenced(args.rereference.as_ref()))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .unzip();
+    let rereference = args.rereference.clone().or_else(|| {
+        recordings[0].metadata.montage.as_ref().and_then(|m| m.rereference.clone())
+    });
+
+    // Fit CSP on the selected pair of classes
+    let csp = if args.csp_pairs > 0 {
+        let classes: BTreeSet<u8> = recordings.iter().map(|r| r.metadata.class_id).collect();
+        let pair: Vec<u8> = if args.csp_classes.is_empty() {
+            classes.iter().take(2).copied().collect()
+        } else {
+            args.csp_classes.clone()
+        };
+        if pair.len() != 2 {
+            bail!("CSP needs exactly two classes, got {:?} (present: {:?})", pair, classes);
+        }
+
+        let of_class = |id: u8| -> Vec<Array2<f32>> {
+            recordings
+                .iter()
+                .zip(&channel_data)
+                .filter(|(r, _)| r.metadata.class_id == id)
+                .map(|(_, d)| trial_array(d))
+                .collect()
+        };
+        let (class_a, class_b) = (of_class(pair[0]), of_class(pair[1]));
+        let views_a: Vec<ArrayView2<f32>> = class_a.iter().map(|t| t.view()).collect();
+        let views_b: Vec<ArrayView2<f32>> = class_b.iter().map(|t| t.view()).collect();
+        let model = Csp::fit(&views_a, &views_b, args.csp_pairs, args.shrinkage.into())?;
+        info!("Fitted CSP on classes {} vs {}", pair[0], pair[1]);
+        Some((model, [pair[0], pair[1]]))
+    } else {
+        None
+    };
+
+    // Tangent point: Riemannian mean over every trial (labels unused)
+    let covariances: Vec<Array2<f64>> = if args.tangent_space {
+        recordings
+            .par_iter()
+            .zip(&channel_data)
+            .map(|(r, d)| riemann::band_covariance(d, r.metadata.effective_sample_rate(), &args.riemann_band, args.shrinkage))
+            .collect::<Result<_>>()?
+    } else {
+        Vec::new()
+    };
+    let tangent = if args.tangent_space {
+        let same_layout: Vec<Array2<f64>> = layouts
+            .iter()
+            .zip(&covariances)
+            .filter(|(layout, _)| **layout == layouts[0])
+            .map(|(_, c)| c.clone())
+            .collect();
+        Some(TangentSpace::fit(&same_layout)?)
+    } else {
+        None
+    };
+
+    let channel_names = &layouts[0];
+    // Checks the bands against the first trial before any work is done
+    let mut feature_names = BandPowerExtractor::new(
+        args.bands.clone(),
+        recordings[0].metadata.effective_sample_rate(),
+        channel_names.len(),
+        channel_data[0].iter().map(Vec::len).min().unwrap_or(0),
+    )?
+    .feature_names(channel_names);
+    if let Some((model, _)) = &csp {
+        feature_names.extend((0..model.components()).map(|k| format!("csp_{}", k)));
+    }
+    feature_names.extend(
+        channel_names
+            .iter()
+            .flat_map(|ch| args.time_features.iter().map(move |f| format!("{}_{}", ch, f))),
+    );
+    if let Some(wavelet) = args.wavelet_energy {
+        feature_names.extend(channel_names.iter().flat_map(|ch| {
+            (1..=args.wavelet_level).map(move |level| format!("{}_{}_d{}_logenergy", ch, wavelet, level))
+        }));
+    }
+    feature_names.extend(connectivity::feature_names(channel_names, &args.bands, &args.connectivity));
+    if let Some(tangent) = &tangent {
+        feature_names.extend((0..tangent.dimension()).map(|k| format!("ts_{}", k)));
+    }
+
+    let mut rng = match args.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    fs::create_dir_all(&args.output_dir)?;
+    let features_path = args.output_dir.join("features.csv");
+    let mut writer = csv::Writer::from_path(&features_path)?;
+    let mut header = vec![
+        "subject_id".to_string(),
+        "session_id".to_string(),
+        "trial_number".to_string(),
+        "class_id".to_string(),
+        "class_label".to_string(),
+    ];
+    header.extend(feature_names.iter().cloned());
+    writer.write_record(&header)?;
+
+    // Features are computed in parallel; noise is drawn in trial order so
+    // a seeded export stays reproducible
+    let trial_values: Vec<Option<Vec<f64>>> = recordings
+        .par_iter()
+        .zip(&channel_data)
+        .enumerate()
+        .map(|(index, (rec, data))| {
+            if layouts[index] != *channel_names {
+                warn!("Skipping {:?}: channel layout differs", rec.metadata_path);
+                return None;
+            }
+            let sample_rate = rec.metadata.effective_sample_rate();
+            let trial = trial_array(data);
+            let mut values = match band_powers(trial.view(), sample_rate, &args.bands) {
+                Ok(values) => values,
+                Err(e) => {
+                    warn!("Skipping {:?}: {}", rec.metadata_path, e);
+                    return None;
+                }
+            };
+            if let Some((model, _)) = &csp {
+                values.extend(model.features(trial.view()));
+            }
+            for channel in data {
+                let all = TimeFeatures::of(channel);
+                values.extend(args.time_features.iter().map(|&f| all.get(f)));
+            }
+            if let Some(wavelet) = args.wavelet_energy {
+                for channel in data {
+                    let decomposition = match eeg_dsp::wavedec(channel, wavelet, args.wavelet_level) {
+                        Ok(decomposition) => decomposition,
+                        Err(e) => {
+                            warn!("Skipping {:?}: {}", rec.metadata_path, e);
+                            return None;
+                        }
+                    };
+                    // Mean squared coefficient, comparable across levels
+                    values.extend(decomposition.details.iter().map(|detail| {
+                        let energy = detail.iter().map(|v| v * v).sum::<f64>() / detail.len() as f64;
+                        energy.max(f64::MIN_POSITIVE).log10()
+                    }));
+                }
+            }
+            if !args.connectivity.is_empty() {
+                match connectivity::connectivity(data, sample_rate, &args.bands, &args.connectivity) {
+                    Ok(pairs) => values.extend(pairs),
+                    Err(e) => {
+                        warn!("Skipping {:?}: {:#}", rec.metadata_path, e);
+                        return None;
+                    }
+                }
+            }
+            if let Some(tangent) = &tangent {
+                values.extend(tangent.transform(&covariances[index]));
+            }
+            Some(values)
+        })
+        .collect();
+
+    let mut exported = 0;
+    for (rec, values) in recordings.iter().zip(trial_values) {
+        let Some(mut values) = values else { continue };
+        if let Some(mechanism) = &mechanism {
+            mechanism.apply(&mut values, &mut rng);
+        }
+
+        let meta = &rec.metadata;
+        let mut record = vec![
+            meta.subject_id.clone(),
+            meta.session_id.clone(),
+            meta.trial_number.to_string(),
+            meta.class_id.to_string(),
+            meta.class_label.clone(),
+        ];
+        record.extend(values.iter().map(|v| v.to_string()));
+        writer.write_record(&record)?;
+        exported += 1;
+    }
+    writer.flush()?;
+    info!("Wrote features to {:?}", features_path);
+
+    let manifest = PackageManifest {
+        package_type: "derived_features_only",
+        contains_raw_timeseries: false,
+        created: Utc::now(),
+        num_trials: exported,
+        subjects: recordings.iter().map(|r| r.metadata.subject_id.clone()).collect(),
+        privacy: mechanism.map(|params| PrivacyInfo {
+            mechanism: "laplace",
+            noise_scale: params.scale(feature_names.len()),
+            params,
+        }),
+        features: feature_names,
+        bands: args.bands.clone(),
+        rereference,
+        csp: csp.map(|(_, classes)| CspInfo {
+            classes,
+            filter_pairs: args.csp_pairs,
+            shrinkage: args.shrinkage,
+        }),
+        time_features: args.time_features.clone(),
+        wavelet_energy: args.wavelet_energy.map(|wavelet| WaveletInfo {
+            wavelet,
+            levels: args.wavelet_level,
+        }),
+        connectivity: args.connectivity.clone(),
+        tangent_space: tangent.map(|t| TangentSpaceInfo {
+            band: args.riemann_band.clone(),
+            shrinkage: args.shrinkage,
+            reference: t.reference().rows().into_iter().map(|row| row.to_vec()).collect(),
+        }),
+        compute,
+    };
+    let manifest_path = args.output_dir.join("manifest.json");
+    fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?)?;
+    info!("Saved package manifest to {:?}", manifest_path);
+
+    Ok(())
+}
@@ -0,0 +1,215 @@
+//! Minimum distance to Riemannian mean (MDM) baseline.
+//!
+//! Cross-validates MDM on band-passed, shrunk trial covariances so the
+//! deep models have a classical reference point: stratified k-fold over
+//! every usable trial, reporting per-fold accuracy and a confusion matrix.
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use eeg_dsp::{Band, Mdm};
+use log::{info, warn};
+use ndarray::Array2;
+use openbci_data_collector::compute::{ComputeArgs, ComputeConfig};
+use openbci_data_collector::recording::{self, Recording};
+use openbci_data_collector::riemann::{self, Shrinkage};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Command line arguments
+#[derive(Parser, Debug)]
+#[command(name = "OpenBCI MDM Baseline")]
+#[command(about = "Cross-validate a Riemannian minimum-distance-to-mean classifier", long_about = None)]
+struct Args {
+    /// Root of the recorded dataset
+    #[arg(short, long, default_value = "motor_imagery_data")]
+    data_dir: PathBuf,
+
+    /// Class IDs to include (all present if omitted)
+    #[arg(long, value_delimiter = ',')]
+    classes: Vec<u8>,
+
+    /// Band the covariance is estimated in, as name=low-high
+    #[arg(long, default_value = "mi=8-30")]
+    band: Band,
+
+    /// Covariance shrinkage: lw (Ledoit-Wolf) or a fixed intensity in [0, 1]
+    #[arg(long, default_value = "lw")]
+    shrinkage: Shrinkage,
+
+    /// Cross-validation folds
+    #[arg(short, long, default_value = "5")]
+    folds: usize,
+
+    /// Seed for the fold assignment
+    #[arg(long, default_value = "0")]
+    seed: u64,
+
+    /// Also use sessions that failed QC
+    #[arg(long)]
+    include_failed_qc: bool,
+
+    /// Write the results as JSON to this file
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    #[command(flatten)]
+    compute: ComputeArgs,
+}
+
+#[derive(Debug, Serialize)]
+struct BaselineResults {
+    classifier: &'static str,
+    band: Band,
+    shrinkage: Shrinkage,
+    num_trials: usize,
+    classes: Vec<u8>,
+    folds: usize,
+    fold_accuracy: Vec<f64>,
+    mean_accuracy: f64,
+    std_accuracy: f64,
+    chance_level: f64,
+    /// `confusion[true][predicted]` in `classes` order
+    confusion: Vec<Vec<usize>>,
+    compute: ComputeConfig,
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_default_env()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let args = Args::parse();
+    let compute = args.compute.apply()?;
+    if args.folds < 2 {
+        bail!("--folds must be at least 2");
+    }
+
+    let paths = recording::find_trials(&args.data_dir, args.include_failed_qc)?;
+    let mut trials: Vec<Recording> = paths
+        .par_iter()
+        .filter_map(|path| match Recording::load(path) {
+            Ok(rec) if rec.metadata.artifact_injection.is_some() => {
+                warn!("Skipping {:?}: contains injected test artifacts", path);
+                None
+            }
+            Ok(rec) if rec.samples.is_empty() => {
+                warn!("Skipping empty trial {:?}", path);
+                None
+            }
+            Ok(rec) if !args.classes.is_empty() && !args.classes.contains(&rec.metadata.class_id) => None,
+            Ok(rec) => Some(rec),
+            Err(e) => {
+                warn!("Skipping {:?}: {}", path, e);
+                None
+            }
+        })
+        .collect();
+    let Some(layout) = trials.first().map(|r| r.channel_names.clone()) else {
+        bail!("No usable trials found under {:?}", args.data_dir);
+    };
+    trials.retain(|r| {
+        let same = r.channel_names == layout;
+        if !same {
+            warn!("Skipping {:?}: channel layout differs", r.metadata_path);
+        }
+        same
+    });
+
+    let covariances: Vec<Array2<f64>> = trials
+        .par_iter()
+        .map(|r| riemann::band_covariance(&r.channel_data(), r.metadata.effective_sample_rate(), &args.band, args.shrinkage))
+        .collect::<Result<_>>()?;
+    let labels: Vec<u8> = trials.iter().map(|r| r.metadata.class_id).collect();
+
+    // Stratified folds: shuffle each class, then deal its trials round-robin
+    let mut by_class: BTreeMap<u8, Vec<usize>> = BTreeMap::new();
+    for (i, &label) in labels.iter().enumerate() {
+        by_class.entry(label).or_default().push(i);
+    }
+    let classes: Vec<u8> = by_class.keys().copied().collect();
+    if classes.len() < 2 {
+        bail!("MDM needs trials from at least two classes, found {:?}", classes);
+    }
+    if let Some((class, members)) = by_class.iter().find(|(_, m)| m.len() < args.folds) {
+        bail!("Class {} has {} trials, fewer than {} folds", class, members.len(), args.folds);
+    }
+    let mut rng = StdRng::seed_from_u64(args.seed);
+    let mut fold_of = vec![0; labels.len()];
+    for members in by_class.values_mut() {
+        members.shuffle(&mut rng);
+        for (position, &trial) in members.iter().enumerate() {
+            fold_of[trial] = position % args.folds;
+        }
+    }
+
+    let class_index = |c: u8| classes.iter().position(|&k| k == c).unwrap_or(0);
+    let mut confusion = vec![vec![0usize; classes.len()]; classes.len()];
+    // Folds are fitted in parallel, then tallied in order
+    let fold_predictions: Vec<Vec<(usize, u8)>> = (0..args.folds)
+        .into_par_iter()
+        .map(|fold| -> Result<Vec<(usize, u8)>> {
+            let (train, test): (Vec<usize>, Vec<usize>) = (0..labels.len()).partition(|&i| fold_of[i] != fold);
+            let train_covs: Vec<Array2<f64>> = train.iter().map(|&i| covariances[i].clone()).collect();
+            let train_labels: Vec<u8> = train.iter().map(|&i| labels[i]).collect();
+            let model = Mdm::fit(&train_covs, &train_labels)?;
+            Ok(test.into_iter().map(|i| (i, model.predict(&covariances[i]))).collect())
+        })
+        .collect::<Result<_>>()?;
+
+    let mut fold_accuracy = Vec::with_capacity(args.folds);
+    for (fold, predictions) in fold_predictions.iter().enumerate() {
+        let mut correct = 0;
+        for &(i, predicted) in predictions {
+            confusion[class_index(labels[i])][class_index(predicted)] += 1;
+            if predicted == labels[i] {
+                correct += 1;
+            }
+        }
+        let accuracy = correct as f64 / predictions.len().max(1) as f64;
+        info!("Fold {}: {}/{} correct ({:.1}%)", fold + 1, correct, predictions.len(), 100.0 * accuracy);
+        fold_accuracy.push(accuracy);
+    }
+
+    let mean_accuracy = fold_accuracy.iter().sum::<f64>() / fold_accuracy.len() as f64;
+    let std_accuracy = (fold_accuracy.iter().map(|a| (a - mean_accuracy).powi(2)).sum::<f64>()
+        / fold_accuracy.len() as f64)
+        .sqrt();
+    let largest_class = by_class.values().map(Vec::len).max().unwrap_or(0);
+    let results = BaselineResults {
+        classifier: "mdm",
+        band: args.band.clone(),
+        shrinkage: args.shrinkage,
+        num_trials: labels.len(),
+        chance_level: largest_class as f64 / labels.len() as f64,
+        classes,
+        folds: args.folds,
+        fold_accuracy,
+        mean_accuracy,
+        std_accuracy,
+        confusion,
+        compute,
+    };
+    info!(
+        "MDM accuracy: {:.1}% +/- {:.1}% over {} trials (chance {:.1}%)",
+        100.0 * results.mean_accuracy,
+        100.0 * results.std_accuracy,
+        results.num_trials,
+        100.0 * results.chance_level
+    );
+
+    let json = serde_json::to_string_pretty(&results)?;
+    match &args.output {
+        Some(path) => {
+            fs::write(path, json)?;
+            info!("Saved results to {:?}", path);
+        }
+        None => println!("{}", json),
+    }
+    Ok(())
+}
@@ -0,0 +1,770 @@
+//! Closed-loop classification of a live EEG stream.
+//!
+//! Reads the WiFi shield (or a replayed trial, an LSL stream or the
+//! synthetic signal), filters every sample with the collector's online
+//! band-pass, notch and common average reference, keeps the last window
+//! the model was trained on (see `window::WindowScheduler`) and runs an
+//! exported ONNX model, or a CSP+LDA model from `openbci calibrate`, on it
+//! every hop, beside the stream. The
+//! probabilities are smoothed and voted into a decision (see
+//! `decision::DecisionMaker`), and each prediction is printed to stdout as
+//! one JSON line; logs go to stderr. With `--osc` the right- and left-hand
+//! probabilities, or the decision, also go to the robot's ESP32 as the
+//! `/neuropype` message it reads.
+//!
+//! A `.bcimodel` container (see `openbci model pack`) brings the filters
+//! its model was trained with, which replace unset ones and refuse
+//! conflicting ones, and the stream must run at the model's sample rate
+//! and name every one of its channels.
+//!
+//! An SSVEP paradigm with a `[decoder]` table is a model too (see
+//! `ssvep::SsvepDecoder`): each window's canonical correlations with the
+//! targets' flicker frequencies are its class probabilities.
+//!
+//! When the true class is known, from `--expected` or the class of a
+//! replayed trial, the session ends with its scores (see `eeg_metrics`):
+//! accuracy, balanced accuracy, kappa, AUC and the information transfer
+//! rate of one selection per window.
+//!
+//! With `--adapt-every` the model keeps up with the session (see
+//! `adapt::Adapter`): labelled blocks and confident predictions are pooled
+//! and the CSP+LDA model, or a linear readout on a network's features, is
+//! refitted to them on schedule and replaces the model the worker runs.
+//!
+//! Every session ends with the latency of each stage (see
+//! `latency::LatencyProfiler`): acquisition, filtering and windowing per
+//! sample, and the wait for the model, inference, output and the total per
+//! prediction, as percentiles. A replayed trial's cues are also timed to
+//! the first command of its class.
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, ValueEnum};
+use log::{info, warn};
+use openbci_data_collector::adapt::{self, AdaptArgs, Adapter, Readout};
+use openbci_data_collector::calibration::CalibrationModel;
+use openbci_data_collector::classifier::Classifier;
+use openbci_data_collector::config::{ExperimentConfig, MontageConfig};
+use openbci_data_collector::container::{self, ModelContainer};
+use openbci_data_collector::decision::{DecisionConfig, DecisionMaker};
+use openbci_data_collector::epoch;
+use openbci_data_collector::filter::{OnlineFilter, Passband};
+use openbci_data_collector::latency::{LatencyProfiler, Stage};
+use openbci_data_collector::montage::Montage;
+use openbci_data_collector::osc::OscSender;
+use openbci_data_collector::recording::Recording;
+use openbci_data_collector::source::{BoardSource, DataSource, ReplaySource, SyntheticSource};
+use openbci_data_collector::window::{Window, WindowScheduler};
+use eeg_metrics::Evaluation;
+use openbci_wifi_client::stream::unix_time;
+use openbci_wifi_client::{timestamp_seconds, BoardTransport, OpenBCIWiFi, StreamLimits, WiFiTransport};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+
+/// Where samples come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Source {
+    /// The WiFi shield's TCP stream
+    Wifi,
+    /// A recorded trial played back in real time
+    Replay,
+    /// Alpha rhythm plus noise, for trying a model without a board
+    Synthetic,
+    /// Another amplifier's LSL stream (needs the `lsl` feature)
+    Lsl,
+}
+
+/// Command line arguments
+#[derive(Parser, Debug)]
+#[command(name = "OpenBCI Online BCI")]
+#[command(about = "Classify a live EEG stream with an exported ONNX model, a calibration model or an SSVEP paradigm", long_about = None)]
+struct Args {
+    /// ONNX model or network from `openbci_train` (`.mpk`), its spec read
+    /// from the `.json` file next to it, the calibration_model.json of
+    /// `openbci calibrate`, a `.bcimodel` container of any of them, or an
+    /// SSVEP paradigm with a [decoder] table (`.toml` or `.yaml`)
+    #[arg(short, long)]
+    model: PathBuf,
+
+    /// Experiment config whose [board], [montage] and [filters] tables
+    /// supply defaults for the flags below
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Where samples come from (default: the config's transport, or wifi)
+    #[arg(long, value_enum)]
+    source: Option<Source>,
+
+    /// OpenBCI WiFi Shield IP address [default: 192.168.4.1]
+    #[arg(short, long)]
+    shield_ip: Option<String>,
+
+    /// Local IP address the shield streams to [default: 192.168.4.2]
+    #[arg(short, long)]
+    local_ip: Option<String>,
+
+    /// TCP port for data reception [default: 3000]
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Trial to play back with --source replay (CSV, BDF, ... or its
+    /// `*_metadata.json`)
+    #[arg(long)]
+    replay_file: Option<PathBuf>,
+
+    /// Playback speed relative to real time
+    #[arg(long, default_value = "1.0")]
+    replay_speed: f64,
+
+    /// LSL stream to classify with --source lsl
+    #[arg(long)]
+    stream_name: Option<String>,
+
+    /// Sample rate of the shield or synthetic signal in Hz [default: 250]
+    #[arg(long)]
+    sample_rate: Option<u32>,
+
+    /// Channels of the synthetic signal [default: the model's]
+    #[arg(long)]
+    channels: Option<usize>,
+
+    /// Montage file labelling the stream's channels, for matching the
+    /// model's channel_names
+    #[arg(long)]
+    montage: Option<PathBuf>,
+
+    /// Band-pass filter as low-high in Hz, e.g. 8-30
+    #[arg(long)]
+    bandpass: Option<Passband>,
+
+    /// Notch filter frequency in Hz (50 or 60)
+    #[arg(long)]
+    notch: Option<f64>,
+
+    /// Re-reference to the common average of all stream channels, after
+    /// --bandpass and --notch
+    #[arg(long)]
+    car: bool,
+
+    /// Seconds between predictions once the first window is full
+    #[arg(long, default_value = "0.25")]
+    hop: f64,
+
+    /// Longest break in the stream, in seconds, that is bridged by
+    /// interpolating lost samples or holding NaN values; a longer one
+    /// refills the window
+    #[arg(long, default_value = "0.1")]
+    max_gap: f64,
+
+    /// Weight of the newest window when smoothing probabilities across
+    /// windows, in (0, 1]; 1 for no smoothing
+    #[arg(long, default_value = "1.0")]
+    smoothing: f64,
+
+    /// Decide by majority over this many windows; idle when no class has
+    /// more than half of them
+    #[arg(long, default_value = "1")]
+    vote: usize,
+
+    /// Lowest smoothed probability a class is decided on; windows below it
+    /// count as idle
+    #[arg(long, default_value = "0.0")]
+    threshold: f64,
+
+    /// Seconds a new decision has to hold before it replaces the current
+    /// one
+    #[arg(long, default_value = "0.0")]
+    dwell: f64,
+
+    /// Name of the decision when no class is decided on
+    #[arg(long, default_value = "idle")]
+    idle_label: String,
+
+    /// Send the right- and left-hand probabilities of every prediction to
+    /// an OSC receiver over UDP, e.g. the robot's ESP32 at 192.168.4.50:9002
+    #[arg(long, value_name = "ADDR")]
+    osc: Option<SocketAddr>,
+
+    /// OSC address of the messages; the ESP32 firmware reads /neuropype
+    /// [default: /neuropype]
+    #[arg(long, value_name = "ADDRESS")]
+    osc_address: Option<String>,
+
+    /// Class sent first in each OSC message, as the right-hand probability
+    #[arg(long, default_value = "right_hand", value_name = "CLASS")]
+    osc_right: String,
+
+    /// Class sent second in each OSC message, as the left-hand probability
+    #[arg(long, default_value = "left_hand", value_name = "CLASS")]
+    osc_left: String,
+
+    /// Stop after this many seconds of samples (run until Ctrl+C or the
+    /// end of a replay if omitted)
+    #[arg(long)]
+    duration: Option<f64>,
+
+    /// Class the user performs throughout, to score a live session against;
+    /// a replay is scored against its trial's class from the first cue on
+    #[arg(long, value_name = "CLASS")]
+    expected: Option<String>,
+
+    /// Write the session's scores as JSON to this file
+    #[arg(long, value_name = "FILE")]
+    summary: Option<PathBuf>,
+
+    /// Write the latency percentiles of every stage as JSON to this file
+    #[arg(long, value_name = "FILE")]
+    latency: Option<PathBuf>,
+
+    #[command(flatten)]
+    adapt: AdaptArgs,
+}
+
+/// Settings from the command line, else the config, else the defaults
+struct Settings {
+    source: Source,
+    shield_ip: String,
+    local_ip: String,
+    port: u16,
+    sample_rate: u32,
+    montage: Option<Montage>,
+    bandpass: Option<Passband>,
+    notch: Option<f64>,
+    car: bool,
+    osc: Option<SocketAddr>,
+    osc_address: String,
+}
+
+impl Settings {
+    fn resolve(args: &Args) -> Result<Self> {
+        let config = match &args.config {
+            Some(path) => ExperimentConfig::load(path)?.0,
+            None => ExperimentConfig::default(),
+        };
+        let source = match (args.source, &config.board.transport) {
+            (Some(source), _) => source,
+            (None, Some(transport)) => Source::from_str(transport, true)
+                .map_err(|_| anyhow::anyhow!("Config transport '{}' cannot be classified online", transport))?,
+            (None, None) => Source::Wifi,
+        };
+        let montage = match (&args.montage, &config.montage) {
+            (Some(path), _) => Some(MontageConfig::load(path)?.montage()?),
+            (None, Some(montage)) => Some(montage.montage()?),
+            (None, None) => None,
+        };
+        Ok(Self {
+            source,
+            shield_ip: args.shield_ip.clone().or(config.board.shield_ip).unwrap_or_else(|| "192.168.4.1".to_string()),
+            local_ip: args.local_ip.clone().or(config.board.local_ip).unwrap_or_else(|| "192.168.4.2".to_string()),
+            port: args.port.or(config.board.port).unwrap_or(3000),
+            sample_rate: args.sample_rate.or(config.board.sample_rate).unwrap_or(250),
+            montage,
+            bandpass: args.bandpass.or(config.filters.bandpass.map(|[low, high]| Passband { low, high })),
+            notch: args.notch.or(config.filters.notch),
+            car: args.car || config.filters.car.unwrap_or(false),
+            osc: args.osc.or(config.output.osc),
+            osc_address: args.osc_address.clone().or(config.output.osc_address).unwrap_or_else(|| "/neuropype".to_string()),
+        })
+    }
+}
+
+/// An open stream, with the board to stop when done
+struct Stream {
+    source: Box<dyn DataSource>,
+    board: Option<Box<dyn BoardTransport>>,
+    sample_rate: u32,
+    /// Channel labels when the source knows them
+    labels: Option<Vec<String>>,
+    /// Samples are stamped by the board's clock, so lost ones show
+    board_clock: bool,
+    /// Class of a replayed trial and the row of its first cue
+    truth: Option<(String, u64)>,
+    /// Rows of a replayed trial's cues
+    cues: Vec<u64>,
+}
+
+async fn open_stream(args: &Args, settings: &Settings, model: &Classifier) -> Result<Stream> {
+    match settings.source {
+        Source::Wifi => {
+            let shield = OpenBCIWiFi::with_timeout(&settings.shield_ip, Duration::from_secs(30));
+            let channels = settings.montage.as_ref().map_or(model.channels(), |m| m.channels.len());
+            info!("Shield {}: streaming to {}:{}", settings.shield_ip, settings.local_ip, settings.port);
+            let board: Box<dyn BoardTransport> = Box::new(
+                WiFiTransport::new(shield, &settings.local_ip, settings.port, 4000)
+                    .with_limits(StreamLimits::for_rate(settings.sample_rate, channels)),
+            );
+            let source = BoardSource::open(board.as_ref()).await?;
+            Ok(Stream {
+                source: Box::new(source),
+                board: Some(board),
+                sample_rate: settings.sample_rate,
+                labels: None,
+                board_clock: true,
+                truth: None,
+                cues: Vec::new(),
+            })
+        }
+        Source::Replay => {
+            let path = args.replay_file.as_ref().context("--source replay needs --replay-file")?;
+            if args.replay_speed <= 0.0 {
+                bail!("--replay-speed must be positive, got {}", args.replay_speed);
+            }
+            let recording = Recording::open(path)?;
+            let montage = recording.metadata.montage.as_ref().filter(|m| m.channels.len() == recording.num_channels());
+            let labels = montage.map_or_else(|| recording.channel_names.clone(), Montage::labels);
+            let cues: Vec<u64> = epoch::cue_samples(&recording).into_iter().map(|cue| cue as u64).collect();
+            let truth = cues.first().map(|&cue| (recording.metadata.class_label.clone(), cue));
+            let source = ReplaySource::open(path, args.replay_speed)?;
+            let sample_rate = source.sample_rate();
+            Ok(Stream { source: Box::new(source), board: None, sample_rate, labels: Some(labels), board_clock: false, truth, cues })
+        }
+        Source::Synthetic => {
+            let channels = args.channels.unwrap_or(model.channels());
+            let source = SyntheticSource::new(channels, settings.sample_rate, 0);
+            Ok(Stream {
+                source: Box::new(source),
+                board: None,
+                sample_rate: settings.sample_rate,
+                labels: None,
+                board_clock: false,
+                truth: None,
+                cues: Vec::new(),
+            })
+        }
+        #[cfg(feature = "lsl")]
+        Source::Lsl => {
+            use openbci_data_collector::lsl;
+            let name = args.stream_name.clone().context("--source lsl needs --stream-name")?;
+            let stream = lsl::resolve(&name)?;
+            if stream.sample_rate <= 0.0 || stream.sample_rate.fract() != 0.0 {
+                bail!("LSL stream '{}' has no whole fixed sample rate ({} Hz)", name, stream.sample_rate);
+            }
+            let source = lsl::LslSource::open(&name)?;
+            Ok(Stream {
+                source: Box::new(source),
+                board: None,
+                sample_rate: stream.sample_rate as u32,
+                labels: None,
+                board_clock: false,
+                truth: None,
+                cues: Vec::new(),
+            })
+        }
+        #[cfg(not(feature = "lsl"))]
+        Source::Lsl => bail!("LSL support not compiled in, rebuild with --features lsl (needs liblsl)"),
+    }
+}
+
+/// One line of output
+#[derive(Debug, Serialize)]
+struct Prediction<'a> {
+    /// Time of the window's last sample, Unix seconds
+    timestamp: f64,
+    sample_id: u64,
+    class: &'a str,
+    probabilities: BTreeMap<&'a str, f32>,
+    /// Probabilities averaged across windows, with --smoothing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    smoothed: Option<BTreeMap<&'a str, f32>>,
+    /// Class after smoothing, threshold, vote and dwell, or the idle label
+    decision: &'a str,
+    /// Samples of the window bridged over gaps
+    #[serde(skip_serializing_if = "is_zero")]
+    repaired: usize,
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::Builder::from_default_env()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let args = Args::parse();
+    if args.hop <= 0.0 || args.max_gap < 0.0 || args.dwell < 0.0 {
+        bail!("--hop must be positive, --max-gap and --dwell not negative");
+    }
+    let mut settings = Settings::resolve(&args)?;
+    let container = match container::is_container(&args.model) {
+        true => Some(ModelContainer::open(&args.model)?),
+        false => None,
+    };
+    let model = match &container {
+        Some(container) => container.classifier(&args.model)?,
+        None => Classifier::open(&args.model)?,
+    };
+    if let Some(container) = &container {
+        let filters = container.metadata.filters.resolve(settings.bandpass, settings.notch, settings.car)?;
+        info!("Filters the model was trained with: {}", filters);
+        settings.bandpass = filters.passband();
+        settings.notch = filters.notch;
+        settings.car = filters.car;
+    }
+    let classes = model.classes();
+    info!(
+        "Model {:?}: {} x {} {}, classes {:?}",
+        args.model,
+        model.channels(),
+        model.window(),
+        model.describe(),
+        classes
+    );
+    if let Classifier::Calibrated(CalibrationModel { cross_validation: Some(cv), .. }) = &model {
+        info!("Calibrated to {:.1}% cross-validated accuracy", 100.0 * cv.mean_accuracy);
+    }
+
+    let mut adapter = Adapter::new(&args.adapt, &model)?;
+    if adapter.is_some() {
+        info!(
+            "Adapting every {} s from {}",
+            args.adapt.adapt_every.unwrap_or_default(),
+            match (&args.adapt.label_blocks, args.adapt.adapt_confidence) {
+                (Some(path), Some(p)) => format!("the blocks of {:?} and predictions of at least {}", path, p),
+                (Some(path), None) => format!("the blocks of {:?}", path),
+                (None, p) => format!("predictions of at least {}", p.unwrap_or_default()),
+            }
+        );
+    }
+
+    if classes.contains(&args.idle_label) {
+        bail!("--idle-label '{}' is one of the model's classes, pick another name", args.idle_label);
+    }
+
+    let mut osc = match settings.osc {
+        Some(target) => {
+            let index = |class: &str, flag: &str| {
+                classes
+                    .iter()
+                    .position(|c| c == class)
+                    .with_context(|| format!("{} '{}' is not one of the model's classes {:?}", flag, class, classes))
+            };
+            let pair = (index(&args.osc_right, "--osc-right")?, index(&args.osc_left, "--osc-left")?);
+            info!(
+                "Sending [{}, {}] probabilities to osc.udp://{}{}",
+                args.osc_right, args.osc_left, target, settings.osc_address
+            );
+            Some((OscSender::connect(target, &settings.osc_address)?, pair))
+        }
+        None => None,
+    };
+
+    let mut stream = open_stream(&args, &settings, &model).await?;
+    if let Some(rate) = model.sample_rate() {
+        if (rate - stream.sample_rate as f64).abs() > 1e-6 {
+            bail!("The model was trained at {} Hz but the stream runs at {} Hz", rate, stream.sample_rate);
+        }
+    }
+    // Windows starting at or after `from` are scored as class `truth`
+    let truth = match (&args.expected, &stream.truth) {
+        (Some(class), _) => Some((class.clone(), 0)),
+        (None, Some(truth)) => Some(truth.clone()),
+        (None, None) => None,
+    };
+    let truth = match truth {
+        Some((class, from)) => match classes.iter().position(|c| *c == class) {
+            Some(index) => Some((index, from)),
+            None if args.expected.is_some() => bail!("--expected '{}' is not one of the model's classes {:?}", class, classes),
+            None => {
+                warn!("The replayed trial's class '{}' is not one of the model's, it is not scored", class);
+                None
+            }
+        },
+        None => None,
+    };
+    if truth.is_none() && args.summary.is_some() {
+        bail!("--summary needs --expected, or a replayed trial of one of the model's classes");
+    }
+    let mut evaluation = truth.map(|_| Evaluation::new(classes.clone()));
+    let mut decided_counts: BTreeMap<String, usize> = BTreeMap::new();
+
+    let rate = stream.sample_rate as f64;
+    let hop = ((args.hop * rate).round() as usize).max(1);
+    let max_gap = (args.max_gap * rate).round() as usize;
+    let mut decisions = DecisionMaker::new(DecisionConfig {
+        smoothing: args.smoothing,
+        vote: args.vote,
+        threshold: args.threshold,
+        dwell: ((args.dwell * rate / hop as f64).ceil() as usize).max(1),
+    })?;
+    let max_samples = args.duration.map(|seconds| (seconds * rate).round() as u64);
+    info!(
+        "Classifying {} at {} Hz: {:.2} s windows every {} samples",
+        stream.source.describe(),
+        stream.sample_rate,
+        model.window() as f64 / rate,
+        hop
+    );
+
+    // Inference runs beside the stream; a window due while the model is
+    // still busy replaces the one waiting, so predictions stay current
+    // and the stream is never held up
+    let model = Arc::new(model);
+    let (windows, mut pending) = watch::channel::<Option<Window>>(None);
+    let (results, mut outcomes) = mpsc::unbounded_channel();
+    // Refits reach the worker as the readout it classifies with
+    let (readouts, readout) = watch::channel::<Option<Arc<Readout>>>(None);
+    let worker = {
+        let model = model.clone();
+        let adapting = adapter.is_some();
+        tokio::spawn(async move {
+            while pending.changed().await.is_ok() {
+                let Some(window) = pending.borrow_and_update().clone() else { continue };
+                let (model, readout) = (model.clone(), readout.borrow().clone());
+                let outcome = tokio::task::spawn_blocking(move || {
+                    let start = Instant::now();
+                    let outcome = match adapting {
+                        true => adapt::classify(&model, readout.as_deref(), window.data.view()),
+                        false => model.predict(window.data.view()).map(|probabilities| (probabilities, None)),
+                    };
+                    (window, outcome, start, start.elapsed())
+                });
+                let Ok(outcome) = outcome.await else { break };
+                if results.send(outcome).is_err() {
+                    break;
+                }
+            }
+        })
+    };
+
+    let mut windows = Some(windows);
+    let mut scheduler: Option<(usize, WindowScheduler)> = None;
+    // Sample ID the session starts at, for the seconds of label blocks
+    let mut first_sample = 0;
+    // A refit runs beside the stream, one at a time
+    let mut refitting: Option<tokio::task::JoinHandle<Result<Readout>>> = None;
+    let (mut received, mut predictions, mut slow) = (0u64, 0u64, 0u64);
+    let mut inference_time = Duration::ZERO;
+    let mut profiler = LatencyProfiler::new();
+    // The latest cue that came in, so a command answers it only from a
+    // window that saw it
+    let mut last_cue: Option<u64> = None;
+    let budget = Duration::from_secs_f64(hop as f64 / rate);
+    let mut stdout = std::io::stdout().lock();
+
+    loop {
+        tokio::select! {
+            sample = stream.source.next_sample(), if windows.is_some() => {
+                let Some(sample) = sample else {
+                    windows = None;
+                    continue;
+                };
+                let arrived = Instant::now();
+                if let Some(ms) = since_stamp(sample.timestamp) {
+                    profiler.record_ms(Stage::Acquisition, ms);
+                }
+                if scheduler.is_none() {
+                    // Channels are only known for sure once the first sample is in
+                    let count = sample.channels.len();
+                    let labels = stream
+                        .labels
+                        .clone()
+                        .filter(|labels| labels.len() == count)
+                        .or_else(|| settings.montage.as_ref().filter(|m| m.channels.len() == count).map(Montage::labels));
+                    // A container's channels are matched by name, never by position
+                    if labels.is_none() && container.is_some() {
+                        bail!(
+                            "The stream's {} channels are unlabelled but the model takes {:?}; give their montage with --montage or the config",
+                            count,
+                            model.channel_names()
+                        );
+                    }
+                    let labels = labels.unwrap_or_else(|| Montage::default_for(count).labels());
+                    let selected = model.select_channels(&labels)?;
+                    info!("Model channels from the stream's {:?}", selected.iter().map(|&c| &labels[c]).collect::<Vec<_>>());
+                    let filter = OnlineFilter::new(settings.bandpass, settings.notch, settings.car.then_some(count), stream.sample_rate, count)?;
+                    if let Some(filter) = &filter {
+                        let info = filter.info();
+                        info!(
+                            "Filtering online: band-pass {}, notch {}, common average reference {}",
+                            info.highpass_hz.zip(info.lowpass_hz).map_or("off".to_string(), |(low, high)| format!("{}-{} Hz", low, high)),
+                            info.notch_hz.map_or("off".to_string(), |hz| format!("{} Hz", hz)),
+                            if info.common_average_reference { "on" } else { "off" }
+                        );
+                    }
+                    let mut windowing = WindowScheduler::new(selected, model.window(), hop, max_gap, filter)?;
+                    if stream.board_clock {
+                        windowing = windowing.with_board_clock(stream.sample_rate);
+                    }
+                    scheduler = Some((count, windowing));
+                    first_sample = sample.sample_id;
+                }
+                let (count, windowing) = scheduler.as_mut().expect("set by the first sample");
+                if sample.channels.len() != *count {
+                    warn!("Skipping sample {} with {} channels instead of {}", sample.sample_id, sample.channels.len(), count);
+                    continue;
+                }
+                received += 1;
+                if stream.cues.binary_search(&sample.sample_id).is_ok() {
+                    profiler.cue(arrived);
+                    last_cue = Some(sample.sample_id);
+                }
+                let start = Instant::now();
+                let window = windowing.push(&sample, arrived);
+                let (pushed, filtering) = (start.elapsed(), windowing.filter_time());
+                profiler.record(Stage::Filtering, filtering);
+                profiler.record(Stage::Windowing, pushed.saturating_sub(filtering));
+                if let Some(window) = window {
+                    if let Some(windows) = &windows {
+                        windows.send_replace(Some(window));
+                    }
+                }
+                if max_samples.is_some_and(|max| received >= max) {
+                    windows = None;
+                }
+            }
+            outcome = outcomes.recv() => {
+                // The worker is done once the stream ended and it ran the last window
+                let Some((window, outcome, started, elapsed)) = outcome else { break };
+                let (probabilities, input) = outcome?;
+                profiler.record(Stage::Queue, started.saturating_duration_since(window.ready));
+                profiler.record(Stage::Inference, elapsed);
+                inference_time += elapsed;
+                if elapsed > budget {
+                    slow += 1;
+                }
+                predictions += 1;
+                let best = probabilities.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1)).map_or(0, |(i, _)| i);
+                let decision = decisions.update(&probabilities);
+                let decided = decision.class.map_or(args.idle_label.as_str(), |i| classes[i].as_str());
+                *decided_counts.entry(decided.to_string()).or_default() += 1;
+                if let (Some(evaluation), Some((index, from))) = (&mut evaluation, truth) {
+                    if window.sample_id + 1 >= from + model.window() as u64 {
+                        evaluation.add(index, &probabilities)?;
+                    }
+                }
+                if decision.changed {
+                    info!("Decision: {}", decided);
+                }
+                let probability_map = |values: &[f32]| classes.iter().map(String::as_str).zip(values.iter().copied()).collect();
+                let line = Prediction {
+                    timestamp: window.timestamp,
+                    sample_id: window.sample_id,
+                    class: &classes[best],
+                    probabilities: probability_map(&probabilities),
+                    smoothed: (args.smoothing < 1.0).then(|| probability_map(&decision.smoothed)),
+                    decision: decided,
+                    repaired: window.repaired,
+                };
+                writeln!(stdout, "{}", serde_json::to_string(&line)?)?;
+                stdout.flush()?;
+                if let Some((sender, (right, left))) = &mut osc {
+                    // Decided commands are sent as certainties, idle as neither
+                    // side, so the robot follows the decision and not each window
+                    if decisions.is_active() {
+                        let side = |class: usize| if decision.class == Some(class) { 1.0 } else { 0.0 };
+                        sender.prediction(side(*right), side(*left));
+                    } else {
+                        sender.prediction(probabilities[*right], probabilities[*left]);
+                    }
+                }
+                let sent = Instant::now();
+                profiler.record(Stage::Output, sent.saturating_duration_since(started + elapsed));
+                if let Some(ms) = since_stamp(window.timestamp) {
+                    profiler.record_ms(Stage::Total, ms);
+                }
+                let cued = truth.is_some_and(|(index, _)| decision.class == Some(index));
+                if cued && last_cue.is_some_and(|cue| window.sample_id >= cue) {
+                    profiler.command(sent);
+                }
+                if let (Some(adapter), Some(input)) = (&mut adapter, input) {
+                    let end = (window.sample_id + 1).saturating_sub(first_sample) as f64 / rate;
+                    adapter.add(input, end - model.window() as f64 / rate, end, &probabilities);
+                    if refitting.is_none() {
+                        if let Some(refit) = adapter.due(end) {
+                            let model = model.clone();
+                            refitting = Some(tokio::task::spawn_blocking(move || refit.run(&model)));
+                        }
+                    }
+                }
+            }
+            readout = async { refitting.as_mut().expect("guarded").await }, if refitting.is_some() => {
+                refitting = None;
+                let readout = Arc::new(readout??);
+                if let Some(adapter) = &mut adapter {
+                    adapter.refitted(readout.clone());
+                }
+                readouts.send_replace(Some(readout));
+            }
+            _ = tokio::signal::ctrl_c(), if windows.is_some() => {
+                info!("Interrupted");
+                windows = None;
+            }
+        }
+    }
+    worker.await?;
+
+    if let Some(board) = &stream.board {
+        if let Err(e) = board.stop_stream().await {
+            warn!("Failed to stop the stream: {}", e);
+        }
+    }
+    if let Some((sender, _)) = &osc {
+        let (sent, dropped) = sender.counts();
+        info!("OSC: {} messages sent, {} dropped", sent, dropped);
+    }
+    let stats = scheduler.map(|(_, windowing)| windowing.stats()).unwrap_or_default();
+    if slow > 0 {
+        warn!("{} of {} predictions took longer than the {} ms between them", slow, predictions, budget.as_millis());
+    }
+    if stats.windows > predictions {
+        warn!("{} windows were replaced by newer ones while the model was busy", stats.windows - predictions);
+    }
+    if stats.repaired > 0 || stats.restarts > 0 {
+        info!("{} samples bridged over gaps, the window refilled {} times after longer breaks", stats.repaired, stats.restarts);
+    }
+    info!(
+        "{} predictions from {} samples, {:.2} ms inference on average",
+        predictions,
+        received,
+        inference_time.as_secs_f64() * 1000.0 / predictions.max(1) as f64
+    );
+    if !decided_counts.is_empty() {
+        let counts: Vec<String> = decided_counts.iter().map(|(class, count)| format!("{} {}", class, count)).collect();
+        info!("Decisions: {}", counts.join(", "));
+    }
+    if let Some(adapter) = &adapter {
+        adapter.finish()?;
+    }
+    let latency = profiler.report();
+    for line in latency.to_string().lines() {
+        info!("{}", line);
+    }
+    if args.replay_speed != 1.0 && latency.cue_to_command_ms.is_some() {
+        warn!("Cue to command is in wall-clock time, at {}x replay speed", args.replay_speed);
+    }
+    if let Some(path) = &args.latency {
+        fs::write(path, serde_json::to_string_pretty(&latency)?)?;
+        info!("Saved the latencies to {:?}", path);
+    }
+    match evaluation {
+        Some(evaluation) if !evaluation.is_empty() => {
+            let report = evaluation.report(Some(model.window() as f64 / rate))?;
+            for line in report.to_string().lines() {
+                info!("{}", line);
+            }
+            if let Some(path) = &args.summary {
+                fs::write(path, serde_json::to_string_pretty(&report)?)?;
+                info!("Saved the scores to {:?}", path);
+            }
+        }
+        Some(_) => warn!("No windows after the cue were classified, nothing to score"),
+        None => {}
+    }
+    Ok(())
+}
+
+/// Milliseconds from a sample's timestamp to now, when the timestamp is
+/// wall-clock time; boards without a clock give implausible values
+fn since_stamp(timestamp: f64) -> Option<f64> {
+    let seconds = unix_time() - timestamp_seconds(timestamp);
+    (0.0..60.0).contains(&seconds).then_some(seconds * 1000.0)
+}
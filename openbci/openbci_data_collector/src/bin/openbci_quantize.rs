@@ -0,0 +1,136 @@
+//! Quantize a trained EEGNet to int8, to run on the robot's ESP32.
+//!
+//! The network from `openbci_train` gets activation scales from its
+//! training windows, then its validation windows, cut from the dataset as
+//! in training, are classified by both the float and the int8 network; the
+//! export is refused when accuracy drops by more than --max-drop. The int8
+//! network is written as a blob (`.bin`) for `include_bytes!` and
+//! `QuantizedEegNet::from_bytes`, and as a C header (`.h`) with the same
+//! reference inference in C.
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use eeg_metrics::Evaluation;
+use eeg_train::{QuantizedEegNet, TrainedModel};
+use log::{info, warn};
+use openbci_data_collector::compute::ComputeArgs;
+use openbci_data_collector::loader::{DatasetArgs, SplitArgs, Trials};
+use openbci_data_collector::montage;
+use std::fs;
+use std::path::PathBuf;
+
+/// Command line arguments
+#[derive(Parser, Debug)]
+#[command(name = "OpenBCI Quantize")]
+#[command(about = "Quantize a trained EEGNet to int8 and export it as a blob and C header", long_about = None)]
+struct Args {
+    /// Trained EEGNet weights (.mpk), with their spec next to them
+    #[arg(short, long)]
+    model: PathBuf,
+
+    #[command(flatten)]
+    data: DatasetArgs,
+
+    #[command(flatten)]
+    split: SplitArgs,
+
+    /// Seed the validation share was drawn from [default: the training seed]
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Seconds after each cue the window starts
+    #[arg(long, default_value = "0.5")]
+    window_start: f64,
+
+    /// Largest accepted drop in validation accuracy, in percentage points
+    #[arg(long, default_value = "2")]
+    max_drop: f64,
+
+    /// Output path, written with .bin and .h [default: the model's with _int8]
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Prefix of the names in the C header
+    #[arg(long, default_value = "eegnet")]
+    prefix: String,
+
+    #[command(flatten)]
+    compute: ComputeArgs,
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_default_env()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let args = Args::parse();
+    args.compute.apply()?;
+
+    let model = TrainedModel::open(&args.model).with_context(|| format!("Failed to load {:?}", args.model))?;
+    let spec = model.spec();
+    let trials = Trials::load(&args.data)?;
+    if trials.sample_rate() != spec.sample_rate {
+        bail!("Trials are at {} Hz, the network at {} Hz", trials.sample_rate(), spec.sample_rate);
+    }
+    let split = trials.split(&args.split, args.seed.unwrap_or(spec.training.seed))?;
+    let cut = |indices: &[usize]| {
+        trials.windows(indices, args.window_start, spec.window, |labels| montage::find_channels(&spec.channel_names, labels))
+    };
+    let (train, valid) = (cut(&split.train)?, cut(&split.val)?);
+    if valid.is_empty() {
+        bail!("No validation windows to check the int8 network on");
+    }
+
+    let quantized = QuantizedEegNet::new(&model, &train)?;
+    info!(
+        "Quantized {} parameters on {} training windows: {} bytes of int8 weights",
+        model.num_params(),
+        train.len(),
+        quantized.weight_bytes()
+    );
+
+    let (mut float, mut int8) = (Evaluation::new(spec.classes.clone()), Evaluation::new(spec.classes.clone()));
+    let (mut agree, mut largest) = (0, 0.0f32);
+    for (window, &label) in valid.windows.outer_iter().zip(&valid.labels) {
+        // Classes the network does not know are left out
+        let Some(truth) = spec.classes.iter().position(|c| *c == trials.classes[label]) else {
+            continue;
+        };
+        let (p_float, p_int8) = (model.predict(window)?, quantized.predict(window)?);
+        largest = p_float.iter().zip(&p_int8).fold(largest, |max, (a, b)| max.max((a - b).abs()));
+        if float.add(truth, &p_float)? == int8.add(truth, &p_int8)? {
+            agree += 1;
+        }
+    }
+    if float.is_empty() {
+        bail!("No validation windows of the network's classes {:?}", spec.classes);
+    }
+    let (float_accuracy, int8_accuracy) = (100.0 * float.confusion().accuracy(), 100.0 * int8.confusion().accuracy());
+    let drop = float_accuracy - int8_accuracy;
+    info!(
+        "{} validation windows: float {:.1}%, int8 {:.1}% ({:+.1} points); {:.1}% of predictions agree, probabilities differ by up to {:.3}",
+        float.len(),
+        float_accuracy,
+        int8_accuracy,
+        -drop,
+        100.0 * agree as f64 / float.len() as f64,
+        largest
+    );
+    if drop > args.max_drop {
+        bail!("Int8 accuracy is {:.1} points below float, more than --max-drop {}", drop, args.max_drop);
+    }
+    if drop > 0.0 {
+        warn!("Int8 accuracy is {:.1} points below float", drop);
+    }
+
+    let output = args.output.clone().unwrap_or_else(|| {
+        let stem = args.model.file_stem().unwrap_or_default().to_string_lossy();
+        args.model.with_file_name(format!("{}_int8", stem))
+    });
+    let (blob, header) = (output.with_extension("bin"), output.with_extension("h"));
+    let bytes = quantized.to_bytes();
+    fs::write(&blob, &bytes)?;
+    fs::write(&header, quantized.c_header(&args.prefix))?;
+    info!("Wrote {:?} ({} bytes) and {:?}", blob, bytes.len(), header);
+    Ok(())
+}
@@ -0,0 +1,306 @@
+//! Train EEGNet or the tiny transformer on a built dataset, without Python.
+//!
+//! The trials of a dataset manifest are loaded from the recording tree,
+//! filtered as online and cut into one window per cue through
+//! [`openbci_data_collector::loader`], exactly as `openbci benchmark` and
+//! `openbci_online_bci` see them. Training and validation trials come from
+//! a `dataset split` file, or a stratified random share is held out; test
+//! trials of the split file are scored once at the end, so both halves of
+//! the comparison run end to end from here to `benchmark`. The best network is saved as `.mpk` weights with a JSON spec
+//! next to them, which `--model` of `openbci_online_bci` and `benchmark`
+//! accept (build with `--features train`).
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, ValueEnum};
+use eeg_dsp::{Augmentation, Augmenter};
+use eeg_metrics::Evaluation;
+use eeg_train::{
+    Architecture, EegNetConfig, NetSpec, Standardization, TinyTransformerConfig, TrainedModel, TrainingConfig,
+};
+use log::{info, warn};
+use openbci_data_collector::compute::ComputeArgs;
+use openbci_data_collector::loader::{DatasetArgs, SplitArgs, Trials};
+use openbci_data_collector::montage;
+use std::path::{Path, PathBuf};
+
+/// Network to train
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Kind {
+    /// Temporal, depthwise spatial and separable convolutions
+    Eegnet,
+    /// Patch embedding, positional encoding and self-attention layers
+    Transformer,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Eegnet => "eegnet",
+            Self::Transformer => "transformer",
+        }
+    }
+}
+
+/// Command line arguments
+#[derive(Parser, Debug)]
+#[command(name = "OpenBCI Train")]
+#[command(about = "Train EEGNet or a tiny transformer on a built dataset with early stopping and checkpoints", long_about = None)]
+struct Args {
+    #[command(flatten)]
+    data: DatasetArgs,
+
+    #[command(flatten)]
+    split: SplitArgs,
+
+    /// Seconds after each cue the window starts
+    #[arg(long, default_value = "0.5")]
+    window_start: f64,
+
+    /// Window length in seconds; online windows are this long too
+    #[arg(long, default_value = "2.0")]
+    window_length: f64,
+
+    /// Network to train
+    #[arg(short, long, value_enum, default_value = "eegnet")]
+    architecture: Kind,
+
+    /// EEGNet temporal filters
+    #[arg(long, default_value = "8")]
+    f1: usize,
+
+    /// EEGNet spatial filters per temporal filter
+    #[arg(long, default_value = "2")]
+    depth: usize,
+
+    /// EEGNet pointwise filters of the separable convolution
+    #[arg(long, default_value = "16")]
+    f2: usize,
+
+    /// EEGNet temporal filter length in samples [default: half a second]
+    #[arg(long)]
+    kernel_length: Option<usize>,
+
+    /// Transformer patch length in samples, one token each [default: 100 ms]
+    #[arg(long)]
+    patch_length: Option<usize>,
+
+    /// Transformer token size
+    #[arg(long, default_value = "32")]
+    d_model: usize,
+
+    /// Transformer attention heads, dividing --d-model
+    #[arg(long, default_value = "4")]
+    heads: usize,
+
+    /// Transformer encoder layers; 2 to 4 keep it tiny
+    #[arg(long, default_value = "2")]
+    layers: usize,
+
+    /// Transformer feed-forward size
+    #[arg(long, default_value = "64")]
+    d_ff: usize,
+
+    /// [default: 0.25 for EEGNet, 0.1 for the transformer]
+    #[arg(long)]
+    dropout: Option<f64>,
+
+    /// Most epochs to train
+    #[arg(long, default_value = "300")]
+    epochs: usize,
+
+    #[arg(long, default_value = "32")]
+    batch_size: usize,
+
+    #[arg(long, default_value = "0.001")]
+    learning_rate: f64,
+
+    /// L2 penalty on the weights
+    #[arg(long, default_value = "0.0")]
+    weight_decay: f64,
+
+    /// Stop after this many epochs without a lower validation loss
+    #[arg(long, default_value = "30")]
+    patience: usize,
+
+    /// Augment the training windows afresh every epoch with these steps,
+    /// in order: noise=STD, shift=SECONDS, dropout=P, scale=SPREAD,
+    /// freq=HZ, mix=P
+    #[arg(long, value_delimiter = ',')]
+    augment: Vec<Augmentation>,
+
+    /// Seed of the weights, batch order, augmentation and validation share
+    #[arg(long, default_value = "0")]
+    seed: u64,
+
+    /// Weights of the best network; its spec goes next to it as .json
+    /// [default: eegnet.mpk or transformer.mpk]
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Directory for per-epoch checkpoints [default: next to --output]
+    #[arg(long)]
+    checkpoint_dir: Option<PathBuf>,
+
+    /// Continue the run checkpointed in --checkpoint-dir
+    #[arg(long)]
+    resume: bool,
+
+    #[command(flatten)]
+    compute: ComputeArgs,
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_default_env()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let args = Args::parse();
+    args.compute.apply()?;
+
+    let trials = Trials::load(&args.data)?;
+    let classes = trials.classes.clone();
+    if classes.len() < 2 {
+        bail!("Training needs at least two classes, the manifest has {:?}", classes);
+    }
+    let sample_rate = trials.sample_rate();
+    let channel_names = trials.channel_names();
+    let window = (args.window_length * sample_rate).round() as usize;
+
+    let split = trials.split(&args.split, args.seed)?;
+    let cut = |indices: &[usize]| {
+        trials.windows(indices, args.window_start, window, |labels| montage::find_channels(&channel_names, labels))
+    };
+    let (train, valid, test) = (cut(&split.train)?, cut(&split.val)?, cut(&split.test)?);
+    info!(
+        "{} classes {:?}, {} x {} windows at {} Hz: {} train, {} validation, {} test",
+        classes.len(),
+        classes,
+        channel_names.len(),
+        window,
+        sample_rate,
+        train.len(),
+        valid.len(),
+        test.len()
+    );
+    if valid.is_empty() {
+        warn!("No validation windows, early stopping watches the training loss");
+    }
+
+    let architecture = match args.architecture {
+        Kind::Eegnet => {
+            let defaults = EegNetConfig::new(channel_names.len(), window, classes.len(), sample_rate);
+            Architecture::EegNet(EegNetConfig {
+                f1: args.f1,
+                depth: args.depth,
+                f2: args.f2,
+                kernel_length: args.kernel_length.unwrap_or(defaults.kernel_length),
+                dropout: args.dropout.unwrap_or(defaults.dropout),
+                ..defaults
+            })
+        }
+        Kind::Transformer => {
+            let defaults = TinyTransformerConfig::new(channel_names.len(), window, classes.len(), sample_rate);
+            Architecture::Transformer(TinyTransformerConfig {
+                patch_length: args.patch_length.unwrap_or(defaults.patch_length),
+                d_model: args.d_model,
+                heads: args.heads,
+                layers: args.layers,
+                d_ff: args.d_ff,
+                dropout: args.dropout.unwrap_or(defaults.dropout),
+                ..defaults
+            })
+        }
+    };
+    architecture.check().map_err(anyhow::Error::msg)?;
+    let output = args.output.clone().unwrap_or_else(|| PathBuf::from(format!("{}.mpk", args.architecture.name())));
+    let config = TrainingConfig {
+        epochs: args.epochs,
+        batch_size: args.batch_size,
+        learning_rate: args.learning_rate,
+        weight_decay: args.weight_decay,
+        patience: args.patience,
+        seed: args.seed,
+        augmentation: match args.augment.is_empty() {
+            true => None,
+            false => Some(Augmenter::new(args.augment.clone(), sample_rate, args.seed)?),
+        },
+    };
+    if let Some(augmenter) = &config.augmentation {
+        let steps: Vec<String> = augmenter.steps.iter().map(|step| step.to_string()).collect();
+        info!("Augmenting training windows every epoch: {}", steps.join(", "));
+    }
+
+    let checkpoints = args.checkpoint_dir.clone().unwrap_or_else(|| checkpoint_dir(&output));
+    if checkpoints.exists() && !args.resume {
+        bail!("Checkpoints {:?} exist; pass --resume to continue that run or remove them", checkpoints);
+    }
+    if args.resume {
+        info!("Resuming from {:?}", checkpoints);
+    }
+
+    let standardization = Standardization::fit(train.windows.view());
+    let (net, history) = eeg_train::train(&architecture, &config, &standardization, &train, &valid, Some(&checkpoints), |epoch| {
+        let valid = epoch
+            .valid_loss
+            .zip(epoch.valid_accuracy)
+            .map_or(String::new(), |(loss, accuracy)| format!(", validation loss {:.4}, {:.1}%", loss, 100.0 * accuracy));
+        info!(
+            "Epoch {}: loss {:.4}, {:.1}%{} ({:.1} s)",
+            epoch.epoch,
+            epoch.train_loss,
+            100.0 * epoch.train_accuracy,
+            valid,
+            epoch.seconds
+        );
+    })?;
+    let best = history.best().context("No epoch was trained")?;
+    info!(
+        "Kept epoch {} of {}{}: {} loss {:.4}",
+        best.epoch,
+        history.epochs.len(),
+        if history.stopped_early { " (stopped early)" } else { "" },
+        if best.valid_loss.is_some() { "validation" } else { "training" },
+        best.monitored_loss()
+    );
+
+    let model = TrainedModel::new(
+        NetSpec {
+            architecture,
+            classes: classes.clone(),
+            channel_names,
+            sample_rate,
+            window,
+            standardization,
+            training: config,
+            history,
+        },
+        net,
+    );
+    model.save(&output)?;
+    info!(
+        "Saved {} {:?} ({} parameters) with its spec {:?}",
+        model.spec().architecture.name(),
+        output,
+        model.num_params(),
+        eeg_train::spec_path(&output)
+    );
+
+    if !test.is_empty() {
+        let mut evaluation = Evaluation::new(classes);
+        for (window, &label) in test.windows.outer_iter().zip(&test.labels) {
+            evaluation.add(label, &model.predict(window.view())?)?;
+        }
+        let report = evaluation.report(Some(args.window_length))?;
+        info!("Test windows:");
+        for line in report.to_string().lines() {
+            info!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// `eegnet_checkpoint/` for `eegnet.mpk`
+fn checkpoint_dir(output: &Path) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    output.with_file_name(format!("{}_checkpoint", stem))
+}
@@ -0,0 +1,119 @@
+//! Archive a recorded dataset as a single Parquet file.
+//!
+//! Trials are loaded a few at a time and written as row groups, so memory
+//! stays bounded however large the dataset is.
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use log::{info, warn};
+use openbci_data_collector::compute::ComputeArgs;
+use openbci_data_collector::metadata::TrialMetadata;
+use openbci_data_collector::parquet_sink::ParquetSink;
+use openbci_data_collector::recording::{self, Recording};
+use rayon::prelude::*;
+use std::fs;
+use std::path::PathBuf;
+
+/// Command line arguments
+#[derive(Parser, Debug)]
+#[command(name = "OpenBCI Parquet Export")]
+#[command(about = "Archive every trial of a dataset into one Parquet file", long_about = None)]
+struct Args {
+    /// Root of the recorded dataset
+    #[arg(short, long, default_value = "motor_imagery_data")]
+    data_dir: PathBuf,
+
+    /// Output Parquet file
+    #[arg(short, long, default_value = "motor_imagery_data.parquet")]
+    output: PathBuf,
+
+    /// Only these subjects (all if omitted)
+    #[arg(long, value_delimiter = ',')]
+    subjects: Vec<String>,
+
+    /// Zstandard compression level, 1-22
+    #[arg(long, default_value = "3")]
+    zstd_level: i32,
+
+    /// Also archive sessions that failed QC
+    #[arg(long)]
+    include_failed_qc: bool,
+
+    /// Also archive trials with injected test artifacts
+    #[arg(long)]
+    include_injected: bool,
+
+    #[command(flatten)]
+    compute: ComputeArgs,
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_default_env()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let args = Args::parse();
+    let compute = args.compute.apply()?;
+
+    // Channel columns come from the metadata alone, before any data is read
+    let mut trials = Vec::new();
+    let mut channels: Vec<String> = Vec::new();
+    for path in recording::find_trials(&args.data_dir, args.include_failed_qc)? {
+        let metadata: TrialMetadata = match fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| Ok(serde_json::from_str(&text)?))
+        {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                warn!("Skipping {:?}: {}", path, e);
+                continue;
+            }
+        };
+        if !args.subjects.is_empty() && !args.subjects.contains(&metadata.subject_id) {
+            continue;
+        }
+        if metadata.artifact_injection.is_some() && !args.include_injected {
+            warn!("Skipping {:?}: contains injected test artifacts", path);
+            continue;
+        }
+        for name in &metadata.electrode_config.channels {
+            if !channels.contains(name) {
+                channels.push(name.clone());
+            }
+        }
+        trials.push(path);
+    }
+    if trials.is_empty() {
+        bail!("No usable trials found under {:?}", args.data_dir);
+    }
+    info!("Archiving {} trials, channels: {}", trials.len(), channels.join(", "));
+
+    let mut sink = ParquetSink::create(&args.output, &channels, args.zstd_level)?;
+    let mut input_bytes = 0;
+    for batch in trials.chunks(compute.threads) {
+        let loaded: Vec<_> = batch.par_iter().map(|path| (path, Recording::load(path))).collect();
+        for (path, rec) in loaded {
+            match rec {
+                Ok(rec) if rec.samples.is_empty() => warn!("Skipping empty trial {:?}", path),
+                Ok(rec) => {
+                    sink.write_trial(&rec)?;
+                    input_bytes += fs::metadata(&rec.data_path).map_or(0, |m| m.len());
+                }
+                Err(e) => warn!("Skipping {:?}: {}", path, e),
+            }
+        }
+    }
+    let path = sink.path().to_path_buf();
+    let (written, rows) = sink.finish()?;
+
+    let output_bytes = fs::metadata(&path)?.len();
+    info!(
+        "Wrote {} trials ({} samples) to {:?}: {:.1} MB from {:.1} MB of trial files",
+        written,
+        rows,
+        path,
+        output_bytes as f64 / 1e6,
+        input_bytes as f64 / 1e6
+    );
+    Ok(())
+}
@@ -0,0 +1,437 @@
+//! Connecting to the board and what runs on it before recording: its
+//! settings, the signal check, the montage wizard, ASR and EOG
+//! calibration, and the `--dry-run` preflight.
+
+use crate::asr::AsrCalibration;
+use crate::bdf;
+use crate::disk;
+use crate::eog::EogCalibrationFile;
+use crate::metadata::BoardSettings;
+use crate::montage::Montage;
+use crate::multiboard::MultiTransport;
+use crate::preflight::{self, PreflightReport};
+use crate::record::{fits_channels, session_montage, RecordArgs, Transport};
+use crate::recording::Recording;
+use crate::signal_check::{self, SignalCheck, Verdict};
+use crate::simulate::{EegSimulator, SimulatedSource};
+use crate::sink::CYTON_DEFAULT_GAIN;
+use crate::source::{BoardSource, DataSource, ReplaySource, SourceTransport, SyntheticSource};
+use crate::wizard::MontageWizard;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use openbci_wifi_client::{
+    BoardCommands, BoardKind, BoardTransport, CapabilityError, OpenBCIWiFi, StreamLimits, WiFiTransport,
+};
+use std::time::{Duration, Instant};
+
+/// Open the board link selected by `--transport`, or the simulator
+pub async fn connect_board(args: &RecordArgs) -> Result<Box<dyn BoardTransport>> {
+    if let Some(info) = args.simulation() {
+        if !(0.0..=1.0).contains(&info.erd_depth) {
+            anyhow::bail!("--simulate-erd must be between 0 and 1, got {}", info.erd_depth);
+        }
+        let labels = session_montage(args)?.labels();
+        let (channels, rate) = (args.channels, args.sample_rate);
+        let class = args.class.clone().unwrap_or_else(|| "rest".to_string());
+        info!("Simulating {} EEG: {} channels at {} Hz, seed {}", class, channels, rate, info.seed);
+        let description = format!("simulated {} EEG", class);
+        return Ok(Box::new(SourceTransport::new("simulated", description, move || {
+            let simulator = EegSimulator::new(&labels, channels, rate, &class, &info);
+            Ok(Box::new(SimulatedSource::new(simulator)) as Box<dyn DataSource>)
+        })));
+    }
+
+    match args.transport {
+        Transport::Wifi if !args.shields.is_empty() => {
+            let stall_timeout = (args.stall_timeout > 0.0).then(|| Duration::from_secs_f64(args.stall_timeout));
+            let boards = args
+                .shields
+                .iter()
+                .enumerate()
+                .map(|(i, shield)| {
+                    let port = args.shield_port(i);
+                    info!("Shield {}: ip={}, port={}", i + 1, shield.host, port);
+                    let client = OpenBCIWiFi::with_timeout(&shield.host, Duration::from_secs(30));
+                    Box::new(
+                        WiFiTransport::new(client, &args.local_ip, port, 4000)
+                            .with_stall_timeout(stall_timeout)
+                            .with_burst(args.burst)
+                            .with_limits(StreamLimits::for_rate(args.sample_rate, args.board_channels())),
+                    ) as Box<dyn BoardTransport>
+                })
+                .collect();
+            Ok(Box::new(MultiTransport::new(boards, args.board_channels(), args.sample_rate)?))
+        }
+        Transport::Wifi => {
+            // Use a longer timeout than the default, POST /tcp can take a while
+            let shield = OpenBCIWiFi::with_timeout(&args.shield_ip, Duration::from_secs(30));
+            info!("Config: ip={}, port={}", args.local_ip, args.port);
+            // 4ms latency for 250Hz
+            let stall_timeout = (args.stall_timeout > 0.0).then(|| Duration::from_secs_f64(args.stall_timeout));
+            Ok(Box::new(
+                WiFiTransport::new(shield, &args.local_ip, args.port, 4000)
+                    .with_stall_timeout(stall_timeout)
+                    .with_burst(args.burst)
+                    .with_limits(StreamLimits::for_rate(args.sample_rate, args.channels)),
+            ))
+        }
+        Transport::Serial => Ok(Box::new(openbci_wifi_client::SerialTransport::open(&args.serial_port)?)),
+        #[cfg(feature = "ble")]
+        Transport::Ble => Ok(Box::new(openbci_wifi_client::BleTransport::connect(&args.ble_name).await?)),
+        #[cfg(not(feature = "ble"))]
+        Transport::Ble => anyhow::bail!("BLE support not compiled in, rebuild with --features ble"),
+        Transport::Replay => {
+            let path = args.replay_file.clone().context("--transport replay needs --replay-file")?;
+            if args.replay_speed <= 0.0 {
+                anyhow::bail!("--replay-speed must be positive, got {}", args.replay_speed);
+            }
+            let metadata = Recording::open(&path)?.metadata;
+            if metadata.num_channels != args.channels || metadata.sample_rate != args.sample_rate {
+                anyhow::bail!(
+                    "{:?} has {} channels at {} Hz, run with --channels {} --sample-rate {}",
+                    path,
+                    metadata.num_channels,
+                    metadata.sample_rate,
+                    metadata.num_channels,
+                    metadata.sample_rate
+                );
+            }
+            let speed = args.replay_speed;
+            let description = format!("replay {}", path.display());
+            Ok(Box::new(SourceTransport::new("replay", description, move || {
+                Ok(Box::new(ReplaySource::open(&path, speed)?) as Box<dyn DataSource>)
+            })))
+        }
+        Transport::Synthetic => {
+            let (channels, rate) = (args.channels, args.sample_rate);
+            let seed = args.synthetic_seed.unwrap_or_default();
+            info!("Synthetic signal: {} channels at {} Hz, seed {}", channels, rate, seed);
+            Ok(Box::new(SourceTransport::new("synthetic", "synthetic signal", move || {
+                Ok(Box::new(SyntheticSource::new(channels, rate, seed)) as Box<dyn DataSource>)
+            })))
+        }
+        #[cfg(feature = "lsl")]
+        Transport::Lsl => {
+            let name = args.stream_name.clone().context("--transport lsl needs --stream-name")?;
+            let stream = crate::lsl::resolve(&name)?;
+            if stream.sample_rate <= 0.0 {
+                anyhow::bail!("LSL stream '{}' has no fixed sample rate, only regular streams can be recorded", name);
+            }
+            if stream.channel_count != args.channels || stream.sample_rate != args.sample_rate as f64 {
+                anyhow::bail!(
+                    "LSL stream '{}' has {} channels at {} Hz, run with --channels {} --sample-rate {}",
+                    name,
+                    stream.channel_count,
+                    stream.sample_rate,
+                    stream.channel_count,
+                    stream.sample_rate
+                );
+            }
+            let description = format!("LSL stream '{}' ({}) on {}", name, stream.kind, stream.hostname);
+            Ok(Box::new(SourceTransport::new("lsl", description, move || {
+                Ok(Box::new(crate::lsl::LslSource::open(&name)?) as Box<dyn DataSource>)
+            })))
+        }
+        #[cfg(not(feature = "lsl"))]
+        Transport::Lsl => anyhow::bail!("LSL support not compiled in, rebuild with --features lsl (needs liblsl)"),
+    }
+}
+
+/// Refuse to record a configuration the attached board cannot deliver.
+/// Boards that cannot be queried are recorded as configured.
+pub async fn query_board(args: &RecordArgs, board: &dyn BoardTransport) -> Result<Option<BoardSettings>> {
+    let commands = match BoardCommands::detect(board).await {
+        Ok(commands) => commands,
+        Err(e) => {
+            warn!("Could not query board capabilities, skipping checks: {}", e);
+            return Ok(None);
+        }
+    };
+    let Some(caps) = commands.capabilities() else {
+        return Ok(None);
+    };
+    if args.channels > caps.num_channels as usize {
+        return Err(CapabilityError::NoSuchChannel {
+            board: caps.board,
+            requested: args.channels as u8,
+            available: caps.num_channels,
+        }
+        .into());
+    }
+    if !caps.sample_rates.contains(&args.sample_rate) {
+        return Err(CapabilityError::SampleRate {
+            board: caps.board,
+            link: caps.link,
+            requested: args.sample_rate,
+            supported: caps.sample_rates.clone(),
+        }
+        .into());
+    }
+
+    let scaling_gains = board.scaling_gains().await.unwrap_or_else(|e| {
+        warn!("Could not read the gains samples are scaled with: {}", e);
+        None
+    });
+    // The Ganglion has no ADS1299 to dump
+    let dump = match caps.board {
+        BoardKind::Ganglion => None,
+        _ => commands
+            .registers()
+            .await
+            .inspect_err(|e| warn!("Could not read the board registers: {}", e))
+            .ok(),
+    };
+    let settings = BoardSettings::new(caps, scaling_gains, dump.as_ref());
+    check_board_settings(args, &settings);
+    Ok(Some(settings))
+}
+
+/// Warn about board settings that make the recording other than it seems
+fn check_board_settings(args: &RecordArgs, settings: &BoardSettings) {
+    let gains: Vec<String> = settings
+        .gains(args.channels)
+        .iter()
+        .map(|gain| gain.map_or("?".to_string(), |g| format!("x{}", g)))
+        .collect();
+    info!("Board gains: {}", gains.join(" "));
+    for (i, channel) in settings.channels.iter().take(args.channels).enumerate() {
+        if channel.power_down {
+            warn!("Channel {} is powered down on the board and records no signal", channel.channel);
+        } else if channel.input_type != "normal" {
+            warn!("Channel {} measures '{}', not its electrode", channel.channel, channel.input_type);
+        }
+        if let Some(&scaled) = settings.scaling_gains.get(i).filter(|&&g| g != channel.gain) {
+            warn!(
+                "Channel {} runs at gain x{} but is scaled as x{}, so its values are {:.2} times too large",
+                channel.channel,
+                channel.gain,
+                scaled,
+                channel.gain as f64 / scaled as f64
+            );
+        }
+    }
+    if let Some(rate) = settings.adc_sample_rate.filter(|&rate| rate != args.sample_rate) {
+        warn!("The board ADC runs at {} Hz, not the {} Hz recorded as the sample rate", rate, args.sample_rate);
+    }
+}
+
+/// Run the montage wizard on a fresh stream and save the result into the
+/// session directory
+async fn run_montage_wizard(args: &RecordArgs, board: &dyn BoardTransport) -> Result<()> {
+    let session_dir = args.session_dir();
+    let initial = session_montage(args)?;
+
+    board.stop_stream().await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let mut stream = board.open_stream().await?;
+    let result = MontageWizard::new(&mut stream, args.channels).run(&initial).await;
+    board.stop_stream().await?;
+    drop(stream);
+
+    match result? {
+        Some(montage) => montage.save(&session_dir),
+        None => {
+            warn!("Montage wizard cancelled, session montage unchanged");
+            Ok(())
+        }
+    }
+}
+
+/// Score every channel before a trial; fails unless the signal is usable
+/// or --force is given
+pub async fn run_signal_check(args: &RecordArgs, board: &dyn BoardTransport) -> Result<SignalCheck> {
+    let labels = session_montage(args)?.column_names();
+    let check = signal_check::run(
+        board,
+        &labels,
+        args.sample_rate,
+        args.line_frequency,
+        bdf::ads1299_range_uv(CYTON_DEFAULT_GAIN),
+        args.qc_criteria()?.max_impedance_kohm,
+    )
+    .await?;
+    println!();
+    println!("{}", check.table());
+    println!();
+    if check.verdict() == Verdict::Fail {
+        if !args.force {
+            anyhow::bail!("Signal check failed, fix the electrodes marked FAIL or pass --force to record anyway");
+        }
+        warn!("Signal check failed, recording anyway (--force)");
+    }
+    Ok(check)
+}
+
+/// Record a clean baseline on a fresh stream and save the ASR calibration
+/// into the session directory
+async fn run_asr_calibration(args: &RecordArgs, board: &dyn BoardTransport, seconds: u64) -> Result<()> {
+    let channel_names = session_montage(args)?.column_names();
+
+    info!("ASR calibration: sit still and relax for {} seconds", seconds);
+    let baseline = record_calibration(args, board, seconds).await?;
+    let calibration = AsrCalibration::fit(&baseline, args.sample_rate, &channel_names)?;
+    calibration.save(&args.session_dir())
+}
+
+/// Record blinks and eye movements on a fresh stream and save the EOG
+/// regression into the session directory
+async fn run_eog_calibration(args: &RecordArgs, board: &dyn BoardTransport, seconds: u64) -> Result<()> {
+    let labels = session_montage(args)?.labels();
+
+    info!(
+        "EOG calibration: for {} seconds, blink every few seconds and look left, right, up and down now and then",
+        seconds
+    );
+    let data = record_calibration(args, board, seconds).await?;
+    let calibration = EogCalibrationFile::fit(&data, args.sample_rate, &labels, &args.eog_channels)?;
+    calibration.save(&args.session_dir())
+}
+
+/// `seconds` of every channel from a fresh stream, channel-major
+async fn record_calibration(args: &RecordArgs, board: &dyn BoardTransport, seconds: u64) -> Result<Vec<Vec<f32>>> {
+    board.stop_stream().await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let mut source = BoardSource::open(board).await?;
+    let mut baseline = vec![Vec::new(); args.channels];
+    let end = Instant::now() + Duration::from_secs(seconds);
+    while Instant::now() < end {
+        match tokio::time::timeout(Duration::from_millis(100), source.next_sample()).await {
+            Ok(Some(sample)) => {
+                for (channel, value) in baseline.iter_mut().zip(sample.channels) {
+                    channel.push(value);
+                }
+            }
+            Ok(None) => break,
+            Err(_) => {}
+        }
+    }
+    board.stop_stream().await?;
+    drop(source);
+    Ok(baseline)
+}
+
+/// --dry-run: check what recording depends on, print the report and fail
+/// on a no-go
+pub async fn run_dry_run(args: &RecordArgs) -> Result<()> {
+    let mut report = PreflightReport::default();
+
+    let boardless = matches!(args.transport, Transport::Synthetic | Transport::Replay) || args.simulate;
+    match connect_board(args).await {
+        Ok(board) if boardless => report.go("board", format!("no board to reach, recording {}", board.describe())),
+        Ok(board) => preflight_board(args, board.as_ref(), &mut report).await,
+        Err(e) => report.no_go("board", format!("{:#}", e)),
+    }
+
+    if matches!(args.transport, Transport::Wifi) && !args.simulate {
+        let ports: Vec<u16> = if args.shields.is_empty() {
+            vec![args.port]
+        } else {
+            (0..args.shields.len()).map(|i| args.shield_port(i)).collect()
+        };
+        for port in ports {
+            match preflight::check_port(port) {
+                Ok(()) => report.go("port", format!("{} is free for the shield stream", port)),
+                Err(e) => report.no_go("port", format!("{:#}", e)),
+            }
+        }
+    }
+
+    if !args.monitor {
+        let session_dir = args.session_dir();
+        match preflight::check_writable(&session_dir) {
+            Ok(dir) => {
+                report.go("output", format!("{:?} is writable", session_dir));
+                match disk::free_bytes(&dir) {
+                    Ok(free) if free < args.min_free_mb * 1024 * 1024 => report.warn(
+                        "disk",
+                        format!("{} MB free, below --min-free-mb {}", disk::megabytes(free), args.min_free_mb),
+                    ),
+                    Ok(free) => report.go("disk", format!("{} MB free", disk::megabytes(free))),
+                    Err(e) => report.warn("disk", format!("{:#}", e)),
+                }
+            }
+            Err(e) => report.no_go("output", format!("{:#}", e)),
+        }
+    }
+
+    match Montage::load(&args.session_dir()) {
+        Ok(Some(confirmed)) if !fits_channels(args, &confirmed) => report.warn(
+            "montage",
+            format!(
+                "the session's confirmed montage has {} channels, recording {}; defaults would be used",
+                confirmed.channels.len(),
+                args.channels
+            ),
+        ),
+        Ok(_) => {}
+        Err(e) => report.no_go("montage", format!("{:#}", e)),
+    }
+    match session_montage(args).and_then(|montage| montage.validate().map(|_| montage)) {
+        Ok(montage) if montage.channels.len() == args.channels => {
+            report.go("montage", montage.labels().join(", "))
+        }
+        Ok(montage) => report.no_go(
+            "montage",
+            format!("{} channels labelled, recording {}", montage.channels.len(), args.channels),
+        ),
+        Err(e) => report.no_go("montage", format!("{:#}", e)),
+    }
+
+    report.log();
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if !report.passed() {
+        anyhow::bail!("Pre-flight check failed, not ready to record");
+    }
+    Ok(())
+}
+
+/// Shield and board checks of --dry-run
+async fn preflight_board(args: &RecordArgs, board: &dyn BoardTransport, report: &mut PreflightReport) {
+    if matches!(args.transport, Transport::Wifi) {
+        match board.shield_info().await {
+            Ok(shields) => {
+                for shield in shields {
+                    let detail = format!(
+                        "{} at {}, firmware {}, {} bytes free heap",
+                        shield.name, shield.ip, shield.version, shield.heap
+                    );
+                    if shield.board_connected {
+                        report.go("shield", detail);
+                    } else {
+                        report.no_go("shield", format!("{}, but no board attached", detail));
+                    }
+                }
+            }
+            Err(e) => {
+                report.no_go("shield", format!("{} does not answer: {}", board.describe(), e.root_cause()));
+                return;
+            }
+        }
+    }
+    match query_board(args, board).await {
+        Ok(Some(settings)) => report.go(
+            "board",
+            format!("{} channels at {} Hz on a {}", args.channels, args.sample_rate, settings.board),
+        ),
+        Ok(None) => report.warn("board", "could not be queried, channel count and sample rate unchecked"),
+        Err(e) => report.no_go("board", format!("{:#}", e)),
+    }
+}
+
+/// The steps asked for before recording: --montage-wizard, then
+/// --eog-calibrate, then --asr-calibrate
+pub async fn calibrate(args: &RecordArgs) -> Result<()> {
+    if args.montage_wizard {
+        let board = connect_board(args).await?;
+        run_montage_wizard(args, board.as_ref()).await?;
+    }
+    if let Some(seconds) = args.eog_calibrate {
+        let board = connect_board(args).await?;
+        run_eog_calibration(args, board.as_ref(), seconds).await?;
+    }
+    if let Some(seconds) = args.asr_calibrate {
+        let board = connect_board(args).await?;
+        run_asr_calibration(args, board.as_ref(), seconds).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,404 @@
+//! BrainVision Core Data Format: a `.vhdr` header, a `.vmrk` marker file
+//! and a binary `.eeg` data file sharing one name.
+//!
+//! BrainVision Analyzer, FieldTrip, MNE and EEGLAB read the triplet
+//! natively. Data is multiplexed little-endian float32 in µV. Cue markers
+//! become `Stimulus` markers, injected artifacts `Bad Interval` markers
+//! spanning the segment, and discontinuities in the recording
+//! `New Segment` markers.
+
+use crate::augment::{ARTIFACT_END, ARTIFACT_START};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use log::info;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const HEADER_TITLE: &str = "Brain Vision Data Exchange Header File Version 1.0";
+const MARKER_TITLE: &str = "Brain Vision Data Exchange Marker File, Version 1.0";
+
+/// Marker types understood by Analyzer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkerType {
+    NewSegment,
+    Stimulus,
+    Comment,
+    BadInterval,
+}
+
+impl MarkerType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NewSegment => "New Segment",
+            Self::Stimulus => "Stimulus",
+            Self::Comment => "Comment",
+            Self::BadInterval => "Bad Interval",
+        }
+    }
+
+    fn parse(name: &str) -> Self {
+        match name {
+            "New Segment" => Self::NewSegment,
+            "Stimulus" => Self::Stimulus,
+            "Bad Interval" => Self::BadInterval,
+            _ => Self::Comment,
+        }
+    }
+}
+
+/// One `.vmrk` entry; `position` is 0-based here, 1-based on disk
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmrkMarker {
+    pub kind: MarkerType,
+    pub description: String,
+    pub position: u64,
+    pub length: u64,
+}
+
+/// Commas in names and descriptions are coded as `\1`
+fn escape(text: &str) -> String {
+    text.replace(',', "\\1")
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("\\1", ",")
+}
+
+/// Data (`.eeg`) and marker (`.vmrk`) files belonging to a header
+pub fn companion_paths(header_path: &Path) -> [PathBuf; 2] {
+    [header_path.with_extension("eeg"), header_path.with_extension("vmrk")]
+}
+
+/// Writes a triplet, streaming the data file and the markers at the end
+pub struct BrainVisionWriter {
+    header_path: PathBuf,
+    marker_path: PathBuf,
+    data_path: PathBuf,
+    data: BufWriter<File>,
+    num_channels: usize,
+    start: DateTime<Utc>,
+    markers: Vec<VmrkMarker>,
+    /// Injected artifacts still running, `(kind, start position)`
+    open_artifacts: Vec<(String, u64)>,
+    samples_written: u64,
+}
+
+impl BrainVisionWriter {
+    /// Create the triplet for `header_path` (`*.vhdr`); the data and marker
+    /// files take the same name with `.eeg` and `.vmrk`
+    pub fn create(
+        header_path: &Path,
+        labels: &[String],
+        reference: &str,
+        sample_rate: u32,
+        start: DateTime<Utc>,
+    ) -> Result<Self> {
+        if sample_rate == 0 || labels.is_empty() {
+            bail!("BrainVision needs a non-zero sample rate and at least one channel");
+        }
+        let [data_path, marker_path] = companion_paths(header_path);
+        let file_name = |path: &Path| path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+
+        let mut header = format!(
+            "{}\n; Data written by openbci_data_collector\n\n[Common Infos]\nCodepage=UTF-8\nDataFile={}\nMarkerFile={}\n\
+             DataFormat=BINARY\nDataOrientation=MULTIPLEXED\nNumberOfChannels={}\n; Sampling interval in microseconds\n\
+             SamplingInterval={}\n\n[Binary Infos]\nBinaryFormat=IEEE_FLOAT_32\n\n[Channel Infos]\n\
+             ; Ch<n>=<Name>,<Reference channel name>,<Resolution in \"Unit\">,<Unit>\n",
+            HEADER_TITLE,
+            file_name(&data_path),
+            file_name(&marker_path),
+            labels.len(),
+            1e6 / sample_rate as f64
+        );
+        for (i, label) in labels.iter().enumerate() {
+            header.push_str(&format!("Ch{}={},{},1,µV\n", i + 1, escape(label), escape(reference)));
+        }
+        fs::write(header_path, header).with_context(|| format!("Failed to write {:?}", header_path))?;
+
+        let data = File::create(&data_path).with_context(|| format!("Failed to create {:?}", data_path))?;
+        let writer = Self {
+            header_path: header_path.to_path_buf(),
+            marker_path,
+            data_path,
+            data: BufWriter::new(data),
+            num_channels: labels.len(),
+            start,
+            markers: Vec::new(),
+            open_artifacts: Vec::new(),
+            samples_written: 0,
+        };
+        // The marker file is rewritten on finish; this one keeps a crashed
+        // recording readable
+        writer.write_markers()?;
+        Ok(writer)
+    }
+
+    pub fn samples_written(&self) -> u64 {
+        self.samples_written
+    }
+
+    /// Attach a marker label to the next sample. Artifact injection
+    /// brackets become one `Bad Interval` over the segment.
+    pub fn annotate(&mut self, label: &str) {
+        let position = self.samples_written;
+        if let Some(kind) = label.strip_prefix(ARTIFACT_START) {
+            self.open_artifacts.push((kind.to_string(), position));
+        } else if let Some(kind) = label.strip_prefix(ARTIFACT_END) {
+            match self.open_artifacts.iter().position(|(k, _)| k == kind) {
+                Some(index) => {
+                    let (kind, start) = self.open_artifacts.remove(index);
+                    self.add_marker(MarkerType::BadInterval, &kind, start, position - start);
+                }
+                None => self.add_marker(MarkerType::Comment, label, position, 1),
+            }
+        } else {
+            self.add_marker(MarkerType::Stimulus, label, position, 1);
+        }
+    }
+
+    /// Mark a discontinuity before the sample at `position`
+    pub fn new_segment(&mut self, position: u64) {
+        if position > 0 && position < self.samples_written {
+            self.add_marker(MarkerType::NewSegment, "", position, 1);
+        }
+    }
+
+    fn add_marker(&mut self, kind: MarkerType, description: &str, position: u64, length: u64) {
+        self.markers.push(VmrkMarker {
+            kind,
+            description: description.to_string(),
+            position,
+            length: length.max(1),
+        });
+    }
+
+    /// Append one sample, values in nanovolts
+    pub fn write_sample(&mut self, values: &[f32]) -> Result<()> {
+        for ch in 0..self.num_channels {
+            let uv = values.get(ch).copied().unwrap_or(f32::NAN) / 1000.0;
+            self.data.write_all(&uv.to_le_bytes())?;
+        }
+        self.samples_written += 1;
+        Ok(())
+    }
+
+    fn write_markers(&self) -> Result<()> {
+        let mut markers = vec![VmrkMarker {
+            kind: MarkerType::NewSegment,
+            description: String::new(),
+            position: 0,
+            length: 1,
+        }];
+        markers.extend(self.markers.iter().cloned());
+        markers.sort_by_key(|m| m.position);
+
+        let data_file = self.data_path.file_name().unwrap_or_default().to_string_lossy();
+        let mut text = format!(
+            "{}\n\n[Common Infos]\nCodepage=UTF-8\nDataFile={}\n\n[Marker Infos]\n\
+             ; Mk<n>=<Type>,<Description>,<Position in data points>,<Size in data points>,\
+             <Channel number (0 = all)>,<Date (New Segment only)>\n",
+            MARKER_TITLE, data_file
+        );
+        for (i, marker) in markers.iter().enumerate() {
+            text.push_str(&format!(
+                "Mk{}={},{},{},{},0",
+                i + 1,
+                marker.kind.name(),
+                escape(&marker.description),
+                marker.position + 1,
+                marker.length
+            ));
+            if i == 0 {
+                text.push_str(&format!(",{}", self.start.format("%Y%m%d%H%M%S%6f")));
+            }
+            text.push('\n');
+        }
+        fs::write(&self.marker_path, text).with_context(|| format!("Failed to write {:?}", self.marker_path))
+    }
+
+    /// Close injected artifacts still running, flush the data and write the
+    /// final marker file
+    pub fn finish(mut self) -> Result<()> {
+        for (kind, start) in std::mem::take(&mut self.open_artifacts) {
+            let length = self.samples_written.saturating_sub(start);
+            self.add_marker(MarkerType::BadInterval, &kind, start, length);
+        }
+        self.data.flush()?;
+        self.write_markers()?;
+        info!(
+            "Finalized BrainVision file: {:?} ({} samples, {} markers)",
+            self.header_path,
+            self.samples_written,
+            self.markers.len()
+        );
+        Ok(())
+    }
+}
+
+/// Contents of a triplet
+#[derive(Debug)]
+pub struct BrainVisionData {
+    pub labels: Vec<String>,
+    pub sample_rate: f64,
+    /// Row-major samples in nanovolts, `samples[i][channel]`
+    pub samples: Vec<Vec<f32>>,
+    pub markers: Vec<VmrkMarker>,
+}
+
+/// `key=value` lines of one INI section
+fn section<'a>(text: &'a str, name: &str) -> Vec<(&'a str, &'a str)> {
+    let header = format!("[{}]", name);
+    text.lines()
+        .map(str::trim)
+        .skip_while(|line| !line.eq_ignore_ascii_case(&header))
+        .skip(1)
+        .take_while(|line| !line.starts_with('['))
+        .filter(|line| !line.starts_with(';'))
+        .filter_map(|line| line.split_once('='))
+        .collect()
+}
+
+/// Nanovolts per unit
+fn unit_scale(unit: &str) -> Result<f64> {
+    match unit.trim() {
+        "" | "µV" | "uV" | "μV" => Ok(1e3),
+        "nV" => Ok(1.0),
+        "mV" => Ok(1e6),
+        "V" => Ok(1e9),
+        other => bail!("unsupported unit '{}'", other),
+    }
+}
+
+impl BrainVisionData {
+    /// Read a triplet from its `.vhdr`. Multiplexed IEEE_FLOAT_32 and
+    /// INT_16 data are supported, which covers Analyzer's binary exports.
+    pub fn read(header_path: &Path) -> Result<Self> {
+        let header = fs::read_to_string(header_path).with_context(|| format!("Failed to read {:?}", header_path))?;
+        if !header.trim_start().starts_with("Brain Vision Data Exchange Header File") {
+            bail!("{:?} is not a BrainVision header", header_path);
+        }
+        let common = section(&header, "Common Infos");
+        let value = |key: &str| common.iter().find(|(k, _)| k.trim() == key).map(|(_, v)| v.trim());
+        if value("DataFormat").is_some_and(|f| f != "BINARY") {
+            bail!("{:?}: only BINARY data is supported", header_path);
+        }
+        if value("DataOrientation").is_some_and(|o| o != "MULTIPLEXED") {
+            bail!("{:?}: only MULTIPLEXED data is supported", header_path);
+        }
+        let num_channels: usize = value("NumberOfChannels")
+            .context("no NumberOfChannels")?
+            .parse()
+            .context("bad NumberOfChannels")?;
+        let interval_us: f64 = value("SamplingInterval")
+            .context("no SamplingInterval")?
+            .parse()
+            .context("bad SamplingInterval")?;
+        let dir = header_path.parent().unwrap_or(Path::new("."));
+        let data_path = dir.join(value("DataFile").context("no DataFile")?);
+        let marker_path = value("MarkerFile").map(|name| dir.join(name));
+
+        let binary = section(&header, "Binary Infos");
+        let format = binary
+            .iter()
+            .find(|(k, _)| k.trim() == "BinaryFormat")
+            .map_or("INT_16", |(_, v)| v.trim());
+        let width = match format {
+            "IEEE_FLOAT_32" => 4,
+            "INT_16" => 2,
+            other => bail!("{:?}: unsupported BinaryFormat {}", header_path, other),
+        };
+
+        let channel_infos = section(&header, "Channel Infos");
+        let mut labels = Vec::with_capacity(num_channels);
+        let mut scales = Vec::with_capacity(num_channels);
+        for ch in 1..=num_channels {
+            let key = format!("Ch{}", ch);
+            let info = channel_infos
+                .iter()
+                .find(|(k, _)| k.trim() == key)
+                .map(|(_, v)| *v)
+                .with_context(|| format!("{:?} has no {}", header_path, key))?;
+            let fields: Vec<&str> = info.split(',').collect();
+            labels.push(unescape(fields[0].trim()));
+            let resolution: f64 = fields.get(2).map(|r| r.trim()).filter(|r| !r.is_empty()).unwrap_or("1").parse()?;
+            scales.push(resolution * unit_scale(fields.get(3).copied().unwrap_or(""))?);
+        }
+
+        let bytes = fs::read(&data_path).with_context(|| format!("Failed to read {:?}", data_path))?;
+        let samples = bytes
+            .chunks_exact(num_channels.max(1) * width)
+            .map(|row| {
+                row.chunks_exact(width)
+                    .zip(&scales)
+                    .map(|(b, scale)| {
+                        let raw = if width == 4 {
+                            f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64
+                        } else {
+                            i16::from_le_bytes([b[0], b[1]]) as f64
+                        };
+                        (raw * scale) as f32
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let markers = match marker_path {
+            Some(path) if path.exists() => read_markers(&path)?,
+            _ => Vec::new(),
+        };
+        Ok(Self {
+            labels,
+            sample_rate: 1e6 / interval_us,
+            samples,
+            markers,
+        })
+    }
+
+    /// Marker labels per sample as the other formats record them: stimuli
+    /// by description, bad intervals as `artifact_start:`/`artifact_end:`
+    pub fn marker_labels(&self) -> Vec<String> {
+        let mut labels = vec![String::new(); self.samples.len()];
+        let mut put = |position: u64, label: String| {
+            if let Some(slot) = labels.get_mut(position as usize) {
+                if !slot.is_empty() {
+                    slot.push('|');
+                }
+                slot.push_str(&label);
+            }
+        };
+        for marker in &self.markers {
+            match marker.kind {
+                MarkerType::Stimulus | MarkerType::Comment => put(marker.position, marker.description.clone()),
+                MarkerType::BadInterval => {
+                    put(marker.position, format!("{}{}", ARTIFACT_START, marker.description));
+                    put(marker.position + marker.length, format!("{}{}", ARTIFACT_END, marker.description));
+                }
+                MarkerType::NewSegment => {}
+            }
+        }
+        labels
+    }
+}
+
+fn read_markers(path: &Path) -> Result<Vec<VmrkMarker>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+    let mut markers = Vec::new();
+    for (key, entry) in section(&text, "Marker Infos") {
+        if !key.trim().starts_with("Mk") {
+            continue;
+        }
+        let fields: Vec<&str> = entry.split(',').collect();
+        let [kind, description, position, ref rest @ ..] = fields[..] else {
+            bail!("{:?}: bad marker {}", path, key);
+        };
+        let position: u64 = position.trim().parse().with_context(|| format!("{:?}: bad marker {}", path, key))?;
+        markers.push(VmrkMarker {
+            kind: MarkerType::parse(kind.trim()),
+            description: unescape(description),
+            position: position.saturating_sub(1),
+            length: rest.first().and_then(|l| l.trim().parse().ok()).unwrap_or(1),
+        });
+    }
+    Ok(markers)
+}
@@ -0,0 +1,371 @@
+//! Subject-specific CSP+LDA models, fitted in the session they are used in.
+//!
+//! `openbci calibrate` records a short block of labelled trials and hands
+//! them to [`run`]: one window is cut per cue (`--window-start` seconds
+//! after it, `--window-length` long), band-passed on its own with the
+//! zero-phase filter of [`features::bandpass`], and two-class CSP (see
+//! `eeg_dsp::Csp`) with an LDA on its log-variance features is fitted to
+//! the windows. Stratified k-fold over the trials estimates how well that
+//! will do before the model is refitted on all of them and saved as JSON.
+//! With `--base` the spatial filters of an earlier model are kept and only
+//! the LDA is refitted, which needs fewer trials. `openbci_online_bci`
+//! loads the file in place of an ONNX model and filters each live window
+//! the same way, so it sees what the model was fitted on.
+
+use crate::epoch;
+use crate::features;
+use crate::recording::{self, Recording};
+use crate::riemann::Shrinkage;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use eeg_dsp::{Band, Classifier, Csp, Lda, Model};
+use log::{info, warn};
+use ndarray::{Array1, Array2, ArrayView2};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File name of the model in the session directory
+pub const MODEL_FILE: &str = "calibration_model.json";
+
+/// Options of fitting and cross-validating, shared by `openbci calibrate`
+/// and `openbci benchmark`
+#[derive(clap::Args, Debug, Clone)]
+pub struct FitArgs {
+    /// Seconds after each cue the window starts
+    #[arg(long, default_value = "0.5")]
+    pub window_start: f64,
+
+    /// CSP+LDA window length in seconds; online windows are this long too
+    #[arg(long, default_value = "2.0")]
+    pub window_length: f64,
+
+    /// Band the windows are filtered to, as name=low-high
+    #[arg(long, default_value = "mi=8-30")]
+    pub band: Band,
+
+    /// CSP filter pairs, the most discriminative of each class
+    #[arg(long, default_value = "3")]
+    pub csp_pairs: usize,
+
+    /// Covariance shrinkage of CSP and LDA: lw (Ledoit-Wolf) or a fixed
+    /// intensity in [0, 1]
+    #[arg(long, default_value = "lw")]
+    pub shrinkage: Shrinkage,
+
+    /// Cross-validation folds, fewer if a class has fewer trials; 1 for
+    /// none
+    #[arg(long, default_value = "5")]
+    pub folds: usize,
+
+    /// Seed for the fold assignment
+    #[arg(long, default_value = "0")]
+    pub seed: u64,
+}
+
+/// Cross-validated accuracy of the calibration block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrossValidation {
+    pub folds: usize,
+    pub fold_accuracy: Vec<f64>,
+    pub mean_accuracy: f64,
+    pub std_accuracy: f64,
+    pub chance_level: f64,
+    /// `confusion[true][predicted]` in class order
+    pub confusion: Vec<Vec<usize>>,
+}
+
+/// A fitted model as saved to disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibrationModel {
+    pub created: DateTime<Utc>,
+    pub subject_id: String,
+    pub session_id: String,
+    pub sample_rate: u32,
+    /// Montage labels of the channels, in the order the model takes them
+    pub channel_names: Vec<String>,
+    /// Class names in probability order, and their IDs
+    pub classes: Vec<String>,
+    pub class_ids: Vec<u8>,
+    pub band: Band,
+    /// Window length in samples
+    pub window: usize,
+    /// Windows per class the model was fitted on
+    pub windows_per_class: Vec<usize>,
+    /// Earlier model the CSP filters come from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<PathBuf>,
+    pub csp: Csp,
+    pub classifier: Model,
+    /// `None` when the block was too small to cross-validate, or with one
+    /// fold
+    pub cross_validation: Option<CrossValidation>,
+}
+
+/// One labelled window of a trial
+struct Example {
+    trial: usize,
+    label: u8,
+    /// `channels x samples`, band-passed
+    data: Array2<f32>,
+}
+
+impl CalibrationModel {
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+        serde_json::from_str(&json).with_context(|| format!("{:?} is not a calibration model", path))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?).with_context(|| format!("Failed to write {:?}", path))
+    }
+
+    /// Class probabilities of one unfiltered `channels x window` window
+    pub fn predict(&self, window: ArrayView2<f32>) -> Result<Vec<f32>> {
+        Ok(self.classify(self.filter(window)?.view()))
+    }
+
+    /// A window band-passed to the model's band, as it is fitted on
+    pub fn filter(&self, window: ArrayView2<f32>) -> Result<Array2<f32>> {
+        filter_window(window, self.sample_rate as f64, &self.band)
+    }
+
+    /// Class probabilities of one band-passed window
+    pub fn classify(&self, filtered: ArrayView2<f32>) -> Vec<f32> {
+        let features = self.csp.features(filtered);
+        self.classifier.probabilities(features.view()).iter().map(|&p| p as f32).collect()
+    }
+}
+
+/// Band-pass every row of a window on its own
+fn filter_window(window: ArrayView2<f32>, sample_rate: f64, band: &Band) -> Result<Array2<f32>> {
+    let mut filtered = Array2::zeros(window.raw_dim());
+    for (row, mut out) in window.rows().into_iter().zip(filtered.rows_mut()) {
+        let row: Vec<f32> = row.to_vec();
+        out.assign(&Array1::from(features::bandpass(&row, sample_rate, band)?));
+    }
+    Ok(filtered)
+}
+
+/// Trials of a calibration block, leaving out those replaced by a better
+/// take, with injected test artifacts or without samples
+pub fn load_trials(dir: &Path) -> Result<Vec<Recording>> {
+    let mut trials = Vec::new();
+    for path in recording::find_trials(dir, true)? {
+        match Recording::load(&path) {
+            Ok(rec) if rec.metadata.replaced_by.is_some() => info!("Skipping {:?}: replaced", path),
+            Ok(rec) if rec.metadata.artifact_injection.is_some() => {
+                warn!("Skipping {:?}: contains injected test artifacts", path)
+            }
+            Ok(rec) if rec.samples.is_empty() => warn!("Skipping empty trial {:?}", path),
+            Ok(rec) => trials.push(rec),
+            Err(e) => warn!("Skipping {:?}: {}", path, e),
+        }
+    }
+    Ok(trials)
+}
+
+/// Fit, cross-validate and save a model from the trials under `dir`, to
+/// `output` or [`MODEL_FILE`] in `dir`; returns it and where it was saved
+pub fn run(dir: &Path, args: &FitArgs, base: Option<&Path>, output: Option<&Path>) -> Result<(CalibrationModel, PathBuf)> {
+    let trials = load_trials(dir)?;
+    let model = fit(&trials.iter().collect::<Vec<_>>(), args, base)?;
+    let path = output.map_or_else(|| dir.join(MODEL_FILE), Path::to_path_buf);
+    model.save(&path)?;
+    info!("Saved the calibration model to {:?}", path);
+    Ok((model, path))
+}
+
+/// Unfiltered `channels x window` windows `start` seconds after each cue of
+/// a trial, of its `channels`; windows outside the trial or with missing
+/// samples are left out
+pub fn cue_windows(rec: &Recording, start: f64, window: usize, channels: &[usize]) -> Vec<Array2<f32>> {
+    let offset = (start * rec.metadata.sample_rate as f64).round() as isize;
+    let mut windows = Vec::new();
+    for cue in epoch::cue_samples(rec) {
+        let first = cue as isize + offset;
+        if first < 0 || first as usize + window > rec.samples.len() {
+            warn!("{:?}: the window of the cue at row {} is outside the trial", rec.metadata_path, cue);
+            continue;
+        }
+        let span = &rec.samples[first as usize..first as usize + window];
+        if span.iter().any(|row| channels.iter().any(|&c| !row[c].is_finite())) {
+            warn!("{:?}: the window of the cue at row {} has missing samples", rec.metadata_path, cue);
+            continue;
+        }
+        windows.push(Array2::from_shape_fn((channels.len(), window), |(c, t)| span[t][channels[c]]));
+    }
+    windows
+}
+
+/// Fit a model to the trials of one session, keeping the CSP filters of
+/// the `base` model when given
+pub fn fit(trials: &[&Recording], args: &FitArgs, base: Option<&Path>) -> Result<CalibrationModel> {
+    let Some(first) = trials.first() else {
+        bail!("No usable calibration trials");
+    };
+    let sample_rate = first.metadata.sample_rate;
+    let channel_names = first.labels();
+    let window = (args.window_length * sample_rate as f64).round() as usize;
+    let channels: Vec<usize> = (0..channel_names.len()).collect();
+    if window < 2 {
+        bail!("--window-length {} s is shorter than two samples", args.window_length);
+    }
+
+    let mut examples = Vec::new();
+    let mut names: BTreeMap<u8, String> = BTreeMap::new();
+    for (i, rec) in trials.iter().enumerate() {
+        if rec.metadata.sample_rate != sample_rate || rec.labels() != channel_names {
+            warn!("Skipping {:?}: sample rate or channels differ from the first trial", rec.metadata_path);
+            continue;
+        }
+        names.insert(rec.metadata.class_id, rec.metadata.class_label.clone());
+        for data in cue_windows(rec, args.window_start, window, &channels) {
+            examples.push(Example {
+                trial: i,
+                label: rec.metadata.class_id,
+                data: filter_window(data.view(), sample_rate as f64, &args.band)?,
+            });
+        }
+    }
+    if names.len() != 2 {
+        bail!("CSP separates two classes, the calibration block has {:?}", names.values().collect::<Vec<_>>());
+    }
+    let class_ids: Vec<u8> = names.keys().copied().collect();
+    let windows_per_class: Vec<usize> =
+        class_ids.iter().map(|&id| examples.iter().filter(|e| e.label == id).count()).collect();
+    if windows_per_class.iter().any(|&n| n < 2) {
+        bail!("Each class needs at least two windows, got {:?}", windows_per_class);
+    }
+
+    let base_csp = match base {
+        Some(path) => {
+            let base = CalibrationModel::load(path)?;
+            if base.channel_names != channel_names || base.sample_rate != sample_rate {
+                bail!(
+                    "Base model {:?} takes {:?} at {} Hz, the block has {:?} at {} Hz",
+                    path,
+                    base.channel_names,
+                    base.sample_rate,
+                    channel_names,
+                    sample_rate
+                );
+            }
+            info!("Keeping the CSP filters of {:?}", path);
+            Some(base.csp)
+        }
+        None => None,
+    };
+
+    let cross_validation = cross_validate(&examples, &class_ids, base_csp.as_ref(), args)?;
+    let all: Vec<&Example> = examples.iter().collect();
+    let (csp, classifier) = train(&all, &class_ids, base_csp.as_ref(), args)?;
+    let (subject_id, session_id) = (first.metadata.subject_id.clone(), first.metadata.session_id.clone());
+    Ok(CalibrationModel {
+        created: Utc::now(),
+        subject_id,
+        session_id,
+        sample_rate,
+        channel_names,
+        classes: names.into_values().collect(),
+        class_ids,
+        band: args.band.clone(),
+        window,
+        windows_per_class,
+        base: base.map(Path::to_path_buf),
+        csp,
+        classifier,
+        cross_validation,
+    })
+}
+
+/// CSP (unless kept from a base model) and LDA fitted to some windows
+fn train(examples: &[&Example], class_ids: &[u8], base: Option<&Csp>, args: &FitArgs) -> Result<(Csp, Model)> {
+    let shrinkage = args.shrinkage.into();
+    let csp = match base {
+        Some(csp) => csp.clone(),
+        None => {
+            let class = |id: u8| examples.iter().filter(|e| e.label == id).map(|e| e.data.view()).collect::<Vec<_>>();
+            Csp::fit(&class(class_ids[0]), &class(class_ids[1]), args.csp_pairs, shrinkage)?
+        }
+    };
+    let mut features = Array2::zeros((examples.len(), csp.components()));
+    for (example, mut row) in examples.iter().zip(features.rows_mut()) {
+        row.assign(&csp.features(example.data.view()));
+    }
+    let labels: Vec<u8> = examples.iter().map(|e| e.label).collect();
+    let lda = Lda::fit(features.view(), &labels, shrinkage)?;
+    Ok((csp, lda.into()))
+}
+
+/// Stratified k-fold over trials, so windows of one trial never end up on
+/// both sides
+fn cross_validate(
+    examples: &[Example],
+    class_ids: &[u8],
+    base: Option<&Csp>,
+    args: &FitArgs,
+) -> Result<Option<CrossValidation>> {
+    let mut by_class: BTreeMap<u8, Vec<usize>> = BTreeMap::new();
+    for example in examples {
+        let members = by_class.entry(example.label).or_default();
+        if !members.contains(&example.trial) {
+            members.push(example.trial);
+        }
+    }
+    if args.folds < 2 {
+        return Ok(None);
+    }
+    let fewest = by_class.values().map(Vec::len).min().unwrap_or(0);
+    let folds = args.folds.min(fewest);
+    if folds < 2 {
+        warn!("Too few trials per class to cross-validate");
+        return Ok(None);
+    }
+    if folds < args.folds {
+        warn!("A class has only {} trials, cross-validating with {} folds", fewest, folds);
+    }
+
+    let mut rng = StdRng::seed_from_u64(args.seed);
+    let mut fold_of: BTreeMap<usize, usize> = BTreeMap::new();
+    for members in by_class.values_mut() {
+        members.shuffle(&mut rng);
+        for (position, &trial) in members.iter().enumerate() {
+            fold_of.insert(trial, position % folds);
+        }
+    }
+
+    let class_index = |id: u8| class_ids.iter().position(|&c| c == id).unwrap_or(0);
+    let mut confusion = vec![vec![0usize; class_ids.len()]; class_ids.len()];
+    let mut fold_accuracy = Vec::with_capacity(folds);
+    for fold in 0..folds {
+        let (test, train_set): (Vec<&Example>, Vec<&Example>) = examples.iter().partition(|e| fold_of[&e.trial] == fold);
+        let (csp, classifier) = train(&train_set, class_ids, base, args)?;
+        let mut correct = 0;
+        for example in &test {
+            let predicted = classifier.predict(csp.features(example.data.view()).view());
+            confusion[class_index(example.label)][class_index(predicted)] += 1;
+            correct += (predicted == example.label) as usize;
+        }
+        let accuracy = correct as f64 / test.len().max(1) as f64;
+        info!("Fold {}: {}/{} windows correct ({:.1}%)", fold + 1, correct, test.len(), 100.0 * accuracy);
+        fold_accuracy.push(accuracy);
+    }
+
+    let mean_accuracy = fold_accuracy.iter().sum::<f64>() / folds as f64;
+    let std_accuracy =
+        (fold_accuracy.iter().map(|a| (a - mean_accuracy).powi(2)).sum::<f64>() / folds as f64).sqrt();
+    let largest = confusion.iter().map(|row| row.iter().sum::<usize>()).max().unwrap_or(0);
+    Ok(Some(CrossValidation {
+        folds,
+        fold_accuracy,
+        mean_accuracy,
+        std_accuracy,
+        chance_level: largest as f64 / examples.len() as f64,
+        confusion,
+    }))
+}
@@ -0,0 +1,205 @@
+//! SHA-256 checksums of recorded files, and `openbci verify`.
+//!
+//! Each trial's metadata lists the checksums of its data files, taken when
+//! the trial is finalized, and `session_manifest.json` lists those of every
+//! file in the session directory. Verifying hashes the files again, so a
+//! dataset copied between lab machines and cloud storage can be checked
+//! against what was recorded.
+
+use crate::compute::ComputeArgs;
+use crate::metadata::TrialMetadata;
+use crate::qc::{SessionManifest, SESSION_MANIFEST};
+use crate::recording;
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// Options of `openbci verify`
+#[derive(Args, Debug, Clone)]
+pub struct VerifyArgs {
+    /// Trials to verify: metadata JSON, a session manifest, or a directory
+    /// to verify every trial and session under
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    #[command(flatten)]
+    pub compute: ComputeArgs,
+}
+
+/// Checksum of one file, named relative to the metadata or manifest
+/// listing it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileChecksum {
+    pub file: String,
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+impl FileChecksum {
+    /// Hash `dir/file`
+    pub fn of(dir: &Path, file: &str) -> Result<Self> {
+        let path = dir.join(file);
+        let mut reader = File::open(&path).with_context(|| format!("Failed to open {:?}", path))?;
+        let mut hasher = Sha256::new();
+        let bytes = std::io::copy(&mut reader, &mut hasher).with_context(|| format!("Failed to read {:?}", path))?;
+        Ok(Self {
+            file: file.to_string(),
+            bytes,
+            sha256: format!("{:x}", hasher.finalize()),
+        })
+    }
+}
+
+/// Checksums of a trial's data files in `dir`
+pub fn data_files(dir: &Path, metadata: &TrialMetadata) -> Result<Vec<FileChecksum>> {
+    metadata.all_data_files().into_iter().map(|file| FileChecksum::of(dir, file)).collect()
+}
+
+/// Checksums of every file in a session directory but its manifest. Data
+/// files keep the checksum their trial recorded if their size still
+/// matches, so long recordings are not read again after every trial.
+pub fn session_files(session_dir: &Path, trials: &[TrialMetadata]) -> Result<Vec<FileChecksum>> {
+    let recorded: BTreeMap<&str, &FileChecksum> = trials
+        .iter()
+        .flat_map(|trial| &trial.checksums)
+        .map(|checksum| (checksum.file.as_str(), checksum))
+        .collect();
+    let mut files = Vec::new();
+    for entry in fs::read_dir(session_dir).with_context(|| format!("Failed to read {:?}", session_dir))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !entry.file_type()?.is_file() || name == SESSION_MANIFEST || name.starts_with('.') {
+            continue;
+        }
+        let bytes = entry.metadata()?.len();
+        match recorded.get(name.as_str()).filter(|checksum| checksum.bytes == bytes) {
+            Some(&checksum) => files.push(checksum.clone()),
+            None => files.push(FileChecksum::of(session_dir, &name)?),
+        }
+    }
+    files.sort_by(|a, b| a.file.cmp(&b.file));
+    Ok(files)
+}
+
+/// Outcome of `openbci verify`
+#[derive(Debug, Default)]
+pub struct Verification {
+    /// Files whose checksum matched
+    pub verified: usize,
+    /// What did not: missing, changed or unreadable files, and listings
+    /// that disagree
+    pub failures: Vec<String>,
+}
+
+/// Checksums listed for each file, from trial metadata and manifests
+#[derive(Default)]
+struct Expected {
+    files: BTreeMap<PathBuf, FileChecksum>,
+    failures: Vec<String>,
+}
+
+impl Expected {
+    fn add(&mut self, dir: &Path, checksums: &[FileChecksum], source: &Path) {
+        for checksum in checksums {
+            let path = dir.join(&checksum.file);
+            match self.files.get(&path) {
+                Some(listed) if listed.sha256 != checksum.sha256 => {
+                    self.failures.push(format!("{:?}: {:?} lists another checksum", path, source));
+                }
+                Some(_) => {}
+                None => {
+                    self.files.insert(path, checksum.clone());
+                }
+            }
+        }
+    }
+
+    fn add_listing(&mut self, path: &Path) {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let listed = if path.file_name().is_some_and(|name| name == SESSION_MANIFEST) {
+            SessionManifest::load(dir).map(|manifest| manifest.files)
+        } else {
+            recording::read_metadata(path).map(|metadata| metadata.checksums)
+        };
+        match listed {
+            Ok(checksums) => self.add(dir, &checksums, path),
+            Err(e) => self.failures.push(format!("{:#}", e)),
+        }
+    }
+
+    fn add_tree(&mut self, root: &Path) -> Result<()> {
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries: Vec<PathBuf> = fs::read_dir(&dir)
+                .with_context(|| format!("Failed to read {:?}", dir))?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<std::io::Result<_>>()?;
+            entries.sort();
+            for path in entries {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if name == SESSION_MANIFEST || name.ends_with("_metadata.json") {
+                    self.add_listing(&path);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hash every file the metadata and manifests under `args.paths` list and
+/// compare them with the recorded checksums
+pub fn run(args: &VerifyArgs) -> Result<Verification> {
+    args.compute.apply()?;
+    let mut expected = Expected::default();
+    for path in &args.paths {
+        if path.is_dir() {
+            expected.add_tree(path)?;
+        } else {
+            expected.add_listing(path);
+        }
+    }
+    if expected.files.is_empty() && expected.failures.is_empty() {
+        bail!("No checksums found under {:?}", args.paths);
+    }
+    info!("Verifying {} files", expected.files.len());
+
+    let mut verification = Verification {
+        failures: expected.failures,
+        ..Default::default()
+    };
+    let checked: Vec<Option<String>> = expected
+        .files
+        .par_iter()
+        .map(|(path, listed)| {
+            let dir = path.parent().unwrap_or(Path::new(""));
+            match FileChecksum::of(dir, &listed.file) {
+                Err(e) => Some(format!("{:#}", e)),
+                Ok(actual) if actual.bytes != listed.bytes => {
+                    Some(format!("{:?}: {} bytes, {} recorded", path, actual.bytes, listed.bytes))
+                }
+                Ok(actual) if actual.sha256 != listed.sha256 => Some(format!("{:?}: checksum differs", path)),
+                Ok(_) => None,
+            }
+        })
+        .collect();
+    for failure in checked {
+        match failure {
+            Some(failure) => verification.failures.push(failure),
+            None => verification.verified += 1,
+        }
+    }
+
+    for failure in &verification.failures {
+        warn!("{}", failure);
+    }
+    info!("{} files verified, {} problems", verification.verified, verification.failures.len());
+    Ok(verification)
+}
@@ -0,0 +1,182 @@
+//! The models windows are classified with, online and in benchmarks.
+//!
+//! A [`Classifier`] is an exported network (an `.onnx` file with its spec
+//! next to it, with the `inference` feature), a network trained by
+//! `openbci_train` (a `.mpk` file and its spec, with the `train` feature)
+//! or the CSP+LDA JSON of [`crate::calibration`], bare or bundled in a
+//! [`crate::container`] with their metadata, or the CCA decoder of an SSVEP
+//! paradigm file ([`crate::ssvep`]). All say which channels, window and sample
+//! rate they take and turn one unfiltered `channels x window` window into
+//! class probabilities, so callers do not care which one they hold.
+
+use crate::calibration::CalibrationModel;
+#[cfg(feature = "gpu")]
+use crate::compute;
+use crate::compute::GpuSelection;
+use crate::container::{self, ModelContainer};
+use crate::montage;
+use crate::ssvep::SsvepDecoder;
+use anyhow::{bail, Result};
+#[cfg(feature = "inference")]
+use eeg_inference::OnnxModel;
+#[cfg(feature = "train")]
+use eeg_train::TrainedModel;
+use ndarray::ArrayView2;
+use std::path::Path;
+
+/// An exported or trained network, a subject's CSP+LDA model or an SSVEP
+/// decoder; there are
+/// only ever a few, so the variants' sizes do not matter
+#[allow(clippy::large_enum_variant)]
+pub enum Classifier {
+    #[cfg(feature = "inference")]
+    Onnx(OnnxModel),
+    #[cfg(feature = "train")]
+    Trained(TrainedModel),
+    Calibrated(CalibrationModel),
+    Ssvep(SsvepDecoder),
+}
+
+impl Classifier {
+    /// `.onnx` files are exported networks, `.mpk` files trained ones,
+    /// `.bcimodel` files containers of any of these, `.toml` and `.yaml`
+    /// files SSVEP paradigms, anything else a calibration model
+    pub fn open(path: &Path) -> Result<Self> {
+        let extension = |name: &str| path.extension().is_some_and(|e| e.eq_ignore_ascii_case(name));
+        if container::is_container(path) {
+            return ModelContainer::open(path)?.classifier(path);
+        }
+        if extension("onnx") {
+            #[cfg(feature = "inference")]
+            return Ok(Self::Onnx(OnnxModel::open(path)?));
+            #[cfg(not(feature = "inference"))]
+            bail!("ONNX support not compiled in, rebuild with --features inference");
+        }
+        if extension("mpk") {
+            #[cfg(feature = "train")]
+            return Ok(Self::Trained(TrainedModel::open(path)?));
+            #[cfg(not(feature = "train"))]
+            bail!("Trained network support not compiled in, rebuild with --features train");
+        }
+        if extension("toml") || extension("yaml") || extension("yml") {
+            return Ok(Self::Ssvep(SsvepDecoder::open(path)?));
+        }
+        Ok(Self::Calibrated(CalibrationModel::load(path)?))
+    }
+
+    /// Move a trained network to the GPU `gpu` selects, when built with the
+    /// `gpu` feature and one works; other models stay on the CPU
+    #[cfg_attr(not(feature = "gpu"), allow(unused_variables))]
+    pub fn on_gpu(self, gpu: GpuSelection) -> Result<Self> {
+        match self {
+            #[cfg(feature = "gpu")]
+            Self::Trained(model) => Ok(Self::Trained(model.on_device(compute::inference_device(gpu))?)),
+            model => Ok(model),
+        }
+    }
+
+    /// Channels of a window
+    pub fn channels(&self) -> usize {
+        match self {
+            #[cfg(feature = "inference")]
+            Self::Onnx(model) => model.spec().channels,
+            #[cfg(feature = "train")]
+            Self::Trained(model) => model.spec().channel_names.len(),
+            Self::Calibrated(model) => model.channel_names.len(),
+            Self::Ssvep(decoder) => decoder.channel_names.len(),
+        }
+    }
+
+    /// Montage labels of the channels, empty when the model does not say
+    pub fn channel_names(&self) -> &[String] {
+        match self {
+            #[cfg(feature = "inference")]
+            Self::Onnx(model) => &model.spec().channel_names,
+            #[cfg(feature = "train")]
+            Self::Trained(model) => &model.spec().channel_names,
+            Self::Calibrated(model) => &model.channel_names,
+            Self::Ssvep(decoder) => &decoder.channel_names,
+        }
+    }
+
+    /// Samples of a window
+    pub fn window(&self) -> usize {
+        match self {
+            #[cfg(feature = "inference")]
+            Self::Onnx(model) => model.spec().window,
+            #[cfg(feature = "train")]
+            Self::Trained(model) => model.spec().window,
+            Self::Calibrated(model) => model.window,
+            Self::Ssvep(decoder) => decoder.window,
+        }
+    }
+
+    /// Rate the model was trained at, when known
+    pub fn sample_rate(&self) -> Option<f64> {
+        match self {
+            #[cfg(feature = "inference")]
+            Self::Onnx(model) => model.spec().sample_rate,
+            #[cfg(feature = "train")]
+            Self::Trained(model) => Some(model.spec().sample_rate),
+            Self::Calibrated(model) => Some(model.sample_rate as f64),
+            Self::Ssvep(decoder) => Some(decoder.sample_rate as f64),
+        }
+    }
+
+    /// Class names in probability order
+    pub fn classes(&self) -> Vec<String> {
+        match self {
+            #[cfg(feature = "inference")]
+            Self::Onnx(model) => (0..model.classes()).map(|i| model.class_name(i)).collect(),
+            #[cfg(feature = "train")]
+            Self::Trained(model) => model.spec().classes.clone(),
+            Self::Calibrated(model) => model.classes.clone(),
+            Self::Ssvep(decoder) => decoder.classes.clone(),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            #[cfg(feature = "inference")]
+            Self::Onnx(model) => format!("{} windows", model.layout()),
+            #[cfg(feature = "train")]
+            Self::Trained(model) => match model.device() {
+                eeg_train::Device::Cpu => format!("{}, {} parameters", model.spec().architecture.name(), model.num_params()),
+                device => format!("{}, {} parameters, on the {}", model.spec().architecture.name(), model.num_params(), device),
+            },
+            Self::Calibrated(model) => format!("CSP+LDA, {}-{} Hz", model.band.low, model.band.high),
+            Self::Ssvep(decoder) => {
+                let frequencies: Vec<String> = decoder.frequencies().iter().map(|hz| hz.to_string()).collect();
+                format!("SSVEP CCA, {} Hz with {} harmonics", frequencies.join(", "), decoder.harmonics())
+            }
+        }
+    }
+
+    /// Class probabilities of one `channels x window` window
+    pub fn predict(&self, window: ArrayView2<f32>) -> Result<Vec<f32>> {
+        match self {
+            #[cfg(feature = "inference")]
+            Self::Onnx(model) => Ok(model.predict(window)?),
+            #[cfg(feature = "train")]
+            Self::Trained(model) => Ok(model.predict(window)?),
+            Self::Calibrated(model) => model.predict(window),
+            Self::Ssvep(decoder) => Ok(decoder.predict(window)),
+        }
+    }
+
+    /// Which of the channels labelled `labels` feed the model, in its
+    /// order; without channel names the channels must match one to one
+    pub fn select_channels(&self, labels: &[String]) -> Result<Vec<usize>> {
+        if self.channel_names().is_empty() {
+            if labels.len() != self.channels() {
+                bail!(
+                    "There are {} channels but the model takes {}; list the model's channel_names in its spec to pick them",
+                    labels.len(),
+                    self.channels()
+                );
+            }
+            return Ok((0..labels.len()).collect());
+        }
+        montage::find_channels(self.channel_names(), labels)
+    }
+}
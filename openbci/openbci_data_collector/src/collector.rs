@@ -0,0 +1,1061 @@
+//! The collector behind every recording: it reads the board, feeds the
+//! preprocessing, detectors and live outputs, and hands the samples to
+//! the trial files on the writer thread.
+
+use crate::asr::{AsrCalibration, AsrProcessor, CleanedSource, ASR_FILE};
+use crate::augment::{ArtifactInjector, MixedSource};
+use crate::bandpower::{BandPowerMonitor, MOTOR_CHANNELS};
+use crate::bids::{self, BidsRun};
+use crate::brainvision;
+use crate::checksum;
+use crate::compress::Compression;
+use crate::connectivity::{ConnectivityMetric, ConnectivityMonitor};
+use crate::cue::{CuePlan, CuePresenter, CUE_PREFIX};
+use crate::detect::{ArtifactDetector, DetectorLimits};
+use crate::disk::{self, DiskMonitor};
+use crate::eog::{EogCalibrationFile, EogProcessor, EOG_FILE};
+use crate::erd::ErdRecorder;
+use crate::events::{self, BAD_TRIAL, CLOCK_JUMP, DISK_LOW, GAP};
+use crate::filter::OnlineFilter;
+use crate::gaps::{Discontinuity, GapDetector, GapFill};
+use crate::gui_bridge::GuiBridge;
+use crate::keys::{self, Hotkey, KeyRecorder};
+#[cfg(feature = "lsl")]
+use crate::lsl::LslOutlets;
+use crate::metadata::{
+    ArtifactInjectionInfo, AsrInfo, BoardSettings, ElectrodeConfig, EogRegressionInfo, GapCause, GapRecord,
+    MarkerRecord, Segment, StreamHealth, TrialMetadata,
+};
+use crate::osc::{OscSamples, OscSender};
+use crate::platform;
+use crate::progress::TrialProgress;
+use crate::qc;
+use crate::rate::{RateMonitor, RATE_TOLERANCE};
+use crate::record::{
+    build_injector, check_free_space, log_quick_look, session_montage, trial_data_path, ArtifactAction, OscData,
+    RecordArgs, ShieldOutput, Transport,
+};
+use crate::recording::Recording;
+use crate::scope::Scope;
+use crate::shutdown;
+use crate::sink::{format_path, open_sink, DataSink, EEGSample, OutputFormat};
+use crate::timesync::{HostTimes, HOST_TIMES_SUFFIX};
+use crate::websocket::{WsServer, WsTrial};
+use crate::writer::TrialWriter;
+#[cfg(feature = "zmq")]
+use crate::zmq_pub::{ZmqPublisher, ZmqTrial};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use indicatif::MultiProgress;
+use log::{error, info, warn};
+use openbci_wifi_client::{BoardTransport, Marker, StreamEvent};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Stem of segment `index` (1-based) of a rotated trial
+fn segment_stem(stem: &Path, index: usize) -> PathBuf {
+    let name = stem.file_name().unwrap_or_default().to_string_lossy();
+    stem.with_file_name(format!("{}_seg{:03}", name, index))
+}
+
+/// Names of the files `sinks` write, in order
+fn file_names(sinks: &[Box<dyn DataSink>]) -> Vec<String> {
+    sinks
+        .iter()
+        .filter_map(|sink| sink.data_file()?.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// How the trial files are rotated with --segment-minutes
+struct Rotation {
+    /// Sample IDs per segment
+    samples: u64,
+    /// Sample ID the next segment starts at
+    next: u64,
+    /// Trial stem the segment stems are made from
+    stem: PathBuf,
+    formats: Vec<OutputFormat>,
+    channel_names: Vec<String>,
+    compress: Option<Compression>,
+}
+
+/// Sink wrapper whose writes fail with ENOSPC while `full` is set, for
+/// soak testing
+struct DiskFaultSink {
+    inner: Box<dyn DataSink>,
+    full: Arc<AtomicBool>,
+}
+
+impl DataSink for DiskFaultSink {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn data_file(&self) -> Option<&Path> {
+        self.inner.data_file()
+    }
+
+    fn write_batch(&mut self, samples: &[EEGSample]) -> Result<()> {
+        if self.full.load(Ordering::SeqCst) {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOSPC).into());
+        }
+        self.inner.write_batch(samples)
+    }
+
+    fn insert_event(&mut self, event: &MarkerRecord) -> Result<()> {
+        self.inner.insert_event(event)
+    }
+
+    fn finalize(&mut self, metadata: &TrialMetadata) -> Result<()> {
+        self.inner.finalize(metadata)
+    }
+}
+
+/// Main data collector
+pub struct DataCollector {
+    board: Box<dyn BoardTransport>,
+    /// Samples per write to the trial files
+    write_batch: usize,
+    /// Trial files first, in --format order, then live sinks. While
+    /// collecting, the files are with the writer thread.
+    sinks: Vec<Box<dyn DataSink>>,
+    pub metadata: TrialMetadata,
+    sample_count: u64,
+    injector: Option<ArtifactInjector>,
+    connectivity_every: Option<f64>,
+    band_power: Option<BandPowerMonitor>,
+    /// Set with --quick-look, unless the boards record different subjects
+    erd: Option<ErdRecorder>,
+    eog: Option<EogProcessor>,
+    asr: Option<AsrProcessor>,
+    filter: Option<OnlineFilter>,
+    detector: Option<ArtifactDetector>,
+    /// Contaminated samples at which --on-artifact stops the trial
+    abort_after: Option<u64>,
+    /// Board timestamps are a real clock (WiFi shield), not host time
+    detect_gaps: bool,
+    /// Measure the delivered sample rate; off for replays, which run at
+    /// the file's rate
+    check_rate: bool,
+    /// Set with --fill-gaps
+    fill_gaps: Option<GapFill>,
+    /// Set with --segment-minutes
+    rotation: Option<Rotation>,
+    /// Host receive times of the trial; none while monitoring
+    host_times: Option<HostTimes>,
+    /// Output directory and the free bytes below which to warn, unless
+    /// --min-free-mb is 0
+    min_free: Option<(PathBuf, u64)>,
+    /// Set with --bids: the run this trial is written as
+    bids: Option<BidsRun>,
+    cues: Option<CuePlan>,
+    /// Set with --keys: the hotkeys in effect
+    hotkeys: Option<Vec<Hotkey>>,
+    /// Subject, session, trial and class, or that it is monitoring
+    title: String,
+    /// Set with --scope
+    scope: bool,
+    /// Where the progress bars go when there is no scope
+    progress: MultiProgress,
+    /// Set with --ws-port: band power goes out next to the samples
+    ws: Option<WsServer>,
+    /// Set with --osc-data band-power
+    osc: Option<OscSender>,
+    /// Set with --zmq-pub: as above
+    #[cfg(feature = "zmq")]
+    zmq: Option<ZmqPublisher>,
+}
+
+impl DataCollector {
+    pub fn new(args: &RecordArgs, board: Box<dyn BoardTransport>, board_settings: Option<BoardSettings>) -> Result<Self> {
+        // Create output directory
+        if !args.monitor {
+            fs::create_dir_all(&args.output_dir)?;
+        }
+
+        // Channel labels come from the session montage when the wizard ran
+        let session_dir = args.session_dir();
+        let montage = session_montage(args)?;
+        // BIDS tools match channels to 10-20 positions by their plain label
+        let channel_names = if args.bids { montage.labels() } else { montage.column_names() };
+        if args.bids && montage.validate().is_err() {
+            anyhow::bail!("BIDS needs a distinct position on every channel, give a --montage of all {} channels", args.channels);
+        }
+
+        let electrode_config = ElectrodeConfig {
+            channels: channel_names.clone(),
+            reference: montage.reference.clone(),
+            ground: montage.ground.clone(),
+        };
+
+        let class_id = args.class_id();
+
+        let mut metadata = TrialMetadata {
+            subject_id: args.subject_id.clone(),
+            session_id: args.session_id.clone(),
+            trial_number: args.trial,
+            class_label: args.class().to_string(),
+            class_id,
+            start_time: Utc::now(),
+            end_time: None,
+            sample_rate: args.sample_rate,
+            num_channels: args.channels,
+            total_samples: 0,
+            duration_seconds: args.duration,
+            electrode_config,
+            board_settings,
+            environment: None,
+            montage: Some(montage.clone()),
+            markers: Vec::new(),
+            impedance_kohm: None,
+            data_file: None,
+            other_data_files: Vec::new(),
+            segments: Vec::new(),
+            checksums: Vec::new(),
+            artifact_injection: None,
+            simulation: args.simulation(),
+            multi_board: args.multi_board(),
+            experiment: args.experiment.as_ref().map(|e| e.info.clone()),
+            paradigm: args.paradigm_info(),
+            eog_regression: None,
+            asr: None,
+            online_filter: None,
+            artifact_detection: None,
+            stream_health: None,
+            measured_sample_rate: None,
+            host_clock: None,
+            erd: None,
+            interrupted: false,
+            replaced_by: None,
+        };
+        if metadata.simulation.is_some() {
+            warn!("Recording simulated EEG, not data from a board");
+        }
+
+        let injector = build_injector(args, &channel_names)?;
+        if injector.is_some() {
+            warn!("Artifact injection enabled, this trial is for robustness testing only");
+            metadata.artifact_injection = Some(ArtifactInjectionInfo {
+                kinds: args
+                    .inject_artifacts
+                    .iter()
+                    .chain(args.artifact_recording.iter().map(|r| &r.kind))
+                    .map(|k| k.to_string())
+                    .collect::<std::collections::BTreeSet<_>>()
+                    .into_iter()
+                    .collect(),
+                interval_seconds: args.artifact_interval,
+                seed: args.artifact_seed,
+                recordings: args
+                    .artifact_recording
+                    .iter()
+                    .map(|r| r.path.display().to_string())
+                    .collect(),
+                injected: 0,
+            });
+        }
+
+        let eog = if args.eog_regression {
+            let Some(calibration) = EogCalibrationFile::load(&session_dir)? else {
+                anyhow::bail!("--eog-regression needs a calibration, run check --eog-calibrate first (or pass --eog-calibrate here)");
+            };
+            let regression = &calibration.regression;
+            if regression.labels() != montage.labels() || regression.sample_rate() != args.sample_rate as f64 {
+                anyhow::bail!(
+                    "EOG regression was calibrated for {:?} at {} Hz, recording {:?} at {} Hz; recalibrate",
+                    regression.labels(),
+                    regression.sample_rate(),
+                    montage.labels(),
+                    args.sample_rate
+                );
+            }
+            let eog_channels: Vec<String> = regression.eog_labels().iter().map(|l| l.to_string()).collect();
+            info!("EOG regression enabled on {} (calibrated {})", eog_channels.join(", "), calibration.calibrated_at);
+            metadata.eog_regression = Some(EogRegressionInfo {
+                eog_channels,
+                calibration_file: EOG_FILE.to_string(),
+                calibrated_at: calibration.calibrated_at,
+            });
+            Some(EogProcessor::new(calibration))
+        } else {
+            None
+        };
+
+        let asr = if args.asr {
+            let Some(calibration) = AsrCalibration::load(&session_dir)? else {
+                anyhow::bail!("--asr needs a calibration, run check --asr-calibrate first (or pass --asr-calibrate here)");
+            };
+            if calibration.channel_names != channel_names || calibration.sample_rate != args.sample_rate {
+                anyhow::bail!(
+                    "ASR calibration was made for {:?} at {} Hz, recording {:?} at {} Hz; recalibrate",
+                    calibration.channel_names,
+                    calibration.sample_rate,
+                    channel_names,
+                    args.sample_rate
+                );
+            }
+            info!("ASR cleaning enabled (cutoff {}, calibrated {})", args.asr_cutoff, calibration.calibrated_at);
+            metadata.asr = Some(AsrInfo {
+                cutoff: args.asr_cutoff,
+                calibration_file: ASR_FILE.to_string(),
+                calibrated_at: calibration.calibrated_at,
+                blocks: 0,
+                reconstructed_blocks: 0,
+            });
+            Some(AsrProcessor::new(calibration, args.asr_cutoff))
+        } else {
+            None
+        };
+
+        // Boards recording different subjects keep their own average
+        let car_group = args.car.then(|| match args.shield_output {
+            ShieldOutput::SideBySide if !args.shields.is_empty() => args.board_channels(),
+            _ => channel_names.len(),
+        });
+        let filter = OnlineFilter::new(args.bandpass, args.notch, car_group, args.sample_rate, channel_names.len())?;
+        if let Some(filter) = &filter {
+            let info = filter.info();
+            info!(
+                "Filtering online: band-pass {}, notch {}, common average reference {}",
+                match (info.highpass_hz, info.lowpass_hz) {
+                    (Some(low), Some(high)) => format!("{}-{} Hz", low, high),
+                    _ => "off".to_string(),
+                },
+                info.notch_hz.map_or("off".to_string(), |hz| format!("{} Hz", hz)),
+                if info.common_average_reference { "on" } else { "off" }
+            );
+            metadata.online_filter = Some(info.clone());
+        }
+
+        let detector = if args.detect_artifacts {
+            let limits = DetectorLimits {
+                max_amplitude_uv: args.max_amplitude,
+                flat_amplitude_uv: args.flat_amplitude,
+                max_gradient_uv: args.max_gradient,
+            };
+            limits.validate()?;
+            info!(
+                "Detecting artifacts: > {} µV peak-to-peak, flat < {} µV, jumps > {} µV",
+                limits.max_amplitude_uv, limits.flat_amplitude_uv, limits.max_gradient_uv
+            );
+            let detector = ArtifactDetector::new(limits, args.sample_rate, &channel_names);
+            metadata.artifact_detection = Some(detector.info().clone());
+            Some(detector)
+        } else {
+            None
+        };
+        // Past this the trial cannot pass QC any more
+        let abort_after = (detector.is_some() && args.on_artifact != ArtifactAction::Mark).then(|| {
+            (args.qc_criteria().map_or(0.0, |c| c.max_artifact_fraction) * metadata.expected_samples() as f64) as u64
+        });
+
+        let band_power = args
+            .band_power_every
+            .map(|every| BandPowerMonitor::new(&montage.labels(), args.sample_rate, every))
+            .transpose()?;
+        let separate_subjects = args.shield_output == ShieldOutput::SideBySide && !args.shields.is_empty();
+        let erd = (args.quick_look && !args.monitor && !separate_subjects && args.configured_paradigm.is_motor_imagery())
+            .then(|| ErdRecorder::new(&montage.labels(), args.sample_rate))
+            .flatten();
+
+        let bids = if args.bids && !args.monitor {
+            let run = BidsRun::next(
+                Path::new(&args.output_dir),
+                &args.subject_id,
+                &args.session_id,
+                args.line_frequency,
+                &args.configured_paradigm,
+            )?;
+            info!("Recording trial {} as BIDS run {}", args.trial, run.run);
+            Some(run)
+        } else {
+            None
+        };
+
+        let stem = match &bids {
+            Some(_) => None,
+            None if args.monitor => None,
+            None => Some(trial_data_path(args, class_id)?),
+        };
+        if !args.monitor {
+            check_free_space(args);
+        }
+        let mut rotation = None;
+        if let (Some(minutes), Some(stem)) = (args.segment_minutes, &stem) {
+            let samples = ((minutes * 60.0 * args.sample_rate as f64) as u64).max(1);
+            info!("Rotating the trial files every {} min ({} samples)", minutes, samples);
+            rotation = Some(Rotation {
+                samples,
+                next: samples,
+                stem: stem.clone(),
+                formats: args.formats(),
+                channel_names: channel_names.clone(),
+                compress: args.compress,
+            });
+        }
+        let mut sinks: Vec<Box<dyn DataSink>> = Vec::new();
+        let formats = if args.monitor { Vec::new() } else { args.formats() };
+        for format in formats {
+            let data_path = match (&bids, &stem) {
+                (Some(run), _) => run.data_path(format.extension()),
+                (None, Some(stem)) if rotation.is_some() => format_path(format, &segment_stem(stem, 1), args.compress),
+                (None, Some(stem)) => format_path(format, stem, args.compress),
+                (None, None) => unreachable!(),
+            };
+            sinks.push(open_sink(format, data_path, &metadata, &channel_names, args.compress)?);
+        }
+        let files = file_names(&sinks);
+        metadata.data_file = files.first().cloned();
+        metadata.other_data_files = files.iter().skip(1).cloned().collect();
+        if rotation.is_some() {
+            metadata.segments.push(Segment {
+                first_sample: 0,
+                samples: 0,
+                start_time: metadata.start_time,
+                files,
+            });
+        }
+        // Only the WiFi shield stamps samples with a clock of its own
+        let board_clock = matches!(args.transport, Transport::Wifi) && !args.simulate;
+        let host_times_path = match (&bids, &stem) {
+            (Some(run), _) => Some(run.host_times_path()),
+            (None, Some(stem)) => Some(stem.with_file_name(format!(
+                "{}_{}",
+                stem.file_name().unwrap_or_default().to_string_lossy(),
+                HOST_TIMES_SUFFIX
+            ))),
+            (None, None) => None,
+        };
+        let host_times = host_times_path
+            .map(|path| HostTimes::create(&path, board_clock).with_context(|| format!("Failed to create {:?}", path)))
+            .transpose()?;
+        if let Some(addr) = args.gui_udp {
+            sinks.push(Box::new(GuiBridge::connect(addr, channel_names.len(), args.sample_rate)?));
+        }
+        #[cfg(feature = "lsl")]
+        if args.lsl {
+            // The same for every trial of the session, so inlets reconnect
+            let source_id = format!("{}_{}_{}", args.lsl_name, args.subject_id, args.session_id);
+            sinks.push(Box::new(LslOutlets::open(&args.lsl_name, &source_id, &channel_names, args.sample_rate)?));
+        }
+        if let Some(server) = &args.ws_server {
+            sinks.push(Box::new(WsTrial::start(
+                server.clone(),
+                &args.subject_id,
+                &args.session_id,
+                args.trial,
+                args.class(),
+                &channel_names,
+                args.sample_rate,
+            )?));
+        }
+        let mut osc = None;
+        if let Some(target) = args.osc {
+            let sender = OscSender::connect(target, &args.osc_address)?;
+            match args.osc_data {
+                OscData::Samples => sinks.push(Box::new(OscSamples::new(sender, args.sample_rate))),
+                OscData::BandPower => {
+                    info!("Sending band power scores to osc.udp://{}{}", target, args.osc_address);
+                    if !MOTOR_CHANNELS.iter().all(|c| montage.labels().iter().any(|l| l == c)) {
+                        warn!("The montage lacks C3 or C4, OSC gets band power without [right, left] scores");
+                    }
+                    osc = Some(sender);
+                }
+            }
+        }
+        #[cfg(feature = "zmq")]
+        if let Some(publisher) = &args.zmq_publisher {
+            sinks.push(Box::new(ZmqTrial::start(
+                publisher.clone(),
+                &args.subject_id,
+                &args.session_id,
+                args.trial,
+                args.class(),
+                &channel_names,
+                args.sample_rate,
+            )));
+        }
+
+        let class = args.configured_paradigm.class(args.class());
+        Ok(Self {
+            board,
+            write_batch: platform::write_buffer_capacity(args.sample_rate),
+            sinks,
+            metadata,
+            sample_count: 0,
+            injector,
+            connectivity_every: args.connectivity_every,
+            band_power,
+            erd,
+            eog,
+            asr,
+            filter,
+            detector,
+            abort_after,
+            detect_gaps: board_clock,
+            check_rate: !matches!(args.transport, Transport::Replay),
+            fill_gaps: args.fill_gaps,
+            rotation,
+            host_times,
+            min_free: (args.min_free_mb > 0 && !args.monitor)
+                .then(|| (PathBuf::from(&args.output_dir), args.min_free_mb * 1024 * 1024)),
+            bids,
+            cues: args.cues.then(|| CuePlan {
+                class: args.class().to_string(),
+                kind: class.map(|class| class.cue).unwrap_or_default(),
+                text: class.map_or_else(|| args.class().to_uppercase(), |class| class.cue_text()),
+                stimuli: args.configured_paradigm.trial.stimuli.clone(),
+                delay: args.cue_delay,
+                beep: args.cue_beep,
+                draw: true,
+            }),
+            hotkeys: args.keys.then(|| keys::default_hotkeys().into_iter().chain(args.hotkey.iter().cloned()).collect()),
+            title: if args.monitor {
+                format!("{} {}: monitoring", args.subject_id, args.session_id)
+            } else {
+                format!("{} {} trial {}: {}", args.subject_id, args.session_id, args.trial, args.class())
+            },
+            scope: args.scope,
+            progress: args.progress.clone(),
+            ws: args.ws_server.clone(),
+            osc,
+            #[cfg(feature = "zmq")]
+            zmq: args.zmq_publisher.clone(),
+        })
+    }
+
+    /// Fail trial file writes with ENOSPC while `full` is set
+    pub fn simulate_disk_full(&mut self, full: Arc<AtomicBool>) {
+        self.sinks = std::mem::take(&mut self.sinks)
+            .into_iter()
+            .map(|inner| -> Box<dyn DataSink> {
+                if inner.is_live() {
+                    inner
+                } else {
+                    Box::new(DiskFaultSink { inner, full: Arc::clone(&full) })
+                }
+            })
+            .collect();
+    }
+
+    pub async fn collect_data(&mut self, duration_secs: u64) -> Result<()> {
+        info!("Starting data collection for {} seconds", duration_secs);
+        let channels_str = self.metadata.electrode_config.channels.join(", ");
+        info!("Electrode configuration: {} (active) | {} (ref) | {} (gnd)",
+              channels_str,
+              self.metadata.electrode_config.reference,
+              self.metadata.electrode_config.ground);
+
+        // First, try to stop any existing stream
+        info!("Cleaning up any existing stream on {}", self.board.describe());
+        self.board.stop_stream().await?;
+
+        // Wait a moment for cleanup
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // Artifacts are injected before cleaning so EOG regression and ASR
+        // see them
+        let mut stream = CleanedSource::new(
+            MixedSource::new(self.board.open_stream().await?, self.injector.take()),
+            self.eog.take(),
+            self.asr.take(),
+        );
+        let mut connectivity = self.connectivity_every.filter(|s| *s > 0.0).map(|every| {
+            ConnectivityMonitor::new(
+                &self.metadata.electrode_config.channels,
+                self.metadata.sample_rate,
+                eeg_dsp::motor_imagery_bands(),
+                vec![ConnectivityMetric::Plv, ConnectivityMetric::Coherence],
+                every,
+            )
+        });
+
+        let end_time = if duration_secs > 0 {
+            Some(Instant::now() + Duration::from_secs(duration_secs))
+        } else {
+            None
+        };
+
+        let scope = self.scope.then(|| {
+            Scope::start(self.title.clone(), &self.metadata.electrode_config.channels, self.metadata.sample_rate, end_time)
+        }).flatten();
+        let mut scope_updated = Instant::now();
+        // The scope shows the stream counters itself
+        let mut progress = scope
+            .is_none()
+            .then(|| TrialProgress::start(&self.progress, self.title.clone(), end_time.map(|_| Duration::from_secs(duration_secs))))
+            .flatten();
+        if progress.is_none() {
+            stream.inner_mut().live_mut().log_stats_every(Duration::from_secs(5));
+        }
+        let cues = self.cues.clone().map(|mut plan| {
+            // The scope shows the phase where the cues would be drawn
+            plan.draw = scope.is_none();
+            CuePresenter::start(plan, stream.inner().live().marker_sender())
+        });
+        let keys = self
+            .hotkeys
+            .as_ref()
+            .and_then(|hotkeys| KeyRecorder::start(stream.inner().live().marker_sender(), hotkeys));
+
+        // Markers waiting for the next sample to anchor to
+        let mut pending_markers: Vec<Marker> = Vec::new();
+        let mut health = StreamHealth {
+            fill: self.fill_gaps.filter(|_| self.detect_gaps),
+            ..StreamHealth::default()
+        };
+        // Timestamp and values of the last sample, to fill a gap after it
+        let mut last_sample: Option<(f64, Vec<f32>)> = None;
+        let mut gaps = self.detect_gaps.then(|| GapDetector::new(self.metadata.sample_rate));
+        let mut rate = self.check_rate.then(|| RateMonitor::new(self.metadata.sample_rate, self.detect_gaps));
+        // Trial files are written on their own thread, live sinks from here
+        let (files, live) = std::mem::take(&mut self.sinks).into_iter().partition(|sink| !sink.is_live());
+        self.sinks = live;
+        let mut writer = TrialWriter::start(files, self.metadata.sample_rate, self.write_batch);
+        let mut disk = self.min_free.as_ref().map(|(path, threshold)| DiskMonitor::new(path, *threshold));
+        let mut host_times = self.host_times.take();
+
+        loop {
+            // Check if we should stop
+            if shutdown::requested() && !stream.is_finished() {
+                info!("Stopping collection early");
+                self.metadata.interrupted = true;
+                stream.finish();
+                if let Some(scope) = &scope {
+                    scope.set_phase("Stopping");
+                }
+            }
+            if let Some(end) = end_time {
+                if !stream.is_finished() && Instant::now() >= end {
+                    info!("Duration reached, stopping collection");
+                    // Drain samples still held by the cleaning stage
+                    stream.finish();
+                    if let Some(scope) = &scope {
+                        scope.set_phase("Stopping");
+                    }
+                }
+            }
+            if let Some(scope) = &scope {
+                if scope_updated.elapsed() >= Duration::from_secs(1) {
+                    scope.update_stats(stream.inner().live().stats(), health.missing_samples);
+                    scope_updated = Instant::now();
+                }
+            }
+            if let Some(progress) = progress.as_mut().filter(|p| p.due()) {
+                progress.update(self.sample_count, &stream.inner().live().stats(), health.missing_samples);
+            }
+            if let Some(free) = disk.as_mut().and_then(DiskMonitor::check) {
+                warn!("Only {} MB left on the output disk", disk::megabytes(free));
+                pending_markers.push(Marker::now(format!("{}{}", DISK_LOW, disk::megabytes(free))));
+            }
+
+            // Read data with timeout
+            match tokio::time::timeout(Duration::from_millis(100), stream.recv()).await {
+                Ok(None) => {
+                    if !stream.is_finished() {
+                        warn!("Connection closed");
+                    }
+                    break;
+                }
+                Ok(Some(StreamEvent::Marker(marker))) => {
+                    info!("Marker '{}' at {:.3}", marker.label, marker.host_time);
+                    pending_markers.push(marker);
+                }
+                Ok(Some(StreamEvent::Sample(mut sample))) => {
+                    if self.rotation.as_ref().is_some_and(|r| self.sample_count >= r.next) {
+                        self.rotate(&mut writer, &health);
+                    }
+                    if let Some(filter) = &mut self.filter {
+                        filter.process(&mut sample.data);
+                    }
+                    let received = Instant::now();
+                    let discontinuity = gaps.as_mut().and_then(|g| g.push(sample.timestamp, received));
+                    // Samples drained after the stop arrive all at once
+                    if let (Some(rate), false) = (&mut rate, stream.is_finished()) {
+                        let (missing, clock_jump) = match discontinuity {
+                            Some(Discontinuity::Gap { missing }) => (missing, false),
+                            Some(Discontinuity::ClockJump { missing, .. }) => (missing, true),
+                            None => (0, false),
+                        };
+                        rate.push(sample.timestamp, received, missing, clock_jump);
+                    }
+                    match discontinuity {
+                        Some(Discontinuity::Gap { missing }) => {
+                            let sample_id = self.sample_count;
+                            warn!("{} samples missing before sample {}", missing, sample_id);
+                            health.missing_samples += missing;
+                            health.gaps.push(GapRecord { sample_id, samples: missing, cause: GapCause::Stream });
+                            let marker = Marker::now(format!("{}{}", GAP, missing));
+                            match (health.fill, &last_sample) {
+                                (Some(fill), Some((timestamp, channels))) => {
+                                    // The marker opens the filled rows
+                                    self.record_marker(marker, sample_id, &mut writer, scope.as_ref());
+                                    let before = (*timestamp, channels.as_slice());
+                                    for (timestamp, channels) in fill.rows(before, (sample.timestamp, &sample.data), missing) {
+                                        writer.push_sample(EEGSample {
+                                            timestamp,
+                                            sample_id: self.sample_count,
+                                            channels,
+                                        });
+                                        self.sample_count += 1;
+                                    }
+                                    health.filled_samples += missing;
+                                }
+                                _ => pending_markers.push(marker),
+                            }
+                        }
+                        Some(Discontinuity::ClockJump { seconds, missing }) => {
+                            let sample_id = self.sample_count;
+                            warn!("Board clock jumped {:+.3} s before sample {}", seconds, sample_id);
+                            health.clock_jumps += 1;
+                            health.missing_samples += missing;
+                            health.gaps.push(GapRecord { sample_id, samples: missing, cause: GapCause::ClockJump });
+                            pending_markers.push(Marker::now(format!("{}{:+.3}", CLOCK_JUMP, seconds)));
+                        }
+                        None => {}
+                    }
+                    let sample_id = self.sample_count;
+                    self.sample_count += 1;
+                    if let Some(host_times) = &mut host_times {
+                        let clock_jump = matches!(discontinuity, Some(Discontinuity::ClockJump { .. }));
+                        host_times.push(sample_id, sample.timestamp, received, clock_jump);
+                    }
+                    if health.fill.is_some() {
+                        match &mut last_sample {
+                            Some((timestamp, channels)) => {
+                                *timestamp = sample.timestamp;
+                                channels.clone_from(&sample.data);
+                            }
+                            None => last_sample = Some((sample.timestamp, sample.data.clone())),
+                        }
+                    }
+
+                    if let Some(connectivity) = &mut connectivity {
+                        connectivity.push(&sample.data);
+                    }
+                    if let Some(erd) = &mut self.erd {
+                        erd.push(&sample.data);
+                    }
+                    if let Some(powers) = self.band_power.as_mut().and_then(|b| b.push(&sample.data)) {
+                        info!("Band power: {}", powers);
+                        if let Some(scope) = &scope {
+                            scope.set_band_power(&powers);
+                        }
+                        if let Some(server) = &self.ws {
+                            server.band_power(&powers);
+                        }
+                        if let Some(osc) = &mut self.osc {
+                            osc.band_power(&powers);
+                        }
+                        #[cfg(feature = "zmq")]
+                        if let Some(publisher) = &self.zmq {
+                            publisher.band_power(&powers);
+                        }
+                    }
+
+                    if let Some(detector) = &mut self.detector {
+                        pending_markers.extend(detector.push(&sample.data).into_iter().map(Marker::now));
+                        let over = self.abort_after.is_some_and(|limit| detector.contaminated_samples() > limit);
+                        if over && !detector.info().aborted {
+                            warn!("Artifacts cover more of the trial than QC allows, stopping it");
+                            detector.abort();
+                            stream.finish();
+                            if let Some(scope) = &scope {
+                                scope.set_phase("Stopped for artifacts");
+                            }
+                        }
+                    }
+
+                    for marker in std::mem::take(&mut pending_markers) {
+                        self.record_marker(marker, sample_id, &mut writer, scope.as_ref());
+                    }
+
+                    if let Some(scope) = &scope {
+                        scope.push(&sample.data);
+                    }
+                    let sample = EEGSample {
+                        timestamp: sample.timestamp,
+                        sample_id,
+                        channels: sample.data,
+                    };
+                    for sink in &mut self.sinks {
+                        if let Err(e) = sink.write_batch(std::slice::from_ref(&sample)) {
+                            warn!("{} live sink failed: {}", sink.name(), e);
+                        }
+                    }
+                    writer.push_sample(sample);
+                }
+                Err(_) => {
+                    // Timeout, continue
+                }
+            }
+
+        }
+
+        if let Some(scope) = scope {
+            scope.finish();
+        }
+        drop(progress);
+        if let Some(keys) = keys {
+            keys.finish();
+        }
+        if let Some(cues) = cues {
+            cues.finish();
+        }
+
+        // Write what is still queued and take the files back for finalize
+        let mut sinks = writer.finish(&mut health);
+        sinks.append(&mut self.sinks);
+        self.sinks = sinks;
+
+        let stats = stream.inner().live().stats();
+        info!("Stream totals: {}", stats);
+        health.parse_errors = stats.parse_errors;
+        health.dropped_packets = stats.dropped_packets;
+        health.reconnects = stats.reconnects;
+        if health.missing_samples > 0 || health.unwritten_samples > 0 {
+            warn!(
+                "{} samples missing from the stream, {} lost to failed writes",
+                health.missing_samples, health.unwritten_samples
+            );
+        }
+        health.min_free_bytes = disk.as_ref().and_then(DiskMonitor::min_free);
+        self.metadata.host_clock = host_times.map(HostTimes::finish).transpose().unwrap_or_else(|e| {
+            warn!("Failed to write the host receive times: {}", e);
+            None
+        });
+        self.metadata.stream_health = Some(health);
+        if let Some(info) = rate.as_ref().and_then(RateMonitor::info) {
+            match info.clock_drift_ppm {
+                Some(drift) => info!(
+                    "Measured sample rate {:.3} Hz ({:+.2}%), board clock drift {:+.0} ppm",
+                    info.measured_hz, info.deviation_percent, drift
+                ),
+                None => info!("Measured sample rate {:.3} Hz ({:+.2}%)", info.measured_hz, info.deviation_percent),
+            }
+            if info.deviation_percent.abs() > RATE_TOLERANCE * 100.0 {
+                warn!(
+                    "Sample rate is {:.2} Hz, not {} Hz; the metadata records the measured rate for analysis",
+                    info.measured_hz, self.metadata.sample_rate
+                );
+            }
+            self.metadata.measured_sample_rate = Some(info);
+        }
+        if let (Some(info), Some(injector)) = (&mut self.metadata.artifact_injection, stream.inner().injector()) {
+            info.injected = injector.injected();
+            info!("Injected {} artifacts", info.injected);
+        }
+        if let Some(detector) = &self.detector {
+            let info = detector.info();
+            info!("Detected {} artifact segments over {} samples", info.segments, info.contaminated_samples);
+            self.metadata.artifact_detection = Some(info.clone());
+        }
+        if let (Some(info), Some(asr)) = (&mut self.metadata.asr, stream.asr()) {
+            let stats = asr.stats();
+            info.blocks = stats.blocks;
+            info.reconstructed_blocks = stats.reconstructed_blocks;
+            info!(
+                "ASR reconstructed {} of {} blocks ({} components)",
+                stats.reconstructed_blocks, stats.blocks, stats.removed_components
+            );
+        }
+        info!("Stopping stream");
+        self.board.stop_stream().await?;
+
+        Ok(())
+    }
+
+    /// Close the current segment of the trial files and go on in the next
+    /// from sample `self.sample_count`. If the next cannot be opened, the
+    /// current one goes on until the next rotation is due.
+    fn rotate(&mut self, writer: &mut TrialWriter, health: &StreamHealth) {
+        let Some(rotation) = &mut self.rotation else {
+            return;
+        };
+        // Tried again with the next sample
+        if !writer.can_rotate() {
+            return;
+        }
+        rotation.next = self.sample_count + rotation.samples;
+        let index = self.metadata.segments.len() + 1;
+        let stem = segment_stem(&rotation.stem, index);
+        let now = Utc::now();
+        // File headers carry the segment's start
+        let trial_start = std::mem::replace(&mut self.metadata.start_time, now);
+        let sinks: Result<Vec<_>> = rotation
+            .formats
+            .iter()
+            .map(|&format| {
+                let path = format_path(format, &stem, rotation.compress);
+                open_sink(format, path, &self.metadata, &rotation.channel_names, rotation.compress)
+            })
+            .collect();
+        self.metadata.start_time = trial_start;
+        let sinks = match sinks {
+            Ok(sinks) => sinks,
+            Err(e) => {
+                error!("Failed to open segment {}, continuing the current one: {:#}", index, e);
+                return;
+            }
+        };
+
+        if let Some(last) = self.metadata.segments.last_mut() {
+            last.samples = self.sample_count - last.first_sample;
+        }
+        // The closed segment's files get the metadata so far
+        let mut closed = self.metadata.clone();
+        closed.end_time = Some(now);
+        closed.total_samples = self.sample_count - health.filled_samples;
+        closed.stream_health = Some(health.clone());
+        let files = file_names(&sinks);
+        writer.rotate(sinks, closed);
+        self.metadata.segments.push(Segment {
+            first_sample: self.sample_count,
+            samples: 0,
+            start_time: now,
+            files,
+        });
+        info!("Segment {} of the trial files starts at sample {}", index, self.sample_count);
+    }
+
+    /// Anchor `marker` to sample `sample_id`, which is written next
+    fn record_marker(&mut self, marker: Marker, sample_id: u64, writer: &mut TrialWriter, scope: Option<&Scope>) {
+        if let Some(scope) = scope {
+            scope.marker(&marker.label);
+        }
+        if let (Some(band_power), true) = (&mut self.band_power, marker.label.starts_with(CUE_PREFIX)) {
+            if !band_power.set_reference() {
+                warn!("Fixation too short for a band power reference, raise --cue-delay");
+            }
+        }
+        if let (Some(erd), true) = (&mut self.erd, marker.label.starts_with(CUE_PREFIX)) {
+            erd.cue();
+        }
+        let event = MarkerRecord {
+            label: marker.label,
+            host_time: marker.host_time,
+            sample_id,
+        };
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.insert_event(&event) {
+                warn!("{} dropped marker '{}': {}", sink.name(), event.label, e);
+            }
+        }
+        writer.push_event(event.clone());
+        self.metadata.markers.push(event);
+    }
+
+    /// Write the data file footer and the metadata; returns the metadata path
+    pub fn finalize(&mut self, output_dir: &str) -> Result<PathBuf> {
+        // Rows written in place of missing samples were not received
+        let filled = self.metadata.stream_health.as_ref().map_or(0, |h| h.filled_samples);
+        let total_samples = self.sample_count - filled;
+        self.metadata.end_time = Some(Utc::now());
+        self.metadata.total_samples = total_samples;
+        if let Some(last) = self.metadata.segments.last_mut() {
+            last.samples = self.sample_count - last.first_sample;
+        }
+
+        info!("Finalizing data collection...");
+        if self.metadata.markers.iter().any(|m| m.label == BAD_TRIAL) {
+            warn!("Trial was flagged as bad during recording, QC will leave it out of training");
+        }
+        info!("Total samples collected: {}", total_samples);
+
+        for sink in &mut self.sinks {
+            sink.finalize(&self.metadata)?;
+        }
+        if let Some(osc) = &self.osc {
+            let (sent, dropped) = osc.counts();
+            info!("OSC: {} band power bundles sent, {} dropped", sent, dropped);
+        }
+
+        // Save metadata in same directory structure as the data file
+        let subject_dir = PathBuf::from(output_dir)
+            .join(&self.metadata.subject_id)
+            .join(&self.metadata.session_id);
+
+        let metadata_filename = format!("{}_{}_trial_{:02}_class_{}_metadata.json",
+                                       self.metadata.subject_id,
+                                       self.metadata.class_label,
+                                       self.metadata.trial_number,
+                                       self.metadata.class_id);
+        let metadata_path = match &self.bids {
+            Some(run) => run.metadata_path(),
+            None => subject_dir.join(metadata_filename),
+        };
+        let data_dir = metadata_path.parent().unwrap_or(Path::new(""));
+        if let Some(erd) = &self.erd {
+            // Earlier rest trials are the baseline of trials without cues
+            let session: Vec<TrialMetadata> = qc::read_trial_metadata(data_dir)?.into_iter().map(|(_, m)| m).collect();
+            self.metadata.erd = erd.finish(&session);
+        }
+        self.metadata.checksums = checksum::data_files(data_dir, &self.metadata)?;
+        let metadata_json = serde_json::to_string_pretty(&self.metadata)?;
+        fs::write(&metadata_path, metadata_json)?;
+        info!("Saved metadata to: {:?}", metadata_path);
+
+        let recording = Recording::load(&metadata_path)?;
+        match &self.bids {
+            Some(run) => {
+                bids::init_dataset(Path::new(output_dir), &self.metadata.subject_id, &run.paradigm)?;
+                run.write_sidecars(&recording)?;
+            }
+            None => {
+                for events_path in events::write_events(&recording)? {
+                    info!("Saved events to: {:?}", events_path);
+                }
+            }
+        }
+        if self.metadata.erd.is_some() {
+            log_quick_look(&self.metadata, data_dir)?;
+        }
+
+        Ok(metadata_path)
+    }
+
+    /// Finalize the live sinks of a `monitor` run, which has no trial files
+    pub fn close(&mut self) -> Result<()> {
+        self.metadata.end_time = Some(Utc::now());
+        self.metadata.total_samples = self.sample_count;
+        info!("Total samples streamed: {}", self.sample_count);
+        for sink in &mut self.sinks {
+            sink.finalize(&self.metadata)?;
+        }
+        Ok(())
+    }
+
+    /// Delete every file `finalize` wrote for the trial
+    pub fn discard(&self, metadata_path: &Path) -> Result<()> {
+        let metadata = &self.metadata;
+        for data_file in metadata.all_data_files() {
+            let data_path = metadata_path.with_file_name(data_file);
+            if data_path.extension().is_some_and(|e| e == "vhdr") {
+                for path in brainvision::companion_paths(&data_path) {
+                    fs::remove_file(path)?;
+                }
+            }
+            fs::remove_file(data_path)?;
+        }
+        if let Some(host_clock) = &metadata.host_clock {
+            let path = metadata_path.with_file_name(&host_clock.file);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        match &self.bids {
+            Some(run) => run.remove_sidecars()?,
+            None => {
+                if let Some(data_file) = &metadata.data_file {
+                    fs::remove_file(events::events_path(&metadata_path.with_file_name(data_file)))?;
+                }
+            }
+        }
+        fs::remove_file(metadata_path)?;
+        Ok(())
+    }
+}
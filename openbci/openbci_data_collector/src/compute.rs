@@ -0,0 +1,197 @@
+//! Resource limits for the offline commands (feature export, baselines,
+//! dataset builds and statistics, epoching, reports).
+//!
+//! Offline analysis often runs on the acquisition machine while a session
+//! is being recorded. Every offline binary flattens [`ComputeArgs`] into
+//! its command line and calls [`ComputeArgs::apply`] first thing, so they
+//! all share the same `--threads`, `--nice` and `--gpu` flags and defaults.
+
+use crate::platform;
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use log::{info, warn};
+use serde::Serialize;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+#[cfg(feature = "gpu")]
+use std::sync::OnceLock;
+
+/// Default niceness: offline work yields to acquisition at nice 0
+pub const DEFAULT_NICE: i32 = 10;
+
+/// Where the kernel driver lists one directory per NVIDIA GPU
+const NVIDIA_GPUS: &str = "/proc/driver/nvidia/gpus";
+
+/// Which CUDA device GPU stages may use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpuSelection {
+    /// Keep the inherited `CUDA_VISIBLE_DEVICES`
+    Auto,
+    /// Hide every GPU
+    None,
+    /// Only this device index
+    Device(u32),
+}
+
+impl fmt::Display for GpuSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => f.write_str("auto"),
+            Self::None => f.write_str("none"),
+            Self::Device(index) => write!(f, "{}", index),
+        }
+    }
+}
+
+impl Serialize for GpuSelection {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for GpuSelection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "none" | "cpu" => Ok(Self::None),
+            other => match other.parse() {
+                Ok(index) => Ok(Self::Device(index)),
+                Err(_) => bail!("GPU '{}' must be auto, none or a device index", s),
+            },
+        }
+    }
+}
+
+/// Command line flags shared by the offline binaries
+#[derive(Args, Debug, Clone)]
+pub struct ComputeArgs {
+    /// Worker threads for parallel stages (defaults to all cores but the
+    /// ones acquisition needs)
+    #[arg(long)]
+    pub threads: Option<usize>,
+
+    /// Scheduling niceness, 0-19; higher yields more to acquisition
+    #[arg(long, default_value_t = DEFAULT_NICE, value_parser = clap::value_parser!(i32).range(0..=19))]
+    pub nice: i32,
+
+    /// CUDA device for GPU stages: auto, none or a device index. Exported
+    /// as CUDA_VISIBLE_DEVICES. With the gpu feature, trained networks
+    /// classify on this wgpu adapter (a discrete GPU index) or the CPU.
+    #[arg(long, default_value = "auto")]
+    pub gpu: GpuSelection,
+}
+
+/// Limits actually in effect, for logs and result files
+#[derive(Debug, Clone, Serialize)]
+pub struct ComputeConfig {
+    pub threads: usize,
+    pub nice: Option<i32>,
+    pub gpu: GpuSelection,
+    pub gpus_detected: Option<usize>,
+}
+
+/// Device trained networks classify on under `gpu`: the GPU when it
+/// works, else the CPU. The first call decides, and logs, for the process.
+#[cfg(feature = "gpu")]
+pub fn inference_device(gpu: GpuSelection) -> eeg_train::Device {
+    static DEVICE: OnceLock<eeg_train::Device> = OnceLock::new();
+    *DEVICE.get_or_init(|| {
+        let device = match gpu {
+            GpuSelection::Auto => eeg_train::Device::Gpu(None),
+            GpuSelection::None => return eeg_train::Device::Cpu,
+            GpuSelection::Device(index) => eeg_train::Device::Gpu(Some(index as usize)),
+        };
+        match eeg_train::device::probe(device) {
+            Ok(()) => {
+                info!("Trained networks classify on the {} through wgpu", device);
+                device
+            }
+            Err(e) => {
+                warn!("Trained networks classify on the CPU: {}", e);
+                eeg_train::Device::Cpu
+            }
+        }
+    })
+}
+
+/// Threads left for offline work: acquisition keeps one core, two on
+/// aarch64 where the collector's workers already take half a Pi
+pub fn default_offline_threads() -> usize {
+    let reserved = if cfg!(target_arch = "aarch64") { 2 } else { 1 };
+    platform::cores().saturating_sub(reserved).max(1)
+}
+
+/// Number of NVIDIA GPUs the driver reports, `None` without the driver
+pub fn detect_gpus() -> Option<usize> {
+    std::fs::read_dir(Path::new(NVIDIA_GPUS)).ok().map(|entries| entries.count())
+}
+
+impl ComputeArgs {
+    /// Apply the limits to this process. Call before any threads are
+    /// spawned: niceness is per thread on Linux and is inherited, and the
+    /// environment is not safe to change once other threads run.
+    pub fn apply(&self) -> Result<ComputeConfig> {
+        let nice = set_nice(self.nice);
+
+        let gpus_detected = detect_gpus();
+        match self.gpu {
+            GpuSelection::Auto => {}
+            GpuSelection::None => std::env::set_var("CUDA_VISIBLE_DEVICES", ""),
+            GpuSelection::Device(index) => {
+                if let Some(count) = gpus_detected.filter(|&count| index as usize >= count) {
+                    bail!("GPU {} requested but only {} detected", index, count);
+                }
+                std::env::set_var("CUDA_VISIBLE_DEVICES", index.to_string());
+            }
+        }
+
+        let threads = self.threads.unwrap_or_else(default_offline_threads).max(1);
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("offline-{}", i))
+            .build_global()
+            .context("Failed to start the worker thread pool")?;
+
+        let config = ComputeConfig {
+            threads,
+            nice,
+            gpu: self.gpu,
+            gpus_detected,
+        };
+        info!(
+            "Compute: {} threads, nice {}, GPU {} ({} detected)",
+            config.threads,
+            config.nice.map_or("unchanged".to_string(), |n| n.to_string()),
+            config.gpu,
+            config.gpus_detected.map_or("none".to_string(), |n| n.to_string())
+        );
+        Ok(config)
+    }
+}
+
+/// Raise the niceness to `nice`; returns the level in effect. Lowering it
+/// needs privileges, so an already nicer process is left alone.
+#[cfg(unix)]
+fn set_nice(nice: i32) -> Option<i32> {
+    // SAFETY: plain syscalls on the calling thread. getpriority returns -1
+    // on error too, which only matters if we already run at -1 (root).
+    let current = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+    if current >= nice {
+        return Some(current);
+    }
+    // SAFETY: as above
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+        warn!("Failed to set nice level {}: {}", nice, std::io::Error::last_os_error());
+        return Some(current);
+    }
+    Some(nice)
+}
+
+#[cfg(not(unix))]
+fn set_nice(_nice: i32) -> Option<i32> {
+    warn!("--nice is not supported on this platform");
+    None
+}
@@ -0,0 +1,293 @@
+//! Experiment configuration files for `--config`.
+//!
+//! One TOML (or YAML) file describes a whole experiment: subject, board,
+//! montage, protocol (paradigm, classes, trials, durations, cues), online filtering,
+//! artifact detection and output formats. Every field is optional and command line flags win
+//! over the file, so a versioned config plus a short command line
+//! reproduces a session. See `experiment.example.toml`.
+
+use crate::montage::{normalize_label, ChannelAssignment, Montage};
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use eeg_dsp::Reference;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// The whole experiment
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExperimentConfig {
+    pub subject_id: Option<String>,
+    pub session_id: Option<String>,
+    pub output_dir: Option<String>,
+    pub board: BoardConfig,
+    pub montage: Option<MontageConfig>,
+    pub protocol: ProtocolConfig,
+    pub filters: FilterConfig,
+    pub artifacts: ArtifactConfig,
+    pub output: OutputConfig,
+}
+
+/// How to reach the board, as the matching command line flags
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BoardConfig {
+    /// `wifi`, `serial`, `ble`, `replay`, `synthetic` or `lsl`
+    pub transport: Option<String>,
+    pub shield_ip: Option<String>,
+    pub local_ip: Option<String>,
+    pub port: Option<u16>,
+    /// Seconds without data before the WiFi stream is restarted, 0 to
+    /// never restart
+    pub stall_timeout: Option<f64>,
+    /// Shield burst mode, for high sample rates
+    pub burst: Option<bool>,
+    pub serial_port: Option<String>,
+    pub ble_name: Option<String>,
+    /// LSL stream to record with `transport = "lsl"`
+    pub stream_name: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<usize>,
+    /// Record the EEG simulator instead of a board
+    pub simulate: Option<bool>,
+    /// Several WiFi shields as one board, `HOST[=PORT]` each
+    pub shields: Vec<String>,
+    /// `merged` or `side-by-side`
+    pub shield_output: Option<String>,
+    /// Subject of each shield, for side-by-side hyperscanning
+    pub shield_subjects: Vec<String>,
+}
+
+/// Electrodes in board channel order, the `[montage]` table of a config or
+/// a standalone `--montage` file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MontageConfig {
+    /// Name of the montage, kept in the trial metadata
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The first on board channel 1
+    pub channels: Vec<ChannelConfig>,
+    #[serde(default = "default_reference")]
+    pub reference: String,
+    #[serde(default = "default_ground")]
+    pub ground: String,
+    /// Re-referencing for export and online classification, e.g.
+    /// `{ kind = "bipolar", pairs = ["C3-Cz", "C4-Cz"] }`
+    #[serde(default)]
+    pub rereference: Option<Reference>,
+}
+
+/// One electrode: a 10-20 label, or `{ position = "C3", name = "..." }`
+/// to name its column
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ChannelConfig {
+    Position(String),
+    Named {
+        position: String,
+        #[serde(default)]
+        name: Option<String>,
+    },
+}
+
+impl ChannelConfig {
+    fn position(&self) -> &str {
+        match self {
+            Self::Position(position) | Self::Named { position, .. } => position,
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        match self {
+            Self::Position(_) => None,
+            Self::Named { name, .. } => name.as_deref(),
+        }
+    }
+}
+
+fn default_reference() -> String {
+    "Cz".to_string()
+}
+
+fn default_ground() -> String {
+    "Fpz".to_string()
+}
+
+impl MontageConfig {
+    /// Read a `.toml`, `.yaml`, `.yml` or `.json` montage file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("Failed to read montage {:?}", path))?;
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+        let parse_error = || format!("Failed to parse montage {:?}", path);
+        Ok(match extension.as_str() {
+            "toml" => toml::from_str(&text).with_context(parse_error)?,
+            "yaml" | "yml" => serde_yaml_ng::from_str(&text).with_context(parse_error)?,
+            "json" => serde_json::from_str(&text).with_context(parse_error)?,
+            _ => bail!("Montage {:?} must be .toml, .yaml, .yml or .json", path),
+        })
+    }
+
+    /// The montage as the wizard would save it, unverified
+    pub fn montage(&self) -> Result<Montage> {
+        let channels = self
+            .channels
+            .iter()
+            .enumerate()
+            .map(|(i, channel)| {
+                let label = normalize_label(channel.position()).with_context(|| {
+                    format!("Montage channel {} label '{}' is not a 10-20 position", i + 1, channel.position())
+                })?;
+                Ok(ChannelAssignment {
+                    channel: i + 1,
+                    label: label.to_string(),
+                    name: channel.name().map(|n| n.trim().to_string()),
+                    verified: false,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let montage = Montage {
+            name: self.name.clone(),
+            channels,
+            reference: self.reference.clone(),
+            ground: self.ground.clone(),
+            rereference: self.rereference.clone(),
+            confirmed_at: Utc::now(),
+        };
+        montage.validate()?;
+        Ok(montage)
+    }
+}
+
+/// Which trials to record and how each one runs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProtocolConfig {
+    /// Paradigm file with the classes and trial structure, relative to the
+    /// config file (motor imagery if omitted)
+    pub paradigm: Option<PathBuf>,
+    /// Classes of the session, e.g. `["left_hand", "right_hand", "rest"]`
+    pub classes: Vec<String>,
+    pub trials_per_class: Option<u32>,
+    /// Seconds per trial, cue delay included
+    pub duration: Option<u64>,
+    /// Seconds between trials
+    pub rest_seconds: Option<f64>,
+    /// Randomize the class order within each round of trials
+    pub shuffle: Option<bool>,
+    /// Seed of the class order (random if omitted)
+    pub seed: Option<u64>,
+    pub cues: Option<bool>,
+    pub cue_delay: Option<f64>,
+    pub cue_beep: Option<bool>,
+    pub keys: Option<bool>,
+    /// Show the live scope while recording
+    pub scope: Option<bool>,
+    /// Check the signal before every trial
+    pub signal_check: Option<bool>,
+    /// Print the ERD quick-look after every trial
+    pub quick_look: Option<bool>,
+    /// Record a bad trial again at the end of the session
+    pub replace_bad_trials: Option<bool>,
+    /// Most trials a session records in place of bad ones
+    pub max_replacements: Option<u32>,
+}
+
+/// Online cleaning
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    /// Regress blinks out with the session's EOG calibration
+    pub eog_regression: Option<bool>,
+    /// Labels of the EOG channels, Fp1 and Fp2 by default
+    pub eog_channels: Option<Vec<String>>,
+    pub asr: Option<bool>,
+    pub asr_cutoff: Option<f64>,
+    /// Pass band in Hz, e.g. `[1.0, 40.0]`
+    pub bandpass: Option<[f64; 2]>,
+    /// Power line frequency to notch out
+    pub notch: Option<f64>,
+    /// Re-reference to the common average
+    pub car: Option<bool>,
+}
+
+/// Online artifact detection, in µV
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArtifactConfig {
+    pub detect: Option<bool>,
+    pub max_amplitude: Option<f64>,
+    pub flat_amplitude: Option<f64>,
+    pub max_gradient: Option<f64>,
+    /// `mark`, `abort` or `repeat`
+    pub on_artifact: Option<String>,
+    pub max_repeats: Option<u32>,
+}
+
+/// Where and how trials are written
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    /// `csv`, `bdf`, `edf`, `npz` or `brainvision`; the first is primary
+    pub format: Vec<String>,
+    /// `zstd` or `gzip`
+    pub compress: Option<String>,
+    /// `nan` or `interpolate`
+    pub fill_gaps: Option<String>,
+    /// Rotate the trial files every this many minutes
+    pub segment_minutes: Option<f64>,
+    /// Warn below this much free disk space
+    pub min_free_mb: Option<u64>,
+    pub bids: Option<bool>,
+    pub line_frequency: Option<f64>,
+    /// Relative to the config file
+    pub qc_config: Option<PathBuf>,
+    /// Publish Lab Streaming Layer outlets
+    pub lsl: Option<bool>,
+    pub lsl_name: Option<String>,
+    /// Serve the live stream to WebSocket clients on this port
+    pub ws_port: Option<u16>,
+    /// Address the WebSocket server listens on
+    pub ws_bind: Option<IpAddr>,
+    /// Publish the live stream on a ZeroMQ PUB socket at this endpoint
+    pub zmq_pub: Option<String>,
+    /// Send to an OSC receiver at this address, e.g. `192.168.4.50:9002`
+    pub osc: Option<SocketAddr>,
+    pub osc_address: Option<String>,
+    /// `band-power` or `samples`
+    pub osc_data: Option<String>,
+}
+
+/// Which config a trial was recorded with, kept in its metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExperimentInfo {
+    pub config_file: String,
+    pub sha256: String,
+}
+
+impl ExperimentConfig {
+    /// Read a `.toml`, `.yaml` or `.yml` file. Relative paths in it are
+    /// resolved against the file's directory.
+    pub fn load(path: &Path) -> Result<(Self, ExperimentInfo)> {
+        let text = fs::read_to_string(path).with_context(|| format!("Failed to read config {:?}", path))?;
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+        let mut config: Self = match extension.as_str() {
+            "toml" => toml::from_str(&text).with_context(|| format!("Failed to parse config {:?}", path))?,
+            "yaml" | "yml" => serde_yaml_ng::from_str(&text).with_context(|| format!("Failed to parse config {:?}", path))?,
+            _ => bail!("Config {:?} must be .toml, .yaml or .yml", path),
+        };
+        for file in [&mut config.output.qc_config, &mut config.protocol.paradigm].into_iter().flatten() {
+            if file.is_relative() {
+                *file = path.parent().unwrap_or(Path::new("")).join(&*file);
+            }
+        }
+        let info = ExperimentInfo {
+            config_file: path.display().to_string(),
+            sha256: format!("{:x}", Sha256::digest(text.as_bytes())),
+        };
+        Ok((config, info))
+    }
+}
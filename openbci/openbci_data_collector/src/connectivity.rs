@@ -0,0 +1,227 @@
+//! Channel-pair connectivity: phase-locking value and magnitude-squared
+//! coherence per frequency band.
+//!
+//! Both are estimated from the same Hann-windowed, half-overlapping
+//! one-second segments as the band powers, so a trial needs a few seconds
+//! of data (several segments) for the values to mean anything; with a
+//! single segment both are trivially 1.
+
+use anyhow::{bail, Context, Result};
+use eeg_dsp::{segment_spectra, Band, Complex64, SpectralParams};
+use log::{debug, info, warn};
+use ndarray::{aview1, ArrayView1};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use tokio::task::JoinHandle;
+
+/// Window the online monitor estimates over
+pub const ONLINE_WINDOW_SECONDS: f64 = 4.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectivityMetric {
+    /// Phase-locking value: consistency of the phase difference across
+    /// segments, ignoring amplitude
+    Plv,
+    /// Magnitude-squared coherence
+    Coherence,
+}
+
+impl ConnectivityMetric {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Plv => "plv",
+            Self::Coherence => "coh",
+        }
+    }
+}
+
+impl fmt::Display for ConnectivityMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ConnectivityMetric {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "plv" => Ok(Self::Plv),
+            "coh" | "coherence" | "msc" => Ok(Self::Coherence),
+            _ => bail!("Unknown connectivity metric '{}', expected plv or coherence", s),
+        }
+    }
+}
+
+/// Every unordered channel pair `(i, j)` with `i < j`
+pub fn channel_pairs(num_channels: usize) -> Vec<(usize, usize)> {
+    (0..num_channels)
+        .flat_map(|i| (i + 1..num_channels).map(move |j| (i, j)))
+        .collect()
+}
+
+/// Feature names matching [`connectivity`]'s order, e.g. `C3-C4_mu_plv`
+pub fn feature_names(channel_names: &[String], bands: &[Band], metrics: &[ConnectivityMetric]) -> Vec<String> {
+    let mut names = Vec::new();
+    for (i, j) in channel_pairs(channel_names.len()) {
+        for band in bands {
+            for metric in metrics {
+                names.push(format!("{}-{}_{}_{}", channel_names[i], channel_names[j], band.name, metric));
+            }
+        }
+    }
+    names
+}
+
+/// Connectivity of every channel pair, ordered pair-major, then band, then
+/// metric. Each value is the mean over the frequency bins in the band.
+pub fn connectivity(
+    channels: &[Vec<f32>],
+    sample_rate: f64,
+    bands: &[Band],
+    metrics: &[ConnectivityMetric],
+) -> Result<Vec<f64>> {
+    let len = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+    // One-second segments give 1 Hz resolution
+    let params = SpectralParams::new(sample_rate, (sample_rate.round() as usize).min(len));
+    let spectra = channels
+        .iter()
+        .map(|c| segment_spectra(aview1(&c[..len]), &params))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Cannot estimate connectivity")?;
+    let freqs = params.frequencies();
+    let band_bins: Vec<Vec<usize>> = bands
+        .iter()
+        .map(|band| {
+            (0..freqs.len())
+                .filter(|&k| freqs[k] >= band.low && freqs[k] < band.high)
+                .collect()
+        })
+        .collect();
+
+    let mut values = Vec::new();
+    for (i, j) in channel_pairs(channels.len()) {
+        for bins in &band_bins {
+            for metric in metrics {
+                let per_bin: Vec<f64> = bins
+                    .iter()
+                    .map(|&k| match metric {
+                        ConnectivityMetric::Plv => plv(spectra[i].column(k), spectra[j].column(k)),
+                        ConnectivityMetric::Coherence => coherence(spectra[i].column(k), spectra[j].column(k)),
+                    })
+                    .collect();
+                values.push(per_bin.iter().sum::<f64>() / per_bin.len().max(1) as f64);
+            }
+        }
+    }
+    Ok(values)
+}
+
+/// Length of the mean unit phasor of the cross spectrum over segments
+fn plv(x: ArrayView1<Complex64>, y: ArrayView1<Complex64>) -> f64 {
+    let (mut sum, mut n) = (Complex64::new(0.0, 0.0), 0usize);
+    for (a, b) in x.iter().zip(y) {
+        let cross = a * b.conj();
+        let magnitude = cross.norm();
+        if magnitude > 0.0 {
+            sum += cross / magnitude;
+            n += 1;
+        }
+    }
+    if n == 0 {
+        return 0.0;
+    }
+    sum.norm() / n as f64
+}
+
+/// `|Sxy|^2 / (Sxx * Syy)`, spectra averaged over segments
+fn coherence(x: ArrayView1<Complex64>, y: ArrayView1<Complex64>) -> f64 {
+    let (mut sxy, mut pxx, mut pyy) = (Complex64::new(0.0, 0.0), 0.0, 0.0);
+    for (a, b) in x.iter().zip(y) {
+        sxy += a * b.conj();
+        pxx += a.norm_sqr();
+        pyy += b.norm_sqr();
+    }
+    let denominator = pxx * pyy;
+    if denominator <= 0.0 {
+        return 0.0;
+    }
+    sxy.norm_sqr() / denominator
+}
+
+/// Slow-rate connectivity over a sliding window of the live stream. The
+/// estimate runs on a blocking task so the sample reader never waits on it.
+pub struct ConnectivityMonitor {
+    sample_rate: f64,
+    bands: Vec<Band>,
+    metrics: Vec<ConnectivityMetric>,
+    names: Vec<String>,
+    window: usize,
+    every: usize,
+    buffers: Vec<VecDeque<f32>>,
+    since_last: usize,
+    task: Option<JoinHandle<()>>,
+}
+
+impl ConnectivityMonitor {
+    /// Estimate over the last [`ONLINE_WINDOW_SECONDS`] every
+    /// `every_seconds`
+    pub fn new(
+        channel_names: &[String],
+        sample_rate: u32,
+        bands: Vec<Band>,
+        metrics: Vec<ConnectivityMetric>,
+        every_seconds: f64,
+    ) -> Self {
+        let window = (ONLINE_WINDOW_SECONDS * sample_rate as f64) as usize;
+        Self {
+            sample_rate: sample_rate as f64,
+            names: feature_names(channel_names, &bands, &metrics),
+            bands,
+            metrics,
+            window,
+            every: ((every_seconds * sample_rate as f64) as usize).max(1),
+            buffers: vec![VecDeque::with_capacity(window); channel_names.len()],
+            since_last: 0,
+            task: None,
+        }
+    }
+
+    /// Add one sample; once the window is full and an update is due, a
+    /// copy of the window is estimated in the background and logged. An
+    /// update is skipped while the previous one is still running.
+    pub fn push(&mut self, sample: &[f32]) {
+        for (buffer, &value) in self.buffers.iter_mut().zip(sample) {
+            if buffer.len() == self.window {
+                buffer.pop_front();
+            }
+            buffer.push_back(value);
+        }
+        self.since_last += 1;
+        if self.since_last < self.every || self.buffers.first().is_none_or(|b| b.len() < self.window) {
+            return;
+        }
+        self.since_last = 0;
+        if self.task.as_ref().is_some_and(|t| !t.is_finished()) {
+            debug!("Previous connectivity estimate still running, skipping this one");
+            return;
+        }
+
+        let channels: Vec<Vec<f32>> = self.buffers.iter().map(|b| b.iter().copied().collect()).collect();
+        let (sample_rate, bands, metrics, names) =
+            (self.sample_rate, self.bands.clone(), self.metrics.clone(), self.names.clone());
+        self.task = Some(tokio::task::spawn_blocking(move || {
+            match connectivity(&channels, sample_rate, &bands, &metrics) {
+                Ok(values) => {
+                    let summary: Vec<String> =
+                        names.iter().zip(values).map(|(name, v)| format!("{}={:.2}", name, v)).collect();
+                    info!("Connectivity: {}", summary.join(" "));
+                }
+                Err(e) => warn!("{:#}", e),
+            }
+        }));
+    }
+}
@@ -0,0 +1,511 @@
+//! Self-describing model files.
+//!
+//! A `.bcimodel` container bundles any [`Classifier`] (an exported ONNX
+//! network, a network from `openbci_train` or a CSP+LDA calibration) with
+//! everything needed to feed it correctly: the input shape, sample rate and
+//! channel order, the online filter chain its training data went through,
+//! the normalization statistics, the class names and a hash of the training
+//! data. The file is `OPENBCI-MODEL\n`, the metadata JSON preceded by its
+//! length as a little-endian u32, then the bundled files back to back in the
+//! order and with the checksums the metadata lists. `openbci model pack`
+//! writes one, `openbci model inspect` and `openbci model list` read them
+//! back, and `openbci_online_bci` refuses a stream whose sample rate,
+//! montage or filters do not match.
+
+use crate::checksum::FileChecksum;
+use crate::classifier::Classifier;
+use crate::filter::Passband;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::Args;
+use eeg_dsp::Standardization;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File extension of model containers
+pub const EXTENSION: &str = "bcimodel";
+
+/// Version written by this build, and the newest it reads
+pub const FORMAT_VERSION: u32 = 1;
+
+const MAGIC: &[u8] = b"OPENBCI-MODEL\n";
+
+/// Names of the bundled files
+const ONNX: &str = "model.onnx";
+const WEIGHTS: &str = "model.mpk";
+const SPEC: &str = "model.json";
+const CALIBRATION: &str = "calibration_model.json";
+
+/// Options of `openbci model pack`
+#[derive(Args, Debug, Clone)]
+pub struct PackArgs {
+    /// Model to bundle: an `.onnx` or `.mpk` network with its spec next to
+    /// it, or a calibration_model.json
+    pub model: PathBuf,
+
+    /// Container to write [default: the model's path with .bcimodel]
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Band-pass the training data went through, low-high in Hz
+    #[arg(long)]
+    pub bandpass: Option<Passband>,
+
+    /// Notch the training data went through, in Hz
+    #[arg(long)]
+    pub notch: Option<f64>,
+
+    /// The training data was common average referenced
+    #[arg(long)]
+    pub car: bool,
+
+    /// Dataset manifest the model was trained on, hashed to identify its
+    /// training data
+    #[arg(long)]
+    pub manifest: Option<PathBuf>,
+
+    /// Replace --output if it exists
+    #[arg(long)]
+    pub overwrite: bool,
+}
+
+/// Options of `openbci model inspect`
+#[derive(Args, Debug, Clone)]
+pub struct InspectArgs {
+    /// Container to describe
+    pub model: PathBuf,
+
+    /// Print the metadata as JSON to stdout instead
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Options of `openbci model list`
+#[derive(Args, Debug, Clone)]
+pub struct ListArgs {
+    /// Directory of containers
+    #[arg(default_value = ".")]
+    pub dir: PathBuf,
+}
+
+/// Which kind of classifier a container holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelKind {
+    /// An exported `.onnx` network and its spec
+    Onnx,
+    /// `.mpk` weights from `openbci_train` and their spec
+    Trained,
+    /// A CSP+LDA `calibration_model.json`
+    Calibration,
+}
+
+impl ModelKind {
+    /// The kind of the model file at `path`, by extension as in
+    /// [`Classifier::open`]
+    fn of(path: &Path) -> Self {
+        match path.extension().map(|e| e.to_string_lossy().to_lowercase()).as_deref() {
+            Some("onnx") => Self::Onnx,
+            Some("mpk") => Self::Trained,
+            _ => Self::Calibration,
+        }
+    }
+
+    /// Bundled file names, the model first
+    fn files(self) -> &'static [&'static str] {
+        match self {
+            Self::Onnx => &[ONNX, SPEC],
+            Self::Trained => &[WEIGHTS, SPEC],
+            Self::Calibration => &[CALIBRATION],
+        }
+    }
+}
+
+impl fmt::Display for ModelKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Onnx => "onnx",
+            Self::Trained => "trained",
+            Self::Calibration => "calibration",
+        })
+    }
+}
+
+/// Online filters the model's training data went through, in the order
+/// [`crate::filter::OnlineFilter`] applies them
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FilterChain {
+    /// Band-pass edges in Hz
+    pub bandpass: Option<[f64; 2]>,
+    /// Notch in Hz
+    pub notch: Option<f64>,
+    /// Common average reference over the stream's channels
+    pub car: bool,
+}
+
+impl FilterChain {
+    pub fn new(bandpass: Option<Passband>, notch: Option<f64>, car: bool) -> Self {
+        Self { bandpass: bandpass.map(|band| [band.low, band.high]), notch, car }
+    }
+
+    pub fn passband(&self) -> Option<Passband> {
+        self.bandpass.map(|[low, high]| Passband { low, high })
+    }
+
+    /// The chain to run on a stream configured with `bandpass`, `notch` and
+    /// `car`: what the configuration leaves out is taken from the model,
+    /// and anything it sets differently is refused
+    pub fn resolve(&self, bandpass: Option<Passband>, notch: Option<f64>, car: bool) -> Result<Self> {
+        let requested = Self::new(bandpass, notch, car);
+        if requested.bandpass.is_some() && requested.bandpass != self.bandpass {
+            bail!("The model was trained with band-pass {}, not {}", self.describe_bandpass(), requested.describe_bandpass());
+        }
+        if requested.notch.is_some() && requested.notch != self.notch {
+            bail!("The model was trained with notch {}, not {}", self.describe_notch(), requested.describe_notch());
+        }
+        if requested.car && !self.car {
+            bail!("The model was trained without a common average reference");
+        }
+        Ok(self.clone())
+    }
+
+    fn describe_bandpass(&self) -> String {
+        self.bandpass.map_or("off".to_string(), |[low, high]| format!("{}-{} Hz", low, high))
+    }
+
+    fn describe_notch(&self) -> String {
+        self.notch.map_or("off".to_string(), |hz| format!("{} Hz", hz))
+    }
+}
+
+impl fmt::Display for FilterChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "band-pass {}, notch {}, common average reference {}",
+            self.describe_bandpass(),
+            self.describe_notch(),
+            if self.car { "on" } else { "off" }
+        )
+    }
+}
+
+/// What a container says about its model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelMetadata {
+    pub format_version: u32,
+    pub kind: ModelKind,
+    pub created: DateTime<Utc>,
+    /// Input shape: channels x window samples
+    pub channels: usize,
+    pub window: usize,
+    pub sample_rate: f64,
+    /// Montage labels in input order
+    pub channel_names: Vec<String>,
+    pub filters: FilterChain,
+    /// Per-channel statistics the network standardizes windows with; part
+    /// of the network, so shown rather than applied again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normalization: Option<Standardization>,
+    /// Class names in probability order
+    pub classes: Vec<String>,
+    /// SHA-256 of the dataset manifest the model was trained on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub training_hash: Option<String>,
+    /// Bundled files, in the order they follow the metadata
+    pub files: Vec<FileChecksum>,
+}
+
+/// A model with its metadata
+#[derive(Debug, Clone)]
+pub struct ModelContainer {
+    pub metadata: ModelMetadata,
+    files: Vec<Vec<u8>>,
+}
+
+impl ModelContainer {
+    /// Bundle the model at `path` (with its spec, for networks), trained on
+    /// data filtered by `filters`
+    pub fn pack(path: &Path, filters: FilterChain, training_hash: Option<String>) -> Result<Self> {
+        if is_container(path) {
+            bail!("{:?} is already a model container", path);
+        }
+        let kind = ModelKind::of(path);
+        let model = Classifier::open(path).with_context(|| format!("Failed to load {:?}", path))?;
+        if let Classifier::Ssvep(_) = model {
+            bail!("{:?} is an SSVEP paradigm, which carries its own settings and is used as it is", path);
+        }
+        let sample_rate = model
+            .sample_rate()
+            .with_context(|| format!("{:?} does not say its sample rate; add sample_rate to its spec", path))?;
+        if model.channel_names().is_empty() {
+            bail!("{:?} does not name its channels; add channel_names to its spec", path);
+        }
+        let mut checksums = Vec::new();
+        let mut files = Vec::new();
+        for (i, &name) in kind.files().iter().enumerate() {
+            let source = if i == 0 { path.to_path_buf() } else { spec_path(path) };
+            let bytes = fs::read(&source).with_context(|| format!("Failed to read {:?}", source))?;
+            checksums.push(FileChecksum { file: name.to_string(), bytes: bytes.len() as u64, sha256: sha256(&bytes) });
+            files.push(bytes);
+        }
+        let normalization = match &model {
+            #[cfg(feature = "train")]
+            Classifier::Trained(model) => Some(model.spec().standardization.clone()),
+            _ => None,
+        };
+        let metadata = ModelMetadata {
+            format_version: FORMAT_VERSION,
+            kind,
+            created: Utc::now(),
+            channels: model.channels(),
+            window: model.window(),
+            sample_rate,
+            channel_names: model.channel_names().to_vec(),
+            filters,
+            normalization,
+            classes: model.classes(),
+            training_hash,
+            files: checksums,
+        };
+        Ok(Self { metadata, files })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let header = serde_json::to_vec_pretty(&self.metadata)?;
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&header);
+        for file in &self.files {
+            bytes.extend_from_slice(file);
+        }
+        fs::write(path, bytes).with_context(|| format!("Failed to write {:?}", path))
+    }
+
+    /// Read a container, checking its version and every file's checksum
+    pub fn open(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let rest = bytes.strip_prefix(MAGIC).with_context(|| format!("{:?} is not a model container", path))?;
+        let (length, rest) = rest.split_at_checked(4).with_context(|| format!("{:?} is truncated", path))?;
+        let length = u32::from_le_bytes(length.try_into().expect("four bytes")) as usize;
+        let (header, mut data) = rest.split_at_checked(length).with_context(|| format!("{:?} is truncated", path))?;
+        let metadata: ModelMetadata =
+            serde_json::from_slice(header).with_context(|| format!("{:?} has unreadable metadata", path))?;
+        if metadata.format_version > FORMAT_VERSION {
+            bail!(
+                "{:?} is model format {}, this build reads up to {}; update the collector",
+                path,
+                metadata.format_version,
+                FORMAT_VERSION
+            );
+        }
+        let mut files = Vec::new();
+        for file in &metadata.files {
+            let (bytes, tail) =
+                data.split_at_checked(file.bytes as usize).with_context(|| format!("{:?} is truncated in {}", path, file.file))?;
+            if sha256(bytes) != file.sha256 {
+                bail!("{} in {:?} does not match its checksum", file.file, path);
+            }
+            files.push(bytes.to_vec());
+            data = tail;
+        }
+        if !data.is_empty() {
+            bail!("{:?} has {} bytes after its files", path, data.len());
+        }
+        Ok(Self { metadata, files })
+    }
+
+    fn file(&self, name: &str) -> Result<&[u8]> {
+        self.metadata
+            .files
+            .iter()
+            .position(|file| file.file == name)
+            .map(|i| self.files[i].as_slice())
+            .with_context(|| format!("The container has no {}", name))
+    }
+
+    /// Load the bundled model, checked against the metadata; `source` names
+    /// the container in errors
+    pub fn classifier(&self, source: &Path) -> Result<Classifier> {
+        let model = self.load(source)?;
+        let metadata = &self.metadata;
+        if model.channel_names() != metadata.channel_names
+            || model.window() != metadata.window
+            || model.classes() != metadata.classes
+            || model.sample_rate().is_some_and(|rate| rate != metadata.sample_rate)
+        {
+            bail!("The model in {:?} does not match the container's metadata", source);
+        }
+        Ok(model)
+    }
+
+    fn load(&self, source: &Path) -> Result<Classifier> {
+        match self.metadata.kind {
+            ModelKind::Onnx => {
+                #[cfg(feature = "inference")]
+                return Ok(Classifier::Onnx(eeg_inference::OnnxModel::from_bytes(
+                    source,
+                    self.file(ONNX)?,
+                    serde_json::from_slice(self.file(SPEC)?)?,
+                )?));
+                #[cfg(not(feature = "inference"))]
+                bail!("ONNX support not compiled in, rebuild with --features inference");
+            }
+            ModelKind::Trained => {
+                #[cfg(feature = "train")]
+                return Ok(Classifier::Trained(eeg_train::TrainedModel::from_bytes(
+                    serde_json::from_slice(self.file(SPEC)?)?,
+                    self.file(WEIGHTS)?.to_vec(),
+                )?));
+                #[cfg(not(feature = "train"))]
+                bail!("Trained network support not compiled in, rebuild with --features train");
+            }
+            ModelKind::Calibration => Ok(Classifier::Calibrated(
+                serde_json::from_slice(self.file(CALIBRATION)?)
+                    .with_context(|| format!("{:?} holds no readable calibration model", source))?,
+            )),
+        }
+    }
+}
+
+/// Write the container of `args.model`
+pub fn pack(args: &PackArgs) -> Result<ModelContainer> {
+    let output = args.output.clone().unwrap_or_else(|| args.model.with_extension(EXTENSION));
+    if !is_container(&output) {
+        bail!("{:?} must end in .{}", output, EXTENSION);
+    }
+    if output.exists() && !args.overwrite {
+        bail!("{:?} exists; pass --overwrite to replace it", output);
+    }
+    let training_hash = args.manifest.as_deref().map(training_hash).transpose()?;
+    if training_hash.is_none() {
+        warn!("No --manifest, so the container does not say what data the model was trained on");
+    }
+    let container = ModelContainer::pack(&args.model, FilterChain::new(args.bandpass, args.notch, args.car), training_hash)?;
+    container.save(&output)?;
+    let metadata = &container.metadata;
+    info!(
+        "Packed {:?} into {:?}: {} model, {} x {} at {} Hz, {}",
+        args.model, output, metadata.kind, metadata.channels, metadata.window, metadata.sample_rate, metadata.filters
+    );
+    Ok(container)
+}
+
+/// Describe one container
+pub fn inspect(args: &InspectArgs) -> Result<()> {
+    let container = ModelContainer::open(&args.model)?;
+    let metadata = &container.metadata;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(metadata)?);
+        return Ok(());
+    }
+    info!("{:?}: format {}, created {}", args.model, metadata.format_version, metadata.created.to_rfc3339());
+    info!("  Model:         {}", container.classifier(&args.model)?.describe());
+    info!(
+        "  Input:         {} channels x {} samples ({:.2} s) at {} Hz",
+        metadata.channels,
+        metadata.window,
+        metadata.window as f64 / metadata.sample_rate,
+        metadata.sample_rate
+    );
+    info!("  Channels:      {}", metadata.channel_names.join(", "));
+    info!("  Filters:       {}", metadata.filters);
+    match &metadata.normalization {
+        Some(stats) => {
+            let channels = metadata.channel_names.iter().zip(stats.center.iter().zip(&stats.scale));
+            let stats: Vec<String> =
+                channels.map(|(name, (center, scale))| format!("{} {:.2}/{:.2}", name, center, scale)).collect();
+            info!("  Normalization: center/scale {}", stats.join(", "));
+        }
+        None => info!("  Normalization: none"),
+    }
+    info!("  Classes:       {}", metadata.classes.join(", "));
+    info!("  Training data: {}", metadata.training_hash.as_deref().unwrap_or("unknown"));
+    for file in &metadata.files {
+        info!("  File:          {} ({} bytes, sha256 {})", file.file, file.bytes, file.sha256);
+    }
+    Ok(())
+}
+
+/// Tabulate the containers of a directory, a registry of the models
+/// available to a rig
+pub fn list(args: &ListArgs) -> Result<()> {
+    let mut rows = Vec::new();
+    for path in find(&args.dir)? {
+        match ModelContainer::open(&path) {
+            Ok(container) => rows.push((path.file_name().unwrap_or_default().to_string_lossy().to_string(), container.metadata)),
+            Err(e) => warn!("Skipping {:?}: {:#}", path, e),
+        }
+    }
+    if rows.is_empty() {
+        info!("No .{} files in {:?}", EXTENSION, args.dir);
+        return Ok(());
+    }
+    let mut text = String::new();
+    let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0).max(5);
+    // Writing to a String cannot fail
+    let _ = writeln!(
+        text,
+        "{:<width$}  {:<11}  {:>5}  {:>8}  {:<10}  {:<12}  {:<16}  Classes",
+        "Model", "Kind", "Rate", "Input", "Created", "Training", "Filters"
+    );
+    for (name, metadata) in &rows {
+        let filters = [
+            metadata.filters.bandpass.map(|[low, high]| format!("{}-{}", low, high)),
+            metadata.filters.notch.map(|hz| format!("n{}", hz)),
+            metadata.filters.car.then(|| "car".to_string()),
+        ];
+        let filters: Vec<String> = filters.into_iter().flatten().collect();
+        let _ = writeln!(
+            text,
+            "{:<width$}  {:<11}  {:>5}  {:>8}  {:<10}  {:<12}  {:<16}  {}",
+            name,
+            metadata.kind.to_string(),
+            metadata.sample_rate,
+            format!("{}x{}", metadata.channels, metadata.window),
+            metadata.created.format("%Y-%m-%d"),
+            metadata.training_hash.as_deref().map_or("unknown", |hash| &hash[..hash.len().min(12)]),
+            if filters.is_empty() { "none".to_string() } else { filters.join(" ") },
+            metadata.classes.join(", ")
+        );
+    }
+    for line in text.lines() {
+        info!("{}", line);
+    }
+    Ok(())
+}
+
+/// Whether `path` is named like a model container
+pub fn is_container(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e.eq_ignore_ascii_case(EXTENSION))
+}
+
+/// Containers directly in `dir`, sorted by name
+pub fn find(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to list {:?}", dir))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && is_container(path))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// SHA-256 of a training manifest, identifying the data a model saw
+pub fn training_hash(manifest: &Path) -> Result<String> {
+    let bytes = fs::read(manifest).with_context(|| format!("Failed to read {:?}", manifest))?;
+    Ok(sha256(&bytes))
+}
+
+/// The spec exported or saved next to a network
+fn spec_path(path: &Path) -> PathBuf {
+    path.with_extension("json")
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
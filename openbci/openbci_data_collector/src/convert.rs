@@ -0,0 +1,218 @@
+//! Recorded trials rewritten in other formats, next to their data file or
+//! into a copy of the tree. Run as `openbci convert`.
+
+use crate::checksum;
+use crate::compress::{self, Compression};
+use crate::compute::ComputeArgs;
+use crate::events;
+use crate::qc;
+use crate::recording::{self, Recording};
+use crate::sink::{open_sink, write_rows, OutputFormat};
+use anyhow::{Context, Result};
+use log::{error, info};
+use rayon::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Options of `openbci convert`
+#[derive(clap::Args, Debug, Clone)]
+pub struct ConvertArgs {
+    /// Trials to convert: metadata JSON, a data file, or a subject,
+    /// session or dataset directory to convert every trial under
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// Formats to write, comma separated; the first becomes the trial's
+    /// data file with --output-dir
+    #[arg(long, value_enum, value_delimiter = ',', required = true)]
+    pub format: Vec<OutputFormat>,
+
+    /// Compress the new CSV and NPZ files with zstd or gzip
+    #[arg(long, value_name = "CODEC")]
+    pub compress: Option<Compression>,
+
+    /// Write the converted trials, with their metadata and events, into a
+    /// copy of the directory tree under DIR instead of next to the source
+    #[arg(short, long, value_name = "DIR")]
+    pub output_dir: Option<PathBuf>,
+
+    /// Replace converted files that already exist (never the file a trial
+    /// is read from)
+    #[arg(long)]
+    pub overwrite: bool,
+
+    /// Also convert trials from sessions that failed QC (directories only)
+    #[arg(long)]
+    pub include_failed_qc: bool,
+
+    #[command(flatten)]
+    pub compute: ComputeArgs,
+}
+
+/// Write every trial under `--paths` in the `--format`s, next to the
+/// source or into `--output-dir`
+pub fn run(args: &ConvertArgs) -> Result<()> {
+    if args.format.contains(&OutputFormat::Hdf5) && !cfg!(feature = "hdf5") {
+        anyhow::bail!("HDF5 support not compiled in, rebuild with --features hdf5 (needs libhdf5)");
+    }
+    let compute = args.compute.apply()?;
+    let (mut converted, mut files, mut current, mut failed) = (0, 0, 0, 0);
+    // Session directories written to, with their source, to redo their QC
+    // and checksums in
+    let mut sessions = std::collections::BTreeMap::new();
+    let mut jobs = Vec::new();
+    for input in &args.paths {
+        // Trials keep their place relative to the directory they were found in
+        let (base, trials) = if input.is_dir() {
+            (input.as_path(), recording::find_trials(input, args.include_failed_qc)?)
+        } else {
+            (input.parent().unwrap_or(Path::new("")), vec![input.clone()])
+        };
+        for path in trials {
+            let dest = args.output_dir.as_ref().map(|out| {
+                let dir = path.parent().unwrap_or(Path::new(""));
+                out.join(dir.strip_prefix(base).unwrap_or(Path::new("")))
+            });
+            if let Some(dir) = path.parent() {
+                sessions.insert(dest.clone().unwrap_or_else(|| dir.to_path_buf()), dir.to_path_buf());
+            }
+            jobs.push((path, dest));
+        }
+    }
+
+    for batch in jobs.chunks(compute.threads) {
+        let results: Vec<_> = batch.par_iter().map(|(path, dest)| (path, convert_trial(path, dest.as_deref(), args))).collect();
+        for (path, result) in results {
+            match result {
+                Ok(written) if written.is_empty() => {
+                    info!("{:?}: already converted", path);
+                    current += 1;
+                }
+                Ok(written) => {
+                    info!("{:?}: wrote {}", path, written.join(", "));
+                    converted += 1;
+                    files += written.len();
+                }
+                Err(e) => {
+                    error!("{:?}: {:#}", path, e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    // The copies are judged by the criteria the originals were
+    for (dest, source) in &sessions {
+        if source.join(qc::SESSION_MANIFEST).exists() && dest.is_dir() {
+            let criteria = qc::SessionManifest::load(source)?.qc.criteria;
+            qc::finalize_session(dest, &criteria)?;
+        }
+    }
+
+    info!(
+        "Converted {} trials ({} files), {} already converted, {} failed",
+        converted, files, current, failed
+    );
+    if failed > 0 {
+        anyhow::bail!("{} of {} trials could not be converted", failed, converted + current + failed);
+    }
+    if converted + current == 0 {
+        anyhow::bail!("No trials found");
+    }
+    Ok(())
+}
+
+/// Write one trial in the requested formats, next to the file it was read
+/// from or into `dest`, and list the new files in its metadata. Every new
+/// file is read back before it counts; returns their names.
+fn convert_trial(path: &Path, dest: Option<&Path>, args: &ConvertArgs) -> Result<Vec<String>> {
+    let Recording {
+        metadata_path,
+        data_path,
+        mut metadata,
+        channel_names,
+        timestamps,
+        samples,
+        markers,
+    } = Recording::open(path)?;
+    let source = compress::uncompressed_path(&data_path);
+    let segmented = !metadata.segments.is_empty();
+    let stem = match dest {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            let name = source.file_stem().context("Data file without a name")?.to_string_lossy();
+            // The segments are joined into one file
+            let joined = name
+                .rsplit_once("_seg")
+                .filter(|(_, index)| segmented && index.bytes().all(|b| b.is_ascii_digit()))
+                .map_or(&*name, |(stem, _)| stem);
+            dir.join(joined)
+        }
+        None if segmented => {
+            anyhow::bail!("{:?} is rotated into segments, convert it with --output-dir to join them", metadata_path);
+        }
+        None => source.with_extension(""),
+    };
+
+    let mut sinks = Vec::new();
+    let mut written = Vec::new();
+    for &format in &args.format {
+        let path = match format {
+            OutputFormat::Csv => compress::compressed_path(&stem.with_extension("csv"), args.compress),
+            _ => stem.with_extension(format.extension()),
+        };
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        if written.contains(&name) || path == data_path || (path.exists() && !args.overwrite) {
+            continue;
+        }
+        sinks.push(open_sink(format, path, &metadata, &channel_names, args.compress)?);
+        written.push(name);
+    }
+    if sinks.is_empty() {
+        return Ok(written);
+    }
+
+    let num_samples = samples.len();
+    write_rows(&mut sinks, &timestamps, samples, &markers)?;
+    for sink in &mut sinks {
+        sink.finalize(&metadata)?;
+    }
+
+    if let (Some(dir), Some(host_clock)) = (dest, &metadata.host_clock) {
+        let (from, to) = (metadata_path.with_file_name(&host_clock.file), dir.join(&host_clock.file));
+        if from != to {
+            fs::copy(from, to)?;
+        }
+    }
+    let metadata_path = match dest {
+        Some(dir) => {
+            // The copy is a trial of its own, read from the first new file
+            metadata.data_file = Some(written[0].clone());
+            metadata.other_data_files = written[1..].to_vec();
+            metadata.segments.clear();
+            dir.join(metadata_path.file_name().context("Metadata file without a name")?)
+        }
+        None => {
+            for name in &written {
+                if !metadata.other_data_files.contains(name) {
+                    metadata.other_data_files.push(name.clone());
+                }
+            }
+            metadata_path
+        }
+    };
+    let dir = metadata_path.parent().unwrap_or(Path::new(""));
+    metadata.checksums = checksum::data_files(dir, &metadata)?;
+    fs::write(&metadata_path, serde_json::to_string_pretty(&metadata)?)?;
+
+    for name in &written {
+        let copy = Recording::open(&dir.join(name))?;
+        if copy.samples.len() != num_samples {
+            anyhow::bail!("{} reads back {} of {} samples", name, copy.samples.len(), num_samples);
+        }
+    }
+    if dest.is_some() {
+        events::write_events(&Recording::load(&metadata_path)?)?;
+    }
+    Ok(written)
+}
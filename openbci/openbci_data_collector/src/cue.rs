@@ -0,0 +1,236 @@
+//! Cue presentation for cued motor imagery trials.
+//!
+//! With `--cues` the terminal shows a fixation cross when the trial starts,
+//! then after the cue delay an arrow for the class (left, right, both hands)
+//! or a rest sign, with an optional beep. Each is marked in the event stream
+//! right after it is drawn, so `fixation` and `cue:<class>` markers carry
+//! the host time of the onset and are anchored to the next sample like any
+//! other marker. Paradigms other than motor imagery cue with text, or only
+//! mark the cue, and a P300 paradigm flashes its oddball sequence after the
+//! cue, each flash marked `stimulus:<name>`. Onsets are as precise as the
+//! terminal: the write is flushed before the marker is taken, but the time
+//! to the next screen refresh (a few ms) is not measured.
+
+use crate::paradigm::{CueKind, StimulusSequence, STIMULUS_PREFIX};
+use log::{info, warn};
+use openbci_wifi_client::MarkerSender;
+use std::io::Write;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Marker at the fixation cross, when the trial starts
+pub const FIXATION: &str = "fixation";
+/// Marker prefix of the cue onset, followed by the class label
+pub const CUE_PREFIX: &str = "cue:";
+
+/// Beep length and pitch
+const BEEP: Duration = Duration::from_millis(150);
+const BEEP_HZ: f32 = 1000.0;
+
+/// How cues are presented
+#[derive(Debug, Clone)]
+pub struct CuePlan {
+    pub class: String,
+    pub kind: CueKind,
+    /// Text of a `text` cue
+    pub text: String,
+    /// Flashes after the cue
+    pub stimuli: Option<StimulusSequence>,
+    /// Seconds of fixation before the cue
+    pub delay: f64,
+    pub beep: bool,
+    /// Draw on the terminal; off while the scope owns it and shows the
+    /// phase instead
+    pub draw: bool,
+}
+
+/// Cues of a running trial, presented from their own thread so sample
+/// processing cannot delay them
+pub struct CuePresenter {
+    draw: bool,
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CuePresenter {
+    /// Show the fixation cross now and the cue after `plan.delay`
+    pub fn start(plan: CuePlan, markers: MarkerSender) -> Self {
+        let show = plan.draw;
+        let (stop, stopped) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("cues".to_string())
+            .spawn(move || {
+                // The audio device is opened up front, not at the cue
+                let beeper = plan.beep.then(Beeper::open);
+                let start = Instant::now();
+                if plan.draw {
+                    draw(&fixation_screen());
+                }
+                markers.insert_marker(FIXATION);
+
+                match stopped.recv_timeout(Duration::from_secs_f64(plan.delay.max(0.0)).saturating_sub(start.elapsed())) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    // The trial ended first
+                    _ => return,
+                }
+                if let Some(beeper) = &beeper {
+                    beeper.beep();
+                }
+                if plan.draw {
+                    match plan.kind {
+                        CueKind::Arrow => draw(&cue_screen(&plan.class)),
+                        CueKind::Text => draw(&screen(&["", &plan.text, ""])),
+                        CueKind::None => {}
+                    }
+                }
+                let marker = markers.insert_marker(format!("{}{}", CUE_PREFIX, plan.class));
+                info!("Cue '{}' at {:.3}", plan.class, marker.host_time);
+
+                if let Some(stimuli) = &plan.stimuli {
+                    let (interval, flash) = (Duration::from_secs_f64(stimuli.interval), Duration::from_secs_f64(stimuli.flash));
+                    let mut onset = Instant::now();
+                    loop {
+                        onset += interval;
+                        if stopped.recv_timeout(onset.saturating_duration_since(Instant::now())) != Err(RecvTimeoutError::Timeout) {
+                            return;
+                        }
+                        let stimulus = stimuli.pick(rand::random());
+                        if plan.draw {
+                            draw(&screen(&["", &stimulus.text(), ""]));
+                        }
+                        markers.insert_marker(format!("{}{}", STIMULUS_PREFIX, stimulus.name));
+                        if stopped.recv_timeout((onset + flash).saturating_duration_since(Instant::now())) != Err(RecvTimeoutError::Timeout) {
+                            return;
+                        }
+                        if plan.draw {
+                            draw(&fixation_screen());
+                        }
+                    }
+                }
+
+                // Keep the audio device open until the trial ends
+                let _ = stopped.recv();
+            })
+            .expect("failed to spawn the cue thread");
+        Self {
+            draw: show,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// End of trial: cancel a cue not shown yet and tell the subject to relax
+    pub fn finish(mut self) {
+        self.stop_thread();
+        if self.draw {
+            draw(&screen(&["", "RELAX", ""]));
+        }
+    }
+
+    fn stop_thread(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("Cue thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for CuePresenter {
+    fn drop(&mut self) {
+        self.stop_thread();
+    }
+}
+
+fn fixation_screen() -> String {
+    screen(&["   |   ", "---+---", "   |   "])
+}
+
+/// Arrow pointing at the hand to imagine moving
+fn cue_screen(class: &str) -> String {
+    match class {
+        "left_hand" => screen(&["   /          ", "  /           ", " <============", "  \\           ", "   \\          "]),
+        "right_hand" => screen(&["          \\   ", "           \\  ", "============> ", "           /  ", "          /   "]),
+        "both_hands" => screen(&["  /              \\  ", " <=====      =====> ", "  \\              /  "]),
+        "rest" => screen(&["+------+", "| REST |", "+------+"]),
+        other => screen(&["", &other.to_uppercase(), ""]),
+    }
+}
+
+/// Clear the terminal and center `lines` roughly in an 80x24 window
+fn screen(lines: &[&str]) -> String {
+    let width = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+    let indent = " ".repeat(40usize.saturating_sub(width / 2));
+    let mut out = String::from("\x1b[2J\x1b[H");
+    out.push_str(&"\n".repeat(12usize.saturating_sub(lines.len() / 2)));
+    for line in lines {
+        out.push_str(&indent);
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+fn draw(screen: &str) {
+    let mut stdout = std::io::stdout().lock();
+    if let Err(e) = stdout.write_all(screen.as_bytes()).and_then(|_| stdout.flush()) {
+        warn!("Failed to draw cue: {}", e);
+    }
+}
+
+/// Cue onset tone
+#[cfg(feature = "audio")]
+struct Beeper {
+    /// `None` if there is no audio device; the terminal bell rings instead
+    output: Option<(rodio::OutputStream, rodio::OutputStreamHandle)>,
+}
+
+#[cfg(feature = "audio")]
+impl Beeper {
+    fn open() -> Self {
+        match rodio::OutputStream::try_default() {
+            Ok(output) => Self { output: Some(output) },
+            Err(e) => {
+                warn!("No audio output ({}), beeping with the terminal bell", e);
+                Self { output: None }
+            }
+        }
+    }
+
+    fn beep(&self) {
+        use rodio::Source;
+        let Some((_, handle)) = &self.output else {
+            return terminal_bell();
+        };
+        let tone = rodio::source::SineWave::new(BEEP_HZ).take_duration(BEEP).amplify(0.3);
+        if let Err(e) = handle.play_raw(tone) {
+            warn!("Failed to play the cue beep: {}", e);
+        }
+    }
+}
+
+/// Cue onset tone; without the `audio` feature, the terminal bell
+#[cfg(not(feature = "audio"))]
+struct Beeper;
+
+#[cfg(not(feature = "audio"))]
+impl Beeper {
+    fn open() -> Self {
+        info!(
+            "Cue beeps use the terminal bell, rebuild with --features audio for a {} Hz {} ms tone",
+            BEEP_HZ,
+            BEEP.as_millis()
+        );
+        Self
+    }
+
+    fn beep(&self) {
+        terminal_bell();
+    }
+}
+
+fn terminal_bell() {
+    draw("\x07");
+}
@@ -0,0 +1,857 @@
+//! Training datasets built from a recording tree. Run as `openbci dataset
+//! build`.
+//!
+//! Every trial's metadata is checked against its data file, sessions are
+//! put through QC, and the trials that make the cut are merged into one
+//! Parquet, NPZ or HDF5 file. A manifest CSV next to it lists them with
+//! their rows in that file and their quality flags, so training needs
+//! neither the directory tree nor the per-trial metadata.
+//!
+//! `openbci dataset split` (or `build --split`) assigns the manifest's
+//! trials to train, validation and test sets per trial, per session or
+//! leaving one subject out, from a fixed seed, so every model is scored on
+//! the same split files.
+
+use crate::compute::ComputeArgs;
+#[cfg(feature = "hdf5")]
+use crate::hdf5;
+use crate::metadata::TrialMetadata;
+use crate::npz;
+#[cfg(feature = "parquet")]
+use crate::parquet_sink::ParquetSink;
+use crate::qc::{self, QcCriteria, SessionManifest};
+use crate::recording::{self, Recording};
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use eeg_dsp::{EpochStatistics, Rejection, Thresholding, Wavelet};
+use log::{info, warn};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Options of `openbci dataset build`
+#[derive(Args, Debug, Clone)]
+pub struct BuildArgs {
+    /// Root of the recorded dataset
+    #[arg(short, long, default_value = "motor_imagery_data")]
+    pub data_dir: PathBuf,
+
+    /// Consolidated file, `.parquet` (build with --features parquet),
+    /// `.npz` or `.h5` (build with --features hdf5); the manifest is
+    /// written next to it as <name>_manifest.csv
+    #[arg(short, long, default_value = "motor_imagery_dataset.parquet")]
+    pub output: PathBuf,
+
+    /// Only these subjects (all if omitted)
+    #[arg(long, value_delimiter = ',')]
+    pub subjects: Vec<String>,
+
+    /// QC criteria JSON for every session (by default each session's own
+    /// from its manifest)
+    #[arg(long)]
+    pub qc_config: Option<PathBuf>,
+
+    /// Also take sessions that fail QC
+    #[arg(long)]
+    pub include_failed_qc: bool,
+
+    /// Also take trials QC finds unusable (injected artifacts, interrupted,
+    /// flagged bad, ...); the manifest keeps their issues
+    #[arg(long)]
+    pub include_unusable: bool,
+
+    /// Fail instead of skipping trials whose metadata and data disagree
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Resample every trial to this rate in Hz first (e.g. 250 for 1 kHz
+    /// recordings or imported 160 Hz data), with an anti-alias filter
+    #[arg(long)]
+    pub resample: Option<u32>,
+
+    /// Wavelet-denoise every channel with this wavelet, db4 or sym4,
+    /// after resampling
+    #[arg(long)]
+    pub denoise: Option<Wavelet>,
+
+    /// Levels the denoising decomposes into
+    #[arg(long, default_value = "4")]
+    pub denoise_level: usize,
+
+    /// What denoising does to the details: hard keeps rhythm amplitudes,
+    /// soft is smoother
+    #[arg(long, default_value = "hard")]
+    pub thresholding: Thresholding,
+
+    /// Rebuild channels that correlate with no other channel (below this,
+    /// e.g. 0.4) from their neighbours on the scalp, after denoising
+    #[arg(long)]
+    pub bad_channel_correlation: Option<f64>,
+
+    /// Drop trials whose variance or kurtosis lies more than this many
+    /// robust standard deviations from their session's median, e.g. 3;
+    /// the processed trials are then held in memory until written
+    #[arg(long)]
+    pub reject_epochs: Option<f64>,
+
+    /// Zstandard compression level of a Parquet file, 1-22
+    #[arg(long, default_value = "3")]
+    pub zstd_level: i32,
+
+    #[command(flatten)]
+    pub split: SplitOptions,
+
+    #[command(flatten)]
+    pub compute: ComputeArgs,
+}
+
+/// Options of `openbci dataset split`
+#[derive(Args, Debug, Clone)]
+pub struct SplitArgs {
+    /// Manifest CSV of a built dataset
+    #[arg(short, long, default_value = "motor_imagery_dataset_manifest.csv")]
+    pub manifest: PathBuf,
+
+    #[command(flatten)]
+    pub split: SplitOptions,
+}
+
+/// How the split files are drawn
+#[derive(Args, Debug, Clone)]
+pub struct SplitOptions {
+    /// Split files to write next to the manifest: trial (stratified by
+    /// class), session (whole sessions) or subject (leave one subject out),
+    /// comma separated; `dataset split` writes all three if omitted, or
+    /// trial and session for a single subject
+    #[arg(long = "split", value_delimiter = ',')]
+    pub strategies: Vec<SplitStrategy>,
+
+    /// Seed of the assignment; the same manifest and seed give the same
+    /// splits
+    #[arg(long, default_value = "0")]
+    pub seed: u64,
+
+    /// Share of trials, sessions or training subjects' sessions held out
+    /// for validation
+    #[arg(long, default_value = "0.15")]
+    pub val_fraction: f64,
+
+    /// Share of trials or sessions held out for testing (the held-out
+    /// subject with `subject`)
+    #[arg(long, default_value = "0.15")]
+    pub test_fraction: f64,
+}
+
+impl SplitOptions {
+    fn check(&self) -> Result<()> {
+        for (name, fraction) in [("--val-fraction", self.val_fraction), ("--test-fraction", self.test_fraction)] {
+            if !(0.0..1.0).contains(&fraction) {
+                bail!("{} must be in [0, 1), got {}", name, fraction);
+            }
+        }
+        if self.val_fraction + self.test_fraction >= 1.0 {
+            bail!("--val-fraction and --test-fraction leave nothing to train on");
+        }
+        Ok(())
+    }
+}
+
+/// Unit a split keeps together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitStrategy {
+    /// Trials drawn independently, stratified by class
+    Trial,
+    /// Whole sessions, so no session is in two sets
+    Session,
+    /// One fold per subject with that subject as the test set
+    Subject,
+}
+
+impl FromStr for SplitStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "trial" | "per-trial" => Ok(Self::Trial),
+            "session" | "per-session" => Ok(Self::Session),
+            "subject" | "loso" | "leave-one-subject-out" => Ok(Self::Subject),
+            _ => bail!("Unknown split '{}', expected trial, session or subject", s),
+        }
+    }
+}
+
+impl fmt::Display for SplitStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Trial => "trial",
+            Self::Session => "session",
+            Self::Subject => "subject",
+        })
+    }
+}
+
+/// One trial of a built dataset, a row of the manifest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub subject_id: String,
+    pub session_id: String,
+    pub trial_number: u32,
+    pub class_label: String,
+    pub class_id: u8,
+    pub n_samples: usize,
+    pub n_channels: usize,
+    /// The measured rate when the trial has one, else the configured one
+    pub sample_rate: f64,
+    /// First row of the trial in the consolidated file
+    pub row_offset: usize,
+    /// The trial passed QC
+    pub usable: bool,
+    pub session_passed: bool,
+    pub simulated: bool,
+    /// QC issues, `; ` separated
+    pub issues: String,
+    /// Channels rebuilt from their neighbours, `; ` separated
+    #[serde(default)]
+    pub interpolated: String,
+    /// Metadata file, relative to the data directory
+    pub source: String,
+}
+
+/// Where `output`'s manifest goes
+pub fn manifest_path(output: &Path) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    output.with_file_name(format!("{}_manifest.csv", stem))
+}
+
+/// Where the `strategy` split of `manifest` goes, `<name>_split_<strategy>.csv`
+pub fn split_path(manifest: &Path, strategy: SplitStrategy) -> PathBuf {
+    let stem = manifest.file_stem().unwrap_or_default().to_string_lossy();
+    let name = stem.strip_suffix("_manifest").unwrap_or(&stem);
+    manifest.with_file_name(format!("{}_split_{}.csv", name, strategy))
+}
+
+/// Read a manifest written by `build`
+pub fn load_manifest(path: &Path) -> Result<Vec<ManifestEntry>> {
+    let mut reader = csv::Reader::from_path(path).with_context(|| format!("Failed to open {:?}", path))?;
+    reader
+        .deserialize()
+        .collect::<std::result::Result<_, _>>()
+        .with_context(|| format!("Invalid manifest {:?}", path))
+}
+
+/// Sources a split file assigns to `part` (train, val or test) in column
+/// `fold`
+pub fn split_sources(split_file: &Path, fold: &str, part: &str) -> Result<BTreeSet<String>> {
+    let mut reader =
+        csv::Reader::from_path(split_file).with_context(|| format!("Failed to open {:?}", split_file))?;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h == name)
+            .with_context(|| format!("{:?} has no '{}' column", split_file, name))
+    };
+    let (source, assigned) = (column("source")?, column(fold)?);
+    let mut sources = BTreeSet::new();
+    for record in reader.records() {
+        let record = record.with_context(|| format!("Invalid split file {:?}", split_file))?;
+        if record.get(assigned) == Some(part) {
+            sources.insert(record.get(source).unwrap_or_default().to_string());
+        }
+    }
+    Ok(sources)
+}
+
+/// Ways a loaded trial disagrees with its metadata; empty when it is
+/// consistent
+pub fn validate(rec: &Recording) -> Vec<String> {
+    let meta = &rec.metadata;
+    let mut problems = Vec::new();
+    if meta.sample_rate == 0 {
+        problems.push("sample rate is 0".to_string());
+    }
+    if meta.electrode_config.channels.len() != meta.num_channels {
+        problems.push(format!(
+            "{} channel labels for {} channels",
+            meta.electrode_config.channels.len(),
+            meta.num_channels
+        ));
+    }
+    if rec.num_channels() != meta.num_channels {
+        problems.push(format!("data has {} channels, metadata {}", rec.num_channels(), meta.num_channels));
+    }
+    if rec.samples.is_empty() {
+        problems.push("no samples".to_string());
+    } else if rec.samples.len() as u64 != meta.written_samples() {
+        problems.push(format!("data has {} samples, metadata {}", rec.samples.len(), meta.written_samples()));
+    }
+    problems
+}
+
+/// Every trial's samples in one array, for formats written in one go at
+/// the end
+struct Arrays {
+    channels: Vec<String>,
+    values: Vec<f32>,
+    offsets: Vec<i64>,
+    labels: Vec<i64>,
+}
+
+impl Arrays {
+    fn new(channels: &[String]) -> Self {
+        Self {
+            channels: channels.to_vec(),
+            values: Vec::new(),
+            offsets: vec![0],
+            labels: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, rec: &Recording) {
+        // Channels a trial lacks are NaN, as they are null in Parquet
+        let columns: Vec<Option<usize>> =
+            self.channels.iter().map(|name| rec.channel_names.iter().position(|c| c == name)).collect();
+        for row in &rec.samples {
+            self.values.extend(columns.iter().map(|c| c.and_then(|i| row.get(i).copied()).unwrap_or(f32::NAN)));
+        }
+        self.offsets.push(self.offsets.last().copied().unwrap_or_default() + rec.samples.len() as i64);
+        self.labels.push(rec.metadata.class_id as i64);
+    }
+
+    fn rows(&self) -> usize {
+        self.values.len() / self.channels.len().max(1)
+    }
+}
+
+/// The consolidated file being written
+enum Output {
+    #[cfg(feature = "parquet")]
+    Parquet(Box<ParquetSink>),
+    /// NPZ is one zip write at the end, so the samples are held until then
+    Npz(Arrays),
+    /// Held like NPZ
+    #[cfg(feature = "hdf5")]
+    Hdf5(Arrays),
+}
+
+impl Output {
+    #[cfg_attr(not(feature = "parquet"), allow(unused_variables))]
+    fn create(path: &Path, channels: &[String], zstd_level: i32) -> Result<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            #[cfg(feature = "parquet")]
+            Some("parquet") => Ok(Self::Parquet(Box::new(ParquetSink::create(path, channels, zstd_level)?))),
+            #[cfg(not(feature = "parquet"))]
+            Some("parquet") => bail!("Parquet support not compiled in, rebuild with --features parquet or write a .npz"),
+            Some("npz") => Ok(Self::Npz(Arrays::new(channels))),
+            #[cfg(feature = "hdf5")]
+            Some("h5") => Ok(Self::Hdf5(Arrays::new(channels))),
+            #[cfg(not(feature = "hdf5"))]
+            Some("h5") => bail!("HDF5 support not compiled in, rebuild with --features hdf5 or write a .npz"),
+            _ => bail!("Unknown dataset format {:?}, use .parquet, .npz or .h5", path),
+        }
+    }
+
+    fn write(&mut self, rec: &Recording) -> Result<()> {
+        match self {
+            #[cfg(feature = "parquet")]
+            Self::Parquet(sink) => {
+                sink.write_trial(rec)?;
+            }
+            Self::Npz(arrays) => arrays.push(rec),
+            #[cfg(feature = "hdf5")]
+            Self::Hdf5(arrays) => arrays.push(rec),
+        }
+        Ok(())
+    }
+
+    fn finish(self, path: &Path, manifest: &[ManifestEntry]) -> Result<()> {
+        match self {
+            #[cfg(feature = "parquet")]
+            Self::Parquet(sink) => {
+                sink.finish()?;
+            }
+            Self::Npz(arrays) => {
+                let rows = arrays.rows();
+                let files = [
+                    ("X", npz::npy_f32(rows, arrays.channels.len(), &arrays.values)),
+                    ("y", npz::npy_i64(&arrays.labels)),
+                    ("offsets", npz::npy_i64(&arrays.offsets)),
+                    ("channels", npz::npy_str_scalar(&serde_json::to_string(&arrays.channels)?)),
+                    ("manifest", npz::npy_str_scalar(&serde_json::to_string(manifest)?)),
+                ];
+                npz::write_npz(path, &files, true)?;
+            }
+            #[cfg(feature = "hdf5")]
+            Self::Hdf5(arrays) => {
+                let manifest = serde_json::to_string(manifest)?;
+                hdf5::write(
+                    path,
+                    &[
+                        ("X", hdf5::Data::F32 { rows: arrays.rows(), cols: arrays.channels.len(), values: &arrays.values }),
+                        ("y", hdf5::Data::I64(&arrays.labels)),
+                        ("offsets", hdf5::Data::I64(&arrays.offsets)),
+                        ("channels", hdf5::Data::Strings(&arrays.channels)),
+                        ("manifest", hdf5::Data::Str(&manifest)),
+                    ],
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Load `path`, check it against its metadata and preprocess it as `args`
+/// say; also returns the channels that were interpolated. `None`, after a
+/// warning, when the trial is skipped, which is an error with `--strict`.
+fn prepare(path: &Path, args: &BuildArgs) -> Result<Option<(Recording, Vec<String>)>> {
+    let mut rec = match Recording::load(path) {
+        Ok(rec) => rec,
+        Err(e) if args.strict => return Err(e.context(format!("Failed to load {:?}", path))),
+        Err(e) => {
+            warn!("Skipping {:?}: {:#}", path, e);
+            return Ok(None);
+        }
+    };
+    let problems = validate(&rec);
+    if !problems.is_empty() {
+        if args.strict {
+            bail!("{:?}: {}", path, problems.join("; "));
+        }
+        warn!("Skipping {:?}: {}", path, problems.join("; "));
+        return Ok(None);
+    }
+    if let Some(rate) = args.resample {
+        rec.resample(rate)?;
+    }
+    if let Some(wavelet) = args.denoise {
+        rec.denoise(wavelet, args.denoise_level, args.thresholding)?;
+    }
+    let interpolated = match args.bad_channel_correlation {
+        Some(min_correlation) => match rec.interpolate_bad_channels(min_correlation) {
+            Ok(names) => names,
+            Err(e) if args.strict => return Err(e.context(format!("Bad channels in {:?}", path))),
+            Err(e) => {
+                warn!("Skipping {:?}: {:#}", path, e);
+                return Ok(None);
+            }
+        },
+        None => Vec::new(),
+    };
+    Ok(Some((rec, interpolated)))
+}
+
+/// Epoch rejection verdicts of the trials at `paths`, scored against the
+/// other trials of their session; `None` statistics are of skipped trials
+fn reject_by_session(paths: &[&Path], statistics: &[Option<EpochStatistics>], threshold: f64) -> Vec<Option<Rejection>> {
+    let mut sessions: BTreeMap<&Path, Vec<usize>> = BTreeMap::new();
+    for (i, path) in paths.iter().enumerate().filter(|(i, _)| statistics[*i].is_some()) {
+        sessions.entry(path.parent().unwrap_or(Path::new(""))).or_default().push(i);
+    }
+    let mut verdicts = vec![None; paths.len()];
+    for (dir, members) in sessions {
+        if members.len() < eeg_dsp::rejection::MIN_EPOCHS {
+            warn!("Not rejecting epochs of {:?}: {} trials are too few to score", dir, members.len());
+            continue;
+        }
+        let session: Vec<EpochStatistics> = members.iter().filter_map(|&i| statistics[i]).collect();
+        for (&i, verdict) in members.iter().zip(eeg_dsp::reject_epochs(&session, threshold)) {
+            verdicts[i] = verdict;
+        }
+    }
+    verdicts
+}
+
+/// Session QC, evaluated afresh with `criteria` or the session's own
+pub(crate) fn session_qc(dir: &Path, criteria: Option<&QcCriteria>) -> Result<SessionManifest> {
+    let criteria = match criteria {
+        Some(criteria) => criteria.clone(),
+        None if dir.join(qc::SESSION_MANIFEST).exists() => SessionManifest::load(dir)?.qc.criteria,
+        None => QcCriteria::default(),
+    };
+    qc::evaluate_session(dir, &criteria)
+}
+
+/// Validate and merge every selected trial under `--data-dir` into
+/// `--output` and write the manifest; returns its entries
+pub fn build(args: &BuildArgs) -> Result<Vec<ManifestEntry>> {
+    args.split.check()?;
+    if args.bad_channel_correlation.is_some_and(|r| !(r > 0.0 && r <= 1.0)) {
+        bail!("--bad-channel-correlation must be in (0, 1]");
+    }
+    if args.reject_epochs.is_some_and(|z| z <= 0.0) {
+        bail!("--reject-epochs must be positive");
+    }
+    let compute = args.compute.apply()?;
+    let criteria = args.qc_config.as_deref().map(QcCriteria::load).transpose()?;
+
+    // Selection and the channel columns come from the metadata alone
+    let mut sessions: BTreeMap<PathBuf, SessionManifest> = BTreeMap::new();
+    let mut selected = Vec::new();
+    let mut channels: Vec<String> = Vec::new();
+    let (mut failed_sessions, mut unusable) = (0, 0);
+    for path in recording::find_trials(&args.data_dir, true)? {
+        let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        if !sessions.contains_key(&dir) {
+            let manifest = session_qc(&dir, criteria.as_ref())?;
+            if !manifest.qc.passed && !args.include_failed_qc {
+                warn!("Skipping {:?}: session failed QC ({})", dir, manifest.qc.failures.join("; "));
+            }
+            sessions.insert(dir.clone(), manifest);
+        }
+        let session = &sessions[&dir];
+        if !session.qc.passed && !args.include_failed_qc {
+            failed_sessions += 1;
+            continue;
+        }
+
+        let metadata: TrialMetadata = match fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| Ok(serde_json::from_str(&text)?))
+        {
+            Ok(metadata) => metadata,
+            Err(e) if args.strict => return Err(e.context(format!("Invalid metadata {:?}", path))),
+            Err(e) => {
+                warn!("Skipping {:?}: {}", path, e);
+                continue;
+            }
+        };
+        if !args.subjects.is_empty() && !args.subjects.contains(&metadata.subject_id) {
+            continue;
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let trial_qc = session.trials.iter().find(|t| t.metadata_file == name);
+        let usable = trial_qc.is_some_and(|t| t.usable);
+        if !usable && !args.include_unusable {
+            let issues = trial_qc.map(|t| t.issues.join("; ")).unwrap_or_default();
+            warn!("Skipping {:?}: {}", path, issues);
+            unusable += 1;
+            continue;
+        }
+        for name in &metadata.electrode_config.channels {
+            if !channels.contains(name) {
+                channels.push(name.clone());
+            }
+        }
+        let issues = trial_qc.map(|t| t.issues.join("; ")).unwrap_or_default();
+        selected.push((path, usable, session.qc.passed, issues));
+    }
+    if selected.is_empty() {
+        bail!("No usable trials found under {:?}", args.data_dir);
+    }
+    info!("Building from {} trials, channels: {}", selected.len(), channels.join(", "));
+
+    let (mut rows, mut invalid, mut rejected) = (0, 0, 0);
+    let mut todo: Vec<usize> = (0..selected.len()).collect();
+    // Outliers are judged against the whole session, so every trial is
+    // prepared and scored before the first is written, and kept for writing
+    let mut kept = Vec::new();
+    if let Some(threshold) = args.reject_epochs {
+        kept.reserve(selected.len());
+        for batch in selected.chunks(compute.threads) {
+            let prepared: Vec<_> = batch.par_iter().map(|(path, ..)| prepare(path, args)).collect();
+            for trial in prepared {
+                kept.push(trial?);
+            }
+        }
+        let statistics: Vec<_> = kept.iter().map(|trial| trial.as_ref().map(|(rec, _)| rec.epoch_statistics())).collect();
+        invalid = statistics.iter().filter(|s| s.is_none()).count();
+        let paths: Vec<&Path> = selected.iter().map(|(path, ..)| path.as_path()).collect();
+        let verdicts = reject_by_session(&paths, &statistics, threshold);
+        todo.retain(|&i| match verdicts[i] {
+            _ if statistics[i].is_none() => false,
+            Some(rejection) => {
+                warn!("Rejecting {:?}: {}", paths[i], rejection);
+                rejected += 1;
+                kept[i] = None;
+                false
+            }
+            None => true,
+        });
+    }
+
+    let mut output = Output::create(&args.output, &channels, args.zstd_level)?;
+    let mut manifest = Vec::new();
+    for batch in todo.chunks(compute.threads) {
+        let prepared: Vec<_> = if args.reject_epochs.is_some() {
+            batch.iter().map(|&i| Ok(kept[i].take())).collect()
+        } else {
+            batch.par_iter().map(|&i| prepare(&selected[i].0, args)).collect()
+        };
+        for (&i, trial) in batch.iter().zip(prepared) {
+            let (path, usable, session_passed, issues) = &selected[i];
+            let Some((rec, interpolated)) = trial? else {
+                invalid += 1;
+                continue;
+            };
+            if !interpolated.is_empty() {
+                info!("Interpolated {} in {:?}", interpolated.join(", "), path);
+            }
+            output.write(&rec)?;
+            let meta = &rec.metadata;
+            manifest.push(ManifestEntry {
+                subject_id: meta.subject_id.clone(),
+                session_id: meta.session_id.clone(),
+                trial_number: meta.trial_number,
+                class_label: meta.class_label.clone(),
+                class_id: meta.class_id,
+                n_samples: rec.samples.len(),
+                n_channels: rec.num_channels(),
+                sample_rate: meta.effective_sample_rate(),
+                row_offset: rows,
+                usable: *usable,
+                session_passed: *session_passed,
+                simulated: meta.simulation.is_some(),
+                issues: issues.clone(),
+                interpolated: interpolated.join("; "),
+                source: path.strip_prefix(&args.data_dir).unwrap_or(path).display().to_string(),
+            });
+            rows += rec.samples.len();
+        }
+    }
+    if manifest.is_empty() {
+        bail!("No trial under {:?} could be read", args.data_dir);
+    }
+    output.finish(&args.output, &manifest)?;
+
+    let manifest_path = manifest_path(&args.output);
+    let mut writer = csv::Writer::from_path(&manifest_path)
+        .with_context(|| format!("Failed to create {:?}", manifest_path))?;
+    for entry in &manifest {
+        writer.serialize(entry)?;
+    }
+    writer.flush()?;
+
+    let mut per_class: BTreeMap<&str, usize> = BTreeMap::new();
+    for entry in &manifest {
+        *per_class.entry(entry.class_label.as_str()).or_default() += 1;
+    }
+    let subjects: BTreeSet<_> = manifest.iter().map(|e| e.subject_id.as_str()).collect();
+    info!(
+        "Wrote {} trials ({} samples) of {} subjects to {:?}: {}",
+        manifest.len(),
+        rows,
+        subjects.len(),
+        args.output,
+        per_class.iter().map(|(class, n)| format!("{} {}", class, n)).collect::<Vec<_>>().join(", ")
+    );
+    info!(
+        "Skipped {} trials of failed sessions, {} unusable, {} invalid, {} rejected as outliers; manifest in {:?}",
+        failed_sessions, unusable, invalid, rejected, manifest_path
+    );
+    for &strategy in &args.split.strategies {
+        write_split(&manifest_path, &manifest, strategy, &args.split)?;
+    }
+    Ok(manifest)
+}
+
+/// Write the split files of an existing manifest
+pub fn split(args: &SplitArgs) -> Result<()> {
+    args.split.check()?;
+    let manifest = load_manifest(&args.manifest)?;
+    if manifest.is_empty() {
+        bail!("{:?} lists no trials", args.manifest);
+    }
+    let strategies = match args.split.strategies.as_slice() {
+        [] => {
+            let subjects: BTreeSet<&str> = manifest.iter().map(|e| e.subject_id.as_str()).collect();
+            if subjects.len() < 2 {
+                warn!("Only one subject, skipping the leave-one-subject-out split");
+                vec![SplitStrategy::Trial, SplitStrategy::Session]
+            } else {
+                vec![SplitStrategy::Trial, SplitStrategy::Session, SplitStrategy::Subject]
+            }
+        }
+        strategies => strategies.to_vec(),
+    };
+    for strategy in strategies {
+        write_split(&args.manifest, &manifest, strategy, &args.split)?;
+    }
+    Ok(())
+}
+
+/// Set a trial is assigned to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Part {
+    Train,
+    Val,
+    Test,
+}
+
+impl Part {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Train => "train",
+            Self::Val => "val",
+            Self::Test => "test",
+        }
+    }
+}
+
+/// Shuffle `groups` and deal the first `test` share to test, the next `val`
+/// share to validation and the rest to training. A non-zero share gets at
+/// least one group; with too few groups validation, then testing, is
+/// skipped so that training keeps at least one.
+fn deal<T: Ord + Clone>(groups: &BTreeSet<T>, val: f64, test: f64, rng: &mut StdRng, what: &str) -> BTreeMap<T, Part> {
+    let share = |fraction: f64| {
+        if fraction > 0.0 {
+            ((groups.len() as f64 * fraction).round() as usize).max(1)
+        } else {
+            0
+        }
+    };
+    let (mut n_test, mut n_val) = (share(test), share(val));
+    if n_val > 0 && n_test + n_val >= groups.len() {
+        warn!("{} {}, too few to hold out any for validation", groups.len(), what);
+        n_val = 0;
+    }
+    if n_test > 0 && n_test >= groups.len() {
+        warn!("{} {}, too few to hold out any for testing", groups.len(), what);
+        n_test = 0;
+    }
+    let mut order: Vec<T> = groups.iter().cloned().collect();
+    order.shuffle(rng);
+    order
+        .into_iter()
+        .enumerate()
+        .map(|(i, group)| {
+            let part = if i < n_test {
+                Part::Test
+            } else if i < n_test + n_val {
+                Part::Val
+            } else {
+                Part::Train
+            };
+            (group, part)
+        })
+        .collect()
+}
+
+/// Assign `manifest`'s trials with `strategy` and write the split file next
+/// to `manifest_path`: the manifest's identifying columns, then `split`, or
+/// one `fold_<subject>` column per held-out subject
+fn write_split(
+    manifest_path: &Path,
+    manifest: &[ManifestEntry],
+    strategy: SplitStrategy,
+    options: &SplitOptions,
+) -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let session_of = |e: &ManifestEntry| (e.subject_id.clone(), e.session_id.clone());
+    let (columns, parts): (Vec<String>, Vec<Vec<Part>>) = match strategy {
+        SplitStrategy::Trial => {
+            let mut by_class: BTreeMap<&str, BTreeSet<usize>> = BTreeMap::new();
+            for (i, entry) in manifest.iter().enumerate() {
+                by_class.entry(entry.class_label.as_str()).or_default().insert(i);
+            }
+            let mut parts = vec![Part::Train; manifest.len()];
+            for (class, members) in &by_class {
+                let what = format!("{} trials", class);
+                for (i, part) in deal(members, options.val_fraction, options.test_fraction, &mut rng, &what) {
+                    parts[i] = part;
+                }
+            }
+            (vec!["split".to_string()], parts.into_iter().map(|p| vec![p]).collect())
+        }
+        SplitStrategy::Session => {
+            let sessions: BTreeSet<_> = manifest.iter().map(session_of).collect();
+            let assigned = deal(&sessions, options.val_fraction, options.test_fraction, &mut rng, "sessions");
+            let parts = manifest.iter().map(|e| vec![assigned[&session_of(e)]]).collect();
+            (vec!["split".to_string()], parts)
+        }
+        SplitStrategy::Subject => {
+            let subjects: BTreeSet<&str> = manifest.iter().map(|e| e.subject_id.as_str()).collect();
+            if subjects.len() < 2 {
+                bail!("Leave-one-subject-out needs at least two subjects, found {:?}", subjects);
+            }
+            let mut parts = vec![Vec::with_capacity(subjects.len()); manifest.len()];
+            for &held_out in &subjects {
+                // Validation sessions come from the training subjects
+                let sessions: BTreeSet<_> =
+                    manifest.iter().filter(|e| e.subject_id != held_out).map(session_of).collect();
+                let what = format!("sessions besides {}", held_out);
+                let assigned = deal(&sessions, options.val_fraction, 0.0, &mut rng, &what);
+                for (entry, trial) in manifest.iter().zip(&mut parts) {
+                    trial.push(if entry.subject_id == held_out { Part::Test } else { assigned[&session_of(entry)] });
+                }
+            }
+            (subjects.iter().map(|s| format!("fold_{}", s)).collect(), parts)
+        }
+    };
+
+    let path = split_path(manifest_path, strategy);
+    let mut writer = csv::Writer::from_path(&path).with_context(|| format!("Failed to create {:?}", path))?;
+    let mut header: Vec<String> = ["source", "subject_id", "session_id", "trial_number", "class_label", "class_id", "row_offset"]
+        .iter()
+        .map(|c| c.to_string())
+        .collect();
+    header.extend(columns.iter().cloned());
+    writer.write_record(&header)?;
+    for (entry, trial) in manifest.iter().zip(&parts) {
+        let mut record = vec![
+            entry.source.clone(),
+            entry.subject_id.clone(),
+            entry.session_id.clone(),
+            entry.trial_number.to_string(),
+            entry.class_label.clone(),
+            entry.class_id.to_string(),
+            entry.row_offset.to_string(),
+        ];
+        record.extend(trial.iter().map(|p| p.as_str().to_string()));
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+
+    if strategy == SplitStrategy::Subject {
+        info!("Leave-one-subject-out split, {} folds (seed {}), in {:?}", columns.len(), options.seed, path);
+    } else {
+        let count = |part: Part| parts.iter().filter(|p| p[0] == part).count();
+        info!(
+            "Per-{} split (seed {}): {} train, {} val, {} test trials, in {:?}",
+            strategy,
+            options.seed,
+            count(Part::Train),
+            count(Part::Val),
+            count(Part::Test),
+            path
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dealt(groups: usize, val: f64, test: f64) -> [usize; 3] {
+        let groups: BTreeSet<usize> = (0..groups).collect();
+        let parts = deal(&groups, val, test, &mut StdRng::seed_from_u64(0), "sessions");
+        assert_eq!(parts.len(), groups.len());
+        let count = |part: Part| parts.values().filter(|&&p| p == part).count();
+        [count(Part::Train), count(Part::Val), count(Part::Test)]
+    }
+
+    #[test]
+    fn deals_what_the_groups_allow() {
+        assert_eq!(dealt(1, 0.15, 0.15), [1, 0, 0]);
+        assert_eq!(dealt(2, 0.15, 0.15), [1, 0, 1]);
+        assert_eq!(dealt(2, 0.15, 0.0), [1, 1, 0]);
+        assert_eq!(dealt(3, 0.15, 0.15), [1, 1, 1]);
+        assert_eq!(dealt(20, 0.15, 0.15), [14, 3, 3]);
+        assert_eq!(dealt(20, 0.0, 0.25), [15, 0, 5]);
+    }
+
+    #[test]
+    fn deal_is_seeded() {
+        let groups: BTreeSet<usize> = (0..20).collect();
+        let deal_with = |seed| deal(&groups, 0.2, 0.2, &mut StdRng::seed_from_u64(seed), "trials");
+        assert_eq!(deal_with(7), deal_with(7));
+        assert_ne!(deal_with(7), deal_with(8));
+    }
+}
@@ -0,0 +1,131 @@
+//! Turning a classifier's per-window probabilities into stable commands.
+//!
+//! Each window's probabilities go through up to four steps, each off by
+//! default: exponential smoothing across windows, a confidence threshold
+//! below which the window counts as idle, a majority vote over the last
+//! few windows (idle unless one class has more than half of them), and a
+//! dwell time a new decision has to hold before it replaces the current
+//! one. Together they keep a robot from flickering between commands every
+//! window at the cost of reacting a little later.
+
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+
+/// Post-processing settings; the defaults pass the most likely class
+/// straight through
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecisionConfig {
+    /// Weight of the newest window in the running average, 1 for none
+    pub smoothing: f64,
+    /// Windows voted over, 1 for no vote
+    pub vote: usize,
+    /// Lowest (smoothed) probability a class is decided on
+    pub threshold: f64,
+    /// Consecutive windows a new decision has to hold, 1 to switch at once
+    pub dwell: usize,
+}
+
+impl Default for DecisionConfig {
+    fn default() -> Self {
+        Self { smoothing: 1.0, vote: 1, threshold: 0.0, dwell: 1 }
+    }
+}
+
+/// Outcome of one window
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    /// Probabilities after smoothing
+    pub smoothed: Vec<f32>,
+    /// Class decided on, `None` for idle
+    pub class: Option<usize>,
+    /// The decision differs from the previous window's
+    pub changed: bool,
+}
+
+/// Smoothing, voting and dwell state across windows
+#[derive(Debug, Clone)]
+pub struct DecisionMaker {
+    config: DecisionConfig,
+    smoothed: Option<Vec<f32>>,
+    /// Candidates of the last `vote` windows
+    votes: VecDeque<Option<usize>>,
+    current: Option<usize>,
+    /// Decision waiting out the dwell time, and for how many windows
+    pending: Option<(Option<usize>, usize)>,
+}
+
+impl DecisionMaker {
+    pub fn new(config: DecisionConfig) -> Result<Self> {
+        if !(config.smoothing > 0.0 && config.smoothing <= 1.0) {
+            bail!("Smoothing weight must be in (0, 1], got {}", config.smoothing);
+        }
+        if !(0.0..=1.0).contains(&config.threshold) {
+            bail!("Confidence threshold must be between 0 and 1, got {}", config.threshold);
+        }
+        if config.vote == 0 || config.dwell == 0 {
+            bail!("Votes and dwell need at least one window");
+        }
+        Ok(Self {
+            votes: VecDeque::with_capacity(config.vote),
+            config,
+            smoothed: None,
+            current: None,
+            pending: None,
+        })
+    }
+
+    /// Whether any step is on, i.e. decisions can differ from the most
+    /// likely class of the window
+    pub fn is_active(&self) -> bool {
+        self.config != DecisionConfig::default()
+    }
+
+    /// Take the next window's probabilities
+    pub fn update(&mut self, probabilities: &[f32]) -> Decision {
+        let alpha = self.config.smoothing as f32;
+        let smoothed = match self.smoothed.take() {
+            Some(previous) if previous.len() == probabilities.len() => {
+                previous.iter().zip(probabilities).map(|(s, p)| alpha * p + (1.0 - alpha) * s).collect()
+            }
+            _ => probabilities.to_vec(),
+        };
+
+        let best = smoothed.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1));
+        let candidate = best.filter(|(_, &p)| p as f64 >= self.config.threshold).map(|(i, _)| i);
+        if self.votes.len() == self.config.vote {
+            self.votes.pop_front();
+        }
+        self.votes.push_back(candidate);
+        let voted = self.majority();
+
+        let previous = self.current;
+        if voted == self.current {
+            self.pending = None;
+        } else {
+            let held = match self.pending {
+                Some((class, windows)) if class == voted => windows + 1,
+                _ => 1,
+            };
+            if held >= self.config.dwell {
+                self.current = voted;
+                self.pending = None;
+            } else {
+                self.pending = Some((voted, held));
+            }
+        }
+        self.smoothed = Some(smoothed.clone());
+        Decision { smoothed, class: self.current, changed: self.current != previous }
+    }
+
+    /// Class with more than half the votes, if any
+    fn majority(&self) -> Option<usize> {
+        let mut counts: Vec<(usize, usize)> = Vec::new();
+        for class in self.votes.iter().flatten() {
+            match counts.iter_mut().find(|(c, _)| c == class) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((*class, 1)),
+            }
+        }
+        counts.into_iter().find(|&(_, count)| 2 * count > self.votes.len()).map(|(class, _)| class)
+    }
+}
@@ -0,0 +1,187 @@
+//! Online artifact detection for `--detect-artifacts`.
+//!
+//! Three detectors run on every recorded sample, after online filtering:
+//! peak-to-peak amplitude over the last half second above a limit (blinks,
+//! movement), peak-to-peak over the last second below a limit (flat or
+//! railed channels), and a jump between consecutive samples above a limit
+//! (electrode pops). A detector fires when any channel crosses its limit,
+//! and every stretch it fires for is bracketed by `artifact_start:<kind>`
+//! and `artifact_end:<kind>` markers, the same brackets artifact injection
+//! uses, so detected segments show up with their duration in `_events.tsv`
+//! and as BrainVision bad intervals. Windowed detectors keep firing until
+//! the artifact has left their window.
+
+use crate::augment::{ARTIFACT_END, ARTIFACT_START};
+use crate::metadata::ArtifactDetectionInfo;
+use anyhow::{bail, Result};
+use log::warn;
+use std::collections::VecDeque;
+
+/// Default peak-to-peak limit, in microvolts
+pub const DEFAULT_MAX_AMPLITUDE_UV: f64 = 150.0;
+/// Default flatline limit, in microvolts peak-to-peak
+pub const DEFAULT_FLAT_AMPLITUDE_UV: f64 = 0.5;
+/// Default limit between consecutive samples, in microvolts
+pub const DEFAULT_MAX_GRADIENT_UV: f64 = 50.0;
+
+const AMPLITUDE_SECONDS: f64 = 0.5;
+const FLAT_SECONDS: f64 = 1.0;
+/// Samples are in nanovolts
+const NV_PER_UV: f64 = 1000.0;
+
+/// Detector limits, in microvolts
+#[derive(Debug, Clone, Copy)]
+pub struct DetectorLimits {
+    pub max_amplitude_uv: f64,
+    pub flat_amplitude_uv: f64,
+    pub max_gradient_uv: f64,
+}
+
+impl DetectorLimits {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.max_amplitude_uv > 0.0 && self.flat_amplitude_uv >= 0.0 && self.max_gradient_uv > 0.0) {
+            bail!("Artifact limits must be positive (flatline may be 0 to turn it off)");
+        }
+        if self.flat_amplitude_uv >= self.max_amplitude_uv {
+            bail!("Flatline limit must be below the amplitude limit");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Amplitude,
+    Flatline,
+    Gradient,
+}
+
+impl Kind {
+    const ALL: [Kind; 3] = [Kind::Amplitude, Kind::Flatline, Kind::Gradient];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Amplitude => "amplitude",
+            Self::Flatline => "flatline",
+            Self::Gradient => "gradient",
+        }
+    }
+}
+
+/// Amplitude, flatline and gradient detectors over all channels
+pub struct ArtifactDetector {
+    limits: DetectorLimits,
+    channel_names: Vec<String>,
+    amplitude_window: usize,
+    flat_window: usize,
+    /// Last `flat_window` samples, newest at the back
+    history: VecDeque<Vec<f32>>,
+    /// Which kinds have an open segment, in `Kind::ALL` order
+    open: [bool; 3],
+    samples: u64,
+    info: ArtifactDetectionInfo,
+}
+
+impl ArtifactDetector {
+    pub fn new(limits: DetectorLimits, sample_rate: u32, channel_names: &[String]) -> Self {
+        let fs = sample_rate as f64;
+        let amplitude_window = ((AMPLITUDE_SECONDS * fs) as usize).max(2);
+        let flat_window = ((FLAT_SECONDS * fs) as usize).max(amplitude_window);
+        Self {
+            limits,
+            channel_names: channel_names.to_vec(),
+            amplitude_window,
+            flat_window,
+            history: VecDeque::with_capacity(flat_window + 1),
+            open: [false; 3],
+            samples: 0,
+            info: ArtifactDetectionInfo {
+                max_amplitude_uv: limits.max_amplitude_uv,
+                flat_amplitude_uv: limits.flat_amplitude_uv,
+                max_gradient_uv: limits.max_gradient_uv,
+                segments: 0,
+                contaminated_samples: 0,
+                aborted: false,
+            },
+        }
+    }
+
+    /// Counters so far, for the trial metadata
+    pub fn info(&self) -> &ArtifactDetectionInfo {
+        &self.info
+    }
+
+    pub fn contaminated_samples(&self) -> u64 {
+        self.info.contaminated_samples
+    }
+
+    /// Feed the next sample; returns the markers to anchor to it
+    pub fn push(&mut self, data: &[f32]) -> Vec<String> {
+        if self.history.len() == self.flat_window {
+            self.history.pop_front();
+        }
+        self.history.push_back(data.to_vec());
+
+        let mut markers = Vec::new();
+        for (i, kind) in Kind::ALL.into_iter().enumerate() {
+            let channel = self.firing_channel(kind);
+            match (channel, self.open[i]) {
+                (Some(channel), false) => {
+                    warn!(
+                        "Artifact ({}) on {} at sample {}",
+                        kind.name(),
+                        self.channel_names.get(channel).map_or("?", String::as_str),
+                        self.samples
+                    );
+                    self.info.segments += 1;
+                    markers.push(format!("{}{}", ARTIFACT_START, kind.name()));
+                }
+                (None, true) => markers.push(format!("{}{}", ARTIFACT_END, kind.name())),
+                _ => {}
+            }
+            self.open[i] = channel.is_some();
+        }
+        if self.open.iter().any(|&open| open) {
+            self.info.contaminated_samples += 1;
+        }
+        self.samples += 1;
+        markers
+    }
+
+    /// First channel the detector fires on for the current sample
+    fn firing_channel(&self, kind: Kind) -> Option<usize> {
+        let channels = self.history.back()?.len();
+        (0..channels).find(|&c| match kind {
+            Kind::Amplitude => self.peak_to_peak(c, self.amplitude_window) > self.limits.max_amplitude_uv,
+            // Only once a full window has been seen
+            Kind::Flatline => {
+                self.history.len() == self.flat_window
+                    && self.peak_to_peak(c, self.flat_window) < self.limits.flat_amplitude_uv
+            }
+            Kind::Gradient => {
+                let n = self.history.len();
+                n >= 2 && {
+                    let jump = (self.history[n - 1][c] - self.history[n - 2][c]).abs() as f64;
+                    jump / NV_PER_UV > self.limits.max_gradient_uv
+                }
+            }
+        })
+    }
+
+    /// Peak-to-peak of channel `c` over the last `window` samples, in µV
+    fn peak_to_peak(&self, c: usize, window: usize) -> f64 {
+        let (min, max) = self
+            .history
+            .iter()
+            .rev()
+            .take(window)
+            .map(|x| x[c])
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), x| (min.min(x), max.max(x)));
+        (max - min) as f64 / NV_PER_UV
+    }
+
+    /// Mark the trial as stopped early
+    pub fn abort(&mut self) {
+        self.info.aborted = true;
+    }
+}
@@ -0,0 +1,91 @@
+//! Free space on the disk trials are written to.
+//!
+//! An overnight recording at 250 Hz grows by hundreds of MB per hour in
+//! CSV, and a full disk only shows up as failed writes. Free space is
+//! checked before every trial and, through [`DiskMonitor`], every few
+//! seconds while recording, so the experimenter is warned while there is
+//! still room to act.
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How often [`DiskMonitor`] asks the file system
+const CHECK_EVERY: Duration = Duration::from_secs(10);
+
+/// Bytes available to this process on the file system holding `path`
+#[cfg(unix)]
+pub fn free_bytes(path: &Path) -> Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: statvfs fills the zeroed struct and only reads the path
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(anyhow::Error::new(std::io::Error::last_os_error())
+            .context(format!("Failed to query free space of {:?}", path)));
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_bytes(_path: &Path) -> Result<u64> {
+    anyhow::bail!("Free disk space cannot be queried on this platform")
+}
+
+/// Bytes as MB for logs and markers
+pub fn megabytes(bytes: u64) -> u64 {
+    bytes / (1024 * 1024)
+}
+
+/// Watches free space during a recording and reports when it first falls
+/// below the threshold
+#[derive(Debug)]
+pub struct DiskMonitor {
+    path: PathBuf,
+    threshold: u64,
+    checked: Instant,
+    low: bool,
+    /// Least free space seen so far
+    min_free: Option<u64>,
+}
+
+impl DiskMonitor {
+    /// Watch the file system of `path`, warning below `threshold` bytes
+    pub fn new(path: &Path, threshold: u64) -> Self {
+        let mut monitor = Self {
+            path: path.to_path_buf(),
+            threshold,
+            checked: Instant::now(),
+            low: false,
+            min_free: None,
+        };
+        monitor.check_now();
+        monitor
+    }
+
+    /// Query the disk when it is due; returns the free bytes when they
+    /// just fell below the threshold
+    pub fn check(&mut self) -> Option<u64> {
+        if self.checked.elapsed() < CHECK_EVERY {
+            return None;
+        }
+        self.check_now()
+    }
+
+    fn check_now(&mut self) -> Option<u64> {
+        self.checked = Instant::now();
+        // A failed query is not worth stopping a recording for
+        let free = free_bytes(&self.path).ok()?;
+        self.min_free = Some(self.min_free.map_or(free, |min| min.min(free)));
+        let low = free < self.threshold;
+        let fell = low && !self.low;
+        self.low = low;
+        fell.then_some(free)
+    }
+
+    pub fn min_free(&self) -> Option<u64> {
+        self.min_free
+    }
+}
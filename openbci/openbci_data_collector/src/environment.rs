@@ -0,0 +1,92 @@
+//! What a recording was made with: collector build, host and the WiFi
+//! shields' own status, captured when the board is connected so a data
+//! anomaly found months later can be traced to a firmware, a machine or a
+//! command line.
+
+use log::{info, warn};
+use openbci_wifi_client::{BoardTransport, ShieldInfo};
+use serde::{Deserialize, Serialize};
+
+/// Collector build and host of a recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnvironmentInfo {
+    pub collector_version: String,
+    /// `git describe` of the source the collector was built from, with
+    /// `-dirty` for uncommitted changes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    pub os: String,
+    pub arch: String,
+    /// Distribution name, from `/etc/os-release`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os_release: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Arguments the collector was started with, the program first
+    pub command_line: Vec<String>,
+    /// `/all` of every WiFi shield, in board order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shields: Vec<ShieldInfo>,
+}
+
+impl EnvironmentInfo {
+    /// Snapshot the build, the host and the shields behind `board`. A
+    /// shield that does not answer is left out with a warning.
+    pub async fn capture(board: &dyn BoardTransport) -> Self {
+        let shields = board.shield_info().await.unwrap_or_else(|e| {
+            warn!("Could not read the shield status: {}", e);
+            Vec::new()
+        });
+        for shield in &shields {
+            info!(
+                "Shield {}: firmware {}, {} bytes free heap, {} us latency{}",
+                shield.name,
+                shield.version,
+                shield.heap,
+                shield.latency,
+                shield.rssi.map(|rssi| format!(", {} dBm", rssi)).unwrap_or_default()
+            );
+        }
+        let (kernel, hostname) = uname();
+        Self {
+            collector_version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: option_env!("OPENBCI_GIT_COMMIT").map(str::to_string),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            os_release: os_release(),
+            kernel,
+            hostname,
+            command_line: std::env::args().collect(),
+            shields,
+        }
+    }
+}
+
+/// `PRETTY_NAME` of `/etc/os-release`
+fn os_release() -> Option<String> {
+    let text = std::fs::read_to_string("/etc/os-release").ok()?;
+    let line = text.lines().find_map(|line| line.strip_prefix("PRETTY_NAME="))?;
+    Some(line.trim_matches('"').to_string())
+}
+
+/// Kernel release and host name
+#[cfg(unix)]
+fn uname() -> (Option<String>, Option<String>) {
+    // SAFETY: uname fills the zeroed struct with NUL-terminated strings
+    let mut name: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut name) } != 0 {
+        return (None, None);
+    }
+    let field = |chars: &[libc::c_char]| {
+        let bytes: Vec<u8> = chars.iter().take_while(|&&c| c != 0).map(|&c| c as u8).collect();
+        Some(String::from_utf8_lossy(&bytes).into_owned()).filter(|s| !s.is_empty())
+    };
+    (field(&name.release), field(&name.nodename))
+}
+
+#[cfg(not(unix))]
+fn uname() -> (Option<String>, Option<String>) {
+    (None, std::env::var("COMPUTERNAME").ok())
+}
@@ -0,0 +1,113 @@
+//! Blink removal by EOG regression, calibrated once per session.
+//!
+//! During calibration the participant blinks and looks around while the
+//! propagation of the EOG channels into every other channel is learned
+//! (see `eeg_dsp::regression`). Montages without EOG electrodes use the
+//! frontal ones, Fp1 and Fp2, in their place. Trials recorded with
+//! `--eog-regression` have the scaled EOG subtracted sample by sample,
+//! before ASR and the online filters see the stream.
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use eeg_dsp::{EogCleaner, EogRegression};
+use log::info;
+use ndarray::Array2;
+use openbci_wifi_client::Sample;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// File name of the regression in a session directory
+pub const EOG_FILE: &str = "eog_regression.json";
+
+/// Shortest calibration with enough blinks to fit on
+pub const MIN_CALIBRATION_SECONDS: f64 = 20.0;
+
+/// Session calibration as saved in [`EOG_FILE`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EogCalibrationFile {
+    pub calibrated_at: DateTime<Utc>,
+    pub calibration_seconds: f64,
+    pub regression: EogRegression,
+}
+
+impl EogCalibrationFile {
+    /// Fit on a channel-major calibration recording whose channels carry
+    /// montage `labels`; the `eog` labels missing from the montage are
+    /// skipped
+    pub fn fit(data: &[Vec<f32>], sample_rate: u32, labels: &[String], eog: &[String]) -> Result<Self> {
+        let len = data.iter().map(|c| c.len()).min().unwrap_or(0);
+        let seconds = len as f64 / sample_rate.max(1) as f64;
+        if seconds < MIN_CALIBRATION_SECONDS {
+            bail!(
+                "EOG calibration needs at least {} s of blinks and eye movements, got {:.1} s",
+                MIN_CALIBRATION_SECONDS,
+                seconds
+            );
+        }
+        let present: Vec<String> = eog
+            .iter()
+            .filter(|e| labels.iter().any(|l| l.eq_ignore_ascii_case(e.trim())))
+            .cloned()
+            .collect();
+        if present.is_empty() {
+            bail!("None of the EOG channels {:?} is in the montage {:?}, set --eog-channels", eog, labels);
+        }
+
+        let array = Array2::from_shape_fn((data.len(), len), |(c, t)| data[c][t]);
+        let regression = EogRegression::fit(array.view(), labels, &present, sample_rate as f64)?;
+        for (label, weights) in labels.iter().zip(regression.coefficients().rows()) {
+            info!("EOG propagation into {}: {:?}", label, weights.to_vec());
+        }
+        Ok(Self {
+            calibrated_at: Utc::now(),
+            calibration_seconds: seconds,
+            regression,
+        })
+    }
+
+    /// Read the session's regression, if one was calibrated
+    pub fn load(session_dir: &Path) -> Result<Option<Self>> {
+        let path = session_dir.join(EOG_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let text = fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        let calibration = serde_json::from_str(&text).with_context(|| format!("Failed to parse {:?}", path))?;
+        Ok(Some(calibration))
+    }
+
+    pub fn save(&self, session_dir: &Path) -> Result<()> {
+        fs::create_dir_all(session_dir)?;
+        let path = session_dir.join(EOG_FILE);
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        info!("Saved EOG regression to {:?}", path);
+        Ok(())
+    }
+}
+
+/// Online blink removal on stream samples
+#[derive(Debug, Clone)]
+pub struct EogProcessor {
+    cleaner: EogCleaner,
+    cleaned: Vec<f32>,
+}
+
+impl EogProcessor {
+    pub fn new(calibration: EogCalibrationFile) -> Self {
+        let channels = calibration.regression.labels().len();
+        Self {
+            cleaner: EogCleaner::new(calibration.regression),
+            cleaned: vec![0.0; channels],
+        }
+    }
+
+    /// Subtract the EOG from one sample in place
+    pub fn process(&mut self, sample: &mut Sample) {
+        if sample.data.len() != self.cleaned.len() {
+            return;
+        }
+        self.cleaner.push(&sample.data, &mut self.cleaned);
+        sample.data.copy_from_slice(&self.cleaned);
+    }
+}
@@ -0,0 +1,418 @@
+//! Fixed-length epochs cut around the cues, the model input of EEGNet and
+//! the tiny transformer. Run as `openbci epoch`.
+//!
+//! Every `cue:<class>` marker starts a segment from `--tmin` to `--tmax`
+//! seconds after it (cut short by the next cue or the end of the trial),
+//! which is sliced into `--window` second epochs overlapping by
+//! `--overlap`. Trials recorded without `--cues` are referenced to their
+//! first sample. The epochs go to one NPZ as a `[epochs, channels, samples]`
+//! tensor with a label per epoch, and an `_epochs.csv` next to it says
+//! where each one came from. `--normalize` scales them with the training
+//! set's statistics from `dataset stats`, and `--augment` appends augmented
+//! copies of every epoch for trainers without augmentation of their own.
+
+use crate::compute::ComputeArgs;
+use crate::cue::CUE_PREFIX;
+use crate::dataset;
+use crate::normalize::Normalization;
+use crate::npz;
+use crate::recording::{self, Recording};
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use eeg_dsp::{Augmentation, Augmenter};
+use log::{info, warn};
+use ndarray::Array3;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Options of `openbci epoch`
+#[derive(Args, Debug, Clone)]
+pub struct EpochArgs {
+    /// Root of the recorded dataset
+    #[arg(short, long, default_value = "motor_imagery_data")]
+    pub data_dir: PathBuf,
+
+    /// Epoch only the trials of this `dataset build` manifest, so trial QC
+    /// and the split files apply (every trial of sessions that passed QC if
+    /// omitted)
+    #[arg(short, long)]
+    pub manifest: Option<PathBuf>,
+
+    /// Output NPZ; the epoch list is written next to it as <name>_epochs.csv
+    #[arg(short, long, default_value = "motor_imagery_epochs.npz")]
+    pub output: PathBuf,
+
+    /// Only these subjects (all if omitted)
+    #[arg(long, value_delimiter = ',')]
+    pub subjects: Vec<String>,
+
+    /// Also epoch sessions that failed QC (without --manifest)
+    #[arg(long)]
+    pub include_failed_qc: bool,
+
+    /// Epoch length in seconds
+    #[arg(long, default_value = "2.0")]
+    pub window: f64,
+
+    /// Overlap of consecutive epochs, a fraction in [0, 1)
+    #[arg(long, default_value = "0.5")]
+    pub overlap: f64,
+
+    /// Start of the epoched segment in seconds from the cue
+    #[arg(long, default_value = "0.0", allow_hyphen_values = true)]
+    pub tmin: f64,
+
+    /// End of the epoched segment in seconds from the cue (the next cue or
+    /// the end of the trial if omitted)
+    #[arg(long, allow_hyphen_values = true)]
+    pub tmax: Option<f64>,
+
+    /// Subtract each channel's mean over START:END seconds from the cue,
+    /// e.g. -0.5:0
+    #[arg(long, value_name = "START:END", allow_hyphen_values = true)]
+    pub baseline: Option<Baseline>,
+
+    /// Normalize every channel with these statistics from `dataset stats`,
+    /// after the baseline correction
+    #[arg(long, value_name = "JSON")]
+    pub normalize: Option<PathBuf>,
+
+    /// Append augmented copies of every epoch, made by these steps in
+    /// order: noise=STD, shift=SECONDS, dropout=P, scale=SPREAD, freq=HZ,
+    /// mix=P
+    #[arg(long, value_delimiter = ',')]
+    pub augment: Vec<Augmentation>,
+
+    /// Augmented copies of every epoch with --augment
+    #[arg(long, default_value = "1")]
+    pub augment_copies: usize,
+
+    /// Seed of --augment
+    #[arg(long, default_value = "0")]
+    pub seed: u64,
+
+    #[command(flatten)]
+    pub compute: ComputeArgs,
+}
+
+impl EpochArgs {
+    fn check(&self) -> Result<()> {
+        if self.window <= 0.0 {
+            bail!("--window must be positive, got {}", self.window);
+        }
+        if !(0.0..1.0).contains(&self.overlap) {
+            bail!("--overlap must be in [0, 1), got {}", self.overlap);
+        }
+        if let Some(tmax) = self.tmax {
+            if tmax - self.tmin < self.window {
+                bail!("--tmin {} to --tmax {} is shorter than one {} s window", self.tmin, tmax, self.window);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Baseline interval relative to the cue, parsed from `start:end` seconds
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Baseline {
+    pub start: f64,
+    pub end: f64,
+}
+
+impl FromStr for Baseline {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once(':')
+            .with_context(|| format!("Baseline '{}' must look like start:end", s))?;
+        let baseline = Self {
+            start: start.trim().parse().with_context(|| format!("Invalid baseline start '{}'", start))?,
+            end: end.trim().parse().with_context(|| format!("Invalid baseline end '{}'", end))?,
+        };
+        if baseline.end <= baseline.start {
+            bail!("Baseline '{}' ends before it starts", s);
+        }
+        Ok(baseline)
+    }
+}
+
+/// One epoch, a row of `<name>_epochs.csv`
+#[derive(Debug, Clone, Serialize)]
+pub struct EpochEntry {
+    pub epoch: usize,
+    pub subject_id: String,
+    pub session_id: String,
+    pub trial_number: u32,
+    pub class_label: String,
+    pub class_id: u8,
+    /// Row of the trial's data file the epoch is referenced to
+    pub cue_sample: usize,
+    /// Epoch start in seconds from the cue
+    pub onset: f64,
+    /// Metadata file, relative to the data directory
+    pub source: String,
+    /// 0 for the epoch as cut, from 1 for its augmented copies
+    pub copy: usize,
+}
+
+/// How the epochs were cut, stored as `info` in the NPZ
+#[derive(Debug, Serialize)]
+struct EpochInfo {
+    sample_rate: u32,
+    window: f64,
+    overlap: f64,
+    tmin: f64,
+    tmax: Option<f64>,
+    baseline: Option<Baseline>,
+    normalization: Option<Normalization>,
+    augmentation: Option<Augmenter>,
+    copies: usize,
+}
+
+/// Where the epoch list of `output` goes
+pub fn epochs_path(output: &Path) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    output.with_file_name(format!("{}_epochs.csv", stem))
+}
+
+/// Rows of the cue markers, or the first row for trials without cues
+pub fn cue_samples(rec: &Recording) -> Vec<usize> {
+    let cues: Vec<usize> = rec
+        .markers
+        .iter()
+        .enumerate()
+        .filter(|(_, labels)| labels.split('|').any(|l| l.starts_with(CUE_PREFIX)))
+        .map(|(row, _)| row)
+        .collect();
+    if cues.is_empty() {
+        vec![0]
+    } else {
+        cues
+    }
+}
+
+/// Epochs of one trial as `(cue row, first row, channel-major samples)`,
+/// divided by `scaling`'s scale when given; epochs with missing (NaN)
+/// samples are dropped and counted
+fn cut(
+    rec: &Recording,
+    args: &EpochArgs,
+    rate: f64,
+    scaling: Option<&(Vec<f64>, Vec<f64>)>,
+) -> (Vec<(usize, isize, Vec<f32>)>, usize) {
+    let to_samples = |seconds: f64| (seconds * rate).round() as isize;
+    let length = to_samples(args.window).max(1);
+    let step = args.window * (1.0 - args.overlap);
+    let rows = rec.samples.len() as isize;
+    let cues = cue_samples(rec);
+
+    let (mut epochs, mut dropped) = (Vec::new(), 0);
+    for (i, &cue) in cues.iter().enumerate() {
+        let cue = cue as isize;
+        let next = cues.get(i + 1).map_or(rows, |&c| c as isize);
+        let end = args.tmax.map_or(next, |tmax| (cue + to_samples(tmax)).min(next));
+        let offsets = args.baseline.map(|b| {
+            let (from, to) = (cue + to_samples(b.start), cue + to_samples(b.end));
+            if from < 0 || to > rows {
+                return None;
+            }
+            let span = &rec.samples[from as usize..to as usize];
+            let means: Vec<f64> = (0..rec.num_channels())
+                .map(|ch| span.iter().map(|r| r[ch] as f64).sum::<f64>() / span.len().max(1) as f64)
+                .collect();
+            means.iter().all(|m| m.is_finite()).then_some(means)
+        });
+        let offsets = match offsets {
+            Some(None) => {
+                warn!(
+                    "{:?}: baseline of the cue at row {} is outside the trial or has missing samples, skipped",
+                    rec.metadata_path, cue
+                );
+                continue;
+            }
+            Some(Some(offsets)) => offsets,
+            // The baseline takes the place of the normalization's center
+            None => scaling.map_or_else(|| vec![0.0; rec.num_channels()], |(center, _)| center.clone()),
+        };
+        let scale = scaling.map_or_else(|| vec![1.0; rec.num_channels()], |(_, scale)| scale.clone());
+
+        // Onsets are rounded one by one so they do not drift, and windows
+        // starting before the trial are left out rather than shifted. Steps
+        // under a sample would repeat a window.
+        let mut previous = None;
+        for k in 0.. {
+            let start = cue + to_samples(args.tmin + k as f64 * step);
+            if start + length > end {
+                break;
+            }
+            if start >= 0 && previous != Some(start) {
+                previous = Some(start);
+                let span = &rec.samples[start as usize..(start + length) as usize];
+                if span.iter().flatten().any(|v| !v.is_finite()) {
+                    dropped += 1;
+                } else {
+                    let mut values = Vec::with_capacity(span.len() * offsets.len());
+                    for (ch, (offset, scale)) in offsets.iter().zip(&scale).enumerate() {
+                        values.extend(span.iter().map(|r| ((r[ch] as f64 - offset) / scale) as f32));
+                    }
+                    epochs.push((cue as usize, start - cue, values));
+                }
+            }
+        }
+    }
+    (epochs, dropped)
+}
+
+/// Cut the selected trials into epochs and write the NPZ and epoch list;
+/// returns the list
+pub fn run(args: &EpochArgs) -> Result<Vec<EpochEntry>> {
+    args.check()?;
+    if args.output.extension().and_then(|e| e.to_str()) != Some("npz") {
+        bail!("Epochs are written as .npz, not {:?}", args.output);
+    }
+    let normalization = args.normalize.as_deref().map(Normalization::load).transpose()?;
+    let compute = args.compute.apply()?;
+
+    let trials: Vec<PathBuf> = match &args.manifest {
+        Some(manifest) => dataset::load_manifest(manifest)?
+            .into_iter()
+            .filter(|e| args.subjects.is_empty() || args.subjects.contains(&e.subject_id))
+            .map(|e| args.data_dir.join(e.source))
+            .collect(),
+        None => recording::find_trials(&args.data_dir, args.include_failed_qc)?,
+    };
+    if trials.is_empty() {
+        bail!("No trials found under {:?}", args.data_dir);
+    }
+
+    // The first trial fixes the channel layout and rate of the tensor
+    let mut layout: Option<(Vec<String>, u32)> = None;
+    let mut scaling = None;
+    let (mut values, mut labels, mut entries) = (Vec::new(), Vec::new(), Vec::new());
+    let (mut skipped, mut dropped) = (0, 0);
+    for batch in trials.chunks(compute.threads) {
+        let loaded: Vec<_> = batch.par_iter().map(|path| Recording::load(path)).collect();
+        for (path, rec) in batch.iter().zip(loaded) {
+            let rec = match rec {
+                Ok(rec) => rec,
+                Err(e) => {
+                    warn!("Skipping {:?}: {:#}", path, e);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            let meta = &rec.metadata;
+            if !args.subjects.is_empty() && !args.subjects.contains(&meta.subject_id) {
+                continue;
+            }
+            let (channels, rate) = layout.get_or_insert_with(|| (rec.channel_names.clone(), meta.sample_rate));
+            if rec.channel_names != *channels || meta.sample_rate != *rate {
+                warn!("Skipping {:?}: channel layout or sample rate differs from the first trial", path);
+                skipped += 1;
+                continue;
+            }
+            let rate = *rate as f64;
+            if let (None, Some(normalization)) = (&scaling, &normalization) {
+                scaling = Some(normalization.for_channels(channels)?);
+            }
+
+            let (epochs, nan) = cut(&rec, args, rate, scaling.as_ref());
+            dropped += nan;
+            let source = path.strip_prefix(&args.data_dir).unwrap_or(path).display().to_string();
+            for (cue_sample, offset, epoch) in epochs {
+                values.extend(epoch);
+                labels.push(meta.class_id as i64);
+                entries.push(EpochEntry {
+                    epoch: entries.len(),
+                    subject_id: meta.subject_id.clone(),
+                    session_id: meta.session_id.clone(),
+                    trial_number: meta.trial_number,
+                    class_label: meta.class_label.clone(),
+                    class_id: meta.class_id,
+                    cue_sample,
+                    onset: offset as f64 / rate,
+                    source: source.clone(),
+                    copy: 0,
+                });
+            }
+        }
+    }
+    let Some((channels, sample_rate)) = layout else {
+        bail!("No trial under {:?} could be read", args.data_dir);
+    };
+    if entries.is_empty() {
+        bail!("No {} s epoch fits in the trials under {:?}", args.window, args.data_dir);
+    }
+
+    let samples = values.len() / entries.len() / channels.len().max(1);
+    let augmentation = match args.augment.is_empty() {
+        true => None,
+        false => Some(Augmenter::new(args.augment.clone(), sample_rate as f64, args.seed)?),
+    };
+    let copies = if augmentation.is_some() { args.augment_copies } else { 0 };
+    if let Some(augmenter) = &augmentation {
+        let cut = Array3::from_shape_vec((entries.len(), channels.len(), samples), values.clone())?;
+        let classes: Vec<usize> = labels.iter().map(|&id| id as usize).collect();
+        let originals = entries.clone();
+        for copy in 1..=copies {
+            let mut augmented = cut.clone();
+            augmenter.apply(&mut augmented, &classes, copy as u64)?;
+            values.extend(augmented.iter());
+            labels.extend(classes.iter().map(|&id| id as i64));
+            entries.extend(originals.iter().map(|entry| EpochEntry {
+                epoch: entry.epoch + copy * originals.len(),
+                copy,
+                ..entry.clone()
+            }));
+        }
+        info!("Appended {} augmented copies of every epoch", copies);
+    }
+    let info = EpochInfo {
+        sample_rate,
+        window: args.window,
+        overlap: args.overlap,
+        tmin: args.tmin,
+        tmax: args.tmax,
+        baseline: args.baseline,
+        normalization,
+        augmentation,
+        copies,
+    };
+    let arrays = [
+        ("X", npz::npy_f32_nd(&[entries.len(), channels.len(), samples], &values)),
+        ("y", npz::npy_i64(&labels)),
+        ("channels", npz::npy_str_scalar(&serde_json::to_string(&channels)?)),
+        ("info", npz::npy_str_scalar(&serde_json::to_string(&info)?)),
+    ];
+    npz::write_npz(&args.output, &arrays, true)?;
+
+    let epochs_path = epochs_path(&args.output);
+    let mut writer =
+        csv::Writer::from_path(&epochs_path).with_context(|| format!("Failed to create {:?}", epochs_path))?;
+    for entry in &entries {
+        writer.serialize(entry)?;
+    }
+    writer.flush()?;
+
+    let mut per_class: BTreeMap<&str, usize> = BTreeMap::new();
+    for entry in &entries {
+        *per_class.entry(entry.class_label.as_str()).or_default() += 1;
+    }
+    info!(
+        "Wrote {} epochs of {} channels x {} samples to {:?}: {}",
+        entries.len(),
+        channels.len(),
+        samples,
+        args.output,
+        per_class.iter().map(|(class, n)| format!("{} {}", class, n)).collect::<Vec<_>>().join(", ")
+    );
+    info!(
+        "Skipped {} trials, dropped {} epochs with missing samples; epoch list in {:?}",
+        skipped, dropped, epochs_path
+    );
+    Ok(entries)
+}
@@ -0,0 +1,154 @@
+//! Post-trial ERD quick-look over the motor cortex.
+//!
+//! While a trial records, C3 and C4 are kept. When it ends, their mu and
+//! beta power over the imagery window, from the cue to the end, is
+//! compared with a rest baseline: the fixation before the cue in cued
+//! trials, else the session's earlier `rest` trials. Imagery of one hand
+//! should lower mu power over the opposite hemisphere, so the
+//! lateralization `C3 - C4` of the mu changes comes out negative for the
+//! right hand and positive for the left. Its mean per class, after every
+//! trial, shows early whether the subject's classes separate.
+
+use crate::bandpower::{self, ChannelPower, MOTOR_CHANNELS};
+use crate::metadata::{ErdInfo, TrialMetadata};
+use std::collections::BTreeMap;
+
+/// Least window, fixation or imagery, one Welch segment
+const MIN_WINDOW_SECONDS: f64 = 1.0;
+
+/// C3 and C4 of one trial, split at the cue
+pub struct ErdRecorder {
+    sample_rate: f64,
+    /// Index into the sample and label of every followed channel
+    channels: Vec<(usize, String)>,
+    fixation: Vec<Vec<f32>>,
+    imagery: Vec<Vec<f32>>,
+    cued: bool,
+}
+
+impl ErdRecorder {
+    /// Follow the first C3 and C4 among `labels` (10-20 labels in channel
+    /// order). `None` without either, or below the 60 Hz the beta band
+    /// needs.
+    pub fn new(labels: &[String], sample_rate: u32) -> Option<Self> {
+        let channels: Vec<(usize, String)> = MOTOR_CHANNELS
+            .iter()
+            .filter_map(|&c| Some((labels.iter().position(|l| l == c)?, c.to_string())))
+            .collect();
+        if channels.is_empty() || sample_rate <= 60 {
+            return None;
+        }
+        Some(Self {
+            sample_rate: sample_rate as f64,
+            fixation: vec![Vec::new(); channels.len()],
+            imagery: vec![Vec::new(); channels.len()],
+            channels,
+            cued: false,
+        })
+    }
+
+    pub fn push(&mut self, sample: &[f32]) {
+        let window = if self.cued { &mut self.imagery } else { &mut self.fixation };
+        for (signal, (index, _)) in window.iter_mut().zip(&self.channels) {
+            signal.push(sample.get(*index).copied().unwrap_or(0.0));
+        }
+    }
+
+    /// The cue appeared: what follows is imagery. Later cues are ignored.
+    pub fn cue(&mut self) {
+        self.cued = true;
+    }
+
+    /// Powers of the imagery window and their change against the fixation,
+    /// or against the mean of `session`'s rest trials when the trial had no
+    /// cue or too short a fixation. `None` if the window is under a second.
+    pub fn finish(&self, session: &[TrialMetadata]) -> Option<ErdInfo> {
+        // Without a cue the whole trial is the window
+        let window = if self.cued { &self.imagery } else { &self.fixation };
+        let seconds = window.first().map_or(0, |s| s.len()) as f64 / self.sample_rate;
+        if seconds < MIN_WINDOW_SECONDS {
+            return None;
+        }
+        let fixation_seconds = self.fixation.first().map_or(0, |s| s.len()) as f64 / self.sample_rate;
+        let (baseline, references) = if self.cued && fixation_seconds >= MIN_WINDOW_SECONDS {
+            let powers = self.fixation.iter().map(|s| Some(bandpower::mu_beta(s, self.sample_rate))).collect();
+            (Some("fixation".to_string()), powers)
+        } else {
+            let rest = rest_powers(session);
+            let powers: Vec<Option<[f64; 2]>> = self.channels.iter().map(|(_, label)| rest.get(label).copied()).collect();
+            let baseline = powers.iter().any(Option::is_some).then(|| "rest_trials".to_string());
+            (baseline, powers)
+        };
+
+        let channels = self
+            .channels
+            .iter()
+            .zip(window)
+            .zip(references)
+            .map(|(((_, label), signal), reference)| {
+                let [mu, beta] = bandpower::mu_beta(signal, self.sample_rate);
+                ChannelPower {
+                    label: label.clone(),
+                    mu,
+                    beta,
+                    mu_change: reference.map(|[r, _]| bandpower::change(mu, r)),
+                    beta_change: reference.map(|[_, r]| bandpower::change(beta, r)),
+                }
+            })
+            .collect();
+        Some(ErdInfo {
+            baseline,
+            window_seconds: seconds,
+            channels,
+        })
+    }
+}
+
+/// Mean `[mu, beta]` per channel over the imagery windows of the rest
+/// trials among `session`
+fn rest_powers(session: &[TrialMetadata]) -> BTreeMap<String, [f64; 2]> {
+    let mut sums: BTreeMap<String, ([f64; 2], usize)> = BTreeMap::new();
+    for erd in session.iter().filter(|t| t.class_label == "rest").filter_map(|t| t.erd.as_ref()) {
+        for channel in &erd.channels {
+            let (sum, count) = sums.entry(channel.label.clone()).or_default();
+            sum[0] += channel.mu;
+            sum[1] += channel.beta;
+            *count += 1;
+        }
+    }
+    sums.into_iter()
+        .map(|(label, ([mu, beta], count))| (label, [mu / count as f64, beta / count as f64]))
+        .collect()
+}
+
+/// `C3 - C4` of the mu changes in percentage points, when both channels
+/// have one
+pub fn lateralization(erd: &ErdInfo) -> Option<f64> {
+    let change = |label: &str| erd.channels.iter().find(|c| c.label == label)?.mu_change;
+    Some(change("C3")? - change("C4")?)
+}
+
+/// Sign `lateralization` should have for `class`, for the one-hand classes
+pub fn expected_sign(class: &str) -> Option<f64> {
+    match class {
+        "left_hand" => Some(1.0),
+        "right_hand" => Some(-1.0),
+        _ => None,
+    }
+}
+
+/// Mean lateralization and trial count of every class among `trials`
+pub fn class_lateralization(trials: &[TrialMetadata]) -> BTreeMap<String, (f64, usize)> {
+    let mut classes: BTreeMap<String, (f64, usize)> = BTreeMap::new();
+    for trial in trials {
+        if let Some(index) = trial.erd.as_ref().and_then(lateralization) {
+            let (sum, count) = classes.entry(trial.class_label.clone()).or_default();
+            *sum += index;
+            *count += 1;
+        }
+    }
+    for (sum, count) in classes.values_mut() {
+        *sum /= *count as f64;
+    }
+    classes
+}
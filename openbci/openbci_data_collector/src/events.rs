@@ -0,0 +1,185 @@
+//! Events of a trial: cue onsets, key presses, stream gaps and injected
+//! artifacts, read back from the recording.
+//!
+//! Markers are stored per sample (the CSV `marker` column, BDF/EDF
+//! annotations, BrainVision markers). Every trial also gets an
+//! `_events.tsv` next to its data file in the BIDS layout (onset, duration,
+//! trial_type, value, sample), whatever its formats, so epochs can be cut
+//! relative to the cues without parsing the data file. A rotated recording
+//! gets one per segment, counted from the segment's first row. Labels of
+//! the form `<type>:<value>` are split into the two columns, e.g.
+//! `cue:left_hand` or `gap:12`.
+
+use crate::augment::{ARTIFACT_END, ARTIFACT_START};
+use crate::compress;
+use crate::recording::Recording;
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Marker prefix of samples missing from the stream, followed by their count
+pub const GAP: &str = "gap:";
+/// Marker prefix of a jump in the board clock, followed by its size in seconds
+pub const CLOCK_JUMP: &str = "clock_jump:";
+/// Marker prefix of the output disk running low, followed by the MB left
+pub const DISK_LOW: &str = "disk_low:";
+/// Marker prefix of a key pressed during recording, followed by the key
+pub const KEY: &str = "key:";
+/// Marker prefix of an experimenter annotation made with a hotkey, followed
+/// by its label
+pub const FLAG: &str = "flag:";
+/// Hotkey annotation that excludes the trial from training (see QC)
+pub const BAD_TRIAL: &str = "flag:bad_trial";
+
+/// One row of `_events.tsv`
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// Row of the data file the event starts at
+    pub sample: usize,
+    /// `None` for instantaneous markers
+    pub samples: Option<usize>,
+    pub trial_type: String,
+    pub value: Option<String>,
+}
+
+/// The trial-long class event, then the recorded markers in file order with
+/// artifact brackets merged into one event each
+pub fn events_of(recording: &Recording) -> Vec<Event> {
+    let metadata = &recording.metadata;
+    let mut events = vec![Event {
+        sample: 0,
+        samples: Some(recording.samples.len()),
+        trial_type: metadata.class_label.clone(),
+        value: Some(metadata.class_id.to_string()),
+    }];
+
+    // Filled gaps span their rows in the file
+    let filled = metadata.stream_health.as_ref().is_some_and(|h| h.fill.is_some());
+    let mut open: Vec<(String, usize)> = Vec::new();
+    for (row, labels) in recording.markers.iter().enumerate() {
+        for label in labels.split('|').filter(|l| !l.is_empty()) {
+            if let Some(kind) = label.strip_prefix(ARTIFACT_START) {
+                open.push((kind.to_string(), row));
+            } else if let Some(kind) = label.strip_prefix(ARTIFACT_END) {
+                if let Some(i) = open.iter().position(|(k, _)| k == kind) {
+                    let (kind, start) = open.remove(i);
+                    events.push(artifact(kind, start, row));
+                }
+            } else {
+                let (trial_type, value) = match label.split_once(':') {
+                    Some((kind, value)) => (kind, Some(value.to_string())),
+                    None => (label, None),
+                };
+                let samples = match label.strip_prefix(GAP) {
+                    Some(missing) if filled => missing.parse().ok(),
+                    _ => None,
+                };
+                events.push(Event {
+                    sample: row,
+                    samples,
+                    trial_type: trial_type.to_string(),
+                    value,
+                });
+            }
+        }
+    }
+    // Artifacts still running when the trial ended
+    for (kind, start) in open {
+        events.push(artifact(kind, start, recording.samples.len()));
+    }
+
+    events.sort_by_key(|e| e.sample);
+    events
+}
+
+fn artifact(kind: String, start: usize, end: usize) -> Event {
+    Event {
+        sample: start,
+        samples: Some(end - start),
+        trial_type: format!("artifact_{}", kind),
+        value: None,
+    }
+}
+
+/// `events` as a BIDS `_events.tsv`, onsets in seconds from the first sample
+pub fn events_tsv(events: &[Event], sample_rate: u32) -> String {
+    let rate = sample_rate.max(1) as f64;
+    let mut tsv_text = String::from("onset\tduration\ttrial_type\tvalue\tsample\n");
+    for event in events {
+        let duration = event.samples.map_or("n/a".to_string(), |n| format!("{:.4}", n as f64 / rate));
+        // Writing to a String cannot fail
+        let _ = writeln!(
+            tsv_text,
+            "{:.4}\t{}\t{}\t{}\t{}",
+            event.sample as f64 / rate,
+            duration,
+            tsv(&event.trial_type),
+            event.value.as_deref().map_or("n/a".to_string(), tsv),
+            event.sample
+        );
+    }
+    tsv_text
+}
+
+/// Where the events of the trial with data file `data_path` go
+pub fn events_path(data_path: &Path) -> PathBuf {
+    let data_path = compress::uncompressed_path(data_path);
+    let stem = data_path.file_stem().unwrap_or_default().to_string_lossy();
+    data_path.with_file_name(format!("{}_events.tsv", stem))
+}
+
+/// The events of rows `first..first + rows`, counted from `first`. The
+/// class event and artifacts running across the edges are cut to the rows.
+fn segment_events(events: &[Event], first: usize, rows: usize) -> Vec<Event> {
+    let end = first + rows;
+    events
+        .iter()
+        .filter_map(|event| {
+            let event_end = event.sample + event.samples.unwrap_or(0);
+            let inside = (first..end).contains(&event.sample);
+            let spans = event.samples.is_some() && event.sample < first && event_end > first;
+            if !inside && !spans {
+                return None;
+            }
+            let start = event.sample.max(first);
+            Some(Event {
+                sample: start - first,
+                samples: event.samples.map(|_| event_end.min(end) - start),
+                ..event.clone()
+            })
+        })
+        .collect()
+}
+
+/// Write the events of `recording` next to its data file, or those of
+/// each segment next to the segment's files; returns the paths
+pub fn write_events(recording: &Recording) -> Result<Vec<PathBuf>> {
+    let metadata = &recording.metadata;
+    let events = events_of(recording);
+    let dir = recording.data_path.parent().unwrap_or(Path::new(""));
+    let files: Vec<(PathBuf, Vec<Event>)> = if metadata.segments.is_empty() {
+        vec![(events_path(&recording.data_path), events)]
+    } else {
+        metadata
+            .segments
+            .iter()
+            .filter_map(|segment| {
+                let file = segment.files.first()?;
+                let events = segment_events(&events, segment.first_sample as usize, segment.samples as usize);
+                Some((events_path(&dir.join(file)), events))
+            })
+            .collect()
+    };
+    let mut written = Vec::new();
+    for (path, events) in files {
+        fs::write(&path, events_tsv(&events, metadata.sample_rate)).with_context(|| format!("Failed to write {:?}", path))?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// TSV cells cannot hold tabs or line breaks
+pub(crate) fn tsv(cell: &str) -> String {
+    cell.replace(['\t', '\n', '\r'], " ")
+}
@@ -0,0 +1,20 @@
+//! Zero-phase band-pass filtering of recorded trials. Band powers, CSP and
+//! covariance geometry come from `eeg_dsp`; connectivity features live in
+//! [`crate::connectivity`].
+
+use anyhow::{Context, Result};
+use eeg_dsp::{Band, FilterBank, FilterSpec, Response};
+
+/// Zero-phase band-pass: second-order Butterworth high- and low-pass
+/// sections run forward and backward (see `eeg_dsp::FilterBank::filtfilt`)
+pub fn bandpass(signal: &[f32], sample_rate: f64, band: &Band) -> Result<Vec<f32>> {
+    let mut specs = vec![FilterSpec::butterworth(2, Response::HighPass(band.low))];
+    if band.high < sample_rate / 2.0 {
+        specs.push(FilterSpec::butterworth(2, Response::LowPass(band.high)));
+    }
+    let mut filter = FilterBank::<f64>::from_specs(&specs, sample_rate, 1)
+        .with_context(|| format!("Cannot band-pass to {}-{} Hz", band.low, band.high))?;
+    let mut data: Vec<f64> = signal.iter().map(|&x| x as f64).collect();
+    filter.filtfilt(0, &mut data);
+    Ok(data.into_iter().map(|x| x as f32).collect())
+}
@@ -0,0 +1,139 @@
+//! Online band-pass, notch and common average reference before samples are
+//! written.
+//!
+//! Every channel runs through a cascade of second-order sections: an
+//! even-order Butterworth high-pass and low-pass for `--bandpass` and a
+//! notch at the power line frequency for `--notch`. `--car` then subtracts
+//! the mean of the channels from each of them. The sections are causal
+//! and keep their state from sample to sample, so the recorded data is
+//! delayed by the filters' group delay (a few samples in the pass band)
+//! but never looks ahead. The sections are designed and run by
+//! `eeg_dsp::FilterBank`, settled on the first sample so the board's DC
+//! offset does not ring through the trial.
+
+use crate::metadata::OnlineFilterInfo;
+use anyhow::{bail, Context, Result};
+use eeg_dsp::{Biquad, FilterBank, FilterSpec, Response};
+use std::str::FromStr;
+
+/// Order of the high- and low-pass edges
+pub const ORDER: u32 = 4;
+/// Quality factor of the notch, about 1.7 Hz wide at 50 Hz
+pub const NOTCH_Q: f64 = 30.0;
+
+/// Pass band in Hz, parsed from `low-high`
+#[derive(Debug, Clone, Copy)]
+pub struct Passband {
+    pub low: f64,
+    pub high: f64,
+}
+
+impl FromStr for Passband {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (low, high) = s
+            .split_once('-')
+            .with_context(|| format!("Pass band '{}' must look like low-high, e.g. 1-40", s))?;
+        Ok(Self {
+            low: low.trim().parse().with_context(|| format!("Pass band '{}' has no low edge", s))?,
+            high: high.trim().parse().with_context(|| format!("Pass band '{}' has no high edge", s))?,
+        })
+    }
+}
+
+/// Band-pass and notch filter with per-channel state, and common average
+/// reference
+pub struct OnlineFilter {
+    bank: FilterBank<f64>,
+    /// Whether the first sample has settled the sections
+    settled: bool,
+    frame: Vec<f64>,
+    /// Channels each common average is taken over, `None` without CAR
+    car_group: Option<usize>,
+    info: OnlineFilterInfo,
+}
+
+impl OnlineFilter {
+    /// `None` if no pass band, notch or common average reference is given.
+    /// The average is taken over consecutive groups of `car_group`
+    /// channels, e.g. those of each board recording its own subject.
+    pub fn new(
+        bandpass: Option<Passband>,
+        notch_hz: Option<f64>,
+        car_group: Option<usize>,
+        sample_rate: u32,
+        num_channels: usize,
+    ) -> Result<Option<Self>> {
+        if bandpass.is_none() && notch_hz.is_none() && car_group.is_none() {
+            return Ok(None);
+        }
+        if car_group.is_some_and(|group| group < 2) {
+            bail!("A common average reference needs at least 2 channels");
+        }
+        let fs = sample_rate as f64;
+        let nyquist = fs / 2.0;
+        let mut sections = Vec::new();
+        if let Some(band) = bandpass {
+            if !(band.low > 0.0 && band.low < band.high && band.high < nyquist) {
+                bail!(
+                    "Pass band {}-{} Hz must satisfy 0 < low < high < {} Hz (half the sample rate)",
+                    band.low,
+                    band.high,
+                    nyquist
+                );
+            }
+            sections.extend(FilterSpec::butterworth(ORDER as usize, Response::HighPass(band.low)).design(fs)?);
+            sections.extend(FilterSpec::butterworth(ORDER as usize, Response::LowPass(band.high)).design(fs)?);
+        }
+        if let Some(frequency) = notch_hz {
+            if !(frequency > 0.0 && frequency < nyquist) {
+                bail!("Notch at {} Hz must be between 0 and {} Hz (half the sample rate)", frequency, nyquist);
+            }
+            sections.push(Biquad::notch(frequency, fs, NOTCH_Q));
+        }
+
+        Ok(Some(Self {
+            bank: FilterBank::new(&sections, num_channels),
+            settled: false,
+            frame: vec![0.0; num_channels],
+            car_group,
+            info: OnlineFilterInfo {
+                highpass_hz: bandpass.map(|b| b.low),
+                lowpass_hz: bandpass.map(|b| b.high),
+                order: bandpass.map(|_| ORDER),
+                notch_hz,
+                notch_q: notch_hz.map(|_| NOTCH_Q),
+                common_average_reference: car_group.is_some(),
+            },
+        }))
+    }
+
+    /// What the filter does, for the trial metadata
+    pub fn info(&self) -> &OnlineFilterInfo {
+        &self.info
+    }
+
+    /// Filter one sample in place, one value per channel
+    pub fn process(&mut self, data: &mut [f32]) {
+        for (x, &value) in self.frame.iter_mut().zip(data.iter()) {
+            *x = value as f64;
+        }
+        if !self.settled {
+            self.bank.settle(&self.frame);
+            self.settled = true;
+        }
+        self.bank.process_frame(&mut self.frame);
+        for (value, &x) in data.iter_mut().zip(&self.frame) {
+            *value = x as f32;
+        }
+        if let Some(group) = self.car_group {
+            for channels in data.chunks_mut(group) {
+                let mean = channels.iter().map(|&v| v as f64).sum::<f64>() / channels.len() as f64;
+                for value in channels {
+                    *value = (*value as f64 - mean) as f32;
+                }
+            }
+        }
+    }
+}
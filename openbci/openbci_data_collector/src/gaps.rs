@@ -0,0 +1,131 @@
+//! Gap accounting from board timestamps.
+//!
+//! The WiFi shield stamps every sample with its own clock but carries no
+//! sequence number, so lost samples only show up as a step in the
+//! timestamps. A step the host clock did not see as well is the board
+//! clock jumping (NTP resync, RTC reset) rather than data going missing.
+//!
+//! With `--fill-gaps`, the rows of a gap are written anyway, as NaN or
+//! interpolated between the samples either side, so a trial's row numbers
+//! stay proportional to time. Clock jumps are not filled: the timestamps
+//! across them cannot place the rows.
+
+use anyhow::{bail, Result};
+use openbci_wifi_client::timestamp_seconds;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+/// Disagreement between board and host clocks, in seconds, above which a
+/// step counts as a clock jump. Covers delivery jitter and ASR lookahead.
+const CLOCK_JUMP_TOLERANCE: f64 = 1.0;
+
+/// Slack on the expected sample interval: the shield stamps whole ms
+const TIMESTAMP_RESOLUTION: f64 = 0.001;
+
+/// A break between two consecutive samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Discontinuity {
+    /// `missing` samples were lost
+    Gap { missing: u64 },
+    /// The board clock stepped by `seconds`; `missing` is estimated from
+    /// the host clock
+    ClockJump { seconds: f64, missing: u64 },
+}
+
+/// Watches consecutive sample timestamps against their arrival times
+#[derive(Debug)]
+pub struct GapDetector {
+    sample_rate: f64,
+    /// Board time (s) and arrival of the previous sample
+    last: Option<(f64, Instant)>,
+}
+
+impl GapDetector {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate: sample_rate as f64,
+            last: None,
+        }
+    }
+
+    /// Feed the next sample's board timestamp and arrival time
+    pub fn push(&mut self, timestamp: f64, received: Instant) -> Option<Discontinuity> {
+        let board = timestamp_seconds(timestamp);
+        let previous = self.last.replace((board, received));
+        let (last_board, last_received) = previous?;
+
+        let interval = 1.0 / self.sample_rate;
+        let step = board - last_board;
+        let host_step = received.duration_since(last_received).as_secs_f64();
+        if step < 0.0 || step - host_step > CLOCK_JUMP_TOLERANCE {
+            // Timestamps are unusable across the jump; the host clock only
+            // resolves losses longer than the tolerance
+            let missing = if host_step > CLOCK_JUMP_TOLERANCE {
+                (host_step * self.sample_rate).round() as u64
+            } else {
+                0
+            };
+            return Some(Discontinuity::ClockJump { seconds: step, missing });
+        }
+        if step > 1.5 * interval + TIMESTAMP_RESOLUTION {
+            let missing = ((step * self.sample_rate).round() as u64).saturating_sub(1);
+            return Some(Discontinuity::Gap { missing });
+        }
+        None
+    }
+}
+
+/// What the rows of a gap hold, for `--fill-gaps`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GapFill {
+    /// Not-a-number; formats that cannot store it (BDF, EDF) hold 0
+    Nan,
+    /// Straight line from the sample before the gap to the one after
+    Interpolate,
+}
+
+impl GapFill {
+    /// The `missing` rows between samples `before` and `after`, each with a
+    /// timestamp spaced evenly between theirs
+    pub fn rows(self, before: (f64, &[f32]), after: (f64, &[f32]), missing: u64) -> Vec<(f64, Vec<f32>)> {
+        let ((t0, x0), (t1, x1)) = (before, after);
+        let steps = (missing + 1) as f64;
+        (1..=missing)
+            .map(|k| {
+                let fraction = k as f64 / steps;
+                let values = match self {
+                    Self::Nan => vec![f32::NAN; x1.len()],
+                    Self::Interpolate => {
+                        let fraction = fraction as f32;
+                        x0.iter().zip(x1).map(|(a, b)| a + (b - a) * fraction).collect()
+                    }
+                };
+                (t0 + (t1 - t0) * fraction, values)
+            })
+            .collect()
+    }
+}
+
+impl FromStr for GapFill {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "nan" => Ok(Self::Nan),
+            "interpolate" | "linear" => Ok(Self::Interpolate),
+            _ => bail!("Unknown gap fill '{}', expected nan or interpolate", s),
+        }
+    }
+}
+
+impl fmt::Display for GapFill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Nan => "nan",
+            Self::Interpolate => "interpolate",
+        })
+    }
+}
@@ -0,0 +1,101 @@
+//! UDP output in the OpenBCI GUI networking format.
+//!
+//! Re-emits the samples the collector records (after artifact injection)
+//! as `timeSeriesRaw` JSON packets, the format the GUI's Networking widget
+//! speaks over UDP, so the official GUI can be used for a visual sanity
+//! check while recording.
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+
+/// Packets per second; keeps each datagram well below a typical MTU
+const PACKETS_PER_SECOND: u32 = 25;
+
+#[derive(Serialize)]
+struct TimeSeriesPacket<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// Channel-major: `data[channel][sample]`, in microvolts
+    data: &'a [Vec<f32>],
+}
+
+/// Batches samples and sends them to the GUI. Sending never blocks the
+/// recording loop; datagrams the socket cannot take are dropped.
+pub struct GuiBridge {
+    socket: UdpSocket,
+    target: SocketAddr,
+    batch: usize,
+    channels: Vec<Vec<f32>>,
+    sent: u64,
+    dropped: u64,
+}
+
+impl GuiBridge {
+    pub fn connect(target: SocketAddr, num_channels: usize, sample_rate: u32) -> Result<Self> {
+        let bind: SocketAddr = if target.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(bind).context("Failed to bind UDP socket for the GUI bridge")?;
+        socket.set_nonblocking(true)?;
+        let batch = (sample_rate / PACKETS_PER_SECOND).max(1) as usize;
+        info!("Streaming to OpenBCI GUI at udp://{} ({} samples per packet)", target, batch);
+        Ok(Self {
+            socket,
+            target,
+            batch,
+            channels: vec![Vec::with_capacity(batch); num_channels],
+            sent: 0,
+            dropped: 0,
+        })
+    }
+
+    /// Queue one sample (nanovolts, as recorded) and send a packet once a
+    /// batch is full
+    pub fn push(&mut self, sample: &[f32]) {
+        for (channel, value) in self.channels.iter_mut().zip(sample) {
+            channel.push(value / 1000.0);
+        }
+        if self.channels.first().is_some_and(|c| c.len() >= self.batch) {
+            self.flush();
+        }
+    }
+
+    /// Send whatever is queued
+    pub fn flush(&mut self) {
+        if self.channels.first().is_none_or(|c| c.is_empty()) {
+            return;
+        }
+        let packet = TimeSeriesPacket {
+            kind: "timeSeriesRaw",
+            data: &self.channels,
+        };
+        match serde_json::to_vec(&packet) {
+            Ok(mut bytes) => {
+                bytes.extend_from_slice(b"\r\n");
+                match self.socket.send_to(&bytes, self.target) {
+                    Ok(_) => self.sent += 1,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => self.dropped += 1,
+                    Err(e) => {
+                        // Nobody listening yet shows up as ConnectionRefused
+                        debug!("GUI bridge send failed: {}", e);
+                        self.dropped += 1;
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to encode GUI packet: {}", e),
+        }
+        for channel in &mut self.channels {
+            channel.clear();
+        }
+    }
+
+    /// Packets sent and dropped so far
+    pub fn counts(&self) -> (u64, u64) {
+        (self.sent, self.dropped)
+    }
+}
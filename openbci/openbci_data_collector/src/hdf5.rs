@@ -0,0 +1,293 @@
+//! HDF5 trial and dataset files for `--format hdf5` and `dataset build`.
+//!
+//! A trial is one `.h5` file of flat datasets, named like the NPZ arrays
+//! where they overlap:
+//!
+//! - `X`: float32, shape `[samples, channels]`, nanovolts
+//! - `y`: int64 scalar, the class ID
+//! - `meta`: string scalar holding the trial metadata JSON
+//! - `timestamps`: float64 `[samples]`, as in the CSV `timestamp` column
+//! - `channels`: strings, the column names
+//! - `event_sample`, `event_label`: the row and label of every marker
+//!
+//! so `h5py.File(path)["X"][:]` reads a trial without the metadata file.
+//! Strings are fixed-length and null-padded. Datasets are written in one
+//! go when the file is finished, like NPZ.
+//!
+//! Links against libhdf5 (1.10 or later), hence the `hdf5` feature. The
+//! library is not thread-safe in its default build, so every file is
+//! written and read under one lock.
+
+use anyhow::{bail, Context, Result};
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::path::Path;
+use std::sync::Mutex;
+
+type Hid = i64;
+type Herr = c_int;
+type Hsize = u64;
+
+const H5F_ACC_RDONLY: c_uint = 0x0000;
+const H5F_ACC_TRUNC: c_uint = 0x0002;
+const H5P_DEFAULT: Hid = 0;
+const H5S_ALL: Hid = 0;
+/// `H5S_class_t` of a single value
+const H5S_SCALAR: c_int = 0;
+/// `H5T_str_t`: strings that fill their size are not terminated
+const H5T_STR_NULLPAD: c_int = 1;
+
+#[link(name = "hdf5")]
+extern "C" {
+    fn H5open() -> Herr;
+    fn H5Fcreate(name: *const c_char, flags: c_uint, fcpl: Hid, fapl: Hid) -> Hid;
+    fn H5Fopen(name: *const c_char, flags: c_uint, fapl: Hid) -> Hid;
+    fn H5Fclose(file: Hid) -> Herr;
+    fn H5Screate(class: c_int) -> Hid;
+    fn H5Screate_simple(rank: c_int, dims: *const Hsize, maxdims: *const Hsize) -> Hid;
+    fn H5Sget_simple_extent_ndims(space: Hid) -> c_int;
+    fn H5Sget_simple_extent_dims(space: Hid, dims: *mut Hsize, maxdims: *mut Hsize) -> c_int;
+    fn H5Sclose(space: Hid) -> Herr;
+    fn H5Dcreate2(loc: Hid, name: *const c_char, dtype: Hid, space: Hid, lcpl: Hid, dcpl: Hid, dapl: Hid) -> Hid;
+    fn H5Dopen2(loc: Hid, name: *const c_char, dapl: Hid) -> Hid;
+    fn H5Dget_space(dataset: Hid) -> Hid;
+    fn H5Dwrite(dataset: Hid, mem_type: Hid, mem_space: Hid, file_space: Hid, xfer: Hid, buf: *const c_void) -> Herr;
+    fn H5Dread(dataset: Hid, mem_type: Hid, mem_space: Hid, file_space: Hid, xfer: Hid, buf: *mut c_void) -> Herr;
+    fn H5Dclose(dataset: Hid) -> Herr;
+    fn H5Tcopy(dtype: Hid) -> Hid;
+    fn H5Tset_size(dtype: Hid, size: usize) -> Herr;
+    fn H5Tset_strpad(dtype: Hid, strpad: c_int) -> Herr;
+    fn H5Tclose(dtype: Hid) -> Herr;
+
+    static H5T_NATIVE_FLOAT_g: Hid;
+    static H5T_NATIVE_DOUBLE_g: Hid;
+    static H5T_NATIVE_INT64_g: Hid;
+    static H5T_C_S1_g: Hid;
+}
+
+/// libhdf5 keeps global state; one file operation at a time
+static LIBRARY: Mutex<()> = Mutex::new(());
+
+/// One dataset to write
+pub enum Data<'a> {
+    /// Row-major `[rows, cols]`
+    F32 { rows: usize, cols: usize, values: &'a [f32] },
+    F64(&'a [f64]),
+    I64(&'a [i64]),
+    I64Scalar(i64),
+    Strings(&'a [String]),
+    Str(&'a str),
+}
+
+/// An open identifier, closed on drop
+struct Handle(Hid, unsafe extern "C" fn(Hid) -> Herr);
+
+impl Handle {
+    fn new(id: Hid, close: unsafe extern "C" fn(Hid) -> Herr, what: impl FnOnce() -> String) -> Result<Self> {
+        if id < 0 {
+            bail!("HDF5 could not {}", what());
+        }
+        Ok(Self(id, close))
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe {
+            (self.1)(self.0);
+        }
+    }
+}
+
+fn check(status: Herr, what: impl FnOnce() -> String) -> Result<()> {
+    if status < 0 {
+        bail!("HDF5 could not {}", what());
+    }
+    Ok(())
+}
+
+fn c_string(text: &str) -> Result<CString> {
+    CString::new(text).with_context(|| format!("{:?} contains a NUL byte", text))
+}
+
+fn dataspace(dims: &[usize]) -> Result<Handle> {
+    let id = unsafe {
+        if dims.is_empty() {
+            H5Screate(H5S_SCALAR)
+        } else {
+            let dims: Vec<Hsize> = dims.iter().map(|&d| d as Hsize).collect();
+            H5Screate_simple(dims.len() as c_int, dims.as_ptr(), std::ptr::null())
+        }
+    };
+    Handle::new(id, H5Sclose, || format!("create a dataspace of {:?}", dims))
+}
+
+/// Fixed-length string type wide enough for every one of `strings`, and
+/// the strings null-padded to it
+fn strings(strings: &[&str]) -> Result<(Handle, Vec<u8>)> {
+    let width = strings.iter().map(|s| s.len()).max().unwrap_or(0).max(1);
+    let dtype = Handle::new(unsafe { H5Tcopy(H5T_C_S1_g) }, H5Tclose, || "copy the string type".to_string())?;
+    check(unsafe { H5Tset_size(dtype.0, width) }, || format!("size strings to {} bytes", width))?;
+    check(unsafe { H5Tset_strpad(dtype.0, H5T_STR_NULLPAD) }, || "pad strings".to_string())?;
+    let mut buffer = vec![0; width * strings.len()];
+    for (slot, s) in buffer.chunks_mut(width).zip(strings) {
+        slot[..s.len()].copy_from_slice(s.as_bytes());
+    }
+    Ok((dtype, buffer))
+}
+
+fn write_dataset(file: &Handle, name: &str, dtype: Hid, dims: &[usize], buffer: *const c_void) -> Result<()> {
+    let space = dataspace(dims)?;
+    let c_name = c_string(name)?;
+    let dataset = Handle::new(
+        unsafe { H5Dcreate2(file.0, c_name.as_ptr(), dtype, space.0, H5P_DEFAULT, H5P_DEFAULT, H5P_DEFAULT) },
+        H5Dclose,
+        || format!("create dataset {}", name),
+    )?;
+    check(unsafe { H5Dwrite(dataset.0, dtype, H5S_ALL, H5S_ALL, H5P_DEFAULT, buffer) }, || format!("write dataset {}", name))
+}
+
+/// Write `datasets` to a new file at `path`, replacing any there
+pub fn write(path: &Path, datasets: &[(&str, Data)]) -> Result<()> {
+    let _library = LIBRARY.lock().unwrap_or_else(|e| e.into_inner());
+    check(unsafe { H5open() }, || "initialize".to_string())?;
+    let c_path = c_string(&path.to_string_lossy())?;
+    let file = Handle::new(
+        unsafe { H5Fcreate(c_path.as_ptr(), H5F_ACC_TRUNC, H5P_DEFAULT, H5P_DEFAULT) },
+        H5Fclose,
+        || format!("create {:?}", path),
+    )?;
+    for (name, data) in datasets {
+        match data {
+            Data::F32 { rows, cols, values } => {
+                if values.len() != rows * cols {
+                    bail!("{} has {} values for {} x {}", name, values.len(), rows, cols);
+                }
+                write_dataset(&file, name, unsafe { H5T_NATIVE_FLOAT_g }, &[*rows, *cols], values.as_ptr().cast())?;
+            }
+            Data::F64(values) => write_dataset(&file, name, unsafe { H5T_NATIVE_DOUBLE_g }, &[values.len()], values.as_ptr().cast())?,
+            Data::I64(values) => write_dataset(&file, name, unsafe { H5T_NATIVE_INT64_g }, &[values.len()], values.as_ptr().cast())?,
+            Data::I64Scalar(value) => {
+                write_dataset(&file, name, unsafe { H5T_NATIVE_INT64_g }, &[], (value as *const i64).cast())?
+            }
+            Data::Strings(values) => {
+                let (dtype, buffer) = strings(&values.iter().map(String::as_str).collect::<Vec<_>>())?;
+                write_dataset(&file, name, dtype.0, &[values.len()], buffer.as_ptr().cast())?;
+            }
+            Data::Str(value) => {
+                let (dtype, buffer) = strings(&[value])?;
+                write_dataset(&file, name, dtype.0, &[], buffer.as_ptr().cast())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read dataset `name` of the file at `path` as `dtype`; returns its shape
+/// and values
+fn read<T: Copy + Default>(path: &Path, name: &str, dtype: impl FnOnce() -> Hid) -> Result<(Vec<usize>, Vec<T>)> {
+    let _library = LIBRARY.lock().unwrap_or_else(|e| e.into_inner());
+    check(unsafe { H5open() }, || "initialize".to_string())?;
+    let c_path = c_string(&path.to_string_lossy())?;
+    let file = Handle::new(unsafe { H5Fopen(c_path.as_ptr(), H5F_ACC_RDONLY, H5P_DEFAULT) }, H5Fclose, || format!("open {:?}", path))?;
+    let c_name = c_string(name)?;
+    let dataset = Handle::new(unsafe { H5Dopen2(file.0, c_name.as_ptr(), H5P_DEFAULT) }, H5Dclose, || {
+        format!("find {} in {:?}", name, path)
+    })?;
+    let space = Handle::new(unsafe { H5Dget_space(dataset.0) }, H5Sclose, || format!("get the shape of {}", name))?;
+    let rank = unsafe { H5Sget_simple_extent_ndims(space.0) };
+    check(rank, || format!("get the rank of {}", name))?;
+    let mut dims = vec![0 as Hsize; rank as usize];
+    check(unsafe { H5Sget_simple_extent_dims(space.0, dims.as_mut_ptr(), std::ptr::null_mut()) }, || {
+        format!("get the shape of {}", name)
+    })?;
+    let shape: Vec<usize> = dims.iter().map(|&d| d as usize).collect();
+    let mut values = vec![T::default(); shape.iter().product()];
+    check(
+        unsafe { H5Dread(dataset.0, dtype(), H5S_ALL, H5S_ALL, H5P_DEFAULT, values.as_mut_ptr().cast()) },
+        || format!("read {} from {:?}", name, path),
+    )?;
+    Ok((shape, values))
+}
+
+/// Float dataset `name`, converted to float32 by the library
+pub fn read_f32(path: &Path, name: &str) -> Result<(Vec<usize>, Vec<f32>)> {
+    read(path, name, || unsafe { H5T_NATIVE_FLOAT_g })
+}
+
+/// Float dataset `name`, converted to float64 by the library
+pub fn read_f64(path: &Path, name: &str) -> Result<(Vec<usize>, Vec<f64>)> {
+    read(path, name, || unsafe { H5T_NATIVE_DOUBLE_g })
+}
+
+/// Samples and timestamps of a trial file
+#[derive(Debug)]
+pub struct Hdf5Trial {
+    /// `samples[i][channel]`, nanovolts
+    pub samples: Vec<Vec<f32>>,
+    pub timestamps: Vec<f64>,
+}
+
+impl Hdf5Trial {
+    pub fn read(path: &Path) -> Result<Self> {
+        let (shape, values) = read_f32(path, "X")?;
+        let [rows, cols] = shape[..] else {
+            bail!("X in {:?} has shape {:?}, expected [samples, channels]", path, shape);
+        };
+        if cols == 0 {
+            bail!("X in {:?} has {} samples of no channels", path, rows);
+        }
+        let (_, timestamps) = read_f64(path, "timestamps").with_context(|| format!("Failed to read {:?}", path))?;
+        if timestamps.len() != rows {
+            bail!("{:?} has {} timestamps for {} samples", path, timestamps.len(), rows);
+        }
+        Ok(Self {
+            samples: values.chunks_exact(cols).map(<[f32]>::to_vec).collect(),
+            timestamps,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("openbci_hdf5_{}_{}.h5", std::process::id(), name))
+    }
+
+    #[test]
+    fn round_trips_a_trial() {
+        let path = temp_path("trial");
+        let values = [1.0f32, -2.0, 3.5, 4.25, -5.0, 6.0];
+        let timestamps = [10.0, 10.004, 10.008];
+        let channels = vec!["EEG1".to_string(), "EEG2".to_string()];
+        write(
+            &path,
+            &[
+                ("X", Data::F32 { rows: 3, cols: 2, values: &values }),
+                ("y", Data::I64Scalar(1)),
+                ("meta", Data::Str("{}")),
+                ("timestamps", Data::F64(&timestamps)),
+                ("channels", Data::Strings(&channels)),
+            ],
+        )
+        .unwrap();
+
+        let trial = Hdf5Trial::read(&path).unwrap();
+        assert_eq!(trial.samples, [[1.0, -2.0], [3.5, 4.25], [-5.0, 6.0]]);
+        assert_eq!(trial.timestamps, timestamps);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_trials_without_channels_or_timestamps() {
+        let path = temp_path("empty");
+        write(&path, &[("X", Data::F32 { rows: 2, cols: 0, values: &[] }), ("timestamps", Data::F64(&[0.0, 1.0]))]).unwrap();
+        assert!(Hdf5Trial::read(&path).unwrap_err().to_string().contains("no channels"));
+
+        write(&path, &[("X", Data::F32 { rows: 2, cols: 1, values: &[0.0, 1.0] }), ("timestamps", Data::F64(&[0.0]))]).unwrap();
+        assert!(Hdf5Trial::read(&path).unwrap_err().to_string().contains("1 timestamps for 2 samples"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+//! Key presses during recording, for `--keys`.
+//!
+//! The terminal is switched to raw mode for the trial and every key becomes
+//! a marker stamped with the host time it was read at. Hotkeys let the
+//! experimenter annotate the trial as it runs: by default `a` marks an
+//! artifact (`flag:artifact`), `b` a bad trial (`flag:bad_trial`, which
+//! fails QC) and space anything else worth finding later (`flag:marker`).
+//! Other keys become `key:<name>`: letters and digits as themselves, and
+//! `space`, `enter`, `tab`, `backspace`, `escape` and the arrow keys by
+//! name. Ctrl-C still interrupts the collector, after the terminal is
+//! restored.
+
+use crate::events::{FLAG, KEY};
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use openbci_wifi_client::MarkerSender;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// How often the reader checks whether the trial is over, in milliseconds
+const POLL_MS: i32 = 100;
+
+/// Key that annotates the trial, parsed from `key=label`
+#[derive(Debug, Clone)]
+pub struct Hotkey {
+    /// Key name as in `key:<name>` markers, e.g. `a` or `space`
+    pub key: String,
+    pub label: String,
+}
+
+impl FromStr for Hotkey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, label) = s
+            .split_once('=')
+            .with_context(|| format!("Hotkey '{}' must look like key=label", s))?;
+        let (key, label) = (key.trim(), label.trim());
+        if key.is_empty() || label.is_empty() {
+            bail!("Hotkey '{}' needs both a key and a label", s);
+        }
+        // Labels end up in CSV marker cells and TSV rows
+        if label.contains(['|', ':', '\t', '\n', '\r']) {
+            bail!("Hotkey label '{}' may not contain '|', ':' or line breaks", label);
+        }
+        Ok(Self {
+            key: key.to_string(),
+            label: label.to_string(),
+        })
+    }
+}
+
+/// `a` artifact, `b` bad trial, space a generic marker
+pub fn default_hotkeys() -> Vec<Hotkey> {
+    [("a", "artifact"), ("b", "bad_trial"), ("space", "marker")]
+        .into_iter()
+        .map(|(key, label)| Hotkey {
+            key: key.to_string(),
+            label: label.to_string(),
+        })
+        .collect()
+}
+
+/// Records key presses as markers from its own thread
+pub struct KeyRecorder {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl KeyRecorder {
+    /// Start reading keys; later `hotkeys` override earlier ones for the
+    /// same key. `None` if stdin is not a terminal.
+    pub fn start(markers: MarkerSender, hotkeys: &[Hotkey]) -> Option<Self> {
+        let terminal = match raw::RawTerminal::enable() {
+            Ok(Some(terminal)) => terminal,
+            Ok(None) => {
+                warn!("stdin is not a terminal, key presses are not recorded");
+                return None;
+            }
+            Err(e) => {
+                warn!("Failed to read keys from the terminal: {}", e);
+                return None;
+            }
+        };
+        let hotkeys: HashMap<String, String> = hotkeys
+            .iter()
+            .map(|h| (h.key.clone(), format!("{}{}", FLAG, h.label)))
+            .collect();
+        let mut help: Vec<String> = hotkeys.iter().map(|(key, label)| format!("{} = {}", key, label)).collect();
+        help.sort();
+        info!("Recording key presses as markers, hotkeys: {}", help.join(", "));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            std::thread::Builder::new()
+                .name("keys".to_string())
+                .spawn(move || read_keys(terminal, &markers, &hotkeys, &stop))
+                .expect("failed to spawn the key thread")
+        };
+        Some(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Stop reading and restore the terminal
+    pub fn finish(mut self) {
+        self.stop_thread();
+    }
+
+    fn stop_thread(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("Key thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for KeyRecorder {
+    fn drop(&mut self) {
+        self.stop_thread();
+    }
+}
+
+fn read_keys(terminal: raw::RawTerminal, markers: &MarkerSender, hotkeys: &HashMap<String, String>, stop: &AtomicBool) {
+    let mut buf = [0u8; 32];
+    while !stop.load(Ordering::SeqCst) {
+        let read = match terminal.read(&mut buf, POLL_MS) {
+            Ok(read) => read,
+            Err(e) => {
+                warn!("Stopped reading keys: {}", e);
+                return;
+            }
+        };
+        for key in key_names(&buf[..read]) {
+            if key == INTERRUPT {
+                drop(terminal);
+                raw::interrupt();
+                return;
+            }
+            let label = hotkeys.get(&key).cloned().unwrap_or_else(|| format!("{}{}", KEY, key));
+            markers.insert_marker(label);
+        }
+    }
+}
+
+/// Ctrl-C, which raw mode delivers as a byte instead of a signal
+const INTERRUPT: &str = "ctrl-c";
+
+/// Names of the keys in one read from the terminal
+fn key_names(bytes: &[u8]) -> Vec<String> {
+    let mut keys = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let (name, len) = match bytes[i..] {
+            [0x1b, b'[', b'A', ..] => ("up".to_string(), 3),
+            [0x1b, b'[', b'B', ..] => ("down".to_string(), 3),
+            [0x1b, b'[', b'C', ..] => ("right".to_string(), 3),
+            [0x1b, b'[', b'D', ..] => ("left".to_string(), 3),
+            [0x1b, ..] => ("escape".to_string(), 1),
+            [0x03, ..] => (INTERRUPT.to_string(), 1),
+            [b' ', ..] => ("space".to_string(), 1),
+            [b'\r' | b'\n', ..] => ("enter".to_string(), 1),
+            [b'\t', ..] => ("tab".to_string(), 1),
+            [0x7f | 0x08, ..] => ("backspace".to_string(), 1),
+            // `|` separates markers of one sample in the CSV
+            [b'|', ..] => ("bar".to_string(), 1),
+            [b, ..] if b.is_ascii_graphic() => ((b as char).to_string(), 1),
+            [b, ..] if (1..=26).contains(&b) => (format!("ctrl-{}", (b + b'a' - 1) as char), 1),
+            [b, ..] if b < 0x80 => (format!("0x{:02x}", b), 1),
+            // The rest of the read as UTF-8, one key per character
+            _ => {
+                keys.extend(String::from_utf8_lossy(&bytes[i..]).chars().map(String::from));
+                break;
+            }
+        };
+        keys.push(name);
+        i += len;
+    }
+    keys
+}
+
+#[cfg(unix)]
+mod raw {
+    use std::io;
+
+    /// stdin in non-canonical mode without echo or signal keys; the
+    /// previous settings come back on drop
+    pub struct RawTerminal {
+        saved: libc::termios,
+    }
+
+    impl RawTerminal {
+        pub fn enable() -> io::Result<Option<Self>> {
+            // SAFETY: isatty, tcgetattr and tcsetattr only read and write
+            // the termios struct we pass for file descriptor 0
+            unsafe {
+                if libc::isatty(libc::STDIN_FILENO) == 0 {
+                    return Ok(None);
+                }
+                let mut saved: libc::termios = std::mem::zeroed();
+                if libc::tcgetattr(libc::STDIN_FILENO, &mut saved) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let mut raw = saved;
+                raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+                raw.c_cc[libc::VMIN] = 1;
+                raw.c_cc[libc::VTIME] = 0;
+                if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(Some(Self { saved }))
+            }
+        }
+
+        /// Bytes typed within `timeout_ms`, 0 if none
+        pub fn read(&self, buf: &mut [u8], timeout_ms: i32) -> io::Result<usize> {
+            let mut fds = libc::pollfd {
+                fd: libc::STDIN_FILENO,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: one valid pollfd, and read writes at most buf.len()
+            // bytes into buf
+            unsafe {
+                match libc::poll(&mut fds, 1, timeout_ms) {
+                    0 => return Ok(0),
+                    n if n < 0 => {
+                        let e = io::Error::last_os_error();
+                        return if e.kind() == io::ErrorKind::Interrupted { Ok(0) } else { Err(e) };
+                    }
+                    _ => {}
+                }
+                match libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) {
+                    0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stdin closed")),
+                    n if n < 0 => Err(io::Error::last_os_error()),
+                    n => Ok(n as usize),
+                }
+            }
+        }
+    }
+
+    impl Drop for RawTerminal {
+        fn drop(&mut self) {
+            // SAFETY: restores the settings read in enable()
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved);
+            }
+        }
+    }
+
+    /// Deliver the Ctrl-C that raw mode swallowed
+    pub fn interrupt() {
+        // SAFETY: signals our own process
+        unsafe {
+            libc::raise(libc::SIGINT);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod raw {
+    use std::io;
+
+    pub struct RawTerminal;
+
+    impl RawTerminal {
+        pub fn enable() -> io::Result<Option<Self>> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "not supported on this platform"))
+        }
+
+        pub fn read(&self, _buf: &mut [u8], _timeout_ms: i32) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    pub fn interrupt() {}
+}
@@ -0,0 +1,195 @@
+//! Where the time of the closed loop goes.
+//!
+//! How usable a BCI feels depends on the delay from intent to command, so
+//! `openbci_online_bci` times every stage on the way: acquisition (a
+//! sample's timestamp to its arrival; only board-stamped streams show the
+//! transport, host-stamped ones are stamped on arrival), filtering and
+//! windowing of every sample, then for every window the wait for a busy
+//! model, inference and output (decision, JSON line and OSC message), and
+//! the total from the window's last sample to its command. A replayed
+//! trial's cues are timed to the first command of the trial's class. A
+//! [`LatencyProfiler`] collects the times and its [`LatencyReport`] has
+//! their percentiles, which `openbci benchmark` uses for inference alone.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt::{self, Write};
+use std::time::{Duration, Instant};
+
+/// A stage of the closed loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Sample timestamp to arrival, per sample
+    Acquisition,
+    /// Band-pass, notch and common average reference, per sample
+    Filtering,
+    /// Gap repair and the window copy, per sample
+    Windowing,
+    /// A finished window waiting for the model
+    Queue,
+    Inference,
+    /// Decision, JSON line and OSC message
+    Output,
+    /// The window's last sample to its command
+    Total,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 7] = [
+        Self::Acquisition,
+        Self::Filtering,
+        Self::Windowing,
+        Self::Queue,
+        Self::Inference,
+        Self::Output,
+        Self::Total,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Acquisition => "acquisition",
+            Self::Filtering => "filtering",
+            Self::Windowing => "windowing",
+            Self::Queue => "queue",
+            Self::Inference => "inference",
+            Self::Output => "output",
+            Self::Total => "total",
+        }
+    }
+
+    /// Timed once per sample rather than once per window
+    pub fn per_sample(self) -> bool {
+        matches!(self, Self::Acquisition | Self::Filtering | Self::Windowing)
+    }
+}
+
+/// Distribution of times, in milliseconds
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Latency {
+    pub count: usize,
+    pub mean: f64,
+    pub median: f64,
+    pub p90: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Latency {
+    /// Percentiles of `times`, nearest rank; all zero when empty
+    pub fn of(mut times: Vec<f64>) -> Self {
+        if times.is_empty() {
+            return Self::default();
+        }
+        times.sort_by(f64::total_cmp);
+        let at = |q: f64| times[((times.len() - 1) as f64 * q).round() as usize];
+        Self {
+            count: times.len(),
+            mean: times.iter().sum::<f64>() / times.len() as f64,
+            median: at(0.5),
+            p90: at(0.9),
+            p95: at(0.95),
+            p99: at(0.99),
+            max: times[times.len() - 1],
+        }
+    }
+}
+
+/// Times of one stage
+#[derive(Debug, Clone, Serialize)]
+pub struct StageLatency {
+    pub stage: Stage,
+    pub per_sample: bool,
+    #[serde(flatten)]
+    pub latency_ms: Latency,
+}
+
+/// Percentiles of every stage after a session
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyReport {
+    pub stages: Vec<StageLatency>,
+    /// A cue's arrival to the first command of its class
+    pub cue_to_command_ms: Option<Latency>,
+    /// Cues no command of their class followed
+    pub missed_cues: usize,
+}
+
+impl fmt::Display for LatencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut text = String::new();
+        let _ = writeln!(
+            text,
+            "{:<19}  {:>7}  {:>8}  {:>8}  {:>8}  {:>8}  {:>8}  {:>8}",
+            "Latency (ms)", "Count", "Mean", "p50", "p90", "p95", "p99", "Max"
+        );
+        let mut row = |name: String, latency: &Latency| {
+            let _ = writeln!(
+                text,
+                "{:<19}  {:>7}  {:>8.3}  {:>8.3}  {:>8.3}  {:>8.3}  {:>8.3}  {:>8.3}",
+                name, latency.count, latency.mean, latency.median, latency.p90, latency.p95, latency.p99, latency.max
+            );
+        };
+        for stage in &self.stages {
+            row(format!("{}{}", stage.stage.name(), if stage.stage.per_sample() { " /sample" } else { "" }), &stage.latency_ms);
+        }
+        if let Some(cues) = &self.cue_to_command_ms {
+            row("cue to command".to_string(), cues);
+        }
+        if self.missed_cues > 0 {
+            let _ = writeln!(text, "{} cues were not followed by a command of their class", self.missed_cues);
+        }
+        f.write_str(text.trim_end())
+    }
+}
+
+/// Collects stage times and cue-to-command delays through a session
+#[derive(Debug, Default)]
+pub struct LatencyProfiler {
+    times: [Vec<f64>; Stage::ALL.len()],
+    cues: Vec<f64>,
+    /// Arrival of cues still waiting for their command, oldest first
+    waiting: VecDeque<Instant>,
+}
+
+impl LatencyProfiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, stage: Stage, elapsed: Duration) {
+        self.record_ms(stage, elapsed.as_secs_f64() * 1000.0);
+    }
+
+    pub fn record_ms(&mut self, stage: Stage, ms: f64) {
+        self.times[stage as usize].push(ms);
+    }
+
+    /// A cue arrived; the next [`command`](Self::command) answers it
+    pub fn cue(&mut self, arrived: Instant) {
+        self.waiting.push_back(arrived);
+    }
+
+    /// A command of the cued class went out at `sent`, answering every
+    /// waiting cue
+    pub fn command(&mut self, sent: Instant) {
+        for arrived in self.waiting.drain(..) {
+            self.cues.push(sent.saturating_duration_since(arrived).as_secs_f64() * 1000.0);
+        }
+    }
+
+    pub fn report(&self) -> LatencyReport {
+        LatencyReport {
+            stages: Stage::ALL
+                .iter()
+                .map(|&stage| StageLatency {
+                    stage,
+                    per_sample: stage.per_sample(),
+                    latency_ms: Latency::of(self.times[stage as usize].clone()),
+                })
+                .collect(),
+            cue_to_command_ms: (!self.cues.is_empty()).then(|| Latency::of(self.cues.clone())),
+            missed_cues: self.waiting.len(),
+        }
+    }
+}
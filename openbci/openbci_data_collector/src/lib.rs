@@ -0,0 +1,80 @@
+//! Library side of the OpenBCI motor imagery data collector.
+
+pub mod adapt;
+pub mod asr;
+pub mod augment;
+pub mod auth;
+pub mod bandpower;
+pub mod bdf;
+pub mod benchmark;
+pub mod bids;
+pub mod board;
+pub mod brainvision;
+pub mod calibration;
+pub mod classifier;
+pub mod collector;
+pub mod checksum;
+pub mod compress;
+pub mod compute;
+pub mod config;
+pub mod connectivity;
+pub mod container;
+pub mod convert;
+pub mod cue;
+pub mod dataset;
+pub mod decision;
+pub mod detect;
+pub mod disk;
+pub mod environment;
+pub mod eog;
+pub mod epoch;
+pub mod erd;
+pub mod events;
+pub mod filter;
+pub mod gaps;
+pub mod metadata;
+pub mod montage;
+pub mod multiboard;
+pub mod normalize;
+pub mod npz;
+pub mod osc;
+pub mod paradigm;
+#[cfg(feature = "parquet")]
+pub mod parquet_sink;
+pub mod qc;
+pub mod rate;
+pub mod features;
+pub mod gui_bridge;
+#[cfg(feature = "hdf5")]
+pub mod hdf5;
+pub mod keys;
+pub mod latency;
+pub mod live;
+pub mod loader;
+#[cfg(feature = "lsl")]
+pub mod lsl;
+pub mod privacy;
+pub mod platform;
+pub mod preflight;
+pub mod progress;
+pub mod record;
+pub mod recording;
+pub mod replay;
+pub mod report;
+pub mod riemann;
+pub mod scope;
+pub mod session;
+pub mod shutdown;
+pub mod signal_check;
+pub mod simulate;
+pub mod sink;
+pub mod soak;
+pub mod source;
+pub mod ssvep;
+pub mod timesync;
+pub mod websocket;
+pub mod window;
+pub mod wizard;
+pub mod writer;
+#[cfg(feature = "zmq")]
+pub mod zmq_pub;
@@ -0,0 +1,92 @@
+//! JSON messages of the live stream.
+//!
+//! The WebSocket server and the ZeroMQ publisher send the same messages,
+//! tagged by `type`, so a dashboard or analysis script can switch between
+//! them without changes.
+
+use crate::bandpower::ChannelPower;
+use serde::Serialize;
+
+/// `samples` messages per second
+const MESSAGES_PER_SECOND: u32 = 25;
+/// Samples are in nanovolts, messages carry microvolts
+const NV_PER_UV: f32 = 1000.0;
+
+/// Everything the live outputs send, as `{"type": ..., ...}`
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum LiveMessage<'a> {
+    Trial {
+        subject: &'a str,
+        session: &'a str,
+        trial: u32,
+        class: &'a str,
+        channels: &'a [String],
+        sample_rate: u32,
+    },
+    /// `data[sample][channel]` in microvolts
+    Samples {
+        first_id: u64,
+        timestamps: &'a [f64],
+        data: &'a [Vec<f32>],
+    },
+    Marker {
+        label: &'a str,
+        timestamp: f64,
+        sample_id: u64,
+    },
+    /// Powers in µV²/Hz, changes in % against fixation
+    BandPower {
+        timestamp: f64,
+        channels: &'a [ChannelPower],
+    },
+    TrialEnd {
+        samples: u64,
+        markers: u64,
+    },
+}
+
+/// Samples queued for the next `samples` message
+pub(crate) struct SampleBatch {
+    size: usize,
+    first_id: u64,
+    timestamps: Vec<f64>,
+    data: Vec<Vec<f32>>,
+}
+
+impl SampleBatch {
+    pub(crate) fn new(sample_rate: u32) -> Self {
+        let size = (sample_rate / MESSAGES_PER_SECOND).max(1) as usize;
+        Self {
+            size,
+            first_id: 0,
+            timestamps: Vec::with_capacity(size),
+            data: Vec::with_capacity(size),
+        }
+    }
+
+    /// Queue one sample (nanovolts, as recorded); returns whether the
+    /// batch is full
+    pub(crate) fn push(&mut self, sample_id: u64, timestamp: f64, channels: &[f32]) -> bool {
+        if self.data.is_empty() {
+            self.first_id = sample_id;
+        }
+        self.timestamps.push(timestamp);
+        self.data.push(channels.iter().map(|v| v / NV_PER_UV).collect());
+        self.data.len() >= self.size
+    }
+
+    /// The queued samples as a message, `None` if there are none
+    pub(crate) fn message(&self) -> Option<LiveMessage<'_>> {
+        (!self.data.is_empty()).then(|| LiveMessage::Samples {
+            first_id: self.first_id,
+            timestamps: &self.timestamps,
+            data: &self.data,
+        })
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.timestamps.clear();
+        self.data.clear();
+    }
+}
@@ -0,0 +1,254 @@
+//! The windows of a built dataset, for training and benchmarking.
+//!
+//! [`DatasetArgs`] picks the trials of a dataset manifest and the online
+//! filters they get. [`Trials::load`] reads them from the recording tree
+//! (their cues are not in the consolidated file) and filters them as the
+//! live stream would be, with the classes in class ID order so outputs
+//! match the dataset's labels; [`Trials::windows`] cuts one window per cue
+//! of some of them into raw [`Windows`], and [`Trials::split`] assigns them
+//! to training, validation and test as [`SplitArgs`] say. An
+//! [`eeg_dsp::BatchLoader`] deals
+//! those out as shuffled mini-batches, augmented and standardized on the
+//! fly. `openbci_train` and `openbci benchmark` both load through here, so
+//! networks are scored on windows cut exactly like the ones they learned
+//! from.
+
+use crate::calibration;
+use crate::dataset::{self, ManifestEntry};
+use crate::filter::{OnlineFilter, Passband};
+use crate::recording::Recording;
+use anyhow::{bail, Result};
+use clap::Args;
+use eeg_dsp::Windows;
+use log::warn;
+use ndarray::{Array3, Axis};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Which trials of a built dataset, filtered how
+#[derive(Args, Debug, Clone)]
+pub struct DatasetArgs {
+    /// Manifest CSV of a built dataset
+    #[arg(long, default_value = "motor_imagery_dataset_manifest.csv")]
+    pub manifest: PathBuf,
+
+    /// Root of the recorded dataset the manifest's sources are under
+    #[arg(short, long, default_value = "motor_imagery_data")]
+    pub data_dir: PathBuf,
+
+    /// Only trials of these classes (all if omitted)
+    #[arg(long, value_delimiter = ',')]
+    pub classes: Vec<String>,
+
+    /// Band-pass applied to the trials as online, low-high in Hz
+    #[arg(long)]
+    pub bandpass: Option<Passband>,
+
+    /// Notch applied to the trials as online, in Hz
+    #[arg(long)]
+    pub notch: Option<f64>,
+
+    /// Common average reference over the trials' channels, as online
+    #[arg(long)]
+    pub car: bool,
+}
+
+/// How trials are split into training, validation and test
+#[derive(Args, Debug, Clone)]
+pub struct SplitArgs {
+    /// Split file of `dataset split` assigning trials to train, val and
+    /// test (a stratified --val-fraction of the trials if omitted)
+    #[arg(long)]
+    pub split_file: Option<PathBuf>,
+
+    /// Column of --split-file to use, e.g. fold_S01 for a
+    /// leave-one-subject-out fold
+    #[arg(long, default_value = "split")]
+    pub fold: String,
+
+    /// Share of each class's trials held out for validation without
+    /// --split-file
+    #[arg(long, default_value = "0.2")]
+    pub val_fraction: f64,
+}
+
+/// Indices of the trials in each part of a split
+#[derive(Debug, Clone, Default)]
+pub struct Split {
+    pub train: Vec<usize>,
+    pub val: Vec<usize>,
+    pub test: Vec<usize>,
+}
+
+/// The loaded and filtered trials of a manifest
+#[derive(Debug)]
+pub struct Trials {
+    /// Class labels in class ID order; a window's label indexes these
+    pub classes: Vec<String>,
+    /// Metadata file of every trial, relative to the data directory
+    pub sources: Vec<String>,
+    pub recordings: Vec<Recording>,
+}
+
+impl Trials {
+    /// Load the trials of `args`, leaving out those that cannot be read
+    pub fn load(args: &DatasetArgs) -> Result<Self> {
+        let manifest: Vec<ManifestEntry> = dataset::load_manifest(&args.manifest)?
+            .into_iter()
+            .filter(|e| args.classes.is_empty() || args.classes.contains(&e.class_label))
+            .collect();
+        let mut ids: BTreeMap<u8, String> = BTreeMap::new();
+        for entry in &manifest {
+            ids.entry(entry.class_id).or_insert_with(|| entry.class_label.clone());
+        }
+        let loaded: Vec<(String, Recording)> = manifest
+            .par_iter()
+            .map(|entry| {
+                let rec = load_trial(&args.data_dir.join(&entry.source), args.bandpass, args.notch, args.car)?;
+                Ok(rec.map(|rec| (entry.source.clone(), rec)))
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+        if loaded.is_empty() {
+            bail!("No trials of {:?} could be loaded from {:?}", args.manifest, args.data_dir);
+        }
+        let (sources, recordings) = loaded.into_iter().unzip();
+        Ok(Self { classes: ids.into_values().collect(), sources, recordings })
+    }
+
+    pub fn len(&self) -> usize {
+        self.recordings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.recordings.is_empty()
+    }
+
+    /// Rate of the first trial, which the others must share to be windowed
+    pub fn sample_rate(&self) -> f64 {
+        self.recordings.first().map_or(0.0, |rec| rec.metadata.sample_rate as f64)
+    }
+
+    /// Channel labels of the first trial
+    pub fn channel_names(&self) -> Vec<String> {
+        self.recordings.first().map(Recording::labels).unwrap_or_default()
+    }
+
+    /// Class index of trial `i`
+    pub fn label(&self, i: usize) -> usize {
+        let class = &self.recordings[i].metadata.class_label;
+        self.classes.iter().position(|c| c == class).expect("classes come from the manifest")
+    }
+
+    /// Assign every trial to a part of `args`; the random validation share
+    /// is drawn from `seed`, so the same seed gives the same split
+    pub fn split(&self, args: &SplitArgs, seed: u64) -> Result<Split> {
+        if !(0.0..1.0).contains(&args.val_fraction) {
+            bail!("--val-fraction must be in [0, 1), got {}", args.val_fraction);
+        }
+        let mut split = Split::default();
+        if let Some(split_file) = &args.split_file {
+            let mut parts: BTreeMap<String, &str> = BTreeMap::new();
+            for part in ["train", "val", "test"] {
+                for source in dataset::split_sources(split_file, &args.fold, part)? {
+                    parts.insert(source, part);
+                }
+            }
+            let mut missing = 0;
+            for (i, source) in self.sources.iter().enumerate() {
+                match parts.get(source) {
+                    Some(&"train") => split.train.push(i),
+                    Some(&"val") => split.val.push(i),
+                    Some(_) => split.test.push(i),
+                    None => missing += 1,
+                }
+            }
+            if missing > 0 {
+                warn!("{} trials are not in {:?} and are left out", missing, split_file);
+            }
+            return Ok(split);
+        }
+        // Stratified by class: the first share of each shuffled class validates
+        let mut by_class: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for (i, rec) in self.recordings.iter().enumerate() {
+            by_class.entry(rec.metadata.class_label.as_str()).or_default().push(i);
+        }
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut val = vec![false; self.len()];
+        for members in by_class.values_mut() {
+            members.shuffle(&mut rng);
+            let held = (members.len() as f64 * args.val_fraction).round() as usize;
+            for &i in &members[..held] {
+                val[i] = true;
+            }
+        }
+        for (i, val) in val.into_iter().enumerate() {
+            match val {
+                true => split.val.push(i),
+                false => split.train.push(i),
+            }
+        }
+        Ok(split)
+    }
+
+    /// Raw `window`-sample windows `start` seconds after every cue of the
+    /// trials `indices`, of the channels `select` picks from a trial's
+    /// labels
+    pub fn windows(
+        &self,
+        indices: &[usize],
+        start: f64,
+        window: usize,
+        select: impl Fn(&[String]) -> Result<Vec<usize>>,
+    ) -> Result<Windows> {
+        let mut cut = Vec::new();
+        let mut labels = Vec::new();
+        let mut channels = 0;
+        for &i in indices {
+            let rec = &self.recordings[i];
+            if rec.metadata.sample_rate as f64 != self.sample_rate() {
+                bail!("{} is at {} Hz, the first trial at {} Hz", self.sources[i], rec.metadata.sample_rate, self.sample_rate());
+            }
+            let picked = select(&rec.labels())?;
+            channels = picked.len();
+            for window in calibration::cue_windows(rec, start, window, &picked) {
+                cut.push(window);
+                labels.push(self.label(i));
+            }
+        }
+        let mut array = Array3::zeros((cut.len(), channels, window));
+        for (mut slot, window) in array.axis_iter_mut(Axis(0)).zip(&cut) {
+            slot.assign(window);
+        }
+        Ok(Windows::new(array, labels)?)
+    }
+}
+
+/// Load one trial and filter it as the live stream would be; `None` when
+/// it cannot be used
+pub fn load_trial(path: &Path, bandpass: Option<Passband>, notch: Option<f64>, car: bool) -> Result<Option<Recording>> {
+    let mut rec = match Recording::load(path) {
+        Ok(rec) if rec.samples.is_empty() => {
+            warn!("Skipping empty trial {:?}", path);
+            return Ok(None);
+        }
+        Ok(rec) => rec,
+        Err(e) => {
+            warn!("Skipping {:?}: {}", path, e);
+            return Ok(None);
+        }
+    };
+    let count = rec.num_channels();
+    if let Some(mut filter) = OnlineFilter::new(bandpass, notch, car.then_some(count), rec.metadata.sample_rate, count)? {
+        for row in &mut rec.samples {
+            filter.process(row);
+        }
+    }
+    Ok(Some(rec))
+}
@@ -0,0 +1,516 @@
+//! Lab Streaming Layer outlets for `--lsl` and inlets for `--transport lsl`.
+//!
+//! Outlets publish the recorded stream as an LSL `EEG` stream (float32,
+//! microvolts, channel labels in the stream description) and the trial's
+//! markers as a string `Markers` stream, so LabRecorder, NeuroPype,
+//! OpenViBE and other LSL tools can record or process the session next to
+//! the collector. Samples are stamped with the LSL clock when they are
+//! pushed; markers keep their host time, moved onto the LSL clock. Both
+//! outlets are recreated for every trial with the same source ids, so
+//! inlets reconnect on their own between trials.
+//!
+//! [`LslSource`] goes the other way: it records any amplifier that
+//! publishes an LSL stream, converting its unit to nanovolts and its
+//! timestamps to host time like every other source.
+//!
+//! Links against liblsl, hence the `lsl` feature.
+
+use crate::sink::EEGSample;
+use crate::source::DataSource;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use log::{info, warn};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_ulong, c_void};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// `lsl_channel_format_t` values
+const CFT_FLOAT32: c_int = 1;
+const CFT_STRING: c_int = 3;
+/// Nominal rate of streams without a fixed rate
+const IRREGULAR_RATE: f64 = 0.0;
+/// Seconds of data an outlet buffers for slow inlets
+const MAX_BUFFERED: i32 = 360;
+/// Samples are in nanovolts, LSL EEG streams are in microvolts
+const NV_PER_UV: f32 = 1000.0;
+/// How long to look for a stream on the network
+const RESOLVE_TIMEOUT: f64 = 5.0;
+/// How long opening an inlet and its first clock offset may take
+const OPEN_TIMEOUT: f64 = 5.0;
+/// Pause when an inlet has no sample ready
+const POLL: Duration = Duration::from_millis(2);
+/// How often the inlet's clock offset is refreshed
+const CLOCK_REFRESH: Duration = Duration::from_secs(5);
+/// `lsl_error_code_t` of an inlet whose source is gone for good
+const LSL_LOST_ERROR: i32 = -2;
+
+#[link(name = "lsl")]
+extern "C" {
+    fn lsl_create_streaminfo(
+        name: *const c_char,
+        kind: *const c_char,
+        channel_count: i32,
+        nominal_srate: f64,
+        channel_format: c_int,
+        source_id: *const c_char,
+    ) -> *mut c_void;
+    fn lsl_destroy_streaminfo(info: *mut c_void);
+    fn lsl_get_desc(info: *mut c_void) -> *mut c_void;
+    fn lsl_append_child(element: *mut c_void, name: *const c_char) -> *mut c_void;
+    fn lsl_append_child_value(element: *mut c_void, name: *const c_char, value: *const c_char) -> *mut c_void;
+    fn lsl_create_outlet(info: *mut c_void, chunk_size: i32, max_buffered: i32) -> *mut c_void;
+    fn lsl_destroy_outlet(outlet: *mut c_void);
+    fn lsl_push_chunk_ftp(outlet: *mut c_void, data: *const f32, data_elements: c_ulong, timestamp: f64, pushthrough: i32) -> i32;
+    fn lsl_push_sample_strtp(outlet: *mut c_void, data: *const *const c_char, timestamp: f64, pushthrough: i32) -> i32;
+    fn lsl_local_clock() -> f64;
+
+    fn lsl_resolve_byprop(
+        buffer: *mut *mut c_void,
+        buffer_elements: u32,
+        prop: *const c_char,
+        value: *const c_char,
+        minimum: i32,
+        timeout: f64,
+    ) -> i32;
+    fn lsl_get_channel_count(info: *mut c_void) -> i32;
+    fn lsl_get_nominal_srate(info: *mut c_void) -> f64;
+    fn lsl_get_type(info: *mut c_void) -> *const c_char;
+    fn lsl_get_hostname(info: *mut c_void) -> *const c_char;
+    fn lsl_child(element: *mut c_void, name: *const c_char) -> *mut c_void;
+    fn lsl_next_sibling(element: *mut c_void) -> *mut c_void;
+    fn lsl_child_value_n(element: *mut c_void, name: *const c_char) -> *const c_char;
+    fn lsl_empty(element: *mut c_void) -> i32;
+    fn lsl_create_inlet(info: *mut c_void, max_buflen: i32, max_chunklen: i32, recover: i32) -> *mut c_void;
+    fn lsl_destroy_inlet(inlet: *mut c_void);
+    fn lsl_get_fullinfo(inlet: *mut c_void, timeout: f64, ec: *mut i32) -> *mut c_void;
+    fn lsl_open_stream(inlet: *mut c_void, timeout: f64, ec: *mut i32);
+    fn lsl_time_correction(inlet: *mut c_void, timeout: f64, ec: *mut i32) -> f64;
+    fn lsl_pull_sample_f(inlet: *mut c_void, buffer: *mut f32, buffer_elements: i32, timeout: f64, ec: *mut i32) -> f64;
+}
+
+/// Seconds since the Unix epoch
+fn unix_now() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64())
+}
+
+/// LSL clock minus Unix time
+fn clock_offset() -> f64 {
+    // SAFETY: no arguments, reads the LSL clock
+    let now = unsafe { lsl_local_clock() };
+    now - unix_now()
+}
+
+/// Owned copy of a string liblsl returns, empty for null
+///
+/// # Safety
+/// `s` must be null or a NUL-terminated string valid for the call.
+unsafe fn owned(s: *const c_char) -> String {
+    if s.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(s).to_string_lossy().into_owned()
+    }
+}
+
+fn c_string(s: &str) -> CString {
+    // Labels and names never contain NUL; drop any rather than fail
+    CString::new(s.replace('\0', "")).unwrap_or_default()
+}
+
+/// One liblsl outlet, destroyed on drop
+struct Outlet(*mut c_void);
+
+// liblsl outlets may be pushed to from any thread
+unsafe impl Send for Outlet {}
+
+impl Outlet {
+    /// Create the outlet from a stream info that `describe` fills in
+    fn create(
+        name: &str,
+        kind: &str,
+        channel_count: usize,
+        rate: f64,
+        format: c_int,
+        source_id: &str,
+        describe: impl FnOnce(*mut c_void),
+    ) -> Result<Self> {
+        let (name, kind, source_id) = (c_string(name), c_string(kind), c_string(source_id));
+        // SAFETY: every pointer is a valid NUL-terminated string for the
+        // duration of the call; the outlet copies the stream info, which
+        // is destroyed right after
+        unsafe {
+            let info = lsl_create_streaminfo(
+                name.as_ptr(),
+                kind.as_ptr(),
+                channel_count as i32,
+                rate,
+                format,
+                source_id.as_ptr(),
+            );
+            if info.is_null() {
+                bail!("liblsl could not describe stream '{}'", name.to_string_lossy());
+            }
+            describe(lsl_get_desc(info));
+            let outlet = lsl_create_outlet(info, 0, MAX_BUFFERED);
+            lsl_destroy_streaminfo(info);
+            if outlet.is_null() {
+                bail!("liblsl could not open an outlet for stream '{}'", name.to_string_lossy());
+            }
+            Ok(Self(outlet))
+        }
+    }
+}
+
+impl Drop for Outlet {
+    fn drop(&mut self) {
+        // SAFETY: created by lsl_create_outlet and destroyed only here
+        unsafe { lsl_destroy_outlet(self.0) }
+    }
+}
+
+/// Append `<name>value</name>` under `element`
+fn append_value(element: *mut c_void, name: &str, value: &str) {
+    let (name, value) = (c_string(name), c_string(value));
+    // SAFETY: `element` comes from the stream info being built
+    unsafe {
+        lsl_append_child_value(element, name.as_ptr(), value.as_ptr());
+    }
+}
+
+fn append_child(element: *mut c_void, name: &str) -> *mut c_void {
+    let name = c_string(name);
+    // SAFETY: as above
+    unsafe { lsl_append_child(element, name.as_ptr()) }
+}
+
+/// EEG and marker outlets of one trial
+pub struct LslOutlets {
+    eeg: Outlet,
+    markers: Outlet,
+    num_channels: usize,
+    /// LSL clock minus Unix time, to move marker host times onto the LSL
+    /// clock
+    clock_offset: f64,
+    /// Reused buffer of microvolt values
+    chunk: Vec<f32>,
+    samples: u64,
+    events: u64,
+}
+
+impl LslOutlets {
+    /// Open `<name>` (EEG) and `<name>-Markers` outlets; `source_id` should
+    /// stay the same across a session's trials
+    pub fn open(name: &str, source_id: &str, channel_names: &[String], sample_rate: u32) -> Result<Self> {
+        let eeg = Outlet::create(
+            name,
+            "EEG",
+            channel_names.len(),
+            sample_rate as f64,
+            CFT_FLOAT32,
+            source_id,
+            |desc| {
+                append_value(desc, "manufacturer", "OpenBCI");
+                let channels = append_child(desc, "channels");
+                for label in channel_names {
+                    let channel = append_child(channels, "channel");
+                    append_value(channel, "label", label);
+                    append_value(channel, "unit", "microvolts");
+                    append_value(channel, "type", "EEG");
+                }
+            },
+        )?;
+        let marker_name = format!("{}-Markers", name);
+        let markers = Outlet::create(
+            &marker_name,
+            "Markers",
+            1,
+            IRREGULAR_RATE,
+            CFT_STRING,
+            &format!("{}-markers", source_id),
+            |_| {},
+        )?;
+        let clock_offset = clock_offset();
+        info!(
+            "Publishing LSL streams '{}' ({} channels at {} Hz) and '{}'",
+            name,
+            channel_names.len(),
+            sample_rate,
+            marker_name
+        );
+        Ok(Self {
+            eeg,
+            markers,
+            num_channels: channel_names.len(),
+            clock_offset,
+            chunk: Vec::new(),
+            samples: 0,
+            events: 0,
+        })
+    }
+
+    /// Push samples (nanovolts, one vector per sample), stamped now
+    pub fn push_samples<'a>(&mut self, samples: impl IntoIterator<Item = &'a [f32]>) -> Result<()> {
+        self.chunk.clear();
+        let mut count = 0;
+        for sample in samples {
+            // Pad or cut to the announced channel count
+            self.chunk.extend((0..self.num_channels).map(|c| sample.get(c).map_or(0.0, |x| x / NV_PER_UV)));
+            count += 1;
+        }
+        if count == 0 {
+            return Ok(());
+        }
+        // SAFETY: `chunk` holds `count * num_channels` floats
+        let code = unsafe { lsl_push_chunk_ftp(self.eeg.0, self.chunk.as_ptr(), self.chunk.len() as c_ulong, 0.0, 1) };
+        if code < 0 {
+            bail!("liblsl rejected {} samples (error {})", count, code);
+        }
+        self.samples += count;
+        Ok(())
+    }
+
+    /// Push a marker stamped with its host time (seconds since the Unix
+    /// epoch)
+    pub fn push_marker(&mut self, label: &str, host_time: f64) -> Result<()> {
+        let label = c_string(label);
+        let data = [label.as_ptr()];
+        // SAFETY: one valid string for the one-channel stream
+        let code = unsafe { lsl_push_sample_strtp(self.markers.0, data.as_ptr(), host_time + self.clock_offset, 1) };
+        if code < 0 {
+            bail!("liblsl rejected marker '{}' (error {})", label.to_string_lossy(), code);
+        }
+        self.events += 1;
+        Ok(())
+    }
+
+    /// Samples and markers pushed so far
+    pub fn counts(&self) -> (u64, u64) {
+        (self.samples, self.events)
+    }
+}
+
+/// A stream found on the network
+#[derive(Debug, Clone)]
+pub struct LslStreamInfo {
+    pub name: String,
+    pub kind: String,
+    pub hostname: String,
+    pub channel_count: usize,
+    /// 0 for streams without a fixed rate
+    pub sample_rate: f64,
+}
+
+/// Resolved stream infos, destroyed on drop
+struct Resolved(Vec<*mut c_void>);
+
+impl Resolved {
+    /// Every stream called `name` that answers within the resolve timeout
+    fn by_name(name: &str) -> Self {
+        let (prop, value) = (c_string("name"), c_string(name));
+        let mut buffer = [std::ptr::null_mut(); 8];
+        // SAFETY: `buffer` has room for the 8 infos asked for
+        let found = unsafe {
+            lsl_resolve_byprop(buffer.as_mut_ptr(), buffer.len() as u32, prop.as_ptr(), value.as_ptr(), 1, RESOLVE_TIMEOUT)
+        };
+        Self(buffer[..found.max(0) as usize].to_vec())
+    }
+
+    fn first(&self) -> Option<*mut c_void> {
+        self.0.first().copied()
+    }
+}
+
+impl Drop for Resolved {
+    fn drop(&mut self) {
+        for &info in &self.0 {
+            // SAFETY: returned by lsl_resolve_byprop and destroyed only here
+            unsafe { lsl_destroy_streaminfo(info) }
+        }
+    }
+}
+
+/// Fields of a stream info
+fn describe_info(info: *mut c_void, name: &str) -> LslStreamInfo {
+    // SAFETY: `info` is a live stream info; the strings it returns are
+    // copied before it can be destroyed
+    unsafe {
+        LslStreamInfo {
+            name: name.to_string(),
+            kind: owned(lsl_get_type(info)),
+            hostname: owned(lsl_get_hostname(info)),
+            channel_count: lsl_get_channel_count(info).max(0) as usize,
+            sample_rate: lsl_get_nominal_srate(info),
+        }
+    }
+}
+
+/// Look for the stream called `name`
+pub fn resolve(name: &str) -> Result<LslStreamInfo> {
+    let resolved = Resolved::by_name(name);
+    let Some(info) = resolved.first() else {
+        bail!("No LSL stream named '{}' found within {} s", name, RESOLVE_TIMEOUT);
+    };
+    if resolved.0.len() > 1 {
+        warn!("{} LSL streams are named '{}', recording the first", resolved.0.len(), name);
+    }
+    Ok(describe_info(info, name))
+}
+
+/// Nanovolts per unit of an LSL channel `unit`, microvolts if unknown
+fn nv_per_unit(unit: &str) -> f32 {
+    match unit.trim().to_lowercase().as_str() {
+        "v" | "volt" | "volts" => 1e9,
+        "mv" | "millivolt" | "millivolts" => 1e6,
+        "nv" | "nanovolt" | "nanovolts" => 1.0,
+        _ => NV_PER_UV,
+    }
+}
+
+/// Per-channel units from a full stream info's `<channels>` description
+///
+/// # Safety
+/// `info` must be a live stream info.
+unsafe fn channel_units(info: *mut c_void, channel_count: usize) -> Vec<String> {
+    let (channels, channel, unit) = (c_string("channels"), c_string("channel"), c_string("unit"));
+    let mut units = Vec::with_capacity(channel_count);
+    let mut element = lsl_child(lsl_child(lsl_get_desc(info), channels.as_ptr()), channel.as_ptr());
+    while units.len() < channel_count && lsl_empty(element) == 0 {
+        units.push(owned(lsl_child_value_n(element, unit.as_ptr())));
+        element = lsl_next_sibling(element);
+    }
+    units.resize(channel_count, String::new());
+    units
+}
+
+/// Samples of an LSL stream, in nanovolts and host time
+pub struct LslSource {
+    inlet: *mut c_void,
+    info: LslStreamInfo,
+    /// Nanovolts per stream unit, per channel
+    scales: Vec<f32>,
+    buffer: Vec<f32>,
+    /// Add to a stream timestamp for the LSL clock of this host
+    correction: f64,
+    corrected_at: Instant,
+    /// LSL clock minus Unix time
+    clock_offset: f64,
+    next_id: u64,
+}
+
+// liblsl inlets may be used from any thread, one at a time
+unsafe impl Send for LslSource {}
+
+impl LslSource {
+    /// Resolve the stream called `name` and open an inlet on it
+    pub fn open(name: &str) -> Result<Self> {
+        let resolved = Resolved::by_name(name);
+        let Some(resolved_info) = resolved.first() else {
+            bail!("No LSL stream named '{}' found within {} s", name, RESOLVE_TIMEOUT);
+        };
+        let info = describe_info(resolved_info, name);
+        // SAFETY: the resolved info outlives the call and the inlet copies
+        // it; the full info is destroyed once its units are read
+        unsafe {
+            let inlet = lsl_create_inlet(resolved_info, MAX_BUFFERED, 0, 1);
+            if inlet.is_null() {
+                bail!("liblsl could not open an inlet on '{}'", name);
+            }
+            // Owned from here on, so errors below destroy it
+            let mut source = Self {
+                inlet,
+                scales: Vec::new(),
+                buffer: vec![0.0; info.channel_count],
+                correction: 0.0,
+                corrected_at: Instant::now(),
+                clock_offset: clock_offset(),
+                next_id: 0,
+                info,
+            };
+            let mut ec = 0;
+            let full = lsl_get_fullinfo(inlet, OPEN_TIMEOUT, &mut ec);
+            let units = if ec == 0 && !full.is_null() {
+                let units = channel_units(full, source.info.channel_count);
+                lsl_destroy_streaminfo(full);
+                units
+            } else {
+                vec![String::new(); source.info.channel_count]
+            };
+            source.scales = units.iter().map(|u| nv_per_unit(u)).collect();
+            lsl_open_stream(inlet, OPEN_TIMEOUT, &mut ec);
+            if ec != 0 {
+                bail!("LSL stream '{}' did not start within {} s (error {})", name, OPEN_TIMEOUT, ec);
+            }
+            source.correction = lsl_time_correction(inlet, OPEN_TIMEOUT, &mut ec);
+            if ec != 0 {
+                warn!("No clock offset for LSL stream '{}' yet (error {}), using its timestamps as they are", name, ec);
+                source.correction = 0.0;
+            }
+            info!(
+                "Recording LSL stream '{}' ({}, {} channels at {} Hz from {}, unit {})",
+                source.info.name,
+                source.info.kind,
+                source.info.channel_count,
+                source.info.sample_rate,
+                source.info.hostname,
+                units.first().filter(|u| !u.is_empty()).map_or("microvolts", String::as_str)
+            );
+            Ok(source)
+        }
+    }
+
+    /// Refresh the clock offset now and then, without waiting for it
+    fn refresh_correction(&mut self) {
+        if self.corrected_at.elapsed() < CLOCK_REFRESH {
+            return;
+        }
+        self.corrected_at = Instant::now();
+        let mut ec = 0;
+        // SAFETY: the inlet is live
+        let correction = unsafe { lsl_time_correction(self.inlet, 0.0, &mut ec) };
+        if ec == 0 {
+            self.correction = correction;
+        }
+    }
+}
+
+impl Drop for LslSource {
+    fn drop(&mut self) {
+        // SAFETY: created by lsl_create_inlet and destroyed only here
+        unsafe { lsl_destroy_inlet(self.inlet) }
+    }
+}
+
+#[async_trait]
+impl DataSource for LslSource {
+    fn describe(&self) -> String {
+        format!("LSL stream '{}' on {}", self.info.name, self.info.hostname)
+    }
+
+    async fn next_sample(&mut self) -> Option<EEGSample> {
+        loop {
+            self.refresh_correction();
+            let mut ec = 0;
+            // SAFETY: `buffer` holds one value per channel
+            let timestamp = unsafe {
+                lsl_pull_sample_f(self.inlet, self.buffer.as_mut_ptr(), self.buffer.len() as i32, 0.0, &mut ec)
+            };
+            if ec == LSL_LOST_ERROR {
+                warn!("LSL stream '{}' is gone", self.info.name);
+                return None;
+            }
+            if ec != 0 || timestamp == 0.0 {
+                // Nothing ready (or a timeout); liblsl recovers lost
+                // connections by itself
+                tokio::time::sleep(POLL).await;
+                continue;
+            }
+            let sample_id = self.next_id;
+            self.next_id += 1;
+            return Some(EEGSample {
+                timestamp: timestamp + self.correction - self.clock_offset,
+                sample_id,
+                channels: self.buffer.iter().zip(&self.scales).map(|(x, scale)| x * scale).collect(),
+            });
+        }
+    }
+}
@@ -1,547 +1,641 @@
 use anyhow::Result;
-use chrono::{DateTime, Utc};
-use clap::Parser;
+use chrono::Utc;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use indicatif::MultiProgress;
 use log::{error, info, warn};
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use std::fs::{self, OpenOptions};
+use openbci_data_collector::benchmark::{self, BenchmarkArgs};
+use openbci_data_collector::board::{calibrate, connect_board, query_board, run_dry_run, run_signal_check};
+use openbci_data_collector::calibration::{self, FitArgs};
+use openbci_data_collector::checksum::{self, VerifyArgs};
+use openbci_data_collector::collector::DataCollector;
+use openbci_data_collector::container::{self, InspectArgs, ListArgs, PackArgs};
+use openbci_data_collector::convert::{self, ConvertArgs};
+use openbci_data_collector::dataset::{self, BuildArgs, SplitArgs};
+use openbci_data_collector::epoch::{self, EpochArgs};
+use openbci_data_collector::normalize::{self, StatsArgs};
+use openbci_data_collector::report::{self, ReportArgs};
+use openbci_data_collector::environment::EnvironmentInfo;
+use openbci_data_collector::platform::{self, PlatformReport};
+use openbci_data_collector::record::{
+    install_metrics_exporter, load_config, prepare, record_trial, run_monitor, ArtifactAction, RecordArgs, Transport,
+};
+use openbci_data_collector::recording::Recording;
+use openbci_data_collector::replay::{self, ReplayArgs};
+use openbci_data_collector::scope;
+use openbci_data_collector::session;
+use openbci_data_collector::shutdown;
+use openbci_data_collector::sink::OutputFormat;
+use openbci_data_collector::soak::{self, FaultKind, FaultPlan, MemoryReport, MemoryWatch, MockShield, SoakReport};
+use std::fs;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpListener;
 
 /// Command line arguments
 #[derive(Parser, Debug)]
-#[command(name = "OpenBCI Motor Imagery Data Collector")]
+#[command(name = "openbci")]
 #[command(about = "Collect and save OpenBCI EEG data for motor imagery deep learning", long_about = None)]
-struct Args {
-    /// OpenBCI WiFi Shield IP address
-    #[arg(short, long, default_value = "192.168.4.1")]
-    shield_ip: String,
-
-    /// Local IP address (your laptop on wlan1)
-    #[arg(short, long, default_value = "192.168.4.2")]
-    local_ip: String,
-
-    /// TCP port for data reception
-    #[arg(short, long, default_value = "3000")]
-    port: u16,
-
-    /// Output directory for saved data
-    #[arg(short, long, default_value = "motor_imagery_data")]
-    output_dir: String,
-
-    /// Motor imagery class: left_hand, right_hand, both_hands, rest
-    #[arg(short = 'c', long)]
-    class: String,
-
-    /// Trial number (for organizing multiple repetitions)
-    #[arg(short = 't', long, default_value = "1")]
-    trial: u32,
-
-    /// Duration per trial in seconds
-    #[arg(short, long, default_value = "5")]
-    duration: u64,
-
-    /// Sampling rate (Hz)
-    #[arg(short = 'r', long, default_value = "250")]
-    sample_rate: u32,
-
-    /// Number of channels to record
-    #[arg(long, default_value = "2")]
-    channels: usize,
-
-    /// Subject ID
-    #[arg(long, default_value = "S01")]
-    subject_id: String,
-
-    /// Session ID (for grouping trials in one recording session)
-    #[arg(long, default_value = "session_01")]
-    session_id: String,
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
 }
 
-/// EEG sample with metadata
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct EEGSample {
-    timestamp: f64,
-    sample_id: u64,
-    channels: Vec<f32>,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Record one trial of --class
+    Record(RecordArgs),
+    /// Record every trial of the protocol in --config, resting in between
+    Session(RecordArgs),
+    /// Record a short block of the config's protocol, fit CSP+LDA to it,
+    /// cross-validate, save the model and optionally start classifying
+    /// with it
+    Calibrate(CalibrateArgs),
+    /// Score every channel for railing, flat or noisy signal, line noise
+    /// and impedance; fails when one is clearly bad
+    Check(CheckArgs),
+    /// Stream to the scope and live outputs without writing trial files,
+    /// until Ctrl+C or --duration
+    Monitor(RecordArgs),
+    /// Write recorded trials in other formats next to their data file
+    Convert(ConvertArgs),
+    /// Cut recorded trials into fixed-length epochs around the cues, as a
+    /// [epochs, channels, samples] NPZ with labels
+    Epoch(EpochArgs),
+    /// Summarize class balance and data quality of a recording tree, as
+    /// tables and optionally JSON and HTML
+    Report(ReportArgs),
+    /// Cross-validate ONNX models and CSP+LDA on a built dataset and
+    /// compare accuracy, kappa, per-class F1 and latency
+    Benchmark(BenchmarkArgs),
+    /// Check recorded files against the checksums in their metadata and
+    /// session manifests
+    Verify(VerifyArgs),
+    /// Re-stream recorded trials in real time over TCP or UDP
+    Replay(ReplayArgs),
+    /// Record back-to-back trials from a built-in mock shield while
+    /// injecting faults, then write a stability report
+    Soak(SoakArgs),
+    /// Build training datasets from recorded trials
+    Dataset {
+        #[command(subcommand)]
+        command: DatasetCommand,
+    },
+    /// Bundle models with their metadata and inspect the bundles
+    Model {
+        #[command(subcommand)]
+        command: ModelCommand,
+    },
 }
 
-/// Motor imagery trial metadata
-#[derive(Debug, Serialize, Deserialize)]
-struct TrialMetadata {
-    subject_id: String,
-    session_id: String,
-    trial_number: u32,
-    class_label: String,
-    class_id: u8,
-    start_time: DateTime<Utc>,
-    end_time: Option<DateTime<Utc>>,
-    sample_rate: u32,
-    num_channels: usize,
-    total_samples: u64,
-    duration_seconds: u64,
-    electrode_config: ElectrodeConfig,
+#[derive(Subcommand, Debug)]
+enum DatasetCommand {
+    /// Validate every trial under --data-dir and merge those that pass QC
+    /// into one Parquet or NPZ file with a manifest CSV
+    Build(BuildArgs),
+    /// Write train/val/test split files for a built dataset's manifest
+    Split(SplitArgs),
+    /// Compute per-channel normalization statistics over a split's training
+    /// trials
+    Stats(StatsArgs),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ElectrodeConfig {
-    channels: Vec<String>,
-    reference: String,
-    ground: String,
+#[derive(Subcommand, Debug)]
+enum ModelCommand {
+    /// Bundle a model with its input shape, sample rate, channel order,
+    /// filters, normalization, classes and training data hash into one
+    /// .bcimodel file
+    Pack(PackArgs),
+    /// Print a .bcimodel file's metadata after checking its contents
+    Inspect(InspectArgs),
+    /// Tabulate the .bcimodel files of a directory
+    List(ListArgs),
 }
 
-/// Map motor imagery class names to numeric IDs for deep learning
-fn get_class_id(class_name: &str) -> u8 {
-    match class_name.to_lowercase().as_str() {
-        "left_hand" | "left" => 0,
-        "right_hand" | "right" => 1,
-        "both_hands" | "both" => 2,
-        "rest" | "baseline" => 3,
-        _ => {
-            warn!("Unknown class '{}', defaulting to rest (3)", class_name);
-            3
-        }
-    }
-}
+/// Options of `openbci check`
+#[derive(clap::Args, Debug, Clone)]
+struct CheckArgs {
+    /// Benchmark the processing pipeline on this device instead
+    #[arg(long)]
+    platform: bool,
 
-/// Data buffer for batch writing
-struct DataBuffer {
-    samples: Vec<EEGSample>,
-    capacity: usize,
+    #[command(flatten)]
+    args: RecordArgs,
 }
 
-impl DataBuffer {
-    fn new(capacity: usize) -> Self {
-        Self {
-            samples: Vec::with_capacity(capacity),
-            capacity,
-        }
-    }
-
-    fn push(&mut self, sample: EEGSample) -> bool {
-        self.samples.push(sample);
-        self.samples.len() >= self.capacity
-    }
-
-    fn clear(&mut self) -> Vec<EEGSample> {
-        std::mem::take(&mut self.samples)
-    }
-
-    fn len(&self) -> usize {
-        self.samples.len()
-    }
+/// Options of `openbci calibrate`
+#[derive(clap::Args, Debug, Clone)]
+struct CalibrateArgs {
+    /// Trials of each class to record [default: the config's
+    /// trials_per_class]
+    #[arg(long, value_name = "N")]
+    calibration_trials: Option<u32>,
+
+    /// Fit to the trials already recorded in this session directory
+    /// instead of recording
+    #[arg(long, value_name = "DIR")]
+    from: Option<PathBuf>,
+
+    /// Earlier calibration model whose CSP filters are kept; only the LDA
+    /// is refitted
+    #[arg(long, value_name = "FILE")]
+    base: Option<PathBuf>,
+
+    /// Where to save the model [default: calibration_model.json in the
+    /// session directory]
+    #[arg(long, value_name = "FILE")]
+    model_output: Option<PathBuf>,
+
+    /// Start openbci_online_bci on the same source with the new model
+    /// (build with --features inference); arguments after -- go to it
+    #[arg(long)]
+    online: bool,
+
+    /// Extra arguments of openbci_online_bci
+    #[arg(last = true)]
+    online_args: Vec<String>,
+
+    #[command(flatten)]
+    fit: FitArgs,
+
+    #[command(flatten)]
+    args: RecordArgs,
 }
 
-/// Data writer for CSV format
-struct CSVWriter {
-    file_path: PathBuf,
-    writer: csv::Writer<std::fs::File>,
-    samples_written: u64,
-    class_id: u8,
+/// Options of `openbci soak`
+#[derive(clap::Args, Debug, Clone)]
+struct SoakArgs {
+    /// Hours to keep recording --duration trials for
+    #[arg(long, value_name = "HOURS")]
+    hours: f64,
+
+    /// Faults to inject (comma separated: disconnect, malformed,
+    /// disk-full, clock-jump; all if omitted)
+    #[arg(long, value_delimiter = ',')]
+    faults: Vec<FaultKind>,
+
+    /// Mean seconds between faults
+    #[arg(long, default_value = "8.0")]
+    fault_interval: f64,
+
+    /// Seed for the fault schedule and signal (random if omitted)
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Resident memory growth over the first trial, in MB, that fails the
+    /// run
+    #[arg(long, default_value = "64")]
+    max_memory_growth: u64,
+
+    /// Keep the data of trials that passed (by default only failed trials
+    /// are kept for inspection)
+    #[arg(long)]
+    keep_data: bool,
+
+    #[command(flatten)]
+    args: RecordArgs,
 }
 
-impl CSVWriter {
-    fn new(output_dir: &str, subject_id: &str, session_id: &str, class_label: &str, trial: u32, class_id: u8, num_channels: usize) -> Result<Self> {
-        // Create directory structure: motor_imagery_data/S01/session_01/
-        let subject_dir = PathBuf::from(output_dir).join(subject_id).join(session_id);
-        fs::create_dir_all(&subject_dir)?;
-
-        let timestamp = Utc::now().format("%Y%m%d_%H%M%S");
-        // Filename: S01_left_hand_trial_01_class_0_20250128_143022.csv
-        let filename = format!("{}_{}_{}_trial_{:02}_class_{}_{}.csv",
-                              subject_id, class_label, session_id, trial, class_id, timestamp);
-        let file_path = subject_dir.join(filename);
-
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&file_path)?;
-
-        let mut writer = csv::Writer::from_writer(file);
-
-        // Generate channel labels with motor cortex annotations
-        let channel_labels = Self::generate_channel_labels(num_channels);
-
-        // Write header with class_id for easy loading in deep learning
-        let mut header = vec!["timestamp".to_string(), "sample_id".to_string(), "class_id".to_string()];
-        header.extend(channel_labels.clone());
-        writer.write_record(&header)?;
-
-        Ok(Self {
-            file_path,
-            writer,
-            samples_written: 0,
-            class_id,
-        })
+/// Print measured real-time headroom for the configured rate and channels
+fn platform_report(args: &RecordArgs) -> Result<()> {
+    let report = PlatformReport::measure(args.sample_rate, args.channels, args.worker_threads());
+    info!("=== Platform Report ===");
+    info!("Target: {} ({}), {} cores", report.arch, report.os, report.cores);
+    info!("Worker threads: {}", report.worker_threads);
+    info!("Socket read buffer: {} bytes", report.read_buffer_bytes);
+    info!("CSV write buffer: {} samples", report.write_buffer_samples);
+    info!("Workload: {} Hz x {} channels", report.sample_rate, report.channels);
+    for stage in &report.stages {
+        info!("  {:<12} {:>8.3} ms per second of data ({:.0}x real time)",
+              stage.name, 1000.0 / stage.headroom, stage.headroom);
     }
-
-    fn generate_channel_labels(num_channels: usize) -> Vec<String> {
-        // Map channels to standard 10-20 positions with motor cortex labels
-        let labels = vec![
-            "C3_left_motor",
-            "C4_right_motor",
-            "Cz_central",
-            "F3_frontal_left",
-            "F4_frontal_right",
-            "P3_parietal_left",
-            "P4_parietal_right",
-            "O1_occipital_left",
-        ];
-
-        labels.iter()
-            .take(num_channels)
-            .map(|s| s.to_string())
-            .collect()
+    if report.headroom >= 10.0 {
+        info!("Total headroom: {:.1}x real time", report.headroom);
+    } else {
+        warn!("Total headroom: {:.1}x real time, expect drops under load", report.headroom);
     }
+    if report.unwritten_samples > 0 {
+        warn!("The trial writer lost {} samples", report.unwritten_samples);
+    }
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
 
-    fn write_batch(&mut self, samples: &[EEGSample]) -> Result<()> {
-        for sample in samples {
-            let mut record = vec![
-                sample.timestamp.to_string(),
-                sample.sample_id.to_string(),
-                self.class_id.to_string(),
-            ];
-            for ch in &sample.channels {
-                record.push(ch.to_string());
+/// The runtime is built by hand so the worker count can follow the target.
+/// Only tokio is supported: the shield's HTTP client (reqwest) requires it.
+fn main() -> Result<()> {
+    let progress = MultiProgress::new();
+    scope::init_logger(
+        env_logger::Builder::from_default_env()
+            .filter_level(log::LevelFilter::Info)
+            .build(),
+        progress.clone(),
+    );
+
+    let matches = Cli::command().get_matches();
+    let mut command = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()).command;
+    let Some((_, matches)) = matches.subcommand() else {
+        unreachable!("clap requires a subcommand");
+    };
+    let worker_threads = match &mut command {
+        Command::Record(args) | Command::Session(args) | Command::Monitor(args) => {
+            load_config(args, matches)?;
+            args.progress = progress.clone();
+            args.worker_threads()
+        }
+        Command::Check(check) => {
+            load_config(&mut check.args, matches)?;
+            check.args.progress = progress.clone();
+            if check.platform {
+                return platform_report(&check.args);
             }
-            self.writer.write_record(&record)?;
-            self.samples_written += 1;
+            check.args.worker_threads()
+        }
+        Command::Calibrate(calibration) => {
+            load_config(&mut calibration.args, matches)?;
+            calibration.args.progress = progress.clone();
+            calibration.args.worker_threads()
+        }
+        Command::Soak(soak) => {
+            load_config(&mut soak.args, matches)?;
+            soak.args.progress = progress.clone();
+            soak.args.worker_threads()
+        }
+        Command::Convert(convert) => return convert::run(convert),
+        Command::Epoch(epoch) => return epoch::run(epoch).map(drop),
+        Command::Report(report) => return report::run(report).map(drop),
+        Command::Benchmark(benchmark) => return benchmark::run(benchmark).map(drop),
+        Command::Verify(verify) => return run_verify(verify),
+        Command::Dataset { command: DatasetCommand::Build(build) } => return dataset::build(build).map(drop),
+        Command::Dataset { command: DatasetCommand::Split(split) } => return dataset::split(split),
+        Command::Dataset { command: DatasetCommand::Stats(stats) } => return normalize::stats(stats).map(drop),
+        Command::Model { command: ModelCommand::Pack(pack) } => return container::pack(pack).map(drop),
+        Command::Model { command: ModelCommand::Inspect(inspect) } => return container::inspect(inspect),
+        Command::Model { command: ModelCommand::List(list) } => return container::list(list),
+        Command::Replay(_) => platform::default_worker_threads(),
+    };
+    if let Command::Monitor(args) = &mut command {
+        args.monitor = true;
+        // Monitoring runs until Ctrl+C unless told otherwise
+        if matches.value_source("duration") != Some(ValueSource::CommandLine) {
+            args.duration = 0;
         }
-
-        self.writer.flush()?;
-        info!("Wrote {} samples to CSV (total: {})", samples.len(), self.samples_written);
-
-        Ok(())
     }
-
-    fn finalize(&mut self) -> Result<()> {
-        self.writer.flush()?;
-        info!("Finalized CSV file: {:?}", self.file_path);
-        Ok(())
+    let monitor = matches!(command, Command::Monitor(_));
+
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(worker_threads.max(1))
+        .enable_all()
+        .build()?
+        .block_on(run(command))?;
+    // The trial was saved, but the run did not finish; Ctrl+C is how a
+    // monitor run ends
+    if let (Some(code), false) = (shutdown::exit_code(), monitor) {
+        std::process::exit(code);
     }
+    Ok(())
 }
 
-/// Main data collector
-struct DataCollector {
-    shield_ip: String,
-    local_ip: String,
-    port: u16,
-    client: Client,
-    buffer: Arc<Mutex<DataBuffer>>,
-    csv_writer: Arc<Mutex<CSVWriter>>,
-    metadata: TrialMetadata,
-    sample_count: Arc<Mutex<u64>>,
-    start_time: Instant,
-}
-
-impl DataCollector {
-    fn new(args: &Args) -> Result<Self> {
-        // Create output directory
-        fs::create_dir_all(&args.output_dir)?;
-
-        // Generate channel labels matching CSV headers
-        let channel_names = CSVWriter::generate_channel_labels(args.channels);
-
-        let electrode_config = ElectrodeConfig {
-            channels: channel_names,
-            reference: "Cz".to_string(),
-            ground: "Fpz".to_string(),
-        };
-
-        let class_id = get_class_id(&args.class);
-
-        let metadata = TrialMetadata {
-            subject_id: args.subject_id.clone(),
-            session_id: args.session_id.clone(),
-            trial_number: args.trial,
-            class_label: args.class.clone(),
-            class_id,
-            start_time: Utc::now(),
-            end_time: None,
-            sample_rate: args.sample_rate,
-            num_channels: args.channels,
-            total_samples: 0,
-            duration_seconds: args.duration,
-            electrode_config,
-        };
-
-        let client = Client::builder()
-            .timeout(Duration::from_secs(10))
-            .build()?;
-
-        let buffer = Arc::new(Mutex::new(DataBuffer::new(250))); // Buffer 1 second at 250Hz
-
-        let csv_writer = Arc::new(Mutex::new(CSVWriter::new(
-            &args.output_dir,
-            &args.subject_id,
-            &args.session_id,
-            &args.class,
-            args.trial,
-            class_id,
-            args.channels,
-        )?));
-
-        Ok(Self {
-            shield_ip: args.shield_ip.clone(),
-            local_ip: args.local_ip.clone(),
-            port: args.port,
-            client,
-            buffer,
-            csv_writer,
-            metadata,
-            sample_count: Arc::new(Mutex::new(0)),
-            start_time: Instant::now(),
-        })
+/// Record trials from a mock shield for `--hours` while injecting faults,
+/// check every trial against the shield's ledger and write the stability
+/// report. Fails when any invariant was violated.
+async fn run_soak(options: &SoakArgs) -> Result<()> {
+    let (args, hours) = (&options.args, options.hours);
+    if hours <= 0.0 {
+        anyhow::bail!("soak needs a positive number of --hours");
+    }
+    if args.duration < soak::MIN_TRIAL_SECONDS {
+        anyhow::bail!("soak needs --duration of at least {} s to fit faults into trials", soak::MIN_TRIAL_SECONDS);
     }
+    // Trials run unattended against the mock shield, one stream at a time
+    let interactive = [
+        ("--cues", args.cues),
+        ("--keys", args.keys),
+        ("--scope", args.scope),
+        ("--lsl", args.lsl),
+        ("--ws-port", args.ws_port.is_some()),
+        ("--zmq-pub", args.zmq_pub.is_some()),
+        ("--bids", args.bids),
+        ("--signal-check", args.signal_check),
+        ("--simulate", args.simulate),
+        ("--on-artifact", args.on_artifact != ArtifactAction::Mark),
+    ];
+    if let Some((flag, _)) = interactive.iter().find(|(_, set)| *set) {
+        anyhow::bail!("{} cannot be used with soak", flag);
+    }
+    soak::install_panic_counter();
+
+    let seed = options.seed.unwrap_or_else(rand::random);
+    let kinds = if options.faults.is_empty() {
+        FaultKind::ALL.to_vec()
+    } else {
+        options.faults.clone()
+    };
+    let mut plan = FaultPlan::new(kinds.clone(), options.fault_interval, seed)?;
+    let shield = MockShield::start(args.channels, args.sample_rate, seed).await?;
+
+    let soak_dir = PathBuf::from(&args.output_dir).join(format!("soak_{}", Utc::now().format("%Y%m%d_%H%M%S")));
+    let mut trial_args = args.clone();
+    trial_args.transport = Transport::Wifi;
+    trial_args.shield_ip = shield.addr().to_string();
+    trial_args.local_ip = "127.0.0.1".to_string();
+    trial_args.output_dir = soak_dir.display().to_string();
+    fs::create_dir_all(&soak_dir)?;
+
+    info!("=== Soak Test ===");
+    info!("Duration: {} h of {} s trials, seed {}", hours, args.duration, seed);
+    info!(
+        "Faults: {} every {} s on average",
+        kinds.iter().map(|k| k.name()).collect::<Vec<_>>().join(", "),
+        options.fault_interval
+    );
+    info!("Output: {:?}", soak_dir);
+
+    let mut report = SoakReport {
+        started_at: Utc::now(),
+        finished_at: None,
+        hours_requested: hours,
+        elapsed_seconds: 0.0,
+        seed,
+        sample_rate: args.sample_rate,
+        channels: args.channels,
+        format: args.formats().iter().map(OutputFormat::extension).collect::<Vec<_>>().join(","),
+        trial_seconds: args.duration,
+        fault_kinds: kinds,
+        fault_interval_seconds: options.fault_interval,
+        trials: 0,
+        failed_trials: 0,
+        samples_received: 0,
+        samples_lost: 0,
+        samples_unwritten: 0,
+        faults: Default::default(),
+        panics: 0,
+        memory: MemoryReport {
+            allowed_growth_bytes: options.max_memory_growth * 1_000_000,
+            ..Default::default()
+        },
+        violations: Vec::new(),
+        passed: false,
+    };
+    let memory = MemoryWatch::start(Duration::from_secs(1));
+    let started = Instant::now();
+    let end = started + Duration::from_secs_f64(hours * 3600.0);
+
+    while Instant::now() < end && !shutdown::requested() {
+        let trial = report.trials + 1;
+        trial_args.trial = trial;
+        // Soak trials cycle through the classes
+        let classes = trial_args.configured_paradigm.class_names();
+        trial_args.class = Some(classes[(trial as usize - 1) % classes.len()].to_string());
+
+        let board = connect_board(&trial_args).await?;
+        let settings = query_board(&trial_args, board.as_ref()).await?;
+        let environment = EnvironmentInfo::capture(board.as_ref()).await;
+        let mut collector = DataCollector::new(&trial_args, board, settings)?;
+        collector.metadata.environment = Some(environment);
+        let disk_full = Arc::new(AtomicBool::new(false));
+        collector.simulate_disk_full(Arc::clone(&disk_full));
+
+        let length = Duration::from_secs(args.duration);
+        let recording = shutdown::Recording::start();
+        let (collected, faults) = tokio::join!(
+            collector.collect_data(args.duration),
+            plan.run_trial(&shield, &disk_full, length)
+        );
+        let ledger = shield.ledger();
+        let metadata_path = collector.finalize(&trial_args.output_dir)?;
+        drop(recording);
+
+        let mut violations = Vec::new();
+        if let Err(e) = collected {
+            violations.push(format!("trial {}: collection failed: {}", trial, e));
+        }
+        match Recording::load(&metadata_path) {
+            Ok(rec) => violations.extend(soak::check_trial(&rec.metadata, &ledger, rec.samples.len())),
+            Err(e) => violations.push(format!("trial {}: could not read back: {:#}", trial, e)),
+        }
+        if violations.is_empty() && !options.keep_data {
+            collector.discard(&metadata_path)?;
+        }
+        report.add_trial(&collector.metadata, &ledger, faults, violations);
 
-    async fn start_streaming(&self) -> Result<()> {
-        // First, try to stop any existing TCP stream
-        info!("Cleaning up any existing TCP streams");
-        let stop_url = format!("http://{}/tcp", self.shield_ip);
-        let _ = self.client.delete(&stop_url).send().await;
-
-        // Wait a moment for cleanup
-        tokio::time::sleep(Duration::from_millis(500)).await;
-
-        let tcp_config = serde_json::json!({
-            "ip": self.local_ip,
-            "port": self.port,
-            "output": "json",
-            "delimiter": true,
-            "latency": 4000, // 4ms for 250Hz
-            "burst": false
-        });
-
-        let url = format!("http://{}/tcp", self.shield_ip);
-        info!("Starting TCP stream from {}", url);
-        info!("Config: ip={}, port={}", self.local_ip, self.port);
-
-        // Use a longer timeout specifically for this request (30 seconds)
-        let client_with_long_timeout = Client::builder()
-            .timeout(Duration::from_secs(30))
-            .build()?;
-
-        let response = client_with_long_timeout
-            .post(&url)
-            .json(&tcp_config)
-            .send()
-            .await?;
-
-        if response.status().is_success() {
-            let body = response.text().await?;
-            info!("Stream started successfully. Response: {}", body);
-            Ok(())
+        let rss = platform::resident_memory().unwrap_or_default();
+        if trial == 1 {
+            report.memory.baseline_bytes = rss;
+            memory.reset();
         } else {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_else(|_| "Unable to read response body".to_string());
-            anyhow::bail!("Failed to start stream: {} - {}", status, body)
+            report.memory.peak_bytes = report.memory.peak_bytes.max(memory.peak()).max(rss);
         }
+        report.memory.final_bytes = rss;
+        info!(
+            "Soak trial {}: {} received, {} lost, {} unwritten, {:.1} MB resident, {} violations so far",
+            trial,
+            collector.metadata.total_samples,
+            ledger.lost_samples,
+            collector.metadata.stream_health.as_ref().map_or(0, |h| h.unwritten_samples),
+            rss as f64 / 1e6,
+            report.violations.len()
+        );
     }
 
-    async fn stop_streaming(&self) -> Result<()> {
-        let url = format!("http://{}/tcp", self.shield_ip);
-        info!("Stopping stream");
-        let _ = self.client.delete(&url).send().await;
-        Ok(())
+    report.finish(started);
+    let report_path = soak_dir.join("soak_report.json");
+    fs::write(&report_path, serde_json::to_string_pretty(&report)?)?;
+
+    info!("=== Soak Report ===");
+    info!(
+        "{} trials in {:.1} h, {} samples received",
+        report.trials,
+        report.elapsed_seconds / 3600.0,
+        report.samples_received
+    );
+    info!(
+        "Faults: {} disconnects, {} malformed, {} disk full, {} clock jumps",
+        report.faults.disconnects, report.faults.malformed, report.faults.disk_full, report.faults.clock_jumps
+    );
+    info!(
+        "Memory: {:.1} MB after warm-up, {:.1} MB peak",
+        report.memory.baseline_bytes as f64 / 1e6,
+        report.memory.peak_bytes as f64 / 1e6
+    );
+    info!("Saved report to {:?}", report_path);
+    if !report.passed {
+        for violation in &report.violations {
+            error!("  {}", violation);
+        }
+        anyhow::bail!("Soak test FAILED with {} violations", report.violations.len());
     }
+    info!("Soak test PASSED");
+    Ok(())
+}
 
-    async fn collect_data(&mut self, duration_secs: u64) -> Result<()> {
-        info!("Starting data collection for {} seconds", duration_secs);
-        let channels_str = self.metadata.electrode_config.channels.join(", ");
-        info!("Electrode configuration: {} (active) | {} (ref) | {} (gnd)",
-              channels_str,
-              self.metadata.electrode_config.reference,
-              self.metadata.electrode_config.ground);
-
-        // Setup TCP listener FIRST (before starting stream)
-        let addr = format!("0.0.0.0:{}", self.port);
-        let listener = TcpListener::bind(&addr).await?;
-        info!("Listening on {}", addr);
-
-        // Now start streaming (this will cause the board to connect to us)
-        self.start_streaming().await?;
-
-        // Accept connection with timeout
-        let accept_future = listener.accept();
-        let (mut socket, addr) = tokio::time::timeout(
-            Duration::from_secs(10),
-            accept_future
-        ).await??;
-        
-        info!("Connected to: {}", addr);
-
-        let mut buffer_vec = vec![0u8; 16384];
-        let end_time = if duration_secs > 0 {
-            Some(Instant::now() + Duration::from_secs(duration_secs))
-        } else {
-            None
-        };
-
-        let sample_count = Arc::clone(&self.sample_count);
-        let buffer = Arc::clone(&self.buffer);
-        let csv_writer = Arc::clone(&self.csv_writer);
-
-        let mut last_progress = Instant::now();
-
-        loop {
-            // Check if we should stop
-            if let Some(end) = end_time {
-                if Instant::now() >= end {
-                    info!("Duration reached, stopping collection");
-                    break;
-                }
+async fn run(command: Command) -> Result<()> {
+    shutdown::install()?;
+    match command {
+        Command::Record(args) => {
+            let args = prepare(args).await?;
+            if args.class.is_none() {
+                anyhow::bail!("record needs --class; to record the classes of a config's [protocol], use session");
             }
-
-            // Read data with timeout
-            let read_future = socket.read(&mut buffer_vec);
-            match tokio::time::timeout(Duration::from_millis(100), read_future).await {
-                Ok(Ok(0)) => {
-                    warn!("Connection closed");
-                    break;
-                }
-                Ok(Ok(n)) => {
-                    let data_str = String::from_utf8_lossy(&buffer_vec[..n]);
-                    
-                    for line in data_str.lines() {
-                        if line.trim().is_empty() {
-                            continue;
-                        }
-                        
-                        if let Ok(chunk) = serde_json::from_str::<serde_json::Value>(line) {
-                            if let Some(samples_array) = chunk.get("chunk").and_then(|c| c.as_array()) {
-                                for sample_json in samples_array {
-                                    if let (Some(data), Some(ts)) = (
-                                        sample_json.get("data").and_then(|d| d.as_array()),
-                                        sample_json.get("timestamp").and_then(|t| t.as_f64())
-                                    ) {
-                                        let channels: Vec<f32> = data
-                                            .iter()
-                                            .filter_map(|v| v.as_f64().map(|f| f as f32))
-                                            .collect();
-
-                                        let mut count = sample_count.lock().unwrap();
-                                        let sample = EEGSample {
-                                            timestamp: ts,
-                                            sample_id: *count,
-                                            channels,
-                                        };
-                                        *count += 1;
-
-                                        let mut buf = buffer.lock().unwrap();
-                                        if buf.push(sample) {
-                                            // Buffer full, write to disk
-                                            let samples_to_write = buf.clear();
-
-                                            let mut w = csv_writer.lock().unwrap();
-                                            if let Err(e) = w.write_batch(&samples_to_write) {
-                                                error!("Failed to write to CSV: {}", e);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    // Progress update every 5 seconds
-                    if last_progress.elapsed() >= Duration::from_secs(5) {
-                        let count = *sample_count.lock().unwrap();
-                        let elapsed = self.start_time.elapsed().as_secs();
-                        let rate = count as f64 / elapsed as f64;
-                        info!("Collected {} samples ({:.1} Hz)", count, rate);
-                        last_progress = Instant::now();
-                    }
-                }
-                Ok(Err(e)) => {
-                    error!("Error reading: {}", e);
-                    break;
-                }
-                Err(_) => {
-                    // Timeout, continue
-                }
+            if args.dry_run {
+                return run_dry_run(&args).await;
             }
+            calibrate(&args).await?;
+            record_trial(&args).await?;
+            session::update_qc(&args)
         }
-
-        // Write remaining buffered samples
-        let mut buf = buffer.lock().unwrap();
-        if buf.len() > 0 {
-            let samples_to_write = buf.clear();
-
-            let mut w = csv_writer.lock().unwrap();
-            let _ = w.write_batch(&samples_to_write);
+        Command::Session(args) => {
+            let args = prepare(args).await?;
+            let Some(plan) = args.experiment.as_ref().and_then(|e| e.session.clone()) else {
+                anyhow::bail!("session needs a --config whose [protocol] lists classes");
+            };
+            if args.class.is_some() {
+                anyhow::bail!("session records the classes of the config, use record for a single --class");
+            }
+            if args.dry_run {
+                return run_dry_run(&args).await;
+            }
+            calibrate(&args).await?;
+            session::run(&args, &plan).await
+        }
+        Command::Calibrate(calibration) => run_calibration(calibration).await,
+        Command::Check(check) => {
+            let args = prepare(check.args).await?;
+            calibrate(&args).await?;
+            let board = connect_board(&args).await?;
+            query_board(&args, board.as_ref()).await?;
+            run_signal_check(&args, board.as_ref()).await?;
+            Ok(())
+        }
+        Command::Monitor(args) => {
+            let args = prepare(args).await?;
+            if args.dry_run {
+                return run_dry_run(&args).await;
+            }
+            run_monitor(&args).await
+        }
+        Command::Soak(soak) => {
+            if let Some(addr) = soak.args.metrics_addr {
+                install_metrics_exporter(addr)?;
+            }
+            run_soak(&soak).await
+        }
+        Command::Replay(args) => replay::run(&args).await,
+        Command::Convert(_)
+        | Command::Epoch(_)
+        | Command::Report(_)
+        | Command::Benchmark(_)
+        | Command::Verify(_)
+        | Command::Dataset { .. }
+        | Command::Model { .. } => {
+            unreachable!("runs without a runtime")
         }
-
-        self.stop_streaming().await?;
-
-        Ok(())
-    }
-
-    fn finalize(&mut self, output_dir: &str) -> Result<()> {
-        let total_samples = *self.sample_count.lock().unwrap();
-        self.metadata.end_time = Some(Utc::now());
-        self.metadata.total_samples = total_samples;
-
-        info!("Finalizing data collection...");
-        info!("Total samples collected: {}", total_samples);
-
-        let mut w = self.csv_writer.lock().unwrap();
-        w.finalize()?;
-
-        // Save metadata in same directory structure as CSV
-        let subject_dir = PathBuf::from(output_dir)
-            .join(&self.metadata.subject_id)
-            .join(&self.metadata.session_id);
-
-        let metadata_filename = format!("{}_{}_trial_{:02}_class_{}_metadata.json",
-                                       self.metadata.subject_id,
-                                       self.metadata.class_label,
-                                       self.metadata.trial_number,
-                                       self.metadata.class_id);
-        let metadata_path = subject_dir.join(metadata_filename);
-        let metadata_json = serde_json::to_string_pretty(&self.metadata)?;
-        fs::write(&metadata_path, metadata_json)?;
-        info!("Saved metadata to: {:?}", metadata_path);
-
-        Ok(())
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::Builder::from_default_env()
-        .filter_level(log::LevelFilter::Info)
-        .init();
-
-    let args = Args::parse();
-
-    info!("=== OpenBCI Motor Imagery Data Collector ===");
-    info!("Subject: {}", args.subject_id);
-    info!("Session: {}", args.session_id);
-    info!("Class: {} (ID: {})", args.class, get_class_id(&args.class));
-    info!("Trial: {}", args.trial);
-    info!("Duration: {} seconds", args.duration);
-    info!("Output: {}", args.output_dir);
-    info!("Channels: {}", args.channels);
-    info!("");
-
-    let mut collector = DataCollector::new(&args)?;
-
-    match collector.collect_data(args.duration).await {
-        Ok(_) => {
-            info!("Data collection completed successfully");
-        }
-        Err(e) => {
-            error!("Error during collection: {}", e);
+/// Record a calibration block, fit a CSP+LDA model to it and hand over to
+/// online classification
+async fn run_calibration(calibration: CalibrateArgs) -> Result<()> {
+    let dir = match &calibration.from {
+        Some(dir) => dir.clone(),
+        None => {
+            let args = prepare(calibration.args.clone()).await?;
+            let Some(mut plan) = args.experiment.as_ref().and_then(|e| e.session.clone()) else {
+                anyhow::bail!("calibrate needs a --config whose [protocol] lists classes, or --from");
+            };
+            if args.class.is_some() {
+                anyhow::bail!("calibrate records the classes of the config, not --class");
+            }
+            if let Some(trials) = calibration.calibration_trials {
+                plan.trials_per_class = trials;
+            }
+            if args.dry_run {
+                return run_dry_run(&args).await;
+            }
+            calibrate(&args).await?;
+            session::run(&args, &plan).await?;
+            if shutdown::requested() {
+                anyhow::bail!("Calibration interrupted, fit the trials recorded so far with --from");
+            }
+            args.session_dir()
         }
+    };
+
+    let (model, path) =
+        calibration::run(&dir, &calibration.fit, calibration.base.as_deref(), calibration.model_output.as_deref())?;
+    match &model.cross_validation {
+        Some(cv) => info!(
+            "Calibration accuracy: {:.1}% +/- {:.1}% over {} folds (chance {:.1}%)",
+            100.0 * cv.mean_accuracy,
+            100.0 * cv.std_accuracy,
+            cv.folds,
+            100.0 * cv.chance_level
+        ),
+        None => warn!("The model was not cross-validated, record more trials to see how well it does"),
     }
 
-    collector.finalize(&args.output_dir)?;
+    let args = &calibration.args;
+    let online = match args.transport {
+        _ if args.simulate => None,
+        Transport::Wifi => Some(vec![
+            "--source".to_string(),
+            "wifi".to_string(),
+            "--shield-ip".to_string(),
+            args.shield_ip.clone(),
+            "--local-ip".to_string(),
+            args.local_ip.clone(),
+            "--port".to_string(),
+            args.port.to_string(),
+        ]),
+        Transport::Synthetic => Some(vec![
+            "--source".to_string(),
+            "synthetic".to_string(),
+            "--sample-rate".to_string(),
+            args.sample_rate.to_string(),
+            "--channels".to_string(),
+            args.channels.to_string(),
+        ]),
+        Transport::Lsl => args.stream_name.as_ref().map(|name| {
+            vec!["--source".to_string(), "lsl".to_string(), "--stream-name".to_string(), name.clone()]
+        }),
+        Transport::Replay => args.replay_file.as_ref().map(|file| {
+            vec!["--source".to_string(), "replay".to_string(), "--replay-file".to_string(), file.display().to_string()]
+        }),
+        Transport::Serial | Transport::Ble => None,
+    };
+    let Some(source) = online else {
+        info!("Classify online with: openbci_online_bci --model {}", path.display());
+        return Ok(());
+    };
+    let mut online_args = vec!["--model".to_string(), path.display().to_string()];
+    if let Some(config) = &args.config {
+        online_args.extend(["--config".to_string(), config.display().to_string()]);
+    }
+    online_args.extend(source);
+    online_args.extend(calibration.online_args.iter().cloned());
+    if !calibration.online {
+        info!("Classify online with: openbci_online_bci {}", online_args.join(" "));
+        return Ok(());
+    }
 
-    info!("=== Collection Complete ===");
+    let binary = std::env::current_exe()?.with_file_name(format!("openbci_online_bci{}", std::env::consts::EXE_SUFFIX));
+    if !binary.exists() {
+        anyhow::bail!("{:?} not found, build it with --features inference", binary);
+    }
+    info!("Starting online classification: {:?} {}", binary, online_args.join(" "));
+    let status = tokio::process::Command::new(&binary).args(&online_args).status().await?;
+    if !status.success() {
+        anyhow::bail!("openbci_online_bci exited with {}", status);
+    }
+    Ok(())
+}
 
+/// Verify the checksums under `--paths`; fails on any mismatch
+fn run_verify(args: &VerifyArgs) -> Result<()> {
+    let verification = checksum::run(args)?;
+    if !verification.failures.is_empty() {
+        anyhow::bail!("{} problems in {} files", verification.failures.len(), verification.verified + verification.failures.len());
+    }
     Ok(())
 }
@@ -0,0 +1,467 @@
+//! Per-trial metadata written next to every recording.
+
+use crate::bandpower::ChannelPower;
+use crate::checksum::FileChecksum;
+use crate::config::ExperimentInfo;
+use crate::environment::EnvironmentInfo;
+use crate::gaps::GapFill;
+use crate::montage::Montage;
+use crate::multiboard::MultiBoardInfo;
+use crate::paradigm::ParadigmInfo;
+use crate::simulate::SimulationInfo;
+use chrono::{DateTime, Utc};
+use openbci_wifi_client::{Capabilities, RegisterDump};
+use serde::{Deserialize, Serialize};
+
+/// Marker recorded during a trial, anchored to the next written sample
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarkerRecord {
+    pub label: String,
+    pub host_time: f64,
+    pub sample_id: u64,
+}
+
+/// Motor imagery trial metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrialMetadata {
+    pub subject_id: String,
+    pub session_id: String,
+    pub trial_number: u32,
+    pub class_label: String,
+    pub class_id: u8,
+    pub start_time: DateTime<Utc>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub sample_rate: u32,
+    pub num_channels: usize,
+    pub total_samples: u64,
+    pub duration_seconds: u64,
+    pub electrode_config: ElectrodeConfig,
+    /// Board, firmware, gains and registers read before the trial, when
+    /// the board answered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub board_settings: Option<BoardSettings>,
+    /// Collector build, host, command line and shield status when the
+    /// board was connected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<EnvironmentInfo>,
+    /// Electrode positions, names, reference and ground the trial was
+    /// recorded with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub montage: Option<Montage>,
+    #[serde(default)]
+    pub markers: Vec<MarkerRecord>,
+    /// Per-channel electrode impedance in kOhm, when measured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impedance_kohm: Option<Vec<f32>>,
+    /// Name of the data file in the same directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_file: Option<String>,
+    /// The trial in the other formats it was written in, same directory
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub other_data_files: Vec<String>,
+    /// Present when the data files were rotated with --segment-minutes;
+    /// `data_file` and `other_data_files` are those of the first segment
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub segments: Vec<Segment>,
+    /// SHA-256 of every data file, taken once they were closed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub checksums: Vec<FileChecksum>,
+    /// Present when synthetic artifacts were mixed into the live signal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_injection: Option<ArtifactInjectionInfo>,
+    /// Present when the trial was recorded from the EEG simulator
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub simulation: Option<SimulationInfo>,
+    /// Present when the trial was recorded from several shields
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multi_board: Option<MultiBoardInfo>,
+    /// Present when the trial was recorded with --config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub experiment: Option<ExperimentInfo>,
+    /// Present when the trial was recorded with --paradigm
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paradigm: Option<ParadigmInfo>,
+    /// Present when blinks were regressed out before recording
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eog_regression: Option<EogRegressionInfo>,
+    /// Present when the stream was cleaned with ASR before recording
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asr: Option<AsrInfo>,
+    /// Present when samples were band-pass or notch filtered before
+    /// recording
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub online_filter: Option<OnlineFilterInfo>,
+    /// Present when the trial ran with --detect-artifacts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_detection: Option<ArtifactDetectionInfo>,
+    /// Stream and disk discontinuities during the trial
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_health: Option<StreamHealth>,
+    /// Rate the board actually delivered, when the trial was long enough
+    /// to measure it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub measured_sample_rate: Option<SampleRateInfo>,
+    /// Host receive times of the sample batches and the board clock model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_clock: Option<HostClockInfo>,
+    /// Mu and beta power over C3/C4 after the cue, against rest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub erd: Option<ErdInfo>,
+    /// The recording was stopped by Ctrl+C or SIGTERM before its duration
+    #[serde(default)]
+    pub interrupted: bool,
+    /// Trial number of the trial a session recorded in place of this one,
+    /// when it came out bad
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replaced_by: Option<u32>,
+}
+
+/// How a contaminated test recording was produced. The injected segments
+/// are marked with `artifact_start:*` / `artifact_end:*` markers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactInjectionInfo {
+    pub kinds: Vec<String>,
+    pub interval_seconds: f64,
+    pub seed: Option<u64>,
+    /// Recordings replayed instead of synthetic templates
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recordings: Vec<String>,
+    pub injected: usize,
+}
+
+/// EOG regression applied online: the EOG channels, high-passed at
+/// 0.1 Hz and scaled per channel, are subtracted from the others, which
+/// keep their DC. The EOG channels themselves are left as recorded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EogRegressionInfo {
+    pub eog_channels: Vec<String>,
+    pub calibration_file: String,
+    pub calibrated_at: DateTime<Utc>,
+}
+
+/// Artifact subspace reconstruction applied online. The recorded data is
+/// high-passed at 0.5 Hz and cleaned; the raw signal is not kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AsrInfo {
+    pub cutoff: f64,
+    pub calibration_file: String,
+    pub calibrated_at: DateTime<Utc>,
+    pub blocks: u64,
+    pub reconstructed_blocks: u64,
+}
+
+/// Causal filters applied to every channel after ASR; the unfiltered
+/// signal is not kept. Edges are Butterworth of `order`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OnlineFilterInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub highpass_hz: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lowpass_hz: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notch_hz: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notch_q: Option<f64>,
+    /// The mean of the channels was subtracted from each (`--car`)
+    #[serde(default)]
+    pub common_average_reference: bool,
+}
+
+/// Post-trial quick-look at C3/C4 in µV²/Hz over the imagery window, from
+/// the cue to the end of the trial (all of it without cues). Changes are in
+/// % against `baseline`: `fixation` before the cue or the session's earlier
+/// `rest_trials`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErdInfo {
+    pub baseline: Option<String>,
+    pub window_seconds: f64,
+    pub channels: Vec<ChannelPower>,
+}
+
+/// Online artifact detection. Detected segments are bracketed by
+/// `artifact_start:*` / `artifact_end:*` markers of kind `amplitude`,
+/// `flatline` or `gradient`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactDetectionInfo {
+    pub max_amplitude_uv: f64,
+    pub flat_amplitude_uv: f64,
+    pub max_gradient_uv: f64,
+    pub segments: u64,
+    /// Samples inside at least one detected segment
+    pub contaminated_samples: u64,
+    /// The trial was stopped early for too many artifacts
+    #[serde(default)]
+    pub aborted: bool,
+}
+
+/// Where samples went missing. `total_samples` counts received samples,
+/// so the data file holds `total_samples - unwritten_samples +
+/// filled_samples` rows.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StreamHealth {
+    /// Lines or packets the board link could not decode
+    pub parse_errors: u64,
+    /// Packets missing from the board's sequence numbering (serial, BLE)
+    pub dropped_packets: u64,
+    pub reconnects: u64,
+    /// Samples missing between received ones, from the board's timestamps
+    /// (WiFi)
+    pub missing_samples: u64,
+    /// Board timestamps that jumped against the host clock
+    pub clock_jumps: u64,
+    /// Batches the data file rejected
+    pub write_errors: u64,
+    /// Received samples lost to failed writes
+    pub unwritten_samples: u64,
+    /// Most samples and markers queued for the trial files at once; at
+    /// the ring's 10 s of samples they start to be dropped
+    #[serde(default)]
+    pub peak_write_backlog: u64,
+    /// How stream gaps were filled in the data file, with --fill-gaps
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fill: Option<GapFill>,
+    /// Rows written in place of missing samples
+    #[serde(default)]
+    pub filled_samples: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gaps: Vec<GapRecord>,
+    /// Least free space on the output disk while recording
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_free_bytes: Option<u64>,
+}
+
+/// What the board reported about its configuration before the trial.
+/// `channels` are the ADS1299's own settings; `scaling_gains` are the ones
+/// the link converted samples to nanovolts with, so where the two differ
+/// the recorded values are off by their ratio.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BoardSettings {
+    /// `cyton`, `daisy` or `ganglion`
+    pub board: String,
+    /// Board firmware from `V`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware: Option<String>,
+    /// WiFi shield firmware from `/version`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shield_firmware: Option<String>,
+    /// Gain per channel the samples were scaled with (`/board` on WiFi)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scaling_gains: Vec<u8>,
+    /// ADC data rate from CONFIG1 in Hz
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adc_sample_rate: Option<u32>,
+    /// Decoded CHnSET registers in channel order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub channels: Vec<ChannelRegister>,
+    /// The whole `?` dump, for settings without a decoded field (bias,
+    /// lead-off, SRB1)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub registers: Vec<RegisterValue>,
+}
+
+/// One channel's CHnSET register
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelRegister {
+    pub channel: u8,
+    pub gain: u8,
+    /// `normal`, `shorted`, `testsig`, ...
+    pub input_type: String,
+    pub power_down: bool,
+    pub srb2: bool,
+}
+
+/// One register of the dump, address and value as read
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterValue {
+    pub name: String,
+    pub address: u8,
+    pub value: u8,
+}
+
+impl BoardSettings {
+    pub fn new(caps: &Capabilities, scaling_gains: Option<Vec<u8>>, dump: Option<&RegisterDump>) -> Self {
+        Self {
+            board: format!("{:?}", caps.board).to_lowercase(),
+            firmware: caps.firmware.clone(),
+            shield_firmware: caps.shield_firmware.clone(),
+            scaling_gains: scaling_gains.unwrap_or_default(),
+            adc_sample_rate: dump.and_then(RegisterDump::sample_rate),
+            channels: dump
+                .map(|dump| {
+                    dump.channels()
+                        .into_iter()
+                        .map(|c| ChannelRegister {
+                            channel: c.channel,
+                            gain: c.gain,
+                            input_type: c.input_type.to_string(),
+                            power_down: c.power_down,
+                            srb2: c.srb2,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            registers: dump
+                .map(|dump| {
+                    dump.registers
+                        .iter()
+                        .map(|r| RegisterValue {
+                            name: r.name.clone(),
+                            address: r.address,
+                            value: r.value,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Gain of each of the first `channels` channels: the register's when
+    /// read, else the one the samples were scaled with
+    pub fn gains(&self, channels: usize) -> Vec<Option<u8>> {
+        (0..channels)
+            .map(|i| self.channels.get(i).map(|c| c.gain).or_else(|| self.scaling_gains.get(i).copied()))
+            .collect()
+    }
+}
+
+/// One set of data files of a rotated recording. Sample IDs run on
+/// across segments, so markers and gaps keep their place.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Segment {
+    /// Sample ID of the segment's first row
+    pub first_sample: u64,
+    /// Sample IDs the segment covers
+    pub samples: u64,
+    pub start_time: DateTime<Utc>,
+    /// Data files in the order of `data_file` and `other_data_files`
+    pub files: Vec<String>,
+}
+
+/// Why a gap is in the recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GapCause {
+    /// Samples never arrived
+    Stream,
+    /// The board clock jumped; `samples` is estimated from the host clock
+    ClockJump,
+    /// Samples arrived but could not be written
+    Write,
+}
+
+/// One discontinuity: `samples` are missing before (stream, clock jump) or
+/// from (write, filled stream gaps) `sample_id`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GapRecord {
+    pub sample_id: u64,
+    pub samples: u64,
+    pub cause: GapCause,
+}
+
+/// Host side of the trial's timing. `file` has a row per batch of samples
+/// that arrived together: its first sample ID, the number of samples, the
+/// board timestamp of the first (s), and when it arrived on the host's
+/// monotonic clock (s since `monotonic_epoch`) and wall clock (Unix s).
+/// For boards with their own clock, the model maps board time to host time:
+/// `board + offset_seconds + drift_ppm * 1e-6 * (board - reference_board_time)`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostClockInfo {
+    pub file: String,
+    pub batches: u64,
+    /// Wall clock (Unix s) when the first sample arrived, monotonic zero
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monotonic_epoch: Option<f64>,
+    /// Board time (s) the model starts at: the first sample, or the first
+    /// after the last clock jump
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference_board_time: Option<f64>,
+    /// Lowest delivery delay at the reference, from the per-second floors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset_seconds: Option<f64>,
+    /// Host clock against board clock; needs two seconds of samples
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub drift_ppm: Option<f64>,
+}
+
+/// Sampling rate measured against the host clock over the trial, missing
+/// samples included
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SampleRateInfo {
+    pub measured_hz: f64,
+    /// Off the configured `sample_rate`, in percent
+    pub deviation_percent: f64,
+    /// Board clock against host clock, for boards that timestamp samples
+    /// (WiFi)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_drift_ppm: Option<f64>,
+    /// Host seconds the measurement covers
+    pub seconds: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ElectrodeConfig {
+    pub channels: Vec<String>,
+    pub reference: String,
+    pub ground: String,
+}
+
+impl TrialMetadata {
+    /// Rate to analyse the trial at: the measured one when there is one,
+    /// else the configured one
+    pub fn effective_sample_rate(&self) -> f64 {
+        match &self.measured_sample_rate {
+            Some(measured) => measured.measured_hz,
+            None => self.sample_rate as f64,
+        }
+    }
+
+    /// Samples the trial should contain at the rate the board delivered
+    pub fn expected_samples(&self) -> u64 {
+        let seconds = if self.duration_seconds > 0 {
+            self.duration_seconds as f64
+        } else {
+            // Open-ended recording, fall back to wall-clock length
+            match self.end_time {
+                Some(end) => (end - self.start_time).num_milliseconds().max(0) as f64 / 1000.0,
+                None => 0.0,
+            }
+        };
+        (seconds * self.effective_sample_rate()).round() as u64
+    }
+
+    /// Every data file of the trial, in all formats and segments
+    pub fn all_data_files(&self) -> Vec<&String> {
+        if self.segments.is_empty() {
+            self.data_file.iter().chain(&self.other_data_files).collect()
+        } else {
+            self.segments.iter().flat_map(|segment| &segment.files).collect()
+        }
+    }
+
+    /// Rows in the data file: received samples less failed writes, plus
+    /// filled gaps
+    pub fn written_samples(&self) -> u64 {
+        let (unwritten, filled) = self
+            .stream_health
+            .as_ref()
+            .map_or((0, 0), |h| (h.unwritten_samples, h.filled_samples));
+        self.total_samples.saturating_sub(unwritten) + filled
+    }
+
+    /// Fraction of received samples inside detected artifact segments
+    pub fn contaminated_fraction(&self) -> Option<f64> {
+        let detection = self.artifact_detection.as_ref()?;
+        Some(detection.contaminated_samples as f64 / self.total_samples.max(1) as f64)
+    }
+
+    /// Fraction of expected samples that never arrived
+    pub fn drop_rate(&self) -> f64 {
+        let expected = self.expected_samples();
+        if expected == 0 {
+            return 0.0;
+        }
+        (1.0 - self.total_samples as f64 / expected as f64).max(0.0)
+    }
+}
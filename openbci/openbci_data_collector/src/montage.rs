@@ -0,0 +1,247 @@
+//! Session montage: which 10-20 electrode sits on which board channel.
+//!
+//! The montage wizard confirms the mapping at session start and writes it
+//! to `montage.json` in the session directory; every trial recorded in that
+//! session then takes its channel labels from there. Without the wizard
+//! the montage comes from `--montage` or the `[montage]` table of
+//! `--config`, which may also name the channels. Every trial embeds the
+//! montage it was recorded with in its metadata.
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use eeg_dsp::{Reference, Rereference};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// File name of the confirmed montage in a session directory
+pub const MONTAGE_FILE: &str = "montage.json";
+
+/// 10-20 positions plus the 10-10 positions common on motor imagery caps
+const POSITIONS: &[&str] = &[
+    "Fp1", "Fpz", "Fp2", "AF3", "AF4", "F7", "F3", "Fz", "F4", "F8", "FC5", "FC3", "FC1", "FCz",
+    "FC2", "FC4", "FC6", "T7", "T3", "C5", "C3", "C1", "Cz", "C2", "C4", "C6", "T8", "T4", "CP5",
+    "CP3", "CP1", "CPz", "CP2", "CP4", "CP6", "P7", "T5", "P3", "Pz", "P4", "P8", "T6", "PO3",
+    "POz", "PO4", "O1", "Oz", "O2", "A1", "A2", "M1", "M2",
+];
+
+/// Annotated CSV column names kept for the original default montage
+const ANNOTATIONS: &[(&str, &str)] = &[
+    ("C3", "left_motor"),
+    ("C4", "right_motor"),
+    ("Cz", "central"),
+    ("F3", "frontal_left"),
+    ("F4", "frontal_right"),
+    ("P3", "parietal_left"),
+    ("P4", "parietal_right"),
+    ("O1", "occipital_left"),
+];
+
+/// Tap response must exceed the channel's baseline RMS by this factor
+const RESPONSE_RATIO: f32 = 3.0;
+
+/// Canonical spelling of a 10-20 label (`c3` -> `C3`), if it is one
+pub fn normalize_label(label: &str) -> Option<&'static str> {
+    let label = label.trim();
+    POSITIONS.iter().copied().find(|p| p.eq_ignore_ascii_case(label))
+}
+
+/// CSV column name for a label, e.g. `C3_left_motor`
+pub fn column_name(label: &str) -> String {
+    match ANNOTATIONS.iter().find(|(l, _)| *l == label) {
+        Some((l, note)) => format!("{}_{}", l, note),
+        None => label.to_string(),
+    }
+}
+
+/// Index in `labels` of each of `names`, in their order. Labels match
+/// ignoring case and an annotation after an underscore, so `C3` finds
+/// `C3_left_motor`.
+pub fn find_channels(names: &[String], labels: &[String]) -> Result<Vec<usize>> {
+    let key = |label: &str| label.trim().split('_').next().unwrap_or_default().to_lowercase();
+    names
+        .iter()
+        .map(|name| {
+            labels
+                .iter()
+                .position(|label| label.eq_ignore_ascii_case(name) || key(label) == key(name))
+                .with_context(|| format!("Channel '{}' is not among {:?}", name, labels))
+        })
+        .collect()
+}
+
+/// One board channel and the electrode wired to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelAssignment {
+    /// Board channel, 1-based as printed on the Cyton
+    pub channel: usize,
+    pub label: String,
+    /// Channel name from a montage file, used instead of the annotated
+    /// column name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Whether a tap on the electrode showed up on this channel
+    pub verified: bool,
+}
+
+/// Confirmed electrode montage for a session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Montage {
+    /// Name given in the montage file, e.g. `motor-8ch`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub channels: Vec<ChannelAssignment>,
+    pub reference: String,
+    pub ground: String,
+    /// Re-referencing applied at export time and by online classifiers,
+    /// the recorded channels are kept as they are
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rereference: Option<Reference>,
+    pub confirmed_at: DateTime<Utc>,
+}
+
+impl Montage {
+    /// The default montage the collector has always assumed; channels past
+    /// the annotated eight take the remaining positions in order
+    pub fn default_for(num_channels: usize) -> Self {
+        let annotated = ANNOTATIONS.iter().map(|(label, _)| *label);
+        let others = POSITIONS
+            .iter()
+            .copied()
+            .filter(|p| *p != "Fpz" && !ANNOTATIONS.iter().any(|(label, _)| label == p));
+        Self {
+            name: None,
+            channels: annotated
+                .chain(others)
+                .take(num_channels)
+                .enumerate()
+                .map(|(i, label)| ChannelAssignment {
+                    channel: i + 1,
+                    label: label.to_string(),
+                    name: None,
+                    verified: false,
+                })
+                .collect(),
+            reference: "Cz".to_string(),
+            ground: "Fpz".to_string(),
+            rereference: None,
+            confirmed_at: Utc::now(),
+        }
+    }
+
+    /// Read `montage.json` from a session directory, if the wizard was run
+    pub fn load(session_dir: &Path) -> Result<Option<Self>> {
+        let path = session_dir.join(MONTAGE_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let text = fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        let montage = serde_json::from_str(&text).with_context(|| format!("Failed to parse {:?}", path))?;
+        Ok(Some(montage))
+    }
+
+    pub fn save(&self, session_dir: &Path) -> Result<()> {
+        fs::create_dir_all(session_dir)?;
+        let path = session_dir.join(MONTAGE_FILE);
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        info!("Saved montage to {:?}", path);
+        Ok(())
+    }
+
+    /// Reject duplicate or non 10-20 labels and duplicate channel names
+    pub fn validate(&self) -> Result<()> {
+        for (i, assignment) in self.channels.iter().enumerate() {
+            if normalize_label(&assignment.label).is_none() {
+                bail!("Channel {} label '{}' is not a 10-20 position", assignment.channel, assignment.label);
+            }
+            if let Some(other) = self.channels[..i].iter().find(|a| a.label == assignment.label) {
+                bail!(
+                    "Channels {} and {} are both labelled {}",
+                    other.channel,
+                    assignment.channel,
+                    assignment.label
+                );
+            }
+        }
+        let names = self.column_names();
+        for (i, name) in names.iter().enumerate() {
+            if name.trim().is_empty() {
+                bail!("Channel {} has an empty name", self.channels[i].channel);
+            }
+            if names[..i].contains(name) {
+                bail!("Channel name {} is used twice", name);
+            }
+        }
+        if let Some(reference) = &self.rereference {
+            Rereference::new(reference, &self.labels()).with_context(|| format!("Cannot re-reference as {}", reference))?;
+        }
+        Ok(())
+    }
+
+    /// This montage on each of `boards` boards wired the same way: channels
+    /// are numbered on and each name gets a `_b<board>` suffix
+    pub fn repeat(&self, boards: usize) -> Self {
+        let names = self.column_names();
+        let per_board = self.channels.len();
+        let channels = (0..boards)
+            .flat_map(|board| {
+                self.channels.iter().zip(&names).map(move |(a, name)| ChannelAssignment {
+                    channel: board * per_board + a.channel,
+                    label: a.label.clone(),
+                    name: Some(format!("{}_b{}", name, board + 1)),
+                    verified: a.verified,
+                })
+            })
+            .collect();
+        Self {
+            channels,
+            ..self.clone()
+        }
+    }
+
+    pub fn labels(&self) -> Vec<String> {
+        self.channels.iter().map(|a| a.label.clone()).collect()
+    }
+
+    /// CSV column names in channel order: the names from the montage file,
+    /// or the annotated labels
+    pub fn column_names(&self) -> Vec<String> {
+        self.channels
+            .iter()
+            .map(|a| a.name.clone().unwrap_or_else(|| column_name(&a.label)))
+            .collect()
+    }
+}
+
+/// RMS of each channel after removing its mean
+pub fn channel_rms(channels: &[Vec<f32>]) -> Vec<f32> {
+    channels
+        .iter()
+        .map(|signal| {
+            if signal.is_empty() {
+                return 0.0;
+            }
+            let mean = signal.iter().sum::<f32>() / signal.len() as f32;
+            (signal.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / signal.len() as f32).sqrt()
+        })
+        .collect()
+}
+
+/// Channel that responded to an electrode tap: the one whose RMS grew the
+/// most over its baseline, provided it grew by `RESPONSE_RATIO` and at
+/// least twice as much as any other channel. Returns the 0-based index and
+/// its ratio.
+pub fn responding_channel(baseline_rms: &[f32], tap_rms: &[f32]) -> Option<(usize, f32)> {
+    let mut ratios: Vec<(usize, f32)> = baseline_rms
+        .iter()
+        .zip(tap_rms)
+        .map(|(base, tap)| tap / base.max(f32::EPSILON))
+        .enumerate()
+        .collect();
+    ratios.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let (best, ratio) = *ratios.first()?;
+    let runner_up = ratios.get(1).map_or(1.0, |r| r.1);
+    (ratio >= RESPONSE_RATIO && ratio >= 2.0 * runner_up).then_some((best, ratio))
+}
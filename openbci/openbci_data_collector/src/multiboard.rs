@@ -0,0 +1,374 @@
+//! Recording from several WiFi shields at once.
+//!
+//! `--shields` records two or more shields in one trial, each streaming to
+//! its own local port: two Cytons as one 16-channel cap, two Cyton+Daisy
+//! boards as 32 channels, or one board per subject for hyperscanning.
+//! [`MultiTransport`] puts the boards behind a single [`BoardTransport`], so
+//! the collector, signal check and sinks see one board with every channel.
+//!
+//! The first board paces the stream. [`ClockSync`] puts the other boards'
+//! timestamps on its clock, and each of its samples is joined with the
+//! other boards' samples nearest in time; a board with no sample within
+//! half a sample period leaves NaN in its channels for that row. Samples
+//! keep the first board's timestamps, so gap detection sees its clock.
+
+use crate::timesync::{ClockSync, CLOCK_SYNC};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use log::{info, warn};
+use openbci_wifi_client::stream::unix_time;
+use openbci_wifi_client::{
+    timestamp_seconds, BoardTransport, Capabilities, Marker, Sample, SampleFeed, ShieldInfo, StreamEvent,
+    StreamHandle,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Seconds the first board's samples wait for the other boards before
+/// their rows go out with NaN
+const MAX_WAIT: f64 = 1.0;
+
+/// One entry of `--shields`: `HOST[=PORT]`, where `PORT` is the local port
+/// the shield streams to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShieldAddr {
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl FromStr for ShieldAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (host, port) = match s.split_once('=') {
+            Some((host, port)) => {
+                let port = port.trim().parse().with_context(|| format!("Invalid port in '{}'", s))?;
+                (host, Some(port))
+            }
+            None => (s, None),
+        };
+        let host = host.trim();
+        if host.is_empty() {
+            bail!("Expected HOST[=PORT], got '{}'", s);
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for ShieldAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}={}", self.host, port),
+            None => f.write_str(&self.host),
+        }
+    }
+}
+
+/// Which channels of a trial came from which shield, kept in its metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultiBoardInfo {
+    pub boards: Vec<BoardChannels>,
+    /// Every shield's channels went into a trial of its own
+    pub side_by_side: bool,
+    /// Shield this trial holds, 1-based, in side-by-side output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub board: Option<usize>,
+}
+
+/// One shield of a multi-board recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BoardChannels {
+    pub shield: String,
+    pub port: u16,
+    /// First channel in the merged recording, 1-based
+    pub first_channel: usize,
+    pub channels: usize,
+    /// Subject wearing this board, for hyperscanning
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject_id: Option<String>,
+}
+
+/// Several boards with the same channel count and rate, streamed as one
+pub struct MultiTransport {
+    boards: Vec<Box<dyn BoardTransport>>,
+    /// Channels of each board
+    channels: usize,
+    sample_rate: u32,
+}
+
+impl MultiTransport {
+    pub fn new(boards: Vec<Box<dyn BoardTransport>>, channels: usize, sample_rate: u32) -> Result<Self> {
+        if boards.len() < 2 {
+            bail!("A multi-board recording needs at least two boards");
+        }
+        if channels == 0 || sample_rate == 0 {
+            bail!("Every board needs channels and a sample rate");
+        }
+        Ok(Self {
+            boards,
+            channels,
+            sample_rate,
+        })
+    }
+}
+
+#[async_trait]
+impl BoardTransport for MultiTransport {
+    fn describe(&self) -> String {
+        self.boards.iter().map(|b| b.describe()).collect::<Vec<_>>().join(" + ")
+    }
+
+    /// Sent to every board; returns the first board's response, or every
+    /// board's one after another for the `?` register dump, whose channel
+    /// banks then number on like a Daisy's
+    async fn send_command(&self, command: &str) -> Result<String> {
+        let mut responses = Vec::with_capacity(self.boards.len());
+        for board in &self.boards {
+            responses.push(board.send_command(command).await?);
+        }
+        if command == "?" {
+            return Ok(responses.join("\n"));
+        }
+        Ok(responses.into_iter().next().unwrap_or_default())
+    }
+
+    /// The first board's, with the channels of all and the rates all of
+    /// them support
+    async fn capabilities(&self, firmware: Option<&str>) -> Result<Capabilities> {
+        let mut caps = self.boards[0].capabilities(firmware).await?;
+        for board in &self.boards[1..] {
+            let other = board.capabilities(firmware).await?;
+            caps.num_channels = caps.num_channels.saturating_add(other.num_channels);
+            caps.sample_rates.retain(|rate| other.sample_rates.contains(rate));
+        }
+        Ok(caps)
+    }
+
+    /// Every board's gains in channel order, if all of them know theirs
+    async fn scaling_gains(&self) -> Result<Option<Vec<u8>>> {
+        let mut gains = Vec::new();
+        for board in &self.boards {
+            let Some(board_gains) = board.scaling_gains().await? else {
+                return Ok(None);
+            };
+            gains.extend(board_gains.into_iter().take(self.channels));
+        }
+        Ok(Some(gains))
+    }
+
+    /// Every board's shields, in board order
+    async fn shield_info(&self) -> Result<Vec<ShieldInfo>> {
+        let mut shields = Vec::new();
+        for board in &self.boards {
+            shields.extend(board.shield_info().await?);
+        }
+        Ok(shields)
+    }
+
+    async fn open_stream(&self) -> Result<StreamHandle> {
+        let mut streams = Vec::with_capacity(self.boards.len());
+        for board in &self.boards {
+            // Handles opened so far stop their boards when dropped on error
+            streams.push(board.open_stream().await?);
+        }
+        info!("Streaming {} boards as one: {}", streams.len(), self.describe());
+        let aligner = Aligner::new(streams.len(), self.channels, self.sample_rate);
+        Ok(StreamHandle::spawn("multi", move |feed| merge(streams, aligner, feed)))
+    }
+
+    async fn stop_stream(&self) -> Result<()> {
+        let mut result = Ok(());
+        for board in &self.boards {
+            if let Err(e) = board.stop_stream().await {
+                warn!("Failed to stop {}: {}", board.describe(), e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+/// Reader tasks of the boards' streams, stopped with the merged stream
+struct Readers(Vec<JoinHandle<()>>);
+
+impl Drop for Readers {
+    fn drop(&mut self) {
+        for reader in &self.0 {
+            reader.abort();
+        }
+    }
+}
+
+/// Forward every board's events into one aligned stream
+async fn merge(streams: Vec<StreamHandle>, mut aligner: Aligner, feed: SampleFeed) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let _readers = Readers(
+        streams
+            .into_iter()
+            .enumerate()
+            .map(|(board, mut stream)| {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    while let Some(event) = stream.recv().await {
+                        if tx.send((board, event)).is_err() {
+                            return;
+                        }
+                    }
+                    warn!("Stream of board {} ended", board + 1);
+                })
+            })
+            .collect(),
+    );
+    drop(tx);
+
+    'stream: while let Some((board, event)) = rx.recv().await {
+        let sample = match event {
+            StreamEvent::Sample(sample) => sample,
+            // Stream restarts and the like, in arrival order
+            StreamEvent::Marker(marker) => {
+                if !feed.send_marker(marker) {
+                    break 'stream;
+                }
+                continue;
+            }
+        };
+        if let Some(marker) = aligner.push(board, sample, unix_time()) {
+            info!("{}", marker.label);
+            if !feed.send_marker(marker) {
+                break 'stream;
+            }
+        }
+        for row in aligner.rows() {
+            if !feed.send(row) {
+                break 'stream;
+            }
+        }
+    }
+}
+
+/// A board sample waiting for its row: board time (s), data, raw timestamp
+type Pending = (f64, Vec<f32>, f64);
+
+/// Joins the boards' samples into rows on the first board's clock
+struct Aligner {
+    channels: usize,
+    interval: f64,
+    /// First-board samples that wait for the others at most
+    max_wait: usize,
+    syncs: Vec<ClockSync>,
+    queues: Vec<VecDeque<Pending>>,
+    /// Offset last announced with a marker, per board
+    announced: Vec<Option<f64>>,
+    /// Samples that matched no row, and rows left NaN, per board
+    unmatched: Vec<u64>,
+    missing: Vec<u64>,
+}
+
+impl Aligner {
+    fn new(boards: usize, channels: usize, sample_rate: u32) -> Self {
+        Self {
+            channels,
+            interval: 1.0 / sample_rate as f64,
+            max_wait: ((MAX_WAIT * sample_rate as f64) as usize).max(1),
+            syncs: vec![ClockSync::new(); boards],
+            queues: vec![VecDeque::new(); boards],
+            announced: vec![None; boards],
+            unmatched: vec![0; boards],
+            missing: vec![0; boards],
+        }
+    }
+
+    /// Queue a board's sample; returns a [`CLOCK_SYNC`] marker when the
+    /// board's offset is first known or has moved by a quarter sample
+    fn push(&mut self, board: usize, sample: Sample, host_time: f64) -> Option<Marker> {
+        let sync = &mut self.syncs[board];
+        sync.observe(sample.timestamp, host_time);
+        let Sample { mut data, timestamp } = sample;
+        if data.len() != self.channels {
+            warn!("Board {} sent {} channels, expected {}", board + 1, data.len(), self.channels);
+            data.resize(self.channels, f32::NAN);
+        }
+        let queue = &mut self.queues[board];
+        queue.push_back((timestamp_seconds(timestamp), data, timestamp));
+        // A stalled first board must not let the others pile up
+        if queue.len() > 4 * self.max_wait {
+            queue.pop_front();
+            self.unmatched[board] += 1;
+        }
+
+        let offset = sync.offset().filter(|_| sync.settled())?;
+        let moved = self.announced[board].is_none_or(|last| (offset - last).abs() > self.interval / 4.0);
+        if !moved {
+            return None;
+        }
+        self.announced[board] = Some(offset);
+        Some(Marker::now(format!("{}{}={:+.1}", CLOCK_SYNC, board + 1, offset * 1e3)))
+    }
+
+    /// Seconds from a board's clock to the first board's
+    fn shift(&self, board: usize) -> f64 {
+        let offset = |b: usize| self.syncs[b].offset().unwrap_or_default();
+        offset(board) - offset(0)
+    }
+
+    /// Rows that can be completed now, in order
+    fn rows(&mut self) -> Vec<Sample> {
+        let half = self.interval / 2.0;
+        let shifts: Vec<f64> = (0..self.queues.len()).map(|b| self.shift(b)).collect();
+        let mut rows = Vec::new();
+        while let Some(&(time, ..)) = self.queues[0].front() {
+            // Each other board needs a sample past this row to tell whether
+            // one belongs in it, unless the row has waited long enough
+            let waiting = self.queues[0].len() <= self.max_wait;
+            let mut ready = true;
+            for ((queue, shift), unmatched) in self.queues.iter_mut().zip(&shifts).zip(&mut self.unmatched).skip(1) {
+                while queue.front().is_some_and(|s| s.0 + shift < time - half) {
+                    queue.pop_front();
+                    *unmatched += 1;
+                }
+                ready &= !(queue.is_empty() && waiting);
+            }
+            if !ready {
+                break;
+            }
+
+            let Some((_, mut data, timestamp)) = self.queues[0].pop_front() else {
+                break;
+            };
+            for ((queue, shift), missing) in self.queues.iter_mut().zip(&shifts).zip(&mut self.missing).skip(1) {
+                match queue.front() {
+                    Some(s) if s.0 + shift <= time + half => {
+                        data.extend(queue.pop_front().map(|s| s.1).unwrap_or_default());
+                    }
+                    _ => {
+                        data.extend(std::iter::repeat_n(f32::NAN, self.channels));
+                        *missing += 1;
+                    }
+                }
+            }
+            rows.push(Sample { data, timestamp });
+        }
+        rows
+    }
+}
+
+impl Drop for Aligner {
+    /// The merged stream ends by being dropped
+    fn drop(&mut self) {
+        for board in 1..self.queues.len() {
+            info!(
+                "Board {}: {} rows without its sample (NaN), {} samples without a row",
+                board + 1,
+                self.missing[board],
+                self.unmatched[board]
+            );
+        }
+    }
+}
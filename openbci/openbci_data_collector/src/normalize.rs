@@ -0,0 +1,284 @@
+//! Per-channel normalization statistics of a training set.
+//!
+//! `openbci dataset stats` reads the trials of a dataset manifest that a
+//! split file assigns to training and stores each channel's mean and
+//! standard deviation (or median and interquartile range) as
+//! `<name>_normalization.json`, one per fold, next to the manifest.
+//! `epoch --normalize` applies them when exporting, and online inference
+//! loads the same file with [`Normalization::load`] so it scales samples
+//! exactly like training did. Statistics are in the recorded unit,
+//! nanovolts.
+
+use crate::compute::ComputeArgs;
+use crate::dataset;
+use crate::recording::Recording;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::Args;
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Options of `openbci dataset stats`
+#[derive(Args, Debug, Clone)]
+pub struct StatsArgs {
+    /// Root of the recorded dataset
+    #[arg(short, long, default_value = "motor_imagery_data")]
+    pub data_dir: PathBuf,
+
+    /// Manifest CSV of a built dataset
+    #[arg(short, long, default_value = "motor_imagery_dataset_manifest.csv")]
+    pub manifest: PathBuf,
+
+    /// Split file whose training trials the statistics come from (every
+    /// trial of the manifest if omitted)
+    #[arg(long)]
+    pub split_file: Option<PathBuf>,
+
+    /// Column of --split-file to use, e.g. fold_S01 for a
+    /// leave-one-subject-out fold
+    #[arg(long, default_value = "split")]
+    pub fold: String,
+
+    /// Statistics: zscore (mean and standard deviation) or robust (median
+    /// and interquartile range)
+    #[arg(long, default_value = "zscore")]
+    pub method: NormMethod,
+
+    #[command(flatten)]
+    pub compute: ComputeArgs,
+}
+
+/// How samples are centered and scaled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NormMethod {
+    /// Mean and standard deviation
+    Zscore,
+    /// Median and interquartile range, less pulled by artifacts
+    Robust,
+}
+
+impl FromStr for NormMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "zscore" | "z-score" | "standard" => Ok(Self::Zscore),
+            "robust" | "median-iqr" => Ok(Self::Robust),
+            _ => bail!("Unknown normalization '{}', expected zscore or robust", s),
+        }
+    }
+}
+
+impl fmt::Display for NormMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Zscore => "zscore",
+            Self::Robust => "robust",
+        })
+    }
+}
+
+/// Contents of `<name>_normalization.json`; a sample `x` of channel `i` is
+/// normalized as `(x - center[i]) / scale[i]`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Normalization {
+    pub method: NormMethod,
+    pub channels: Vec<String>,
+    /// Mean or median per channel
+    pub center: Vec<f64>,
+    /// Standard deviation or interquartile range per channel, 1 for flat
+    /// channels
+    pub scale: Vec<f64>,
+    /// Trials and samples the statistics were computed from
+    pub trials: usize,
+    pub samples: u64,
+    /// Manifest, and split file and column, they were computed from
+    pub manifest: PathBuf,
+    pub split_file: Option<PathBuf>,
+    pub fold: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+impl Normalization {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read normalization {:?}", path))?;
+        serde_json::from_str(&text).with_context(|| format!("Failed to parse normalization {:?}", path))
+    }
+
+    /// `(center, scale)` in the order of `channels`, which must all be known
+    pub fn for_channels(&self, channels: &[String]) -> Result<(Vec<f64>, Vec<f64>)> {
+        let mut center = Vec::with_capacity(channels.len());
+        let mut scale = Vec::with_capacity(channels.len());
+        for name in channels {
+            let i = self
+                .channels
+                .iter()
+                .position(|c| c == name)
+                .with_context(|| format!("Normalization has no statistics for channel {}", name))?;
+            center.push(self.center[i]);
+            scale.push(self.scale[i]);
+        }
+        Ok((center, scale))
+    }
+
+    /// Normalize one sample row in the order of [`Self::channels`]
+    pub fn apply(&self, row: &mut [f32]) {
+        for ((value, center), scale) in row.iter_mut().zip(&self.center).zip(&self.scale) {
+            *value = ((*value as f64 - center) / scale) as f32;
+        }
+    }
+}
+
+/// Where the statistics of `manifest` go, `<name>_normalization.json`, or
+/// `<name>_normalization_<fold>.json` for a fold other than `split`
+pub fn normalization_path(manifest: &Path, fold: Option<&str>) -> PathBuf {
+    let stem = manifest.file_stem().unwrap_or_default().to_string_lossy();
+    let name = stem.strip_suffix("_manifest").unwrap_or(&stem);
+    match fold {
+        Some(fold) if fold != "split" => manifest.with_file_name(format!("{}_normalization_{}.json", name, fold)),
+        _ => manifest.with_file_name(format!("{}_normalization.json", name)),
+    }
+}
+
+/// Running sums of one channel, or every sample for the robust statistics
+#[derive(Default)]
+struct Accumulator {
+    n: u64,
+    mean: f64,
+    m2: f64,
+    values: Vec<f32>,
+}
+
+impl Accumulator {
+    fn push(&mut self, x: f32, keep: bool) {
+        // Welford's update keeps the variance exact over millions of samples
+        self.n += 1;
+        let delta = x as f64 - self.mean;
+        self.mean += delta / self.n as f64;
+        self.m2 += delta * (x as f64 - self.mean);
+        if keep {
+            self.values.push(x);
+        }
+    }
+
+    fn finish(mut self, method: NormMethod) -> (f64, f64) {
+        match method {
+            NormMethod::Zscore => (self.mean, (self.m2 / self.n.saturating_sub(1).max(1) as f64).sqrt()),
+            NormMethod::Robust => {
+                let mut quantile = |q: f64| {
+                    let k = ((self.values.len() - 1) as f64 * q).round() as usize;
+                    *self.values.select_nth_unstable_by(k, f32::total_cmp).1 as f64
+                };
+                let median = quantile(0.5);
+                (median, quantile(0.75) - quantile(0.25))
+            }
+        }
+    }
+}
+
+/// Compute the statistics of the training trials and write them next to
+/// the manifest; returns them
+pub fn stats(args: &StatsArgs) -> Result<Normalization> {
+    let compute = args.compute.apply()?;
+    let mut entries = dataset::load_manifest(&args.manifest)?;
+    if let Some(split_file) = &args.split_file {
+        let train = dataset::split_sources(split_file, &args.fold, "train")?;
+        entries.retain(|e| train.contains(&e.source));
+    } else {
+        warn!("No --split-file, so the statistics include validation and test trials");
+    }
+    if entries.is_empty() {
+        bail!("No training trials in {:?}", args.manifest);
+    }
+
+    // The first trial fixes the channels; trials without one of them are
+    // skipped
+    let mut channels: Vec<String> = Vec::new();
+    let mut accumulators: Vec<Accumulator> = Vec::new();
+    let (mut trials, mut skipped) = (0, 0);
+    let paths: Vec<PathBuf> = entries.iter().map(|e| args.data_dir.join(&e.source)).collect();
+    for batch in paths.chunks(compute.threads) {
+        let loaded: Vec<_> = batch.par_iter().map(|path| Recording::load(path)).collect();
+        for (path, rec) in batch.iter().zip(loaded) {
+            let rec = match rec {
+                Ok(rec) => rec,
+                Err(e) => {
+                    warn!("Skipping {:?}: {:#}", path, e);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            if channels.is_empty() {
+                channels = rec.channel_names.clone();
+                accumulators = channels.iter().map(|_| Accumulator::default()).collect();
+            }
+            let Some(columns) = channels
+                .iter()
+                .map(|name| rec.channel_names.iter().position(|c| c == name))
+                .collect::<Option<Vec<usize>>>()
+            else {
+                warn!("Skipping {:?}: channels differ from the first trial", path);
+                skipped += 1;
+                continue;
+            };
+            for row in &rec.samples {
+                for (acc, &column) in accumulators.iter_mut().zip(&columns) {
+                    // Filled gaps are NaN
+                    if row[column].is_finite() {
+                        acc.push(row[column], args.method == NormMethod::Robust);
+                    }
+                }
+            }
+            trials += 1;
+        }
+    }
+    let samples = accumulators.iter().map(|a| a.n).max().unwrap_or_default();
+    if samples == 0 {
+        bail!("No samples in the training trials of {:?}", args.manifest);
+    }
+
+    let (mut center, mut scale) = (Vec::new(), Vec::new());
+    for (name, acc) in channels.iter().zip(accumulators) {
+        let (c, s) = if acc.n == 0 { (0.0, 1.0) } else { acc.finish(args.method) };
+        if s <= 0.0 || !s.is_finite() {
+            warn!("Channel {} is flat in the training trials, its scale is set to 1", name);
+        }
+        center.push(c);
+        scale.push(if s > 0.0 && s.is_finite() { s } else { 1.0 });
+    }
+    let normalization = Normalization {
+        method: args.method,
+        channels,
+        center,
+        scale,
+        trials,
+        samples,
+        manifest: args.manifest.clone(),
+        split_file: args.split_file.clone(),
+        fold: args.split_file.as_ref().map(|_| args.fold.clone()),
+        created: Utc::now(),
+    };
+
+    let path = normalization_path(&args.manifest, normalization.fold.as_deref());
+    fs::write(&path, serde_json::to_string_pretty(&normalization)?)
+        .with_context(|| format!("Failed to write {:?}", path))?;
+    info!(
+        "{} statistics of {} channels from {} trials ({} skipped) in {:?}",
+        normalization.method,
+        normalization.channels.len(),
+        trials,
+        skipped,
+        path
+    );
+    for ((name, c), s) in normalization.channels.iter().zip(&normalization.center).zip(&normalization.scale) {
+        info!("  {}: center {:.1}, scale {:.1}", name, c, s);
+    }
+    Ok(normalization)
+}
@@ -0,0 +1,390 @@
+//! NumPy `.npz` trial files.
+//!
+//! An `.npz` is a zip archive of `.npy` arrays. Trials are written as
+//! uncompressed (stored) entries, exactly like `np.savez`, or deflated like
+//! `np.savez_compressed` with `--compress`:
+//!
+//! - `X`: float32 (float64 is read too), shape `[samples, channels]`, nanovolts
+//! - `y`: int64 scalar, the class ID
+//! - `meta`: unicode scalar holding the trial metadata JSON
+//!
+//! so `np.load(path)` reads them without pickle. The reader accepts the
+//! same layout, including files re-saved with `np.savez` or
+//! `np.savez_compressed`.
+
+use anyhow::{bail, Context, Result};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::borrow::Cow;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+/// Zip "version needed": 2.0, stored or deflated entries
+const ZIP_VERSION: u16 = 20;
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// `.npy` version 1.0 header, padded so the data starts 64-byte aligned
+fn npy_header(descr: &str, shape: &[usize]) -> Vec<u8> {
+    let shape = match shape {
+        [] => "()".to_string(),
+        [n] => format!("({},)", n),
+        dims => format!("({})", dims.iter().map(|d| d.to_string()).collect::<Vec<_>>().join(", ")),
+    };
+    let mut dict = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", descr, shape);
+    let unpadded = NPY_MAGIC.len() + 2 + 2 + dict.len() + 1;
+    dict.extend(std::iter::repeat_n(' ', unpadded.next_multiple_of(64) - unpadded));
+    dict.push('\n');
+
+    let mut header = NPY_MAGIC.to_vec();
+    header.extend([1, 0]);
+    header.extend((dict.len() as u16).to_le_bytes());
+    header.extend(dict.as_bytes());
+    header
+}
+
+/// Row-major float32 matrix
+pub fn npy_f32(rows: usize, cols: usize, values: &[f32]) -> Vec<u8> {
+    npy_f32_nd(&[rows, cols], values)
+}
+
+/// C-order float32 array of any shape
+pub fn npy_f32_nd(shape: &[usize], values: &[f32]) -> Vec<u8> {
+    let mut npy = npy_header("<f4", shape);
+    npy.reserve(values.len() * 4);
+    for value in values {
+        npy.extend(value.to_le_bytes());
+    }
+    npy
+}
+
+/// One-dimensional int64 array
+pub fn npy_i64(values: &[i64]) -> Vec<u8> {
+    let mut npy = npy_header("<i8", &[values.len()]);
+    npy.reserve(values.len() * 8);
+    for value in values {
+        npy.extend(value.to_le_bytes());
+    }
+    npy
+}
+
+pub fn npy_i64_scalar(value: i64) -> Vec<u8> {
+    let mut npy = npy_header("<i8", &[]);
+    npy.extend(value.to_le_bytes());
+    npy
+}
+
+/// Unicode scalar (`<U`, UTF-32), as NumPy stores `np.array(str)`
+pub fn npy_str_scalar(value: &str) -> Vec<u8> {
+    let chars: Vec<char> = value.chars().collect();
+    let mut npy = npy_header(&format!("<U{}", chars.len().max(1)), &[]);
+    for c in &chars {
+        npy.extend((*c as u32).to_le_bytes());
+    }
+    if chars.is_empty() {
+        npy.extend([0; 4]);
+    }
+    npy
+}
+
+/// Write `(name, npy bytes)` entries as a zip, deflated if `compressed`;
+/// names get the `.npy` suffix NumPy expects
+pub fn write_npz(path: &Path, arrays: &[(&str, Vec<u8>)], compressed: bool) -> Result<()> {
+    let method = if compressed { DEFLATED } else { STORED };
+    let mut out = Vec::new();
+    let mut central = Vec::new();
+    for (name, data) in arrays {
+        let name = format!("{}.npy", name);
+        let offset = out.len();
+        if offset > u32::MAX as usize || data.len() > u32::MAX as usize {
+            bail!("{:?} is too large for a zip without zip64", path);
+        }
+        let crc = crc32(data);
+        let stored = if compressed {
+            let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            Cow::Owned(encoder.finish()?)
+        } else {
+            Cow::Borrowed(data)
+        };
+
+        // Local header; no timestamp (1980-01-01), as the trial metadata
+        // carries the real one
+        out.extend(LOCAL_HEADER.to_le_bytes());
+        for field in [ZIP_VERSION, 0, method, 0, 0x21] {
+            out.extend(field.to_le_bytes());
+        }
+        out.extend(crc.to_le_bytes());
+        out.extend((stored.len() as u32).to_le_bytes());
+        out.extend((data.len() as u32).to_le_bytes());
+        out.extend((name.len() as u16).to_le_bytes());
+        out.extend(0u16.to_le_bytes());
+        out.extend(name.as_bytes());
+        out.extend(stored.as_ref());
+
+        central.extend(CENTRAL_HEADER.to_le_bytes());
+        for field in [ZIP_VERSION, ZIP_VERSION, 0, method, 0, 0x21] {
+            central.extend(field.to_le_bytes());
+        }
+        central.extend(crc.to_le_bytes());
+        central.extend((stored.len() as u32).to_le_bytes());
+        central.extend((data.len() as u32).to_le_bytes());
+        central.extend((name.len() as u16).to_le_bytes());
+        // Extra and comment length, disk number, internal attributes
+        central.extend([0u8; 8]);
+        // External attributes
+        central.extend(0u32.to_le_bytes());
+        central.extend((offset as u32).to_le_bytes());
+        central.extend(name.as_bytes());
+    }
+
+    let central_offset = out.len();
+    out.extend(&central);
+    out.extend(END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+    out.extend([0u8; 4]);
+    out.extend((arrays.len() as u16).to_le_bytes());
+    out.extend((arrays.len() as u16).to_le_bytes());
+    out.extend((central.len() as u32).to_le_bytes());
+    out.extend((central_offset as u32).to_le_bytes());
+    out.extend(0u16.to_le_bytes());
+
+    fs::write(path, out).with_context(|| format!("Failed to write {:?}", path))
+}
+
+/// One parsed `.npy` array
+struct NpyArray<'a> {
+    descr: String,
+    shape: Vec<usize>,
+    data: &'a [u8],
+}
+
+fn parse_npy(bytes: &[u8]) -> Result<NpyArray<'_>> {
+    if !bytes.starts_with(NPY_MAGIC) || bytes.len() < 10 {
+        bail!("not an .npy array");
+    }
+    let (header_len, start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (u32::from_le_bytes(bytes[8..12].try_into()?) as usize, 12),
+        version => bail!("unsupported .npy version {}", version),
+    };
+    let header = std::str::from_utf8(bytes.get(start..start + header_len).context("truncated .npy header")?)?;
+
+    let value_of = |key: &str| -> Result<&str> {
+        let at = header.find(&format!("'{}':", key)).with_context(|| format!("no '{}' in .npy header", key))?;
+        Ok(header[at + key.len() + 3..].trim_start())
+    };
+    if value_of("fortran_order")?.starts_with("True") {
+        bail!("Fortran-ordered arrays are not supported");
+    }
+    let descr = value_of("descr")?
+        .trim_start_matches('\'')
+        .split('\'')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let shape_text = value_of("shape")?;
+    let shape = shape_text[1..shape_text.find(')').context("bad .npy shape")?]
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse().context("bad .npy shape"))
+        .collect::<Result<Vec<usize>>>()?;
+
+    Ok(NpyArray {
+        descr,
+        shape,
+        data: &bytes[start + header_len..],
+    })
+}
+
+/// Stored or deflated entries of a zip, by name. Walks the local headers,
+/// which is enough for archives written in one pass like NumPy's.
+fn zip_entries(bytes: &[u8]) -> Result<Vec<(String, Cow<'_, [u8]>)>> {
+    let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]) as usize;
+    let u32_at = |at: usize| u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]) as usize;
+
+    let mut entries = Vec::new();
+    let mut at = 0;
+    while at + 30 <= bytes.len() && u32_at(at) == LOCAL_HEADER as usize {
+        let flags = u16_at(at + 6);
+        let method = u16_at(at + 8);
+        let mut size = u32_at(at + 18);
+        let name_len = u16_at(at + 26);
+        let extra_len = u16_at(at + 28);
+        let name = String::from_utf8_lossy(bytes.get(at + 30..at + 30 + name_len).context("truncated zip")?);
+        if bytes.len() < at + 30 + name_len + extra_len {
+            bail!("truncated zip");
+        }
+        // np.savez forces zip64: the sizes then live in the extra field
+        if size == 0xffff_ffff {
+            let mut field = at + 30 + name_len;
+            let end = field + extra_len;
+            while field + 4 <= end {
+                let (id, len) = (u16_at(field), u16_at(field + 2));
+                if id == 1 && len >= 16 {
+                    size = u64::from_le_bytes(bytes[field + 12..field + 20].try_into()?) as usize;
+                    break;
+                }
+                field += 4 + len;
+            }
+        }
+        if flags & 0x08 != 0 {
+            bail!("{} uses a zip data descriptor, which is not supported", name);
+        }
+        let start = at + 30 + name_len + extra_len;
+        let data = bytes.get(start..start + size).context("truncated zip entry")?;
+        let data = match method as u16 {
+            STORED => Cow::Borrowed(data),
+            DEFLATED => {
+                let mut inflated = Vec::new();
+                DeflateDecoder::new(data)
+                    .read_to_end(&mut inflated)
+                    .with_context(|| format!("Failed to inflate {}", name))?;
+                Cow::Owned(inflated)
+            }
+            method => bail!("{} uses zip compression method {}, only stored and deflated are supported", name, method),
+        };
+        entries.push((name.into_owned(), data));
+        at = start + size;
+    }
+    Ok(entries)
+}
+
+/// Contents of a trial `.npz`
+#[derive(Debug)]
+pub struct NpzTrial {
+    /// Row-major samples in nanovolts, `samples[i][channel]`
+    pub samples: Vec<Vec<f32>>,
+    pub class_id: i64,
+    /// Trial metadata JSON
+    pub meta: String,
+}
+
+impl NpzTrial {
+    pub fn read(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let entries = zip_entries(&bytes).with_context(|| format!("Failed to parse {:?}", path))?;
+        let array = |name: &str| -> Result<NpyArray<'_>> {
+            let (_, data) = entries
+                .iter()
+                .find(|(n, _)| n == &format!("{}.npy", name))
+                .with_context(|| format!("{:?} has no '{}' array", path, name))?;
+            parse_npy(data).with_context(|| format!("Failed to parse '{}' in {:?}", name, path))
+        };
+
+        let x = array("X")?;
+        let [rows, cols] = x.shape[..] else {
+            bail!("'X' in {:?} must be 2-D, got shape {:?}", path, x.shape);
+        };
+        let width = match x.descr.as_str() {
+            "<f4" => 4,
+            "<f8" => 8,
+            descr => bail!("'X' in {:?} must be float32 or float64, got {}", path, descr),
+        };
+        if x.data.len() < rows * cols * width {
+            bail!("'X' in {:?} is truncated", path);
+        }
+        let samples = x.data[..rows * cols * width]
+            .chunks_exact(cols.max(1) * width)
+            .map(|row| {
+                row.chunks_exact(width)
+                    .map(|b| match *b {
+                        [b0, b1, b2, b3] => f32::from_le_bytes([b0, b1, b2, b3]),
+                        [b0, b1, b2, b3, b4, b5, b6, b7] => f64::from_le_bytes([b0, b1, b2, b3, b4, b5, b6, b7]) as f32,
+                        _ => unreachable!("samples are {} bytes", width),
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let y = array("y")?;
+        let class_id = match (y.descr.as_str(), y.data) {
+            ("<i8", [b0, b1, b2, b3, b4, b5, b6, b7, ..]) => i64::from_le_bytes([*b0, *b1, *b2, *b3, *b4, *b5, *b6, *b7]),
+            ("<i4", [b0, b1, b2, b3, ..]) => i32::from_le_bytes([*b0, *b1, *b2, *b3]) as i64,
+            ("|u1", [b, ..]) => *b as i64,
+            ("|i1", [b, ..]) => *b as i8 as i64,
+            (descr, _) => bail!("'y' in {:?} has unsupported type {}", path, descr),
+        };
+
+        let meta = array("meta")?;
+        if !meta.descr.starts_with("<U") {
+            bail!("'meta' in {:?} must be a unicode string, got {}", path, meta.descr);
+        }
+        let meta = meta
+            .data
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .take_while(|&c| c != 0)
+            .filter_map(char::from_u32)
+            .collect();
+
+        Ok(Self { samples, class_id, meta })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("openbci_npz_{}_{}.npz", std::process::id(), name))
+    }
+
+    fn npy(descr: &str, shape: &[usize], data: &[u8]) -> Vec<u8> {
+        [npy_header(descr, shape), data.to_vec()].concat()
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn round_trips_float32_trials() {
+        for compressed in [false, true] {
+            let path = temp_path(&format!("f32_{}", compressed));
+            let values = [1.5f32, -2.25, 1e6, f32::MIN_POSITIVE, 0.0, -7.0];
+            let arrays = [("X", npy_f32(3, 2, &values)), ("y", npy_i64_scalar(-3)), ("meta", npy_str_scalar("{\"µ\":1}"))];
+            write_npz(&path, &arrays, compressed).unwrap();
+
+            let trial = NpzTrial::read(&path).unwrap();
+            assert_eq!(trial.samples, [[1.5, -2.25], [1e6, f32::MIN_POSITIVE], [0.0, -7.0]]);
+            assert_eq!(trial.class_id, -3);
+            assert_eq!(trial.meta, "{\"µ\":1}");
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_float64_samples_and_byte_labels() {
+        let x: Vec<u8> = [0.5f64, -1e3].iter().flat_map(|v| v.to_le_bytes()).collect();
+        for (descr, byte, class_id) in [("|i1", 0xFF, -1), ("|u1", 0xFF, 255), ("|i1", 0x05, 5)] {
+            let path = temp_path(&format!("bytes_{}", class_id));
+            let arrays = [("X", npy("<f8", &[1, 2], &x)), ("y", npy(descr, &[], &[byte])), ("meta", npy_str_scalar(""))];
+            write_npz(&path, &arrays, false).unwrap();
+
+            let trial = NpzTrial::read(&path).unwrap();
+            assert_eq!(trial.samples, [[0.5, -1e3]]);
+            assert_eq!(trial.class_id, class_id, "{}", descr);
+            assert_eq!(trial.meta, "");
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
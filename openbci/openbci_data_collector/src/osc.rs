@@ -0,0 +1,192 @@
+//! Open Sound Control output, for `--osc`.
+//!
+//! Sends to a UDP host and port in OSC 1.0, either every sample, one
+//! bundle per band power estimate or, from the online classifier, one
+//! message per prediction. Estimates and predictions carry the
+//! `[right, left]` float pair the robot's ESP32 firmware reads from
+//! `/neuropype`, so the collector can drive it without NeuroPype in
+//! between; estimate bundles add the powers themselves on
+//! `<address>/band_power`. Samples go to `<address>/eeg` (float32
+//! microvolts, one message per sample, bundled 25 times a second), which
+//! the firmware ignores rather than taking channel values for scores.
+//!
+//! The band power pair is a lateralization index, not a trained
+//! classifier: imagining a hand's movement lowers mu power over the
+//! opposite hemisphere, so `right = c4 / (c3 + c4)` with each channel's mu
+//! power relative to its fixation reference (raw power before there is one)
+//! and `left = 1 - right`. Both stay near 0.5 at rest, below the firmware's
+//! 0.6 threshold.
+
+use crate::bandpower::BandPowers;
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+
+/// Sample bundles per second; keeps each datagram well below a typical MTU
+const PACKETS_PER_SECOND: u32 = 25;
+/// Samples are in nanovolts, OSC messages carry microvolts
+const NV_PER_UV: f32 = 1000.0;
+/// Bundle time tag meaning "immediately"
+const IMMEDIATELY: u64 = 1;
+
+/// Append an OSC string: NUL terminated, padded to four bytes
+fn push_string(packet: &mut Vec<u8>, s: &str) {
+    packet.extend_from_slice(s.as_bytes());
+    packet.extend(std::iter::repeat_n(0, 4 - s.len() % 4));
+}
+
+/// One message of float32 arguments
+fn message(address: &str, args: &[f32]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(address.len() + args.len() * 5 + 8);
+    push_string(&mut packet, address);
+    push_string(&mut packet, &format!(",{}", "f".repeat(args.len())));
+    for arg in args {
+        packet.extend_from_slice(&arg.to_be_bytes());
+    }
+    packet
+}
+
+/// A bundle of messages, to be handled at once
+fn bundle(messages: &[Vec<u8>]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(16 + messages.iter().map(|m| m.len() + 4).sum::<usize>());
+    push_string(&mut packet, "#bundle");
+    packet.extend_from_slice(&IMMEDIATELY.to_be_bytes());
+    for message in messages {
+        packet.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        packet.extend_from_slice(message);
+    }
+    packet
+}
+
+/// Sends packets to the OSC target. Sending never blocks the recording
+/// loop; datagrams the socket cannot take are dropped.
+pub struct OscSender {
+    socket: UdpSocket,
+    target: SocketAddr,
+    address: String,
+    sent: u64,
+    dropped: u64,
+}
+
+impl OscSender {
+    /// Send to `target` at OSC `address`, e.g. `/neuropype`
+    pub fn connect(target: SocketAddr, address: &str) -> Result<Self> {
+        if !address.starts_with('/') {
+            bail!("OSC addresses start with '/', got '{}'", address);
+        }
+        let bind: SocketAddr = if target.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(bind).context("Failed to bind UDP socket for OSC")?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            target,
+            address: address.to_string(),
+            sent: 0,
+            dropped: 0,
+        })
+    }
+
+    fn send(&mut self, packet: &[u8]) {
+        match self.socket.send_to(packet, self.target) {
+            Ok(_) => self.sent += 1,
+            Err(e) => {
+                if e.kind() != ErrorKind::WouldBlock {
+                    // Nobody listening yet shows up as ConnectionRefused
+                    debug!("OSC send failed: {}", e);
+                }
+                self.dropped += 1;
+            }
+        }
+    }
+
+    /// Send the lateralization scores and powers of one estimate
+    pub fn band_power(&mut self, powers: &BandPowers) {
+        let mut messages = Vec::with_capacity(2);
+        if let Some(right) = lateralization(powers) {
+            messages.push(message(&self.address, &[right as f32, (1.0 - right) as f32]));
+        }
+        let values: Vec<f32> = powers.0.iter().flat_map(|c| [c.mu as f32, c.beta as f32]).collect();
+        messages.push(message(&format!("{}/band_power", self.address), &values));
+        let packet = bundle(&messages);
+        self.send(&packet);
+    }
+
+    /// Send a classifier's right- and left-hand probabilities as the one
+    /// `[right, left]` message the firmware reads
+    pub fn prediction(&mut self, right: f32, left: f32) {
+        let packet = message(&self.address, &[right, left]);
+        self.send(&packet);
+    }
+
+    /// Packets sent and dropped so far
+    pub fn counts(&self) -> (u64, u64) {
+        (self.sent, self.dropped)
+    }
+}
+
+/// Every sample of a trial, bundled
+pub struct OscSamples {
+    sender: OscSender,
+    address: String,
+    batch: usize,
+    samples: Vec<Vec<u8>>,
+}
+
+impl OscSamples {
+    pub fn new(sender: OscSender, sample_rate: u32) -> Self {
+        let batch = (sample_rate / PACKETS_PER_SECOND).max(1) as usize;
+        let address = format!("{}/eeg", sender.address);
+        info!("Sending samples to osc.udp://{}{} ({} per bundle)", sender.target, address, batch);
+        Self {
+            sender,
+            address,
+            batch,
+            samples: Vec::with_capacity(batch),
+        }
+    }
+
+    /// Queue one sample (nanovolts, as recorded) and send a bundle once
+    /// full
+    pub fn push(&mut self, sample: &[f32]) {
+        let uv: Vec<f32> = sample.iter().map(|v| v / NV_PER_UV).collect();
+        self.samples.push(message(&self.address, &uv));
+        if self.samples.len() >= self.batch {
+            self.flush();
+        }
+    }
+
+    /// Send whatever is queued
+    pub fn flush(&mut self) {
+        if self.samples.is_empty() {
+            return;
+        }
+        let packet = bundle(&self.samples);
+        self.sender.send(&packet);
+        self.samples.clear();
+    }
+
+    /// Bundles sent and dropped so far
+    pub fn counts(&self) -> (u64, u64) {
+        self.sender.counts()
+    }
+}
+
+/// Right-hand score in `[0, 1]` from C3 and C4 mu power, `None` without
+/// both
+fn lateralization(powers: &BandPowers) -> Option<f64> {
+    let relative = |label: &str| {
+        let channel = powers.0.iter().find(|c| c.label == label)?;
+        // Relative to the reference once there is one
+        Some(channel.mu_change.map_or(channel.mu, |change| 1.0 + change / 100.0))
+    };
+    let (c3, c4) = (relative("C3")?, relative("C4")?);
+    if c3 + c4 <= 0.0 {
+        return None;
+    }
+    Some(c4 / (c3 + c4))
+}
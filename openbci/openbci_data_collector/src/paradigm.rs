@@ -0,0 +1,296 @@
+//! Recording paradigms for `--paradigm`.
+//!
+//! A paradigm file declares what a trial can be: the class names and IDs,
+//! how each class is cued and how a trial runs. Without one the collector
+//! records the four motor imagery classes it always has. SSVEP classes
+//! carry the flicker frequency of their target, which the stimulus
+//! (a monitor or LED panel outside the collector) provides; P300 trials add
+//! an oddball sequence of flashes, each marked `stimulus:<name>` so epochs
+//! can be locked to it. An SSVEP paradigm with a `[decoder]` table is also
+//! a model that decodes its targets online (see `ssvep`). See `paradigms/`
+//! for examples.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Marker prefix of a stimulus flash, followed by the stimulus name
+pub const STIMULUS_PREFIX: &str = "stimulus:";
+
+/// Classes and trial structure of one kind of session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Paradigm {
+    /// e.g. `ssvep`, kept in the trial metadata
+    pub name: String,
+    /// BIDS task label of the runs, alphanumeric
+    pub task: String,
+    /// What the subject does, for the BIDS `TaskDescription`
+    pub description: String,
+    pub classes: Vec<ClassDef>,
+    #[serde(default)]
+    pub trial: TrialStructure,
+    /// How SSVEP targets are decoded online
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decoder: Option<DecoderSettings>,
+}
+
+/// One class a trial can be recorded as
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClassDef {
+    pub name: String,
+    /// Label of the class for training
+    pub id: u8,
+    /// Other names `--class` accepts
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    #[serde(default)]
+    pub cue: CueKind,
+    /// Text of a `text` cue, the class name in capitals if omitted
+    #[serde(default)]
+    pub text: Option<String>,
+    /// Flicker frequency of the attended SSVEP target, in Hz
+    #[serde(default)]
+    pub frequency: Option<f64>,
+}
+
+/// How the class is shown at cue onset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CueKind {
+    /// Arrow to the hand to imagine moving, a REST box for rest
+    #[default]
+    Arrow,
+    Text,
+    /// Marked but not drawn, e.g. when the stimulus shows the target
+    None,
+}
+
+/// Defaults of the trial flags, each overridden by the config and the
+/// command line
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrialStructure {
+    /// Seconds per trial, cue delay included
+    pub duration: Option<u64>,
+    pub cue_delay: Option<f64>,
+    /// Seconds between the trials of a session
+    pub rest_seconds: Option<f64>,
+    pub cues: Option<bool>,
+    /// Flashes after the cue, for P300
+    pub stimuli: Option<StimulusSequence>,
+}
+
+/// Oddball sequence: every `interval` seconds one of the items, drawn by
+/// probability, is shown for `flash` seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StimulusSequence {
+    pub interval: f64,
+    pub flash: f64,
+    pub items: Vec<Stimulus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Stimulus {
+    pub name: String,
+    /// What is drawn, the name in capitals if omitted
+    #[serde(default)]
+    pub text: Option<String>,
+    /// Relative weight of the item
+    pub probability: f64,
+}
+
+/// Settings of decoding SSVEP targets by canonical correlation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecoderSettings {
+    /// Montage labels of the channels decoded, occipital for SSVEP
+    pub channels: Vec<String>,
+    /// Seconds of a window
+    pub window: f64,
+    /// Harmonics of each frequency in its references
+    pub harmonics: usize,
+    /// Rate of the stream decoded, in Hz
+    pub sample_rate: u32,
+    /// Softmax temperature turning correlations into probabilities;
+    /// smaller is more decisive
+    pub temperature: f64,
+}
+
+impl Default for DecoderSettings {
+    fn default() -> Self {
+        Self {
+            channels: vec!["O1".to_string(), "Oz".to_string(), "O2".to_string()],
+            window: 2.0,
+            harmonics: 3,
+            sample_rate: 250,
+            temperature: 0.05,
+        }
+    }
+}
+
+/// Paradigm a trial was recorded under, kept in its metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParadigmInfo {
+    pub name: String,
+    pub task: String,
+    /// The `--paradigm` file
+    pub file: String,
+    /// SSVEP target frequency of the trial's class
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_hz: Option<f64>,
+}
+
+impl Default for Paradigm {
+    /// The motor imagery classes the collector has always recorded
+    fn default() -> Self {
+        let class = |name: &str, id, alias: &str| ClassDef {
+            name: name.to_string(),
+            id,
+            aliases: vec![alias.to_string()],
+            cue: CueKind::Arrow,
+            text: None,
+            frequency: None,
+        };
+        Self {
+            name: "motor_imagery".to_string(),
+            task: "motorimagery".to_string(),
+            description: "Cued motor imagery of the class in events.tsv for the whole run".to_string(),
+            classes: vec![
+                class("left_hand", 0, "left"),
+                class("right_hand", 1, "right"),
+                class("both_hands", 2, "both"),
+                class("rest", 3, "baseline"),
+            ],
+            trial: TrialStructure::default(),
+            decoder: None,
+        }
+    }
+}
+
+impl Paradigm {
+    /// Read a `.toml`, `.yaml`, `.yml` or `.json` paradigm file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("Failed to read paradigm {:?}", path))?;
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+        let parse_error = || format!("Failed to parse paradigm {:?}", path);
+        let paradigm: Self = match extension.as_str() {
+            "toml" => toml::from_str(&text).with_context(parse_error)?,
+            "yaml" | "yml" => serde_yaml_ng::from_str(&text).with_context(parse_error)?,
+            "json" => serde_json::from_str(&text).with_context(parse_error)?,
+            _ => bail!("Paradigm {:?} must be .toml, .yaml, .yml or .json", path),
+        };
+        paradigm.validate().with_context(|| format!("Invalid paradigm {:?}", path))?;
+        Ok(paradigm)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.task.is_empty() || !self.task.chars().all(|c| c.is_ascii_alphanumeric()) {
+            bail!("Task '{}' must be alphanumeric for BIDS", self.task);
+        }
+        if self.classes.is_empty() {
+            bail!("No classes");
+        }
+        let mut names: Vec<String> = Vec::new();
+        for (i, class) in self.classes.iter().enumerate() {
+            for name in std::iter::once(&class.name).chain(&class.aliases) {
+                let name = name.to_lowercase();
+                if name.is_empty() || names.contains(&name) {
+                    bail!("Class name '{}' is empty or given twice", name);
+                }
+                names.push(name);
+            }
+            if self.classes[..i].iter().any(|other| other.id == class.id) {
+                bail!("Class ID {} is given twice", class.id);
+            }
+            if class.frequency.is_some_and(|hz| hz <= 0.0) {
+                bail!("Class '{}' needs a positive frequency", class.name);
+            }
+        }
+        if self.decoder.is_some() {
+            if let Some(class) = self.classes.iter().find(|class| class.frequency.is_none()) {
+                bail!("Class '{}' has no frequency to decode", class.name);
+            }
+        }
+        if let Some(stimuli) = &self.trial.stimuli {
+            if !(stimuli.flash > 0.0 && stimuli.flash < stimuli.interval) {
+                bail!("Stimulus flash must be positive and shorter than the {} s interval", stimuli.interval);
+            }
+            if stimuli.items.is_empty() || stimuli.items.iter().any(|item| item.probability <= 0.0) {
+                bail!("Stimuli need items with positive probabilities");
+            }
+        }
+        Ok(())
+    }
+
+    /// The class called `name` or one of its aliases, ignoring case
+    pub fn class(&self, name: &str) -> Option<&ClassDef> {
+        self.classes.iter().find(|class| {
+            class.name.eq_ignore_ascii_case(name) || class.aliases.iter().any(|a| a.eq_ignore_ascii_case(name))
+        })
+    }
+
+    /// Class names in ID order
+    pub fn class_names(&self) -> Vec<&str> {
+        let mut classes: Vec<&ClassDef> = self.classes.iter().collect();
+        classes.sort_by_key(|class| class.id);
+        classes.into_iter().map(|class| class.name.as_str()).collect()
+    }
+
+    /// Has the hand classes the ERD quick-look compares
+    pub fn is_motor_imagery(&self) -> bool {
+        self.class("left_hand").is_some() && self.class("right_hand").is_some()
+    }
+
+    /// `0 left_hand, 1 right_hand, ...` for the BIDS events description
+    pub fn class_ids(&self) -> String {
+        self.class_names()
+            .into_iter()
+            .filter_map(|name| self.class(name))
+            .map(|class| format!("{} {}", class.id, class.name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Name for people, `motor_imagery` as `Motor imagery`
+    pub fn title(&self) -> String {
+        let name = self.name.replace('_', " ");
+        let mut chars = name.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => name,
+        }
+    }
+}
+
+impl ClassDef {
+    /// Text of the cue
+    pub fn cue_text(&self) -> String {
+        self.text.clone().unwrap_or_else(|| self.name.to_uppercase())
+    }
+}
+
+impl StimulusSequence {
+    /// The item a uniform `draw` in [0, 1) falls on, by probability
+    pub fn pick(&self, draw: f64) -> &Stimulus {
+        let total: f64 = self.items.iter().map(|item| item.probability).sum();
+        let mut threshold = draw * total;
+        for item in &self.items {
+            if threshold < item.probability {
+                return item;
+            }
+            threshold -= item.probability;
+        }
+        &self.items[self.items.len() - 1]
+    }
+}
+
+impl Stimulus {
+    pub fn text(&self) -> String {
+        self.text.clone().unwrap_or_else(|| self.name.to_uppercase())
+    }
+}
@@ -0,0 +1,144 @@
+//! Apache Parquet archives of whole datasets.
+//!
+//! One row per sample across every subject, session and trial, with each
+//! EEG channel in its own float32 column and the repetitive trial fields
+//! (subject, session, class, marker) dictionary-encoded, so DuckDB or
+//! Polars can filter and aggregate across subjects without touching the
+//! channels they do not read. Every trial becomes one row group.
+//!
+//! Sessions with different montages share the file: the channel columns
+//! are the union of all channel names, null where a trial lacks one.
+
+use crate::recording::Recording;
+use anyhow::{Context, Result};
+use arrow_array::builder::StringDictionaryBuilder;
+use arrow_array::types::Int32Type;
+use arrow_array::{ArrayRef, Float32Array, Float64Array, RecordBatch, UInt32Array, UInt64Array, UInt8Array};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, Encoding, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use parquet::format::KeyValue;
+use parquet::schema::types::ColumnPath;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+fn dictionary() -> DataType {
+    DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8))
+}
+
+/// Column layout for the given channel names
+pub fn schema(channels: &[String]) -> SchemaRef {
+    let mut fields = vec![
+        Field::new("subject_id", dictionary(), false),
+        Field::new("session_id", dictionary(), false),
+        Field::new("trial_number", DataType::UInt32, false),
+        Field::new("class_id", DataType::UInt8, false),
+        Field::new("class_label", dictionary(), false),
+        Field::new("sample_id", DataType::UInt64, false),
+        Field::new("timestamp", DataType::Float64, false),
+    ];
+    fields.extend(channels.iter().map(|name| Field::new(name, DataType::Float32, true)));
+    fields.push(Field::new("marker", dictionary(), true));
+    Arc::new(Schema::new(fields))
+}
+
+/// Dictionary column repeating one value `len` times
+fn repeated(value: &str, len: usize) -> ArrayRef {
+    let mut builder = StringDictionaryBuilder::<Int32Type>::new();
+    builder.append_values(value, len);
+    Arc::new(builder.finish())
+}
+
+/// Writes trials into one Parquet file
+pub struct ParquetSink {
+    path: PathBuf,
+    writer: ArrowWriter<File>,
+    schema: SchemaRef,
+    channels: Vec<String>,
+    trials: usize,
+    rows: usize,
+}
+
+impl ParquetSink {
+    /// `zstd_level` 1-22; 3 is a good speed/size balance for EEG
+    pub fn create(path: &Path, channels: &[String], zstd_level: i32) -> Result<Self> {
+        let schema = schema(channels);
+        // Float samples barely repeat, so only the leading columns and the
+        // marker use dictionaries; splitting channel floats into byte
+        // streams lets zstd find the slowly changing exponent bytes
+        let mut properties = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::try_new(zstd_level)?))
+            .set_dictionary_enabled(false)
+            .set_key_value_metadata(Some(vec![
+                KeyValue::new("source".to_string(), "openbci_data_collector".to_string()),
+                KeyValue::new("sample_unit".to_string(), "nV".to_string()),
+            ]));
+        for column in ["subject_id", "session_id", "class_label", "marker"] {
+            properties = properties.set_column_dictionary_enabled(ColumnPath::from(column), true);
+        }
+        for column in channels {
+            properties = properties.set_column_encoding(ColumnPath::from(column.as_str()), Encoding::BYTE_STREAM_SPLIT);
+        }
+
+        let file = File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+        let writer = ArrowWriter::try_new(file, Arc::clone(&schema), Some(properties.build()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer,
+            schema,
+            channels: channels.to_vec(),
+            trials: 0,
+            rows: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append one trial as its own row group; returns the rows written
+    pub fn write_trial(&mut self, rec: &Recording) -> Result<usize> {
+        let len = rec.samples.len();
+        let meta = &rec.metadata;
+        let mut columns: Vec<ArrayRef> = vec![
+            repeated(&meta.subject_id, len),
+            repeated(&meta.session_id, len),
+            Arc::new(UInt32Array::from(vec![meta.trial_number; len])),
+            Arc::new(UInt8Array::from(vec![meta.class_id; len])),
+            repeated(&meta.class_label, len),
+            Arc::new(UInt64Array::from_iter_values(0..len as u64)),
+            Arc::new(Float64Array::from(rec.timestamps.clone())),
+        ];
+        for name in &self.channels {
+            let column: Float32Array = match rec.channel_names.iter().position(|c| c == name) {
+                Some(index) => rec.samples.iter().map(|row| row.get(index).copied()).collect(),
+                None => std::iter::repeat_n(None::<f32>, len).collect(),
+            };
+            columns.push(Arc::new(column));
+        }
+        let mut markers = StringDictionaryBuilder::<Int32Type>::new();
+        for marker in &rec.markers {
+            if marker.is_empty() {
+                markers.append_null();
+            } else {
+                markers.append_value(marker);
+            }
+        }
+        columns.push(Arc::new(markers.finish()));
+
+        let batch = RecordBatch::try_new(Arc::clone(&self.schema), columns)?;
+        self.writer.write(&batch)?;
+        self.writer.flush()?;
+        self.trials += 1;
+        self.rows += len;
+        Ok(len)
+    }
+
+    /// Write the footer; returns `(trials, rows)`
+    pub fn finish(self) -> Result<(usize, usize)> {
+        self.writer.close()?;
+        Ok((self.trials, self.rows))
+    }
+}
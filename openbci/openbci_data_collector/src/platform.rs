@@ -0,0 +1,195 @@
+//! Platform defaults and the `check --platform` real-time benchmark.
+
+use crate::metadata::StreamHealth;
+use crate::sink::{CsvSink, DataSink, EEGSample};
+use crate::writer::TrialWriter;
+use eeg_dsp::{motor_imagery_bands, BandPowerExtractor};
+use log::warn;
+use ndarray::Array2;
+use openbci_wifi_client::{Sample, StreamLimits};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::time::Instant;
+
+/// Seconds of synthetic signal each benchmark stage processes
+const BENCH_SECONDS: usize = 10;
+
+/// Logical CPUs available to this process
+pub fn cores() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Tokio worker threads. A Pi has four slow cores shared with the WiFi
+/// driver and the desktop, and the collector only ever runs two busy tasks.
+pub fn default_worker_threads() -> usize {
+    if cfg!(target_arch = "aarch64") {
+        cores().min(2)
+    } else {
+        cores()
+    }
+}
+
+/// Samples buffered before a CSV write: half a second on aarch64 to keep
+/// memory and SD card write bursts small, one second elsewhere
+pub fn write_buffer_capacity(sample_rate: u32) -> usize {
+    let capacity = if cfg!(target_arch = "aarch64") {
+        sample_rate / 2
+    } else {
+        sample_rate
+    };
+    capacity.max(1) as usize
+}
+
+/// Resident memory of this process in bytes, where `/proc` has it
+pub fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Time spent on one pipeline stage for `BENCH_SECONDS` of data
+#[derive(Debug, Clone, Serialize)]
+pub struct StageTiming {
+    pub name: &'static str,
+    pub seconds: f64,
+    /// Multiple of real time this stage can sustain
+    pub headroom: f64,
+}
+
+/// Measured real-time headroom on the current device
+#[derive(Debug, Clone, Serialize)]
+pub struct PlatformReport {
+    pub arch: &'static str,
+    pub os: &'static str,
+    pub cores: usize,
+    pub worker_threads: usize,
+    pub read_buffer_bytes: usize,
+    pub write_buffer_samples: usize,
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub stages: Vec<StageTiming>,
+    /// Headroom of all stages run back to back
+    pub headroom: f64,
+    /// Samples the `trial_write` stage failed to write or dropped
+    pub unwritten_samples: u64,
+}
+
+/// Mirror of the shield's chunk format
+#[derive(Deserialize)]
+struct Chunk {
+    chunk: Vec<Sample>,
+}
+
+impl PlatformReport {
+    /// Run the parsing, feature and CSV stages on synthetic data shaped like
+    /// a `sample_rate` Hz, `channels` channel recording
+    pub fn measure(sample_rate: u32, channels: usize, worker_threads: usize) -> Self {
+        let total = sample_rate as usize * BENCH_SECONDS;
+        let signal: Vec<Vec<f32>> = (0..channels)
+            .map(|ch| {
+                (0..total)
+                    .map(|i| {
+                        let t = i as f32 / sample_rate as f32;
+                        let f = 10.0 + ch as f32;
+                        20_000.0 * (2.0 * std::f32::consts::PI * f * t).sin()
+                    })
+                    .collect()
+            })
+            .collect();
+
+        // One sample per chunk is what the shield sends at 4 ms latency
+        let lines: Vec<String> = (0..total)
+            .map(|i| {
+                let sample = Sample {
+                    data: signal.iter().map(|c| c[i]).collect(),
+                    timestamp: i as f64 / sample_rate as f64,
+                };
+                serde_json::json!({ "chunk": [sample] }).to_string()
+            })
+            .collect();
+
+        let mut stages = Vec::new();
+        let mut time = |name: &'static str, f: &mut dyn FnMut()| {
+            let start = Instant::now();
+            f();
+            let seconds = start.elapsed().as_secs_f64();
+            stages.push(StageTiming {
+                name,
+                seconds,
+                headroom: BENCH_SECONDS as f64 / seconds.max(f64::EPSILON),
+            });
+        };
+
+        time("json_parse", &mut || {
+            for line in &lines {
+                let chunk: Option<Chunk> = serde_json::from_str(line).ok();
+                std::hint::black_box(chunk.map(|c| c.chunk.len()));
+            }
+        });
+
+        let data = Array2::from_shape_fn((channels, total), |(channel, t)| signal[channel][t]);
+        match BandPowerExtractor::new(motor_imagery_bands(), sample_rate as f64, channels, total) {
+            Ok(mut extractor) => {
+                let mut powers = vec![0.0; extractor.dimension()];
+                time("band_power", &mut || {
+                    std::hint::black_box(extractor.extract(data.view(), &mut powers).is_ok());
+                });
+            }
+            Err(e) => warn!("Skipping the band_power stage: {}", e),
+        }
+
+        time("csv_format", &mut || {
+            let mut row = String::with_capacity(16 * (channels + 4));
+            for i in 0..total {
+                row.clear();
+                let _ = write!(row, "{:.6},{}", i as f64 / sample_rate as f64, i);
+                for c in &signal {
+                    let _ = write!(row, ",{:.6}", c[i]);
+                }
+                row.push_str(",0,\n");
+                std::hint::black_box(&row);
+            }
+        });
+
+        // The collector's own write path, to a CSV file in the temp directory
+        let path = std::env::temp_dir().join(format!("openbci_platform_{}.csv", std::process::id()));
+        let labels: Vec<String> = (1..=channels).map(|ch| format!("ch{}", ch)).collect();
+        let mut health = StreamHealth::default();
+        time("trial_write", &mut || {
+            let sink = match CsvSink::new(path.clone(), 0, &labels) {
+                Ok(sink) => Box::new(sink) as Box<dyn DataSink>,
+                Err(e) => {
+                    warn!("Skipping the write benchmark: {}", e);
+                    return;
+                }
+            };
+            let mut writer = TrialWriter::start(vec![sink], sample_rate, write_buffer_capacity(sample_rate));
+            for i in 0..total {
+                writer.push_sample(EEGSample {
+                    timestamp: i as f64 / sample_rate as f64,
+                    sample_id: i as u64,
+                    channels: signal.iter().map(|c| c[i]).collect(),
+                });
+            }
+            // Dropping the sinks flushes what is buffered
+            drop(writer.finish(&mut health));
+        });
+        let _ = std::fs::remove_file(&path);
+
+        let busy: f64 = stages.iter().map(|s| s.seconds).sum();
+        Self {
+            arch: std::env::consts::ARCH,
+            os: std::env::consts::OS,
+            cores: cores(),
+            worker_threads,
+            read_buffer_bytes: StreamLimits::for_rate(sample_rate, channels).read_buffer,
+            write_buffer_samples: write_buffer_capacity(sample_rate),
+            sample_rate,
+            channels,
+            stages,
+            headroom: BENCH_SECONDS as f64 / busy.max(f64::EPSILON),
+            unwritten_samples: health.unwritten_samples,
+        }
+    }
+}
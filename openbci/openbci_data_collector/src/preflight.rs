@@ -0,0 +1,99 @@
+//! Pre-flight report of `--dry-run`.
+//!
+//! Everything a recording depends on that can be checked without
+//! recording: the shield answers and has a board, the board has the
+//! channels and rate asked for, the stream ports are free, the output
+//! directory takes files and the montage fits. Each check ends in go, warn
+//! or no-go, and any no-go fails the run, so problems turn up before a
+//! subject is seated rather than in the first trial.
+
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+
+/// Outcome of one check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    Go,
+    /// Recording works, but not quite as asked
+    Warn,
+    NoGo,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PreflightCheck {
+    pub name: &'static str,
+    pub verdict: Verdict,
+    pub detail: String,
+}
+
+/// Checks in the order they ran
+#[derive(Debug, Default, Serialize)]
+pub struct PreflightReport {
+    pub checks: Vec<PreflightCheck>,
+}
+
+impl PreflightReport {
+    pub fn go(&mut self, name: &'static str, detail: impl Into<String>) {
+        self.push(name, Verdict::Go, detail.into());
+    }
+
+    pub fn warn(&mut self, name: &'static str, detail: impl Into<String>) {
+        self.push(name, Verdict::Warn, detail.into());
+    }
+
+    pub fn no_go(&mut self, name: &'static str, detail: impl Into<String>) {
+        self.push(name, Verdict::NoGo, detail.into());
+    }
+
+    fn push(&mut self, name: &'static str, verdict: Verdict, detail: String) {
+        self.checks.push(PreflightCheck { name, verdict, detail });
+    }
+
+    /// No check came out no-go
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.verdict != Verdict::NoGo)
+    }
+
+    /// One line per check, then the verdict
+    pub fn log(&self) {
+        info!("=== Pre-flight Check ===");
+        for check in &self.checks {
+            match check.verdict {
+                Verdict::Go => info!("  GO     {:<10} {}", check.name, check.detail),
+                Verdict::Warn => warn!("  WARN   {:<10} {}", check.name, check.detail),
+                Verdict::NoGo => error!("  NO-GO  {:<10} {}", check.name, check.detail),
+            }
+        }
+        if self.passed() {
+            info!("Pre-flight check: GO");
+        } else {
+            error!("Pre-flight check: NO-GO");
+        }
+    }
+}
+
+/// Whether files can be written to `dir`, or if it does not exist yet, to
+/// the nearest directory above it it would be created under. Returns that
+/// directory; nothing is left behind.
+pub fn check_writable(dir: &Path) -> Result<PathBuf> {
+    let existing = dir
+        .ancestors()
+        .find(|path| path.is_dir() || path.as_os_str().is_empty())
+        .map(|path| if path.as_os_str().is_empty() { Path::new(".") } else { path })
+        .context("No existing directory above the output directory")?;
+    let probe = existing.join(format!(".openbci_preflight_{}", std::process::id()));
+    fs::write(&probe, b"").with_context(|| format!("Cannot write to {:?}", existing))?;
+    fs::remove_file(&probe).ok();
+    Ok(existing.to_path_buf())
+}
+
+/// Whether the shield's stream `port` can be listened on
+pub fn check_port(port: u16) -> Result<()> {
+    TcpListener::bind(("0.0.0.0", port)).with_context(|| format!("Port {} is not available", port))?;
+    Ok(())
+}
@@ -0,0 +1,37 @@
+//! Laplace mechanism for releasing derived features with differential privacy.
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Parameters of the Laplace mechanism applied to each exported trial
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LaplaceMechanism {
+    /// Privacy budget per trial record
+    pub epsilon: f64,
+    /// Features are clipped to `[clip_low, clip_high]` to bound sensitivity
+    pub clip_low: f64,
+    pub clip_high: f64,
+}
+
+impl LaplaceMechanism {
+    /// Noise scale for a record of `num_features` features. The budget is
+    /// split evenly across features (sequential composition).
+    pub fn scale(&self, num_features: usize) -> f64 {
+        let sensitivity = self.clip_high - self.clip_low;
+        sensitivity * num_features as f64 / self.epsilon
+    }
+
+    /// Clip and perturb one record in place
+    pub fn apply<R: Rng>(&self, features: &mut [f64], rng: &mut R) {
+        let scale = self.scale(features.len());
+        for value in features.iter_mut() {
+            *value = value.clamp(self.clip_low, self.clip_high) + sample_laplace(scale, rng);
+        }
+    }
+}
+
+/// Inverse-CDF sample from Laplace(0, scale)
+fn sample_laplace<R: Rng>(scale: f64, rng: &mut R) -> f64 {
+    let u: f64 = rng.gen_range(-0.5..0.5);
+    -scale * u.signum() * (1.0 - 2.0 * u.abs()).max(f64::MIN_POSITIVE).ln()
+}
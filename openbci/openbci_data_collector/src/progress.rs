@@ -0,0 +1,148 @@
+//! Progress bars for trials and sessions on a terminal.
+//!
+//! While a trial records, the bottom of the terminal shows a bar of its
+//! duration with the time elapsed, the samples written, the rate they
+//! arrive at and the packets and samples the board lost, under the
+//! session's progress when recording a protocol. Both bars belong to one
+//! [`MultiProgress`], which the logger prints records above. Without a
+//! terminal on stderr, or while the scope owns it, the stream counters are
+//! logged every few seconds instead.
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use openbci_wifi_client::StreamStats;
+use std::io::IsTerminal;
+use std::time::{Duration, Instant};
+
+const REDRAW: Duration = Duration::from_millis(250);
+/// Seconds the shown rate is averaged over
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+fn clock(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 3600 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{:02}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+/// Progress of one trial
+pub struct TrialProgress {
+    bar: ProgressBar,
+    started: Instant,
+    duration: Option<Duration>,
+    drawn: Instant,
+    /// Time and sample count the shown rate is measured from
+    rate_from: (Instant, u64),
+    rate: Option<f64>,
+}
+
+impl TrialProgress {
+    /// Show the progress of a trial of `duration` (open-ended if `None`)
+    /// in `bars`. `None` if stderr is not a terminal.
+    pub fn start(bars: &MultiProgress, title: String, duration: Option<Duration>) -> Option<Self> {
+        if !std::io::stderr().is_terminal() {
+            return None;
+        }
+        let (bar, template) = match duration {
+            Some(duration) => (ProgressBar::new(duration.as_millis() as u64), "{prefix} [{bar:16}] {wide_msg}"),
+            None => (ProgressBar::no_length(), "{prefix} {wide_msg}"),
+        };
+        let style = ProgressStyle::with_template(template).expect("valid template").progress_chars("=> ");
+        let now = Instant::now();
+        Some(Self {
+            bar: bars.add(bar.with_style(style).with_prefix(title)),
+            started: now,
+            duration,
+            drawn: now,
+            rate_from: (now, 0),
+            rate: None,
+        })
+    }
+
+    /// Whether the bar is due to be drawn again
+    pub fn due(&self) -> bool {
+        self.drawn.elapsed() >= REDRAW
+    }
+
+    /// Draw the bar with the samples written so far, the stream counters
+    /// and the samples missing from the board clock
+    pub fn update(&mut self, samples: u64, stats: &StreamStats, missing_samples: u64) {
+        let now = Instant::now();
+        self.drawn = now;
+        let (since, counted) = self.rate_from;
+        if now.duration_since(since) >= RATE_WINDOW {
+            self.rate = Some(samples.saturating_sub(counted) as f64 / now.duration_since(since).as_secs_f64());
+            self.rate_from = (now, samples);
+        }
+
+        let elapsed = now.duration_since(self.started);
+        let mut text = match self.duration {
+            Some(duration) => {
+                self.bar.set_position((elapsed.as_millis() as u64).min(duration.as_millis() as u64));
+                format!("{}/{}", clock(elapsed), clock(duration))
+            }
+            None => clock(elapsed),
+        };
+        text.push_str(&format!(" | {} samples", samples));
+        if let Some(rate) = self.rate {
+            text.push_str(&format!(" | {:.1} Hz", rate));
+        }
+        text.push_str(&format!(" | {} dropped", stats.dropped_packets));
+        if missing_samples > 0 {
+            text.push_str(&format!(" | {} missing", missing_samples));
+        }
+        self.bar.set_message(text);
+    }
+}
+
+impl Drop for TrialProgress {
+    fn drop(&mut self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+/// Progress of a session's trials, shown above the current trial's
+pub struct SessionProgress {
+    bar: ProgressBar,
+    started: Instant,
+}
+
+impl SessionProgress {
+    /// Show a session of `total` trials at the top of `bars`; hidden if
+    /// stderr is not a terminal
+    pub fn start(bars: &MultiProgress, total: usize) -> Self {
+        let bar = if std::io::stderr().is_terminal() {
+            let style = ProgressStyle::with_template("Session {pos}/{len} {msg}").expect("valid template");
+            bars.insert(0, ProgressBar::new(total as u64).with_style(style))
+        } else {
+            ProgressBar::hidden()
+        };
+        Self {
+            bar,
+            started: Instant::now(),
+        }
+    }
+
+    /// Add `trials` to the end of the session
+    pub fn grow(&mut self, trials: usize) {
+        self.bar.inc_length(trials as u64);
+    }
+
+    /// Trial `index` (from 0) is the one recording now
+    pub fn trial(&self, index: usize) {
+        self.bar.set_position(index as u64 + 1);
+        // The trials so far, rests included, tell how long the rest takes
+        if index > 0 {
+            let total = self.bar.length().unwrap_or_default() as usize;
+            let left = self.started.elapsed().mul_f64(total.saturating_sub(index) as f64 / index as f64);
+            self.bar.set_message(format!("~{} left", clock(left)));
+        }
+    }
+}
+
+impl Drop for SessionProgress {
+    fn drop(&mut self) {
+        self.bar.finish_and_clear();
+    }
+}
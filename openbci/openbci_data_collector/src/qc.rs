@@ -0,0 +1,300 @@
+//! Session-level quality control gate for training-set inclusion.
+//!
+//! At session finalize every trial metadata file in the session directory is
+//! scored against [`QcCriteria`] and the verdict is written to
+//! `session_manifest.json`. Exporters skip sessions whose manifest says
+//! `"passed": false` unless explicitly told to include them. The manifest
+//! also lists the checksum of every file in the session.
+
+use crate::checksum::{self, FileChecksum};
+use crate::events::BAD_TRIAL;
+use crate::metadata::TrialMetadata;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File name of the per-session manifest
+pub const SESSION_MANIFEST: &str = "session_manifest.json";
+
+/// Configurable QC thresholds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QcCriteria {
+    /// Usable trials each recorded class needs for the session to pass
+    pub min_usable_trials_per_class: usize,
+    /// Highest fraction of missing samples a usable trial may have
+    pub max_drop_rate: f64,
+    /// Highest electrode impedance (kOhm) a usable trial may have, if measured
+    pub max_impedance_kohm: Option<f32>,
+    /// Highest fraction of a usable trial the online artifact detectors may
+    /// mark (`--detect-artifacts`); `--on-artifact abort` stops a trial once
+    /// it is exceeded
+    pub max_artifact_fraction: f64,
+}
+
+impl Default for QcCriteria {
+    fn default() -> Self {
+        Self {
+            min_usable_trials_per_class: 5,
+            max_drop_rate: 0.1,
+            max_impedance_kohm: Some(50.0),
+            max_artifact_fraction: 0.25,
+        }
+    }
+}
+
+impl QcCriteria {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read QC config {:?}", path))?;
+        serde_json::from_str(&text).with_context(|| format!("Failed to parse QC config {:?}", path))
+    }
+}
+
+/// QC result for one trial
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrialQc {
+    pub metadata_file: String,
+    pub trial_number: u32,
+    pub class_label: String,
+    pub class_id: u8,
+    pub total_samples: u64,
+    pub expected_samples: u64,
+    pub drop_rate: f64,
+    pub usable: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub issues: Vec<String>,
+}
+
+/// Session verdict
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionQc {
+    pub passed: bool,
+    pub criteria: QcCriteria,
+    pub failures: Vec<String>,
+}
+
+/// Contents of `session_manifest.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionManifest {
+    pub subject_id: String,
+    pub session_id: String,
+    pub updated: DateTime<Utc>,
+    pub qc: SessionQc,
+    pub trials: Vec<TrialQc>,
+    /// Every file in the session directory, for `openbci verify`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<FileChecksum>,
+}
+
+impl SessionManifest {
+    pub fn load(session_dir: &Path) -> Result<Self> {
+        let path = session_dir.join(SESSION_MANIFEST);
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read session manifest {:?}", path))?;
+        serde_json::from_str(&text).with_context(|| format!("Failed to parse session manifest {:?}", path))
+    }
+}
+
+/// Whether a session should feed the training set. Sessions without a
+/// manifest predate QC and are included.
+pub fn session_passed(session_dir: &Path) -> bool {
+    if !session_dir.join(SESSION_MANIFEST).exists() {
+        return true;
+    }
+    match SessionManifest::load(session_dir) {
+        Ok(manifest) => manifest.qc.passed,
+        Err(e) => {
+            warn!("{}, treating session as failed", e);
+            false
+        }
+    }
+}
+
+fn evaluate_trial(metadata_file: String, meta: &TrialMetadata, criteria: &QcCriteria) -> TrialQc {
+    let drop_rate = meta.drop_rate();
+    let mut issues = Vec::new();
+
+    if drop_rate > criteria.max_drop_rate {
+        issues.push(format!(
+            "drop rate {:.1}% exceeds {:.1}%",
+            drop_rate * 100.0,
+            criteria.max_drop_rate * 100.0
+        ));
+    }
+
+    if let (Some(limit), Some(impedances)) = (criteria.max_impedance_kohm, &meta.impedance_kohm) {
+        for (channel, &z) in meta.electrode_config.channels.iter().zip(impedances) {
+            if z > limit {
+                issues.push(format!("{} impedance {:.1} kOhm exceeds {:.1} kOhm", channel, z, limit));
+            }
+        }
+    }
+
+    if meta.artifact_injection.is_some() {
+        issues.push("contains injected test artifacts".to_string());
+    }
+
+    if meta.artifact_detection.as_ref().is_some_and(|d| d.aborted) {
+        issues.push("aborted for artifacts".to_string());
+    } else if let Some(fraction) = meta.contaminated_fraction().filter(|f| *f > criteria.max_artifact_fraction) {
+        issues.push(format!(
+            "{:.1}% of samples in artifacts exceeds {:.1}%",
+            fraction * 100.0,
+            criteria.max_artifact_fraction * 100.0
+        ));
+    }
+
+    if meta.interrupted {
+        issues.push("interrupted before the end".to_string());
+    }
+
+    if meta.markers.iter().any(|m| m.label == BAD_TRIAL) {
+        issues.push("flagged as bad during recording".to_string());
+    }
+
+    if let Some(trial) = meta.replaced_by {
+        issues.push(format!("replaced by trial {}", trial));
+    }
+
+    TrialQc {
+        metadata_file,
+        trial_number: meta.trial_number,
+        class_label: meta.class_label.clone(),
+        class_id: meta.class_id,
+        total_samples: meta.total_samples,
+        expected_samples: meta.expected_samples(),
+        drop_rate,
+        usable: issues.is_empty(),
+        issues,
+    }
+}
+
+/// Why a session should record a trial again: it was aborted for
+/// artifacts, artifacts cover more of it than `criteria` allow, or fewer
+/// samples arrived than its duration at the sample rate would give
+pub fn replacement_reason(meta: &TrialMetadata, criteria: &QcCriteria) -> Option<String> {
+    if meta.artifact_detection.as_ref().is_some_and(|d| d.aborted) {
+        return Some("aborted for artifacts".to_string());
+    }
+    if let Some(fraction) = meta.contaminated_fraction().filter(|f| *f > criteria.max_artifact_fraction) {
+        return Some(format!("{:.1}% of samples in artifacts", fraction * 100.0));
+    }
+    let expected = meta.expected_samples();
+    if meta.drop_rate() > criteria.max_drop_rate {
+        return Some(format!(
+            "{} of {} expected samples ({:.1}%)",
+            meta.total_samples,
+            expected,
+            meta.total_samples as f64 * 100.0 / expected as f64
+        ));
+    }
+    None
+}
+
+/// Metadata of every trial in `session_dir`, by file name
+pub fn read_trial_metadata(session_dir: &Path) -> Result<Vec<(PathBuf, TrialMetadata)>> {
+    let mut trials = Vec::new();
+    for entry in fs::read_dir(session_dir)
+        .with_context(|| format!("Failed to read session directory {:?}", session_dir))?
+    {
+        let path = entry?.path();
+        let is_metadata = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.ends_with("_metadata.json"));
+        if !is_metadata {
+            continue;
+        }
+
+        let text = fs::read_to_string(&path)?;
+        match serde_json::from_str::<TrialMetadata>(&text) {
+            Ok(meta) => trials.push((path, meta)),
+            Err(e) => warn!("Skipping unreadable metadata {:?}: {}", path, e),
+        }
+    }
+    trials.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(trials)
+}
+
+/// Evaluate every trial in `session_dir` and return the session verdict
+pub fn evaluate_session(session_dir: &Path, criteria: &QcCriteria) -> Result<SessionManifest> {
+    let trials = read_trial_metadata(session_dir)?;
+
+    let (subject_id, session_id) = match trials.first() {
+        Some((_, meta)) => (meta.subject_id.clone(), meta.session_id.clone()),
+        None => Default::default(),
+    };
+
+    let trial_qc: Vec<TrialQc> = trials
+        .iter()
+        .map(|(path, meta)| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            evaluate_trial(name, meta, criteria)
+        })
+        .collect();
+
+    let mut usable_per_class: BTreeMap<&str, usize> = BTreeMap::new();
+    for trial in &trial_qc {
+        let count = usable_per_class.entry(trial.class_label.as_str()).or_default();
+        if trial.usable {
+            *count += 1;
+        }
+    }
+
+    let mut failures = Vec::new();
+    if trial_qc.is_empty() {
+        failures.push("no trials recorded".to_string());
+    }
+    for (class, usable) in &usable_per_class {
+        if *usable < criteria.min_usable_trials_per_class {
+            failures.push(format!(
+                "class '{}' has {} usable trials, needs {}",
+                class, usable, criteria.min_usable_trials_per_class
+            ));
+        }
+    }
+
+    let total: u64 = trial_qc.iter().map(|t| t.total_samples).sum();
+    let expected: u64 = trial_qc.iter().map(|t| t.expected_samples).sum();
+    if expected > 0 {
+        let session_drop = (1.0 - total as f64 / expected as f64).max(0.0);
+        if session_drop > criteria.max_drop_rate {
+            failures.push(format!(
+                "session drop rate {:.1}% exceeds {:.1}%",
+                session_drop * 100.0,
+                criteria.max_drop_rate * 100.0
+            ));
+        }
+    }
+
+    Ok(SessionManifest {
+        subject_id,
+        session_id,
+        updated: Utc::now(),
+        qc: SessionQc {
+            passed: failures.is_empty(),
+            criteria: criteria.clone(),
+            failures,
+        },
+        trials: trial_qc,
+        files: Vec::new(),
+    })
+}
+
+/// Evaluate the session, checksum its files and write
+/// `session_manifest.json` into its directory
+pub fn finalize_session(session_dir: &Path, criteria: &QcCriteria) -> Result<SessionManifest> {
+    let mut manifest = evaluate_session(session_dir, criteria)?;
+    let trials: Vec<TrialMetadata> = read_trial_metadata(session_dir)?.into_iter().map(|(_, meta)| meta).collect();
+    manifest.files = checksum::session_files(session_dir, &trials)?;
+    let path = session_dir.join(SESSION_MANIFEST);
+    fs::write(&path, serde_json::to_string_pretty(&manifest)?)
+        .with_context(|| format!("Failed to write session manifest {:?}", path))?;
+    info!("Saved session manifest to: {:?}", path);
+    Ok(manifest)
+}
@@ -0,0 +1,139 @@
+//! Sampling rate as delivered, measured against the host clock.
+//!
+//! A board's oscillator rarely runs at exactly its nominal rate, and a link
+//! that drops samples or a firmware set to another rate shows up the same
+//! way: fewer or more samples per second than configured. The rate is the
+//! slope of a least-squares fit of sample index over arrival time, so the
+//! chunked delivery of the WiFi shield averages out; samples known to be
+//! missing still advance the index. Boards that stamp samples with their
+//! own clock also get that clock's drift against the host's.
+
+use crate::metadata::SampleRateInfo;
+use log::warn;
+use openbci_wifi_client::timestamp_seconds;
+use std::time::Instant;
+
+/// Relative deviation from the configured rate worth a warning
+pub const RATE_TOLERANCE: f64 = 0.01;
+/// Seconds of stream before the rate is checked during a trial; shorter
+/// fits are dominated by delivery jitter
+const WARMUP_SECONDS: f64 = 10.0;
+/// Seconds between checks during a trial
+const CHECK_INTERVAL: f64 = 5.0;
+/// Shortest stream a rate is reported for at the end of a trial
+const MIN_SECONDS: f64 = 2.0;
+
+/// Running least-squares fit of `y` over `x`
+#[derive(Debug, Default)]
+struct Fit {
+    n: f64,
+    mean_x: f64,
+    mean_y: f64,
+    sxx: f64,
+    sxy: f64,
+}
+
+impl Fit {
+    fn push(&mut self, x: f64, y: f64) {
+        self.n += 1.0;
+        let dx = x - self.mean_x;
+        self.mean_x += dx / self.n;
+        self.mean_y += (y - self.mean_y) / self.n;
+        self.sxx += dx * (x - self.mean_x);
+        self.sxy += dx * (y - self.mean_y);
+    }
+
+    fn slope(&self) -> Option<f64> {
+        (self.sxx > 0.0).then(|| self.sxy / self.sxx)
+    }
+}
+
+/// Compares the samples of one trial against the time they took to arrive
+#[derive(Debug)]
+pub struct RateMonitor {
+    nominal: f64,
+    started: Option<Instant>,
+    /// Host seconds at the last sample
+    elapsed: f64,
+    /// Index of the next sample, counting missing ones
+    index: u64,
+    rate: Fit,
+    /// Board clock over host clock, when samples carry the board's time
+    clock: Option<Fit>,
+    /// Last raw board time and the board time with jumps taken out
+    board: Option<(f64, f64)>,
+    next_check: f64,
+    warned: bool,
+}
+
+impl RateMonitor {
+    /// `board_clock`: timestamps come from the board rather than the host
+    pub fn new(sample_rate: u32, board_clock: bool) -> Self {
+        Self {
+            nominal: sample_rate as f64,
+            started: None,
+            elapsed: 0.0,
+            index: 0,
+            rate: Fit::default(),
+            clock: board_clock.then(Fit::default),
+            board: None,
+            next_check: WARMUP_SECONDS,
+            warned: false,
+        }
+    }
+
+    /// Feed a sample stamped `timestamp` that arrived at `received`, after
+    /// `missing` lost samples. Across a board clock jump the board time
+    /// continues by the host's step instead. Warns once when the rate
+    /// drifts out of tolerance.
+    pub fn push(&mut self, timestamp: f64, received: Instant, missing: u64, clock_jump: bool) {
+        let started = *self.started.get_or_insert(received);
+        let host = received.duration_since(started).as_secs_f64();
+        self.index += missing;
+        self.rate.push(host, self.index as f64);
+        self.index += 1;
+
+        if let Some(clock) = &mut self.clock {
+            let raw = timestamp_seconds(timestamp);
+            let board = match self.board {
+                None => 0.0,
+                Some((_, board)) if clock_jump => board + (host - self.elapsed),
+                Some((last, board)) => board + (raw - last),
+            };
+            self.board = Some((raw, board));
+            clock.push(host, board);
+        }
+        self.elapsed = host;
+
+        if host >= self.next_check {
+            self.next_check = host + CHECK_INTERVAL;
+            if let Some(measured) = self.rate.slope() {
+                let deviation = measured / self.nominal - 1.0;
+                if !self.warned && deviation.abs() > RATE_TOLERANCE {
+                    self.warned = true;
+                    warn!(
+                        "Board delivers {:.2} Hz, {:+.1}% off the configured {} Hz",
+                        measured,
+                        deviation * 100.0,
+                        self.nominal
+                    );
+                }
+            }
+        }
+    }
+
+    /// The measured rate and clock drift; `None` for streams too short to
+    /// tell
+    pub fn info(&self) -> Option<SampleRateInfo> {
+        if self.elapsed < MIN_SECONDS {
+            return None;
+        }
+        let measured = self.rate.slope()?;
+        Some(SampleRateInfo {
+            measured_hz: measured,
+            deviation_percent: (measured / self.nominal - 1.0) * 100.0,
+            clock_drift_ppm: self.clock.as_ref().and_then(Fit::slope).map(|slope| (slope - 1.0) * 1e6),
+            seconds: self.elapsed,
+        })
+    }
+}
@@ -0,0 +1,1245 @@
+//! Recording from a board: the options shared by `openbci record`,
+//! `session`, `monitor`, `check`, `calibrate` and `soak`, what `--config`
+//! fills in, and one trial or monitor run from start to finish.
+
+use crate::asr;
+use crate::augment::{ArtifactInjector, ArtifactKind, ArtifactRecording, ArtifactSegment, InjectionSchedule};
+use crate::auth::{AccessControl, AuditLog, TokenStore, AUDIT_FILE};
+use crate::bandpower::BandPowers;
+use crate::bids;
+use crate::board::{connect_board, query_board, run_signal_check};
+use crate::checksum;
+use crate::collector::DataCollector;
+use crate::compress::{self, Compression};
+use crate::config::{ExperimentConfig, ExperimentInfo, MontageConfig};
+use crate::detect;
+use crate::disk;
+use crate::environment::EnvironmentInfo;
+use crate::erd;
+use crate::events;
+use crate::filter::Passband;
+use crate::gaps::GapFill;
+use crate::keys::Hotkey;
+use crate::metadata::TrialMetadata;
+use crate::montage::{self, Montage, MONTAGE_FILE};
+use crate::multiboard::{BoardChannels, MultiBoardInfo, ShieldAddr};
+use crate::paradigm::{Paradigm, ParadigmInfo};
+use crate::platform;
+use crate::qc::{self, QcCriteria};
+use crate::recording::{self, Recording};
+use crate::session::SessionPlan;
+use crate::shutdown;
+use crate::simulate::SimulationInfo;
+use crate::sink::{open_sink, write_rows, OutputFormat};
+use crate::timesync::HOST_TIMES_SUFFIX;
+use crate::websocket::WsServer;
+#[cfg(feature = "zmq")]
+use crate::zmq_pub::ZmqPublisher;
+use anyhow::Result;
+use chrono::Utc;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, ValueEnum};
+use indicatif::MultiProgress;
+use log::{error, info, warn};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// How the host talks to the board
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Transport {
+    /// WiFi shield (HTTP control, TCP data)
+    Wifi,
+    /// Cyton USB dongle
+    Serial,
+    /// Ganglion over Bluetooth LE (build with --features ble)
+    Ble,
+    /// Play back a recorded trial (--replay-file), no hardware needed
+    Replay,
+    /// Generated alpha rhythm plus noise, no hardware needed
+    Synthetic,
+    /// Any amplifier's LSL stream (--stream-name, build with --features lsl)
+    Lsl,
+}
+
+/// What --osc sends
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OscData {
+    /// [right, left] lateralization scores and the powers, per band power
+    /// estimate (needs --band-power-every)
+    BandPower,
+    /// Every sample, in microvolts, on <ADDRESS>/eeg
+    Samples,
+}
+
+/// Where the channels of several shields go
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShieldOutput {
+    /// One trial with every shield's channels
+    Merged,
+    /// One trial per shield, in the directory of its subject
+    SideBySide,
+}
+
+/// What a trial does once it is too contaminated to pass QC
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArtifactAction {
+    /// Only mark the artifacts
+    Mark,
+    /// Stop recording the trial and keep it, flagged for QC
+    Abort,
+    /// Stop, discard the trial and record it again (up to --max-repeats)
+    Repeat,
+}
+
+/// Recording options shared by the subcommands that read a board
+#[derive(clap::Args, Debug, Clone)]
+pub struct RecordArgs {
+    /// Experiment config (TOML or YAML) with defaults for the flags below;
+    /// `session` records its protocol. Switches it turns on can be turned
+    /// off with e.g. --cues=false
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Check the shield, board, stream ports, output directory and
+    /// montage, print a go/no-go report and exit without recording
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Board link to record from
+    #[arg(long, visible_alias = "source", value_enum, default_value = "wifi")]
+    pub transport: Transport,
+
+    /// Serial port of the Cyton dongle (--transport serial)
+    #[arg(long, default_value = "/dev/ttyUSB0")]
+    pub serial_port: String,
+
+    /// Advertised name to look for (--transport ble)
+    #[arg(long, default_value = "Ganglion")]
+    pub ble_name: String,
+
+    /// Name of the LSL stream to record (--transport lsl)
+    #[arg(long, value_name = "NAME", required_if_eq("transport", "lsl"))]
+    pub stream_name: Option<String>,
+
+    /// Trial to play back, as its metadata JSON or a data file (--transport replay)
+    #[arg(long, required_if_eq("transport", "replay"))]
+    pub replay_file: Option<PathBuf>,
+
+    /// Playback speed relative to the recorded sample rate (--transport replay)
+    #[arg(long, default_value = "1.0")]
+    pub replay_speed: f64,
+
+    /// Seed of the generated signal (--transport synthetic or --simulate,
+    /// random if omitted)
+    #[arg(long)]
+    pub synthetic_seed: Option<u64>,
+
+    /// Record simulated EEG for --class instead of reading a board: 1/f
+    /// background, alpha bursts, mu/beta ERD over C3/C4, blinks and EMG
+    #[arg(long, num_args = 0..=1, default_value_t = false, default_missing_value = "true", action = ArgAction::Set, conflicts_with = "replay_file")]
+    pub simulate: bool,
+
+    /// Fraction of mu/beta amplitude lost over the contralateral hemisphere
+    /// during imagery (--simulate)
+    #[arg(long, default_value = "0.3")]
+    pub simulate_erd: f64,
+
+    /// Blinks and EMG bursts per minute (--simulate, 0 for none)
+    #[arg(long, default_value = "4")]
+    pub simulate_artifacts: f64,
+
+    /// OpenBCI WiFi Shield IP address
+    #[arg(short, long, default_value = "192.168.4.1")]
+    pub shield_ip: String,
+
+    /// Local IP address (your laptop on wlan1)
+    #[arg(short, long, default_value = "192.168.4.2")]
+    pub local_ip: String,
+
+    /// TCP port for data reception
+    #[arg(short, long, default_value = "3000")]
+    pub port: u16,
+
+    /// Record several WiFi shields as one board, as HOST[=PORT],... with
+    /// PORT the local port each streams to (default --port, +1 per shield).
+    /// --channels counts the channels of all of them.
+    #[arg(long, value_delimiter = ',', value_name = "HOST[=PORT]")]
+    pub shields: Vec<ShieldAddr>,
+
+    /// With --shields: one trial of all channels, or one trial per shield
+    #[arg(long, value_enum, default_value = "merged")]
+    pub shield_output: ShieldOutput,
+
+    /// Subject of each shield for side-by-side hyperscanning (default
+    /// --subject-id for all)
+    #[arg(long, value_delimiter = ',')]
+    pub shield_subjects: Vec<String>,
+
+    /// Restart the shield's stream after this many seconds without data
+    /// (0 to wait out the trial instead)
+    #[arg(long, value_name = "SECONDS", default_value = "5")]
+    pub stall_timeout: f64,
+
+    /// Have the shield send packets as they fill instead of every 4 ms,
+    /// for 1000 Hz and up
+    #[arg(long, num_args = 0..=1, default_value_t = false, default_missing_value = "true", action = ArgAction::Set)]
+    pub burst: bool,
+
+    /// Output directory for saved data
+    #[arg(short, long, default_value = "motor_imagery_data")]
+    pub output_dir: String,
+
+    /// Class of the trial: left_hand, right_hand, both_hands, rest, or
+    /// one of the --paradigm
+    #[arg(short = 'c', long)]
+    pub class: Option<String>,
+
+    /// Trial number (for organizing multiple repetitions)
+    #[arg(short = 't', long, default_value = "1")]
+    pub trial: u32,
+
+    /// Duration per trial in seconds
+    #[arg(short, long, default_value = "5")]
+    pub duration: u64,
+
+    /// Sampling rate (Hz)
+    #[arg(short = 'r', long, default_value = "250")]
+    pub sample_rate: u32,
+
+    /// Number of channels to record
+    #[arg(long, default_value = "2")]
+    pub channels: usize,
+
+    /// Present the trial: a fixation cross, then after --cue-delay an arrow
+    /// for --class, both marked in the recording
+    #[arg(long, num_args = 0..=1, default_value_t = false, default_missing_value = "true", action = ArgAction::Set)]
+    pub cues: bool,
+
+    /// Seconds of fixation before the cue (--cues)
+    #[arg(long, default_value = "2.0")]
+    pub cue_delay: f64,
+
+    /// Beep at cue onset (a tone when built with --features audio, the
+    /// terminal bell otherwise)
+    #[arg(long, num_args = 0..=1, default_value_t = false, default_missing_value = "true", action = ArgAction::Set)]
+    pub cue_beep: bool,
+
+    /// Record key presses during the trial as markers, with hotkeys to
+    /// annotate it: a = artifact, b = bad trial (fails QC), space = marker
+    #[arg(long, num_args = 0..=1, default_value_t = false, default_missing_value = "true", action = ArgAction::Set)]
+    pub keys: bool,
+
+    /// Add or override a hotkey, as key=label (repeatable, --keys); the
+    /// marker is flag:<label>
+    #[arg(long, value_name = "KEY=LABEL")]
+    pub hotkey: Vec<Hotkey>,
+
+    /// Show a live scope of every channel with the stream rate, dropped
+    /// packets, trial phase and time left while recording
+    #[arg(long, num_args = 0..=1, default_value_t = false, default_missing_value = "true", action = ArgAction::Set)]
+    pub scope: bool,
+
+    /// Subject ID
+    #[arg(long, default_value = "S01")]
+    pub subject_id: String,
+
+    /// Session ID (for grouping trials in one recording session)
+    #[arg(long, default_value = "session_01")]
+    pub session_id: String,
+
+    /// QC criteria JSON for the session manifest (defaults if omitted)
+    #[arg(long)]
+    pub qc_config: Option<PathBuf>,
+
+    /// Tokio worker threads (defaults to 2 on aarch64, all cores elsewhere)
+    #[arg(long)]
+    pub worker_threads: Option<usize>,
+
+    /// Confirm the electrode montage interactively before recording (or
+    /// before the `check`)
+    #[arg(long)]
+    pub montage_wizard: bool,
+
+    /// Montage file (.toml, .yaml or .json) with the channel positions and
+    /// names, reference and ground; replaces the [montage] of --config
+    #[arg(long)]
+    pub montage: Option<PathBuf>,
+
+    /// Paradigm file (.toml, .yaml or .json) with the classes, their cues
+    /// and the trial structure, for SSVEP, P300 or resting-state sessions
+    /// (motor imagery if omitted)
+    #[arg(long, value_name = "FILE")]
+    pub paradigm: Option<PathBuf>,
+
+    /// Mix synthetic artifacts into the live signal for robustness testing
+    /// (comma separated: blink, emg). Trials recorded this way fail QC.
+    #[arg(long, value_delimiter = ',')]
+    pub inject_artifacts: Vec<ArtifactKind>,
+
+    /// Replay a recorded artifact instead of the synthetic template, as
+    /// kind=path/to/trial_metadata.json (repeatable)
+    #[arg(long)]
+    pub artifact_recording: Vec<ArtifactRecording>,
+
+    /// Mean seconds between injected artifacts
+    #[arg(long, default_value = "4.0")]
+    pub artifact_interval: f64,
+
+    /// Seed for artifact timing and EMG noise (random if omitted)
+    #[arg(long)]
+    pub artifact_seed: Option<u64>,
+
+    /// Serve Prometheus metrics on this address, e.g. 0.0.0.0:9100
+    /// (build with --features metrics)
+    #[arg(long)]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Mirror the recorded stream to the OpenBCI GUI's Networking widget
+    /// (UDP, JSON), e.g. 127.0.0.1:12345
+    #[arg(long)]
+    pub gui_udp: Option<SocketAddr>,
+
+    /// Publish the recorded stream and its markers as Lab Streaming Layer
+    /// outlets (build with --features lsl)
+    #[arg(long, num_args = 0..=1, default_value_t = false, default_missing_value = "true", action = ArgAction::Set)]
+    pub lsl: bool,
+
+    /// Name of the LSL EEG stream; markers go to <NAME>-Markers
+    #[arg(long, default_value = "OpenBCI", value_name = "NAME")]
+    pub lsl_name: String,
+
+    /// Serve the live stream (samples, markers, band power) as JSON to
+    /// WebSocket clients on this port, e.g. 8765
+    #[arg(long, value_name = "PORT")]
+    pub ws_port: Option<u16>,
+
+    /// Address --ws-port listens on; anything but loopback exposes the
+    /// stream to the network and needs --ws-tokens
+    #[arg(long, value_name = "IP", default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    pub ws_bind: IpAddr,
+
+    /// Tokens file (JSON) for --ws-port: clients must present one of its
+    /// tokens, and connections and predictions are audited
+    #[arg(long, value_name = "FILE")]
+    pub ws_tokens: Option<PathBuf>,
+
+    /// Audit log of --ws-tokens, JSON lines (default: ws_audit.jsonl in
+    /// --output-dir)
+    #[arg(long, value_name = "FILE")]
+    pub ws_audit_log: Option<PathBuf>,
+
+    /// Send band power scores or every sample to an OSC receiver over UDP,
+    /// e.g. the robot's ESP32 at 192.168.4.50:9002
+    #[arg(long, value_name = "ADDR")]
+    pub osc: Option<SocketAddr>,
+
+    /// OSC address of the messages; the ESP32 firmware reads /neuropype
+    #[arg(long, default_value = "/neuropype", value_name = "ADDRESS")]
+    pub osc_address: String,
+
+    /// What --osc sends
+    #[arg(long, value_enum, default_value = "band-power")]
+    pub osc_data: OscData,
+
+    /// Publish the live stream on a ZeroMQ PUB socket bound to this
+    /// endpoint, topics eeg, markers and predictions, e.g. tcp://*:5556
+    /// (build with --features zmq)
+    #[arg(long, value_name = "ENDPOINT")]
+    pub zmq_pub: Option<String>,
+
+    /// Trial data formats, comma separated to write several at once; the
+    /// first is the one the metadata points to (default csv, brainvision
+    /// with --bids)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub format: Vec<OutputFormat>,
+
+    /// Compress trial files with zstd or gzip: CSV as it is written
+    /// (`.csv.zst`, `.csv.gz`), NPZ with zip deflate whichever is chosen.
+    /// BDF, EDF and BrainVision stay uncompressed
+    #[arg(long, value_name = "CODEC")]
+    pub compress: Option<Compression>,
+
+    /// Write the rows of samples missing from the WiFi stream anyway, as
+    /// `nan` or `interpolate`d between the samples either side, so row
+    /// numbers stay aligned with time
+    #[arg(long, value_name = "FILL")]
+    pub fill_gaps: Option<GapFill>,
+
+    /// Rotate the trial files into segments of this many minutes, with
+    /// sample IDs running on, so long recordings stay manageable
+    #[arg(long, value_name = "MINUTES")]
+    pub segment_minutes: Option<f64>,
+
+    /// Warn when the output disk has less than this many MB free, before
+    /// and while recording; 0 to never check
+    #[arg(long, value_name = "MB", default_value = "1024")]
+    pub min_free_mb: u64,
+
+    /// Write trials as runs of a BIDS-EEG dataset rooted at --output-dir,
+    /// with channels, events and eeg.json sidecars
+    #[arg(long, num_args = 0..=1, default_value_t = false, default_missing_value = "true", action = ArgAction::Set)]
+    pub bids: bool,
+
+    /// Mains frequency at the recording site in Hz, for the BIDS sidecar
+    /// and the signal check
+    #[arg(long, default_value = "50")]
+    pub line_frequency: f64,
+
+    /// Log mu/beta PLV and coherence between channel pairs every N seconds
+    /// (over the last 4 s)
+    #[arg(long)]
+    pub connectivity_every: Option<f64>,
+
+    /// Show mu (8-13 Hz) and beta (13-30 Hz) power of C3/C4 every N
+    /// seconds (over the last 2 s), with the change since fixation when
+    /// --cues is on
+    #[arg(long, value_name = "SECONDS")]
+    pub band_power_every: Option<f64>,
+
+    /// After every trial, print the C3/C4 mu and beta change from rest to
+    /// the imagery window and how the classes lateralize so far
+    #[arg(long, num_args = 0..=1, default_value_t = true, default_missing_value = "true", action = ArgAction::Set)]
+    pub quick_look: bool,
+
+    /// Record N seconds of blinks and eye movements and save the session's
+    /// EOG regression before recording (or before the `check`)
+    #[arg(long, value_name = "SECONDS")]
+    pub eog_calibrate: Option<u64>,
+
+    /// Regress blinks out of every channel online, using the session's
+    /// EOG calibration
+    #[arg(long, num_args = 0..=1, default_value_t = false, default_missing_value = "true", action = ArgAction::Set)]
+    pub eog_regression: bool,
+
+    /// Channels that pick up the EOG, by montage label; without EOG
+    /// electrodes the frontal ones stand in
+    #[arg(long, value_delimiter = ',', default_value = "Fp1,Fp2")]
+    pub eog_channels: Vec<String>,
+
+    /// Record N seconds of clean, relaxed baseline and save it as the
+    /// session's ASR calibration before recording (or before the `check`)
+    #[arg(long, value_name = "SECONDS")]
+    pub asr_calibrate: Option<u64>,
+
+    /// Clean the stream online with artifact subspace reconstruction,
+    /// using the session's calibration
+    #[arg(long, num_args = 0..=1, default_value_t = false, default_missing_value = "true", action = ArgAction::Set)]
+    pub asr: bool,
+
+    /// ASR burst criterion in robust standard deviations of clean-data
+    /// RMS; lower is more aggressive
+    #[arg(long, default_value_t = asr::DEFAULT_CUTOFF)]
+    pub asr_cutoff: f64,
+
+    /// Band-pass every channel before writing, e.g. 1-40 (Hz; causal
+    /// 4th order Butterworth edges)
+    #[arg(long, value_name = "LOW-HIGH")]
+    pub bandpass: Option<Passband>,
+
+    /// Notch out the power line before writing, 50 or 60 (Hz)
+    #[arg(long, value_name = "HZ")]
+    pub notch: Option<f64>,
+
+    /// Re-reference every channel to the common average before writing,
+    /// after --bandpass and --notch (per board with --shield-output
+    /// side-by-side)
+    #[arg(long, num_args = 0..=1, default_value_t = false, default_missing_value = "true", action = ArgAction::Set)]
+    pub car: bool,
+
+    /// Before every trial, check each channel for railing, flat or noisy
+    /// signal, line noise and (Cyton) impedance, and refuse to record when
+    /// one is clearly bad
+    #[arg(long, num_args = 0..=1, default_value_t = false, default_missing_value = "true", action = ArgAction::Set)]
+    pub signal_check: bool,
+
+    /// Record even when the signal check fails
+    #[arg(long, requires = "signal_check")]
+    pub force: bool,
+
+    /// Mark amplitude, flatline and gradient artifacts in the event stream
+    #[arg(long, num_args = 0..=1, default_value_t = false, default_missing_value = "true", action = ArgAction::Set)]
+    pub detect_artifacts: bool,
+
+    /// Artifact if any channel's peak-to-peak over 0.5 s exceeds this (µV)
+    #[arg(long, value_name = "UV", default_value_t = detect::DEFAULT_MAX_AMPLITUDE_UV)]
+    pub max_amplitude: f64,
+
+    /// Flat channel if its peak-to-peak over 1 s stays below this (µV; 0
+    /// turns the check off)
+    #[arg(long, value_name = "UV", default_value_t = detect::DEFAULT_FLAT_AMPLITUDE_UV)]
+    pub flat_amplitude: f64,
+
+    /// Artifact if a channel jumps more than this between samples (µV)
+    #[arg(long, value_name = "UV", default_value_t = detect::DEFAULT_MAX_GRADIENT_UV)]
+    pub max_gradient: f64,
+
+    /// What a trial does once artifacts cover more of it than QC allows
+    /// (max_artifact_fraction, see --qc-config)
+    #[arg(long, value_enum, default_value = "mark")]
+    pub on_artifact: ArtifactAction,
+
+    /// How often --on-artifact repeat records a trial again
+    #[arg(long, default_value_t = 3)]
+    pub max_repeats: u32,
+
+    /// Set by `monitor`: stream without trial files
+    #[arg(skip)]
+    pub monitor: bool,
+
+    /// Loaded from --config
+    #[arg(skip)]
+    pub experiment: Option<Experiment>,
+
+    /// Loaded from --montage, or the [montage] of --config
+    #[arg(skip)]
+    pub configured_montage: Option<Montage>,
+
+    /// Loaded from --paradigm, or the paradigm of --config
+    #[arg(skip)]
+    pub configured_paradigm: Paradigm,
+
+    /// Started from --ws-port once per run, shared by every trial
+    #[arg(skip)]
+    pub ws_server: Option<WsServer>,
+
+    /// The progress bars, which the logger prints above
+    #[arg(skip)]
+    pub progress: MultiProgress,
+
+    /// Bound from --zmq-pub once per run, shared by every trial
+    #[cfg(feature = "zmq")]
+    #[arg(skip)]
+    pub zmq_publisher: Option<ZmqPublisher>,
+}
+
+/// What `--config` adds beyond flag defaults
+#[derive(Debug, Clone)]
+pub struct Experiment {
+    pub info: ExperimentInfo,
+    /// Set when the config lists classes to record
+    pub session: Option<SessionPlan>,
+}
+
+fn parse_value<T: ValueEnum>(value: &str) -> Result<T> {
+    T::from_str(value, true).map_err(|e| anyhow::anyhow!("Invalid value '{}' in config: {}", value, e))
+}
+
+/// Fill in what `--config` sets and the command line does not
+fn apply_config(args: &mut RecordArgs, matches: &ArgMatches, path: &Path) -> Result<()> {
+    let (config, info) = ExperimentConfig::load(path)?;
+    let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    macro_rules! set {
+        ($field:ident, $value:expr) => {
+            if let Some(value) = $value {
+                if !from_cli(stringify!($field)) {
+                    args.$field = value;
+                }
+            }
+        };
+    }
+
+    set!(subject_id, config.subject_id);
+    set!(session_id, config.session_id);
+    set!(output_dir, config.output_dir);
+
+    let board = config.board;
+    set!(transport, board.transport.as_deref().map(parse_value::<Transport>).transpose()?);
+    set!(shield_ip, board.shield_ip);
+    set!(local_ip, board.local_ip);
+    set!(port, board.port);
+    set!(stall_timeout, board.stall_timeout);
+    set!(burst, board.burst);
+    set!(serial_port, board.serial_port);
+    set!(ble_name, board.ble_name);
+    set!(stream_name, board.stream_name.map(Some));
+    set!(sample_rate, board.sample_rate);
+    set!(simulate, board.simulate);
+    if !board.shields.is_empty() && !from_cli("shields") {
+        args.shields = board.shields.iter().map(|s| s.parse()).collect::<Result<_>>()?;
+    }
+    set!(shield_output, board.shield_output.as_deref().map(parse_value::<ShieldOutput>).transpose()?);
+    if !board.shield_subjects.is_empty() && !from_cli("shield_subjects") {
+        args.shield_subjects = board.shield_subjects;
+    }
+    if args.configured_montage.is_none() {
+        args.configured_montage = config.montage.as_ref().map(|m| m.montage()).transpose()?;
+    }
+    // The montage implies the channel count unless one is given
+    set!(channels, board.channels.or(args.configured_montage.as_ref().map(|m| m.channels.len())));
+
+    let protocol = config.protocol;
+    set!(paradigm, protocol.paradigm.clone().map(Some));
+    // The paradigm's trial structure goes under the config's
+    apply_paradigm(args, matches)?;
+    set!(duration, protocol.duration);
+    set!(cues, protocol.cues);
+    set!(cue_delay, protocol.cue_delay);
+    set!(cue_beep, protocol.cue_beep);
+    set!(keys, protocol.keys);
+    set!(scope, protocol.scope);
+    set!(signal_check, protocol.signal_check);
+    set!(quick_look, protocol.quick_look);
+
+    set!(eog_regression, config.filters.eog_regression);
+    set!(eog_channels, config.filters.eog_channels);
+    set!(asr, config.filters.asr);
+    set!(asr_cutoff, config.filters.asr_cutoff);
+    set!(bandpass, config.filters.bandpass.map(|[low, high]| Some(Passband { low, high })));
+    set!(notch, config.filters.notch.map(Some));
+    set!(car, config.filters.car);
+
+    let artifacts = config.artifacts;
+    set!(detect_artifacts, artifacts.detect);
+    set!(max_amplitude, artifacts.max_amplitude);
+    set!(flat_amplitude, artifacts.flat_amplitude);
+    set!(max_gradient, artifacts.max_gradient);
+    set!(on_artifact, artifacts.on_artifact.as_deref().map(parse_value::<ArtifactAction>).transpose()?);
+    set!(max_repeats, artifacts.max_repeats);
+
+    let output = config.output;
+    if !output.format.is_empty() && !from_cli("format") {
+        args.format = output.format.iter().map(|f| parse_value(f)).collect::<Result<_>>()?;
+    }
+    set!(compress, output.compress.as_deref().map(str::parse).transpose()?.map(Some));
+    set!(fill_gaps, output.fill_gaps.as_deref().map(str::parse).transpose()?.map(Some));
+    set!(segment_minutes, output.segment_minutes.map(Some));
+    set!(min_free_mb, output.min_free_mb);
+    set!(bids, output.bids);
+    set!(line_frequency, output.line_frequency);
+    set!(qc_config, output.qc_config.map(Some));
+    set!(lsl, output.lsl);
+    set!(lsl_name, output.lsl_name);
+    set!(ws_port, output.ws_port.map(Some));
+    set!(ws_bind, output.ws_bind);
+    set!(zmq_pub, output.zmq_pub.map(Some));
+    set!(osc, output.osc.map(Some));
+    set!(osc_address, output.osc_address);
+    set!(osc_data, output.osc_data.as_deref().map(parse_value::<OscData>).transpose()?);
+
+    info!("Loaded experiment config {:?}", path);
+    args.experiment = Some(Experiment {
+        info,
+        session: SessionPlan::new(&protocol, &args.configured_paradigm)?,
+    });
+    Ok(())
+}
+
+/// Load --paradigm and fill in the trial flags it sets and the command
+/// line does not
+fn apply_paradigm(args: &mut RecordArgs, matches: &ArgMatches) -> Result<()> {
+    let Some(path) = args.paradigm.clone() else {
+        return Ok(());
+    };
+    let paradigm = Paradigm::load(&path)?;
+    info!("Loaded paradigm {:?}: {} ({})", path, paradigm.name, paradigm.class_names().join(", "));
+    let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let trial = &paradigm.trial;
+    if let Some(duration) = trial.duration.filter(|_| !from_cli("duration")) {
+        args.duration = duration;
+    }
+    if let Some(cue_delay) = trial.cue_delay.filter(|_| !from_cli("cue_delay")) {
+        args.cue_delay = cue_delay;
+    }
+    if let Some(cues) = trial.cues.filter(|_| !from_cli("cues")) {
+        args.cues = cues;
+    }
+    args.configured_paradigm = paradigm;
+    Ok(())
+}
+
+impl RecordArgs {
+    pub fn class(&self) -> &str {
+        self.class.as_deref().unwrap_or_default()
+    }
+
+    /// ID of --class in the paradigm; `prepare` rejects unknown classes
+    pub fn class_id(&self) -> u8 {
+        self.configured_paradigm.class(self.class()).map_or(0, |class| class.id)
+    }
+
+    /// The paradigm for the metadata, with --paradigm
+    pub fn paradigm_info(&self) -> Option<ParadigmInfo> {
+        let path = self.paradigm.as_ref()?;
+        let paradigm = &self.configured_paradigm;
+        Some(ParadigmInfo {
+            name: paradigm.name.clone(),
+            task: paradigm.task.clone(),
+            file: path.display().to_string(),
+            frequency_hz: paradigm.class(self.class()).and_then(|class| class.frequency),
+        })
+    }
+
+    /// Channels of each board, all of them without --shields
+    pub fn board_channels(&self) -> usize {
+        self.channels / self.shields.len().max(1)
+    }
+
+    /// Local port shield `index` streams to
+    pub fn shield_port(&self, index: usize) -> u16 {
+        self.shields[index].port.unwrap_or(self.port.saturating_add(index as u16))
+    }
+
+    /// Shield layout for the metadata, with --shields
+    pub fn multi_board(&self) -> Option<MultiBoardInfo> {
+        if self.shields.is_empty() {
+            return None;
+        }
+        let per_board = self.board_channels();
+        let boards = self
+            .shields
+            .iter()
+            .enumerate()
+            .map(|(i, shield)| BoardChannels {
+                shield: shield.host.clone(),
+                port: self.shield_port(i),
+                first_channel: i * per_board + 1,
+                channels: per_board,
+                subject_id: self.shield_subjects.get(i).cloned(),
+            })
+            .collect();
+        Some(MultiBoardInfo {
+            boards,
+            side_by_side: self.shield_output == ShieldOutput::SideBySide,
+            board: None,
+        })
+    }
+
+    /// Simulator settings with --simulate; `run` resolves the seed first
+    pub fn simulation(&self) -> Option<SimulationInfo> {
+        self.simulate.then(|| SimulationInfo {
+            seed: self.synthetic_seed.unwrap_or_default(),
+            erd_depth: self.simulate_erd,
+            artifacts_per_minute: self.simulate_artifacts,
+            cue_seconds: self.cues.then_some(self.cue_delay),
+        })
+    }
+
+    pub fn formats(&self) -> Vec<OutputFormat> {
+        let mut formats: Vec<OutputFormat> = Vec::new();
+        for &format in &self.format {
+            if !formats.contains(&format) {
+                formats.push(format);
+            }
+        }
+        if formats.is_empty() {
+            formats.push(if self.bids { OutputFormat::Brainvision } else { OutputFormat::Csv });
+        }
+        formats
+    }
+
+    pub fn session_dir(&self) -> PathBuf {
+        if self.bids {
+            return bids::session_dir(Path::new(&self.output_dir), &self.subject_id, &self.session_id);
+        }
+        PathBuf::from(&self.output_dir)
+            .join(&self.subject_id)
+            .join(&self.session_id)
+    }
+
+    pub fn qc_criteria(&self) -> Result<QcCriteria> {
+        match &self.qc_config {
+            Some(path) => QcCriteria::load(path),
+            None => Ok(QcCriteria::default()),
+        }
+    }
+
+    pub fn worker_threads(&self) -> usize {
+        self.worker_threads
+            .unwrap_or_else(platform::default_worker_threads)
+            .max(1)
+    }
+}
+
+/// Path of a trial's data files without the extension:
+/// S01/session_01/S01_left_hand_session_01_trial_01_class_0_20250128_143022
+pub(crate) fn trial_data_path(args: &RecordArgs, class_id: u8) -> Result<PathBuf> {
+    let subject_dir = args.session_dir();
+    fs::create_dir_all(&subject_dir)?;
+
+    let timestamp = Utc::now().format("%Y%m%d_%H%M%S");
+    let filename = format!("{}_{}_{}_trial_{:02}_class_{}_{}",
+                          args.subject_id, args.class(), args.session_id, args.trial, class_id, timestamp);
+    Ok(subject_dir.join(filename))
+}
+
+/// Warn when the output disk is below --min-free-mb or too small for the
+/// trial
+pub(crate) fn check_free_space(args: &RecordArgs) {
+    if args.min_free_mb == 0 {
+        return;
+    }
+    let free = match disk::free_bytes(Path::new(&args.output_dir)) {
+        Ok(free) => free,
+        Err(e) => {
+            warn!("{:#}", e);
+            return;
+        }
+    };
+    let per_second: u64 = args
+        .formats()
+        .iter()
+        .map(|format| format.bytes_per_value() * args.channels as u64 * args.sample_rate as u64)
+        .sum();
+    info!(
+        "Output disk: {} MB free, room for about {:.1} h of recording",
+        disk::megabytes(free),
+        free as f64 / per_second.max(1) as f64 / 3600.0
+    );
+    if free < args.min_free_mb * 1024 * 1024 {
+        warn!("Only {} MB free on the output disk, below --min-free-mb {}", disk::megabytes(free), args.min_free_mb);
+    }
+    let needed = per_second * args.duration;
+    if needed > free {
+        warn!("The trial needs about {} MB but only {} MB are free", disk::megabytes(needed), disk::megabytes(free));
+    }
+}
+
+/// The session's confirmed montage when it matches the channel count,
+/// otherwise the one from --montage or --config, or the default one. With
+/// --shields, a montage of one board's channels is repeated for each.
+pub(crate) fn session_montage(args: &RecordArgs) -> Result<Montage> {
+    let session_dir = args.session_dir();
+    let configured = args.configured_montage.clone();
+    let fallback = || configured.clone().unwrap_or_else(|| Montage::default_for(args.board_channels()));
+    let montage = match Montage::load(&session_dir)? {
+        Some(montage) if fits_channels(args, &montage) => {
+            info!("Using confirmed montage from {:?}", session_dir.join(MONTAGE_FILE));
+            if configured.as_ref().is_some_and(|c| c.labels() != montage.labels()) {
+                warn!("Confirmed montage {:?} differs from the configured one, using the confirmed one", montage.labels());
+            }
+            montage
+        }
+        Some(montage) => {
+            warn!("Session montage has {} channels but recording {}, using defaults",
+                  montage.channels.len(), args.channels);
+            fallback()
+        }
+        None => fallback(),
+    };
+    if montage.channels.len() < args.channels {
+        return Ok(montage.repeat(args.shields.len()));
+    }
+    Ok(montage)
+}
+
+/// Whether a montage covers every channel, or one board's with --shields
+pub(crate) fn fits_channels(args: &RecordArgs, montage: &Montage) -> bool {
+    let len = montage.channels.len();
+    len == args.channels || (args.shields.len() > 1 && len == args.board_channels())
+}
+
+/// Artifact injector for `--inject-artifacts` / `--artifact-recording`,
+/// or `None` when neither is given
+pub(crate) fn build_injector(args: &RecordArgs, channel_labels: &[String]) -> Result<Option<ArtifactInjector>> {
+    if args.inject_artifacts.is_empty() && args.artifact_recording.is_empty() {
+        return Ok(None);
+    }
+    if args.artifact_interval <= 0.0 {
+        anyhow::bail!("--artifact-interval must be positive");
+    }
+
+    let mut segments = Vec::new();
+    for recording in &args.artifact_recording {
+        let segment = ArtifactSegment::from_recording(recording.kind, &recording.path)?;
+        info!("Replaying {} artifact from {:?} ({} samples)", recording.kind, recording.path, segment.len());
+        segments.push(segment);
+    }
+    // Synthetic templates for kinds without a recording
+    let mut rng = match args.artifact_seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    for &kind in &args.inject_artifacts {
+        if !args.artifact_recording.iter().any(|r| r.kind == kind) {
+            segments.push(ArtifactSegment::synthetic(kind, channel_labels, args.sample_rate, &mut rng));
+        }
+    }
+
+    let schedule = InjectionSchedule {
+        interval: args.artifact_interval,
+        sample_rate: args.sample_rate,
+    };
+    Ok(Some(ArtifactInjector::new(segments, schedule, args.artifact_seed)?))
+}
+
+/// Apply `--montage` and `--config` when given
+pub fn load_config(args: &mut RecordArgs, matches: &ArgMatches) -> Result<()> {
+    if let Some(path) = args.montage.clone() {
+        let montage = MontageConfig::load(&path)?.montage()?;
+        info!("Loaded montage {:?}: {}", path, montage.column_names().join(", "));
+        // The montage implies the channel count unless one is given
+        if matches.value_source("channels") != Some(ValueSource::CommandLine) {
+            args.channels = montage.channels.len();
+        }
+        args.configured_montage = Some(montage);
+    }
+    if let Some(path) = args.config.clone() {
+        apply_config(args, matches, &path)?;
+    } else {
+        apply_paradigm(args, matches)?;
+    }
+    if let Some(montage) = &args.configured_montage {
+        if !fits_channels(args, montage) {
+            anyhow::bail!("Montage has {} channels but {} are recorded", montage.channels.len(), args.channels);
+        }
+    }
+    Ok(())
+}
+
+/// Start the Prometheus endpoint that stream health metrics are scraped from
+#[cfg(feature = "metrics")]
+pub fn install_metrics_exporter(addr: SocketAddr) -> Result<()> {
+    metrics_exporter_prometheus::PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .install()?;
+    info!("Serving Prometheus metrics on http://{}/metrics", addr);
+    Ok(())
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn install_metrics_exporter(_addr: SocketAddr) -> Result<()> {
+    anyhow::bail!("Metrics support not compiled in, rebuild with --features metrics")
+}
+
+/// Start the outputs shared by every trial and check the flags against
+/// each other
+pub async fn prepare(mut args: RecordArgs) -> Result<RecordArgs> {
+    if let Some(addr) = args.metrics_addr {
+        install_metrics_exporter(addr)?;
+    }
+    if args.ws_tokens.is_some() && args.ws_port.is_none() {
+        anyhow::bail!("--ws-tokens needs --ws-port");
+    }
+    if let Some(port) = args.ws_port {
+        if !args.ws_bind.is_loopback() && args.ws_tokens.is_none() {
+            anyhow::bail!("--ws-bind {} serves the stream beyond this machine, which needs --ws-tokens", args.ws_bind);
+        }
+        let access = match &args.ws_tokens {
+            Some(tokens) => {
+                fs::create_dir_all(&args.output_dir)?;
+                let audit = args.ws_audit_log.clone().unwrap_or_else(|| Path::new(&args.output_dir).join(AUDIT_FILE));
+                info!("WebSocket clients need a token, auditing to {:?}", audit);
+                Some(AccessControl::new(TokenStore::load(tokens)?, AuditLog::open(&audit)?))
+            }
+            None => None,
+        };
+        args.ws_server = Some(WsServer::start(SocketAddr::new(args.ws_bind, port), access).await?);
+    }
+    if args.osc.is_some() && args.osc_data == OscData::BandPower && args.band_power_every.is_none() {
+        anyhow::bail!("--osc-data band-power needs --band-power-every");
+    }
+    if args.zmq_pub.is_some() && !cfg!(feature = "zmq") {
+        anyhow::bail!("ZeroMQ support not compiled in, rebuild with --features zmq");
+    }
+    #[cfg(feature = "zmq")]
+    if let Some(endpoint) = &args.zmq_pub {
+        let publisher = ZmqPublisher::bind(endpoint)?;
+        // Predictions WebSocket clients send go out on the predictions topic too
+        if let Some(server) = &args.ws_server {
+            let (mut predictions, publisher) = (server.predictions(), publisher.clone());
+            tokio::spawn(async move {
+                loop {
+                    match predictions.recv().await {
+                        Ok(prediction) => publisher.prediction(&prediction),
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+                    }
+                }
+            });
+        }
+        args.zmq_publisher = Some(publisher);
+    }
+
+    if args.simulate || matches!(args.transport, Transport::Synthetic) {
+        // Drawn once so every stream of the run and the metadata agree
+        args.synthetic_seed.get_or_insert_with(rand::random);
+    }
+
+    if args.on_artifact != ArtifactAction::Mark && !(args.detect_artifacts && args.duration > 0) {
+        anyhow::bail!("--on-artifact abort/repeat needs --detect-artifacts and a --duration");
+    }
+    if args.cue_beep && !args.cues {
+        anyhow::bail!("--cue-beep needs --cues");
+    }
+    let paradigm = &args.configured_paradigm;
+    if let Some(class) = &args.class {
+        if paradigm.class(class).is_none() {
+            anyhow::bail!("Unknown class '{}', the {} paradigm has {}", class, paradigm.name, paradigm.class_names().join(", "));
+        }
+    }
+    if paradigm.trial.stimuli.is_some() && !args.cues {
+        warn!("The {} paradigm flashes its stimuli after the cue, without --cues there are none", paradigm.name);
+    }
+    if !args.hotkey.is_empty() && !args.keys {
+        anyhow::bail!("--hotkey needs --keys");
+    }
+    if args.lsl && !cfg!(feature = "lsl") {
+        anyhow::bail!("LSL support not compiled in, rebuild with --features lsl (needs liblsl)");
+    }
+    if args.formats().contains(&OutputFormat::Hdf5) && !cfg!(feature = "hdf5") {
+        anyhow::bail!("HDF5 support not compiled in, rebuild with --features hdf5 (needs libhdf5)");
+    }
+
+    if args.cues && (args.cue_delay < 0.0 || (args.duration > 0 && args.cue_delay >= args.duration as f64)) {
+        anyhow::bail!("--cue-delay must be at least 0 and shorter than the {} s trial, got {}", args.duration, args.cue_delay);
+    }
+
+    if args.compress.is_some() && !args.formats().iter().any(|f| matches!(f, OutputFormat::Csv | OutputFormat::Npz)) {
+        warn!("--compress only applies to CSV and NPZ files, this run writes neither");
+    }
+    if args.fill_gaps.is_some() && (!matches!(args.transport, Transport::Wifi) || args.simulate) {
+        warn!("--fill-gaps only applies to the WiFi shield, whose timestamps show gaps");
+    }
+    if !args.shields.is_empty() {
+        let shields = args.shields.len();
+        if shields < 2 {
+            anyhow::bail!("--shields needs at least two shields, use --shield-ip for one");
+        }
+        if !matches!(args.transport, Transport::Wifi) || args.simulate {
+            anyhow::bail!("--shields records WiFi shields, it cannot be used with --transport {:?} or --simulate", args.transport);
+        }
+        if !args.channels.is_multiple_of(shields) {
+            anyhow::bail!("--channels {} does not split evenly over {} shields", args.channels, shields);
+        }
+        if !args.shield_subjects.is_empty() && args.shield_subjects.len() != shields {
+            anyhow::bail!("--shield-subjects lists {} subjects for {} shields", args.shield_subjects.len(), shields);
+        }
+        if args.shield_output == ShieldOutput::SideBySide && args.bids {
+            anyhow::bail!("--shield-output side-by-side cannot be used with --bids");
+        }
+    } else if args.shield_output != ShieldOutput::Merged || !args.shield_subjects.is_empty() {
+        anyhow::bail!("--shield-output and --shield-subjects need --shields");
+    }
+    if !args.shield_subjects.is_empty() && args.shield_output != ShieldOutput::SideBySide {
+        anyhow::bail!("--shield-subjects needs --shield-output side-by-side");
+    }
+    if let Some(minutes) = args.segment_minutes {
+        if minutes <= 0.0 || !minutes.is_finite() {
+            anyhow::bail!("--segment-minutes must be positive, got {}", minutes);
+        }
+        if args.bids || args.shield_output == ShieldOutput::SideBySide {
+            anyhow::bail!("--segment-minutes cannot be used with --bids or --shield-output side-by-side");
+        }
+    }
+    if args.bids && !args.monitor && !matches!(args.formats()[..], [OutputFormat::Bdf | OutputFormat::Edf | OutputFormat::Brainvision]) {
+        anyhow::bail!("BIDS-EEG runs have one data file in BDF, EDF or BrainVision, use --format bdf, edf or brainvision");
+    }
+
+    Ok(args)
+}
+
+/// Stream to the live outputs until interrupted or --duration is over,
+/// without trial files
+pub async fn run_monitor(args: &RecordArgs) -> Result<()> {
+    if args.cues && args.class.is_none() {
+        anyhow::bail!("--cues needs --class to show");
+    }
+    info!("=== Monitoring {} ===", args.subject_id);
+    let board = connect_board(args).await?;
+    let settings = query_board(args, board.as_ref()).await?;
+    let check = if args.signal_check {
+        Some(run_signal_check(args, board.as_ref()).await?)
+    } else {
+        None
+    };
+    let mut collector = DataCollector::new(args, board, settings)?;
+    collector.metadata.impedance_kohm = check.and_then(|c| c.impedances());
+
+    let recording = shutdown::Recording::start();
+    let collected = collector.collect_data(args.duration).await;
+    let closed = collector.close();
+    drop(recording);
+    collected.and(closed)
+}
+
+/// Print the trial's ERD quick-look and the lateralization of every class
+/// in `session_dir` so far
+pub(crate) fn log_quick_look(metadata: &TrialMetadata, session_dir: &Path) -> Result<()> {
+    let Some(erd) = &metadata.erd else {
+        return Ok(());
+    };
+    let baseline = match erd.baseline.as_deref() {
+        Some("fixation") => "vs fixation",
+        Some(_) => "vs rest trials",
+        None => "no rest baseline yet",
+    };
+    info!(
+        "ERD quick-look ({}, {:.1} s window): {}",
+        baseline,
+        erd.window_seconds,
+        BandPowers(erd.channels.clone())
+    );
+    let Some(index) = erd::lateralization(erd) else {
+        return Ok(());
+    };
+    match erd::expected_sign(&metadata.class_label) {
+        Some(sign) if index * sign > 0.0 => {
+            info!("Mu lateralization C3-C4 {:+.0} points, as expected for {}", index, metadata.class_label)
+        }
+        Some(_) => warn!("Mu lateralization C3-C4 {:+.0} points, opposite to what {} should give", index, metadata.class_label),
+        None => info!("Mu lateralization C3-C4 {:+.0} points", index),
+    }
+
+    let trials: Vec<TrialMetadata> = qc::read_trial_metadata(session_dir)?.into_iter().map(|(_, m)| m).collect();
+    let classes = erd::class_lateralization(&trials);
+    let summary: Vec<String> = classes
+        .iter()
+        .map(|(class, (mean, count))| format!("{} {:+.0} (n={})", class, mean, count))
+        .collect();
+    info!("Session lateralization so far: {}", summary.join(", "));
+    if let (Some((left, _)), Some((right, _))) = (classes.get("left_hand"), classes.get("right_hand")) {
+        if left > right {
+            info!("Left and right hand separate by {:.0} points", left - right);
+        } else {
+            warn!("Left and right hand do not separate yet ({:+.0} vs {:+.0}), left should lateralize above right", left, right);
+        }
+    }
+    Ok(())
+}
+
+/// Write each shield's channels of a finished trial as a trial of its own,
+/// in the session directory of the shield's subject. The data file names
+/// and metadata get a `_b<board>` suffix; returns the metadata paths.
+fn split_shields(args: &RecordArgs, metadata_path: &Path) -> Result<Vec<PathBuf>> {
+    let merged = Recording::load(metadata_path)?;
+    let per_board = args.board_channels();
+    let mut written = Vec::new();
+    for (board, shield) in args.shields.iter().enumerate() {
+        let mut board_args = args.clone();
+        if let Some(subject) = args.shield_subjects.get(board) {
+            board_args.subject_id = subject.clone();
+        }
+        let suffix = format!("_b{}", board + 1);
+        let columns = board * per_board..(board + 1) * per_board;
+        // Names the repeated montage gave every board's channels
+        let channel_names: Vec<String> = merged.channel_names[columns.clone()]
+            .iter()
+            .map(|name| name.strip_suffix(&suffix).unwrap_or(name).to_string())
+            .collect();
+
+        let mut metadata = recording::read_metadata(metadata_path)?;
+        metadata.subject_id = board_args.subject_id.clone();
+        metadata.num_channels = per_board;
+        metadata.electrode_config.channels = channel_names.clone();
+        metadata.impedance_kohm = metadata.impedance_kohm.map(|z| z[columns.clone()].to_vec());
+        if let Some(montage) = &mut metadata.montage {
+            montage.channels = montage.channels[columns.clone()].to_vec();
+            for (i, assignment) in montage.channels.iter_mut().enumerate() {
+                assignment.channel = i + 1;
+                let name = assignment.name.take().map(|n| n.strip_suffix(&suffix).unwrap_or(&n).to_string());
+                assignment.name = name.filter(|n| *n != montage::column_name(&assignment.label));
+            }
+        }
+        if let Some(info) = &mut metadata.multi_board {
+            info.board = Some(board + 1);
+        }
+
+        let stem = trial_data_path(&board_args, metadata.class_id)?;
+        let stem = stem.with_file_name(format!("{}{}", stem.file_name().unwrap_or_default().to_string_lossy(), suffix));
+        let mut sinks = Vec::new();
+        for format in args.formats() {
+            let path = match format {
+                OutputFormat::Csv => compress::compressed_path(&stem.with_extension("csv"), args.compress),
+                _ => stem.with_extension(format.extension()),
+            };
+            sinks.push(open_sink(format, path, &metadata, &channel_names, args.compress)?);
+        }
+        let mut data_files = sinks
+            .iter()
+            .filter_map(|sink| sink.data_file()?.file_name())
+            .map(|name| name.to_string_lossy().into_owned());
+        metadata.data_file = data_files.next();
+        metadata.other_data_files = data_files.collect();
+
+        let samples = merged.samples.iter().map(|row| row[columns.clone()].to_vec()).collect();
+        write_rows(&mut sinks, &merged.timestamps, samples, &merged.markers)?;
+        for sink in &mut sinks {
+            sink.finalize(&metadata)?;
+        }
+        let path = board_args.session_dir().join(format!(
+            "{}_{}_trial_{:02}_class_{}{}_metadata.json",
+            metadata.subject_id, metadata.class_label, metadata.trial_number, metadata.class_id, suffix
+        ));
+        if let Some(host_clock) = &mut metadata.host_clock {
+            // Rows are the same for every shield's channels
+            let name = format!("{}_{}", stem.file_name().unwrap_or_default().to_string_lossy(), HOST_TIMES_SUFFIX);
+            fs::copy(metadata_path.with_file_name(&host_clock.file), board_args.session_dir().join(&name))?;
+            host_clock.file = name;
+        }
+        metadata.checksums = checksum::data_files(&board_args.session_dir(), &metadata)?;
+        fs::write(&path, serde_json::to_string_pretty(&metadata)?)?;
+        events::write_events(&Recording::load(&path)?)?;
+        info!("Saved the channels of shield {} ({}) to {:?}", board + 1, shield.host, path);
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Record one trial of `--class`; returns the metadata of what was kept
+pub async fn record_trial(args: &RecordArgs) -> Result<Vec<PathBuf>> {
+    info!("=== OpenBCI Motor Imagery Data Collector ===");
+    info!("Subject: {}", args.subject_id);
+    info!("Session: {}", args.session_id);
+    info!("Class: {} (ID: {})", args.class(), args.class_id());
+    info!("Trial: {}", args.trial);
+    info!("Duration: {} seconds", args.duration);
+    info!("Output: {}", args.output_dir);
+    info!("Channels: {}", args.channels);
+    info!("");
+
+    let mut attempt = 0;
+    let written = loop {
+        let mut args = args.clone();
+        // A repeat of a generated signal should not be the same signal
+        args.synthetic_seed = args.synthetic_seed.map(|seed| seed.wrapping_add((attempt as u64) << 32));
+        let args = &args;
+        let board = connect_board(args).await?;
+        let settings = query_board(args, board.as_ref()).await?;
+        let environment = EnvironmentInfo::capture(board.as_ref()).await;
+        let check = if args.signal_check {
+            Some(run_signal_check(args, board.as_ref()).await?)
+        } else {
+            None
+        };
+        let mut collector = DataCollector::new(args, board, settings)?;
+        collector.metadata.environment = Some(environment);
+        collector.metadata.impedance_kohm = check.and_then(|c| c.impedances());
+
+        let recording = shutdown::Recording::start();
+        match collector.collect_data(args.duration).await {
+            Ok(_) if collector.metadata.interrupted => {
+                warn!("Data collection interrupted, keeping what was recorded");
+            }
+            Ok(_) => {
+                info!("Data collection completed successfully");
+            }
+            Err(e) => {
+                error!("Error during collection: {}", e);
+            }
+        }
+
+        let metadata_path = collector.finalize(&args.output_dir)?;
+        drop(recording);
+        let aborted = collector.metadata.artifact_detection.as_ref().is_some_and(|d| d.aborted);
+        let repeat = aborted && args.on_artifact == ArtifactAction::Repeat && !shutdown::requested();
+        if repeat && attempt == args.max_repeats {
+            warn!("Trial still too contaminated after {} repeats, keeping the last attempt", attempt);
+        }
+        if !repeat || attempt == args.max_repeats {
+            if args.shield_output == ShieldOutput::SideBySide {
+                let written = split_shields(args, &metadata_path)?;
+                collector.discard(&metadata_path)?;
+                break written;
+            }
+            break vec![metadata_path];
+        }
+        attempt += 1;
+        collector.discard(&metadata_path)?;
+        warn!("Discarded the trial, recording it again ({}/{})", attempt, args.max_repeats);
+    };
+    info!("=== Collection Complete ===");
+    Ok(written)
+}
@@ -0,0 +1,501 @@
+//! Loading recorded trials (metadata JSON + CSV, BDF, NPZ, BrainVision or
+//! HDF5) back from disk.
+//! CSV files may be compressed, see [`crate::compress`]. The segments of a
+//! rotated recording are loaded as one.
+
+use crate::bdf::BdfData;
+use crate::brainvision::BrainVisionData;
+use crate::compress;
+#[cfg(feature = "hdf5")]
+use crate::hdf5::Hdf5Trial;
+use crate::metadata::TrialMetadata;
+use crate::montage::Montage;
+use crate::npz::NpzTrial;
+use crate::qc;
+use anyhow::{bail, Context, Result};
+use eeg_dsp::{ChannelInterpolation, EpochStatistics, Reference, Rereference, Thresholding, Wavelet};
+use log::{info, warn};
+use ndarray::Array2;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// CSV columns that are not EEG channels
+const NON_CHANNEL_COLUMNS: &[&str] = &["timestamp", "sample_id", "class_id", "marker"];
+
+/// Windows bad channels are judged over
+const BAD_CHANNEL_WINDOW_SECONDS: f64 = 1.0;
+
+/// A channel is bad when it correlates with no other in more than this
+/// share of its windows
+const BAD_WINDOW_FRACTION: f64 = 0.5;
+
+/// A recorded trial loaded into memory
+#[derive(Debug)]
+pub struct Recording {
+    pub metadata_path: PathBuf,
+    pub data_path: PathBuf,
+    pub metadata: TrialMetadata,
+    pub channel_names: Vec<String>,
+    pub timestamps: Vec<f64>,
+    /// Row-major samples, `samples[i][channel]`
+    pub samples: Vec<Vec<f32>>,
+    /// Marker labels per row (empty when none)
+    pub markers: Vec<String>,
+}
+
+impl Recording {
+    /// Load a trial from its `*_metadata.json` file
+    pub fn load(metadata_path: &Path) -> Result<Self> {
+        let metadata = read_metadata(metadata_path)?;
+        let data_path = find_data_file(metadata_path, &metadata)?;
+        Self::from_segments(metadata_path, data_path, metadata)
+    }
+
+    /// Load a trial from its metadata file or from one of its data files,
+    /// which need not be the primary one
+    pub fn open(path: &Path) -> Result<Self> {
+        if path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.ends_with("_metadata.json")) {
+            return Self::load(path);
+        }
+        let metadata_path = metadata_for(path)?;
+        let metadata = read_metadata(&metadata_path)?;
+        Self::from_segments(&metadata_path, path.to_path_buf(), metadata)
+    }
+
+    /// Load `data_path` and, for a rotated recording, the files of the
+    /// same format in the later segments after it
+    fn from_segments(metadata_path: &Path, data_path: PathBuf, metadata: TrialMetadata) -> Result<Self> {
+        if metadata.segments.is_empty() {
+            let rows = metadata.written_samples();
+            return Self::from_file(metadata_path, data_path, metadata, 0, rows);
+        }
+        let name = data_path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        let Some(format) = metadata.segments.iter().find_map(|s| s.files.iter().position(|f| f == name)) else {
+            bail!("{:?} is not one of the segments of {:?}", data_path, metadata_path);
+        };
+        let dir = data_path.parent().unwrap_or(Path::new("."));
+        let mut recording: Option<Self> = None;
+        for segment in &metadata.segments {
+            let path = dir.join(segment.files.get(format).context("Segment lacks a data file")?);
+            let part = Self::from_file(metadata_path, path, metadata.clone(), segment.first_sample, segment.samples)?;
+            match &mut recording {
+                None => recording = Some(part),
+                Some(recording) => {
+                    if part.channel_names != recording.channel_names {
+                        bail!("Segment {:?} has other channels than the first", part.data_path);
+                    }
+                    recording.timestamps.extend(part.timestamps);
+                    recording.samples.extend(part.samples);
+                    recording.markers.extend(part.markers);
+                }
+            }
+        }
+        let mut recording = recording.context("Recording has no segments")?;
+        recording.data_path = data_path;
+        Ok(recording)
+    }
+
+    /// Load one data file whose first row is sample `first_sample`, of
+    /// `rows` rows at most where the format pads them
+    fn from_file(
+        metadata_path: &Path,
+        data_path: PathBuf,
+        metadata: TrialMetadata,
+        first_sample: u64,
+        rows: u64,
+    ) -> Result<Self> {
+        if data_path.extension().is_some_and(|e| e.eq_ignore_ascii_case("bdf") || e.eq_ignore_ascii_case("edf")) {
+            return Self::from_bdf(metadata_path, data_path, metadata, first_sample, rows);
+        }
+        if data_path.extension().is_some_and(|e| e.eq_ignore_ascii_case("npz")) {
+            return Self::from_npz(metadata_path, data_path, metadata, first_sample);
+        }
+        if data_path.extension().is_some_and(|e| e.eq_ignore_ascii_case("vhdr")) {
+            return Self::from_brainvision(metadata_path, data_path, metadata, first_sample);
+        }
+        if data_path.extension().is_some_and(|e| e.eq_ignore_ascii_case("h5")) {
+            #[cfg(feature = "hdf5")]
+            return Self::from_hdf5(metadata_path, data_path, metadata, first_sample);
+            #[cfg(not(feature = "hdf5"))]
+            bail!("{:?} is HDF5, rebuild with --features hdf5 (needs libhdf5) to read it", data_path);
+        }
+
+        let mut reader = csv::Reader::from_reader(compress::open(&data_path)?);
+        let headers = reader.headers()?.clone();
+
+        let channel_columns: Vec<usize> = headers
+            .iter()
+            .enumerate()
+            .filter(|(_, h)| !NON_CHANNEL_COLUMNS.contains(h))
+            .map(|(i, _)| i)
+            .collect();
+        let channel_names = channel_columns.iter().map(|&i| headers[i].to_string()).collect();
+        let timestamp_column = headers.iter().position(|h| h == "timestamp");
+        let marker_column = headers.iter().position(|h| h == "marker");
+
+        let mut timestamps = Vec::new();
+        let mut samples = Vec::new();
+        let mut markers = Vec::new();
+        for record in reader.records() {
+            let record = record?;
+            let row = channel_columns
+                .iter()
+                .map(|&i| record[i].parse::<f32>().unwrap_or(f32::NAN))
+                .collect();
+            samples.push(row);
+            timestamps.push(
+                timestamp_column
+                    .and_then(|i| record[i].parse().ok())
+                    .unwrap_or(f64::NAN),
+            );
+            markers.push(marker_column.map(|i| record[i].to_string()).unwrap_or_default());
+        }
+
+        Ok(Self {
+            metadata_path: metadata_path.to_path_buf(),
+            data_path,
+            metadata,
+            channel_names,
+            timestamps,
+            samples,
+            markers,
+        })
+    }
+
+    /// BDF stores no per-sample timestamps, so they are reconstructed from
+    /// the start time; markers come from the annotation signal
+    fn from_bdf(
+        metadata_path: &Path,
+        data_path: PathBuf,
+        metadata: TrialMetadata,
+        first_sample: u64,
+        rows: u64,
+    ) -> Result<Self> {
+        let mut bdf = BdfData::read(&data_path)?;
+        // Drop the zero padding of the last data record
+        bdf.samples.truncate(rows as usize);
+
+        let start = metadata.start_time.timestamp_millis() as f64 / 1000.0;
+        let timestamps = (0..bdf.samples.len())
+            .map(|i| start + (first_sample as usize + i) as f64 / bdf.sample_rate)
+            .collect();
+        let mut markers = vec![String::new(); bdf.samples.len()];
+        for (onset, label) in bdf.annotations {
+            let row = (onset * bdf.sample_rate).round() as usize;
+            if let Some(slot) = markers.get_mut(row) {
+                if !slot.is_empty() {
+                    slot.push('|');
+                }
+                slot.push_str(&label);
+            }
+        }
+
+        Ok(Self {
+            metadata_path: metadata_path.to_path_buf(),
+            data_path,
+            metadata,
+            channel_names: bdf.labels,
+            timestamps,
+            samples: bdf.samples,
+            markers,
+        })
+    }
+
+    /// NPZ trials carry no timestamps either; markers come from the
+    /// metadata's sample IDs
+    fn from_npz(metadata_path: &Path, data_path: PathBuf, metadata: TrialMetadata, first_sample: u64) -> Result<Self> {
+        let npz = NpzTrial::read(&data_path)?;
+        let num_channels = metadata.electrode_config.channels.len();
+        if npz.samples.first().is_some_and(|row| row.len() != num_channels) {
+            bail!("{:?} has {} channels but its metadata lists {}", data_path, npz.samples[0].len(), num_channels);
+        }
+        let start = metadata.start_time.timestamp_millis() as f64 / 1000.0;
+        let rate = metadata.effective_sample_rate().max(1.0);
+        let timestamps = (0..npz.samples.len())
+            .map(|i| start + (first_sample as usize + i) as f64 / rate)
+            .collect();
+        let markers = marker_rows(&metadata, first_sample, npz.samples.len());
+
+        Ok(Self {
+            metadata_path: metadata_path.to_path_buf(),
+            data_path,
+            channel_names: metadata.electrode_config.channels.clone(),
+            metadata,
+            timestamps,
+            samples: npz.samples,
+            markers,
+        })
+    }
+
+    /// BrainVision has no per-sample timestamps either; injected artifacts
+    /// come back as `artifact_start:`/`artifact_end:` markers
+    fn from_brainvision(metadata_path: &Path, data_path: PathBuf, metadata: TrialMetadata, first_sample: u64) -> Result<Self> {
+        let vhdr = BrainVisionData::read(&data_path)?;
+        let start = metadata.start_time.timestamp_millis() as f64 / 1000.0;
+        let timestamps = (0..vhdr.samples.len())
+            .map(|i| start + (first_sample as usize + i) as f64 / vhdr.sample_rate)
+            .collect();
+        let markers = vhdr.marker_labels();
+
+        Ok(Self {
+            metadata_path: metadata_path.to_path_buf(),
+            data_path,
+            metadata,
+            channel_names: vhdr.labels,
+            timestamps,
+            samples: vhdr.samples,
+            markers,
+        })
+    }
+
+    /// HDF5 trials keep their timestamps; markers come from the metadata's
+    /// sample IDs like NPZ
+    #[cfg(feature = "hdf5")]
+    fn from_hdf5(metadata_path: &Path, data_path: PathBuf, metadata: TrialMetadata, first_sample: u64) -> Result<Self> {
+        let trial = Hdf5Trial::read(&data_path)?;
+        let num_channels = metadata.electrode_config.channels.len();
+        if trial.samples.first().is_some_and(|row| row.len() != num_channels) {
+            bail!("{:?} has {} channels but its metadata lists {}", data_path, trial.samples[0].len(), num_channels);
+        }
+        let markers = marker_rows(&metadata, first_sample, trial.samples.len());
+        Ok(Self {
+            metadata_path: metadata_path.to_path_buf(),
+            data_path,
+            channel_names: metadata.electrode_config.channels.clone(),
+            metadata,
+            timestamps: trial.timestamps,
+            samples: trial.samples,
+            markers,
+        })
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.channel_names.len()
+    }
+
+    /// Channel-major copy of the samples, `data[channel][i]`
+    pub fn channel_data(&self) -> Vec<Vec<f32>> {
+        (0..self.num_channels())
+            .map(|ch| self.samples.iter().map(|row| row[ch]).collect())
+            .collect()
+    }
+
+    /// Montage labels of the channels, or their names without a montage
+    pub fn labels(&self) -> Vec<String> {
+        let montage = self.metadata.montage.as_ref().filter(|m| m.channels.len() == self.num_channels());
+        montage.map_or_else(|| self.channel_names.clone(), Montage::labels)
+    }
+
+    /// `channels x samples` copy of the samples
+    fn array(&self) -> Array2<f32> {
+        Array2::from_shape_fn((self.num_channels(), self.samples.len()), |(c, i)| self.samples[i][c])
+    }
+
+    /// Channel names and channel-major data re-referenced as `reference`,
+    /// or as the trial's montage says when `None`. Re-referenced channels
+    /// are named by their montage labels, derivations as `C3-Cz`.
+    pub fn rereferenced(&self, reference: Option<&Reference>) -> Result<(Vec<String>, Vec<Vec<f32>>)> {
+        let montage = self.metadata.montage.as_ref().filter(|m| m.channels.len() == self.num_channels());
+        let Some(reference) = reference.or(montage.and_then(|m| m.rereference.as_ref())) else {
+            return Ok((self.channel_names.clone(), self.channel_data()));
+        };
+        let labels = self.labels();
+        let rereference = Rereference::new(reference, &labels)
+            .with_context(|| format!("Cannot re-reference {:?} as {}", self.metadata_path, reference))?;
+
+        let mut data = vec![Vec::with_capacity(self.samples.len()); rereference.outputs()];
+        let mut frame = vec![0.0; rereference.outputs()];
+        for row in &self.samples {
+            rereference.apply_frame(row, &mut frame);
+            for (channel, &value) in data.iter_mut().zip(&frame) {
+                channel.push(value);
+            }
+        }
+        Ok((rereference.labels().to_vec(), data))
+    }
+
+    /// Resample the trial from its configured rate to `rate` Hz. Timestamps
+    /// are evenly spaced from the first one and each marker moves to the
+    /// nearest new sample.
+    pub fn resample(&mut self, rate: u32) -> Result<()> {
+        let from = self.metadata.sample_rate;
+        if from == rate || self.samples.is_empty() {
+            return Ok(());
+        }
+        let output = eeg_dsp::resample(self.array().view(), from, rate)
+            .with_context(|| format!("Cannot resample {:?}", self.metadata_path))?;
+        let len = output.ncols();
+        self.samples = output.columns().into_iter().map(|column| column.to_vec()).collect();
+
+        let start = self.timestamps.first().copied().unwrap_or_default();
+        self.timestamps = (0..len).map(|i| start + i as f64 / rate as f64).collect();
+        let mut markers = vec![String::new(); len];
+        for (i, marker) in self.markers.iter().enumerate().filter(|(_, m)| !m.is_empty()) {
+            let row = &mut markers[((i as f64 * rate as f64 / from as f64).round() as usize).min(len - 1)];
+            if !row.is_empty() {
+                row.push('|');
+            }
+            row.push_str(marker);
+        }
+        self.markers = markers;
+        self.metadata.sample_rate = rate;
+        self.metadata.measured_sample_rate = None;
+        Ok(())
+    }
+
+    /// Wavelet-denoise every channel over `level` levels (fewer for short
+    /// trials), see `eeg_dsp::denoise`
+    pub fn denoise(&mut self, wavelet: Wavelet, level: usize, thresholding: Thresholding) -> Result<()> {
+        for channel in 0..self.num_channels() {
+            let signal: Vec<f32> = self.samples.iter().map(|row| row[channel]).collect();
+            let cleaned = eeg_dsp::denoise(&signal, wavelet, level, thresholding)
+                .with_context(|| format!("Cannot denoise {:?}", self.metadata_path))?;
+            for (row, value) in self.samples.iter_mut().zip(cleaned) {
+                row[channel] = value;
+            }
+        }
+        Ok(())
+    }
+
+    /// Find channels whose correlation with every other stays below
+    /// `min_correlation` in most 1 s windows and rebuild them from their
+    /// nearest neighbours on the scalp; returns their names. Fails when
+    /// more than half the channels are bad or a bad one has no 10-10
+    /// position.
+    pub fn interpolate_bad_channels(&mut self, min_correlation: f64) -> Result<Vec<String>> {
+        let window = (self.metadata.effective_sample_rate() * BAD_CHANNEL_WINDOW_SECONDS).round() as usize;
+        let mut data = self.array();
+        let bad = eeg_dsp::bad_by_correlation(data.view(), window, min_correlation, BAD_WINDOW_FRACTION);
+        if bad.is_empty() {
+            return Ok(Vec::new());
+        }
+        let names: Vec<String> = bad.iter().map(|&i| self.channel_names[i].clone()).collect();
+        if 2 * bad.len() > self.num_channels() {
+            bail!("{} of {} channels are bad ({})", bad.len(), self.num_channels(), names.join(", "));
+        }
+        let interpolation = ChannelInterpolation::new(&self.labels(), &bad, eeg_dsp::interpolate::NEIGHBORS)
+            .with_context(|| format!("Cannot interpolate {:?}", self.metadata_path))?;
+        interpolation.apply(data.view_mut());
+        for (i, row) in self.samples.iter_mut().enumerate() {
+            for &channel in &bad {
+                row[channel] = data[[channel, i]];
+            }
+        }
+        Ok(names)
+    }
+
+    /// Variance and kurtosis of the whole trial as one epoch
+    pub fn epoch_statistics(&self) -> EpochStatistics {
+        EpochStatistics::of(self.array().view())
+    }
+}
+
+/// Parse a trial metadata file without loading its data
+pub fn read_metadata(metadata_path: &Path) -> Result<TrialMetadata> {
+    let text = fs::read_to_string(metadata_path)
+        .with_context(|| format!("Failed to read metadata {:?}", metadata_path))?;
+    serde_json::from_str(&text).with_context(|| format!("Failed to parse metadata {:?}", metadata_path))
+}
+
+/// Locate the CSV belonging to a metadata file. Older metadata has no
+/// `data_file`, so fall back to the newest CSV matching the naming scheme.
+fn find_data_file(metadata_path: &Path, metadata: &TrialMetadata) -> Result<PathBuf> {
+    let dir = metadata_path.parent().unwrap_or(Path::new("."));
+    if let Some(name) = &metadata.data_file {
+        return Ok(dir.join(name));
+    }
+
+    let prefix = format!(
+        "{}_{}_{}_trial_{:02}_class_{}_",
+        metadata.subject_id, metadata.class_label, metadata.session_id, metadata.trial_number, metadata.class_id
+    );
+    let mut candidates: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(&prefix) && n.ends_with(".csv"))
+        })
+        .collect();
+    candidates.sort();
+    candidates
+        .pop()
+        .with_context(|| format!("No data file found for {:?}", metadata_path))
+}
+
+/// Recursively find every trial metadata file under `root`, skipping
+/// sessions that failed QC unless `include_failed_qc` is set
+pub fn find_trials(root: &Path, include_failed_qc: bool) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut trials = Vec::new();
+        for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read {:?}", dir))? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.ends_with("_metadata.json"))
+            {
+                trials.push(path);
+            }
+        }
+
+        if trials.is_empty() {
+            continue;
+        }
+        if !include_failed_qc && !qc::session_passed(&dir) {
+            warn!("Skipping {:?}: session failed QC", dir);
+            continue;
+        }
+        found.extend(trials);
+    }
+
+    found.sort();
+    info!("Found {} trials under {:?}", found.len(), root);
+    Ok(found)
+}
+
+/// The metadata file of the trial a data file belongs to, found among the
+/// `*_metadata.json` files next to it
+pub fn metadata_for(data_path: &Path) -> Result<PathBuf> {
+    let name = data_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .with_context(|| format!("{:?} is not a file", data_path))?;
+    let dir = data_path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {:?}", dir))? {
+        let path = entry?.path();
+        if !path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.ends_with("_metadata.json")) {
+            continue;
+        }
+        let Ok(metadata) = read_metadata(&path) else {
+            continue;
+        };
+        if metadata.all_data_files().iter().any(|f| *f == name) {
+            return Ok(path);
+        }
+    }
+    bail!("No trial metadata in {:?} lists {}", dir, name)
+}
+
+/// Marker labels per row from the metadata's sample IDs, for formats that
+/// do not store them
+fn marker_rows(metadata: &TrialMetadata, first_sample: u64, rows: usize) -> Vec<String> {
+    let mut markers = vec![String::new(); rows];
+    for marker in &metadata.markers {
+        let Some(row) = marker.sample_id.checked_sub(first_sample) else {
+            continue;
+        };
+        if let Some(slot) = markers.get_mut(row as usize) {
+            if !slot.is_empty() {
+                slot.push('|');
+            }
+            slot.push_str(&marker.label);
+        }
+    }
+    markers
+}
@@ -0,0 +1,229 @@
+//! Re-stream recorded trials in real time.
+//!
+//! Trials are played back at their recorded sample rate with their recorded
+//! timestamps, so online classifiers (and the robot they drive) can be
+//! tested deterministically against known data. TCP clients get the WiFi
+//! shield's JSON chunk lines, and `--gui-udp` speaks the OpenBCI GUI
+//! networking format. Run as `openbci replay`.
+
+use crate::gui_bridge::GuiBridge;
+use crate::recording;
+use crate::source::{DataSource, ReplaySource};
+use anyhow::{bail, Result};
+use clap::Args;
+use log::{info, warn};
+use openbci_wifi_client::Sample;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+
+/// TCP chunks per second, like the shield at its default latency
+const CHUNKS_PER_SECOND: u32 = 25;
+/// Chunks a slow client may fall behind before it skips ahead
+const CLIENT_BACKLOG: usize = 256;
+
+/// Options of `openbci replay`
+#[derive(Args, Debug, Clone)]
+pub struct ReplayArgs {
+    /// Trials to play, in order: metadata JSON, a data file (CSV, EDF, BDF,
+    /// BrainVision, NPZ) or a dataset directory
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// Serve shield-format JSON lines to TCP clients on this address
+    #[arg(long, value_name = "ADDR")]
+    pub tcp: Option<SocketAddr>,
+
+    /// Also send OpenBCI GUI networking packets to this UDP address
+    #[arg(long, value_name = "ADDR")]
+    pub gui_udp: Option<SocketAddr>,
+
+    /// Playback speed relative to the recorded sample rate
+    #[arg(long, default_value = "1.0")]
+    pub speed: f64,
+
+    /// Start over after the last trial until interrupted
+    #[arg(long = "loop")]
+    pub repeat: bool,
+
+    /// Start playing right away instead of waiting for the first TCP client
+    #[arg(long)]
+    pub no_wait: bool,
+
+    /// Also play trials from sessions that failed QC (directories only)
+    #[arg(long)]
+    pub include_failed_qc: bool,
+}
+
+/// One line of the shield's TCP JSON output
+#[derive(Serialize)]
+struct Chunk<'a> {
+    chunk: &'a [Sample],
+}
+
+/// Broadcasts lines to every connected TCP client
+struct TcpServer {
+    lines: broadcast::Sender<Arc<str>>,
+    accept: JoinHandle<()>,
+    clients: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl TcpServer {
+    /// Listen on `addr`, and unless `wait` is false return only once the
+    /// first client is connected
+    async fn start(addr: SocketAddr, wait: bool) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let (lines, _) = broadcast::channel(CLIENT_BACKLOG);
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        if wait {
+            info!("Waiting for a client on tcp://{}", addr);
+            let (socket, peer) = listener.accept().await?;
+            clients.lock().unwrap().push(spawn_client(socket, peer, lines.subscribe()));
+        } else {
+            info!("Serving tcp://{}", addr);
+        }
+
+        let accept = {
+            let (lines, clients) = (lines.clone(), Arc::clone(&clients));
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((socket, peer)) => clients.lock().unwrap().push(spawn_client(socket, peer, lines.subscribe())),
+                        Err(e) => warn!("Failed to accept a client: {}", e),
+                    }
+                }
+            })
+        };
+        Ok(Self { lines, accept, clients })
+    }
+
+    fn send(&self, samples: &[Sample]) -> Result<()> {
+        let mut line = serde_json::to_string(&Chunk { chunk: samples })?;
+        line.push('\n');
+        // No receivers just means nobody is connected right now
+        let _ = self.lines.send(line.into());
+        Ok(())
+    }
+
+    /// Let every client write what is queued, then disconnect them
+    async fn close(self) {
+        self.accept.abort();
+        drop(self.lines);
+        let clients = std::mem::take(&mut *self.clients.lock().unwrap());
+        for client in clients {
+            let _ = client.await;
+        }
+    }
+}
+
+fn spawn_client(mut socket: TcpStream, peer: SocketAddr, mut lines: broadcast::Receiver<Arc<str>>) -> JoinHandle<()> {
+    info!("Client {} connected", peer);
+    tokio::spawn(async move {
+        loop {
+            match lines.recv().await {
+                Ok(line) => {
+                    if let Err(e) = socket.write_all(line.as_bytes()).await {
+                        info!("Client {} disconnected: {}", peer, e);
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => warn!("Client {} fell behind, {} chunks skipped", peer, skipped),
+                Err(RecvError::Closed) => return,
+            }
+        }
+    })
+}
+
+/// Expand directories into their trials; files are taken as given
+pub fn resolve(paths: &[PathBuf], include_failed_qc: bool) -> Result<Vec<PathBuf>> {
+    let mut trials = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            trials.extend(recording::find_trials(path, include_failed_qc)?);
+        } else if path.exists() {
+            trials.push(path.clone());
+        } else {
+            bail!("{:?} does not exist", path);
+        }
+    }
+    if trials.is_empty() {
+        bail!("No trials found");
+    }
+    Ok(trials)
+}
+
+/// Play one trial to the outputs in real time; returns the samples sent
+async fn play(path: &Path, args: &ReplayArgs, tcp: Option<&TcpServer>) -> Result<usize> {
+    let mut source = ReplaySource::open(path, args.speed)?.keep_timestamps();
+    let mut gui = match args.gui_udp {
+        Some(addr) => Some(GuiBridge::connect(addr, source.num_channels(), source.sample_rate())?),
+        None => None,
+    };
+    let per_chunk = (source.sample_rate() / CHUNKS_PER_SECOND).max(1) as usize;
+    let mut chunk = Vec::with_capacity(per_chunk);
+    let mut sent = 0;
+
+    while let Some(sample) = source.next_sample().await {
+        if let Some(gui) = &mut gui {
+            gui.push(&sample.channels);
+        }
+        // The shield stamps milliseconds
+        chunk.push(Sample {
+            data: sample.channels,
+            timestamp: sample.timestamp * 1000.0,
+        });
+        sent += 1;
+        if chunk.len() == per_chunk {
+            if let Some(tcp) = tcp {
+                tcp.send(&chunk)?;
+            }
+            chunk.clear();
+        }
+    }
+    if let (Some(tcp), false) = (tcp, chunk.is_empty()) {
+        tcp.send(&chunk)?;
+    }
+    if let Some(gui) = &mut gui {
+        gui.flush();
+    }
+    Ok(sent)
+}
+
+/// Play the trials to the outputs, once or until interrupted
+pub async fn run(args: &ReplayArgs) -> Result<()> {
+    if args.tcp.is_none() && args.gui_udp.is_none() {
+        bail!("Nothing to stream to, give --tcp and/or --gui-udp");
+    }
+    if args.speed <= 0.0 {
+        bail!("--speed must be positive, got {}", args.speed);
+    }
+    let trials = resolve(&args.paths, args.include_failed_qc)?;
+
+    let tcp = match args.tcp {
+        Some(addr) => Some(TcpServer::start(addr, !args.no_wait).await?),
+        None => None,
+    };
+
+    loop {
+        for path in &trials {
+            info!("Replaying {:?}", path);
+            match play(path, args, tcp.as_ref()).await {
+                Ok(sent) => info!("Sent {} samples from {:?}", sent, path),
+                Err(e) => warn!("Skipping {:?}: {}", path, e),
+            }
+        }
+        if !args.repeat {
+            break;
+        }
+    }
+
+    if let Some(tcp) = tcp {
+        tcp.close().await;
+    }
+    Ok(())
+}
@@ -0,0 +1,830 @@
+//! Dataset quality report, run as `openbci report`.
+//!
+//! Summarizes a recording tree before training: trials per class and
+//! session with their QC verdicts, trial lengths, detected, injected and
+//! flagged artifacts, stream losses, and per-channel RMS and mean power
+//! spectrum. Findings point out what would hurt a model: imbalanced
+//! classes, unreadable or inconsistent trials, flat or noisy channels,
+//! lossy sessions. The report is printed as tables and can be written as
+//! JSON and as a standalone HTML page with the spectra plotted.
+
+use crate::compute::ComputeArgs;
+use crate::dataset;
+use crate::events::BAD_TRIAL;
+use eeg_dsp::{motor_imagery_bands, welch, Band, SpectralParams};
+use ndarray::{aview1, Axis};
+use crate::montage;
+use crate::qc::{QcCriteria, SessionManifest};
+use crate::recording::{self, Recording};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::Args;
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const NV_PER_UV: f64 = 1000.0;
+/// Trial RMS under this is reported as a flat channel
+const FLAT_RMS_UV: f64 = 0.5;
+/// A channel whose median RMS is this many times above or below the
+/// median of all channels is reported
+const RMS_OUTLIER_FACTOR: f64 = 3.0;
+/// Problems listed by name before they are only counted
+const MAX_LISTED: usize = 10;
+
+/// Options of `openbci report`
+#[derive(Args, Debug, Clone)]
+pub struct ReportArgs {
+    /// Root of the recorded dataset
+    #[arg(short, long, default_value = "motor_imagery_data")]
+    pub data_dir: PathBuf,
+
+    /// Only these subjects (all if omitted)
+    #[arg(long, value_delimiter = ',')]
+    pub subjects: Vec<String>,
+
+    /// QC criteria JSON for every session (by default each session's own
+    /// from its manifest)
+    #[arg(long)]
+    pub qc_config: Option<PathBuf>,
+
+    /// Also write the report as JSON to this file
+    #[arg(long)]
+    pub json: Option<PathBuf>,
+
+    /// Also write the report as an HTML page to this file
+    #[arg(long)]
+    pub html: Option<PathBuf>,
+
+    /// Mains frequency in Hz, for the line noise column
+    #[arg(long, default_value = "50")]
+    pub line_frequency: f64,
+
+    /// Report classes as imbalanced when the largest has this many times
+    /// the usable trials of the smallest
+    #[arg(long, default_value = "1.5")]
+    pub max_imbalance: f64,
+
+    #[command(flatten)]
+    pub compute: ComputeArgs,
+}
+
+/// The whole report, as written to `--json`
+#[derive(Debug, Serialize)]
+pub struct DatasetReport {
+    pub created: DateTime<Utc>,
+    pub data_dir: PathBuf,
+    pub trials: usize,
+    /// Trials that could not be loaded
+    pub unreadable: Vec<String>,
+    /// Trials whose data disagrees with their metadata
+    pub invalid: Vec<String>,
+    pub classes: Vec<ClassSummary>,
+    pub sessions: Vec<SessionSummary>,
+    pub samples_per_trial: TrialLengths,
+    pub artifacts: ArtifactSummary,
+    pub stream: StreamSummary,
+    /// Bands of the channels' `band_power_uv2`, the mains band last
+    pub bands: Vec<Band>,
+    pub channels: Vec<ChannelSummary>,
+    /// Mean power spectrum of every channel over the trials at the most
+    /// common sample rate
+    pub psd: Option<Spectrum>,
+    pub findings: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClassSummary {
+    pub class_label: String,
+    pub class_id: u8,
+    pub trials: usize,
+    pub usable: usize,
+    pub seconds: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionSummary {
+    pub subject_id: String,
+    pub session_id: String,
+    pub trials: usize,
+    pub usable: usize,
+    pub passed: bool,
+    pub failures: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TrialLengths {
+    pub min: usize,
+    pub median: usize,
+    pub max: usize,
+    /// Trials with fewer samples than their rate and duration promise
+    pub short_trials: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ArtifactSummary {
+    /// Trials the online detectors marked anything in
+    pub trials_with_detections: usize,
+    /// Mean fraction of the detected trials' samples marked
+    pub mean_contaminated_fraction: f64,
+    /// Trials stopped for artifacts
+    pub aborted: usize,
+    /// Trials with artifacts mixed in, and how many
+    pub injected_trials: usize,
+    pub injected: usize,
+    /// Trials flagged bad with the hotkey
+    pub flagged_bad: usize,
+    pub interrupted: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct StreamSummary {
+    pub dropped_packets: u64,
+    pub missing_samples: u64,
+    pub reconnects: u64,
+    pub mean_drop_rate: f64,
+    pub max_drop_rate: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelSummary {
+    pub name: String,
+    pub trials: usize,
+    /// Median and largest RMS over the trials, mean removed
+    pub median_rms_uv: f64,
+    pub max_rms_uv: f64,
+    /// Trials the channel is flat in
+    pub flat_trials: usize,
+    /// Powers of the mean spectrum in the report's bands, µV²
+    pub band_power_uv2: Vec<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Spectrum {
+    pub sample_rate: u32,
+    pub trials: usize,
+    pub frequencies: Vec<f64>,
+    /// µV²/Hz per channel, in the order of `channels`
+    pub channels: Vec<String>,
+    pub power: Vec<Vec<f64>>,
+}
+
+/// What one trial contributes
+struct TrialStats {
+    source: String,
+    subject_id: String,
+    session_id: String,
+    class_label: String,
+    class_id: u8,
+    samples: usize,
+    expected: u64,
+    seconds: f64,
+    sample_rate: u32,
+    usable: bool,
+    drop_rate: f64,
+    dropped_packets: u64,
+    missing_samples: u64,
+    reconnects: u64,
+    contaminated_fraction: Option<f64>,
+    aborted: bool,
+    injected: usize,
+    flagged_bad: bool,
+    interrupted: bool,
+    problems: Vec<String>,
+    channels: Vec<String>,
+    rms_uv: Vec<f64>,
+    /// µV²/Hz per channel, 1 s Welch segments
+    psd: Vec<Vec<f64>>,
+}
+
+fn trial_stats(rec: &Recording, source: String, session: &SessionManifest) -> TrialStats {
+    let meta = &rec.metadata;
+    let name = rec.metadata_path.file_name().unwrap_or_default().to_string_lossy();
+    let qc = session.trials.iter().find(|t| t.metadata_file == name);
+    let health = meta.stream_health.as_ref();
+    let detection = meta.artifact_detection.as_ref();
+
+    let mut rms_uv = Vec::new();
+    let mut psd = Vec::new();
+    let params = SpectralParams::new(meta.sample_rate as f64, meta.sample_rate.max(2) as usize);
+    for signal in rec.channel_data() {
+        // Filled gaps are NaN
+        let signal: Vec<f32> = signal.into_iter().filter(|v| v.is_finite()).collect();
+        rms_uv.push(montage::channel_rms(std::slice::from_ref(&signal))[0] as f64 / NV_PER_UV);
+        // Trials shorter than a segment have no spectrum
+        let power = welch(aview1(&signal).insert_axis(Axis(0)), &params).map_or_else(|_| Vec::new(), |psd| psd.power.into_raw_vec_and_offset().0);
+        psd.push(power.into_iter().map(|p| p / (NV_PER_UV * NV_PER_UV)).collect());
+    }
+
+    TrialStats {
+        source,
+        subject_id: meta.subject_id.clone(),
+        session_id: meta.session_id.clone(),
+        class_label: meta.class_label.clone(),
+        class_id: meta.class_id,
+        samples: rec.samples.len(),
+        expected: meta.sample_rate as u64 * meta.duration_seconds,
+        seconds: rec.samples.len() as f64 / meta.sample_rate.max(1) as f64,
+        sample_rate: meta.sample_rate,
+        usable: qc.is_some_and(|t| t.usable),
+        drop_rate: qc.map_or(0.0, |t| t.drop_rate),
+        dropped_packets: health.map_or(0, |h| h.dropped_packets),
+        missing_samples: health.map_or(0, |h| h.missing_samples),
+        reconnects: health.map_or(0, |h| h.reconnects),
+        contaminated_fraction: detection
+            .filter(|d| d.contaminated_samples > 0)
+            .map(|d| d.contaminated_samples as f64 / rec.samples.len().max(1) as f64),
+        aborted: detection.is_some_and(|d| d.aborted),
+        injected: meta.artifact_injection.as_ref().map_or(0, |a| a.injected),
+        flagged_bad: meta.markers.iter().any(|m| m.label == BAD_TRIAL),
+        interrupted: meta.interrupted,
+        problems: dataset::validate(rec),
+        channels: rec.channel_names.clone(),
+        rms_uv,
+        psd,
+    }
+}
+
+/// `items` joined, the first `MAX_LISTED` by name
+fn listed(items: &[String]) -> String {
+    let mut text = items.iter().take(MAX_LISTED).cloned().collect::<Vec<_>>().join(", ");
+    if items.len() > MAX_LISTED {
+        let _ = write!(text, " and {} more", items.len() - MAX_LISTED);
+    }
+    text
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(f64::total_cmp);
+    values[values.len() / 2]
+}
+
+/// Summarize every trial under `--data-dir`, print the tables and write the
+/// requested files; returns the report
+pub fn run(args: &ReportArgs) -> Result<DatasetReport> {
+    let compute = args.compute.apply()?;
+    let criteria = args.qc_config.as_deref().map(QcCriteria::load).transpose()?;
+
+    let mut sessions: BTreeMap<PathBuf, SessionManifest> = BTreeMap::new();
+    let mut paths = Vec::new();
+    for path in recording::find_trials(&args.data_dir, true)? {
+        let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        if !sessions.contains_key(&dir) {
+            sessions.insert(dir.clone(), dataset::session_qc(&dir, criteria.as_ref())?);
+        }
+        if args.subjects.is_empty() || args.subjects.contains(&sessions[&dir].subject_id) {
+            paths.push((path, dir));
+        }
+    }
+    if paths.is_empty() {
+        bail!("No trials found under {:?}", args.data_dir);
+    }
+
+    let mut trials = Vec::new();
+    let mut unreadable = Vec::new();
+    for batch in paths.chunks(compute.threads) {
+        let loaded: Vec<_> = batch
+            .par_iter()
+            .map(|(path, dir)| {
+                let source = path.strip_prefix(&args.data_dir).unwrap_or(path).display().to_string();
+                Recording::load(path).map(|rec| trial_stats(&rec, source.clone(), &sessions[dir])).map_err(|e| (source, e))
+            })
+            .collect();
+        for result in loaded {
+            match result {
+                Ok(stats) => trials.push(stats),
+                Err((source, e)) => {
+                    warn!("Cannot read {}: {:#}", source, e);
+                    unreadable.push(source);
+                }
+            }
+        }
+    }
+
+    let report = summarize(args, trials, unreadable, &sessions);
+    println!();
+    println!("{}", report.table());
+    for finding in &report.findings {
+        warn!("{}", finding);
+    }
+    if report.findings.is_empty() {
+        info!("No problems found in {} trials", report.trials);
+    }
+
+    if let Some(path) = &args.json {
+        fs::write(path, serde_json::to_string_pretty(&report)?).with_context(|| format!("Failed to write {:?}", path))?;
+        info!("Report written to {:?}", path);
+    }
+    if let Some(path) = &args.html {
+        fs::write(path, report.html()).with_context(|| format!("Failed to write {:?}", path))?;
+        info!("Report written to {:?}", path);
+    }
+    Ok(report)
+}
+
+fn summarize(
+    args: &ReportArgs,
+    trials: Vec<TrialStats>,
+    unreadable: Vec<String>,
+    session_qc: &BTreeMap<PathBuf, SessionManifest>,
+) -> DatasetReport {
+    let mut findings = Vec::new();
+
+    let mut classes: BTreeMap<u8, ClassSummary> = BTreeMap::new();
+    for trial in &trials {
+        let class = classes.entry(trial.class_id).or_insert_with(|| ClassSummary {
+            class_label: trial.class_label.clone(),
+            class_id: trial.class_id,
+            trials: 0,
+            usable: 0,
+            seconds: 0.0,
+        });
+        class.trials += 1;
+        class.usable += trial.usable as usize;
+        class.seconds += trial.seconds;
+    }
+    let classes: Vec<ClassSummary> = classes.into_values().collect();
+    match (classes.iter().min_by_key(|c| c.usable), classes.iter().max_by_key(|c| c.usable)) {
+        _ if classes.len() < 2 => findings.push(format!("Only {} class recorded, a classifier needs two", classes.len())),
+        (Some(least), _) if least.usable == 0 => {
+            findings.push(format!("Class {} has no usable trials", least.class_label))
+        }
+        (Some(least), Some(most)) if most.usable as f64 > args.max_imbalance * least.usable as f64 => {
+            findings.push(format!(
+                "Classes are imbalanced: {} has {} usable trials, {} only {} ({:.1}x)",
+                most.class_label,
+                most.usable,
+                least.class_label,
+                least.usable,
+                most.usable as f64 / least.usable as f64
+            ))
+        }
+        _ => {}
+    }
+
+    let sessions: Vec<SessionSummary> = session_qc
+        .values()
+        .filter(|s| trials.iter().any(|t| t.subject_id == s.subject_id && t.session_id == s.session_id))
+        .map(|s| SessionSummary {
+            subject_id: s.subject_id.clone(),
+            session_id: s.session_id.clone(),
+            trials: s.trials.len(),
+            usable: s.trials.iter().filter(|t| t.usable).count(),
+            passed: s.qc.passed,
+            failures: s.qc.failures.clone(),
+        })
+        .collect();
+    for session in sessions.iter().filter(|s| !s.passed) {
+        findings.push(format!(
+            "Session {}/{} fails QC: {}",
+            session.subject_id,
+            session.session_id,
+            session.failures.join("; ")
+        ));
+    }
+
+    if !unreadable.is_empty() {
+        findings.push(format!("{} trials cannot be read: {}", unreadable.len(), listed(&unreadable)));
+    }
+    let invalid: Vec<String> = trials
+        .iter()
+        .filter(|t| !t.problems.is_empty())
+        .map(|t| format!("{} ({})", t.source, t.problems.join("; ")))
+        .collect();
+    if !invalid.is_empty() {
+        findings.push(format!("{} trials disagree with their metadata: {}", invalid.len(), listed(&invalid)));
+    }
+
+    let mut lengths: Vec<usize> = trials.iter().map(|t| t.samples).collect();
+    lengths.sort_unstable();
+    let samples_per_trial = TrialLengths {
+        min: lengths.first().copied().unwrap_or_default(),
+        median: lengths.get(lengths.len() / 2).copied().unwrap_or_default(),
+        max: lengths.last().copied().unwrap_or_default(),
+        short_trials: trials.iter().filter(|t| (t.samples as u64) < t.expected).count(),
+    };
+
+    let detected: Vec<f64> = trials.iter().filter_map(|t| t.contaminated_fraction).collect();
+    let artifacts = ArtifactSummary {
+        trials_with_detections: detected.len(),
+        mean_contaminated_fraction: detected.iter().fold(0.0, |sum, f| sum + f) / detected.len().max(1) as f64,
+        aborted: trials.iter().filter(|t| t.aborted).count(),
+        injected_trials: trials.iter().filter(|t| t.injected > 0).count(),
+        injected: trials.iter().map(|t| t.injected).sum(),
+        flagged_bad: trials.iter().filter(|t| t.flagged_bad).count(),
+        interrupted: trials.iter().filter(|t| t.interrupted).count(),
+    };
+    if artifacts.injected_trials > 0 {
+        findings.push(format!(
+            "{} trials have injected artifacts; they are excluded from training sets",
+            artifacts.injected_trials
+        ));
+    }
+
+    let stream = StreamSummary {
+        dropped_packets: trials.iter().map(|t| t.dropped_packets).sum(),
+        missing_samples: trials.iter().map(|t| t.missing_samples).sum(),
+        reconnects: trials.iter().map(|t| t.reconnects).sum(),
+        mean_drop_rate: trials.iter().map(|t| t.drop_rate).sum::<f64>() / trials.len().max(1) as f64,
+        max_drop_rate: trials.iter().map(|t| t.drop_rate).fold(0.0, f64::max),
+    };
+
+    // Spectra are averaged over the trials at the most common rate
+    let mut rates: BTreeMap<u32, usize> = BTreeMap::new();
+    for trial in &trials {
+        *rates.entry(trial.sample_rate).or_default() += 1;
+    }
+    let rate = rates.iter().max_by_key(|(_, n)| **n).map(|(rate, _)| *rate);
+    if rates.len() > 1 {
+        findings.push(format!(
+            "Trials have different sample rates ({}); the spectrum only covers {} Hz",
+            rates.keys().map(|r| format!("{} Hz", r)).collect::<Vec<_>>().join(", "),
+            rate.unwrap_or_default()
+        ));
+    }
+
+    let mut names: Vec<String> = Vec::new();
+    for trial in &trials {
+        for name in &trial.channels {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+    }
+    let psd = rate.map(|rate| {
+        let frequencies = SpectralParams::new(rate as f64, rate.max(2) as usize).frequencies().to_vec();
+        let mut power = vec![vec![0.0; frequencies.len()]; names.len()];
+        let mut counts = vec![0usize; names.len()];
+        let at_rate: Vec<&TrialStats> = trials.iter().filter(|t| t.sample_rate == rate).collect();
+        for trial in &at_rate {
+            for (name, spectrum) in trial.channels.iter().zip(&trial.psd) {
+                let i = names.iter().position(|n| n == name).unwrap_or_default();
+                if spectrum.len() == frequencies.len() {
+                    power[i].iter_mut().zip(spectrum).for_each(|(p, s)| *p += s);
+                    counts[i] += 1;
+                }
+            }
+        }
+        for (channel, count) in power.iter_mut().zip(&counts) {
+            channel.iter_mut().for_each(|p| *p /= (*count).max(1) as f64);
+        }
+        Spectrum {
+            sample_rate: rate,
+            trials: at_rate.len(),
+            frequencies,
+            channels: names.clone(),
+            power,
+        }
+    });
+
+    let mut bands = motor_imagery_bands();
+    bands.push(Band::new(
+        format!("{} Hz", args.line_frequency),
+        args.line_frequency - 1.0,
+        args.line_frequency + 1.0,
+    ));
+    let channels: Vec<ChannelSummary> = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let mut rms: Vec<f64> = trials
+                .iter()
+                .filter_map(|t| t.channels.iter().position(|c| c == name).map(|c| t.rms_uv[c]))
+                .collect();
+            let flat_trials = rms.iter().filter(|&&r| r < FLAT_RMS_UV).count();
+            let band_power_uv2 = bands
+                .iter()
+                .map(|band| {
+                    psd.as_ref().map_or(0.0, |psd| {
+                        let df = psd.frequencies.get(1).copied().unwrap_or(1.0);
+                        psd.frequencies
+                            .iter()
+                            .zip(&psd.power[i])
+                            .filter(|(f, _)| **f >= band.low && **f < band.high)
+                            .map(|(_, p)| p * df)
+                            .sum()
+                    })
+                })
+                .collect();
+            ChannelSummary {
+                name: name.clone(),
+                trials: rms.len(),
+                max_rms_uv: rms.iter().copied().fold(0.0, f64::max),
+                median_rms_uv: median(&mut rms),
+                flat_trials,
+                band_power_uv2,
+            }
+        })
+        .collect();
+    let overall = median(&mut channels.iter().map(|c| c.median_rms_uv).collect::<Vec<_>>());
+    for channel in &channels {
+        if channel.flat_trials > 0 {
+            findings.push(format!("Channel {} is flat in {} of {} trials", channel.name, channel.flat_trials, channel.trials));
+        } else if overall > 0.0
+            && (channel.median_rms_uv > RMS_OUTLIER_FACTOR * overall || channel.median_rms_uv < overall / RMS_OUTLIER_FACTOR)
+        {
+            findings.push(format!(
+                "Channel {} has {:.1} µV RMS against {:.1} µV over all channels, check the electrode",
+                channel.name, channel.median_rms_uv, overall
+            ));
+        }
+    }
+
+    DatasetReport {
+        created: Utc::now(),
+        data_dir: args.data_dir.clone(),
+        trials: trials.len(),
+        unreadable,
+        invalid,
+        classes,
+        sessions,
+        samples_per_trial,
+        artifacts,
+        stream,
+        bands,
+        channels,
+        psd,
+        findings,
+    }
+}
+
+impl DatasetReport {
+    /// The report as printed to the terminal
+    pub fn table(&self) -> String {
+        let mut text = String::new();
+        // Writing to a String cannot fail
+        let _ = writeln!(text, "{:<14}  {:>6}  {:>6}  {:>9}", "Class", "Trials", "Usable", "Seconds");
+        for class in &self.classes {
+            let _ = writeln!(
+                text,
+                "{:<14}  {:>6}  {:>6}  {:>9.1}",
+                class.class_label, class.trials, class.usable, class.seconds
+            );
+        }
+
+        let _ = writeln!(text);
+        let width = self.sessions.iter().map(|s| s.subject_id.len() + s.session_id.len() + 1).max().unwrap_or(0).max(7);
+        let _ = writeln!(text, "{:<width$}  {:>6}  {:>6}  QC", "Session", "Trials", "Usable");
+        for session in &self.sessions {
+            let _ = writeln!(
+                text,
+                "{:<width$}  {:>6}  {:>6}  {}",
+                format!("{}/{}", session.subject_id, session.session_id),
+                session.trials,
+                session.usable,
+                if session.passed { "passed" } else { "failed" }
+            );
+        }
+
+        let lengths = &self.samples_per_trial;
+        let artifacts = &self.artifacts;
+        let stream = &self.stream;
+        let _ = writeln!(text);
+        let _ = writeln!(
+            text,
+            "Samples per trial: min {}, median {}, max {}; {} short",
+            lengths.min, lengths.median, lengths.max, lengths.short_trials
+        );
+        let _ = writeln!(
+            text,
+            "Artifacts: {} trials with detections ({:.1}% marked), {} aborted, {} injected in {} trials, {} flagged bad, {} interrupted",
+            artifacts.trials_with_detections,
+            artifacts.mean_contaminated_fraction * 100.0,
+            artifacts.aborted,
+            artifacts.injected,
+            artifacts.injected_trials,
+            artifacts.flagged_bad,
+            artifacts.interrupted
+        );
+        let _ = writeln!(
+            text,
+            "Stream: {} dropped packets, {} missing samples, {} reconnects; drop rate mean {:.2}%, max {:.2}%",
+            stream.dropped_packets,
+            stream.missing_samples,
+            stream.reconnects,
+            stream.mean_drop_rate * 100.0,
+            stream.max_drop_rate * 100.0
+        );
+
+        let _ = writeln!(text);
+        let width = self.channels.iter().map(|c| c.name.len()).max().unwrap_or(0).max(7);
+        let _ = write!(text, "{:<width$}  {:>9}  {:>9}", "Channel", "RMS µV", "Max µV");
+        for band in &self.bands {
+            let _ = write!(text, "  {:>10}", format!("{} µV²", band.name));
+        }
+        let _ = writeln!(text, "  Flat");
+        for channel in &self.channels {
+            let _ = write!(text, "{:<width$}  {:>9.2}  {:>9.2}", channel.name, channel.median_rms_uv, channel.max_rms_uv);
+            for power in &channel.band_power_uv2 {
+                let _ = write!(text, "  {:>10.3}", power);
+            }
+            let _ = writeln!(text, "  {}", channel.flat_trials);
+        }
+        let _ = write!(
+            text,
+            "Dataset report: {} trials, {} findings",
+            self.trials,
+            self.findings.len()
+        );
+        text
+    }
+
+    /// Standalone HTML page with the tables and the mean spectra
+    pub fn html(&self) -> String {
+        let mut page = String::new();
+        let _ = writeln!(
+            page,
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Dataset report</title>\n<style>\n\
+             body {{ font-family: sans-serif; margin: 2em; }}\n\
+             table {{ border-collapse: collapse; margin-bottom: 1.5em; }}\n\
+             th, td {{ border: 1px solid #ccc; padding: 0.3em 0.7em; text-align: right; }}\n\
+             th:first-child, td:first-child {{ text-align: left; }}\n\
+             .failed {{ color: #b00; }}\n</style></head><body>"
+        );
+        let _ = writeln!(
+            page,
+            "<h1>Dataset report</h1>\n<p>{} trials under <code>{}</code>, {}</p>",
+            self.trials,
+            escape(&self.data_dir.display().to_string()),
+            self.created.format("%Y-%m-%d %H:%M UTC")
+        );
+
+        let _ = writeln!(page, "<h2>Findings</h2>");
+        if self.findings.is_empty() {
+            let _ = writeln!(page, "<p>No problems found.</p>");
+        } else {
+            let _ = writeln!(page, "<ul>");
+            for finding in &self.findings {
+                let _ = writeln!(page, "<li class=\"failed\">{}</li>", escape(finding));
+            }
+            let _ = writeln!(page, "</ul>");
+        }
+
+        let _ = writeln!(page, "<h2>Classes</h2>\n<table><tr><th>Class</th><th>ID</th><th>Trials</th><th>Usable</th><th>Seconds</th></tr>");
+        for class in &self.classes {
+            let _ = writeln!(
+                page,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.1}</td></tr>",
+                escape(&class.class_label),
+                class.class_id,
+                class.trials,
+                class.usable,
+                class.seconds
+            );
+        }
+        let _ = writeln!(page, "</table>");
+
+        let _ = writeln!(page, "<h2>Sessions</h2>\n<table><tr><th>Session</th><th>Trials</th><th>Usable</th><th>QC</th></tr>");
+        for session in &self.sessions {
+            let verdict = if session.passed {
+                "passed".to_string()
+            } else {
+                format!("<span class=\"failed\">failed: {}</span>", escape(&session.failures.join("; ")))
+            };
+            let _ = writeln!(
+                page,
+                "<tr><td>{}/{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(&session.subject_id),
+                escape(&session.session_id),
+                session.trials,
+                session.usable,
+                verdict
+            );
+        }
+        let _ = writeln!(page, "</table>");
+
+        let lengths = &self.samples_per_trial;
+        let artifacts = &self.artifacts;
+        let stream = &self.stream;
+        let _ = writeln!(page, "<h2>Trials</h2>\n<table>");
+        let rows = [
+            ("Samples per trial (min / median / max)", format!("{} / {} / {}", lengths.min, lengths.median, lengths.max)),
+            ("Short trials", lengths.short_trials.to_string()),
+            ("Unreadable trials", self.unreadable.len().to_string()),
+            ("Trials disagreeing with their metadata", self.invalid.len().to_string()),
+            ("Trials with detected artifacts", artifacts.trials_with_detections.to_string()),
+            ("Mean fraction marked", format!("{:.1}%", artifacts.mean_contaminated_fraction * 100.0)),
+            ("Aborted for artifacts", artifacts.aborted.to_string()),
+            ("Injected artifacts (trials)", format!("{} ({})", artifacts.injected, artifacts.injected_trials)),
+            ("Flagged bad", artifacts.flagged_bad.to_string()),
+            ("Interrupted", artifacts.interrupted.to_string()),
+            ("Dropped packets", stream.dropped_packets.to_string()),
+            ("Missing samples", stream.missing_samples.to_string()),
+            ("Reconnects", stream.reconnects.to_string()),
+            ("Drop rate (mean / max)", format!("{:.2}% / {:.2}%", stream.mean_drop_rate * 100.0, stream.max_drop_rate * 100.0)),
+        ];
+        for (label, value) in rows {
+            let _ = writeln!(page, "<tr><td>{}</td><td>{}</td></tr>", label, value);
+        }
+        let _ = writeln!(page, "</table>");
+
+        let _ = write!(page, "<h2>Channels</h2>\n<table><tr><th>Channel</th><th>Trials</th><th>RMS µV</th><th>Max RMS µV</th>");
+        for band in &self.bands {
+            let _ = write!(page, "<th>{} µV²</th>", escape(&band.name));
+        }
+        let _ = writeln!(page, "<th>Flat trials</th></tr>");
+        for channel in &self.channels {
+            let _ = write!(
+                page,
+                "<tr><td>{}</td><td>{}</td><td>{:.2}</td><td>{:.2}</td>",
+                escape(&channel.name),
+                channel.trials,
+                channel.median_rms_uv,
+                channel.max_rms_uv
+            );
+            for power in &channel.band_power_uv2 {
+                let _ = write!(page, "<td>{:.3}</td>", power);
+            }
+            let _ = writeln!(page, "<td>{}</td></tr>", channel.flat_trials);
+        }
+        let _ = writeln!(page, "</table>");
+
+        if let Some(psd) = &self.psd {
+            let _ = writeln!(
+                page,
+                "<h2>Mean power spectrum</h2>\n<p>{} trials at {} Hz, µV²/Hz on a log scale</p>",
+                psd.trials, psd.sample_rate
+            );
+            page.push_str(&spectrum_svg(psd));
+        }
+        let _ = writeln!(page, "</body></html>");
+        page
+    }
+}
+
+/// Log-power line plot of every channel's spectrum from 1 Hz to Nyquist
+fn spectrum_svg(psd: &Spectrum) -> String {
+    const COLORS: [&str; 8] = ["#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f"];
+    // The right margin holds the legend
+    let (width, height, margin, legend) = (760.0, 360.0, 50.0, 150.0);
+    let points: Vec<(f64, f64)> = psd
+        .power
+        .iter()
+        .flat_map(|channel| psd.frequencies.iter().zip(channel).map(|(f, p)| (*f, *p)))
+        .filter(|(f, p)| *f >= 1.0 && *p > 0.0)
+        .collect();
+    if points.is_empty() {
+        return String::new();
+    }
+    let max_f = psd.frequencies.last().copied().unwrap_or(1.0);
+    let (low, high) = points.iter().fold((f64::MAX, f64::MIN), |(lo, hi), (_, p)| (lo.min(p.log10()), hi.max(p.log10())));
+    let (low, high) = (low.floor(), high.ceil().max(low.floor() + 1.0));
+    let x = |f: f64| margin + (f - 1.0) / (max_f - 1.0).max(1.0) * (width - margin - legend);
+    let y = |p: f64| height - margin - (p.log10() - low) / (high - low) * (height - 2.0 * margin);
+
+    let mut svg = String::new();
+    let _ = writeln!(svg, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-size=\"11\">", width, height);
+    let _ = writeln!(
+        svg,
+        "<rect x=\"{m}\" y=\"{m}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"#999\"/>",
+        width - margin - legend,
+        height - 2.0 * margin,
+        m = margin
+    );
+    for decade in low as i32..=high as i32 {
+        let _ = writeln!(
+            svg,
+            "<text x=\"{}\" y=\"{:.1}\" text-anchor=\"end\">1e{}</text>",
+            margin - 4.0,
+            y(10f64.powi(decade)) + 4.0,
+            decade
+        );
+    }
+    let mut f = 10.0;
+    while f <= max_f {
+        let _ = writeln!(svg, "<text x=\"{:.1}\" y=\"{}\" text-anchor=\"middle\">{} Hz</text>", x(f), height - margin + 15.0, f);
+        f += 10.0;
+    }
+    for (i, (name, channel)) in psd.channels.iter().zip(&psd.power).enumerate() {
+        let color = COLORS[i % COLORS.len()];
+        let path: Vec<String> = psd
+            .frequencies
+            .iter()
+            .zip(channel)
+            .filter(|(f, p)| **f >= 1.0 && **p > 0.0)
+            .map(|(f, p)| format!("{:.1},{:.1}", x(*f), y(*p)))
+            .collect();
+        let _ = writeln!(svg, "<polyline fill=\"none\" stroke=\"{}\" points=\"{}\"/>", color, path.join(" "));
+        let _ = writeln!(
+            svg,
+            "<text x=\"{}\" y=\"{}\" fill=\"{}\">{}</text>",
+            width - legend + 8.0,
+            margin + 12.0 * (i as f64 + 1.0),
+            color,
+            escape(name)
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
@@ -0,0 +1,66 @@
+//! Command-line side of the Riemannian tools: the covariance shrinkage
+//! option and band-passed epoch covariances for [`eeg_dsp::riemann`].
+
+use crate::features;
+use anyhow::{bail, Result};
+use eeg_dsp::Band;
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Covariance shrinkage towards a scaled identity, which keeps short or
+/// rank-deficient epochs positive definite
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Shrinkage {
+    /// Analytic Ledoit-Wolf estimate of the optimal intensity
+    LedoitWolf,
+    /// Fixed intensity in `[0, 1]`
+    Fixed(f64),
+}
+
+impl fmt::Display for Shrinkage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LedoitWolf => f.write_str("lw"),
+            Self::Fixed(alpha) => write!(f, "{}", alpha),
+        }
+    }
+}
+
+impl FromStr for Shrinkage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "lw" | "ledoit-wolf" | "ledoit_wolf" => Ok(Self::LedoitWolf),
+            other => match other.parse::<f64>() {
+                Ok(alpha) if (0.0..=1.0).contains(&alpha) => Ok(Self::Fixed(alpha)),
+                _ => bail!("Shrinkage '{}' must be lw or a number in [0, 1]", s),
+            },
+        }
+    }
+}
+
+/// The same shrinkage for `eeg_dsp`
+impl From<Shrinkage> for eeg_dsp::Shrinkage {
+    fn from(shrinkage: Shrinkage) -> Self {
+        match shrinkage {
+            Shrinkage::LedoitWolf => Self::LedoitWolf,
+            Shrinkage::Fixed(alpha) => Self::Fixed(alpha),
+        }
+    }
+}
+
+/// Covariance of a recorded epoch after band-passing every channel,
+/// usually to the 8-30 Hz mu/beta range
+pub fn band_covariance(epoch: &[Vec<f32>], sample_rate: f64, band: &Band, shrinkage: Shrinkage) -> Result<Array2<f64>> {
+    let filtered: Vec<Vec<f32>> = epoch
+        .iter()
+        .map(|channel| features::bandpass(channel, sample_rate, band))
+        .collect::<Result<_>>()?;
+    let len = filtered.iter().map(Vec::len).min().unwrap_or(0);
+    let trial = Array2::from_shape_fn((filtered.len(), len), |(channel, t)| filtered[channel][t]);
+    Ok(eeg_dsp::covariance(trial.view(), shrinkage.into()))
+}
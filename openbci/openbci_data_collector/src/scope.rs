@@ -0,0 +1,357 @@
+//! Live terminal scope for `--scope`.
+//!
+//! While a trial records, the terminal switches to a full-screen view of
+//! the last few seconds of every channel, next to its RMS, with the stream
+//! rate, dropped packets, the trial phase, the time left and any live band
+//! power above and the latest log lines below. Traces and RMS are band-passed 1-40 Hz for
+//! display only and use the signal check's limits: a channel that is flat
+//! or noisy shows in red. The view redraws from its own thread, so drawing
+//! never holds up samples. Log records are held back while it is on screen
+//! and printed once the terminal is restored, so nothing is lost.
+
+use crate::bandpower::BandPowers;
+use crate::cue::{CUE_PREFIX, FIXATION};
+use crate::features;
+use eeg_dsp::Band;
+use crate::montage;
+use crate::signal_check::{FLAT_UV, NOISY_UV};
+use chrono::Utc;
+use indicatif::MultiProgress;
+use log::warn;
+use openbci_wifi_client::StreamStats;
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::{cursor, execute, terminal};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::symbols::Marker;
+use ratatui::text::Line;
+use ratatui::widgets::{Axis, Block, Borders, Chart, Dataset, GraphType, Paragraph};
+use ratatui::{Frame, Terminal};
+use std::collections::VecDeque;
+use std::io::{IsTerminal, Stdout, Write};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Seconds of signal on screen
+const WINDOW_SECONDS: f64 = 4.0;
+const REDRAW: Duration = Duration::from_millis(100);
+/// Smallest half-height of a trace, in µV, so quiet channels are not
+/// blown up to full height
+const MIN_SCALE_UV: f64 = 10.0;
+/// Log records kept while the scope is on screen
+const MAX_HELD_LOGS: usize = 10_000;
+const LOG_LINES: u16 = 4;
+
+/// Log records held back while a scope is on screen, `None` otherwise
+static HELD_LOGS: Mutex<Option<VecDeque<String>>> = Mutex::new(None);
+
+/// Install `inner` as the logger, holding records back while a scope is on
+/// screen and printing them above `bars` otherwise
+pub fn init_logger(inner: env_logger::Logger, bars: MultiProgress) {
+    let max_level = inner.filter();
+    log::set_boxed_logger(Box::new(ScopeLogger { inner, bars })).expect("logger installed twice");
+    log::set_max_level(max_level);
+}
+
+struct ScopeLogger {
+    inner: env_logger::Logger,
+    bars: MultiProgress,
+}
+
+impl log::Log for ScopeLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        let mut held = HELD_LOGS.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(lines) = held.as_mut() else {
+            drop(held);
+            return self.bars.suspend(|| self.inner.log(record));
+        };
+        if lines.len() == MAX_HELD_LOGS {
+            lines.pop_front();
+        }
+        lines.push_back(format!(
+            "[{} {:<5} {}] {}",
+            Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+            record.level(),
+            record.target(),
+            record.args()
+        ));
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// What the scope shows, updated by the recording loop
+struct View {
+    title: String,
+    channel_names: Vec<String>,
+    sample_rate: u32,
+    /// Last `window` samples of every channel, in nanovolts
+    traces: Vec<VecDeque<f32>>,
+    window: usize,
+    phase: String,
+    last_marker: Option<String>,
+    band_power: Option<String>,
+    start: Instant,
+    end: Option<Instant>,
+    stats: Option<StreamStats>,
+    /// Samples per second over the last stats interval
+    rate: Option<f64>,
+    missing_samples: u64,
+}
+
+/// Full-screen live view of a running trial
+pub struct Scope {
+    view: Arc<Mutex<View>>,
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Scope {
+    /// Take over the terminal until `finish`. `None` if stdout is not a
+    /// terminal.
+    pub fn start(title: String, channel_names: &[String], sample_rate: u32, end: Option<Instant>) -> Option<Self> {
+        if !std::io::stdout().is_terminal() {
+            warn!("stdout is not a terminal, not showing the scope");
+            return None;
+        }
+        let mut terminal = match enter() {
+            Ok(terminal) => terminal,
+            Err(e) => {
+                leave();
+                warn!("Failed to open the scope: {}", e);
+                return None;
+            }
+        };
+        HELD_LOGS.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert_with(VecDeque::new);
+
+        let window = (WINDOW_SECONDS * sample_rate as f64) as usize;
+        let view = Arc::new(Mutex::new(View {
+            title,
+            channel_names: channel_names.to_vec(),
+            sample_rate,
+            traces: vec![VecDeque::with_capacity(window + 1); channel_names.len()],
+            window,
+            phase: "Recording".to_string(),
+            last_marker: None,
+            band_power: None,
+            start: Instant::now(),
+            end,
+            stats: None,
+            rate: None,
+            missing_samples: 0,
+        }));
+        let (stop, stopped) = mpsc::channel();
+        let shared = Arc::clone(&view);
+        let thread = std::thread::Builder::new()
+            .name("scope".to_string())
+            .spawn(move || loop {
+                let result = {
+                    let view = shared.lock().unwrap_or_else(|e| e.into_inner());
+                    terminal.draw(|frame| render(frame, &view))
+                };
+                if let Err(e) = result {
+                    warn!("Scope stopped drawing: {}", e);
+                    return;
+                }
+                match stopped.recv_timeout(REDRAW) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+            })
+            .expect("failed to spawn the scope thread");
+        Some(Self {
+            view,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Add the next recorded sample, one value per channel
+    pub fn push(&self, data: &[f32]) {
+        let mut view = self.view.lock().unwrap_or_else(|e| e.into_inner());
+        let window = view.window;
+        for (trace, &value) in view.traces.iter_mut().zip(data) {
+            if trace.len() == window {
+                trace.pop_front();
+            }
+            trace.push_back(value);
+        }
+    }
+
+    /// A marker was recorded; cue markers move the phase on
+    pub fn marker(&self, label: &str) {
+        let mut view = self.view.lock().unwrap_or_else(|e| e.into_inner());
+        if label == FIXATION {
+            view.phase = "Fixation".to_string();
+        } else if let Some(class) = label.strip_prefix(CUE_PREFIX) {
+            view.phase = format!("Cue: {}", class);
+        }
+        view.last_marker = Some(label.to_string());
+    }
+
+    /// Latest mu/beta powers, shown under the stream counters
+    pub fn set_band_power(&self, powers: &BandPowers) {
+        self.view.lock().unwrap_or_else(|e| e.into_inner()).band_power = Some(powers.to_string());
+    }
+
+    pub fn set_phase(&self, phase: &str) {
+        self.view.lock().unwrap_or_else(|e| e.into_inner()).phase = phase.to_string();
+    }
+
+    /// Latest stream counters and samples missing from the board clock
+    pub fn update_stats(&self, stats: StreamStats, missing_samples: u64) {
+        let mut view = self.view.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(earlier) = &view.stats {
+            let interval = stats.since(earlier);
+            if interval.elapsed > 0.0 {
+                view.rate = Some(interval.samples_per_sec());
+            }
+        }
+        view.stats = Some(stats);
+        view.missing_samples = missing_samples;
+    }
+
+    /// Restore the terminal and print the log records held back meanwhile
+    pub fn finish(mut self) {
+        self.close();
+    }
+
+    fn close(&mut self) {
+        let _ = self.stop.send(());
+        let Some(thread) = self.thread.take() else {
+            return;
+        };
+        let panicked = thread.join().is_err();
+        leave();
+        let held = HELD_LOGS.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let mut stderr = std::io::stderr().lock();
+        for line in held.into_iter().flatten() {
+            let _ = writeln!(stderr, "{}", line);
+        }
+        if panicked {
+            warn!("Scope thread panicked");
+        }
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn enter() -> std::io::Result<Terminal<CrosstermBackend<Stdout>>> {
+    let mut stdout = std::io::stdout();
+    execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+    terminal.clear()?;
+    Ok(terminal)
+}
+
+fn leave() {
+    if let Err(e) = execute!(std::io::stdout(), terminal::LeaveAlternateScreen, cursor::Show) {
+        warn!("Failed to restore the terminal: {}", e);
+    }
+}
+
+fn render(frame: &mut Frame, view: &View) {
+    let [header, channels, logs] = Layout::vertical([
+        Constraint::Length(5 + view.band_power.is_some() as u16),
+        Constraint::Min(view.channel_names.len() as u16),
+        Constraint::Length(LOG_LINES + 1),
+    ])
+    .areas(frame.area());
+
+    let time = match view.end {
+        Some(end) => format!("{:.1} s left", end.saturating_duration_since(Instant::now()).as_secs_f64()),
+        None => format!("{:.1} s elapsed", view.start.elapsed().as_secs_f64()),
+    };
+    let rate = view.rate.map_or("-".to_string(), |rate| format!("{:.1}", rate));
+    let (dropped, parse_errors, reconnects) =
+        view.stats.as_ref().map_or((0, 0, 0), |s| (s.dropped_packets, s.parse_errors, s.reconnects));
+    let mut status = vec![
+        Line::from(format!("Phase: {}    {}", view.phase, time)),
+        Line::from(format!(
+            "Rate: {} of {} Hz    Dropped packets: {}    Missing samples: {}    Parse errors: {}    Reconnects: {}",
+            rate, view.sample_rate, dropped, view.missing_samples, parse_errors, reconnects
+        )),
+        Line::from(format!("Last marker: {}", view.last_marker.as_deref().unwrap_or("-"))),
+    ];
+    if let Some(band_power) = &view.band_power {
+        status.push(Line::from(format!("Band power: {}", band_power)));
+    }
+    frame.render_widget(
+        Paragraph::new(status).block(Block::new().borders(Borders::BOTTOM).title(view.title.as_str())),
+        header,
+    );
+
+    let label_width = view.channel_names.iter().map(|n| n.len()).max().unwrap_or(0) as u16 + 2;
+    let rows = Layout::vertical(vec![Constraint::Ratio(1, view.channel_names.len().max(1) as u32); view.channel_names.len()])
+        .split(channels);
+    for ((name, trace), &row) in view.channel_names.iter().zip(&view.traces).zip(rows.iter()) {
+        render_channel(frame, row, label_width, name, trace, view);
+    }
+
+    let held = HELD_LOGS.lock().unwrap_or_else(|e| e.into_inner());
+    let recent: Vec<Line> = held.iter().flat_map(|lines| {
+        lines
+            .iter()
+            .skip(lines.len().saturating_sub(LOG_LINES as usize))
+            .map(|line| Line::from(line.as_str()))
+    })
+    .collect();
+    frame.render_widget(Paragraph::new(recent).block(Block::new().borders(Borders::TOP)), logs);
+}
+
+/// One row: name and RMS on the left, the trace on the right
+fn render_channel(frame: &mut Frame, area: Rect, label_width: u16, name: &str, trace: &VecDeque<f32>, view: &View) {
+    let [label, plot] = Layout::horizontal([Constraint::Length(label_width.max(14)), Constraint::Min(10)]).areas(area);
+    let fs = view.sample_rate as f64;
+    let band = Band::new("scope", 1.0, 40f64.min(fs / 2.0 - 1.0));
+
+    // Mean removed first, the zero-phase filter starts from rest
+    let mean = trace.iter().map(|&x| x as f64).sum::<f64>() / trace.len().max(1) as f64;
+    let centered: Vec<f32> = trace.iter().map(|&x| (x as f64 - mean) as f32).collect();
+    let filtered = features::bandpass(&centered, fs, &band).unwrap_or_else(|_| centered.clone());
+    let rms_uv = montage::channel_rms(std::slice::from_ref(&filtered))[0] as f64 / 1000.0;
+    let color = if trace.is_empty() {
+        Color::Gray
+    } else if !(FLAT_UV..=NOISY_UV).contains(&rms_uv) {
+        Color::Red
+    } else {
+        Color::Green
+    };
+    frame.render_widget(
+        Paragraph::new(vec![Line::from(name), Line::from(format!("{:.1} µV", rms_uv))]).style(Style::new().fg(color)),
+        label,
+    );
+
+    // Newest sample at the right edge
+    let window = view.window;
+    let offset = window.saturating_sub(filtered.len());
+    let points: Vec<(f64, f64)> = filtered
+        .iter()
+        .enumerate()
+        .map(|(i, &x)| ((offset + i) as f64, x as f64 / 1000.0))
+        .collect();
+    let scale = points.iter().map(|p| p.1.abs()).fold(MIN_SCALE_UV, f64::max);
+    let chart = Chart::new(vec![Dataset::default()
+        .marker(Marker::Braille)
+        .graph_type(GraphType::Line)
+        .style(Style::new().fg(color))
+        .data(&points)])
+    .x_axis(Axis::default().bounds([0.0, window as f64]))
+    .y_axis(Axis::default().bounds([-scale, scale]));
+    frame.render_widget(chart, plot);
+}
@@ -0,0 +1,173 @@
+//! A config's protocol recorded trial after trial, with rests in between.
+//! Run as `openbci session`.
+
+use crate::config::ProtocolConfig;
+use crate::paradigm::Paradigm;
+use crate::progress::SessionProgress;
+use crate::qc;
+use crate::record::{record_trial, RecordArgs, ShieldOutput};
+use crate::recording;
+use crate::shutdown;
+use anyhow::Result;
+use log::{info, warn};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Trials to record in one run, from the config's protocol
+#[derive(Debug, Clone)]
+pub struct SessionPlan {
+    pub classes: Vec<String>,
+    pub trials_per_class: u32,
+    pub rest_seconds: f64,
+    pub shuffle: bool,
+    pub seed: u64,
+    /// Append a trial of the same class when one comes out bad
+    pub replace_bad: bool,
+    pub max_replacements: u32,
+}
+
+impl SessionPlan {
+    pub fn new(protocol: &ProtocolConfig, paradigm: &Paradigm) -> Result<Option<Self>> {
+        if protocol.classes.is_empty() {
+            return Ok(None);
+        }
+        for class in &protocol.classes {
+            if paradigm.class(class).is_none() {
+                anyhow::bail!("Unknown class '{}' in config, expected one of {}", class, paradigm.class_names().join(", "));
+            }
+        }
+        Ok(Some(Self {
+            classes: protocol.classes.clone(),
+            trials_per_class: protocol.trials_per_class.unwrap_or(10),
+            rest_seconds: protocol.rest_seconds.or(paradigm.trial.rest_seconds).unwrap_or(3.0),
+            shuffle: protocol.shuffle.unwrap_or(true),
+            seed: protocol.seed.unwrap_or_else(rand::random),
+            replace_bad: protocol.replace_bad_trials.unwrap_or(true),
+            max_replacements: protocol.max_replacements.unwrap_or(5),
+        }))
+    }
+
+    /// `(class, round)` in recording order: one trial of every class per
+    /// round, shuffled within the round
+    pub fn schedule(&self) -> Vec<(String, u32)> {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut schedule = Vec::new();
+        for round in 0..self.trials_per_class {
+            let mut classes = self.classes.clone();
+            if self.shuffle {
+                classes.shuffle(&mut rng);
+            }
+            schedule.extend(classes.into_iter().map(|class| (class, round)));
+        }
+        schedule
+    }
+}
+
+/// Record every trial of the config's protocol, resting in between
+pub async fn run(args: &RecordArgs, plan: &SessionPlan) -> Result<()> {
+    let schedule = plan.schedule();
+    info!("=== Session {} of {} ===", args.session_id, args.subject_id);
+    info!(
+        "{} trials: {} x {} ({}), {} s rest, order seed {}",
+        schedule.len(),
+        plan.trials_per_class,
+        plan.classes.join(", "),
+        if plan.shuffle { "shuffled" } else { "in order" },
+        plan.rest_seconds,
+        plan.seed
+    );
+
+    let criteria = args.qc_criteria()?;
+    let mut schedule = schedule;
+    // Replacements continue the trial numbers of their class
+    let mut next_round: BTreeMap<String, u32> = BTreeMap::new();
+    let mut replacements = 0;
+    let mut progress = SessionProgress::start(&args.progress, schedule.len());
+    let mut i = 0;
+    while i < schedule.len() {
+        let (class, round) = schedule[i].clone();
+        if i > 0 && plan.rest_seconds > 0.0 {
+            info!("Rest for {} s", plan.rest_seconds);
+            tokio::time::sleep(Duration::from_secs_f64(plan.rest_seconds)).await;
+        }
+        let mut trial_args = args.clone();
+        trial_args.class = Some(class.clone());
+        // --trial numbers the first round, so a session can be resumed
+        trial_args.trial = args.trial + round;
+        // Generated signals differ from trial to trial but stay reproducible
+        trial_args.synthetic_seed = args.synthetic_seed.map(|seed| seed.wrapping_add(i as u64));
+        info!("--- Trial {}/{}: {} #{} ---", i + 1, schedule.len(), class, trial_args.trial);
+        progress.trial(i);
+        let written = record_trial(&trial_args).await?;
+        i += 1;
+        if shutdown::requested() {
+            update_qc(&trial_args)?;
+            warn!("Session interrupted after {} of {} trials", i, schedule.len());
+            return Ok(());
+        }
+
+        if plan.replace_bad {
+            let mut reason = None;
+            for path in &written {
+                reason = reason.or(qc::replacement_reason(&recording::read_metadata(path)?, &criteria));
+            }
+            if let Some(reason) = reason {
+                if replacements < plan.max_replacements {
+                    replacements += 1;
+                    let next = next_round.entry(class.clone()).or_insert(plan.trials_per_class);
+                    let number = args.trial + *next;
+                    for path in &written {
+                        let mut metadata = recording::read_metadata(path)?;
+                        metadata.replaced_by = Some(number);
+                        fs::write(path, serde_json::to_string_pretty(&metadata)?)?;
+                    }
+                    warn!(
+                        "Trial {} is bad ({}), recording {} #{} at the end ({}/{})",
+                        trial_args.trial, reason, class, number, replacements, plan.max_replacements
+                    );
+                    schedule.push((class, *next));
+                    *next += 1;
+                    progress.grow(1);
+                } else {
+                    warn!("Trial {} is bad ({}), but {} trials were replaced already", trial_args.trial, reason, replacements);
+                }
+            }
+        }
+        update_qc(&trial_args)?;
+    }
+
+    info!("=== Session Complete: {} trials, {} replaced ===", schedule.len(), replacements);
+    Ok(())
+}
+
+/// Re-evaluate session QC so the manifest reflects every trial so far
+pub fn update_qc(args: &RecordArgs) -> Result<()> {
+    let criteria = args.qc_criteria()?;
+    let mut session_dirs = vec![args.session_dir()];
+    if args.shield_output == ShieldOutput::SideBySide && !args.shield_subjects.is_empty() {
+        session_dirs = args
+            .shield_subjects
+            .iter()
+            .map(|subject| PathBuf::from(&args.output_dir).join(subject).join(&args.session_id))
+            .collect();
+        session_dirs.sort();
+        session_dirs.dedup();
+    }
+    for session_dir in &session_dirs {
+        let manifest = qc::finalize_session(session_dir, &criteria)?;
+        if manifest.qc.passed {
+            info!("Session QC: PASS ({} trials)", manifest.trials.len());
+        } else {
+            warn!("Session QC: FAIL ({} trials)", manifest.trials.len());
+            for failure in &manifest.qc.failures {
+                warn!("  {}", failure);
+            }
+        }
+    }
+    Ok(())
+}
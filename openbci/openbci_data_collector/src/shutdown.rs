@@ -0,0 +1,89 @@
+//! Ctrl+C and SIGTERM handling.
+//!
+//! The first signal while a trial is recording asks it to stop: the
+//! collector ends the stream as if the duration were reached, so the data
+//! files are flushed and closed and the metadata gets its end time and
+//! sample count, and no further trials are started. A second signal, or one
+//! while nothing is recording, exits at once like the default handler.
+
+use anyhow::Result;
+use log::warn;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+
+/// Number of the first signal received, 0 before one
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+/// Trials recording now; a signal only exits at once when there are none
+static RECORDING: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(unix)]
+const SIGINT: i32 = libc::SIGINT;
+#[cfg(not(unix))]
+const SIGINT: i32 = 2;
+#[cfg(unix)]
+const SIGTERM: i32 = libc::SIGTERM;
+
+/// Catch SIGINT and SIGTERM for the rest of the process; needs a tokio
+/// runtime
+pub fn install() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::spawn(async move {
+            loop {
+                let signal = tokio::select! {
+                    _ = interrupt.recv() => SIGINT,
+                    _ = terminate.recv() => SIGTERM,
+                };
+                received(signal);
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    tokio::spawn(async {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            received(SIGINT);
+        }
+    });
+    Ok(())
+}
+
+fn received(signal: i32) {
+    let first = SIGNAL.compare_exchange(0, signal, Ordering::SeqCst, Ordering::SeqCst).is_ok();
+    if !first || RECORDING.load(Ordering::SeqCst) == 0 {
+        std::process::exit(128 + signal);
+    }
+    warn!("Interrupted, finishing the trial (press Ctrl+C again to quit without saving it)");
+}
+
+/// Whether a signal asked the run to stop
+pub fn requested() -> bool {
+    SIGNAL.load(Ordering::SeqCst) != 0
+}
+
+/// Exit status for a run stopped by a signal, `128 + signal` as a shell
+/// reports it
+pub fn exit_code() -> Option<i32> {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(128 + signal),
+    }
+}
+
+/// Held while a trial records and is finalized, so a signal lets it finish
+/// instead of exiting
+pub struct Recording(());
+
+impl Recording {
+    pub fn start() -> Self {
+        RECORDING.fetch_add(1, Ordering::SeqCst);
+        Self(())
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        RECORDING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
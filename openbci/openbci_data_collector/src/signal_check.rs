@@ -0,0 +1,304 @@
+//! Signal quality check before a trial, for `--signal-check`.
+//!
+//! A short resting stream is scored per channel: railed (within 10% of the
+//! ADC range), RMS in 1-40 Hz (flat or noisy electrodes) and power line
+//! noise. On a Cyton the electrode impedance is measured too, one channel
+//! at a time, from the amplitude of the 31.25 Hz lead-off test current on
+//! its N input. Railed, flat, noisy or high-impedance channels fail the
+//! check; strong line noise only warns, since a notch can remove it.
+
+use crate::features;
+use eeg_dsp::Band;
+use crate::montage;
+use crate::source::{BoardSource, DataSource};
+use anyhow::Result;
+use log::{info, warn};
+use openbci_wifi_client::{BoardCommands, BoardKind, BoardTransport};
+use std::f64::consts::PI;
+use std::fmt::Write as _;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Resting stream scored for railing, RMS and line noise
+const BASELINE: Duration = Duration::from_secs(2);
+/// Per channel: settling after the lead-off current is switched on, then
+/// the window its amplitude is measured over
+const IMPEDANCE_SETTLE: Duration = Duration::from_millis(300);
+const IMPEDANCE_WINDOW: Duration = Duration::from_secs(1);
+
+/// ADS1299 lead-off current: 6 nA at fCLK / 2^16
+const LEAD_OFF_HZ: f64 = 31.25;
+const LEAD_OFF_NA: f64 = 6.0;
+/// Cyton input protection resistor in series with every electrode
+const SERIES_OHM: f64 = 2200.0;
+
+/// Share of the ADC range beyond which a channel counts as railed
+const RAILED_FRACTION: f32 = 0.9;
+/// RMS limits in 1-40 Hz, in µV
+pub(crate) const FLAT_UV: f64 = 0.5;
+pub(crate) const NOISY_UV: f64 = 100.0;
+/// Line noise RMS above which the check warns, in µV
+const LINE_NOISE_UV: f64 = 10.0;
+
+/// Outcome for one channel or the whole check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verdict {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Verdict {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Pass => "PASS",
+            Self::Warn => "WARN",
+            Self::Fail => "FAIL",
+        }
+    }
+}
+
+/// Scores of one channel
+#[derive(Debug, Clone)]
+pub struct ChannelQuality {
+    pub label: String,
+    pub railed: bool,
+    pub rms_uv: f64,
+    pub line_noise_uv: f64,
+    /// `None` when the board cannot measure it
+    pub impedance_kohm: Option<f32>,
+    pub verdict: Verdict,
+    pub issues: Vec<String>,
+}
+
+/// Scores of every channel
+#[derive(Debug, Clone)]
+pub struct SignalCheck {
+    pub line_frequency: f64,
+    pub channels: Vec<ChannelQuality>,
+}
+
+impl SignalCheck {
+    /// Score a resting `baseline` (nanovolts, one vector per channel) and
+    /// any measured impedances against `max_impedance_kohm`
+    pub fn analyze(
+        labels: &[String],
+        baseline: &[Vec<f32>],
+        sample_rate: u32,
+        line_frequency: f64,
+        range_uv: f64,
+        impedance_kohm: Option<&[f32]>,
+        max_impedance_kohm: Option<f32>,
+    ) -> Self {
+        let fs = sample_rate as f64;
+        let band = Band::new("check", 1.0, 40f64.min(fs / 2.0 - 1.0));
+        let rail_nv = (range_uv * 1000.0) as f32 * RAILED_FRACTION;
+        let channels = labels
+            .iter()
+            .zip(baseline)
+            .enumerate()
+            .map(|(i, (label, signal))| {
+                let railed = !signal.is_empty() && signal.iter().any(|x| x.abs() >= rail_nv);
+                let rms_uv = if signal.is_empty() {
+                    0.0
+                } else {
+                    // Rates too low for the band are checked unfiltered
+                    let filtered = features::bandpass(signal, fs, &band).unwrap_or_else(|_| signal.clone());
+                    montage::channel_rms(&[filtered])[0] as f64 / 1000.0
+                };
+                let line_noise_uv = if line_frequency < fs / 2.0 {
+                    amplitude_at(signal, fs, line_frequency) / 2f64.sqrt() / 1000.0
+                } else {
+                    0.0
+                };
+                let impedance_kohm = impedance_kohm.and_then(|z| z.get(i).copied());
+
+                let mut issues = Vec::new();
+                let mut verdict = Verdict::Pass;
+                let mut fail = |issue: String| {
+                    verdict = Verdict::Fail;
+                    issues.push(issue);
+                };
+                if railed {
+                    fail("railed".to_string());
+                } else if rms_uv < FLAT_UV {
+                    fail("flat".to_string());
+                } else if rms_uv > NOISY_UV {
+                    fail("noisy".to_string());
+                }
+                if let (Some(z), Some(limit)) = (impedance_kohm, max_impedance_kohm) {
+                    if z > limit {
+                        fail(format!("impedance > {} kOhm", limit));
+                    }
+                }
+                if line_noise_uv > LINE_NOISE_UV {
+                    verdict = verdict.max(Verdict::Warn);
+                    issues.push(format!("{} Hz noise", line_frequency));
+                }
+                ChannelQuality {
+                    label: label.clone(),
+                    railed,
+                    rms_uv,
+                    line_noise_uv,
+                    impedance_kohm,
+                    verdict,
+                    issues,
+                }
+            })
+            .collect();
+        Self { line_frequency, channels }
+    }
+
+    pub fn verdict(&self) -> Verdict {
+        self.channels.iter().map(|c| c.verdict).max().unwrap_or(Verdict::Pass)
+    }
+
+    /// Measured impedances in channel order, for the trial metadata
+    pub fn impedances(&self) -> Option<Vec<f32>> {
+        self.channels.iter().map(|c| c.impedance_kohm).collect()
+    }
+
+    /// The per-channel table printed before the trial
+    pub fn table(&self) -> String {
+        let width = self.channels.iter().map(|c| c.label.len()).max().unwrap_or(0).max(7);
+        let mut table = String::new();
+        // Writing to a String cannot fail
+        let _ = writeln!(
+            table,
+            "{:<width$}  {:>9}  {:>9}  {:>11}  Result",
+            "Channel",
+            "RMS µV",
+            format!("{} Hz µV", self.line_frequency),
+            "Impedance"
+        );
+        for channel in &self.channels {
+            let impedance = channel.impedance_kohm.map_or("n/a".to_string(), |z| format!("{:.1} kOhm", z));
+            let issues = if channel.issues.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", channel.issues.join(", "))
+            };
+            let _ = writeln!(
+                table,
+                "{:<width$}  {:>9.2}  {:>9.2}  {:>11}  {}{}",
+                channel.label,
+                channel.rms_uv,
+                channel.line_noise_uv,
+                impedance,
+                channel.verdict.name(),
+                issues
+            );
+        }
+        let _ = write!(table, "Signal check: {}", self.verdict().name());
+        table
+    }
+}
+
+/// Peak amplitude of the `frequency` component of `signal`, from a
+/// Hann-windowed single-bin DFT of the mean-removed signal
+fn amplitude_at(signal: &[f32], sample_rate: f64, frequency: f64) -> f64 {
+    let n = signal.len();
+    if n < 2 {
+        return 0.0;
+    }
+    let mean = signal.iter().map(|&x| x as f64).sum::<f64>() / n as f64;
+    let (mut re, mut im, mut window_sum) = (0.0, 0.0, 0.0);
+    for (i, &x) in signal.iter().enumerate() {
+        let w = 0.5 - 0.5 * (2.0 * PI * i as f64 / (n - 1) as f64).cos();
+        let phase = 2.0 * PI * frequency * i as f64 / sample_rate;
+        re += w * (x as f64 - mean) * phase.cos();
+        im -= w * (x as f64 - mean) * phase.sin();
+        window_sum += w;
+    }
+    2.0 * (re * re + im * im).sqrt() / window_sum
+}
+
+/// Impedance in kOhm from a channel recorded with the lead-off current on
+fn impedance_kohm(signal: &[f32], sample_rate: u32) -> f32 {
+    let amplitude_nv = amplitude_at(signal, sample_rate as f64, LEAD_OFF_HZ);
+    ((amplitude_nv / LEAD_OFF_NA - SERIES_OHM) / 1000.0).max(0.0) as f32
+}
+
+/// Stream from `board` for a moment, measure impedances if it is a Cyton
+/// and score every channel
+pub async fn run(
+    board: &dyn BoardTransport,
+    labels: &[String],
+    sample_rate: u32,
+    line_frequency: f64,
+    range_uv: f64,
+    max_impedance_kohm: Option<f32>,
+) -> Result<SignalCheck> {
+    info!("Signal check: sit still and relax for a few seconds");
+    board.stop_stream().await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let mut source = BoardSource::open(board).await?;
+    let baseline = capture(&mut source, labels.len(), BASELINE).await;
+
+    let impedances = match BoardCommands::detect(board).await {
+        Ok(commands) if commands.capabilities().is_some_and(|c| c.board != BoardKind::Ganglion) => {
+            match measure_impedances(&commands, &mut source, labels, sample_rate).await {
+                Ok(impedances) => Some(impedances),
+                Err(e) => {
+                    warn!("Impedance check failed, skipping it: {}", e);
+                    None
+                }
+            }
+        }
+        _ => {
+            info!("Board cannot measure impedance over lead-off, skipping it");
+            None
+        }
+    };
+    board.stop_stream().await?;
+    drop(source);
+
+    Ok(SignalCheck::analyze(
+        labels,
+        &baseline,
+        sample_rate,
+        line_frequency,
+        range_uv,
+        impedances.as_deref(),
+        max_impedance_kohm,
+    ))
+}
+
+/// Lead-off on, settle, measure, lead-off off, one channel at a time
+async fn measure_impedances(
+    commands: &BoardCommands<'_>,
+    source: &mut BoardSource,
+    labels: &[String],
+    sample_rate: u32,
+) -> Result<Vec<f32>> {
+    let mut impedances = Vec::with_capacity(labels.len());
+    for (i, label) in labels.iter().enumerate() {
+        let channel = i as u8 + 1;
+        commands.set_impedance_check(channel, true).await?;
+        capture(source, labels.len(), IMPEDANCE_SETTLE).await;
+        let window = capture(source, labels.len(), IMPEDANCE_WINDOW).await;
+        commands.set_impedance_check(channel, false).await?;
+        let z = impedance_kohm(&window[i], sample_rate);
+        info!("Impedance {}: {:.1} kOhm", label, z);
+        impedances.push(z);
+    }
+    Ok(impedances)
+}
+
+/// Samples of every channel for `duration`
+async fn capture(source: &mut BoardSource, channels: usize, duration: Duration) -> Vec<Vec<f32>> {
+    let mut data = vec![Vec::new(); channels];
+    let end = Instant::now() + duration;
+    while Instant::now() < end {
+        match tokio::time::timeout(Duration::from_millis(100), source.next_sample()).await {
+            Ok(Some(sample)) => {
+                for (channel, value) in data.iter_mut().zip(sample.channels) {
+                    channel.push(value);
+                }
+            }
+            Ok(None) => break,
+            Err(_) => {}
+        }
+    }
+    data
+}
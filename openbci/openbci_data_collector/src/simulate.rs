@@ -0,0 +1,310 @@
+//! Simulated EEG for `--simulate`.
+//!
+//! Realistic enough to take recording, training and online classification
+//! end to end without a board: a 1/f background, posterior alpha bursts, a
+//! sensorimotor mu and beta rhythm that desynchronises over the hemisphere
+//! contralateral to the imagined hand, and occasional blinks and EMG bursts
+//! from the artifact injection templates. Amplitudes follow each channel's
+//! 10-20 label; unlabelled channels get background and a little alpha.
+
+use crate::augment::{ArtifactKind, ArtifactSegment};
+use crate::sink::EEGSample;
+use crate::source::{DataSource, Pacer};
+use async_trait::async_trait;
+use openbci_wifi_client::stream::unix_time;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+/// Background RMS, in nanovolts
+const BACKGROUND_RMS_NV: f64 = 8_000.0;
+/// RMS of the pink noise filter for unit white noise
+const PINK_GAIN: f64 = 3.0;
+/// Oscillation peaks at the strongest site, in nanovolts
+const ALPHA_PEAK_NV: f64 = 20_000.0;
+const MU_PEAK_NV: f64 = 12_000.0;
+const BETA_PEAK_NV: f64 = 4_000.0;
+/// Per-channel mu amplitude drift: time constant and step per sample
+const MU_DRIFT_SECONDS: f64 = 0.5;
+const MU_DRIFT_STEP: f64 = 0.03;
+/// Alpha bursts last 0.5-2 s with 1-3 s between them
+const BURST_SECONDS: (f64, f64) = (0.5, 2.0);
+const PAUSE_SECONDS: (f64, f64) = (1.0, 3.0);
+/// Time constant of burst onsets and offsets
+const BURST_RAMP_SECONDS: f64 = 0.15;
+/// Imagery starts this long after the trial (or its cue) does and the ERD
+/// takes `ERD_RAMP_SECONDS` to develop
+const ERD_ONSET_SECONDS: f64 = 0.5;
+const ERD_RAMP_SECONDS: f64 = 1.0;
+/// ERD over the ipsilateral hemisphere and the midline, relative to the
+/// contralateral one
+const IPSILATERAL_ERD: f64 = 0.25;
+const MIDLINE_ERD: f64 = 0.5;
+/// Share of simulated artifacts that are blinks rather than EMG
+const BLINK_SHARE: f64 = 0.7;
+
+/// How a simulated trial was generated, recorded in its metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimulationInfo {
+    pub seed: u64,
+    /// Fraction of mu and beta amplitude lost contralaterally
+    pub erd_depth: f64,
+    pub artifacts_per_minute: f64,
+    /// Seconds from the trial start to the cue, with `--cues`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cue_seconds: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Hemisphere {
+    Left,
+    Right,
+    Midline,
+}
+
+/// Hemisphere of a 10-20 site: odd numbers left, even right, `z` midline
+fn hemisphere(site: &str) -> Option<Hemisphere> {
+    match site.chars().last()? {
+        'z' | 'Z' => Some(Hemisphere::Midline),
+        c => c.to_digit(10).map(|d| if d % 2 == 1 { Hemisphere::Left } else { Hemisphere::Right }),
+    }
+}
+
+/// Alpha is strongest occipitally and parietally
+fn alpha_weight(site: &str) -> f64 {
+    match site {
+        s if s.starts_with("PO") || s.starts_with('O') => 1.0,
+        s if s.starts_with('P') => 0.8,
+        s if s.starts_with('T') => 0.4,
+        s if s.starts_with("CP") || s.starts_with('C') => 0.3,
+        s if s.starts_with("Fp") || s.starts_with("FP") => 0.1,
+        _ => 0.2,
+    }
+}
+
+/// Mu and beta sit over the sensorimotor strip
+fn mu_weight(site: &str) -> f64 {
+    match site {
+        s if s.starts_with("FC") || s.starts_with("CP") => 0.6,
+        s if s.starts_with('C') => 1.0,
+        _ => 0.15,
+    }
+}
+
+/// ERD depth at a site while imagining `class`
+fn erd_depth(class: &str, hemisphere: Option<Hemisphere>, depth: f64) -> f64 {
+    use Hemisphere::*;
+    match (class, hemisphere) {
+        ("left_hand", Some(Right)) | ("right_hand", Some(Left)) | ("both_hands", Some(Left | Right)) => depth,
+        ("left_hand", Some(Left)) | ("right_hand", Some(Right)) => depth * IPSILATERAL_ERD,
+        ("left_hand" | "right_hand" | "both_hands", Some(Midline)) => depth * MIDLINE_ERD,
+        _ => 0.0,
+    }
+}
+
+/// Per-channel weights derived from the label
+struct ChannelModel {
+    alpha: f64,
+    mu: f64,
+    erd: f64,
+    /// Phase offset, so channels are not perfectly coherent
+    phase: f64,
+    /// Slow random modulation of the local mu rhythm, around 1
+    mu_level: f64,
+    /// Paul Kellet's economy pink noise filter state
+    pink: [f64; 3],
+}
+
+/// Smoothed on/off envelope of alpha bursts
+struct BurstEnvelope {
+    level: f64,
+    on: bool,
+    remaining: u64,
+}
+
+/// Blink or EMG waveform being added
+struct ActiveArtifact {
+    segment: ArtifactSegment,
+    position: usize,
+}
+
+/// Sample generator for one simulated trial of `class`
+pub struct EegSimulator {
+    sample_rate: f64,
+    class: String,
+    labels: Vec<String>,
+    channels: Vec<ChannelModel>,
+    rng: StdRng,
+    alpha_hz: f64,
+    mu_hz: f64,
+    beta_hz: f64,
+    bursts: BurstEnvelope,
+    artifacts_per_minute: f64,
+    /// Seconds from the trial start to imagery
+    onset: f64,
+    active: Vec<ActiveArtifact>,
+    index: u64,
+}
+
+impl EegSimulator {
+    /// `labels` are the channel labels (annotated column names work too);
+    /// channels past the end of `labels` are unlabelled
+    pub fn new(labels: &[String], num_channels: usize, sample_rate: u32, class: &str, info: &SimulationInfo) -> Self {
+        let mut rng = StdRng::seed_from_u64(info.seed);
+        let labels: Vec<String> = (0..num_channels).map(|i| labels.get(i).cloned().unwrap_or_default()).collect();
+        let channels = labels
+            .iter()
+            .map(|label| {
+                let site = label.split('_').next().unwrap_or(label);
+                ChannelModel {
+                    alpha: alpha_weight(site),
+                    mu: mu_weight(site),
+                    erd: erd_depth(class, hemisphere(site), info.erd_depth),
+                    phase: rng.gen_range(0.0..0.6),
+                    mu_level: 1.0,
+                    pink: [0.0; 3],
+                }
+            })
+            .collect();
+        // Individual alpha frequency, mu a little above it
+        let alpha_hz = rng.gen_range(9.0..11.0);
+        let mu_hz = alpha_hz + rng.gen_range(0.5..1.5);
+        let beta_hz = rng.gen_range(18.0..24.0);
+        Self {
+            sample_rate: sample_rate.max(1) as f64,
+            class: class.to_string(),
+            labels,
+            channels,
+            rng,
+            alpha_hz,
+            mu_hz,
+            beta_hz,
+            bursts: BurstEnvelope {
+                level: 0.0,
+                on: false,
+                remaining: 0,
+            },
+            artifacts_per_minute: info.artifacts_per_minute.max(0.0),
+            onset: info.cue_seconds.unwrap_or(0.0) + ERD_ONSET_SECONDS,
+            active: Vec::new(),
+            index: 0,
+        }
+    }
+
+    pub fn class(&self) -> &str {
+        &self.class
+    }
+
+    /// Next sample in nanovolts
+    pub fn sample(&mut self) -> Vec<f32> {
+        let t = self.index as f64 / self.sample_rate;
+        self.index += 1;
+
+        self.step_bursts();
+        self.maybe_start_artifact();
+        // AR(1) drift of each channel's mu amplitude
+        let pull = 1.0 / (MU_DRIFT_SECONDS * self.sample_rate);
+
+        let imagery = ((t - self.onset) / ERD_RAMP_SECONDS).clamp(0.0, 1.0);
+        let (alpha_arg, mu_arg, beta_arg) = (
+            2.0 * PI * self.alpha_hz * t,
+            2.0 * PI * self.mu_hz * t,
+            2.0 * PI * self.beta_hz * t,
+        );
+        let bursts = self.bursts.level;
+
+        let mut values = Vec::with_capacity(self.channels.len());
+        for ch in 0..self.channels.len() {
+            let (white, drift) = (self.gaussian(), self.gaussian());
+            let model = &mut self.channels[ch];
+            model.mu_level += pull * (1.0 - model.mu_level) + MU_DRIFT_STEP * drift;
+            model.mu_level = model.mu_level.clamp(0.2, 1.8);
+            model.pink[0] = 0.99765 * model.pink[0] + white * 0.0990460;
+            model.pink[1] = 0.96300 * model.pink[1] + white * 0.2965164;
+            model.pink[2] = 0.57000 * model.pink[2] + white * 1.0526913;
+            let pink = (model.pink.iter().sum::<f64>() + white * 0.1848) / PINK_GAIN;
+
+            let rhythm = model.mu_level * (1.0 - model.erd * imagery) * model.mu;
+            let value = BACKGROUND_RMS_NV * pink
+                + ALPHA_PEAK_NV * model.alpha * bursts * (alpha_arg + model.phase).sin()
+                + MU_PEAK_NV * rhythm * (mu_arg + model.phase).sin()
+                + BETA_PEAK_NV * rhythm * (beta_arg + 2.0 * model.phase).sin();
+            values.push(value as f32);
+        }
+
+        for artifact in &mut self.active {
+            for (value, channel) in values.iter_mut().zip(&artifact.segment.data) {
+                *value += channel.get(artifact.position).copied().unwrap_or(0.0);
+            }
+            artifact.position += 1;
+        }
+        self.active.retain(|a| a.position < a.segment.len());
+
+        values
+    }
+
+    fn step_bursts(&mut self) {
+        if self.bursts.remaining == 0 {
+            self.bursts.on = !self.bursts.on;
+            let (low, high) = if self.bursts.on { BURST_SECONDS } else { PAUSE_SECONDS };
+            self.bursts.remaining = (self.rng.gen_range(low..high) * self.sample_rate) as u64;
+        }
+        self.bursts.remaining -= 1;
+        let target = if self.bursts.on { 1.0 } else { 0.0 };
+        self.bursts.level += (target - self.bursts.level) / (BURST_RAMP_SECONDS * self.sample_rate);
+    }
+
+    fn maybe_start_artifact(&mut self) {
+        let chance = self.artifacts_per_minute / (60.0 * self.sample_rate);
+        if !self.rng.gen_bool(chance.min(1.0)) {
+            return;
+        }
+        let kind = if self.rng.gen_bool(BLINK_SHARE) { ArtifactKind::Blink } else { ArtifactKind::Emg };
+        let segment = ArtifactSegment::synthetic(kind, &self.labels, self.sample_rate as u32, &mut self.rng);
+        self.active.push(ActiveArtifact { segment, position: 0 });
+    }
+
+    /// Standard normal deviate (Box-Muller)
+    fn gaussian(&mut self) -> f64 {
+        let u1: f64 = self.rng.gen_range(f64::EPSILON..1.0);
+        let u2: f64 = self.rng.gen();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+    }
+}
+
+/// [`EegSimulator`] output in real time, without end
+pub struct SimulatedSource {
+    simulator: EegSimulator,
+    index: u64,
+    pacer: Pacer,
+}
+
+impl SimulatedSource {
+    pub fn new(simulator: EegSimulator) -> Self {
+        let pacer = Pacer::new(simulator.sample_rate);
+        Self {
+            simulator,
+            index: 0,
+            pacer,
+        }
+    }
+}
+
+#[async_trait]
+impl DataSource for SimulatedSource {
+    fn describe(&self) -> String {
+        format!("simulated {} EEG, {} channels", self.simulator.class(), self.simulator.channels.len())
+    }
+
+    async fn next_sample(&mut self) -> Option<EEGSample> {
+        self.pacer.wait(self.index).await;
+        let sample = EEGSample {
+            timestamp: unix_time(),
+            sample_id: self.index,
+            channels: self.simulator.sample(),
+        };
+        self.index += 1;
+        Some(sample)
+    }
+}
@@ -0,0 +1,750 @@
+//! Destinations for recorded samples.
+//!
+//! The collector fans every trial out to one or more [`DataSink`]s: the
+//! trial files (CSV, BDF+, EDF+, NPZ, BrainVision, HDF5) get samples in batches,
+//! live sinks such as the OpenBCI GUI bridge, OSC, LSL outlets, WebSocket
+//! clients and the ZeroMQ publisher get each sample as it arrives. Markers reach every sink through
+//! [`DataSink::insert_event`] ahead of the sample they are anchored to.
+//! [`OutputFormat`] is what `--format` picks the trial files from.
+
+use crate::bdf::{self, BdfWriter, Flavor};
+use crate::brainvision::BrainVisionWriter;
+use crate::compress::{self, Compression, FileWriter};
+use crate::gui_bridge::GuiBridge;
+#[cfg(feature = "hdf5")]
+use crate::hdf5;
+#[cfg(feature = "lsl")]
+use crate::lsl::LslOutlets;
+use crate::metadata::{MarkerRecord, TrialMetadata};
+use crate::npz;
+use crate::osc::OscSamples;
+use crate::websocket::WsTrial;
+#[cfg(feature = "zmq")]
+use crate::zmq_pub::ZmqTrial;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use log::{debug, info};
+use openbci_wifi_client::timestamp_seconds;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// EEG sample with metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EEGSample {
+    pub timestamp: f64,
+    pub sample_id: u64,
+    pub channels: Vec<f32>,
+}
+
+/// Destination for a trial's samples
+pub trait DataSink: Send {
+    /// Format name for logs
+    fn name(&self) -> &'static str;
+
+    /// The file this sink writes the trial to, if any
+    fn data_file(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Live sinks are fed every sample as it arrives instead of in batches
+    fn is_live(&self) -> bool {
+        false
+    }
+
+    fn write_batch(&mut self, samples: &[EEGSample]) -> Result<()>;
+
+    /// Marker anchored to the sample with `event.sample_id`, which has not
+    /// been written yet
+    fn insert_event(&mut self, event: &MarkerRecord) -> Result<()>;
+
+    /// Close the sink; `metadata` is final apart from being written
+    fn finalize(&mut self, metadata: &TrialMetadata) -> Result<()>;
+}
+
+/// Events waiting for the sample they are anchored to
+#[derive(Debug, Default)]
+pub struct PendingEvents(VecDeque<MarkerRecord>);
+
+impl PendingEvents {
+    pub fn push(&mut self, event: &MarkerRecord) {
+        self.0.push_back(event.clone());
+    }
+
+    /// Labels due at the sample with `sample_id`. Events of samples that
+    /// never reached the sink (failed writes) land on the next one.
+    pub fn take(&mut self, sample_id: u64) -> impl Iterator<Item = String> + '_ {
+        std::iter::from_fn(move || {
+            if self.0.front()?.sample_id <= sample_id {
+                self.0.pop_front().map(|event| event.label)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// How often a CSV file is flushed while batches keep coming. A writer
+/// catching up on a backlog writes many batches back to back, and flushing
+/// each would cost a compressor block and a write call apiece.
+const CSV_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One row per sample, floats in nanovolts; compressed when the file name
+/// ends in `.zst` or `.gz`
+pub struct CsvSink {
+    file_path: PathBuf,
+    writer: Option<csv::Writer<FileWriter>>,
+    samples_written: u64,
+    class_id: u8,
+    events: PendingEvents,
+    /// Formatting buffer reused for every field
+    field: String,
+    last_flush: Instant,
+}
+
+impl CsvSink {
+    pub fn new(file_path: PathBuf, class_id: u8, channel_labels: &[String]) -> Result<Self> {
+        let file = FileWriter::create(&file_path, Compression::of(&file_path))?;
+
+        let mut writer = csv::Writer::from_writer(file);
+
+        // Write header with class_id for easy loading in deep learning
+        let mut header = vec!["timestamp".to_string(), "sample_id".to_string(), "class_id".to_string()];
+        header.extend(channel_labels.iter().cloned());
+        header.push("marker".to_string());
+        writer.write_record(&header)?;
+
+        Ok(Self {
+            file_path,
+            writer: Some(writer),
+            samples_written: 0,
+            class_id,
+            events: PendingEvents::default(),
+            field: String::new(),
+            last_flush: Instant::now(),
+        })
+    }
+}
+
+impl DataSink for CsvSink {
+    fn name(&self) -> &'static str {
+        "CSV"
+    }
+
+    fn data_file(&self) -> Option<&Path> {
+        Some(&self.file_path)
+    }
+
+    fn write_batch(&mut self, samples: &[EEGSample]) -> Result<()> {
+        let writer = self.writer.as_mut().context("CSV file already finalized")?;
+        let field = &mut self.field;
+        for sample in samples {
+            write_field(writer, field, sample.timestamp)?;
+            write_field(writer, field, sample.sample_id)?;
+            write_field(writer, field, self.class_id)?;
+            for ch in &sample.channels {
+                write_field(writer, field, ch)?;
+            }
+            write_field(writer, field, self.events.take(sample.sample_id).collect::<Vec<_>>().join("|"))?;
+            writer.write_record(None::<&[u8]>)?;
+            self.samples_written += 1;
+        }
+
+        if self.last_flush.elapsed() >= CSV_FLUSH_INTERVAL {
+            writer.flush()?;
+            self.last_flush = Instant::now();
+        }
+        debug!("Wrote {} samples to CSV (total: {})", samples.len(), self.samples_written);
+
+        Ok(())
+    }
+
+    fn insert_event(&mut self, event: &MarkerRecord) -> Result<()> {
+        self.events.push(event);
+        Ok(())
+    }
+
+    fn finalize(&mut self, _metadata: &TrialMetadata) -> Result<()> {
+        let Some(writer) = self.writer.take() else {
+            return Ok(());
+        };
+        writer.into_inner().map_err(|e| e.into_error())?.finish()?;
+        info!("Finalized CSV file: {:?}", self.file_path);
+        Ok(())
+    }
+}
+
+/// Format `value` into `buffer` and write it as the next field of the row
+fn write_field(writer: &mut csv::Writer<FileWriter>, buffer: &mut String, value: impl fmt::Display) -> Result<()> {
+    buffer.clear();
+    write!(buffer, "{}", value)?;
+    writer.write_field(buffer.as_bytes())?;
+    Ok(())
+}
+
+/// BDF+ or EDF+; sample timestamps are implied by the sample rate and
+/// markers become annotations
+pub struct BdfSink {
+    flavor: Flavor,
+    file_path: PathBuf,
+    writer: Option<BdfWriter>,
+    events: PendingEvents,
+}
+
+impl BdfSink {
+    pub fn new(
+        flavor: Flavor,
+        file_path: PathBuf,
+        metadata: &TrialMetadata,
+        channel_labels: &[String],
+        range_uv: f64,
+    ) -> Result<Self> {
+        // EDF+ patient and recording identification fields
+        let patient = format!("{} X X X", metadata.subject_id);
+        let recording = format!(
+            "Startdate {} {} X OpenBCI_Cyton {}",
+            metadata.start_time.format("%d-%b-%Y").to_string().to_uppercase(),
+            metadata.session_id,
+            metadata.class_label
+        );
+        let writer = BdfWriter::create(
+            flavor,
+            &file_path,
+            &patient,
+            &recording,
+            metadata.start_time,
+            channel_labels,
+            metadata.sample_rate,
+            range_uv,
+        )?;
+        Ok(Self {
+            flavor,
+            file_path,
+            writer: Some(writer),
+            events: PendingEvents::default(),
+        })
+    }
+}
+
+impl DataSink for BdfSink {
+    fn name(&self) -> &'static str {
+        self.flavor.name()
+    }
+
+    fn data_file(&self) -> Option<&Path> {
+        Some(&self.file_path)
+    }
+
+    fn write_batch(&mut self, samples: &[EEGSample]) -> Result<()> {
+        let name = self.name();
+        let writer = self.writer.as_mut().with_context(|| format!("{} file already finalized", name))?;
+        for sample in samples {
+            for label in self.events.take(sample.sample_id) {
+                writer.annotate(&label);
+            }
+            writer.write_sample(&sample.channels)?;
+        }
+        debug!("Wrote {} samples to {} (total: {})", samples.len(), name, writer.samples_written());
+        Ok(())
+    }
+
+    fn insert_event(&mut self, event: &MarkerRecord) -> Result<()> {
+        self.events.push(event);
+        Ok(())
+    }
+
+    fn finalize(&mut self, _metadata: &TrialMetadata) -> Result<()> {
+        match self.writer.take() {
+            Some(writer) => writer.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// NumPy `.npz`. The archive is written in one go at the end, so samples
+/// are held in memory until then (about 15 MB for ten minutes of 16
+/// channels at 250 Hz). Markers are only kept in the embedded metadata.
+pub struct NpzSink {
+    file_path: PathBuf,
+    num_channels: usize,
+    /// Deflate the arrays, like `np.savez_compressed`
+    compressed: bool,
+    /// Row-major samples, nanovolts
+    values: Vec<f32>,
+    rows: usize,
+}
+
+impl NpzSink {
+    pub fn new(file_path: PathBuf, num_channels: usize, compressed: bool) -> Self {
+        Self {
+            file_path,
+            num_channels,
+            compressed,
+            values: Vec::new(),
+            rows: 0,
+        }
+    }
+}
+
+impl DataSink for NpzSink {
+    fn name(&self) -> &'static str {
+        "NPZ"
+    }
+
+    fn data_file(&self) -> Option<&Path> {
+        Some(&self.file_path)
+    }
+
+    fn write_batch(&mut self, samples: &[EEGSample]) -> Result<()> {
+        for sample in samples {
+            let mut row = sample.channels.clone();
+            row.resize(self.num_channels, f32::NAN);
+            self.values.extend(row);
+            self.rows += 1;
+        }
+        info!("Buffered {} samples for NPZ (total: {})", samples.len(), self.rows);
+        Ok(())
+    }
+
+    fn insert_event(&mut self, _event: &MarkerRecord) -> Result<()> {
+        Ok(())
+    }
+
+    fn finalize(&mut self, metadata: &TrialMetadata) -> Result<()> {
+        npz::write_npz(
+            &self.file_path,
+            &[
+                ("X", npz::npy_f32(self.rows, self.num_channels, &self.values)),
+                ("y", npz::npy_i64_scalar(metadata.class_id as i64)),
+                ("meta", npz::npy_str_scalar(&serde_json::to_string(metadata)?)),
+            ],
+            self.compressed,
+        )?;
+        info!("Finalized NPZ file: {:?}", self.file_path);
+        Ok(())
+    }
+}
+
+/// HDF5, held in memory and written at the end like NPZ, with the
+/// timestamps and markers alongside the samples
+#[cfg(feature = "hdf5")]
+pub struct Hdf5Sink {
+    file_path: PathBuf,
+    channel_names: Vec<String>,
+    /// Row-major samples, nanovolts
+    values: Vec<f32>,
+    timestamps: Vec<f64>,
+    events: PendingEvents,
+    event_rows: Vec<i64>,
+    event_labels: Vec<String>,
+}
+
+#[cfg(feature = "hdf5")]
+impl Hdf5Sink {
+    pub fn new(file_path: PathBuf, channel_names: &[String]) -> Self {
+        Self {
+            file_path,
+            channel_names: channel_names.to_vec(),
+            values: Vec::new(),
+            timestamps: Vec::new(),
+            events: PendingEvents::default(),
+            event_rows: Vec::new(),
+            event_labels: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "hdf5")]
+impl DataSink for Hdf5Sink {
+    fn name(&self) -> &'static str {
+        "HDF5"
+    }
+
+    fn data_file(&self) -> Option<&Path> {
+        Some(&self.file_path)
+    }
+
+    fn write_batch(&mut self, samples: &[EEGSample]) -> Result<()> {
+        for sample in samples {
+            for label in self.events.take(sample.sample_id) {
+                self.event_rows.push(self.timestamps.len() as i64);
+                self.event_labels.push(label);
+            }
+            let mut row = sample.channels.clone();
+            row.resize(self.channel_names.len(), f32::NAN);
+            self.values.extend(row);
+            self.timestamps.push(sample.timestamp);
+        }
+        debug!("Buffered {} samples for HDF5 (total: {})", samples.len(), self.timestamps.len());
+        Ok(())
+    }
+
+    fn insert_event(&mut self, event: &MarkerRecord) -> Result<()> {
+        self.events.push(event);
+        Ok(())
+    }
+
+    fn finalize(&mut self, metadata: &TrialMetadata) -> Result<()> {
+        let meta = serde_json::to_string(metadata)?;
+        hdf5::write(
+            &self.file_path,
+            &[
+                ("X", hdf5::Data::F32 { rows: self.timestamps.len(), cols: self.channel_names.len(), values: &self.values }),
+                ("y", hdf5::Data::I64Scalar(metadata.class_id as i64)),
+                ("meta", hdf5::Data::Str(&meta)),
+                ("timestamps", hdf5::Data::F64(&self.timestamps)),
+                ("channels", hdf5::Data::Strings(&self.channel_names)),
+                ("event_sample", hdf5::Data::I64(&self.event_rows)),
+                ("event_label", hdf5::Data::Strings(&self.event_labels)),
+            ],
+        )?;
+        info!("Finalized HDF5 file: {:?}", self.file_path);
+        Ok(())
+    }
+}
+
+/// BrainVision triplet. Cues and injected artifacts go into the marker
+/// file, and gaps in the recording become `New Segment` markers.
+pub struct BrainVisionSink {
+    file_path: PathBuf,
+    writer: Option<BrainVisionWriter>,
+    events: PendingEvents,
+    /// `(first sample ID, length)` of every written batch, to place gaps
+    batches: Vec<(u64, u64)>,
+}
+
+impl BrainVisionSink {
+    pub fn new(file_path: PathBuf, metadata: &TrialMetadata) -> Result<Self> {
+        let writer = BrainVisionWriter::create(
+            &file_path,
+            &metadata.electrode_config.channels,
+            &metadata.electrode_config.reference,
+            metadata.sample_rate,
+            metadata.start_time,
+        )?;
+        Ok(Self {
+            file_path,
+            writer: Some(writer),
+            events: PendingEvents::default(),
+            batches: Vec::new(),
+        })
+    }
+
+    /// Rows in the file before the sample with `sample_id`
+    fn position(&self, sample_id: u64) -> u64 {
+        self.batches
+            .iter()
+            .map(|&(first, len)| sample_id.saturating_sub(first).min(len))
+            .sum()
+    }
+}
+
+impl DataSink for BrainVisionSink {
+    fn name(&self) -> &'static str {
+        "BrainVision"
+    }
+
+    fn data_file(&self) -> Option<&Path> {
+        Some(&self.file_path)
+    }
+
+    fn write_batch(&mut self, samples: &[EEGSample]) -> Result<()> {
+        let writer = self.writer.as_mut().context("BrainVision file already finalized")?;
+        for sample in samples {
+            for label in self.events.take(sample.sample_id) {
+                writer.annotate(&label);
+            }
+            writer.write_sample(&sample.channels)?;
+        }
+        if let Some(first) = samples.first() {
+            self.batches.push((first.sample_id, samples.len() as u64));
+        }
+        debug!("Wrote {} samples to BrainVision (total: {})", samples.len(), writer.samples_written());
+        Ok(())
+    }
+
+    fn insert_event(&mut self, event: &MarkerRecord) -> Result<()> {
+        self.events.push(event);
+        Ok(())
+    }
+
+    fn finalize(&mut self, metadata: &TrialMetadata) -> Result<()> {
+        let mut positions: Vec<u64> = metadata
+            .stream_health
+            .iter()
+            .flat_map(|health| &health.gaps)
+            .map(|gap| self.position(gap.sample_id))
+            .collect();
+        // Back-to-back gaps are one discontinuity in the file
+        positions.dedup();
+        match self.writer.take() {
+            Some(mut writer) => {
+                for position in positions {
+                    writer.new_segment(position);
+                }
+                writer.finish()
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// The OpenBCI GUI's Networking widget, fed live
+impl DataSink for GuiBridge {
+    fn name(&self) -> &'static str {
+        "OpenBCI GUI"
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+
+    fn write_batch(&mut self, samples: &[EEGSample]) -> Result<()> {
+        for sample in samples {
+            self.push(&sample.channels);
+        }
+        Ok(())
+    }
+
+    fn insert_event(&mut self, _event: &MarkerRecord) -> Result<()> {
+        Ok(())
+    }
+
+    fn finalize(&mut self, _metadata: &TrialMetadata) -> Result<()> {
+        self.flush();
+        let (sent, dropped) = self.counts();
+        info!("OpenBCI GUI bridge: {} packets sent, {} dropped", sent, dropped);
+        Ok(())
+    }
+}
+
+/// OSC receiver of `--osc-data samples`, fed live
+impl DataSink for OscSamples {
+    fn name(&self) -> &'static str {
+        "OSC"
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+
+    fn write_batch(&mut self, samples: &[EEGSample]) -> Result<()> {
+        for sample in samples {
+            self.push(&sample.channels);
+        }
+        Ok(())
+    }
+
+    fn insert_event(&mut self, _event: &MarkerRecord) -> Result<()> {
+        Ok(())
+    }
+
+    fn finalize(&mut self, _metadata: &TrialMetadata) -> Result<()> {
+        self.flush();
+        let (sent, dropped) = self.counts();
+        info!("OSC: {} sample bundles sent, {} dropped", sent, dropped);
+        Ok(())
+    }
+}
+
+/// WebSocket clients of `--ws-port`, fed live
+impl DataSink for WsTrial {
+    fn name(&self) -> &'static str {
+        "WebSocket"
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+
+    fn write_batch(&mut self, samples: &[EEGSample]) -> Result<()> {
+        for sample in samples {
+            self.push(sample.sample_id, sample.timestamp, &sample.channels);
+        }
+        Ok(())
+    }
+
+    fn insert_event(&mut self, event: &MarkerRecord) -> Result<()> {
+        self.marker(event);
+        Ok(())
+    }
+
+    fn finalize(&mut self, _metadata: &TrialMetadata) -> Result<()> {
+        let (samples, markers) = self.finish();
+        info!("WebSocket: {} samples and {} markers broadcast", samples, markers);
+        Ok(())
+    }
+}
+
+/// Lab Streaming Layer outlets, fed live
+#[cfg(feature = "lsl")]
+impl DataSink for LslOutlets {
+    fn name(&self) -> &'static str {
+        "LSL"
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+
+    fn write_batch(&mut self, samples: &[EEGSample]) -> Result<()> {
+        self.push_samples(samples.iter().map(|s| s.channels.as_slice()))
+    }
+
+    fn insert_event(&mut self, event: &MarkerRecord) -> Result<()> {
+        self.push_marker(&event.label, event.host_time)
+    }
+
+    fn finalize(&mut self, _metadata: &TrialMetadata) -> Result<()> {
+        let (samples, markers) = self.counts();
+        info!("LSL: {} samples and {} markers published", samples, markers);
+        Ok(())
+    }
+}
+
+/// ZeroMQ publisher of `--zmq-pub`, fed live
+#[cfg(feature = "zmq")]
+impl DataSink for ZmqTrial {
+    fn name(&self) -> &'static str {
+        "ZeroMQ"
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+
+    fn write_batch(&mut self, samples: &[EEGSample]) -> Result<()> {
+        for sample in samples {
+            self.push(sample.sample_id, sample.timestamp, &sample.channels);
+        }
+        Ok(())
+    }
+
+    fn insert_event(&mut self, event: &MarkerRecord) -> Result<()> {
+        self.marker(event);
+        Ok(())
+    }
+
+    fn finalize(&mut self, _metadata: &TrialMetadata) -> Result<()> {
+        let (samples, markers) = self.finish();
+        info!("ZeroMQ: {} samples and {} markers published", samples, markers);
+        Ok(())
+    }
+}
+
+/// PGA gain the Cyton firmware applies by default; sets the BDF range
+/// unless the board reported its gains
+pub(crate) const CYTON_DEFAULT_GAIN: u8 = 24;
+
+/// Trial data file format
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// One row per sample, floats in nanovolts
+    Csv,
+    /// BDF+ with full 24-bit resolution and markers as annotations
+    Bdf,
+    /// EDF+ for EDF-only tools: 16-bit over +/-3.3 mV, markers as annotations
+    Edf,
+    /// NumPy archive: `X` [samples, channels] float32, `y`, `meta` JSON
+    Npz,
+    /// BrainVision `.vhdr`/`.vmrk`/`.eeg` triplet, float32 in µV
+    Brainvision,
+    /// HDF5 with `X`, `timestamps`, markers and `meta` (build with
+    /// --features hdf5)
+    Hdf5,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Bdf => "bdf",
+            Self::Edf => "edf",
+            Self::Npz => "npz",
+            Self::Brainvision => "vhdr",
+            Self::Hdf5 => "h5",
+        }
+    }
+
+    /// Rough size of one channel value on disk, for the free space check
+    pub fn bytes_per_value(&self) -> u64 {
+        match self {
+            Self::Csv => 12,
+            Self::Bdf => 3,
+            Self::Edf => 2,
+            Self::Npz | Self::Brainvision | Self::Hdf5 => 4,
+        }
+    }
+}
+
+/// Open a trial file of `format` at `data_path`
+pub(crate) fn open_sink(
+    format: OutputFormat,
+    data_path: PathBuf,
+    metadata: &TrialMetadata,
+    channel_names: &[String],
+    compress: Option<Compression>,
+) -> Result<Box<dyn DataSink>> {
+    // Samples span the range of the gain they were scaled with; the
+    // lowest one is the widest, so no channel clips
+    let gain = metadata
+        .board_settings
+        .as_ref()
+        .and_then(|settings| settings.scaling_gains.iter().take(metadata.num_channels).min().copied())
+        .unwrap_or(CYTON_DEFAULT_GAIN);
+    let cyton_range = bdf::ads1299_range_uv(gain);
+    Ok(match format {
+        OutputFormat::Csv => Box::new(CsvSink::new(data_path, metadata.class_id, channel_names)?),
+        OutputFormat::Bdf => Box::new(BdfSink::new(Flavor::Bdf, data_path, metadata, channel_names, cyton_range)?),
+        OutputFormat::Edf => Box::new(BdfSink::new(Flavor::Edf, data_path, metadata, channel_names, bdf::EDF_RANGE_UV)?),
+        OutputFormat::Npz => Box::new(NpzSink::new(data_path, channel_names.len(), compress.is_some())),
+        OutputFormat::Brainvision => Box::new(BrainVisionSink::new(data_path, metadata)?),
+        #[cfg(feature = "hdf5")]
+        OutputFormat::Hdf5 => Box::new(Hdf5Sink::new(data_path, channel_names)),
+        #[cfg(not(feature = "hdf5"))]
+        OutputFormat::Hdf5 => anyhow::bail!("HDF5 support not compiled in, rebuild with --features hdf5 (needs libhdf5)"),
+    })
+}
+
+/// Path of a trial file of `format` with the stem `stem`
+pub(crate) fn format_path(format: OutputFormat, stem: &Path, compress: Option<Compression>) -> PathBuf {
+    match format {
+        OutputFormat::Csv => compress::compressed_path(&stem.with_extension(format.extension()), compress),
+        _ => stem.with_extension(format.extension()),
+    }
+}
+
+/// Write a recording's rows to `sinks`, each marker before the row it is
+/// anchored to
+pub(crate) fn write_rows(sinks: &mut [Box<dyn DataSink>], timestamps: &[f64], samples: Vec<Vec<f32>>, markers: &[String]) -> Result<()> {
+    let mut batch = Vec::new();
+    for (row, ((&timestamp, channels), labels)) in timestamps.iter().zip(samples).zip(markers).enumerate() {
+        if !labels.is_empty() {
+            for sink in sinks.iter_mut() {
+                sink.write_batch(&batch)?;
+            }
+            batch.clear();
+            for label in labels.split('|') {
+                let event = MarkerRecord {
+                    label: label.to_string(),
+                    host_time: timestamp_seconds(timestamp),
+                    sample_id: row as u64,
+                };
+                for sink in sinks.iter_mut() {
+                    sink.insert_event(&event)?;
+                }
+            }
+        }
+        batch.push(EEGSample {
+            timestamp,
+            sample_id: row as u64,
+            channels,
+        });
+    }
+    for sink in sinks.iter_mut() {
+        sink.write_batch(&batch)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,707 @@
+//! Soak testing: hours of recording against a mock WiFi shield that
+//! misbehaves on purpose.
+//!
+//! [`MockShield`] answers the shield's HTTP API on localhost and streams
+//! synthetic EEG over TCP exactly like the real one, so the collector runs
+//! its normal WiFi path. [`FaultPlan`] drops the connection, corrupts
+//! packets, jumps the shield clock and fails disk writes at random times
+//! inside each trial. The shield keeps a ledger of what it actually did,
+//! and [`check_trial`] compares it with what the collector recorded.
+
+use crate::metadata::{GapCause, TrialMetadata};
+use crate::source::SignalGenerator;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use openbci_wifi_client::stream::unix_time;
+use openbci_wifi_client::{Sample, StreamLimits};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+/// Shortest trial that leaves room for faults between the margins
+pub const MIN_TRIAL_SECONDS: u64 = 10;
+/// Faults stay this far from both ends of a trial, so every loss has
+/// received samples on either side to be measured against
+const FAULT_MARGIN: Duration = Duration::from_secs(3);
+/// Minimum spacing between faults, so two never merge into one gap
+const FAULT_SPACING: f64 = 0.5;
+/// How often the mock shield sends a chunk
+const CHUNK_INTERVAL: Duration = Duration::from_millis(40);
+/// How long the mock shield keeps trying to reach the collector
+const CONNECT_RETRY: Duration = Duration::from_secs(5);
+/// How far the junk of an overlong-line fault runs past the reader's line
+/// limit
+const OVERLONG_EXCESS: usize = 16 * 1024;
+
+/// Faults the soak test can inject
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FaultKind {
+    /// The shield drops the TCP connection and reconnects
+    Disconnect,
+    /// A truncated chunk or an overlong line without a delimiter
+    Malformed,
+    /// Trial file writes fail with ENOSPC for a while
+    DiskFull,
+    /// The shield clock steps forwards or backwards
+    ClockJump,
+}
+
+impl FaultKind {
+    pub const ALL: [FaultKind; 4] = [Self::Disconnect, Self::Malformed, Self::DiskFull, Self::ClockJump];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Disconnect => "disconnect",
+            Self::Malformed => "malformed",
+            Self::DiskFull => "disk-full",
+            Self::ClockJump => "clock-jump",
+        }
+    }
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for FaultKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().replace('_', "-").as_str() {
+            "disconnect" => Ok(Self::Disconnect),
+            "malformed" => Ok(Self::Malformed),
+            "disk-full" => Ok(Self::DiskFull),
+            "clock-jump" => Ok(Self::ClockJump),
+            _ => bail!("Unknown fault '{}', expected disconnect, malformed, disk-full or clock-jump", s),
+        }
+    }
+}
+
+/// Fault executed by the mock shield's stream
+#[derive(Debug, Clone, Copy)]
+enum ShieldFault {
+    Disconnect(Duration),
+    /// Send the next chunk cut in half
+    Truncate,
+    /// Send a line longer than the reader accepts
+    Overlong,
+    /// Step the clock by this many seconds
+    ClockJump(f64),
+}
+
+/// What the mock shield did during one stream, the ground truth for a trial
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ShieldLedger {
+    pub sent_samples: u64,
+    /// Samples generated but never delivered (disconnects, truncated chunks)
+    pub lost_samples: u64,
+    pub disconnects: u64,
+    pub malformed: u64,
+    pub clock_jumps: u64,
+}
+
+struct StreamSession {
+    faults: UnboundedSender<ShieldFault>,
+    task: JoinHandle<()>,
+}
+
+struct ShieldState {
+    num_channels: usize,
+    sample_rate: u32,
+    seed: u64,
+    streams: u64,
+    session: Option<StreamSession>,
+    ledger: Arc<Mutex<ShieldLedger>>,
+}
+
+impl ShieldState {
+    fn stop(&mut self) {
+        if let Some(session) = self.session.take() {
+            session.task.abort();
+        }
+    }
+}
+
+/// In-process stand-in for the WiFi shield on `127.0.0.1`
+pub struct MockShield {
+    addr: SocketAddr,
+    state: Arc<Mutex<ShieldState>>,
+    server: JoinHandle<()>,
+}
+
+impl MockShield {
+    /// Serve the shield HTTP API on an ephemeral localhost port
+    pub async fn start(num_channels: usize, sample_rate: u32, seed: u64) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.context("Failed to start mock shield")?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(ShieldState {
+            num_channels,
+            sample_rate,
+            seed,
+            streams: 0,
+            session: None,
+            ledger: Arc::new(Mutex::new(ShieldLedger::default())),
+        }));
+        let server = tokio::spawn(serve(listener, Arc::clone(&state)));
+        info!("Mock shield on http://{}", addr);
+        Ok(Self { addr, state, server })
+    }
+
+    /// Address to pass as the shield IP
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Ledger of the current (or last) stream
+    pub fn ledger(&self) -> ShieldLedger {
+        let state = self.state.lock().unwrap();
+        let ledger = state.ledger.lock().unwrap().clone();
+        ledger
+    }
+
+    /// Queue a fault on the running stream; false when nothing streams
+    fn inject(&self, fault: ShieldFault) -> bool {
+        let state = self.state.lock().unwrap();
+        state.session.as_ref().is_some_and(|s| s.faults.send(fault).is_ok())
+    }
+}
+
+impl Drop for MockShield {
+    fn drop(&mut self) {
+        self.server.abort();
+        self.state.lock().unwrap().stop();
+    }
+}
+
+async fn serve(listener: TcpListener, state: Arc<Mutex<ShieldState>>) {
+    loop {
+        let Ok((socket, _)) = listener.accept().await else {
+            return;
+        };
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(socket, &state).await {
+                debug!("Mock shield request failed: {}", e);
+            }
+        });
+    }
+}
+
+/// One HTTP request per connection, answered with `Connection: close`
+/// `?` reply of a board at default settings: gain 24, normal input,
+/// SRB2 on, with a second bank for a Daisy
+fn register_dump(num_channels: usize, sample_rate: u32) -> String {
+    let rate_code = (0..=6u8).find(|&code| 16000 >> code == sample_rate).unwrap_or(6);
+    let mut lines = vec![
+        "Board ADS Registers".to_string(),
+        "ADS_ID, 00, 3E, 0, 0, 1, 1, 1, 1, 1, 0".to_string(),
+        format!("CONFIG1, 01, {:02X}", 0x90 | rate_code),
+    ];
+    for bank in 0..num_channels.div_ceil(8).max(1) {
+        if bank > 0 {
+            lines.push("Daisy ADS Registers".to_string());
+        }
+        lines.extend((1..=8).map(|n| format!("CH{}SET, {:02X}, 68, 0, 1, 1, 0, 1, 0, 0, 0", n, 4 + n)));
+    }
+    lines.push("$$$".to_string());
+    lines.join("\n")
+}
+
+async fn handle_request(mut socket: TcpStream, state: &Mutex<ShieldState>) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 4096];
+    let header_end = loop {
+        let n = socket.read(&mut buffer).await?;
+        if n == 0 {
+            bail!("connection closed mid-request");
+        }
+        request.extend_from_slice(&buffer[..n]);
+        if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+    let head = String::from_utf8_lossy(&request[..header_end]).to_string();
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while request.len() < header_end + content_length {
+        let n = socket.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..n]);
+    }
+    let body = &request[header_end..];
+
+    let mut request_line = head.split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+    let (status, response) = match (method, path) {
+        ("GET", "/board") => {
+            let num_channels = state.lock().unwrap().num_channels.max(8);
+            let info = serde_json::json!({
+                "board_connected": true,
+                "board_type": if num_channels > 8 { "daisy" } else { "cyton" },
+                "num_channels": num_channels,
+                "gains": vec![24; num_channels],
+            });
+            ("200 OK", info.to_string())
+        }
+        ("GET", "/version") => ("200 OK", "v2.0.5".to_string()),
+        ("GET", "/all") => {
+            let num_channels = state.lock().unwrap().num_channels.max(8);
+            let info = serde_json::json!({
+                "board_connected": true,
+                "heap": 24576,
+                "ip": "127.0.0.1",
+                "mac": "00:00:00:00:00:00",
+                "name": "OpenBCI-mock",
+                "num_channels": num_channels,
+                "version": "v2.0.5",
+                "latency": 4000,
+            });
+            ("200 OK", info.to_string())
+        }
+        ("POST", "/command") => {
+            let command: serde_json::Value = serde_json::from_slice(body).unwrap_or_default();
+            let reply = match command["command"].as_str() {
+                Some("?") => {
+                    let state = state.lock().unwrap();
+                    register_dump(state.num_channels, state.sample_rate)
+                }
+                _ => "OpenBCI V3 8-16 channel\nFirmware: v3.1.2\n$$$".to_string(),
+            };
+            ("200 OK", reply)
+        }
+        ("POST", "/tcp") => {
+            let config: serde_json::Value = serde_json::from_slice(body)?;
+            let target: SocketAddr = format!(
+                "{}:{}",
+                config["ip"].as_str().unwrap_or("127.0.0.1"),
+                config["port"].as_u64().unwrap_or(3000)
+            )
+            .parse()?;
+            start_stream(state, target);
+            ("200 OK", serde_json::json!({ "connected": true }).to_string())
+        }
+        ("DELETE", "/tcp") => {
+            state.lock().unwrap().stop();
+            ("200 OK", serde_json::json!({ "connected": false }).to_string())
+        }
+        _ => ("404 Not Found", String::new()),
+    };
+    let reply = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        response.len(),
+        response
+    );
+    socket.write_all(reply.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+/// Replace any running stream with a fresh one and reset the ledger
+fn start_stream(state: &Mutex<ShieldState>, target: SocketAddr) {
+    let mut state = state.lock().unwrap();
+    state.stop();
+    state.streams += 1;
+    *state.ledger.lock().unwrap() = ShieldLedger::default();
+
+    let (faults, fault_rx) = mpsc::unbounded_channel();
+    let generator = SignalGenerator::new(state.num_channels, state.sample_rate, state.seed ^ state.streams);
+    let ledger = Arc::clone(&state.ledger);
+    // The collector sizes its line limit to the stream's rate
+    let overlong = StreamLimits::for_rate(state.sample_rate, state.num_channels).max_line + OVERLONG_EXCESS;
+    let task = tokio::spawn(async move {
+        if let Err(e) = stream_samples(target, generator, ledger, fault_rx, overlong).await {
+            debug!("Mock shield stream ended: {}", e);
+        }
+    });
+    state.session = Some(StreamSession { faults, task });
+}
+
+async fn connect(target: SocketAddr) -> Result<TcpStream> {
+    let deadline = Instant::now() + CONNECT_RETRY;
+    loop {
+        match TcpStream::connect(target).await {
+            Ok(socket) => return Ok(socket),
+            Err(e) if Instant::now() >= deadline => bail!("could not reach {}: {}", target, e),
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+}
+
+/// Stream real-time chunks to `target` until aborted or the collector goes
+/// away, executing faults as they arrive
+async fn stream_samples(
+    target: SocketAddr,
+    mut generator: SignalGenerator,
+    ledger: Arc<Mutex<ShieldLedger>>,
+    mut faults: UnboundedReceiver<ShieldFault>,
+    overlong: usize,
+) -> Result<()> {
+    let mut socket = connect(target).await?;
+    let started = Instant::now();
+    let epoch_ms = unix_time() * 1000.0;
+    let mut clock_offset_ms = 0.0;
+    let mut next: u64 = 0;
+    let mut truncate_next = false;
+    let mut ticker = tokio::time::interval(CHUNK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            fault = faults.recv() => match fault {
+                None => return Ok(()),
+                Some(ShieldFault::Disconnect(outage)) => {
+                    socket.shutdown().await.ok();
+                    drop(socket);
+                    tokio::time::sleep(outage).await;
+                    // Everything generated while away is gone
+                    let due = (started.elapsed().as_secs_f64() * generator.sample_rate()) as u64;
+                    ledger.lock().unwrap().lost_samples += due.saturating_sub(next);
+                    next = next.max(due);
+                    socket = connect(target).await?;
+                    ledger.lock().unwrap().disconnects += 1;
+                }
+                Some(ShieldFault::Truncate) => truncate_next = true,
+                Some(ShieldFault::Overlong) => {
+                    let mut junk = vec![b'0'; overlong];
+                    junk.push(b'\n');
+                    socket.write_all(&junk).await?;
+                    ledger.lock().unwrap().malformed += 1;
+                }
+                Some(ShieldFault::ClockJump(seconds)) => {
+                    clock_offset_ms += seconds * 1000.0;
+                    ledger.lock().unwrap().clock_jumps += 1;
+                }
+            },
+            _ = ticker.tick() => {
+                let due = (started.elapsed().as_secs_f64() * generator.sample_rate()) as u64;
+                if due <= next {
+                    continue;
+                }
+                let chunk: Vec<Sample> = (next..due)
+                    .map(|index| Sample {
+                        data: generator.sample(index),
+                        timestamp: epoch_ms + index as f64 * 1000.0 / generator.sample_rate() + clock_offset_ms,
+                    })
+                    .collect();
+                let count = due - next;
+                next = due;
+                let mut line = serde_json::to_string(&serde_json::json!({ "chunk": chunk }))?;
+                if truncate_next {
+                    truncate_next = false;
+                    line.truncate(line.len() / 2);
+                    let mut ledger = ledger.lock().unwrap();
+                    ledger.lost_samples += count;
+                    ledger.malformed += 1;
+                } else {
+                    ledger.lock().unwrap().sent_samples += count;
+                }
+                line.push('\n');
+                socket.write_all(line.as_bytes()).await?;
+            }
+        }
+    }
+}
+
+/// Faults injected into one trial apart from the shield's own ledger
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct TrialFaults {
+    pub disk_full: u64,
+    /// Shield faults that found no running stream
+    pub skipped: u64,
+}
+
+/// Random fault schedule, reproducible from its seed
+pub struct FaultPlan {
+    kinds: Vec<FaultKind>,
+    /// Mean seconds between faults
+    interval: f64,
+    rng: StdRng,
+}
+
+impl FaultPlan {
+    pub fn new(kinds: Vec<FaultKind>, interval: f64, seed: u64) -> Result<Self> {
+        if kinds.is_empty() {
+            bail!("No fault kinds to inject");
+        }
+        if interval <= 0.0 {
+            bail!("Fault interval must be positive");
+        }
+        Ok(Self {
+            kinds,
+            interval,
+            rng: StdRng::seed_from_u64(seed),
+        })
+    }
+
+    /// Inject faults into a trial of `length` starting now. Each fault is
+    /// over before the next starts and before the closing margin.
+    pub async fn run_trial(&mut self, shield: &MockShield, disk_full: &AtomicBool, length: Duration) -> TrialFaults {
+        let started = Instant::now();
+        let window_end = started + length.saturating_sub(FAULT_MARGIN);
+        let mut at = started + FAULT_MARGIN;
+        let mut faults = TrialFaults::default();
+
+        loop {
+            let wait = FAULT_SPACING - self.interval * (1.0 - self.rng.gen::<f64>()).ln();
+            at += Duration::from_secs_f64(wait);
+            let kind = self.kinds[self.rng.gen_range(0..self.kinds.len())];
+            let duration = match kind {
+                FaultKind::Disconnect => Duration::from_secs_f64(self.rng.gen_range(0.2..2.0)),
+                FaultKind::DiskFull => Duration::from_secs_f64(self.rng.gen_range(1.0..3.0)),
+                FaultKind::Malformed | FaultKind::ClockJump => Duration::ZERO,
+            };
+            if at + duration >= window_end {
+                break;
+            }
+            tokio::time::sleep_until(at.into()).await;
+
+            let fault = match kind {
+                FaultKind::Disconnect => Some(ShieldFault::Disconnect(duration)),
+                FaultKind::Malformed if self.rng.gen_bool(0.5) => Some(ShieldFault::Truncate),
+                FaultKind::Malformed => Some(ShieldFault::Overlong),
+                FaultKind::ClockJump => {
+                    let seconds = self.rng.gen_range(2.0..3600.0);
+                    Some(ShieldFault::ClockJump(if self.rng.gen_bool(0.5) { seconds } else { -seconds }))
+                }
+                FaultKind::DiskFull => None,
+            };
+            debug!("Injecting {} fault", kind);
+            match fault {
+                Some(fault) => {
+                    if !shield.inject(fault) {
+                        faults.skipped += 1;
+                    }
+                    tokio::time::sleep(duration).await;
+                }
+                None => {
+                    disk_full.store(true, Ordering::SeqCst);
+                    tokio::time::sleep(duration).await;
+                    disk_full.store(false, Ordering::SeqCst);
+                    faults.disk_full += 1;
+                }
+            }
+            at = Instant::now();
+        }
+        faults
+    }
+}
+
+/// Compare a finished trial with the shield's ledger; returns violations
+pub fn check_trial(metadata: &TrialMetadata, ledger: &ShieldLedger, file_rows: usize) -> Vec<String> {
+    let trial = metadata.trial_number;
+    let mut violations = Vec::new();
+    let Some(health) = &metadata.stream_health else {
+        return vec![format!("trial {}: no stream health recorded", trial)];
+    };
+    if metadata.total_samples == 0 {
+        violations.push(format!("trial {}: no samples received", trial));
+    }
+    let mut expect = |what: &str, recorded: u64, injected: u64| {
+        if recorded != injected {
+            violations.push(format!("trial {}: {} {} recorded, {} injected", trial, recorded, what, injected));
+        }
+    };
+    expect("reconnects", health.reconnects, ledger.disconnects);
+    expect("parse errors", health.parse_errors, ledger.malformed);
+    expect("clock jumps", health.clock_jumps, ledger.clock_jumps);
+
+    // Every lost stretch is one gap; allow a sample of rounding on each
+    let gaps = ledger.disconnects + ledger.malformed;
+    if health.missing_samples.abs_diff(ledger.lost_samples) > gaps {
+        violations.push(format!(
+            "trial {}: {} samples counted missing, shield lost {}",
+            trial, health.missing_samples, ledger.lost_samples
+        ));
+    }
+    let write_gaps: u64 = health
+        .gaps
+        .iter()
+        .filter(|g| g.cause == GapCause::Write)
+        .map(|g| g.samples)
+        .sum();
+    if write_gaps != health.unwritten_samples {
+        violations.push(format!(
+            "trial {}: write gaps cover {} samples, {} unwritten",
+            trial, write_gaps, health.unwritten_samples
+        ));
+    }
+    if file_rows as u64 != metadata.written_samples() {
+        violations.push(format!(
+            "trial {}: data file has {} rows, expected {} ({} received, {} unwritten)",
+            trial,
+            file_rows,
+            metadata.written_samples(),
+            metadata.total_samples,
+            health.unwritten_samples
+        ));
+    }
+    violations
+}
+
+/// Panics seen since [`install_panic_counter`]
+static PANICS: AtomicU64 = AtomicU64::new(0);
+
+/// Count panics on any thread, including tokio tasks that would otherwise
+/// only log them, keeping the default message
+pub fn install_panic_counter() {
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        PANICS.fetch_add(1, Ordering::SeqCst);
+        default(info);
+    }));
+}
+
+pub fn panics() -> u64 {
+    PANICS.load(Ordering::SeqCst)
+}
+
+/// Peak resident memory sampled in the background
+pub struct MemoryWatch {
+    peak: Arc<AtomicU64>,
+    task: JoinHandle<()>,
+}
+
+impl MemoryWatch {
+    pub fn start(every: Duration) -> Self {
+        let peak = Arc::new(AtomicU64::new(0));
+        let task = tokio::spawn({
+            let peak = Arc::clone(&peak);
+            async move {
+                let mut ticker = tokio::time::interval(every);
+                loop {
+                    ticker.tick().await;
+                    if let Some(rss) = crate::platform::resident_memory() {
+                        peak.fetch_max(rss, Ordering::Relaxed);
+                    }
+                }
+            }
+        });
+        Self { peak, task }
+    }
+
+    /// Peak since the last reset
+    pub fn peak(&self) -> u64 {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.peak.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Drop for MemoryWatch {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Fault totals over the whole run
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct FaultTotals {
+    pub disconnects: u64,
+    pub malformed: u64,
+    pub disk_full: u64,
+    pub clock_jumps: u64,
+}
+
+/// Resident memory over the run, in bytes. The baseline is taken after the
+/// first trial, once buffers and the runtime have warmed up.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct MemoryReport {
+    pub baseline_bytes: u64,
+    pub peak_bytes: u64,
+    pub final_bytes: u64,
+    pub allowed_growth_bytes: u64,
+}
+
+/// `soak_report.json`: what ran, what was injected and what broke
+#[derive(Debug, Clone, Serialize)]
+pub struct SoakReport {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub hours_requested: f64,
+    pub elapsed_seconds: f64,
+    pub seed: u64,
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub format: String,
+    pub trial_seconds: u64,
+    pub fault_kinds: Vec<FaultKind>,
+    pub fault_interval_seconds: f64,
+    pub trials: u32,
+    pub failed_trials: u32,
+    pub samples_received: u64,
+    pub samples_lost: u64,
+    pub samples_unwritten: u64,
+    pub faults: FaultTotals,
+    pub panics: u64,
+    pub memory: MemoryReport,
+    pub violations: Vec<String>,
+    pub passed: bool,
+}
+
+impl SoakReport {
+    /// Add one finished trial and its violations
+    pub fn add_trial(&mut self, metadata: &TrialMetadata, ledger: &ShieldLedger, faults: TrialFaults, violations: Vec<String>) {
+        self.trials += 1;
+        self.samples_received += metadata.total_samples;
+        self.samples_lost += ledger.lost_samples;
+        if let Some(health) = &metadata.stream_health {
+            self.samples_unwritten += health.unwritten_samples;
+        }
+        self.faults.disconnects += ledger.disconnects;
+        self.faults.malformed += ledger.malformed;
+        self.faults.clock_jumps += ledger.clock_jumps;
+        self.faults.disk_full += faults.disk_full;
+        if !violations.is_empty() {
+            self.failed_trials += 1;
+            for violation in &violations {
+                warn!("Soak: {}", violation);
+            }
+            self.violations.extend(violations);
+        }
+    }
+
+    /// Apply the run-wide invariants and decide pass/fail
+    pub fn finish(&mut self, started: Instant) {
+        self.finished_at = Some(Utc::now());
+        self.elapsed_seconds = started.elapsed().as_secs_f64();
+        self.panics = panics();
+        if self.panics > 0 {
+            self.violations.push(format!("{} panics", self.panics));
+        }
+        let memory = &self.memory;
+        if memory.baseline_bytes > 0 && memory.peak_bytes > memory.baseline_bytes + memory.allowed_growth_bytes {
+            self.violations.push(format!(
+                "resident memory grew from {:.1} MB to {:.1} MB",
+                memory.baseline_bytes as f64 / 1e6,
+                memory.peak_bytes as f64 / 1e6
+            ));
+        }
+        if self.trials == 0 {
+            self.violations.push("no trial completed".to_string());
+        }
+        self.passed = self.violations.is_empty();
+    }
+}
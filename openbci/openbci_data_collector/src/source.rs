@@ -0,0 +1,317 @@
+//! Where samples come from.
+//!
+//! A [`DataSource`] yields [`EEGSample`]s one at a time. [`BoardSource`]
+//! reads a board stream (the WiFi shield's TCP stream, or the serial and BLE
+//! links), [`ReplaySource`] plays a recorded trial back and
+//! [`SyntheticSource`] generates an alpha rhythm plus noise; with the `lsl`
+//! feature, `lsl::LslSource` records another amplifier's LSL stream. A
+//! [`SourceTransport`] presents a source as a [`BoardTransport`], so the
+//! collector, montage wizard and ASR calibration run unchanged without a
+//! board attached.
+
+use crate::recording::Recording;
+use crate::sink::EEGSample;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use log::info;
+use openbci_wifi_client::stream::unix_time;
+use openbci_wifi_client::{timestamp_seconds, BoardTransport, Capabilities, Sample, StreamEvent, StreamHandle};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Producer of EEG samples
+#[async_trait]
+pub trait DataSource: Send {
+    /// Human-readable description for logs
+    fn describe(&self) -> String;
+
+    /// Next sample in nanovolts; `None` once the source is exhausted
+    async fn next_sample(&mut self) -> Option<EEGSample>;
+}
+
+/// Samples from a board's stream; inserted markers are skipped
+pub struct BoardSource {
+    description: String,
+    stream: StreamHandle,
+    next_id: u64,
+}
+
+impl BoardSource {
+    pub async fn open(board: &dyn BoardTransport) -> Result<Self> {
+        Ok(Self {
+            description: board.describe(),
+            stream: board.open_stream().await?,
+            next_id: 0,
+        })
+    }
+}
+
+#[async_trait]
+impl DataSource for BoardSource {
+    fn describe(&self) -> String {
+        self.description.clone()
+    }
+
+    async fn next_sample(&mut self) -> Option<EEGSample> {
+        loop {
+            if let StreamEvent::Sample(sample) = self.stream.recv().await? {
+                let sample_id = self.next_id;
+                self.next_id += 1;
+                return Some(EEGSample {
+                    timestamp: sample.timestamp,
+                    sample_id,
+                    channels: sample.data,
+                });
+            }
+        }
+    }
+}
+
+/// Releases sample `index` no earlier than it would arrive from a board
+/// streaming at `rate` samples per second
+pub(crate) struct Pacer {
+    start: Instant,
+    rate: f64,
+}
+
+impl Pacer {
+    pub(crate) fn new(rate: f64) -> Self {
+        Self {
+            start: Instant::now(),
+            rate,
+        }
+    }
+
+    pub(crate) async fn wait(&self, index: u64) {
+        tokio::time::sleep_until(self.start + Duration::from_secs_f64(index as f64 / self.rate)).await;
+    }
+}
+
+/// A recorded trial played back at its sample rate (times `speed`), stamped
+/// with the host time of playback unless the recorded timestamps are kept
+pub struct ReplaySource {
+    path: PathBuf,
+    sample_rate: u32,
+    num_channels: usize,
+    samples: Vec<Vec<f32>>,
+    /// When each sample was recorded, in seconds
+    recorded: Vec<f64>,
+    keep_timestamps: bool,
+    position: usize,
+    pacer: Pacer,
+}
+
+impl ReplaySource {
+    /// Load a trial from its metadata or one of its data files, in any
+    /// recorded format
+    pub fn open(path: &Path, speed: f64) -> Result<Self> {
+        if speed <= 0.0 {
+            bail!("Replay speed must be positive, got {}", speed);
+        }
+        let recording = Recording::open(path)?;
+        let rate = recording.metadata.sample_rate.max(1) as f64;
+        info!(
+            "Replaying {} samples of {} channels from {:?} at {}x",
+            recording.samples.len(),
+            recording.num_channels(),
+            recording.data_path,
+            speed
+        );
+        // Formats without per-sample timestamps already get them from the
+        // start time; CSV rows without one are placed the same way
+        let start = recording.metadata.start_time.timestamp_millis() as f64 / 1000.0;
+        let recorded = recording
+            .timestamps
+            .iter()
+            .enumerate()
+            .map(|(i, &t)| if t.is_finite() { timestamp_seconds(t) } else { start + i as f64 / rate })
+            .collect();
+        Ok(Self {
+            path: recording.data_path,
+            sample_rate: recording.metadata.sample_rate,
+            num_channels: recording.channel_names.len(),
+            samples: recording.samples,
+            recorded,
+            keep_timestamps: false,
+            position: 0,
+            pacer: Pacer::new(rate * speed),
+        })
+    }
+
+    /// Stamp samples with the time they were recorded at (in seconds)
+    /// instead of the time of playback
+    pub fn keep_timestamps(mut self) -> Self {
+        self.keep_timestamps = true;
+        self
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+}
+
+#[async_trait]
+impl DataSource for ReplaySource {
+    fn describe(&self) -> String {
+        format!("replay {}", self.path.display())
+    }
+
+    async fn next_sample(&mut self) -> Option<EEGSample> {
+        let channels = self.samples.get(self.position)?.clone();
+        let sample_id = self.position as u64;
+        self.pacer.wait(sample_id).await;
+        let timestamp = if self.keep_timestamps { self.recorded[self.position] } else { unix_time() };
+        self.position += 1;
+        Some(EEGSample {
+            timestamp,
+            sample_id,
+            channels,
+        })
+    }
+}
+
+/// Synthetic EEG: a 10 Hz alpha rhythm with per-channel phase plus noise
+pub struct SignalGenerator {
+    num_channels: usize,
+    sample_rate: f64,
+    rng: StdRng,
+}
+
+impl SignalGenerator {
+    pub fn new(num_channels: usize, sample_rate: u32, seed: u64) -> Self {
+        Self {
+            num_channels,
+            sample_rate: sample_rate as f64,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    /// Sample `index` in nanovolts
+    pub fn sample(&mut self, index: u64) -> Vec<f32> {
+        let t = index as f64 / self.sample_rate;
+        (0..self.num_channels)
+            .map(|ch| {
+                let alpha = 20_000.0 * (2.0 * std::f64::consts::PI * 10.0 * t + ch as f64).sin();
+                (alpha + self.rng.gen_range(-5_000.0..5_000.0)) as f32
+            })
+            .collect()
+    }
+}
+
+/// [`SignalGenerator`] output in real time, without end
+pub struct SyntheticSource {
+    generator: SignalGenerator,
+    index: u64,
+    pacer: Pacer,
+}
+
+impl SyntheticSource {
+    pub fn new(num_channels: usize, sample_rate: u32, seed: u64) -> Self {
+        Self {
+            generator: SignalGenerator::new(num_channels, sample_rate, seed),
+            index: 0,
+            pacer: Pacer::new(sample_rate.max(1) as f64),
+        }
+    }
+}
+
+#[async_trait]
+impl DataSource for SyntheticSource {
+    fn describe(&self) -> String {
+        format!("synthetic {} channels", self.generator.num_channels)
+    }
+
+    async fn next_sample(&mut self) -> Option<EEGSample> {
+        self.pacer.wait(self.index).await;
+        let sample = EEGSample {
+            timestamp: unix_time(),
+            sample_id: self.index,
+            channels: self.generator.sample(self.index),
+        };
+        self.index += 1;
+        Some(sample)
+    }
+}
+
+type OpenSource = dyn Fn() -> Result<Box<dyn DataSource>> + Send + Sync;
+
+/// A host-side source behind the board interface. Every `open_stream`
+/// starts a fresh source; board commands and capability queries fail, so
+/// capability checks are skipped.
+pub struct SourceTransport {
+    /// Metrics label, e.g. `replay`
+    kind: &'static str,
+    description: String,
+    open: Box<OpenSource>,
+}
+
+impl SourceTransport {
+    pub fn new(
+        kind: &'static str,
+        description: impl Into<String>,
+        open: impl Fn() -> Result<Box<dyn DataSource>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            kind,
+            description: description.into(),
+            open: Box::new(open),
+        }
+    }
+}
+
+#[async_trait]
+impl BoardTransport for SourceTransport {
+    fn describe(&self) -> String {
+        self.description.clone()
+    }
+
+    async fn send_command(&self, command: &str) -> Result<String> {
+        bail!("{} takes no board commands ('{}')", self.description, command)
+    }
+
+    async fn capabilities(&self, _firmware: Option<&str>) -> Result<Capabilities> {
+        bail!("{} has no board to query", self.description)
+    }
+
+    async fn open_stream(&self) -> Result<StreamHandle> {
+        let mut source = (self.open)()?;
+        info!("Streaming from {}", source.describe());
+        Ok(StreamHandle::spawn(self.kind, move |feed| async move {
+            let mut sent = 0u64;
+            while let Some(sample) = source.next_sample().await {
+                let sample = Sample {
+                    data: sample.channels,
+                    timestamp: sample.timestamp,
+                };
+                if !feed.send(sample) {
+                    return;
+                }
+                sent += 1;
+            }
+            info!("{} exhausted after {} samples", source.describe(), sent);
+        }))
+    }
+
+    async fn stop_stream(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+//! SSVEP targets decoded by canonical correlation.
+//!
+//! An SSVEP paradigm file already names the targets and the frequencies
+//! they flicker at; with a `[decoder]` table it is also a model that
+//! `openbci_online_bci --model` takes like any other, so the robot can be
+//! steered by looking at flickering targets instead of imagining movement.
+//! Nothing is trained: each window's largest canonical correlation with the
+//! sine and cosine references of every target and its harmonics (see
+//! `eeg_dsp::SsvepReferences`) becomes a class probability through a
+//! softmax with the decoder's temperature.
+
+use crate::paradigm::Paradigm;
+use anyhow::{bail, Context, Result};
+use eeg_dsp::SsvepReferences;
+use ndarray::ArrayView2;
+use std::path::Path;
+
+/// The CCA decoder of an SSVEP paradigm
+#[derive(Debug, Clone)]
+pub struct SsvepDecoder {
+    /// Class names in probability order, as in the paradigm
+    pub classes: Vec<String>,
+    /// Montage labels of the channels, in the order the decoder takes them
+    pub channel_names: Vec<String>,
+    pub sample_rate: u32,
+    /// Window length in samples
+    pub window: usize,
+    temperature: f64,
+    references: SsvepReferences,
+}
+
+impl SsvepDecoder {
+    /// The decoder of a paradigm file with a `[decoder]` table
+    pub fn open(path: &Path) -> Result<Self> {
+        let paradigm = Paradigm::load(path)?;
+        Self::new(&paradigm).with_context(|| format!("{:?} cannot decode SSVEP", path))
+    }
+
+    pub fn new(paradigm: &Paradigm) -> Result<Self> {
+        let settings = paradigm.decoder.as_ref().context("The paradigm has no [decoder] table")?;
+        let frequencies = paradigm
+            .classes
+            .iter()
+            .map(|class| class.frequency.with_context(|| format!("Class '{}' has no frequency", class.name)))
+            .collect::<Result<Vec<f64>>>()?;
+        if settings.channels.is_empty() {
+            bail!("The decoder needs channels");
+        }
+        if settings.temperature.is_nan() || settings.temperature <= 0.0 {
+            bail!("The decoder's temperature must be positive, got {}", settings.temperature);
+        }
+        let window = (settings.window * settings.sample_rate as f64).round() as usize;
+        let references = SsvepReferences::new(&frequencies, settings.harmonics, settings.sample_rate as f64, window)?;
+        Ok(Self {
+            classes: paradigm.classes.iter().map(|class| class.name.clone()).collect(),
+            channel_names: settings.channels.clone(),
+            sample_rate: settings.sample_rate,
+            window,
+            temperature: settings.temperature,
+            references,
+        })
+    }
+
+    /// Flicker frequencies of the classes, in Hz
+    pub fn frequencies(&self) -> &[f64] {
+        self.references.frequencies()
+    }
+
+    pub fn harmonics(&self) -> usize {
+        self.references.harmonics()
+    }
+
+    /// Canonical correlation of a `channels x window` window with each
+    /// class's references
+    pub fn correlations(&self, window: ArrayView2<f32>) -> Vec<f64> {
+        self.references.correlations(window)
+    }
+
+    /// Class probabilities of one `channels x window` window
+    pub fn predict(&self, window: ArrayView2<f32>) -> Vec<f32> {
+        let scores: Vec<f64> = self.correlations(window).iter().map(|r| r / self.temperature).collect();
+        let top = scores.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let exp: Vec<f64> = scores.iter().map(|s| (s - top).exp()).collect();
+        let total: f64 = exp.iter().sum();
+        exp.iter().map(|e| (e / total) as f32).collect()
+    }
+}
@@ -0,0 +1,277 @@
+//! Board clock to host clock offsets.
+//!
+//! Every WiFi shield stamps samples with its own clock, and two shields
+//! disagree by whatever their NTP sync left them with, often tens of ms.
+//! [`ClockSync`] estimates a board's offset from when its samples arrive:
+//! delivery only ever adds delay, so the smallest `arrival - timestamp` in a
+//! window is the offset plus the fastest delivery. The median of the recent
+//! window minima follows slow drift and ignores a window that saw only late
+//! chunks. A board clock that steps (NTP resync) restarts the estimate.
+//! Boards whose offsets are known can be put on one timeline.
+//!
+//! [`HostTimes`] keeps the host side of every trial: when each batch of
+//! samples arrived, on the monotonic and the wall clock, next to the
+//! board's timestamp, and a model of the board clock fitted to them.
+
+use crate::metadata::HostClockInfo;
+use openbci_wifi_client::stream::unix_time;
+use openbci_wifi_client::timestamp_seconds;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Marker prefix of an offset estimate, followed by `<board>=<ms>`
+pub const CLOCK_SYNC: &str = "clock_sync:";
+
+/// Seconds of samples per minimum
+const WINDOW: f64 = 1.0;
+/// Window minima the median is taken over
+const WINDOWS: usize = 30;
+/// Seconds the offset has to move at once to count as a clock step
+const STEP: f64 = 0.5;
+
+/// Running estimate of one board's clock offset to the host clock
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    /// Board time (s) the current window started at, and its minimum
+    window: Option<(f64, f64)>,
+    minima: VecDeque<f64>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed a sample's board timestamp (any unit the boards use) and its
+    /// host arrival time in seconds since the Unix epoch
+    pub fn observe(&mut self, timestamp: f64, host_time: f64) {
+        let board = timestamp_seconds(timestamp);
+        let delay = host_time - board;
+        let current = self.offset();
+        // Faster than delivery can be: the board clock stepped forward
+        if current.is_some_and(|offset| delay < offset - STEP) {
+            self.minima.clear();
+            self.window = None;
+        }
+        match &mut self.window {
+            Some((start, minimum)) if board - *start < WINDOW && board >= *start => {
+                *minimum = minimum.min(delay);
+            }
+            window => {
+                // A board clock stepping back starts a new window too
+                if let Some((_, minimum)) = window.take() {
+                    // A whole window late: the board clock stepped back
+                    if current.is_some_and(|offset| minimum - offset > STEP) {
+                        self.minima.clear();
+                    }
+                    if self.minima.len() == WINDOWS {
+                        self.minima.pop_front();
+                    }
+                    self.minima.push_back(minimum);
+                }
+                *window = Some((board, delay));
+            }
+        }
+    }
+
+    /// Seconds to add to a board timestamp to get host time; until the
+    /// first window is complete, the minimum so far
+    pub fn offset(&self) -> Option<f64> {
+        if self.minima.is_empty() {
+            return self.window.map(|(_, minimum)| minimum);
+        }
+        let mut sorted: Vec<f64> = self.minima.iter().copied().collect();
+        sorted.sort_by(f64::total_cmp);
+        Some(sorted[sorted.len() / 2])
+    }
+
+    /// Whether at least one full window went into the estimate
+    pub fn settled(&self) -> bool {
+        !self.minima.is_empty()
+    }
+}
+
+/// File name suffix of a trial's host receive times
+pub const HOST_TIMES_SUFFIX: &str = "host_times.tsv";
+/// Host time between two samples that starts a new batch; samples of one
+/// network chunk arrive microseconds apart
+const BATCH_GAP: Duration = Duration::from_millis(2);
+
+/// First sample of a batch
+#[derive(Debug, Clone, Copy)]
+struct Batch {
+    sample_id: u64,
+    samples: u64,
+    board_time: f64,
+    monotonic: f64,
+    utc: f64,
+}
+
+/// Least-squares line through the per-window delivery floors
+#[derive(Debug, Default)]
+struct FloorFit {
+    n: f64,
+    sx: f64,
+    sy: f64,
+    sxx: f64,
+    sxy: f64,
+}
+
+impl FloorFit {
+    fn push(&mut self, x: f64, y: f64) {
+        self.n += 1.0;
+        self.sx += x;
+        self.sy += y;
+        self.sxx += x * x;
+        self.sxy += x * y;
+    }
+
+    /// Intercept and slope; a single point has no slope
+    fn line(&self) -> Option<(f64, Option<f64>)> {
+        if self.n == 0.0 {
+            return None;
+        }
+        let denominator = self.n * self.sxx - self.sx * self.sx;
+        if self.n < 2.0 || denominator <= f64::EPSILON {
+            return Some((self.sy / self.n, None));
+        }
+        let slope = (self.n * self.sxy - self.sx * self.sy) / denominator;
+        Some(((self.sy - slope * self.sx) / self.n, Some(slope)))
+    }
+}
+
+/// Writes the host receive time of each batch of samples to a TSV and, for
+/// boards with their own clock, fits `host = board + offset + drift *
+/// (board - reference)` to the lowest delay of every second of samples
+pub struct HostTimes {
+    file: String,
+    writer: BufWriter<File>,
+    board_clock: bool,
+    /// Monotonic zero and the wall clock at it
+    started: Option<(Instant, f64)>,
+    last: Option<Instant>,
+    batch: Option<Batch>,
+    batches: u64,
+    /// Board time of the first sample since the last clock jump
+    reference: Option<f64>,
+    /// Board time the current window started at, and its lowest delay
+    window: Option<(f64, f64)>,
+    fit: FloorFit,
+    error: Option<io::Error>,
+}
+
+impl HostTimes {
+    /// Write to `path`; `board_clock`: timestamps come from the board
+    /// rather than the host, so there is a clock to model
+    pub fn create(path: &Path, board_clock: bool) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "sample_id\tsamples\tboard_time\thost_monotonic\thost_utc")?;
+        Ok(Self {
+            file: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+            writer,
+            board_clock,
+            started: None,
+            last: None,
+            batch: None,
+            batches: 0,
+            reference: None,
+            window: None,
+            fit: FloorFit::default(),
+            error: None,
+        })
+    }
+
+    /// Feed sample `sample_id`, stamped `timestamp` by the board, that
+    /// arrived at `received`. After a board clock jump the model starts
+    /// over.
+    pub fn push(&mut self, sample_id: u64, timestamp: f64, received: Instant, clock_jump: bool) {
+        let board_time = timestamp_seconds(timestamp);
+        let new_batch = self.last.is_none_or(|last| received.duration_since(last) >= BATCH_GAP);
+        self.last = Some(received);
+        let (start, epoch) = *self.started.get_or_insert_with(|| (received, unix_time()));
+        let monotonic = received.duration_since(start).as_secs_f64();
+        if new_batch {
+            let batch = Batch {
+                sample_id,
+                samples: 0,
+                board_time,
+                monotonic,
+                utc: unix_time(),
+            };
+            if let Some(done) = self.batch.replace(batch) {
+                self.write(done);
+            }
+        }
+        if let Some(batch) = &mut self.batch {
+            batch.samples += 1;
+        }
+        if self.board_clock {
+            self.observe(board_time, epoch + monotonic, clock_jump);
+        }
+    }
+
+    /// Host time is the monotonic clock from the wall clock at its zero,
+    /// so a wall clock step does not bend the model
+    fn observe(&mut self, board_time: f64, host_time: f64, clock_jump: bool) {
+        if clock_jump {
+            self.reference = None;
+            self.window = None;
+            self.fit = FloorFit::default();
+        }
+        let reference = *self.reference.get_or_insert(board_time);
+        let delay = host_time - board_time;
+        match &mut self.window {
+            Some((start, minimum)) if board_time - *start < WINDOW && board_time >= *start => {
+                *minimum = minimum.min(delay);
+            }
+            window => {
+                if let Some((start, minimum)) = window.replace((board_time, delay)) {
+                    self.fit.push(start - reference, minimum);
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, batch: Batch) {
+        if self.error.is_some() {
+            return;
+        }
+        self.batches += 1;
+        let row = writeln!(
+            self.writer,
+            "{}\t{}\t{:.6}\t{:.6}\t{:.6}",
+            batch.sample_id, batch.samples, batch.board_time, batch.monotonic, batch.utc
+        );
+        if let Err(e) = row {
+            self.error = Some(e);
+        }
+    }
+
+    /// Write the last batch and close the file; returns what goes into the
+    /// trial metadata
+    pub fn finish(mut self) -> io::Result<HostClockInfo> {
+        if let Some(batch) = self.batch.take() {
+            self.write(batch);
+        }
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.writer.flush()?;
+        // The window still open counts as well
+        if let (Some((start, minimum)), Some(reference)) = (self.window, self.reference) {
+            self.fit.push(start - reference, minimum);
+        }
+        let model = self.fit.line();
+        Ok(HostClockInfo {
+            file: self.file,
+            batches: self.batches,
+            monotonic_epoch: self.started.map(|(_, epoch)| epoch),
+            reference_board_time: model.and(self.reference),
+            offset_seconds: model.map(|(offset, _)| offset),
+            drift_ppm: model.and_then(|(_, slope)| slope).map(|slope| slope * 1e6),
+        })
+    }
+}
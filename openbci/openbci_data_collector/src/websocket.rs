@@ -0,0 +1,309 @@
+//! WebSocket broadcast of the live stream, for `--ws-port`.
+//!
+//! Browser dashboards and remote monitors connect to `ws://<host>:<port>`
+//! and receive JSON text messages tagged by `type`: `trial` when a trial
+//! starts (channel labels, rate, class), `samples` in batches of
+//! microvolts, `marker`, `band_power` and `trial_end`. The server runs for
+//! the whole session; a client that joins mid-trial gets the current
+//! `trial` message first. Clients may send `prediction` messages of their
+//! own, e.g. an online classifier's output, which are stamped with the
+//! host time and relayed to every client (and to the ZeroMQ publisher), so
+//! a dashboard can show the stream and the decoder side by side. Clients
+//! that fall behind skip messages instead of holding up the recording.
+//!
+//! With an [`AccessControl`] (`--ws-tokens`), a client must present a
+//! bearer token in its `Authorization` header, or as `?token=` for
+//! browsers, which cannot set headers. Watching takes an observer token and
+//! sending predictions an operator token; both are audited.
+
+use crate::auth::{AccessControl, Endpoint};
+use crate::bandpower::BandPowers;
+use crate::live::{LiveMessage, SampleBatch};
+use crate::metadata::MarkerRecord;
+use anyhow::{Context, Result};
+use futures::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use openbci_wifi_client::stream::unix_time;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+/// Messages a slow client may fall behind before it skips ahead
+const CLIENT_BACKLOG: usize = 1024;
+
+/// The session's WebSocket server; clones share it
+#[derive(Clone)]
+pub struct WsServer {
+    addr: SocketAddr,
+    messages: broadcast::Sender<Arc<str>>,
+    /// Relayed predictions only, for other outputs to pick up
+    predictions: broadcast::Sender<Arc<str>>,
+    /// `trial` message of the trial recording now, for late joiners
+    current_trial: Arc<Mutex<Option<Arc<str>>>>,
+    /// Token check for every client, if any
+    access: Option<Arc<AccessControl>>,
+}
+
+impl fmt::Debug for WsServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WsServer(ws://{})", self.addr)
+    }
+}
+
+impl WsServer {
+    /// Listen at `addr` until the process exits; clients need a token
+    /// from `access` when given
+    pub async fn start(addr: SocketAddr, access: Option<AccessControl>) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to listen for WebSocket clients on {}", addr))?;
+        let (messages, _) = broadcast::channel(CLIENT_BACKLOG);
+        let (predictions, _) = broadcast::channel(CLIENT_BACKLOG);
+        let server = Self {
+            addr,
+            messages,
+            predictions,
+            current_trial: Arc::new(Mutex::new(None)),
+            access: access.map(Arc::new),
+        };
+        let accepting = server.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((socket, peer)) => {
+                        tokio::spawn(accepting.clone().serve(socket, peer));
+                    }
+                    Err(e) => warn!("Failed to accept a WebSocket client: {}", e),
+                }
+            }
+        });
+        info!("Serving the live stream on ws://{}", addr);
+        Ok(server)
+    }
+
+    fn send(&self, message: &LiveMessage) {
+        match serde_json::to_string(message) {
+            // No receivers just means nobody is connected right now
+            Ok(text) => {
+                let _ = self.messages.send(text.into());
+            }
+            Err(e) => warn!("Failed to encode WebSocket message: {}", e),
+        }
+    }
+
+    /// Latest band power estimate
+    pub fn band_power(&self, powers: &BandPowers) {
+        self.send(&LiveMessage::BandPower {
+            timestamp: unix_time(),
+            channels: &powers.0,
+        });
+    }
+
+    /// Relay a client's `prediction` (any JSON object), stamped with the
+    /// host time it arrived at
+    fn relay(&self, text: &str, peer: SocketAddr, authorization: Option<&str>) {
+        let Ok(serde_json::Value::Object(mut message)) = serde_json::from_str(text) else {
+            debug!("Ignoring non-JSON message from WebSocket client {}", peer);
+            return;
+        };
+        if message.get("type").and_then(|t| t.as_str()) != Some("prediction") {
+            debug!("Ignoring message from WebSocket client {}, only predictions are relayed", peer);
+            return;
+        }
+        if let Some(access) = &self.access {
+            if access.authorize(authorization, Endpoint::Prediction, text).is_err() {
+                return;
+            }
+        }
+        message.insert("received".to_string(), unix_time().into());
+        let text: Arc<str> = serde_json::Value::Object(message).to_string().into();
+        let _ = self.predictions.send(Arc::clone(&text));
+        let _ = self.messages.send(text);
+    }
+
+    /// Predictions clients send from now on
+    pub fn predictions(&self) -> broadcast::Receiver<Arc<str>> {
+        self.predictions.subscribe()
+    }
+
+    /// One client: broadcast messages out, predictions in
+    async fn serve(self, socket: TcpStream, peer: SocketAddr) {
+        let mut authorization = None;
+        // tungstenite fixes the rejection type
+        #[allow(clippy::result_large_err)]
+        let check = |request: &Request, response: Response| -> std::result::Result<Response, ErrorResponse> {
+            authorization = credentials(request);
+            let Some(access) = &self.access else {
+                return Ok(response);
+            };
+            match access.authorize(authorization.as_deref(), Endpoint::Stream, &format!("connect from {}", peer)) {
+                Ok(_) => Ok(response),
+                Err(e) => {
+                    let mut rejection = ErrorResponse::new(Some(e.to_string()));
+                    *rejection.status_mut() = StatusCode::UNAUTHORIZED;
+                    Err(rejection)
+                }
+            }
+        };
+        let mut socket = match tokio_tungstenite::accept_hdr_async(socket, check).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                debug!("WebSocket handshake with {} failed: {}", peer, e);
+                return;
+            }
+        };
+        info!("WebSocket client {} connected", peer);
+        let mut messages = self.messages.subscribe();
+        let current = self.current_trial.lock().unwrap().clone();
+        if let Some(trial) = current {
+            if socket.send(WsMessage::Text(trial.to_string())).await.is_err() {
+                return;
+            }
+        }
+        loop {
+            tokio::select! {
+                message = messages.recv() => match message {
+                    Ok(text) => {
+                        if let Err(e) = socket.send(WsMessage::Text(text.to_string())).await {
+                            info!("WebSocket client {} disconnected: {}", peer, e);
+                            return;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("WebSocket client {} fell behind, {} messages skipped", peer, skipped);
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                incoming = socket.next() => match incoming {
+                    Some(Ok(WsMessage::Text(text))) => self.relay(&text, peer, authorization.as_deref()),
+                    Some(Ok(WsMessage::Close(_))) | None => {
+                        info!("WebSocket client {} disconnected", peer);
+                        return;
+                    }
+                    // Pings are answered by tungstenite on the next send
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        info!("WebSocket client {} disconnected: {}", peer, e);
+                        return;
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// The `Authorization` header of a handshake, or a URL-encoded `token`
+/// query parameter in the same form
+fn credentials(request: &Request) -> Option<String> {
+    if let Some(header) = request.headers().get("authorization") {
+        return header.to_str().ok().map(str::to_string);
+    }
+    form_urlencoded::parse(request.uri().query()?.as_bytes())
+        .find(|(key, _)| key == "token")
+        .map(|(_, token)| format!("Bearer {}", token))
+}
+
+/// One trial on the server: batches samples and forwards markers
+pub struct WsTrial {
+    server: WsServer,
+    batch: SampleBatch,
+    samples: u64,
+    markers: u64,
+}
+
+impl WsTrial {
+    /// Announce the trial to every client
+    pub fn start(
+        server: WsServer,
+        subject: &str,
+        session: &str,
+        trial: u32,
+        class: &str,
+        channels: &[String],
+        sample_rate: u32,
+    ) -> Result<Self> {
+        let announcement: Arc<str> = serde_json::to_string(&LiveMessage::Trial {
+            subject,
+            session,
+            trial,
+            class,
+            channels,
+            sample_rate,
+        })?
+        .into();
+        *server.current_trial.lock().unwrap() = Some(Arc::clone(&announcement));
+        let _ = server.messages.send(announcement);
+        Ok(Self {
+            server,
+            batch: SampleBatch::new(sample_rate),
+            samples: 0,
+            markers: 0,
+        })
+    }
+
+    /// Queue one sample (nanovolts, as recorded) and send a batch once full
+    pub fn push(&mut self, sample_id: u64, timestamp: f64, channels: &[f32]) {
+        self.samples += 1;
+        if self.batch.push(sample_id, timestamp, channels) {
+            self.flush();
+        }
+    }
+
+    /// Send whatever is queued
+    pub fn flush(&mut self) {
+        if let Some(message) = self.batch.message() {
+            self.server.send(&message);
+        }
+        self.batch.clear();
+    }
+
+    /// Forward a marker; samples before it go out first so clients see
+    /// them in order
+    pub fn marker(&mut self, event: &MarkerRecord) {
+        self.flush();
+        self.server.send(&LiveMessage::Marker {
+            label: &event.label,
+            timestamp: event.host_time,
+            sample_id: event.sample_id,
+        });
+        self.markers += 1;
+    }
+
+    /// Flush and tell clients the trial is over; returns samples and
+    /// markers sent
+    pub fn finish(&mut self) -> (u64, u64) {
+        self.flush();
+        *self.server.current_trial.lock().unwrap() = None;
+        self.server.send(&LiveMessage::TrialEnd {
+            samples: self.samples,
+            markers: self.markers,
+        });
+        (self.samples, self.markers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str) -> Request {
+        Request::builder().uri(uri).body(()).unwrap()
+    }
+
+    #[test]
+    fn decodes_query_tokens() {
+        assert_eq!(credentials(&request("/?token=watch")), Some("Bearer watch".to_string()));
+        assert_eq!(credentials(&request("/?v=1&token=a%2Bb%25c%26d")), Some("Bearer a+b%c&d".to_string()));
+        assert_eq!(credentials(&request("/?tokens=x")), None);
+        assert_eq!(credentials(&request("/")), None);
+
+        let mut with_header = request("/?token=ignored");
+        with_header.headers_mut().insert("authorization", "Bearer header".parse().unwrap());
+        assert_eq!(credentials(&with_header), Some("Bearer header".to_string()));
+    }
+}
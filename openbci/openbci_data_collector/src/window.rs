@@ -0,0 +1,205 @@
+//! Sliding windows over a live stream, for online classification.
+//!
+//! A [`WindowScheduler`] takes every sample as it arrives, filters it and
+//! keeps the last `window` samples of the channels a model takes, in the
+//! model's order, handing out a `channels x samples` copy every `hop`
+//! samples once the first window is full. Short breaks in the stream are
+//! bridged before the filters see them: lost samples (a step in the board
+//! timestamps, see [`GapDetector`]; only a board clock shows them, host
+//! sources are stamped on delivery) are interpolated between the samples
+//! either side, and NaN values hold the channel's last good value. A break
+//! longer than `max_gap` samples empties the window, which then refills
+//! before the next one is handed out, rather than classify EEG with a hole
+//! in it.
+
+use crate::filter::OnlineFilter;
+use crate::gaps::{Discontinuity, GapDetector, GapFill};
+use crate::sink::EEGSample;
+use anyhow::{bail, Result};
+use log::warn;
+use ndarray::Array2;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// One window, ready for a model
+#[derive(Debug, Clone)]
+pub struct Window {
+    /// `channels x samples`, filtered, in the model's channel order
+    pub data: Array2<f32>,
+    /// Timestamp of the last sample, Unix seconds
+    pub timestamp: f64,
+    /// ID of the last sample
+    pub sample_id: u64,
+    /// Samples in the window that were interpolated or held
+    pub repaired: usize,
+    /// When the window was handed out
+    pub ready: Instant,
+}
+
+/// What happened to the stream so far
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct WindowStats {
+    pub samples: u64,
+    pub windows: u64,
+    /// Lost samples interpolated and NaN samples held
+    pub repaired: u64,
+    /// Breaks longer than `max_gap` that emptied the window
+    pub restarts: u64,
+}
+
+/// Fixed-length windows from a live stream, every `hop` samples
+pub struct WindowScheduler {
+    /// Stream channel of each window row
+    channels: Vec<usize>,
+    window: usize,
+    hop: usize,
+    max_gap: usize,
+    filter: Option<OnlineFilter>,
+    /// Set for sources stamped by a board clock
+    gaps: Option<GapDetector>,
+    /// Filtered samples of the window's channels, oldest first, and
+    /// whether each was repaired
+    frames: VecDeque<(Vec<f32>, bool)>,
+    /// Last sample with every channel valid, before filtering
+    last: Option<(f64, Vec<f32>)>,
+    /// Samples in a row with a NaN value
+    nan_run: usize,
+    /// Samples since the last window, `None` until the first one
+    since: Option<usize>,
+    stats: WindowStats,
+    /// Time the filters took in the last push
+    filtering: Duration,
+}
+
+impl WindowScheduler {
+    /// Windows of `window` samples of stream `channels` every `hop`
+    /// samples, `filter` applied to every stream channel first
+    pub fn new(
+        channels: Vec<usize>,
+        window: usize,
+        hop: usize,
+        max_gap: usize,
+        filter: Option<OnlineFilter>,
+    ) -> Result<Self> {
+        if channels.is_empty() || window == 0 || hop == 0 {
+            bail!("Windows need at least one channel, one sample and a hop of one sample");
+        }
+        Ok(Self {
+            channels,
+            window,
+            hop,
+            max_gap,
+            filter,
+            gaps: None,
+            frames: VecDeque::with_capacity(window),
+            last: None,
+            nan_run: 0,
+            since: None,
+            stats: WindowStats::default(),
+            filtering: Duration::ZERO,
+        })
+    }
+
+    /// Find lost samples from the timestamps, which come from the board's
+    /// own clock running at `sample_rate`
+    pub fn with_board_clock(mut self, sample_rate: u32) -> Self {
+        self.gaps = Some(GapDetector::new(sample_rate));
+        self
+    }
+
+    pub fn stats(&self) -> WindowStats {
+        self.stats
+    }
+
+    /// Time the filters took on the last pushed sample, and on any lost
+    /// samples interpolated before it
+    pub fn filter_time(&self) -> Duration {
+        self.filtering
+    }
+
+    /// Take the next sample, received at `received`; the window ending
+    /// with it when one is due
+    pub fn push(&mut self, sample: &EEGSample, received: Instant) -> Option<Window> {
+        self.stats.samples += 1;
+        self.filtering = Duration::ZERO;
+        let discontinuity = self.gaps.as_mut().and_then(|gaps| gaps.push(sample.timestamp, received));
+        let mut values = sample.channels.clone();
+
+        // Hold the last good value of NaN channels; a sample with nothing to
+        // hold, or past a long run of them, is dropped
+        let held = values.iter().any(|v| !v.is_finite());
+        if held {
+            self.nan_run += 1;
+            if self.nan_run == self.max_gap + 1 {
+                self.restart(format!("{} samples with NaN values", self.nan_run));
+            }
+            let last = self.last.as_ref().filter(|_| self.nan_run <= self.max_gap)?;
+            for (value, &good) in values.iter_mut().zip(&last.1) {
+                if !value.is_finite() {
+                    *value = good;
+                }
+            }
+        } else {
+            self.nan_run = 0;
+        }
+
+        match discontinuity {
+            Some(Discontinuity::Gap { missing }) | Some(Discontinuity::ClockJump { missing, .. })
+                if missing as usize > self.max_gap =>
+            {
+                self.restart(format!("{} samples lost", missing));
+            }
+            Some(Discontinuity::Gap { missing }) => {
+                if let Some((timestamp, last)) = self.last.clone() {
+                    for (_, row) in GapFill::Interpolate.rows((timestamp, &last), (sample.timestamp, &values), missing) {
+                        self.ingest(row, true);
+                    }
+                }
+            }
+            _ => {}
+        }
+        if !held {
+            self.last = Some((sample.timestamp, values.clone()));
+        }
+        self.ingest(values, held);
+
+        if self.frames.len() < self.window || self.since.is_some_and(|since| since < self.hop) {
+            return None;
+        }
+        self.since = Some(0);
+        self.stats.windows += 1;
+        Some(Window {
+            data: Array2::from_shape_fn((self.channels.len(), self.window), |(c, t)| self.frames[t].0[c]),
+            timestamp: sample.timestamp,
+            sample_id: sample.sample_id,
+            repaired: self.frames.iter().filter(|(_, repaired)| *repaired).count(),
+            ready: Instant::now(),
+        })
+    }
+
+    /// Filter one sample and append its window channels
+    fn ingest(&mut self, mut values: Vec<f32>, repaired: bool) {
+        if let Some(filter) = &mut self.filter {
+            let start = Instant::now();
+            filter.process(&mut values);
+            self.filtering += start.elapsed();
+        }
+        if self.frames.len() == self.window {
+            self.frames.pop_front();
+        }
+        self.frames.push_back((self.channels.iter().map(|&c| values[c]).collect(), repaired));
+        if let Some(since) = &mut self.since {
+            *since += 1;
+        }
+        self.stats.repaired += repaired as u64;
+    }
+
+    /// Empty the window after a break too long to bridge
+    fn restart(&mut self, reason: String) {
+        warn!("{}, refilling the {}-sample window", reason, self.window);
+        self.frames.clear();
+        self.since = None;
+        self.stats.restarts += 1;
+    }
+}
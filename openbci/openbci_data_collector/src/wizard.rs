@@ -0,0 +1,303 @@
+//! Interactive montage wizard run at session start.
+//!
+//! Streams a baseline, shows each channel's live level, lets the operator
+//! assign 10-20 labels and then verifies them one electrode at a time: the
+//! operator taps an electrode and the wizard reports which channel moved,
+//! with every channel's level against its baseline shown live while the
+//! operator taps.
+
+use crate::montage::{self, ChannelAssignment, Montage};
+use anyhow::{bail, Result};
+use chrono::Utc;
+use openbci_wifi_client::{StreamEvent, StreamHandle};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines, Stdin};
+
+/// Length of the resting baseline
+const BASELINE: Duration = Duration::from_secs(3);
+/// Recording window after the operator starts tapping
+const TAP_WINDOW: Duration = Duration::from_secs(2);
+/// How often the live line redraws during the tap window
+const LIVE_REFRESH: Duration = Duration::from_millis(200);
+/// Levels (uV RMS) outside this range usually mean a loose or bridged lead
+const FLAT_UV: f32 = 0.5;
+const RAILED_UV: f32 = 1000.0;
+
+const BAR_WIDTH: usize = 24;
+
+pub struct MontageWizard<'a> {
+    stream: &'a mut StreamHandle,
+    num_channels: usize,
+    input: Lines<BufReader<Stdin>>,
+}
+
+impl<'a> MontageWizard<'a> {
+    pub fn new(stream: &'a mut StreamHandle, num_channels: usize) -> Self {
+        Self {
+            stream,
+            num_channels,
+            input: BufReader::new(tokio::io::stdin()).lines(),
+        }
+    }
+
+    /// Walk the operator through labelling and verifying every channel,
+    /// starting from `initial`. Returns `None` if the operator quits.
+    pub async fn run(&mut self, initial: &Montage) -> Result<Option<Montage>> {
+        println!();
+        println!("=== Montage Wizard ===");
+        println!("Sit still and relax for {} seconds...", BASELINE.as_secs());
+        let baseline = self.capture(BASELINE, None).await?;
+        let baseline_rms = montage::channel_rms(&baseline);
+
+        let mut montage = Montage {
+            name: initial.name.clone(),
+            channels: (0..self.num_channels)
+                .map(|i| ChannelAssignment {
+                    channel: i + 1,
+                    label: initial
+                        .channels
+                        .get(i)
+                        .map_or_else(String::new, |a| a.label.clone()),
+                    name: None,
+                    verified: false,
+                })
+                .collect(),
+            reference: initial.reference.clone(),
+            ground: initial.ground.clone(),
+            rereference: initial.rereference.clone(),
+            confirmed_at: Utc::now(),
+        };
+
+        println!();
+        println!("Live signal (uV RMS):");
+        print_levels(&montage, &baseline_rms);
+
+        println!();
+        println!("Enter the 10-20 position wired to each channel (Enter keeps the default, q quits).");
+        for i in 0..self.num_channels {
+            loop {
+                let current = montage.channels[i].label.clone();
+                let Some(answer) = self
+                    .prompt(&format!("  Channel {} [{}]: ", i + 1, current))
+                    .await?
+                else {
+                    return Ok(None);
+                };
+                let label = if answer.is_empty() { current } else { answer };
+                match montage::normalize_label(&label) {
+                    Some(label) if montage.channels[..i].iter().any(|a| a.label == label) => {
+                        println!("  {} is already assigned to another channel", label)
+                    }
+                    Some(label) => {
+                        montage.channels[i].label = label.to_string();
+                        break;
+                    }
+                    None => println!("  '{}' is not a 10-20 position, try again", label),
+                }
+            }
+        }
+        if let Some(reference) = self.ask_label("Reference", &montage.reference).await? {
+            montage.reference = reference;
+        } else {
+            return Ok(None);
+        }
+        if let Some(ground) = self.ask_label("Ground", &montage.ground).await? {
+            montage.ground = ground;
+        } else {
+            return Ok(None);
+        }
+
+        println!();
+        println!("Verification: tap each electrode gently for {} seconds after pressing Enter.", TAP_WINDOW.as_secs());
+        println!("Type s to skip an electrode, q to quit.");
+        let mut i = 0;
+        while i < montage.channels.len() {
+            let label = montage.channels[i].label.clone();
+            let Some(answer) = self
+                .prompt(&format!("  Tap {} (channel {}) and press Enter: ", label, i + 1))
+                .await?
+            else {
+                return Ok(None);
+            };
+            if answer == "s" {
+                i += 1;
+                continue;
+            }
+
+            let tap = self.capture(TAP_WINDOW, Some((&montage, &baseline_rms))).await?;
+            let tap_rms = montage::channel_rms(&tap);
+            match montage::responding_channel(&baseline_rms, &tap_rms) {
+                Some((hit, ratio)) if hit == i => {
+                    println!("    OK: channel {} responded ({:.1}x baseline)", hit + 1, ratio);
+                    montage.channels[i].verified = true;
+                    i += 1;
+                }
+                Some((hit, ratio)) => {
+                    let other = montage.channels[hit].label.clone();
+                    println!(
+                        "    Channel {} ({}) responded instead ({:.1}x baseline)",
+                        hit + 1,
+                        other,
+                        ratio
+                    );
+                    let Some(answer) = self
+                        .prompt(&format!("    Swap labels of channels {} and {}? [y/N] ", i + 1, hit + 1))
+                        .await?
+                    else {
+                        return Ok(None);
+                    };
+                    if answer.eq_ignore_ascii_case("y") {
+                        montage.channels[hit].label = label;
+                        montage.channels[i].label = other;
+                        montage.channels[hit].verified = true;
+                        montage.channels[i].verified = false;
+                    }
+                    // Re-check this channel under its (possibly new) label
+                }
+                None => println!("    No clear response, check the electrode and try again"),
+            }
+        }
+
+        println!();
+        println!("Montage:");
+        for a in &montage.channels {
+            println!(
+                "  Channel {:>2}  {:<4} {}",
+                a.channel,
+                a.label,
+                if a.verified { "verified" } else { "NOT verified" }
+            );
+        }
+        println!("  Reference {}  Ground {}", montage.reference, montage.ground);
+        // Channel names from a montage file stay with their electrode
+        for assignment in &mut montage.channels {
+            assignment.name = initial
+                .channels
+                .iter()
+                .find(|a| a.label == assignment.label)
+                .and_then(|a| a.name.clone());
+        }
+        montage.validate()?;
+
+        let Some(answer) = self.prompt("Save this montage for the session? [Y/n] ").await? else {
+            return Ok(None);
+        };
+        if answer.eq_ignore_ascii_case("n") {
+            return Ok(None);
+        }
+        montage.confirmed_at = Utc::now();
+        Ok(Some(montage))
+    }
+
+    async fn ask_label(&mut self, what: &str, current: &str) -> Result<Option<String>> {
+        loop {
+            let Some(answer) = self.prompt(&format!("  {} [{}]: ", what, current)).await? else {
+                return Ok(None);
+            };
+            let label = if answer.is_empty() { current } else { answer.as_str() };
+            match montage::normalize_label(label) {
+                Some(label) => return Ok(Some(label.to_string())),
+                None => println!("  '{}' is not a 10-20 position, try again", label),
+            }
+        }
+    }
+
+    /// Print `text` and read a trimmed line; `None` on EOF or `q`
+    async fn prompt(&mut self, text: &str) -> Result<Option<String>> {
+        use std::io::Write;
+        print!("{}", text);
+        std::io::stdout().flush()?;
+        Ok(self
+            .input
+            .next_line()
+            .await?
+            .map(|line| line.trim().to_string())
+            .filter(|line| line != "q"))
+    }
+
+    /// Collect `window` of fresh samples, channel-major. Samples that
+    /// queued up while the operator was typing are discarded first. With a
+    /// montage and its baseline RMS, each channel's level over the last
+    /// [`LIVE_REFRESH`] is shown on one line as a multiple of its baseline.
+    async fn capture(&mut self, window: Duration, live: Option<(&Montage, &[f32])>) -> Result<Vec<Vec<f32>>> {
+        while let Ok(Some(_)) = tokio::time::timeout(Duration::ZERO, self.stream.recv()).await {}
+
+        let mut channels = vec![Vec::new(); self.num_channels];
+        let deadline = tokio::time::Instant::now() + window;
+        let mut redraw = tokio::time::Instant::now() + LIVE_REFRESH;
+        let mut drawn = 0;
+        loop {
+            match tokio::time::timeout_at(deadline.min(redraw), self.stream.recv()).await {
+                Ok(Some(StreamEvent::Sample(sample))) => {
+                    for (channel, value) in channels.iter_mut().zip(sample.data) {
+                        channel.push(value);
+                    }
+                }
+                Ok(Some(StreamEvent::Marker(_))) => {}
+                Ok(None) => bail!("Stream ended during the montage wizard"),
+                Err(_) if tokio::time::Instant::now() >= deadline => break,
+                Err(_) => {
+                    if let Some((montage, baseline_rms)) = live {
+                        let recent: Vec<Vec<f32>> = channels.iter().map(|c| c[drawn.min(c.len())..].to_vec()).collect();
+                        print_live(montage, baseline_rms, &montage::channel_rms(&recent))?;
+                        drawn = channels.first().map_or(0, Vec::len);
+                    }
+                    redraw += LIVE_REFRESH;
+                }
+            }
+        }
+        if live.is_some() {
+            println!();
+        }
+        if channels.first().is_none_or(|c| c.is_empty()) {
+            bail!("No samples received in {:?}", window);
+        }
+        Ok(channels)
+    }
+}
+
+/// Redraw one line of each channel's level as a multiple of its baseline,
+/// the strongest in brackets
+fn print_live(montage: &Montage, baseline_rms: &[f32], rms: &[f32]) -> Result<()> {
+    use std::io::Write;
+    let ratios: Vec<f32> = baseline_rms.iter().zip(rms).map(|(base, now)| now / base.max(f32::EPSILON)).collect();
+    let strongest = ratios.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1)).map(|(i, _)| i);
+    let levels: Vec<String> = montage
+        .channels
+        .iter()
+        .zip(&ratios)
+        .enumerate()
+        .map(|(i, (a, ratio))| {
+            let level = format!("{} {:.1}x", a.label, ratio);
+            if Some(i) == strongest { format!("[{}]", level) } else { format!(" {} ", level) }
+        })
+        .collect();
+    print!("\r    {}", levels.join(" "));
+    std::io::stdout().flush()?;
+    Ok(())
+}
+
+/// One bar per channel, scaled to the loudest channel
+fn print_levels(montage: &Montage, rms_nv: &[f32]) {
+    let levels: Vec<f32> = rms_nv.iter().map(|v| v / 1000.0).collect();
+    let max = levels.iter().copied().fold(f32::EPSILON, f32::max);
+    for (a, &uv) in montage.channels.iter().zip(&levels) {
+        let filled = ((uv / max) * BAR_WIDTH as f32).round() as usize;
+        let flag = if uv < FLAT_UV {
+            "  flat?"
+        } else if uv > RAILED_UV {
+            "  railed?"
+        } else {
+            ""
+        };
+        println!(
+            "  Channel {:>2} {:<4} {:>8.1} |{}{}|{}",
+            a.channel,
+            a.label,
+            uv,
+            "#".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled.min(BAR_WIDTH)),
+            flag
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Trial file writes off the async runtime.
+//!
+//! The collector loop hands samples and markers to a dedicated thread
+//! through a bounded single-producer single-consumer ring buffer, so
+//! neither side takes a lock and disk I/O never stalls the task reading the
+//! board. The thread batches samples and writes them to the file sinks;
+//! live sinks stay with the collector. The ring's high-water mark is kept
+//! in the trial's stream health, with a warning once it is half full.
+//! Should the disk fall so far behind that the ring fills, samples are
+//! dropped and accounted like failed writes rather than blocking the
+//! stream.
+
+use crate::metadata::{GapCause, GapRecord, MarkerRecord, StreamHealth, TrialMetadata};
+use crate::sink::{DataSink, EEGSample};
+use log::{error, warn};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Seconds of samples the ring holds while the disk is slow
+const RING_SECONDS: usize = 10;
+/// How long the writer thread sleeps when the ring is empty
+const IDLE: Duration = Duration::from_millis(20);
+/// Share of the ring queued before the trial files count as falling behind
+const BACKLOG_WARNING: f64 = 0.5;
+
+/// What goes through the ring, in stream order
+enum Item {
+    Sample(EEGSample),
+    /// Anchored to a sample that follows it
+    Event(MarkerRecord),
+    /// Close the file sinks with this metadata and go on in the new ones
+    Rotate(Vec<Box<dyn DataSink>>, Box<TrialMetadata>),
+}
+
+/// Write a batch to every file sink. Samples the first file rejects are
+/// accounted as a write gap, since that is the file the metadata points
+/// to; failures of the other files are only counted.
+pub fn write_samples(sinks: &mut [Box<dyn DataSink>], samples: &[EEGSample], health: &mut StreamHealth) {
+    let Some(first) = samples.first() else {
+        return;
+    };
+    for (i, sink) in sinks.iter_mut().filter(|sink| !sink.is_live()).enumerate() {
+        let Err(e) = sink.write_batch(samples) else {
+            continue;
+        };
+        error!("Failed to write trial data to {}: {}", sink.name(), e);
+        health.write_errors += 1;
+        if i == 0 {
+            health.unwritten_samples += samples.len() as u64;
+            health.gaps.push(GapRecord {
+                sample_id: first.sample_id,
+                samples: samples.len() as u64,
+                cause: GapCause::Write,
+            });
+        }
+    }
+}
+
+/// The writer thread of one trial; feed it from a single task
+pub struct TrialWriter {
+    producer: Producer<Item>,
+    thread: JoinHandle<(Vec<Box<dyn DataSink>>, StreamHealth)>,
+    /// Samples dropped because the ring was full, as write gaps
+    overflow: Vec<GapRecord>,
+    capacity: usize,
+    sample_rate: u32,
+    /// Most items queued at once
+    peak_backlog: usize,
+}
+
+impl TrialWriter {
+    /// Start writing to the file sinks `sinks` in batches of `batch`
+    /// samples
+    pub fn start(sinks: Vec<Box<dyn DataSink>>, sample_rate: u32, batch: usize) -> Self {
+        let capacity = (sample_rate as usize * RING_SECONDS).max(batch * 2);
+        let (producer, consumer) = RingBuffer::new(capacity);
+        let thread = thread::Builder::new()
+            .name("trial-writer".to_string())
+            .spawn(move || run(consumer, sinks, batch))
+            .expect("failed to spawn the trial writer thread");
+        Self {
+            producer,
+            thread,
+            overflow: Vec::new(),
+            capacity,
+            sample_rate,
+            peak_backlog: 0,
+        }
+    }
+
+    /// Queue a sample; never blocks
+    pub fn push_sample(&mut self, sample: EEGSample) {
+        let sample_id = sample.sample_id;
+        let backlog = self.capacity - self.producer.slots();
+        if backlog > self.peak_backlog {
+            let threshold = (self.capacity as f64 * BACKLOG_WARNING) as usize;
+            if backlog >= threshold && self.peak_backlog < threshold {
+                warn!(
+                    "Trial files are {:.1} s behind the stream, samples are dropped at {} s",
+                    backlog as f64 / self.sample_rate.max(1) as f64,
+                    self.capacity / self.sample_rate.max(1) as usize
+                );
+            }
+            self.peak_backlog = backlog;
+        }
+        if self.producer.push(Item::Sample(sample)).is_err() {
+            match self.overflow.last_mut() {
+                Some(gap) if gap.sample_id + gap.samples == sample_id => gap.samples += 1,
+                _ => {
+                    warn!("Trial files are falling behind, dropping samples from {}", sample_id);
+                    self.overflow.push(GapRecord {
+                        sample_id,
+                        samples: 1,
+                        cause: GapCause::Write,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Queue a marker ahead of the sample it is anchored to
+    pub fn push_event(&mut self, event: MarkerRecord) {
+        if let Err(rtrb::PushError::Full(Item::Event(event))) = self.producer.push(Item::Event(event)) {
+            warn!("Trial files are falling behind, marker '{}' only kept in the metadata", event.label);
+        }
+    }
+
+    /// Whether [`Self::rotate`] would be queued now
+    pub fn can_rotate(&self) -> bool {
+        !self.producer.is_full()
+    }
+
+    /// Queue a switch to the file sinks `sinks` ahead of the next sample;
+    /// the current ones are finalized with `metadata` once their samples
+    /// are written. Check [`Self::can_rotate`] first, or the new sinks
+    /// are dropped.
+    pub fn rotate(&mut self, sinks: Vec<Box<dyn DataSink>>, metadata: TrialMetadata) {
+        if self.producer.push(Item::Rotate(sinks, Box::new(metadata))).is_err() {
+            error!("Trial files are falling behind, the segment could not be rotated");
+        }
+    }
+
+    /// Write what is queued and stop the thread; returns the sinks, ready
+    /// to be finalized, and adds write failures to `health`
+    pub fn finish(self, health: &mut StreamHealth) -> Vec<Box<dyn DataSink>> {
+        let Self {
+            producer,
+            thread,
+            overflow,
+            peak_backlog,
+            ..
+        } = self;
+        // Dropping the producer tells the thread to drain and stop
+        drop(producer);
+        thread.thread().unpark();
+        let (sinks, written) = thread.join().expect("trial writer thread panicked");
+
+        health.peak_write_backlog = health.peak_write_backlog.max(peak_backlog as u64);
+        health.write_errors += written.write_errors;
+        health.unwritten_samples += written.unwritten_samples;
+        health.unwritten_samples += overflow.iter().map(|gap| gap.samples).sum::<u64>();
+        health.gaps.extend(written.gaps);
+        health.gaps.extend(overflow);
+        health.gaps.sort_by_key(|gap| gap.sample_id);
+        sinks
+    }
+}
+
+fn run(mut consumer: Consumer<Item>, mut sinks: Vec<Box<dyn DataSink>>, batch: usize) -> (Vec<Box<dyn DataSink>>, StreamHealth) {
+    let mut health = StreamHealth::default();
+    let mut samples = Vec::with_capacity(batch);
+    loop {
+        // Checked before draining, so nothing pushed before the drop is missed
+        let abandoned = consumer.is_abandoned();
+        while let Ok(item) = consumer.pop() {
+            match item {
+                Item::Event(event) => {
+                    for sink in &mut sinks {
+                        if let Err(e) = sink.insert_event(&event) {
+                            warn!("{} dropped marker '{}': {}", sink.name(), event.label, e);
+                        }
+                    }
+                }
+                Item::Sample(sample) => {
+                    samples.push(sample);
+                    if samples.len() >= batch {
+                        write_samples(&mut sinks, &samples, &mut health);
+                        samples.clear();
+                    }
+                }
+                Item::Rotate(next, metadata) => {
+                    write_samples(&mut sinks, &samples, &mut health);
+                    samples.clear();
+                    for mut sink in std::mem::replace(&mut sinks, next) {
+                        if let Err(e) = sink.finalize(&metadata) {
+                            error!("Failed to finalize the {} segment: {}", sink.name(), e);
+                        }
+                    }
+                }
+            }
+        }
+        if abandoned {
+            write_samples(&mut sinks, &samples, &mut health);
+            return (sinks, health);
+        }
+        thread::park_timeout(IDLE);
+    }
+}
@@ -0,0 +1,184 @@
+//! ZeroMQ publisher of the live stream, for `--zmq-pub`.
+//!
+//! One PUB socket for the whole run sends two-frame messages: a topic and
+//! a JSON message of [`crate::live`]. Topics are `eeg` (`trial`, `samples`
+//! and `trial_end`), `markers` (`marker`) and `predictions` (`band_power`,
+//! plus `prediction`s relayed from WebSocket clients), so a subscriber
+//! picks what it needs with a prefix subscription. Publishing never
+//! blocks: messages for a subscriber that is past the high water mark are
+//! dropped by ZeroMQ.
+//!
+//! Builds libzmq from source, hence the `zmq` feature.
+
+use crate::bandpower::BandPowers;
+use crate::live::{LiveMessage, SampleBatch};
+use crate::metadata::MarkerRecord;
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use openbci_wifi_client::stream::unix_time;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Topic of samples and trial boundaries
+pub const EEG_TOPIC: &str = "eeg";
+/// Topic of markers
+pub const MARKERS_TOPIC: &str = "markers";
+/// Topic of band power estimates and relayed predictions
+pub const PREDICTIONS_TOPIC: &str = "predictions";
+
+/// Messages queued per subscriber before ZeroMQ drops, about 40 s of
+/// `samples` messages
+const SEND_HWM: i32 = 1000;
+
+/// The run's PUB socket; clones share it
+#[derive(Clone)]
+pub struct ZmqPublisher {
+    endpoint: String,
+    /// Not thread safe on its own; the context is kept alive with it
+    socket: Arc<Mutex<zmq::Socket>>,
+    _context: zmq::Context,
+}
+
+impl fmt::Debug for ZmqPublisher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ZmqPublisher({})", self.endpoint)
+    }
+}
+
+impl ZmqPublisher {
+    /// Bind a PUB socket to `endpoint`, e.g. `tcp://*:5556`
+    pub fn bind(endpoint: &str) -> Result<Self> {
+        let context = zmq::Context::new();
+        let socket = context.socket(zmq::PUB)?;
+        socket.set_sndhwm(SEND_HWM)?;
+        // Unsent messages are not worth holding up exit for
+        socket.set_linger(0)?;
+        socket
+            .bind(endpoint)
+            .with_context(|| format!("Failed to bind the ZeroMQ publisher to {}", endpoint))?;
+        info!("Publishing the live stream on {} (topics eeg, markers, predictions)", endpoint);
+        Ok(Self {
+            endpoint: endpoint.to_string(),
+            socket: Arc::new(Mutex::new(socket)),
+            _context: context,
+        })
+    }
+
+    fn send(&self, topic: &str, message: &LiveMessage) {
+        match serde_json::to_string(message) {
+            Ok(text) => self.send_text(topic, &text),
+            Err(e) => warn!("Failed to encode ZeroMQ message: {}", e),
+        }
+    }
+
+    fn send_text(&self, topic: &str, text: &str) {
+        let sent = self
+            .socket
+            .lock()
+            .unwrap()
+            .send_multipart([topic.as_bytes(), text.as_bytes()], zmq::DONTWAIT);
+        if let Err(e) = sent {
+            debug!("ZeroMQ publish on '{}' failed: {}", topic, e);
+        }
+    }
+
+    /// Latest band power estimate
+    pub fn band_power(&self, powers: &BandPowers) {
+        self.send(
+            PREDICTIONS_TOPIC,
+            &LiveMessage::BandPower {
+                timestamp: unix_time(),
+                channels: &powers.0,
+            },
+        );
+    }
+
+    /// A prediction from elsewhere, already JSON
+    pub fn prediction(&self, json: &str) {
+        self.send_text(PREDICTIONS_TOPIC, json);
+    }
+}
+
+/// One trial on the publisher: batches samples and forwards markers
+pub struct ZmqTrial {
+    publisher: ZmqPublisher,
+    batch: SampleBatch,
+    samples: u64,
+    markers: u64,
+}
+
+impl ZmqTrial {
+    /// Announce the trial on the `eeg` topic
+    pub fn start(
+        publisher: ZmqPublisher,
+        subject: &str,
+        session: &str,
+        trial: u32,
+        class: &str,
+        channels: &[String],
+        sample_rate: u32,
+    ) -> Self {
+        publisher.send(
+            EEG_TOPIC,
+            &LiveMessage::Trial {
+                subject,
+                session,
+                trial,
+                class,
+                channels,
+                sample_rate,
+            },
+        );
+        Self {
+            publisher,
+            batch: SampleBatch::new(sample_rate),
+            samples: 0,
+            markers: 0,
+        }
+    }
+
+    /// Queue one sample (nanovolts, as recorded) and publish a batch once
+    /// full
+    pub fn push(&mut self, sample_id: u64, timestamp: f64, channels: &[f32]) {
+        self.samples += 1;
+        if self.batch.push(sample_id, timestamp, channels) {
+            self.flush();
+        }
+    }
+
+    /// Publish whatever is queued
+    pub fn flush(&mut self) {
+        if let Some(message) = self.batch.message() {
+            self.publisher.send(EEG_TOPIC, &message);
+        }
+        self.batch.clear();
+    }
+
+    /// Publish a marker; samples before it go out first
+    pub fn marker(&mut self, event: &MarkerRecord) {
+        self.flush();
+        self.publisher.send(
+            MARKERS_TOPIC,
+            &LiveMessage::Marker {
+                label: &event.label,
+                timestamp: event.host_time,
+                sample_id: event.sample_id,
+            },
+        );
+        self.markers += 1;
+    }
+
+    /// Flush and publish the end of the trial; returns samples and
+    /// markers published
+    pub fn finish(&mut self) -> (u64, u64) {
+        self.flush();
+        self.publisher.send(
+            EEG_TOPIC,
+            &LiveMessage::TrialEnd {
+                samples: self.samples,
+                markers: self.markers,
+            },
+        );
+        (self.samples, self.markers)
+    }
+}
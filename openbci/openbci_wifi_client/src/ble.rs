@@ -0,0 +1,262 @@
+use crate::stream::{unix_time, StreamCounters};
+use crate::transport::BoardTransport;
+use crate::{BoardKind, Capabilities, Link, Sample, StreamEvent, StreamHandle};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use btleplug::api::{Central, Characteristic, Manager as _, Peripheral as _, ScanFilter, WriteType};
+use btleplug::platform::{Manager, Peripheral};
+use futures::StreamExt;
+use log::{debug, info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Ganglion Simblee characteristics
+const RECEIVE_UUID: Uuid = Uuid::from_u128(0x2d30c082_f39f_4ce6_923f_3484ea480596);
+const SEND_UUID: Uuid = Uuid::from_u128(0x2d30c083_f39f_4ce6_923f_3484ea480596);
+
+/// How long to scan for the board before giving up
+const SCAN_TIME: Duration = Duration::from_secs(5);
+
+const NUM_CHANNELS: usize = 4;
+
+/// MCP3912 count to nanovolts (1.2 V reference, gain 1.5 x 51)
+const SCALE_NV: f32 = 1.2 / 8_388_607.0 / 1.5 / 51.0 * 1e9;
+
+/// Ganglion over Bluetooth LE
+pub struct BleTransport {
+    name: String,
+    peripheral: Peripheral,
+    send: Characteristic,
+    receive: Characteristic,
+}
+
+impl BleTransport {
+    /// Scan for a peripheral whose advertised name contains `name_filter`
+    /// (e.g. `Ganglion`) and connect to it
+    pub async fn connect(name_filter: &str) -> Result<Self> {
+        let manager = Manager::new().await?;
+        let central = manager
+            .adapters()
+            .await?
+            .into_iter()
+            .next()
+            .context("No Bluetooth adapter found")?;
+
+        info!("Scanning for '{}' for {:?}", name_filter, SCAN_TIME);
+        central.start_scan(ScanFilter::default()).await?;
+        tokio::time::sleep(SCAN_TIME).await;
+        central.stop_scan().await?;
+
+        let mut found = None;
+        for peripheral in central.peripherals().await? {
+            let name = peripheral
+                .properties()
+                .await?
+                .and_then(|p| p.local_name)
+                .unwrap_or_default();
+            if name.contains(name_filter) {
+                found = Some((name, peripheral));
+                break;
+            }
+        }
+        let (name, peripheral) =
+            found.with_context(|| format!("No BLE device matching '{}'", name_filter))?;
+
+        peripheral.connect().await?;
+        peripheral.discover_services().await?;
+        info!("Connected to {}", name);
+
+        let find = |uuid: Uuid| {
+            peripheral
+                .characteristics()
+                .into_iter()
+                .find(|c| c.uuid == uuid)
+                .with_context(|| format!("{} has no characteristic {}", name, uuid))
+        };
+        let send = find(SEND_UUID)?;
+        let receive = find(RECEIVE_UUID)?;
+
+        Ok(Self {
+            name,
+            peripheral,
+            send,
+            receive,
+        })
+    }
+
+    async fn write(&self, bytes: &[u8]) -> Result<()> {
+        self.peripheral
+            .write(&self.send, bytes, WriteType::WithoutResponse)
+            .await
+            .with_context(|| format!("Failed to write to {}", self.name))
+    }
+}
+
+#[async_trait]
+impl BoardTransport for BleTransport {
+    fn describe(&self) -> String {
+        format!("ble {}", self.name)
+    }
+
+    /// Ganglion replies arrive as ASCII notifications on the data
+    /// characteristic; they are logged by the stream reader, not returned here
+    async fn send_command(&self, command: &str) -> Result<String> {
+        info!("Sending command: {}", command);
+        self.write(command.as_bytes()).await?;
+        Ok(String::new())
+    }
+
+    async fn capabilities(&self, firmware: Option<&str>) -> Result<Capabilities> {
+        Ok(Capabilities::for_board(BoardKind::Ganglion, Link::Ble, firmware))
+    }
+
+    async fn open_stream(&self) -> Result<StreamHandle> {
+        self.peripheral.subscribe(&self.receive).await?;
+        let mut notifications = self.peripheral.notifications().await?;
+        self.write(b"b").await?;
+        info!("Streaming from {}", self.name);
+
+        let (tx, events) = mpsc::unbounded_channel();
+        let task_tx = tx.clone();
+        let counters = Arc::new(StreamCounters::new("ble"));
+        let task_counters = Arc::clone(&counters);
+        let task = tokio::spawn(async move {
+            let mut decoder = GanglionDecoder::default();
+            while let Some(notification) = notifications.next().await {
+                if notification.uuid != RECEIVE_UUID {
+                    continue;
+                }
+                task_counters.add_bytes(notification.value.len());
+                let Some(samples) = decoder.decode(&notification.value, &task_counters) else {
+                    task_counters.parse_error();
+                    continue;
+                };
+                for sample in samples {
+                    task_counters.add_sample(sample.timestamp);
+                    if task_tx.send(StreamEvent::Sample(sample)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(StreamHandle::from_parts(events, tx, counters, task))
+    }
+
+    async fn stop_stream(&self) -> Result<()> {
+        info!("Stopping BLE stream");
+        self.write(b"s").await?;
+        if let Err(e) = self.peripheral.unsubscribe(&self.receive).await {
+            warn!("Failed to unsubscribe: {}", e);
+        }
+        Ok(())
+    }
+}
+
+/// Ganglion packets carry either raw samples or compressed deltas against
+/// the previous sample, so decoding is stateful
+#[derive(Default)]
+struct GanglionDecoder {
+    last: [i32; NUM_CHANNELS],
+    /// Previous compressed packet ID, for spotting dropped packets
+    last_id: Option<u8>,
+}
+
+impl GanglionDecoder {
+    /// Samples in one notification; `None` for malformed packets
+    fn decode(&mut self, packet: &[u8], counters: &StreamCounters) -> Option<Vec<Sample>> {
+        let (&id, payload) = packet.split_first()?;
+        self.check_sequence(id, counters);
+
+        let raw_samples: Vec<[i32; NUM_CHANNELS]> = match id {
+            // Uncompressed 24-bit samples
+            0 if payload.len() >= 12 => {
+                let mut sample = [0i32; NUM_CHANNELS];
+                for (ch, value) in sample.iter_mut().enumerate() {
+                    let b = &payload[ch * 3..ch * 3 + 3];
+                    *value = i32::from_be_bytes([b[0], b[1], b[2], 0]) >> 8;
+                }
+                self.last = sample;
+                vec![sample]
+            }
+            // Two samples of 18-bit deltas
+            1..=100 if payload.len() >= 18 => self.apply_deltas(&unpack_deltas(&payload[..18], 18)),
+            // Two samples of 19-bit deltas
+            101..=200 if payload.len() >= 19 => self.apply_deltas(&unpack_deltas(&payload[..19], 19)),
+            // ASCII command responses
+            206..=208 => {
+                debug!("Ganglion: {}", String::from_utf8_lossy(payload).trim());
+                Vec::new()
+            }
+            // Impedance readings, only sent in impedance check mode
+            201..=205 => Vec::new(),
+            _ => return None,
+        };
+
+        let timestamp = unix_time();
+        Some(
+            raw_samples
+                .into_iter()
+                .map(|raw| Sample {
+                    data: raw.iter().map(|&v| v as f32 * SCALE_NV).collect(),
+                    timestamp,
+                })
+                .collect(),
+        )
+    }
+
+    /// Compressed packets count 1..=100 (18-bit) or 101..=200 (19-bit)
+    /// and wrap within their range; an uncompressed packet restarts the count
+    fn check_sequence(&mut self, id: u8, counters: &StreamCounters) {
+        if !(1..=200).contains(&id) {
+            if id == 0 {
+                self.last_id = None;
+            }
+            return;
+        }
+        if let Some(last) = self.last_id.replace(id) {
+            let same_range = (last <= 100) == (id <= 100);
+            if same_range {
+                let missing = (id as u32 + 100 - last as u32 - 1) % 100;
+                if missing > 0 {
+                    counters.add_dropped(missing as u64);
+                }
+            }
+        }
+    }
+
+    fn apply_deltas(&mut self, deltas: &[i32]) -> Vec<[i32; NUM_CHANNELS]> {
+        deltas
+            .chunks_exact(NUM_CHANNELS)
+            .map(|delta| {
+                for (last, d) in self.last.iter_mut().zip(delta) {
+                    *last -= d;
+                }
+                self.last
+            })
+            .collect()
+    }
+}
+
+/// Unpack 8 big-endian `bits`-wide values. The Ganglion stores the sign in
+/// the least significant bit rather than the most significant one.
+fn unpack_deltas(payload: &[u8], bits: u32) -> Vec<i32> {
+    let mut values = Vec::with_capacity(2 * NUM_CHANNELS);
+    let mut bit_pos = 0usize;
+
+    for _ in 0..2 * NUM_CHANNELS {
+        let mut value: u32 = 0;
+        for _ in 0..bits {
+            let byte = payload[bit_pos / 8];
+            let bit = (byte >> (7 - bit_pos % 8)) & 1;
+            value = (value << 1) | bit as u32;
+            bit_pos += 1;
+        }
+        if value & 1 != 0 {
+            value |= !0u32 << bits;
+        }
+        values.push(value as i32);
+    }
+    values
+}
@@ -0,0 +1,189 @@
+use crate::command::FirmwareQuirks;
+use crate::BoardInfo;
+use serde::Serialize;
+use thiserror::Error;
+
+/// Board family, from `/board`'s `board_type`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BoardKind {
+    Cyton,
+    /// Cyton with the Daisy module (16 channels)
+    Daisy,
+    Ganglion,
+}
+
+impl BoardKind {
+    pub fn parse(board_type: &str) -> Option<Self> {
+        match board_type.trim().to_lowercase().as_str() {
+            "cyton" => Some(Self::Cyton),
+            "daisy" | "cyton_daisy" => Some(Self::Daisy),
+            "ganglion" => Some(Self::Ganglion),
+            _ => None,
+        }
+    }
+}
+
+/// How the board is attached; the radio links cap the sample rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Link {
+    Wifi,
+    Serial,
+    Ble,
+}
+
+/// Sample rates selected by `~0`, `~1`, ... on each board
+const CYTON_RATES: [u32; 7] = [16000, 8000, 4000, 2000, 1000, 500, 250];
+const GANGLION_RATES: [u32; 8] = [25600, 12800, 6400, 3200, 1600, 800, 400, 200];
+
+/// ADS1299 PGA gains; the Ganglion's MCP3912 runs at a fixed gain
+const CYTON_GAINS: [u8; 7] = [1, 2, 4, 6, 8, 12, 24];
+
+/// What the attached board and firmware can do
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    pub board: BoardKind,
+    pub link: Link,
+    /// Board firmware from `V`; `None` means v1 or unknown
+    pub firmware: Option<String>,
+    /// WiFi shield firmware from `/version`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shield_firmware: Option<String>,
+    pub num_channels: u8,
+    /// Rates the board can be switched to over this link, highest first
+    pub sample_rates: Vec<u32>,
+    pub gain_options: Vec<u8>,
+    /// Analog/digital aux data via Cyton board modes (`/0`..`/4`)
+    pub aux: bool,
+    pub quirks: FirmwareQuirks,
+}
+
+/// A configuration the attached board cannot satisfy
+#[derive(Debug, Error, PartialEq)]
+pub enum CapabilityError {
+    #[error("{board:?} has {available} channels, channel {requested} does not exist")]
+    NoSuchChannel { board: BoardKind, requested: u8, available: u8 },
+    #[error("{board:?} over {link:?} supports sample rates {supported:?} Hz, not {requested} Hz")]
+    SampleRate {
+        board: BoardKind,
+        link: Link,
+        requested: u32,
+        supported: Vec<u32>,
+    },
+    #[error("{board:?} supports gains {supported:?}, not x{requested}")]
+    Gain { board: BoardKind, requested: u8, supported: Vec<u8> },
+    #[error("{0} needs firmware v3 or later (attached: {1})")]
+    Firmware(&'static str, String),
+    #[error("{0:?} has no aux board modes")]
+    NoAux(BoardKind),
+}
+
+impl Capabilities {
+    /// Derive capabilities from the shield's `/board` and `/version`
+    /// responses plus the board firmware reported by `V`
+    pub fn from_board_info(info: &BoardInfo, shield_version: &str, firmware: Option<&str>) -> Option<Self> {
+        let board = BoardKind::parse(&info.board_type)?;
+        let mut caps = Self::for_board(board, Link::Wifi, firmware);
+        if info.num_channels > 0 {
+            caps.num_channels = info.num_channels;
+        }
+        caps.shield_firmware = Some(shield_version.trim().to_string());
+        Some(caps)
+    }
+
+    /// Capabilities of a known board on a given link. No firmware version
+    /// means the board did not answer `V`, i.e. v1 firmware.
+    pub fn for_board(board: BoardKind, link: Link, firmware: Option<&str>) -> Self {
+        let quirks = FirmwareQuirks::for_version(firmware.unwrap_or("v1"));
+        let (num_channels, all_rates, gain_options): (u8, &[u32], Vec<u8>) = match board {
+            BoardKind::Cyton => (8, &CYTON_RATES, CYTON_GAINS.to_vec()),
+            BoardKind::Daisy => (16, &CYTON_RATES, CYTON_GAINS.to_vec()),
+            BoardKind::Ganglion => (4, &GANGLION_RATES, vec![51]),
+        };
+
+        let sample_rates = match (board, link) {
+            // The RFduino and BLE radios only carry the default rate
+            (BoardKind::Daisy, Link::Serial) => vec![125],
+            (_, Link::Serial | Link::Ble) => vec![*all_rates.last().unwrap_or(&250)],
+            _ if !quirks.sample_rate_command => vec![*all_rates.last().unwrap_or(&250)],
+            // Daisy interleaves two ADCs, halving the usable maximum
+            (BoardKind::Daisy, Link::Wifi) => all_rates.iter().copied().filter(|&r| r <= 8000).collect(),
+            _ => all_rates.to_vec(),
+        };
+
+        Self {
+            board,
+            link,
+            firmware: firmware.map(str::to_string),
+            shield_firmware: None,
+            num_channels,
+            sample_rates,
+            gain_options,
+            aux: board != BoardKind::Ganglion,
+            quirks,
+        }
+    }
+
+    pub fn max_sample_rate(&self) -> u32 {
+        self.sample_rates.iter().copied().max().unwrap_or(0)
+    }
+
+    /// `~n` command selecting `hz`, after checking the board supports it
+    pub fn sample_rate_command(&self, hz: u32) -> Result<String, CapabilityError> {
+        if !self.quirks.sample_rate_command {
+            return Err(CapabilityError::Firmware("Changing the sample rate", self.firmware_name()));
+        }
+        if !self.sample_rates.contains(&hz) {
+            return Err(CapabilityError::SampleRate {
+                board: self.board,
+                link: self.link,
+                requested: hz,
+                supported: self.sample_rates.clone(),
+            });
+        }
+        let table: &[u32] = match self.board {
+            BoardKind::Ganglion => &GANGLION_RATES,
+            _ => &CYTON_RATES,
+        };
+        let code = table.iter().position(|&r| r == hz).unwrap_or(table.len() - 1);
+        Ok(format!("~{}", code))
+    }
+
+    pub fn check_channel(&self, channel: u8) -> Result<(), CapabilityError> {
+        if channel == 0 || channel > self.num_channels {
+            return Err(CapabilityError::NoSuchChannel {
+                board: self.board,
+                requested: channel,
+                available: self.num_channels,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_gain(&self, gain: u8) -> Result<(), CapabilityError> {
+        if !self.gain_options.contains(&gain) {
+            return Err(CapabilityError::Gain {
+                board: self.board,
+                requested: gain,
+                supported: self.gain_options.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_aux(&self) -> Result<(), CapabilityError> {
+        if !self.aux {
+            return Err(CapabilityError::NoAux(self.board));
+        }
+        if !self.quirks.sample_rate_command {
+            // Board modes arrived together with sample rate selection in v3
+            return Err(CapabilityError::Firmware("Board modes", self.firmware_name()));
+        }
+        Ok(())
+    }
+
+    fn firmware_name(&self) -> String {
+        self.firmware.clone().unwrap_or_else(|| "unknown".to_string())
+    }
+}
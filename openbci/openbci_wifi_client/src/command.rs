@@ -0,0 +1,477 @@
+use crate::capabilities::{BoardKind, Capabilities};
+use crate::transport::BoardTransport;
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use serde::Serialize;
+
+/// End-of-transmission marker the Cyton firmware appends to responses
+const EOT: &str = "$$$";
+
+/// Behaviour that differs between Cyton firmware releases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FirmwareQuirks {
+    /// Responses are terminated with `$$$` (added in v2.0.0)
+    pub eot_marker: bool,
+    /// `V` returns the firmware version (added in v2.0.0)
+    pub version_command: bool,
+    /// `~n` selects the sample rate (added in v3.0.0)
+    pub sample_rate_command: bool,
+}
+
+impl FirmwareQuirks {
+    /// Quirks for a version string such as `v3.1.2`; unknown versions are
+    /// treated like the newest firmware
+    pub fn for_version(version: &str) -> Self {
+        match parse_major(version) {
+            Some(1) => Self {
+                eot_marker: false,
+                version_command: false,
+                sample_rate_command: false,
+            },
+            Some(2) => Self {
+                eot_marker: true,
+                version_command: true,
+                sample_rate_command: false,
+            },
+            _ => Self::latest(),
+        }
+    }
+
+    pub fn latest() -> Self {
+        Self {
+            eot_marker: true,
+            version_command: true,
+            sample_rate_command: true,
+        }
+    }
+}
+
+fn parse_major(version: &str) -> Option<u32> {
+    version
+        .trim()
+        .trim_start_matches(['v', 'V'])
+        .split('.')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Outcome the firmware reported, when it reported one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseStatus {
+    Success,
+    Failure,
+    Unknown,
+}
+
+/// One ADS1299 register from a `?` dump
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Register {
+    pub name: String,
+    pub address: u8,
+    pub value: u8,
+}
+
+/// Decoded `CHnSET` register
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChannelSettings {
+    pub channel: u8,
+    pub power_down: bool,
+    pub gain: u8,
+    pub input_type: &'static str,
+    pub srb2: bool,
+}
+
+/// Register dump returned by `?`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RegisterDump {
+    pub registers: Vec<Register>,
+}
+
+impl RegisterDump {
+    /// Parse lines like `CH1SET, 05, 60, 0, 1, 1, 0, 0, 0, 0, 0`
+    pub fn parse(text: &str) -> Self {
+        let registers = text
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split(',').map(str::trim);
+                let name = fields.next()?;
+                let address = u8::from_str_radix(fields.next()?, 16).ok()?;
+                // Always two digits; fewer means the line was cut off
+                let value = fields.next().filter(|v| v.len() == 2)?;
+                let value = u8::from_str_radix(value, 16).ok()?;
+                if name.is_empty() || name.contains(' ') {
+                    return None;
+                }
+                Some(Register {
+                    name: name.to_string(),
+                    address,
+                    value,
+                })
+            })
+            .collect();
+        Self { registers }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Register> {
+        self.registers.iter().find(|r| r.name == name)
+    }
+
+    /// ADC sample rate from CONFIG1 DR[2:0]
+    pub fn sample_rate(&self) -> Option<u32> {
+        let config1 = self.get("CONFIG1")?;
+        Some(16000 >> (config1.value & 0x07).min(6))
+    }
+
+    /// Decoded CHnSET registers in channel order. Daisy boards print a
+    /// second bank after the first, numbered from 9.
+    pub fn channels(&self) -> Vec<ChannelSettings> {
+        let mut channels: Vec<ChannelSettings> = Vec::new();
+        for reg in &self.registers {
+            let Some(index) = reg
+                .name
+                .strip_prefix("CH")
+                .and_then(|rest| rest.strip_suffix("SET"))
+                .and_then(|n| n.parse::<u8>().ok())
+            else {
+                continue;
+            };
+            // Second bank repeats CH1SET..CH8SET
+            let bank = channels.iter().filter(|c| (c.channel - 1) % 8 + 1 == index).count() as u8;
+            channels.push(decode_chset(index + 8 * bank, reg.value));
+        }
+        channels
+    }
+}
+
+/// ADS1299 PGA gain codes
+const GAINS: [u8; 7] = [1, 2, 4, 6, 8, 12, 24];
+
+/// ADS1299 input multiplexer settings, by code
+const INPUT_TYPES: [&str; 8] = [
+    "normal", "shorted", "bias_meas", "mvdd", "temp", "testsig", "bias_drp", "bias_drn",
+];
+
+/// Channel characters for `x...X`: `1`-`8`, then `Q`-`I` on the Daisy
+const CHANNEL_CHARS: &[u8; 16] = b"12345678QWERTYUI";
+
+fn decode_chset(channel: u8, value: u8) -> ChannelSettings {
+    ChannelSettings {
+        channel,
+        power_down: value & 0x80 != 0,
+        gain: GAINS.get(((value >> 4) & 0x07) as usize).copied().unwrap_or(24),
+        input_type: INPUT_TYPES[(value & 0x07) as usize],
+        srb2: value & 0x08 != 0,
+    }
+}
+
+impl ChannelSettings {
+    /// `x<ch><power><gain><input><bias><srb2><srb1>X`, with the channel
+    /// included in bias and SRB1 left open
+    pub fn command(&self) -> Result<String> {
+        let channel = CHANNEL_CHARS
+            .get((self.channel as usize).wrapping_sub(1))
+            .with_context(|| format!("Channel {} is out of range", self.channel))?;
+        let gain = GAINS
+            .iter()
+            .position(|&g| g == self.gain)
+            .with_context(|| format!("Gain x{} is not an ADS1299 gain", self.gain))?;
+        let input = INPUT_TYPES
+            .iter()
+            .position(|&t| t == self.input_type)
+            .with_context(|| format!("Unknown input type '{}'", self.input_type))?;
+        Ok(format!(
+            "x{}{}{}{}1{}0X",
+            *channel as char,
+            self.power_down as u8,
+            gain,
+            input,
+            self.srb2 as u8
+        ))
+    }
+}
+
+/// Structured response to a board command
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandResponse {
+    pub command: String,
+    /// Response text with the `$$$` terminator removed
+    pub message: String,
+    /// Whether the terminator was seen (always false on v1 firmware)
+    pub terminated: bool,
+    pub status: ResponseStatus,
+    /// Present for `?` responses
+    pub registers: Option<RegisterDump>,
+}
+
+impl CommandResponse {
+    pub fn parse(command: &str, raw: &str, quirks: &FirmwareQuirks) -> Self {
+        let trimmed = raw.trim_end_matches(['\0', '\r', '\n', ' ']);
+        let terminated = trimmed.ends_with(EOT);
+        if quirks.eot_marker && !terminated && !trimmed.is_empty() {
+            warn!("Response to '{}' has no {} terminator, may be truncated", command, EOT);
+        }
+        let message = trimmed.trim_end_matches(EOT).trim().to_string();
+
+        let status = if message.starts_with("Success") {
+            ResponseStatus::Success
+        } else if message.starts_with("Failure") {
+            ResponseStatus::Failure
+        } else {
+            ResponseStatus::Unknown
+        };
+
+        let registers = (command == "?").then(|| RegisterDump::parse(&message));
+
+        Self {
+            command: command.to_string(),
+            message,
+            terminated,
+            status,
+            registers,
+        }
+    }
+
+    pub fn is_failure(&self) -> bool {
+        self.status == ResponseStatus::Failure
+    }
+
+    /// Firmware version (`v3.1.2`) mentioned in the response, if any
+    pub fn firmware_version(&self) -> Option<String> {
+        self.message
+            .split(|c: char| c.is_whitespace() || c == ':')
+            .find(|word| {
+                let rest = word.strip_prefix('v').unwrap_or("");
+                rest.contains('.') && rest.split('.').all(|p| p.parse::<u32>().is_ok())
+            })
+            .map(str::to_string)
+    }
+}
+
+/// Command API over any transport that parses responses according to the
+/// attached firmware's quirks
+pub struct BoardCommands<'a> {
+    transport: &'a dyn BoardTransport,
+    firmware: Option<String>,
+    quirks: FirmwareQuirks,
+    capabilities: Option<Capabilities>,
+}
+
+impl<'a> BoardCommands<'a> {
+    /// Assume the newest firmware without querying the board
+    pub fn new(transport: &'a dyn BoardTransport) -> Self {
+        Self {
+            transport,
+            firmware: None,
+            quirks: FirmwareQuirks::latest(),
+            capabilities: None,
+        }
+    }
+
+    /// Query the firmware version with `V`, select matching quirks and look
+    /// up the board's capabilities. v1 firmware does not answer `V`, so no
+    /// version means v1.
+    pub async fn detect(transport: &'a dyn BoardTransport) -> Result<Self> {
+        let raw = transport.send_command("V").await?;
+        let response = CommandResponse::parse("V", &raw, &FirmwareQuirks::latest());
+        let firmware = response.firmware_version();
+        let quirks = match &firmware {
+            Some(version) => FirmwareQuirks::for_version(version),
+            None => FirmwareQuirks::for_version("v1"),
+        };
+        info!(
+            "Board firmware {} on {}",
+            firmware.as_deref().unwrap_or("v1 (no version reply)"),
+            transport.describe()
+        );
+        debug!("Firmware quirks: {:?}", quirks);
+
+        let capabilities = transport.capabilities(firmware.as_deref()).await?;
+        info!(
+            "{:?} over {:?}: {} channels, up to {} Hz",
+            capabilities.board,
+            capabilities.link,
+            capabilities.num_channels,
+            capabilities.max_sample_rate()
+        );
+
+        Ok(Self {
+            transport,
+            firmware,
+            quirks,
+            capabilities: Some(capabilities),
+        })
+    }
+
+    pub fn firmware(&self) -> Option<&str> {
+        self.firmware.as_deref()
+    }
+
+    pub fn quirks(&self) -> &FirmwareQuirks {
+        &self.quirks
+    }
+
+    /// Capabilities found by [`detect`](Self::detect); `None` after `new`
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    fn require_capabilities(&self) -> Result<&Capabilities> {
+        self.capabilities
+            .as_ref()
+            .context("Board capabilities unknown, use BoardCommands::detect")
+    }
+
+    /// Switch the sample rate, rejecting rates the board or link cannot do
+    pub async fn set_sample_rate(&self, hz: u32) -> Result<CommandResponse> {
+        let command = self.require_capabilities()?.sample_rate_command(hz)?;
+        self.send_checked(&command).await
+    }
+
+    /// Configure one channel with the `x...X` channel settings command
+    pub async fn configure_channel(&self, settings: &ChannelSettings) -> Result<CommandResponse> {
+        let caps = self.require_capabilities()?;
+        caps.check_channel(settings.channel)?;
+        caps.check_gain(settings.gain)?;
+        self.send_checked(&settings.command()?).await
+    }
+
+    /// Select a Cyton board mode (`/0` default .. `/4` marker)
+    pub async fn set_board_mode(&self, mode: u8) -> Result<CommandResponse> {
+        self.require_capabilities()?.check_aux()?;
+        if mode > 4 {
+            bail!("Board mode {} does not exist, expected 0-4", mode);
+        }
+        self.send_checked(&format!("/{}", mode)).await
+    }
+
+    /// Switch the Cyton's AC lead-off current (6 nA at 31.2 Hz) on or off
+    /// for one channel's N input, for impedance measurement with the stream
+    /// running. The Ganglion has its own impedance mode and is not supported.
+    pub async fn set_impedance_check(&self, channel: u8, on: bool) -> Result<CommandResponse> {
+        let caps = self.require_capabilities()?;
+        if caps.board == BoardKind::Ganglion {
+            bail!("Impedance checks over lead-off need a Cyton");
+        }
+        caps.check_channel(channel)?;
+        let channel = CHANNEL_CHARS[channel as usize - 1] as char;
+        self.send_checked(&format!("z{}0{}Z", channel, on as u8)).await
+    }
+
+    /// Send a command and parse its response
+    pub async fn send(&self, command: &str) -> Result<CommandResponse> {
+        let raw = self.transport.send_command(command).await?;
+        Ok(CommandResponse::parse(command, &raw, &self.quirks))
+    }
+
+    /// Send a command, turning a firmware `Failure` into an error
+    pub async fn send_checked(&self, command: &str) -> Result<CommandResponse> {
+        let response = self.send(command).await?;
+        if response.is_failure() {
+            bail!("Board rejected '{}': {}", command, response.message);
+        }
+        Ok(response)
+    }
+
+    /// Read and decode the ADS1299 registers
+    pub async fn registers(&self) -> Result<RegisterDump> {
+        let response = self.send("?").await?;
+        let dump = response.registers.unwrap_or_default();
+        if dump.registers.is_empty() {
+            bail!("Register dump was empty: {:?}", response.message);
+        }
+        Ok(dump)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `?` on a Cyton at 250 Hz: channels 1-2 at x24 on SRB2, channel 3
+    /// shorted, channel 4 powered down, channel 5 on the test signal at x1
+    const DUMP: &str = "Board ADS Registers\r
+ADS_ID, 00, 3E, 0, 0, 1, 1, 1, 1, 1, 0\r
+CONFIG1, 01, 96, 1, 0, 0, 1, 0, 1, 1, 0\r
+CONFIG2, 02, C0, 1, 1, 0, 0, 0, 0, 0, 0\r
+CONFIG3, 03, EC, 1, 1, 1, 0, 1, 1, 0, 0\r
+LOFF, 04, 02, 0, 0, 0, 0, 0, 0, 1, 0\r
+CH1SET, 05, 68, 0, 1, 1, 0, 1, 0, 0, 0\r
+CH2SET, 06, 68, 0, 1, 1, 0, 1, 0, 0, 0\r
+CH3SET, 07, 61, 0, 1, 1, 0, 0, 0, 0, 1\r
+CH4SET, 08, E0, 1, 1, 1, 0, 0, 0, 0, 0\r
+CH5SET, 09, 05, 0, 0, 0, 0, 0, 1, 0, 1\r
+CH6SET, 0A, 68, 0, 1, 1, 0, 1, 0, 0, 0\r
+CH7SET, 0B, 68, 0, 1, 1, 0, 1, 0, 0, 0\r
+CH8SET, 0C, 68, 0, 1, 1, 0, 1, 0, 0, 0\r
+BIAS_SENSP, 0D, FF, 1, 1, 1, 1, 1, 1, 1, 1\r
+$$$";
+
+    #[test]
+    fn parses_terminated_responses() {
+        let response = CommandResponse::parse("?", &format!("{}\r\n\0\0", DUMP), &FirmwareQuirks::latest());
+        assert!(response.terminated);
+        assert!(!response.message.ends_with(EOT));
+        assert_eq!(response.status, ResponseStatus::Unknown);
+        let registers = response.registers.unwrap();
+        assert_eq!(registers.registers.len(), 14);
+        assert_eq!(registers.get("BIAS_SENSP").map(|r| (r.address, r.value)), Some((0x0D, 0xFF)));
+
+        let response = CommandResponse::parse("~5", "Success: Sample rate is 500Hz$$$", &FirmwareQuirks::latest());
+        assert_eq!((response.status, response.message.as_str()), (ResponseStatus::Success, "Success: Sample rate is 500Hz"));
+        assert!(response.registers.is_none());
+        let response = CommandResponse::parse("~9", "Failure: Invalid sample rate$$$", &FirmwareQuirks::latest());
+        assert!(response.is_failure());
+
+        let response = CommandResponse::parse("V", "v3.1.2$$$", &FirmwareQuirks::latest());
+        assert_eq!(response.firmware_version().as_deref(), Some("v3.1.2"));
+        assert!(!FirmwareQuirks::for_version("v1.0.0").eot_marker);
+    }
+
+    #[test]
+    fn decodes_the_sample_rate() {
+        let rate = |config1: &str| RegisterDump::parse(&format!("CONFIG1, 01, {}, 1, 0, 0, 1, 0, 1, 1, 0", config1)).sample_rate();
+        assert_eq!(RegisterDump::parse(DUMP).sample_rate(), Some(250));
+        assert_eq!(rate("90"), Some(16000));
+        assert_eq!(rate("94"), Some(1000));
+        assert_eq!(rate("95"), Some(500));
+        // DR = 7 is reserved and clamps to the slowest rate
+        assert_eq!(rate("97"), Some(250));
+        assert_eq!(RegisterDump::parse("CH1SET, 05, 68").sample_rate(), None);
+    }
+
+    #[test]
+    fn decodes_channel_settings() {
+        let channels = RegisterDump::parse(DUMP).channels();
+        assert_eq!(channels.len(), 8);
+        let settings = |c: &ChannelSettings| (c.channel, c.power_down, c.gain, c.input_type, c.srb2);
+        assert_eq!(settings(&channels[0]), (1, false, 24, "normal", true));
+        assert_eq!(settings(&channels[2]), (3, false, 24, "shorted", false));
+        assert_eq!(settings(&channels[3]), (4, true, 24, "normal", false));
+        assert_eq!(settings(&channels[4]), (5, false, 1, "testsig", false));
+        assert_eq!(channels[0].command().unwrap(), "x1060110X");
+
+        // The Daisy bank repeats CH1SET..CH8SET after the board's
+        let daisy = format!("{}\nDaisy ADS Registers\nCH1SET, 05, 50, 0, 1, 0, 1, 0, 0, 0, 0", DUMP.trim_end_matches(EOT));
+        let channels = RegisterDump::parse(&daisy).channels();
+        assert_eq!(settings(&channels[8]), (9, false, 12, "normal", false));
+        assert_eq!(channels[8].command().unwrap(), "xQ050100X");
+    }
+
+    #[test]
+    fn keeps_what_arrived_of_a_truncated_response() {
+        let cut = &DUMP[..DUMP.find("CH3SET").unwrap() + "CH3SET, 07, 6".len()];
+        let response = CommandResponse::parse("?", cut, &FirmwareQuirks::latest());
+        assert!(!response.terminated);
+        let registers = response.registers.unwrap();
+        // The half-printed CH3SET value is dropped rather than misread
+        assert!(registers.get("CH3SET").is_none());
+        assert_eq!(registers.channels().len(), 2);
+        assert_eq!(registers.sample_rate(), Some(250));
+
+        let response = CommandResponse::parse("?", "", &FirmwareQuirks::latest());
+        assert!(!response.terminated);
+        assert_eq!(response.registers, Some(RegisterDump::default()));
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use reqwest::Client;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bound on how long `Drop` blocks waiting for the shield
+const DROP_STOP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Keeps the shield's TCP stream alive; stops it when dropped.
+///
+/// A shield left streaming keeps pushing data at a port nobody listens on
+/// and rejects the next session's `POST /tcp`, so panics and early returns
+/// must not skip the `DELETE /tcp`. Prefer [`StreamGuard::close`], which
+/// reports errors; `Drop` blocks briefly on its own thread and only logs.
+#[must_use = "dropping the guard stops the stream immediately"]
+pub struct StreamGuard {
+    ip_address: String,
+    client: Client,
+    /// Shared with the owning `OpenBCIWiFi`, cleared by any explicit stop
+    streaming: Arc<AtomicBool>,
+}
+
+impl StreamGuard {
+    pub(crate) fn new(ip_address: &str, client: Client, streaming: Arc<AtomicBool>) -> Self {
+        streaming.store(true, Ordering::SeqCst);
+        Self {
+            ip_address: ip_address.to_string(),
+            client,
+            streaming,
+        }
+    }
+
+    /// Stop the stream now. A no-op if it was already stopped through
+    /// `OpenBCIWiFi::stop_stream`.
+    pub async fn close(self) -> Result<()> {
+        if self.streaming.swap(false, Ordering::SeqCst) {
+            delete_tcp(&self.client, &self.ip_address).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether the shield is still streaming as far as this client knows
+    pub fn is_active(&self) -> bool {
+        self.streaming.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if !self.streaming.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        warn!("Stream guard dropped while streaming, stopping shield stream");
+
+        // The caller's runtime may be shutting down (early return from
+        // main, panic unwinding), so stop from a dedicated thread with its
+        // own runtime and client instead of spawning onto it
+        let ip_address = self.ip_address.clone();
+        let stopper = std::thread::spawn(move || -> Result<()> {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            let client = Client::builder().timeout(DROP_STOP_TIMEOUT).build()?;
+            runtime.block_on(delete_tcp(&client, &ip_address))
+        });
+        match stopper.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Failed to stop stream on drop: {}", e),
+            Err(_) => error!("Stream stop thread panicked"),
+        }
+    }
+}
+
+/// `DELETE /tcp`; a non-success status is logged but not an error
+pub(crate) async fn delete_tcp(client: &Client, ip_address: &str) -> Result<()> {
+    let url = format!("http://{}/tcp", ip_address);
+    info!("Stopping TCP stream");
+
+    let response = client
+        .delete(&url)
+        .send()
+        .await
+        .context("Failed to stop stream")?;
+
+    if response.status().is_success() {
+        info!("Stream stopped successfully");
+    } else {
+        warn!("Failed to stop stream: {}", response.status());
+    }
+    Ok(())
+}
@@ -1,8 +1,33 @@
 use anyhow::{Context, Result};
-use log::{debug, error, info, warn};
+use log::{debug, error, info};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
+use stream::Watchdog;
+
+#[cfg(feature = "ble")]
+pub mod ble;
+pub mod capabilities;
+pub mod command;
+pub mod guard;
+#[cfg(feature = "serial")]
+pub mod serial;
+pub mod stream;
+#[cfg(feature = "metrics")]
+mod telemetry;
+pub mod transport;
+
+#[cfg(feature = "ble")]
+pub use ble::BleTransport;
+pub use capabilities::{BoardKind, Capabilities, CapabilityError, Link};
+pub use command::{BoardCommands, CommandResponse, FirmwareQuirks, RegisterDump, ResponseStatus};
+pub use guard::StreamGuard;
+#[cfg(feature = "serial")]
+pub use serial::SerialTransport;
+pub use stream::{timestamp_seconds, Marker, MarkerSender, Sample, SampleFeed, StreamEvent, StreamHandle, StreamLimits, StreamStats, MAX_LINE_BYTES, READ_BUFFER_SIZE, STREAM_RESTART};
+pub use transport::{BoardTransport, WiFiTransport};
 
 /// Board information from /board endpoint
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -24,10 +49,13 @@ pub struct ShieldInfo {
     pub num_channels: u8,
     pub version: String,
     pub latency: u32,
+    /// WiFi signal strength in dBm, from firmware that reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rssi: Option<i32>,
 }
 
 /// TCP streaming configuration
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct TcpConfig {
     pub ip: String,
     pub port: u16,
@@ -42,19 +70,27 @@ pub struct TcpConfig {
 pub struct OpenBCIWiFi {
     ip_address: String,
     client: Client,
+    /// Set while a `StreamGuard` from this client is live
+    streaming: Arc<AtomicBool>,
 }
 
 impl OpenBCIWiFi {
     /// Create a new OpenBCI WiFi Shield client
     pub fn new(ip_address: &str) -> Self {
+        Self::with_timeout(ip_address, Duration::from_secs(10))
+    }
+
+    /// Create a client with a custom HTTP timeout (POST /tcp can be slow)
+    pub fn with_timeout(ip_address: &str, timeout: Duration) -> Self {
         let client = Client::builder()
-            .timeout(Duration::from_secs(10))
+            .timeout(timeout)
             .build()
             .expect("Failed to create HTTP client");
 
         Self {
             ip_address: ip_address.to_string(),
             client,
+            streaming: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -114,65 +150,80 @@ impl OpenBCIWiFi {
         Ok(text)
     }
 
-    /// Start TCP streaming
+    /// Capabilities of the attached board, from `/board` and `/version`.
+    /// `firmware` is the board firmware reported by `V`, if any.
+    pub async fn capabilities(&self, firmware: Option<&str>) -> Result<Capabilities> {
+        let board = self.get_board_info().await?;
+        if !board.board_connected {
+            anyhow::bail!("No board attached to the shield at {}", self.ip_address);
+        }
+        let version = self.get_version().await?;
+        Capabilities::from_board_info(&board, &version, firmware)
+            .with_context(|| format!("Unknown board type '{}'", board.board_type))
+    }
+
+    fn tcp_config(&self, local_ip: &str, local_port: u16, output_format: &str, latency_us: u32, burst: bool) -> TcpConfig {
+        TcpConfig {
+            ip: local_ip.to_string(),
+            port: local_port,
+            output: output_format.to_string(),
+            delimiter: true,
+            latency: latency_us,
+            burst: Some(burst),
+        }
+    }
+
+    /// Start TCP streaming. The shield keeps streaming until the returned
+    /// guard is closed or dropped, or `stop_stream` is called. In `burst`
+    /// mode the shield sends packets as soon as they fill instead of
+    /// every `latency_us`, which keeps up at high sample rates.
     pub async fn start_tcp_stream(
         &self,
         local_ip: &str,
         local_port: u16,
         output_format: &str,
         latency_us: u32,
-    ) -> Result<()> {
-        let config = TcpConfig {
-            ip: local_ip.to_string(),
-            port: local_port,
-            output: output_format.to_string(),
-            delimiter: true,
-            latency: latency_us,
-            burst: Some(false),
-        };
+        burst: bool,
+    ) -> Result<StreamGuard> {
+        let config = self.tcp_config(local_ip, local_port, output_format, latency_us, burst);
 
-        let url = format!("http://{}/tcp", self.ip_address);
         info!("Starting TCP stream to {}:{}", local_ip, local_port);
-        debug!("TCP config: {:?}", config);
+        post_tcp(&self.client, &self.ip_address, &config).await?;
+        Ok(StreamGuard::new(&self.ip_address, self.client.clone(), Arc::clone(&self.streaming)))
+    }
 
-        let response = self
-            .client
-            .post(&url)
-            .json(&config)
-            .send()
-            .await
-            .context("Failed to start TCP stream")?;
-
-        if response.status().is_success() {
-            info!("TCP stream started successfully");
-            Ok(())
-        } else {
-            let status = response.status();
-            let text = response.text().await.unwrap_or_default();
-            error!("Failed to start TCP stream: {} - {}", status, text);
-            anyhow::bail!("Failed to start TCP stream: {}", status)
-        }
+    /// Listen on `local_port`, start TCP streaming to it and return a handle
+    /// yielding parsed samples merged with inserted markers. Dropping the
+    /// handle stops the shield's stream. With `stall_timeout`, a stream
+    /// that sends nothing for that long is stopped and started again.
+    /// `limits` size the reader for the stream's rate.
+    pub async fn open_stream(
+        &self,
+        local_ip: &str,
+        local_port: u16,
+        latency_us: u32,
+        burst: bool,
+        stall_timeout: Option<Duration>,
+        limits: StreamLimits,
+    ) -> Result<StreamHandle> {
+        let watchdog = stall_timeout.map(|stall| Watchdog {
+            stall,
+            client: self.client.clone(),
+            ip_address: self.ip_address.clone(),
+            config: self.tcp_config(local_ip, local_port, "json", latency_us, burst),
+        });
+        // Listener must be up before the shield tries to connect
+        let handle = StreamHandle::listen(local_port, watchdog, limits).await?;
+        let guard = self
+            .start_tcp_stream(local_ip, local_port, "json", latency_us, burst)
+            .await?;
+        Ok(handle.with_guard(guard))
     }
 
-    /// Stop streaming
+    /// Stop streaming; also disarms any live `StreamGuard`
     pub async fn stop_stream(&self) -> Result<()> {
-        let url = format!("http://{}/tcp", self.ip_address);
-        info!("Stopping TCP stream");
-
-        let response = self
-            .client
-            .delete(&url)
-            .send()
-            .await
-            .context("Failed to stop stream")?;
-
-        if response.status().is_success() {
-            info!("Stream stopped successfully");
-            Ok(())
-        } else {
-            warn!("Failed to stop stream: {}", response.status());
-            Ok(()) // Don't fail on stop errors
-        }
+        self.streaming.store(false, Ordering::SeqCst);
+        guard::delete_tcp(&self.client, &self.ip_address).await
     }
 
     /// Send a command to the board
@@ -197,3 +248,27 @@ impl OpenBCIWiFi {
         &self.ip_address
     }
 }
+
+/// `POST /tcp`: have the shield connect to `config.ip:config.port` and
+/// stream
+pub(crate) async fn post_tcp(client: &Client, ip_address: &str, config: &TcpConfig) -> Result<()> {
+    let url = format!("http://{}/tcp", ip_address);
+    debug!("TCP config: {:?}", config);
+
+    let response = client
+        .post(&url)
+        .json(config)
+        .send()
+        .await
+        .context("Failed to start TCP stream")?;
+
+    if response.status().is_success() {
+        info!("TCP stream started successfully");
+        Ok(())
+    } else {
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        error!("Failed to start TCP stream: {} - {}", status, text);
+        anyhow::bail!("Failed to start TCP stream: {}", status)
+    }
+}
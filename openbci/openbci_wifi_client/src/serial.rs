@@ -0,0 +1,204 @@
+use crate::stream::{unix_time, StreamCounters};
+use crate::transport::BoardTransport;
+use crate::{BoardKind, Capabilities, Link, Sample, StreamEvent, StreamHandle};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use log::{debug, error, info};
+use serialport::SerialPort;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+/// Cyton dongle baud rate
+const BAUD_RATE: u32 = 115_200;
+/// Read timeout, also bounds how fast the reader notices a closed stream
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+/// How long to wait for a `$$$`-terminated command response
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Cyton binary packet layout
+const PACKET_LEN: usize = 33;
+const PACKET_HEADER: u8 = 0xA0;
+const NUM_CHANNELS: usize = 8;
+
+/// Gain the samples are scaled with, the Cyton's default
+const SCALE_GAIN: u8 = 24;
+/// ADS1299 count to nanovolts at `SCALE_GAIN`
+const SCALE_NV: f32 = 4.5 / SCALE_GAIN as f32 / 8_388_607.0 * 1e9;
+
+/// Cyton over the USB dongle (RFduino serial bridge)
+pub struct SerialTransport {
+    path: String,
+    port: Arc<Mutex<Box<dyn SerialPort>>>,
+}
+
+impl SerialTransport {
+    /// Open the dongle's serial port, e.g. `/dev/ttyUSB0` or `COM3`
+    pub fn open(path: &str) -> Result<Self> {
+        let port = serialport::new(path, BAUD_RATE)
+            .timeout(READ_TIMEOUT)
+            .open()
+            .with_context(|| format!("Failed to open serial port {}", path))?;
+        info!("Opened serial port {} at {} baud", path, BAUD_RATE);
+
+        Ok(Self {
+            path: path.to_string(),
+            port: Arc::new(Mutex::new(port)),
+        })
+    }
+
+    fn write(&self, bytes: &[u8]) -> Result<()> {
+        let mut port = self.port.lock().unwrap();
+        port.write_all(bytes)
+            .with_context(|| format!("Failed to write to {}", self.path))?;
+        port.flush()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BoardTransport for SerialTransport {
+    fn describe(&self) -> String {
+        format!("serial {}", self.path)
+    }
+
+    async fn send_command(&self, command: &str) -> Result<String> {
+        info!("Sending command: {}", command);
+        let port = Arc::clone(&self.port);
+        let command = command.to_string();
+
+        tokio::task::spawn_blocking(move || -> Result<String> {
+            let mut port = port.lock().unwrap();
+            port.write_all(command.as_bytes())?;
+            port.flush()?;
+
+            // Responses end with "$$$"; commands without one just time out
+            let mut response = Vec::new();
+            let mut buf = [0u8; 256];
+            let deadline = Instant::now() + COMMAND_TIMEOUT;
+            while Instant::now() < deadline && !response.ends_with(b"$$$") {
+                match port.read(&mut buf) {
+                    Ok(n) => response.extend_from_slice(&buf[..n]),
+                    Err(e) if e.kind() == ErrorKind::TimedOut => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Ok(String::from_utf8_lossy(&response).into_owned())
+        })
+        .await?
+    }
+
+    /// The dongle does not report the board type; assume a plain Cyton
+    async fn capabilities(&self, firmware: Option<&str>) -> Result<Capabilities> {
+        Ok(Capabilities::for_board(BoardKind::Cyton, Link::Serial, firmware))
+    }
+
+    async fn scaling_gains(&self) -> Result<Option<Vec<u8>>> {
+        Ok(Some(vec![SCALE_GAIN; NUM_CHANNELS]))
+    }
+
+    async fn open_stream(&self) -> Result<StreamHandle> {
+        let reader = self
+            .port
+            .lock()
+            .unwrap()
+            .try_clone()
+            .context("Failed to clone serial port")?;
+
+        self.write(b"b")?;
+        info!("Streaming from {}", self.path);
+
+        let (tx, events) = mpsc::unbounded_channel();
+        let task_tx = tx.clone();
+        let counters = Arc::new(StreamCounters::new("serial"));
+        let task_counters = Arc::clone(&counters);
+        let task = tokio::task::spawn_blocking(move || read_packets(reader, task_tx, task_counters));
+        Ok(StreamHandle::from_parts(events, tx, counters, task))
+    }
+
+    async fn stop_stream(&self) -> Result<()> {
+        info!("Stopping serial stream");
+        self.write(b"s")
+    }
+}
+
+/// Blocking reader: decode Cyton packets until the stream handle is dropped
+fn read_packets(mut port: Box<dyn SerialPort>, tx: UnboundedSender<StreamEvent>, counters: Arc<StreamCounters>) {
+    let mut buf = [0u8; 1024];
+    let mut pending: Vec<u8> = Vec::new();
+    let mut last_id = None;
+
+    loop {
+        if tx.is_closed() {
+            return;
+        }
+
+        let n = match port.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => {
+                error!("Error reading from serial port: {}", e);
+                return;
+            }
+        };
+
+        counters.add_bytes(n);
+
+        pending.extend_from_slice(&buf[..n]);
+        for sample in drain_packets(&mut pending, &mut last_id, &counters) {
+            counters.add_sample(sample.timestamp);
+            if tx.send(StreamEvent::Sample(sample)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Pull every complete packet out of `pending`, resyncing on bad framing.
+/// Each resync counts as one parse error; gaps in the 8-bit sample number
+/// after `last_id` count as dropped packets.
+fn drain_packets(pending: &mut Vec<u8>, last_id: &mut Option<u8>, counters: &StreamCounters) -> Vec<Sample> {
+    let mut samples = Vec::new();
+    let mut start = 0;
+    let mut resyncing = false;
+
+    while pending.len() - start >= PACKET_LEN {
+        let packet = &pending[start..start + PACKET_LEN];
+        // Footer is 0xC0..=0xCF depending on the aux data mode
+        if packet[0] != PACKET_HEADER || packet[PACKET_LEN - 1] & 0xF0 != 0xC0 {
+            if !resyncing {
+                counters.parse_error();
+                resyncing = true;
+            }
+            start += 1;
+            continue;
+        }
+        resyncing = false;
+
+        let data = (0..NUM_CHANNELS)
+            .map(|ch| {
+                let b = &packet[2 + ch * 3..5 + ch * 3];
+                // 24-bit big-endian two's complement
+                let raw = i32::from_be_bytes([b[0], b[1], b[2], 0]) >> 8;
+                raw as f32 * SCALE_NV
+            })
+            .collect();
+
+        debug!("Cyton packet #{}", packet[1]);
+        if let Some(last) = last_id.replace(packet[1]) {
+            let missing = packet[1].wrapping_sub(last).wrapping_sub(1);
+            if missing > 0 {
+                counters.add_dropped(missing as u64);
+            }
+        }
+        samples.push(Sample {
+            data,
+            timestamp: unix_time(),
+        });
+        start += PACKET_LEN;
+    }
+
+    pending.drain(..start);
+    samples
+}
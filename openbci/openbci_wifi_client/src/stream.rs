@@ -0,0 +1,604 @@
+use crate::guard::{delete_tcp, StreamGuard};
+use crate::{post_tcp, TcpConfig};
+use anyhow::{Context, Result};
+use log::{debug, error, info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+/// How long to wait for the shield to connect back after POST /tcp
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Marker prefix of a stream restarted by the watchdog after a stall,
+/// followed by the seconds without data
+pub const STREAM_RESTART: &str = "stream_restart:";
+
+/// Socket read buffer. A Cyton streams well under 16 KB/s, so the smaller
+/// buffer on aarch64 (Raspberry Pi) hosts costs nothing but cache.
+#[cfg(target_arch = "aarch64")]
+pub const READ_BUFFER_SIZE: usize = 4096;
+#[cfg(not(target_arch = "aarch64"))]
+pub const READ_BUFFER_SIZE: usize = 16384;
+
+/// Longest JSON line accepted. A shield chunk is a few KB; anything longer
+/// is a corrupted stream that lost its delimiter, and is skipped up to the
+/// next newline instead of being buffered.
+pub const MAX_LINE_BYTES: usize = 64 * 1024;
+
+/// Largest socket read buffer [`StreamLimits::for_rate`] picks
+const MAX_READ_BUFFER: usize = 1024 * 1024;
+
+/// Upper bound on the JSON of one sample: the timestamp and braces, plus
+/// one nanovolt value per channel
+fn json_sample_bytes(channels: usize) -> usize {
+    40 + 16 * channels
+}
+
+/// Sizes of a stream reader's buffers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamLimits {
+    /// Socket read buffer in bytes
+    pub read_buffer: usize,
+    /// Longest JSON line accepted, see [`MAX_LINE_BYTES`]
+    pub max_line: usize,
+}
+
+impl Default for StreamLimits {
+    fn default() -> Self {
+        Self {
+            read_buffer: READ_BUFFER_SIZE,
+            max_line: MAX_LINE_BYTES,
+        }
+    }
+}
+
+impl StreamLimits {
+    /// Limits for `sample_rate` Hz of `channels` channels: a read buffer
+    /// holding 50 ms of JSON and lines of up to half a second, never below
+    /// the defaults. At 1 kHz and above a fixed buffer needs a read per
+    /// few samples, and a shield chunk outgrows the fixed line limit.
+    pub fn for_rate(sample_rate: u32, channels: usize) -> Self {
+        let bytes_per_sec = sample_rate as usize * json_sample_bytes(channels);
+        let default = Self::default();
+        Self {
+            read_buffer: (bytes_per_sec / 20)
+                .next_power_of_two()
+                .clamp(default.read_buffer, MAX_READ_BUFFER.max(default.read_buffer)),
+            max_line: (bytes_per_sec / 2).max(default.max_line),
+        }
+    }
+}
+
+/// One sample from the shield's JSON output
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Sample {
+    pub data: Vec<f32>, // Channel data in nanovolts
+    pub timestamp: f64, // Shield timestamp
+}
+
+/// JSON chunk as sent by the shield, one per delimited line
+#[derive(Debug, Deserialize)]
+struct Chunk {
+    chunk: Vec<Sample>,
+}
+
+/// Host-timestamped event inserted by experiment software
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Marker {
+    pub label: String,
+    pub host_time: f64, // Seconds since the Unix epoch
+}
+
+impl Marker {
+    pub fn now(label: impl Into<String>) -> Self {
+        Self {
+            label: label.into(),
+            host_time: unix_time(),
+        }
+    }
+}
+
+/// Host wall-clock time in seconds since the Unix epoch
+pub fn unix_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/// Item of the merged outgoing stream
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    Sample(Sample),
+    Marker(Marker),
+}
+
+/// Cloneable handle for inserting markers from other tasks or threads
+#[derive(Debug, Clone)]
+pub struct MarkerSender {
+    tx: UnboundedSender<StreamEvent>,
+}
+
+impl MarkerSender {
+    /// Record a marker at the current host time and merge it into the stream
+    pub fn insert_marker(&self, label: impl Into<String>) -> Marker {
+        let marker = Marker::now(label);
+        debug!("Marker '{}' at {:.6}", marker.label, marker.host_time);
+        if self.tx.send(StreamEvent::Marker(marker.clone())).is_err() {
+            warn!("Stream closed, marker '{}' dropped", marker.label);
+        }
+        marker
+    }
+}
+
+/// Sending side of a stream produced outside this crate, such as a file
+/// replay or a synthetic signal; see [`StreamHandle::spawn`]
+#[derive(Debug, Clone)]
+pub struct SampleFeed {
+    tx: UnboundedSender<StreamEvent>,
+    counters: Arc<StreamCounters>,
+}
+
+impl SampleFeed {
+    /// Send a sample; `false` once the stream handle is gone
+    pub fn send(&self, sample: Sample) -> bool {
+        self.counters.add_sample(sample.timestamp);
+        self.tx.send(StreamEvent::Sample(sample)).is_ok()
+    }
+
+    /// Send a marker in stream order; `false` once the stream handle is gone
+    pub fn send_marker(&self, marker: Marker) -> bool {
+        self.tx.send(StreamEvent::Marker(marker)).is_ok()
+    }
+}
+
+/// Counters updated by a reader task and read through [`StreamHandle::stats`]
+#[derive(Debug)]
+pub(crate) struct StreamCounters {
+    started: Instant,
+    bytes: AtomicU64,
+    samples: AtomicU64,
+    parse_errors: AtomicU64,
+    dropped_packets: AtomicU64,
+    reconnects: AtomicU64,
+    #[cfg(feature = "metrics")]
+    metrics: crate::telemetry::StreamMetrics,
+}
+
+impl StreamCounters {
+    /// `transport` labels the exported metrics, e.g. `wifi`
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub(crate) fn new(transport: &'static str) -> Self {
+        Self {
+            started: Instant::now(),
+            bytes: AtomicU64::new(0),
+            samples: AtomicU64::new(0),
+            parse_errors: AtomicU64::new(0),
+            dropped_packets: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            #[cfg(feature = "metrics")]
+            metrics: crate::telemetry::StreamMetrics::new(transport),
+        }
+    }
+
+    pub(crate) fn add_bytes(&self, n: usize) {
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.metrics.bytes.increment(n as u64);
+    }
+
+    /// Count a decoded sample stamped `timestamp` (Unix time in s, ms or us)
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub(crate) fn add_sample(&self, timestamp: f64) {
+        self.samples.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        {
+            self.metrics.samples.increment(1);
+            if let Some(latency) = sample_latency(timestamp) {
+                self.metrics.latency.set(latency);
+            }
+        }
+    }
+
+    pub(crate) fn parse_error(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.metrics.parse_errors.increment(1);
+    }
+
+    pub(crate) fn add_dropped(&self, packets: u64) {
+        self.dropped_packets.fetch_add(packets, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.metrics.dropped_packets.increment(packets);
+    }
+
+    pub(crate) fn reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.metrics.reconnects.increment(1);
+    }
+
+    fn snapshot(&self) -> StreamStats {
+        StreamStats {
+            elapsed: self.started.elapsed().as_secs_f64(),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            samples: self.samples.load(Ordering::Relaxed),
+            parse_errors: self.parse_errors.load(Ordering::Relaxed),
+            dropped_packets: self.dropped_packets.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Sample timestamp in seconds. The shield stamps Unix time in ms, the
+/// serial and BLE readers in s; µs is accepted too.
+pub fn timestamp_seconds(timestamp: f64) -> f64 {
+    if timestamp > 1e14 {
+        timestamp / 1e6
+    } else if timestamp > 1e11 {
+        timestamp / 1e3
+    } else {
+        timestamp
+    }
+}
+
+/// Seconds between a sample's timestamp and now, when the timestamp is
+/// wall-clock time. Boards without a clock produce values that are not
+/// plausible and are ignored.
+#[cfg(feature = "metrics")]
+fn sample_latency(timestamp: f64) -> Option<f64> {
+    let latency = unix_time() - timestamp_seconds(timestamp);
+    (0.0..60.0).contains(&latency).then_some(latency)
+}
+
+/// Restarts a shield stream that stops sending while its socket stays
+/// open, which the shield does not recover from on its own
+pub(crate) struct Watchdog {
+    /// Time without data after which the stream is restarted
+    pub(crate) stall: Duration,
+    pub(crate) client: Client,
+    pub(crate) ip_address: String,
+    pub(crate) config: TcpConfig,
+}
+
+impl Watchdog {
+    /// `DELETE` then `POST /tcp`, so the shield connects back again
+    async fn restart(&self) -> Result<()> {
+        delete_tcp(&self.client, &self.ip_address).await?;
+        post_tcp(&self.client, &self.ip_address, &self.config).await
+    }
+}
+
+/// Totals since the stream was opened
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct StreamStats {
+    /// Seconds since the stream was opened
+    pub elapsed: f64,
+    pub bytes: u64,
+    pub samples: u64,
+    /// Lines or packets that could not be decoded
+    pub parse_errors: u64,
+    /// Packets missing from the board's sequence numbering
+    pub dropped_packets: u64,
+    /// Times the board reconnected after dropping the data link
+    pub reconnects: u64,
+}
+
+impl StreamStats {
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.max(f64::EPSILON)
+    }
+
+    pub fn samples_per_sec(&self) -> f64 {
+        self.samples as f64 / self.elapsed.max(f64::EPSILON)
+    }
+
+    /// Counts accumulated between `earlier` and this snapshot, for rates
+    /// over an interval rather than the whole stream
+    pub fn since(&self, earlier: &StreamStats) -> StreamStats {
+        StreamStats {
+            elapsed: self.elapsed - earlier.elapsed,
+            bytes: self.bytes.saturating_sub(earlier.bytes),
+            samples: self.samples.saturating_sub(earlier.samples),
+            parse_errors: self.parse_errors.saturating_sub(earlier.parse_errors),
+            dropped_packets: self.dropped_packets.saturating_sub(earlier.dropped_packets),
+            reconnects: self.reconnects.saturating_sub(earlier.reconnects),
+        }
+    }
+}
+
+impl fmt::Display for StreamStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} samples ({:.1} Hz), {:.1} KB/s, {} parse errors, {} dropped packets, {} reconnects",
+            self.samples,
+            self.samples_per_sec(),
+            self.bytes_per_sec() / 1024.0,
+            self.parse_errors,
+            self.dropped_packets,
+            self.reconnects
+        )
+    }
+}
+
+/// A running sample stream from the board.
+///
+/// Samples and markers arrive through the same channel, so markers are
+/// ordered relative to the samples received before and after them.
+pub struct StreamHandle {
+    events: UnboundedReceiver<StreamEvent>,
+    markers: MarkerSender,
+    counters: Arc<StreamCounters>,
+    task: JoinHandle<()>,
+    stats_logger: Option<JoinHandle<()>>,
+    /// Stops the board-side stream when the handle goes away (WiFi only)
+    guard: Option<StreamGuard>,
+}
+
+impl StreamHandle {
+    /// Bind `0.0.0.0:port` and spawn the reader task. The shield must be
+    /// told to stream to this port after the listener is up.
+    pub(crate) async fn listen(port: u16, watchdog: Option<Watchdog>, limits: StreamLimits) -> Result<Self> {
+        let addr = format!("0.0.0.0:{}", port);
+        let listener = TcpListener::bind(&addr)
+            .await
+            .context(format!("Failed to bind to {}", addr))?;
+        info!("Listening on {}", addr);
+
+        let (tx, events) = mpsc::unbounded_channel();
+        let counters = Arc::new(StreamCounters::new("wifi"));
+        let task = tokio::spawn(read_stream(listener, tx.clone(), Arc::clone(&counters), watchdog, limits));
+        Ok(Self::from_parts(events, tx, counters, task))
+    }
+
+    /// Wrap a channel fed by a transport-specific reader task, which
+    /// updates `counters` as it goes
+    pub(crate) fn from_parts(
+        events: UnboundedReceiver<StreamEvent>,
+        tx: UnboundedSender<StreamEvent>,
+        counters: Arc<StreamCounters>,
+        task: JoinHandle<()>,
+    ) -> Self {
+        Self {
+            events,
+            markers: MarkerSender { tx },
+            counters,
+            task,
+            stats_logger: None,
+            guard: None,
+        }
+    }
+
+    /// Stream fed by `produce`, spawned as a task that runs until it returns
+    /// or the handle is dropped. `transport` labels the exported metrics.
+    pub fn spawn<F, Fut>(transport: &'static str, produce: F) -> Self
+    where
+        F: FnOnce(SampleFeed) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (tx, events) = mpsc::unbounded_channel();
+        let counters = Arc::new(StreamCounters::new(transport));
+        let feed = SampleFeed {
+            tx: tx.clone(),
+            counters: Arc::clone(&counters),
+        };
+        let task = tokio::spawn(produce(feed));
+        Self::from_parts(events, tx, counters, task)
+    }
+
+    /// Tie the board-side stream's lifetime to this handle
+    pub(crate) fn with_guard(mut self, guard: StreamGuard) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    /// Stop the board-side stream, if this handle owns it, and end the
+    /// stream. Unlike dropping the handle this reports stop failures.
+    pub async fn close(mut self) -> Result<()> {
+        match self.guard.take() {
+            Some(guard) => guard.close().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Throughput and error counts since the stream was opened
+    pub fn stats(&self) -> StreamStats {
+        self.counters.snapshot()
+    }
+
+    /// Log a throughput line every `interval` until the stream is dropped.
+    /// Rates cover the last interval; calling again replaces the logger.
+    pub fn log_stats_every(&mut self, interval: Duration) {
+        let counters = Arc::clone(&self.counters);
+        let logger = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            let mut last = counters.snapshot();
+            loop {
+                ticker.tick().await;
+                let now = counters.snapshot();
+                let window = now.since(&last);
+                info!(
+                    "Stream: {} samples total, {:.1} Hz, {:.1} KB/s, {} parse errors, {} dropped, {} reconnects",
+                    now.samples,
+                    window.samples_per_sec(),
+                    window.bytes_per_sec() / 1024.0,
+                    now.parse_errors,
+                    now.dropped_packets,
+                    now.reconnects
+                );
+                last = now;
+            }
+        });
+        if let Some(old) = self.stats_logger.replace(logger) {
+            old.abort();
+        }
+    }
+
+    /// Record a marker at the current host time and merge it into the stream
+    pub fn insert_marker(&self, label: impl Into<String>) -> Marker {
+        self.markers.insert_marker(label)
+    }
+
+    /// Sender for inserting markers from elsewhere
+    pub fn marker_sender(&self) -> MarkerSender {
+        self.markers.clone()
+    }
+
+    /// Next sample or marker; `None` once the board disconnects
+    pub async fn recv(&mut self) -> Option<StreamEvent> {
+        self.events.recv().await
+    }
+}
+
+impl Drop for StreamHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+        if let Some(logger) = &self.stats_logger {
+            logger.abort();
+        }
+    }
+}
+
+async fn read_stream(
+    listener: TcpListener,
+    tx: UnboundedSender<StreamEvent>,
+    counters: Arc<StreamCounters>,
+    watchdog: Option<Watchdog>,
+    limits: StreamLimits,
+) {
+    let mut buffer = vec![0u8; limits.read_buffer];
+    let mut connected_before = false;
+
+    // The shield reconnects on its own after WiFi hiccups, so keep accepting
+    // until it stays away for CONNECT_TIMEOUT
+    loop {
+        let (mut socket, addr) = match tokio::time::timeout(CONNECT_TIMEOUT, listener.accept()).await {
+            Ok(Ok(conn)) => conn,
+            Ok(Err(e)) => {
+                error!("Failed to accept connection: {}", e);
+                return;
+            }
+            Err(_) => {
+                if connected_before {
+                    warn!("Shield did not reconnect within {:?}", CONNECT_TIMEOUT);
+                } else {
+                    error!("Shield did not connect within {:?}", CONNECT_TIMEOUT);
+                }
+                return;
+            }
+        };
+        if connected_before {
+            counters.reconnect();
+            warn!("Shield reconnected from {}", addr);
+        } else {
+            info!("Connected to: {}", addr);
+            connected_before = true;
+        }
+
+        // Bytes of a line split across reads
+        let mut pending: Vec<u8> = Vec::new();
+        // Leading bytes of `pending` already searched for a newline
+        let mut scanned = 0;
+        // Inside an overlong line, dropping bytes up to the next newline
+        let mut skipping = false;
+
+        loop {
+            let read = socket.read(&mut buffer);
+            let read = match &watchdog {
+                Some(watchdog) => match tokio::time::timeout(watchdog.stall, read).await {
+                    Ok(read) => read,
+                    Err(_) => {
+                        let seconds = watchdog.stall.as_secs_f64();
+                        warn!("No data from {} for {:.1} s, restarting the stream", addr, seconds);
+                        // Marks the gap ahead of the first sample after the restart
+                        let marker = Marker::now(format!("{}{:.1}", STREAM_RESTART, seconds));
+                        if tx.send(StreamEvent::Marker(marker)).is_err() {
+                            return;
+                        }
+                        drop(socket);
+                        if let Err(e) = watchdog.restart().await {
+                            error!("Failed to restart the stream: {}", e);
+                        }
+                        break;
+                    }
+                },
+                None => read.await,
+            };
+            let n = match read {
+                Ok(0) => {
+                    info!("Connection closed by {}", addr);
+                    break;
+                }
+                Ok(n) => n,
+                Err(e) => {
+                    error!("Error reading from socket: {}", e);
+                    break;
+                }
+            };
+            counters.add_bytes(n);
+
+            let mut received = &buffer[..n];
+            if skipping {
+                match received.iter().position(|&b| b == b'\n') {
+                    Some(pos) => {
+                        received = &received[pos + 1..];
+                        skipping = false;
+                    }
+                    None => continue,
+                }
+            }
+            pending.extend_from_slice(received);
+            // Lines are parsed in place and drained once per read, so a
+            // read holding many chunks costs no more than one copy
+            let mut start = 0;
+            while let Some(pos) = pending[scanned..].iter().position(|&b| b == b'\n') {
+                let end = scanned + pos;
+                scanned = end + 1;
+                let line = String::from_utf8_lossy(&pending[start..end]);
+                start = scanned;
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+
+                match serde_json::from_str::<Chunk>(line) {
+                    Ok(chunk) => {
+                        for sample in chunk.chunk {
+                            counters.add_sample(sample.timestamp);
+                            if tx.send(StreamEvent::Sample(sample)).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        counters.parse_error();
+                        debug!("Failed to parse JSON: {} - Data: {}", e, line);
+                    }
+                }
+            }
+            pending.drain(..start);
+            scanned = pending.len();
+            if pending.len() > limits.max_line {
+                counters.parse_error();
+                warn!("Skipping {} bytes without a line delimiter", pending.len());
+                pending.clear();
+                scanned = 0;
+                skipping = true;
+            }
+        }
+
+        if tx.is_closed() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! Stream health through the `metrics` facade, for Prometheus/Grafana.
+//!
+//! The library only records; the application installs an exporter (e.g.
+//! `metrics-exporter-prometheus`). Every series carries a `transport` label.
+
+use metrics::{counter, describe_counter, describe_gauge, gauge, Counter, Gauge, Unit};
+use std::sync::Once;
+
+static DESCRIBE: Once = Once::new();
+
+/// Metric handles for one stream
+#[derive(Debug)]
+pub(crate) struct StreamMetrics {
+    pub samples: Counter,
+    pub bytes: Counter,
+    pub parse_errors: Counter,
+    pub dropped_packets: Counter,
+    pub reconnects: Counter,
+    pub connected: Gauge,
+    pub latency: Gauge,
+}
+
+impl StreamMetrics {
+    pub fn new(transport: &'static str) -> Self {
+        DESCRIBE.call_once(|| {
+            describe_counter!("openbci_stream_samples_total", "Samples received from the board");
+            describe_counter!("openbci_stream_bytes_total", Unit::Bytes, "Bytes read from the data link");
+            describe_counter!("openbci_stream_parse_errors_total", "Lines or packets that could not be decoded");
+            describe_counter!("openbci_stream_dropped_packets_total", "Packets missing from the sequence numbering");
+            describe_counter!("openbci_stream_reconnects_total", "Data link reconnects");
+            describe_gauge!("openbci_stream_connected", "1 while a stream is open");
+            describe_gauge!("openbci_stream_latency_seconds", Unit::Seconds, "Host receive time minus sample timestamp");
+        });
+
+        let connected = gauge!("openbci_stream_connected", "transport" => transport);
+        connected.set(1.0);
+        Self {
+            samples: counter!("openbci_stream_samples_total", "transport" => transport),
+            bytes: counter!("openbci_stream_bytes_total", "transport" => transport),
+            parse_errors: counter!("openbci_stream_parse_errors_total", "transport" => transport),
+            dropped_packets: counter!("openbci_stream_dropped_packets_total", "transport" => transport),
+            reconnects: counter!("openbci_stream_reconnects_total", "transport" => transport),
+            connected,
+            latency: gauge!("openbci_stream_latency_seconds", "transport" => transport),
+        }
+    }
+}
+
+impl Drop for StreamMetrics {
+    fn drop(&mut self) {
+        self.connected.set(0.0);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{Capabilities, OpenBCIWiFi, ShieldInfo, StreamHandle, StreamLimits};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::time::Duration;
+
+/// Link between the host and an OpenBCI board.
+///
+/// Everything above this trait (collector, sinks, classifiers) only sees
+/// commands and a [`StreamHandle`], so it works the same over the WiFi
+/// shield, the Cyton USB dongle or Ganglion BLE.
+#[async_trait]
+pub trait BoardTransport: Send + Sync {
+    /// Human-readable description for logs, e.g. `wifi 192.168.4.1`
+    fn describe(&self) -> String;
+
+    /// Send a board command and return its raw response
+    async fn send_command(&self, command: &str) -> Result<String>;
+
+    /// What the attached board can do, given the firmware version `V`
+    /// reported (`None` for v1 firmware)
+    async fn capabilities(&self, firmware: Option<&str>) -> Result<Capabilities>;
+
+    /// PGA gain of each channel the link converts samples to nanovolts
+    /// with, when it assumes any
+    async fn scaling_gains(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Status of the WiFi shields on the link from `/all`: firmware,
+    /// free heap, packet latency
+    async fn shield_info(&self) -> Result<Vec<ShieldInfo>> {
+        Ok(Vec::new())
+    }
+
+    /// Start streaming and return the merged sample/marker stream
+    async fn open_stream(&self) -> Result<StreamHandle>;
+
+    /// Stop streaming; safe to call when nothing is streaming
+    async fn stop_stream(&self) -> Result<()>;
+}
+
+/// WiFi shield transport: control over HTTP, data over TCP JSON
+pub struct WiFiTransport {
+    shield: OpenBCIWiFi,
+    local_ip: String,
+    local_port: u16,
+    latency_us: u32,
+    burst: bool,
+    stall_timeout: Option<Duration>,
+    limits: StreamLimits,
+}
+
+impl WiFiTransport {
+    /// `local_ip`/`local_port` are where the shield should connect back to
+    pub fn new(shield: OpenBCIWiFi, local_ip: &str, local_port: u16, latency_us: u32) -> Self {
+        Self {
+            shield,
+            local_ip: local_ip.to_string(),
+            local_port,
+            latency_us,
+            burst: false,
+            stall_timeout: None,
+            limits: StreamLimits::default(),
+        }
+    }
+
+    /// Restart the shield's stream when it sends nothing for `timeout`
+    /// (see [`OpenBCIWiFi::open_stream`])
+    pub fn with_stall_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.stall_timeout = timeout;
+        self
+    }
+
+    /// Have the shield send packets as they fill rather than every
+    /// `latency_us` (see [`OpenBCIWiFi::start_tcp_stream`])
+    pub fn with_burst(mut self, burst: bool) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    /// Size the stream reader, e.g. with [`StreamLimits::for_rate`]
+    pub fn with_limits(mut self, limits: StreamLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Underlying HTTP client, for shield-specific endpoints
+    pub fn shield(&self) -> &OpenBCIWiFi {
+        &self.shield
+    }
+}
+
+#[async_trait]
+impl BoardTransport for WiFiTransport {
+    fn describe(&self) -> String {
+        format!("wifi {}", self.shield.ip_address())
+    }
+
+    async fn send_command(&self, command: &str) -> Result<String> {
+        self.shield.send_command(command).await
+    }
+
+    async fn capabilities(&self, firmware: Option<&str>) -> Result<Capabilities> {
+        self.shield.capabilities(firmware).await
+    }
+
+    /// The shield scales with the gains it reports on `/board`
+    async fn scaling_gains(&self) -> Result<Option<Vec<u8>>> {
+        Ok(Some(self.shield.get_board_info().await?.gains))
+    }
+
+    async fn shield_info(&self) -> Result<Vec<ShieldInfo>> {
+        Ok(vec![self.shield.get_shield_info().await?])
+    }
+
+    async fn open_stream(&self) -> Result<StreamHandle> {
+        self.shield
+            .open_stream(
+                &self.local_ip,
+                self.local_port,
+                self.latency_us,
+                self.burst,
+                self.stall_timeout,
+                self.limits,
+            )
+            .await
+    }
+
+    async fn stop_stream(&self) -> Result<()> {
+        self.shield.stop_stream().await
+    }
+}